@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static HEAP_BYTES: AtomicU64 = AtomicU64::new(0);
+static PEAK_HEAP_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Wraps an allocator to count allocations and track the peak of live heap memory, for the run
+/// summary. The binary installs it around the system allocator when built with the
+/// 'alloc-stats' feature, and any other global allocator, such as jemalloc or mimalloc, can be
+/// wrapped the same way.
+pub struct CountingAllocator<A> {
+    inner: A,
+}
+
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> CountingAllocator<A> {
+        CountingAllocator { inner }
+    }
+}
+
+/// Allocations made over the run by way of a `CountingAllocator`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct AllocatorStats {
+    /// Allocations and reallocations made
+    pub allocations: u64,
+    /// Bytes requested across every allocation
+    pub allocated_bytes: u64,
+    /// Most heap memory held at one time
+    pub peak_heap_bytes: u64,
+}
+
+impl AllocatorStats {
+    /// Counts so far, or `None` when no `CountingAllocator` is installed.
+    pub fn current() -> Option<AllocatorStats> {
+        match ALLOCATIONS.load(Ordering::Relaxed) {
+            0 => None,
+            allocations => Some(AllocatorStats {
+                allocations,
+                allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+                peak_heap_bytes: PEAK_HEAP_BYTES.load(Ordering::Relaxed),
+            }),
+        }
+    }
+}
+
+/// Memory taken by the run so far, for the run summary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct MemoryUsage {
+    /// Most resident memory held by the process at one time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_rss_bytes: Option<u64>,
+    /// Allocations counted, when a `CountingAllocator` is installed
+    #[serde(flatten)]
+    pub allocator: Option<AllocatorStats>,
+}
+
+impl MemoryUsage {
+    pub fn current() -> MemoryUsage {
+        MemoryUsage {
+            peak_rss_bytes: peak_resident_memory(),
+            allocator: AllocatorStats::current(),
+        }
+    }
+}
+
+//region: Private functions
+
+/// Peak resident memory of the process, as reported by the kernel.
+fn peak_resident_memory() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    match unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } {
+        0 => {
+            let max_rss = unsafe { usage.assume_init() }.ru_maxrss as u64;
+            // Linux reports the peak in KiB, where macOS reports it in bytes
+            match cfg!(target_os = "macos") {
+                true => Some(max_rss),
+                false => Some(max_rss * 1024),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Count an allocation, along with the block it replaces when reallocating. Both are counted as
+/// held at the peak, as a moved block is copied before the old one is freed.
+fn record_allocation(requested_bytes: u64, replaced_bytes: u64) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(requested_bytes, Ordering::Relaxed);
+    let heap_bytes = HEAP_BYTES.fetch_add(requested_bytes, Ordering::Relaxed) + requested_bytes;
+    PEAK_HEAP_BYTES.fetch_max(heap_bytes, Ordering::Relaxed);
+    HEAP_BYTES.fetch_sub(replaced_bytes, Ordering::Relaxed);
+}
+
+//endregion:
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            record_allocation(layout.size() as u64, 0);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            record_allocation(layout.size() as u64, 0);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) };
+        HEAP_BYTES.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            record_allocation(new_size as u64, layout.size() as u64);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::alloc::System;
+
+    #[test]
+    fn test_counting_allocator() {
+        // The test binary keeps the system allocator, so only these calls are counted
+        let allocator = CountingAllocator::new(System);
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            let ptr = allocator.realloc(ptr, layout, 4096);
+            allocator.dealloc(ptr, Layout::from_size_align(4096, 8).unwrap());
+        }
+
+        let obs_stats = AllocatorStats::current().unwrap();
+        assert_eq!(2, obs_stats.allocations);
+        assert_eq!(1024 + 4096, obs_stats.allocated_bytes);
+        assert_eq!(1024 + 4096, obs_stats.peak_heap_bytes);
+        assert_eq!(0, HEAP_BYTES.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let obs_usage = MemoryUsage::current();
+        assert!(obs_usage.peak_rss_bytes.is_some_and(|b| b > 0));
+
+        let obs_json = serde_json::to_value(MemoryUsage {
+            peak_rss_bytes: Some(4096),
+            allocator: None,
+        })
+        .unwrap();
+        assert_eq!(serde_json::json!({ "peak_rss_bytes": 4096 }), obs_json);
+    }
+}
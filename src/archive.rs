@@ -0,0 +1,824 @@
+use crate::frame_cache::{archive_key, decode_cached};
+use crate::join::is_key_sorted;
+use crate::source::FrameSource;
+use crate::{
+    load_index, open_source, ErrorClass, FrameMeta, KeyRange, ParseOptions, PipelineError,
+};
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+// Decoded frames held in memory by default before the least recently read are dropped
+const DEFAULT_RESIDENT_BYTES: u64 = 1 << 30;
+
+/// Urgency of a frame request. Interactive requests are decoded before any other request still
+/// in the queue, and frames prefetched for a sequential reader before any warming request.
+/// Requests of the same priority are decoded in the order made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FramePriority {
+    Warming,
+    Prefetch,
+    Interactive,
+}
+
+/// Place of a decoded frame in the order frames are dropped in: frames a sequential reader has
+/// passed go first, then the rest by the tick of their last use.
+type Recency = (bool, u64);
+
+enum FrameSlot {
+    Queued(FramePriority),
+    Decoding,
+    Ready(Arc<Vec<u8>>, Recency),
+    Failed(ErrorClass, String),
+}
+
+#[derive(Default)]
+struct DecodeQueue {
+    requests: BinaryHeap<(FramePriority, Reverse<u64>, u64)>,
+    slots: HashMap<u64, FrameSlot>,
+    next_ticket: u64,
+    /// Frames held decoded, in the order they are dropped in, and the bytes they hold between
+    /// them
+    recently_used: BTreeMap<Recency, u64>,
+    resident_bytes: u64,
+    resident_limit: u64,
+    /// Place in archive order of the frame last asked for, and whether it followed the one
+    /// before it
+    last_read: Option<usize>,
+    sequential: bool,
+    shutdown: bool,
+}
+
+struct ArchiveState {
+    source: Box<dyn FrameSource>,
+    frames: HashMap<u64, FrameMeta>,
+    /// Frame orders in archive order
+    orders: Vec<u64>,
+    /// Identity of the archive in any frame cache of the parse options
+    archive_key: u64,
+    /// Key range of each frame in archive order, when the index records the archive as sorted
+    key_ranges: Option<Vec<(KeyRange, u64)>>,
+    parse_options: ParseOptions,
+    queue: Mutex<DecodeQueue>,
+    changed: Condvar,
+}
+
+/// An archive whose frames are only decoded when asked for. Frames are decoded by a pool of
+/// background threads and kept once decoded, so a caller can warm the archive while still
+/// reading single frames ahead of the warming pass. Once the decoded frames exceed the resident
+/// limit, those read least recently are dropped, and decoded again if asked for. Given a frame
+/// cache in the parse options, decoded frames are also kept on disk for later runs.
+pub struct IndexedArchive {
+    state: Arc<ArchiveState>,
+    workers: Vec<JoinHandle<()>>,
+    /// Frames decoded ahead of a reader working through the archive in order
+    prefetch: usize,
+}
+
+impl std::fmt::Debug for IndexedArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("IndexedArchive")
+            .field("source", &self.state.source.name())
+            .field("frames", &self.state.frames.len())
+            .field("workers", &self.workers.len())
+            .field("prefetch", &self.prefetch)
+            .finish()
+    }
+}
+
+//region: Private functions
+
+fn enqueue(queue: &mut DecodeQueue, orders: &[u64], priority: FramePriority) {
+    for &order in orders {
+        let queued = match queue.slots.get(&order) {
+            None => true,
+            Some(FrameSlot::Queued(p)) => *p < priority,
+            Some(_) => false,
+        };
+
+        if queued {
+            let ticket = queue.next_ticket;
+            queue.next_ticket += 1;
+            queue.requests.push((priority, Reverse(ticket), order));
+            queue.slots.insert(order, FrameSlot::Queued(priority));
+        }
+    }
+}
+
+/// Note a read of the frame at a place in archive order, returning whether the reader is working
+/// through the archive in order. Repeated reads of one frame, as a reader taking a frame in
+/// several pieces makes, leave the pattern as it was.
+fn note_read(queue: &mut DecodeQueue, position: usize) -> bool {
+    queue.sequential = match queue.last_read {
+        Some(last) if position == last => queue.sequential,
+        Some(last) => position == last + 1,
+        None => false,
+    };
+    queue.last_read = Some(position);
+    queue.sequential
+}
+
+/// Mark a use of a decoded frame now, moving it to the back of the eviction order.
+fn touch(queue: &mut DecodeQueue, order: u64) {
+    let recency = (true, queue.next_ticket);
+    if let Some(FrameSlot::Ready(_, last_used)) = queue.slots.get_mut(&order) {
+        queue.recently_used.remove(last_used);
+        queue.recently_used.insert(recency, order);
+        *last_used = recency;
+        queue.next_ticket += 1;
+    }
+}
+
+/// Move a decoded frame which a sequential reader has moved past to the front of the eviction
+/// order, returning its length. It stays held until room is needed, in case it is read again.
+fn pass(queue: &mut DecodeQueue, order: u64) -> Option<u64> {
+    let Some(FrameSlot::Ready(payload, last_used)) = queue.slots.get_mut(&order) else {
+        return None;
+    };
+    queue.recently_used.remove(last_used);
+    last_used.0 = false;
+    queue.recently_used.insert(*last_used, order);
+    Some(payload.len() as u64)
+}
+
+/// Hold a decoded frame, dropping the frames read least recently while the decoded frames exceed
+/// the resident limit. The frame just decoded is always kept, so that it reaches its reader.
+fn store_ready(queue: &mut DecodeQueue, order: u64, payload: Arc<Vec<u8>>) {
+    let recency = (true, queue.next_ticket);
+    queue.next_ticket += 1;
+    queue.resident_bytes += payload.len() as u64;
+    queue.recently_used.insert(recency, order);
+    queue
+        .slots
+        .insert(order, FrameSlot::Ready(payload, recency));
+
+    while queue.resident_bytes > queue.resident_limit {
+        let Some((&oldest, &evicted)) = queue.recently_used.first_key_value() else {
+            break;
+        };
+        if evicted == order {
+            break;
+        }
+        queue.recently_used.remove(&oldest);
+        if let Some(FrameSlot::Ready(payload, _)) = queue.slots.remove(&evicted) {
+            queue.resident_bytes -= payload.len() as u64;
+        }
+    }
+}
+
+fn take_request(queue: &mut DecodeQueue) -> Option<u64> {
+    // A frame raised to interactive leaves its warming request behind, so requests for frames
+    // which are no longer queued are skipped
+    while let Some((_, _, order)) = queue.requests.pop() {
+        if let Some(slot) = queue.slots.get_mut(&order)
+            && matches!(slot, FrameSlot::Queued(_))
+        {
+            *slot = FrameSlot::Decoding;
+            return Some(order);
+        }
+    }
+    None
+}
+
+fn decode_worker(state: &ArchiveState) {
+    loop {
+        let order = {
+            let mut queue = state.queue.lock().unwrap();
+            loop {
+                if queue.shutdown {
+                    return;
+                }
+                match take_request(&mut queue) {
+                    Some(order) => break order,
+                    None => queue = state.changed.wait(queue).unwrap(),
+                }
+            }
+        };
+
+        let decoded = decode_cached(
+            state.source.as_ref(),
+            &state.frames[&order],
+            state.archive_key,
+            &state.parse_options,
+        );
+
+        let mut queue = state.queue.lock().unwrap();
+        match decoded {
+            Ok(payload) => store_ready(&mut queue, order, Arc::new(payload)),
+            Err(e) => {
+                queue
+                    .slots
+                    .insert(order, FrameSlot::Failed(ErrorClass::of(&e), e.to_string()));
+            }
+        }
+        state.changed.notify_all();
+    }
+}
+
+fn rebuild_error(error_class: ErrorClass, message: &str) -> anyhow::Error {
+    match error_class {
+        ErrorClass::Usage => PipelineError::Usage(message.to_string()).into(),
+        ErrorClass::CorruptArchive => PipelineError::CorruptArchive(message.to_string()).into(),
+        ErrorClass::Io => std::io::Error::other(message.to_string()).into(),
+        ErrorClass::Other => anyhow!("{}", message),
+    }
+}
+
+//endregion:
+
+impl IndexedArchive {
+    /// Open an archive from its index (or by scanning, if there is none) without decoding any
+    /// frames, and start the threads which decode them on request.
+    pub fn open(
+        zstd_file: &str,
+        idx_file: Option<&str>,
+        num_threads: usize,
+        parse_options: &ParseOptions,
+    ) -> Result<IndexedArchive> {
+        let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+        IndexedArchive::start(
+            open_source(zstd_file)?,
+            idx_buffer,
+            num_threads,
+            parse_options,
+        )
+    }
+
+    /// Open an archive read through any frame source, such as a memory map or a buffer, from
+    /// its frame index.
+    pub fn from_source(
+        source: Box<dyn FrameSource>,
+        idx_buffer: Vec<FrameMeta>,
+        num_threads: usize,
+        parse_options: &ParseOptions,
+    ) -> Result<IndexedArchive> {
+        let mut parse_options = parse_options.clone();
+        if let Some(read_batcher) = &parse_options.read_batcher {
+            parse_options.read_batcher = Some(Arc::new(read_batcher.with_plan(&idx_buffer)));
+        }
+        IndexedArchive::start(source, idx_buffer, num_threads, parse_options)
+    }
+
+    fn start(
+        source: Box<dyn FrameSource>,
+        mut idx_buffer: Vec<FrameMeta>,
+        num_threads: usize,
+        parse_options: ParseOptions,
+    ) -> Result<IndexedArchive> {
+        idx_buffer.sort_by_key(|f| f.order);
+        let orders: Vec<u64> = idx_buffer.iter().map(|f| f.order).collect();
+
+        let key_ranges = match is_key_sorted(&idx_buffer) {
+            true => Some(
+                idx_buffer
+                    .iter()
+                    .filter_map(|f| Some((f.key_range.clone()?, f.order)))
+                    .collect(),
+            ),
+            false => None,
+        };
+        let state = Arc::new(ArchiveState {
+            source,
+            archive_key: archive_key(&idx_buffer),
+            frames: idx_buffer.into_iter().map(|f| (f.order, f)).collect(),
+            orders,
+            key_ranges,
+            parse_options,
+            queue: Mutex::new(DecodeQueue {
+                resident_limit: DEFAULT_RESIDENT_BYTES,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+        });
+
+        let mut workers: Vec<JoinHandle<()>> = Vec::new();
+        for i in 0..num_threads.max(1) {
+            let worker_state = Arc::clone(&state);
+            let thread_options = &worker_state.parse_options.threads;
+            let handle = std::thread::Builder::new()
+                .name(thread_options.thread_name(&format!("archive-decoder-{}", i)))
+                .spawn(move || {
+                    worker_state.parse_options.threads.apply_current();
+                    decode_worker(&worker_state)
+                })?;
+            workers.push(handle);
+        }
+
+        let prefetch = workers.len();
+        Ok(IndexedArchive {
+            state,
+            workers,
+            prefetch,
+        })
+    }
+
+    /// Set how many frames are decoded ahead of a reader once it is seen to read frames in
+    /// archive order, one for each decoding thread by default. Zero turns prefetching off.
+    pub fn with_prefetch(mut self, frames: usize) -> IndexedArchive {
+        self.prefetch = frames;
+        self
+    }
+
+    /// Set how many bytes of decoded frames are held in memory before those read least recently
+    /// are dropped, 1GiB by default.
+    pub fn with_resident_limit(self, bytes: u64) -> IndexedArchive {
+        self.state.queue.lock().unwrap().resident_limit = bytes;
+        self
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.state.frames.len()
+    }
+
+    /// Whether the index records the archive as compressed from key-sorted input.
+    pub fn is_key_sorted(&self) -> bool {
+        self.state.key_ranges.is_some()
+    }
+
+    /// Find the frames which may hold records of a key, by binary search over the key ranges of
+    /// a sorted archive. Repeated keys may run across the end of one frame into the next.
+    pub fn frames_for_key(&self, key: &str) -> Result<Vec<u64>> {
+        let Some(key_ranges) = &self.state.key_ranges else {
+            bail!(PipelineError::Usage(format!(
+                "The index of '{}' does not record the archive as key-sorted!",
+                self.state.source.name()
+            )));
+        };
+
+        let start = key_ranges.partition_point(|(r, _)| r.last.as_str() < key);
+        Ok(key_ranges[start..]
+            .iter()
+            .take_while(|(r, _)| r.first.as_str() <= key)
+            .map(|(_, order)| *order)
+            .collect())
+    }
+
+    /// Queue frames for decoding without waiting on them. Frames which are already decoded, or
+    /// queued at the same or a higher priority, are left as they are.
+    pub fn request(&self, orders: &[u64], priority: FramePriority) -> Result<()> {
+        if let Some(order) = orders.iter().find(|o| !self.state.frames.contains_key(o)) {
+            return Err(PipelineError::Usage(format!(
+                "Frame {} is not in the archive index!",
+                order
+            ))
+            .into());
+        }
+
+        let mut queue = self.state.queue.lock().unwrap();
+        enqueue(&mut queue, orders, priority);
+        self.state.changed.notify_all();
+        Ok(())
+    }
+
+    /// Queue every frame of the archive at warming priority, in archive order.
+    pub fn warm(&self) -> Result<()> {
+        let mut orders: Vec<u64> = self.state.frames.keys().copied().collect();
+        orders.sort();
+        self.request(&orders, FramePriority::Warming)
+    }
+
+    /// Return the decoded content of a frame, decoding it ahead of any other requests if it is
+    /// not yet available. A failure is returned once and then forgotten, so that the next request
+    /// for the frame tries it again. Once a frame is asked for straight after the one before it in
+    /// archive order, the frame before it is the first to be dropped when room is needed, and the
+    /// frames after it are queued for prefetching, so that a reader working through the archive
+    /// finds them decoded. Only as many frames are prefetched as fit within the resident limit
+    /// beside the one being read, judged by the length of the frame passed.
+    pub fn frame(&self, order: u64) -> Result<Arc<Vec<u8>>> {
+        let Ok(position) = self.state.orders.binary_search(&order) else {
+            bail!(PipelineError::Usage(format!(
+                "Frame {} is not in the archive index!",
+                order
+            )));
+        };
+
+        let mut queue = self.state.queue.lock().unwrap();
+        enqueue(&mut queue, &[order], FramePriority::Interactive);
+        if note_read(&mut queue, position) {
+            let fits = match pass(&mut queue, self.state.orders[position - 1]) {
+                Some(frame_bytes) => {
+                    (queue.resident_limit / frame_bytes.max(1)).saturating_sub(1) as usize
+                }
+                None => self.prefetch,
+            };
+            let ahead = self
+                .state
+                .orders
+                .iter()
+                .skip(position + 1)
+                .take(self.prefetch.min(fits));
+            enqueue(
+                &mut queue,
+                &ahead.copied().collect::<Vec<u64>>(),
+                FramePriority::Prefetch,
+            );
+        }
+        self.state.changed.notify_all();
+
+        loop {
+            match queue.slots.get(&order) {
+                Some(FrameSlot::Ready(payload, _)) => {
+                    let payload = Arc::clone(payload);
+                    touch(&mut queue, order);
+                    return Ok(payload);
+                }
+                Some(FrameSlot::Failed(..)) => {
+                    if let Some(FrameSlot::Failed(error_class, message)) =
+                        queue.slots.remove(&order)
+                    {
+                        return Err(rebuild_error(error_class, &message));
+                    }
+                }
+                // Dropped while this reader waited, by eviction or by another reader taking its
+                // failure, so it is asked for again
+                None => {
+                    enqueue(&mut queue, &[order], FramePriority::Interactive);
+                    self.state.changed.notify_all();
+                    queue = self.state.changed.wait(queue).unwrap();
+                }
+                _ => queue = self.state.changed.wait(queue).unwrap(),
+            }
+        }
+    }
+
+    /// Number of frames decoded and held in memory.
+    pub fn frames_decoded(&self) -> usize {
+        let queue = self.state.queue.lock().unwrap();
+        queue
+            .slots
+            .values()
+            .filter(|s| matches!(s, FrameSlot::Ready(..)))
+            .count()
+    }
+}
+
+impl Drop for IndexedArchive {
+    fn drop(&mut self) {
+        {
+            let mut queue = self.state.queue.lock().unwrap();
+            queue.shutdown = true;
+            self.state.changed.notify_all();
+        }
+
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_indexed_archive_frame() {
+        let archive = IndexedArchive::open(
+            "test/example.zstd",
+            Some("test/example.zstd.idx"),
+            2,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(3, archive.frame_count());
+        assert_eq!(0, archive.frames_decoded());
+
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        let obs_frame = archive.frame(1).unwrap();
+        assert_eq!(&exp_content[220..424], &obs_frame[..]);
+        assert_eq!(1, archive.frames_decoded());
+    }
+
+    #[test]
+    fn test_indexed_archive_from_source() {
+        let idx_buffer = crate::decompression::load_frame_index(&mut std::io::BufReader::new(
+            std::fs::File::open("test/example.zstd.idx").unwrap(),
+        ))
+        .unwrap();
+        let source =
+            crate::MemorySource::new("example", std::fs::read("test/example.zstd").unwrap());
+
+        let archive =
+            IndexedArchive::from_source(Box::new(source), idx_buffer, 2, &ParseOptions::default())
+                .unwrap();
+
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        assert_eq!(&exp_content[..220], &archive.frame(0).unwrap()[..]);
+    }
+
+    #[test]
+    fn test_indexed_archive_frame_cache() {
+        let cache_dir = "indexed_archive_frame_cache";
+        let idx_buffer = crate::decompression::load_frame_index(
+            std::fs::File::open("test/example.zstd.idx").unwrap(),
+        )
+        .unwrap();
+        let payload = std::fs::read("test/example.zstd").unwrap();
+        let parse_options = ParseOptions {
+            frame_cache: Some(Arc::new(
+                crate::FrameCache::open(cache_dir, 1 << 20).unwrap(),
+            )),
+            ..Default::default()
+        };
+
+        let archive = IndexedArchive::from_source(
+            Box::new(crate::MemorySource::new("example", payload.clone())),
+            idx_buffer.clone(),
+            1,
+            &parse_options,
+        )
+        .unwrap();
+        let exp_frame = archive.frame(1).unwrap();
+        drop(archive);
+
+        // Once cached, the frame is read back rather than decoded, so is found even when the
+        // archive can no longer be read
+        let archive = IndexedArchive::from_source(
+            Box::new(crate::MemorySource::new("example", vec![0; payload.len()])),
+            idx_buffer,
+            1,
+            &parse_options,
+        )
+        .unwrap();
+        let obs_frame = archive.frame(1);
+        let obs_uncached = archive.frame(0);
+        let _ = std::fs::remove_dir_all(cache_dir);
+
+        assert_eq!(exp_frame, obs_frame.unwrap());
+        assert!(obs_uncached.is_err());
+        let frame_cache = parse_options.frame_cache.as_ref().unwrap();
+        assert!(frame_cache.hits() >= 1);
+        assert!(frame_cache.misses() >= 2);
+    }
+
+    #[test]
+    fn test_indexed_archive_frame_missing() {
+        let archive = IndexedArchive::open(
+            "test/example.zstd",
+            Some("test/example.zstd.idx"),
+            1,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        let obs_error = archive.frame(3).unwrap_err();
+        assert_eq!(ErrorClass::Usage, ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_indexed_archive_warm() {
+        let archive = IndexedArchive::open(
+            "test/example.zstd",
+            Some("test/example.zstd.idx"),
+            2,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        archive.warm().unwrap();
+
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        let mut obs_content: Vec<u8> = Vec::new();
+        for order in 0..3 {
+            obs_content.extend(archive.frame(order).unwrap().iter());
+        }
+        assert_eq!(exp_content, obs_content);
+        assert_eq!(3, archive.frames_decoded());
+    }
+
+    #[test]
+    fn test_indexed_archive_frames_for_key() {
+        let zstd_file = "indexed_archive_frames_for_key.zstd";
+        let idx_file = "indexed_archive_frames_for_key.zstd.idx";
+        let compression_options = crate::CompressionOptions {
+            assume_sorted: true,
+            ..Default::default()
+        };
+        crate::compression::write_indexed_zstd(
+            "a\t1\nb\t2\nb\t3\nd\t4\n".as_bytes(),
+            std::fs::File::create(zstd_file).unwrap(),
+            std::io::BufWriter::new(std::fs::File::create(idx_file).unwrap()),
+            8,
+            3,
+            &compression_options,
+        )
+        .unwrap();
+
+        let archive =
+            IndexedArchive::open(zstd_file, Some(idx_file), 1, &ParseOptions::default()).unwrap();
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(idx_file);
+
+        assert!(archive.is_key_sorted());
+        assert_eq!(vec![0], archive.frames_for_key("a").unwrap());
+        assert_eq!(vec![0, 1], archive.frames_for_key("b").unwrap());
+        assert_eq!(vec![1], archive.frames_for_key("c").unwrap());
+        assert!(archive.frames_for_key("e").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_indexed_archive_frames_for_key_unsorted() {
+        let archive = IndexedArchive::open(
+            "test/example.zstd",
+            Some("test/example.zstd.idx"),
+            1,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        assert!(!archive.is_key_sorted());
+        let obs_error = archive.frames_for_key("WP_413685322.1").unwrap_err();
+        assert_eq!(ErrorClass::Usage, ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_take_request_priority() {
+        let mut queue = DecodeQueue::default();
+        enqueue(&mut queue, &[0, 1, 2, 3], FramePriority::Warming);
+        enqueue(&mut queue, &[3], FramePriority::Prefetch);
+        enqueue(&mut queue, &[2, 1], FramePriority::Interactive);
+
+        // Repeated requests at the same priority do not queue a frame twice
+        enqueue(&mut queue, &[1], FramePriority::Interactive);
+
+        let obs_orders: Vec<u64> = std::iter::from_fn(|| take_request(&mut queue)).collect();
+        assert_eq!(vec![2, 1, 3, 0], obs_orders);
+    }
+
+    #[test]
+    fn test_note_read() {
+        let mut queue = DecodeQueue::default();
+        let obs_sequential: Vec<bool> = [0, 1, 1, 2, 5, 5, 6]
+            .iter()
+            .map(|p| note_read(&mut queue, *p))
+            .collect();
+        assert_eq!(
+            vec![false, true, true, true, false, false, true],
+            obs_sequential
+        );
+    }
+
+    #[test]
+    fn test_indexed_archive_prefetch() {
+        let open_archive = || {
+            IndexedArchive::open(
+                "test/example.zstd",
+                Some("test/example.zstd.idx"),
+                1,
+                &ParseOptions::default(),
+            )
+            .unwrap()
+        };
+        let is_requested = |archive: &IndexedArchive, order: u64| {
+            let queue = archive.state.queue.lock().unwrap();
+            queue.slots.contains_key(&order)
+        };
+
+        // Reading frames 0 then 1 queues the frame after them, but a single read does not
+        let archive = open_archive();
+        archive.frame(0).unwrap();
+        assert!(!is_requested(&archive, 1));
+        archive.frame(1).unwrap();
+        assert!(is_requested(&archive, 2));
+
+        let archive = open_archive().with_prefetch(0);
+        archive.frame(0).unwrap();
+        archive.frame(1).unwrap();
+        assert!(!is_requested(&archive, 2));
+
+        // Nor is a frame prefetched when it would not fit beside the one being read
+        let archive = open_archive().with_resident_limit(400);
+        archive.frame(0).unwrap();
+        archive.frame(1).unwrap();
+        assert!(!is_requested(&archive, 2));
+    }
+
+    #[test]
+    fn test_store_ready_evict() {
+        let mut queue = DecodeQueue {
+            resident_limit: 10,
+            ..Default::default()
+        };
+        store_ready(&mut queue, 0, Arc::new(vec![0; 4]));
+        store_ready(&mut queue, 1, Arc::new(vec![1; 4]));
+        touch(&mut queue, 0);
+
+        // Frame 1 was read least recently, so goes first
+        store_ready(&mut queue, 2, Arc::new(vec![2; 4]));
+        let mut obs_held: Vec<u64> = queue.slots.keys().copied().collect();
+        obs_held.sort();
+        assert_eq!(vec![0, 2], obs_held);
+        assert_eq!(8, queue.resident_bytes);
+
+        // A frame larger than the limit is still held, alone
+        store_ready(&mut queue, 3, Arc::new(vec![3; 12]));
+        assert_eq!(vec![&3], queue.slots.keys().collect::<Vec<&u64>>());
+        assert_eq!(12, queue.resident_bytes);
+    }
+
+    #[test]
+    fn test_pass() {
+        let mut queue = DecodeQueue {
+            resident_limit: 10,
+            ..Default::default()
+        };
+        store_ready(&mut queue, 0, Arc::new(vec![0; 4]));
+        store_ready(&mut queue, 1, Arc::new(vec![1; 4]));
+
+        // Frame 1 has been passed by a sequential reader, so goes before the older frame 0
+        assert_eq!(Some(4), pass(&mut queue, 1));
+        assert_eq!(None, pass(&mut queue, 5));
+        store_ready(&mut queue, 2, Arc::new(vec![2; 4]));
+        let mut obs_held: Vec<u64> = queue.slots.keys().copied().collect();
+        obs_held.sort();
+        assert_eq!(vec![0, 2], obs_held);
+    }
+
+    #[test]
+    fn test_indexed_archive_resident_limit() {
+        let archive = IndexedArchive::open(
+            "test/example.zstd",
+            Some("test/example.zstd.idx"),
+            1,
+            &ParseOptions::default(),
+        )
+        .unwrap()
+        .with_prefetch(0)
+        .with_resident_limit(300);
+
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        let mut obs_content: Vec<u8> = Vec::new();
+        for order in [0, 1, 2] {
+            obs_content.extend(archive.frame(order).unwrap().iter());
+        }
+        assert_eq!(exp_content, obs_content);
+        assert_eq!(1, archive.frames_decoded());
+
+        // A dropped frame is decoded again when asked for
+        assert_eq!(&exp_content[..220], &archive.frame(0).unwrap()[..]);
+    }
+
+    /// A source which fails every read while told to.
+    struct FlakySource {
+        payload: Vec<u8>,
+        failing: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl FrameSource for FlakySource {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn read_at(&self, position: u64, length: usize) -> std::io::Result<bytes::Bytes> {
+            if self.failing.load(std::sync::atomic::Ordering::Relaxed) {
+                return Err(std::io::Error::from(std::io::ErrorKind::TimedOut));
+            }
+            let start = position as usize;
+            Ok(bytes::Bytes::copy_from_slice(
+                &self.payload[start..start + length],
+            ))
+        }
+    }
+
+    #[test]
+    fn test_indexed_archive_frame_retry() {
+        let idx_buffer = crate::decompression::load_frame_index(
+            std::fs::File::open("test/example.zstd.idx").unwrap(),
+        )
+        .unwrap();
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let source = FlakySource {
+            payload: std::fs::read("test/example.zstd").unwrap(),
+            failing: Arc::clone(&failing),
+        };
+        let parse_options = ParseOptions {
+            retry: crate::RetryPolicy {
+                retries: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let archive =
+            IndexedArchive::from_source(Box::new(source), idx_buffer, 1, &parse_options).unwrap();
+
+        // A failure is not kept, so the frame is read afresh once the source recovers
+        let obs_error = archive.frame(0).unwrap_err();
+        assert_eq!(ErrorClass::Io, ErrorClass::of(&obs_error));
+        failing.store(false, std::sync::atomic::Ordering::Relaxed);
+
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        assert_eq!(&exp_content[..220], &archive.frame(0).unwrap()[..]);
+    }
+
+    #[test]
+    fn test_take_request_decoded() {
+        let mut queue = DecodeQueue::default();
+        store_ready(&mut queue, 0, Arc::new(b"a\t1\n".to_vec()));
+        enqueue(&mut queue, &[0, 1], FramePriority::Interactive);
+
+        assert_eq!(Some(1), take_request(&mut queue));
+        assert_eq!(None, take_request(&mut queue));
+    }
+}
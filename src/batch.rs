@@ -0,0 +1,233 @@
+use crate::decompression::read_at;
+use crate::source::FrameSource;
+use crate::{FrameMeta, ParseOptions};
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+enum BatchState {
+    Unread,
+    Read(Bytes),
+    Failed(std::io::ErrorKind, String),
+    Released,
+}
+
+/// A run of neighbouring frames read with a single call. The buffer is released once every
+/// frame in the batch has taken its bytes.
+struct ReadBatch {
+    position: u64,
+    length: usize,
+    state: Mutex<(BatchState, usize)>,
+}
+
+/// Coalesces the reads of frames which lie close together in the file, so that one read covers
+/// several frames. The first worker to ask for a frame reads its whole batch, and the workers
+/// decoding the other frames of the batch take their bytes from the shared buffer.
+#[derive(Default)]
+pub struct ReadBatcher {
+    batch_size: usize,
+    batch_of: HashMap<u64, usize>,
+    batches: Vec<ReadBatch>,
+}
+
+impl std::fmt::Debug for ReadBatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ReadBatcher")
+            .field("batch_size", &self.batch_size)
+            .field("batches", &self.batches.len())
+            .finish()
+    }
+}
+
+impl ReadBatcher {
+    /// Create a batcher for reads of up to the given number of bytes. Until it is planned
+    /// against a frame index, every frame is read on its own.
+    pub fn new(batch_size: usize) -> ReadBatcher {
+        ReadBatcher {
+            batch_size,
+            ..Default::default()
+        }
+    }
+
+    /// Group the frames into batches by position. A frame joins the current batch while the
+    /// span from the start of the batch to the end of the frame fits within the batch size, so
+    /// small gaps between frames (such as frame metadata or padding) are read and discarded.
+    pub(crate) fn with_plan(&self, idx_buffer: &[FrameMeta]) -> ReadBatcher {
+        let mut frames: Vec<&FrameMeta> = idx_buffer.iter().collect();
+        frames.sort_by_key(|f| f.position);
+
+        let mut batch_of: HashMap<u64, usize> = HashMap::with_capacity(frames.len());
+        let mut spans: Vec<(u64, u64)> = Vec::new();
+
+        for frame in frames {
+            let frame_end = frame.position + frame.length;
+            match spans.last_mut() {
+                Some((start, end))
+                    if frame.position >= *end && frame_end - *start <= self.batch_size as u64 =>
+                {
+                    *end = frame_end;
+                }
+                _ => spans.push((frame.position, frame_end)),
+            }
+            batch_of.insert(frame.order, spans.len() - 1);
+        }
+
+        let mut frame_counts = vec![0usize; spans.len()];
+        for batch in batch_of.values() {
+            frame_counts[*batch] += 1;
+        }
+
+        let batches = spans
+            .into_iter()
+            .zip(frame_counts)
+            .map(|((start, end), frame_count)| ReadBatch {
+                position: start,
+                length: (end - start) as usize,
+                state: Mutex::new((BatchState::Unread, frame_count)),
+            })
+            .collect();
+
+        ReadBatcher {
+            batch_size: self.batch_size,
+            batch_of,
+            batches,
+        }
+    }
+
+    /// Return the compressed bytes of the frame, reading its batch if no other worker has yet.
+    pub(crate) fn frame_bytes(
+        &self,
+        source: &dyn FrameSource,
+        idx_frame: &FrameMeta,
+        parse_options: &ParseOptions,
+    ) -> Result<Bytes> {
+        let frame_length = idx_frame.parse_length()?;
+        let batch = match self.batch_of.get(&idx_frame.order) {
+            Some(b) => &self.batches[*b],
+            None => {
+                return read_at(
+                    source,
+                    idx_frame.position,
+                    frame_length,
+                    idx_frame.order,
+                    parse_options,
+                );
+            }
+        };
+
+        // Other frames of the batch wait on the lock while it is read, as they need it too
+        let mut guard = batch.state.lock().unwrap();
+        let (state, frames_remaining) = &mut *guard;
+
+        if let BatchState::Unread = state {
+            *state = match read_at(
+                source,
+                batch.position,
+                batch.length,
+                idx_frame.order,
+                parse_options,
+            ) {
+                Ok(buffer) => BatchState::Read(buffer),
+                Err(e) => match e.downcast_ref::<std::io::Error>() {
+                    Some(io_error) => BatchState::Failed(io_error.kind(), io_error.to_string()),
+                    None => BatchState::Failed(std::io::ErrorKind::Other, e.to_string()),
+                },
+            };
+        }
+
+        *frames_remaining = frames_remaining.saturating_sub(1);
+        let result = match state {
+            BatchState::Read(buffer) => {
+                let offset = (idx_frame.position - batch.position) as usize;
+                Ok(buffer.slice(offset..offset + frame_length))
+            }
+            BatchState::Failed(kind, message) => {
+                Err(std::io::Error::new(*kind, message.clone()).into())
+            }
+            BatchState::Unread | BatchState::Released => {
+                bail!(
+                    "The read batch for frame {} was already released!",
+                    idx_frame.order
+                )
+            }
+        };
+
+        if *frames_remaining == 0 && matches!(state, BatchState::Read(_)) {
+            *state = BatchState::Released;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::source::FileSource;
+
+    #[test]
+    fn test_read_batcher_plan() {
+        // Two adjacent frames, a small gap, then a frame too large to share a batch
+        let idx_buffer = vec![
+            FrameMeta::new(100, 50, 1),
+            FrameMeta::new(0, 100, 0),
+            FrameMeta::new(160, 40, 2),
+            FrameMeta::new(200, 300, 3),
+        ];
+
+        let read_batcher = ReadBatcher::new(256).with_plan(&idx_buffer);
+        let obs_spans: Vec<(u64, usize)> = read_batcher
+            .batches
+            .iter()
+            .map(|b| (b.position, b.length))
+            .collect();
+
+        assert_eq!(vec![(0, 200), (200, 300)], obs_spans);
+        assert_eq!(Some(&0), read_batcher.batch_of.get(&2));
+        assert_eq!(Some(&1), read_batcher.batch_of.get(&3));
+    }
+
+    #[test]
+    fn test_read_batcher_frame_bytes() {
+        let zstd_file = "test/example.zstd";
+        let idx_buffer = vec![
+            FrameMeta::new(0, 151, 0),
+            FrameMeta::new(151, 150, 1),
+            FrameMeta::new(301, 120, 2),
+        ];
+        let exp_payload = std::fs::read(zstd_file).unwrap();
+
+        let source = FileSource::new(zstd_file);
+        let read_batcher = ReadBatcher::new(1024).with_plan(&idx_buffer);
+        assert_eq!(1, read_batcher.batches.len());
+
+        for idx_frame in [&idx_buffer[1], &idx_buffer[0], &idx_buffer[2]] {
+            let obs_bytes = read_batcher
+                .frame_bytes(&source, idx_frame, &ParseOptions::default())
+                .unwrap();
+            let frame_start = idx_frame.position as usize;
+            assert_eq!(
+                &exp_payload[frame_start..frame_start + idx_frame.length as usize],
+                &obs_bytes[..]
+            );
+        }
+
+        // The shared buffer is dropped once every frame has been handed out
+        let guard = read_batcher.batches[0].state.lock().unwrap();
+        assert!(matches!(guard.0, BatchState::Released));
+    }
+
+    #[test]
+    fn test_read_batcher_failed() {
+        let idx_buffer = vec![FrameMeta::new(0, 151, 0), FrameMeta::new(151, 150, 1)];
+        let source = FileSource::new("missing.zstd");
+        let read_batcher = ReadBatcher::new(1024).with_plan(&idx_buffer);
+
+        // Every frame of a batch which cannot be read fails in the same way
+        for idx_frame in &idx_buffer {
+            let obs_result = read_batcher.frame_bytes(&source, idx_frame, &ParseOptions::default());
+            assert!(obs_result.unwrap_err().is::<std::io::Error>());
+        }
+    }
+}
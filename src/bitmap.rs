@@ -0,0 +1,331 @@
+use anyhow::{bail, Result};
+
+// A container switches from a sorted array to a bitmap once it holds more values than fit in
+// the 8KiB the bitmap takes
+const ARRAY_LIMIT: usize = 4096;
+const BITMAP_WORDS: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Container {
+    Array(Vec<u16>),
+    Bitmap(Box<[u64; BITMAP_WORDS]>),
+}
+
+/// A compressed set of 32-bit integers in the roaring layout. Values are split by their upper
+/// 16 bits into containers, each holding the lower 16 bits as a sorted array while sparse and as
+/// a bitmap once dense, so that runs of neighbouring ids take a bit each.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RoaringBitmap {
+    containers: Vec<(u16, Container)>,
+}
+
+//region: Private functions
+
+fn split(value: u32) -> (u16, u16) {
+    ((value >> 16) as u16, value as u16)
+}
+
+fn container_len(container: &Container) -> usize {
+    match container {
+        Container::Array(values) => values.len(),
+        Container::Bitmap(words) => words.iter().map(|w| w.count_ones() as usize).sum(),
+    }
+}
+
+fn container_iter(container: &Container) -> Box<dyn Iterator<Item = u16> + '_> {
+    match container {
+        Container::Array(values) => Box::new(values.iter().copied()),
+        Container::Bitmap(words) => Box::new(words.iter().enumerate().flat_map(|(i, word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| (i * 64 + bit) as u16)
+        })),
+    }
+}
+
+fn to_bitmap(values: &[u16]) -> Box<[u64; BITMAP_WORDS]> {
+    let mut words = Box::new([0u64; BITMAP_WORDS]);
+    for value in values {
+        words[*value as usize / 64] |= 1 << (value % 64);
+    }
+    words
+}
+
+/// Merge two sorted runs of values, keeping those wanted by `keep(in_a, in_b)`.
+fn merge_sorted(
+    mut a: impl Iterator<Item = u32>,
+    mut b: impl Iterator<Item = u32>,
+    keep: impl Fn(bool, bool) -> bool,
+) -> RoaringBitmap {
+    let mut merged = RoaringBitmap::new();
+    let (mut next_a, mut next_b) = (a.next(), b.next());
+    loop {
+        let (value, in_a, in_b) = match (next_a, next_b) {
+            (Some(x), Some(y)) if x == y => (x, true, true),
+            (Some(x), Some(y)) if x < y => (x, true, false),
+            (Some(x), None) => (x, true, false),
+            (_, Some(y)) => (y, false, true),
+            (None, None) => break,
+        };
+        if keep(in_a, in_b) {
+            merged.insert(value);
+        }
+        if in_a {
+            next_a = a.next();
+        }
+        if in_b {
+            next_b = b.next();
+        }
+    }
+    merged
+}
+
+//endregion:
+
+impl RoaringBitmap {
+    pub fn new() -> RoaringBitmap {
+        RoaringBitmap::default()
+    }
+
+    /// Add a value, returning whether it was not already held. Values added in ascending order,
+    /// as ids assigned in turn are, only ever touch the last container.
+    pub fn insert(&mut self, value: u32) -> bool {
+        let (high, low) = split(value);
+        let position = match self.containers.last() {
+            Some((h, _)) if *h == high => self.containers.len() - 1,
+            _ => match self.containers.binary_search_by_key(&high, |(h, _)| *h) {
+                Ok(p) => p,
+                Err(p) => {
+                    self.containers
+                        .insert(p, (high, Container::Array(Vec::new())));
+                    p
+                }
+            },
+        };
+
+        let container = &mut self.containers[position].1;
+        match container {
+            Container::Array(values) => {
+                let insert_at = match values.last() {
+                    Some(last) if *last < low => values.len(),
+                    _ => match values.binary_search(&low) {
+                        Ok(_) => return false,
+                        Err(p) => p,
+                    },
+                };
+                values.insert(insert_at, low);
+                if values.len() > ARRAY_LIMIT {
+                    *container = Container::Bitmap(to_bitmap(values));
+                }
+                true
+            }
+            Container::Bitmap(words) => {
+                let (word, bit) = (low as usize / 64, low % 64);
+                let added = words[word] & (1 << bit) == 0;
+                words[word] |= 1 << bit;
+                added
+            }
+        }
+    }
+
+    pub fn contains(&self, value: u32) -> bool {
+        let (high, low) = split(value);
+        match self.containers.binary_search_by_key(&high, |(h, _)| *h) {
+            Ok(p) => match &self.containers[p].1 {
+                Container::Array(values) => values.binary_search(&low).is_ok(),
+                Container::Bitmap(words) => words[low as usize / 64] & (1 << (low % 64)) != 0,
+            },
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.containers.iter().map(|(_, c)| container_len(c)).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+    }
+
+    /// The values of the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.containers.iter().flat_map(|(high, container)| {
+            container_iter(container).map(move |low| (*high as u32) << 16 | low as u32)
+        })
+    }
+
+    pub fn union(&self, other: &RoaringBitmap) -> RoaringBitmap {
+        merge_sorted(self.iter(), other.iter(), |a, b| a || b)
+    }
+
+    pub fn intersection(&self, other: &RoaringBitmap) -> RoaringBitmap {
+        merge_sorted(self.iter(), other.iter(), |a, b| a && b)
+    }
+
+    pub fn difference(&self, other: &RoaringBitmap) -> RoaringBitmap {
+        merge_sorted(self.iter(), other.iter(), |a, b| a && !b)
+    }
+
+    /// Write the set as its containers in turn, each as its upper 16 bits and value count
+    /// followed by its array of values or its bitmap words, all little-endian.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        for (high, container) in &self.containers {
+            bytes.extend_from_slice(&high.to_le_bytes());
+            bytes.extend_from_slice(&(container_len(container) as u32).to_le_bytes());
+            match container {
+                Container::Array(values) => {
+                    values
+                        .iter()
+                        .for_each(|v| bytes.extend_from_slice(&v.to_le_bytes()));
+                }
+                Container::Bitmap(words) => {
+                    words
+                        .iter()
+                        .for_each(|w| bytes.extend_from_slice(&w.to_le_bytes()));
+                }
+            }
+        }
+        bytes
+    }
+
+    pub(crate) fn from_bytes(mut bytes: &[u8]) -> Result<RoaringBitmap> {
+        let mut containers: Vec<(u16, Container)> = Vec::new();
+        while !bytes.is_empty() {
+            if bytes.len() < 6 {
+                bail!("Bitmap ends part way through a container.");
+            }
+            let high = u16::from_le_bytes([bytes[0], bytes[1]]);
+            let count = u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]) as usize;
+            bytes = &bytes[6..];
+
+            let payload_length = match count > ARRAY_LIMIT {
+                true => BITMAP_WORDS * 8,
+                false => count * 2,
+            };
+            if bytes.len() < payload_length || containers.last().is_some_and(|(h, _)| *h >= high) {
+                bail!("Bitmap container {} is malformed.", high);
+            }
+            let (payload, rest) = bytes.split_at(payload_length);
+            bytes = rest;
+
+            let container = match count > ARRAY_LIMIT {
+                true => {
+                    let mut words = Box::new([0u64; BITMAP_WORDS]);
+                    for (word, chunk) in words.iter_mut().zip(payload.chunks_exact(8)) {
+                        *word = u64::from_le_bytes(chunk.try_into()?);
+                    }
+                    Container::Bitmap(words)
+                }
+                false => Container::Array(
+                    payload
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .collect(),
+                ),
+            };
+
+            // Searches and the last value of an array rely on its values being ascending and
+            // unique, and no container is kept once it is empty
+            let well_formed = match &container {
+                Container::Array(values) => {
+                    !values.is_empty() && values.windows(2).all(|w| w[0] < w[1])
+                }
+                Container::Bitmap(_) => container_len(&container) == count,
+            };
+            if !well_formed {
+                bail!("Bitmap container {} is malformed.", high);
+            }
+            containers.push((high, container));
+        }
+        Ok(RoaringBitmap { containers })
+    }
+}
+
+impl FromIterator<u32> for RoaringBitmap {
+    fn from_iter<I: IntoIterator<Item = u32>>(values: I) -> Self {
+        let mut bitmap = RoaringBitmap::new();
+        for value in values {
+            bitmap.insert(value);
+        }
+        bitmap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_roaring_bitmap_insert() {
+        let mut bitmap = RoaringBitmap::new();
+        assert!(bitmap.insert(70_000));
+        assert!(bitmap.insert(3));
+        assert!(bitmap.insert(65_536));
+        assert!(!bitmap.insert(3));
+
+        assert_eq!(3, bitmap.len());
+        assert!(bitmap.contains(65_536));
+        assert!(!bitmap.contains(4));
+        assert_eq!(vec![3, 65_536, 70_000], bitmap.iter().collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_roaring_bitmap_dense() {
+        // Every other value of the first container turns it into a bitmap
+        let exp_values: Vec<u32> = (0..20_000).step_by(2).chain([u32::MAX]).collect();
+        let bitmap: RoaringBitmap = exp_values.iter().rev().copied().collect();
+
+        assert!(matches!(bitmap.containers[0].1, Container::Bitmap(_)));
+        assert_eq!(exp_values.len(), bitmap.len());
+        assert_eq!(exp_values, bitmap.iter().collect::<Vec<u32>>());
+        assert!(!bitmap.contains(1));
+
+        let obs_bitmap = RoaringBitmap::from_bytes(&bitmap.to_bytes()).unwrap();
+        assert_eq!(bitmap, obs_bitmap);
+        assert!(RoaringBitmap::from_bytes(&bitmap.to_bytes()[..100]).is_err());
+    }
+
+    #[test]
+    fn test_roaring_bitmap_from_bytes_malformed() {
+        let container = |count: u32, values: &[u16]| {
+            let mut bytes = 0u16.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&count.to_le_bytes());
+            values
+                .iter()
+                .for_each(|v| bytes.extend_from_slice(&v.to_le_bytes()));
+            bytes
+        };
+
+        let obs_bitmap = RoaringBitmap::from_bytes(&container(3, &[1, 5, 9])).unwrap();
+        assert_eq!(vec![1, 5, 9], obs_bitmap.iter().collect::<Vec<u32>>());
+
+        // Values out of order or repeated, and an empty container
+        assert!(RoaringBitmap::from_bytes(&container(3, &[9, 5, 1])).is_err());
+        assert!(RoaringBitmap::from_bytes(&container(3, &[1, 5, 5])).is_err());
+        assert!(RoaringBitmap::from_bytes(&container(0, &[])).is_err());
+
+        // A bitmap container holding fewer values than its count
+        let mut bytes = 0u16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(ARRAY_LIMIT as u32 + 1).to_le_bytes());
+        bytes.extend_from_slice(&[0u8; BITMAP_WORDS * 8]);
+        assert!(RoaringBitmap::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_roaring_bitmap_set_operations() {
+        let a: RoaringBitmap = [1, 2, 3, 100_000].into_iter().collect();
+        let b: RoaringBitmap = [2, 3, 4].into_iter().collect();
+
+        assert_eq!(
+            vec![1, 2, 3, 4, 100_000],
+            a.union(&b).iter().collect::<Vec<u32>>()
+        );
+        assert_eq!(vec![2, 3], a.intersection(&b).iter().collect::<Vec<u32>>());
+        assert_eq!(
+            vec![1, 100_000],
+            a.difference(&b).iter().collect::<Vec<u32>>()
+        );
+    }
+}
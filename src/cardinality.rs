@@ -0,0 +1,218 @@
+use crate::decompression::{gather_zstd_frame, FrameLedger};
+use crate::numa::build_worker_pool;
+use crate::source::FrameSource;
+use crate::{BadRecord, DecompressionSummary, FrameMeta, ParseOptions, RecordKey, RunStatus};
+use anyhow::Result;
+use rayon::prelude::*;
+use xxhash_rust::xxh3::xxh3_64;
+
+// Bits of the hash which select a register. 2^14 registers give a standard error of about 0.8%
+const HLL_PRECISION: u32 = 14;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+type FrameEntries = Vec<(Box<[u8]>, Box<[u8]>)>;
+
+/// Outcome of a cardinality estimate, for the caller to report.
+#[derive(Debug, Default)]
+pub struct CardinalityReport {
+    pub records: usize,
+    /// Approximate number of distinct keys
+    pub distinct_keys: u64,
+    /// Approximate number of distinct value texts
+    pub distinct_values: u64,
+    pub summary: DecompressionSummary,
+}
+
+impl CardinalityReport {
+    pub fn run_status(&self) -> RunStatus {
+        RunStatus::from_summary(&self.summary)
+    }
+}
+
+/// HyperLogLog sketch of the distinct items seen. Sketches of separate frames are merged by
+/// taking the larger of each register, giving the sketch of all items of both.
+#[derive(Clone)]
+pub(crate) struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub(crate) fn insert(&mut self, item: &[u8]) {
+        let hash = xxh3_64(item);
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+
+        // Position of the first set bit among those not used to select the register
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() + 1;
+        self.registers[register] = self.registers[register].max(rank as u8);
+    }
+
+    pub(crate) fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other_register) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other_register);
+        }
+    }
+
+    pub(crate) fn estimate(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let harmonic_sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw_estimate = alpha * m * m / harmonic_sum;
+
+        // Small cardinalities leave registers empty, and are better counted from those
+        let empty_registers = self.registers.iter().filter(|&&r| r == 0).count();
+        let estimate = match raw_estimate <= 2.5 * m && empty_registers > 0 {
+            true => m * (m / empty_registers as f64).ln(),
+            false => raw_estimate,
+        };
+        estimate.round() as u64
+    }
+}
+
+//region: Private functions
+
+fn sketch_entries(entries: FrameEntries) -> (usize, HyperLogLog, HyperLogLog) {
+    let mut key_sketch = HyperLogLog::default();
+    let mut value_sketch = HyperLogLog::default();
+
+    for (key, value) in &entries {
+        key_sketch.insert(&key.key_bytes());
+        value_sketch.insert(value);
+    }
+    (entries.len(), key_sketch, value_sketch)
+}
+
+fn merge_sketches(
+    a: (usize, HyperLogLog, HyperLogLog),
+    b: (usize, HyperLogLog, HyperLogLog),
+) -> (usize, HyperLogLog, HyperLogLog) {
+    let (records, mut key_sketch, mut value_sketch) = a;
+    key_sketch.merge(&b.1);
+    value_sketch.merge(&b.2);
+    (records + b.0, key_sketch, value_sketch)
+}
+
+//endregion:
+
+/// Estimate the number of distinct keys and values in the archive. Each frame is sketched as it
+/// is decoded and the sketches merged, so memory stays fixed however many records are read.
+pub(crate) fn estimate_cardinality_zstd(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<CardinalityReport> {
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_ledger = FrameLedger::default();
+
+    let frame_sketches: Result<Vec<_>> = pool.install(|| {
+        idx_buffer
+            .into_par_iter()
+            .map(|idx_frame| {
+                let (entries, bad_records): (FrameEntries, Vec<BadRecord>) =
+                    gather_zstd_frame(source, idx_frame, parse_options, &frame_ledger)?
+                        .unwrap_or_default();
+                Ok((sketch_entries(entries), bad_records))
+            })
+            .collect()
+    });
+    let (mut sketches, mut bad_buffer): (Vec<_>, Vec<Vec<BadRecord>>) =
+        frame_sketches?.into_iter().unzip();
+
+    // Lines which span frames are only complete once every frame has been read
+    for (_, (entries, stitched_bad)) in frame_ledger.stitch(parse_options)? {
+        sketches.push(sketch_entries(entries));
+        bad_buffer.push(stitched_bad);
+    }
+
+    let (records, key_sketch, value_sketch) = sketches.into_par_iter().reduce(
+        || (0, HyperLogLog::default(), HyperLogLog::default()),
+        merge_sketches,
+    );
+
+    Ok(CardinalityReport {
+        records,
+        distinct_keys: key_sketch.estimate(),
+        distinct_values: value_sketch.estimate(),
+        summary: frame_ledger.into_summary(bad_buffer.concat()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+    use std::collections::HashSet;
+    use std::fs::OpenOptions;
+    use std::io::BufReader;
+
+    #[test]
+    fn test_hyperloglog_estimate() {
+        let mut sketch = HyperLogLog::default();
+        for i in 0..100_000u32 {
+            sketch.insert(format!("key_{}", i).as_bytes());
+            sketch.insert(format!("key_{}", i % 10).as_bytes());
+        }
+
+        let obs_estimate = sketch.estimate() as f64;
+        assert!((obs_estimate - 100_000.0).abs() < 3_000.0);
+    }
+
+    #[test]
+    fn test_hyperloglog_merge() {
+        let mut a = HyperLogLog::default();
+        let mut b = HyperLogLog::default();
+        let mut exp_sketch = HyperLogLog::default();
+        for i in 0..1_000u32 {
+            let item = format!("item_{}", i);
+            match i % 2 {
+                0 => a.insert(item.as_bytes()),
+                _ => b.insert(item.as_bytes()),
+            }
+            exp_sketch.insert(item.as_bytes());
+        }
+
+        a.merge(&b);
+        assert_eq!(exp_sketch.registers, a.registers);
+    }
+
+    #[test]
+    fn test_estimate_cardinality_zstd() {
+        let idx_file = OpenOptions::new()
+            .read(true)
+            .open("test/example.zstd.idx")
+            .unwrap();
+        let idx_buffer = load_frame_index(&mut BufReader::new(idx_file)).unwrap();
+
+        let obs_report = estimate_cardinality_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        // Small counts are taken from the empty registers, so are exact barring collisions
+        let content = std::fs::read_to_string("test/data.txt").unwrap();
+        let exp_values: HashSet<&str> = content
+            .lines()
+            .filter_map(|line| line.split('\t').nth(1))
+            .collect();
+        assert_eq!(30, obs_report.records);
+        assert_eq!(30, obs_report.distinct_keys);
+        assert_eq!(exp_values.len() as u64, obs_report.distinct_values);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::decompression::{decode_frame, locate_record, trim_line_ending};
+use crate::numa::build_worker_pool;
+use crate::repack::{pack_frames, RepackTarget};
+use crate::source::FrameSource;
+use crate::{FrameMeta, ParseOptions, PipelineError};
+use anyhow::{bail, Result};
+use dashmap::DashMap;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Value of a record which deletes its key. Records of the key in earlier frames, and the
+/// tombstone itself, are dropped when the archive is compacted.
+pub const TOMBSTONE: &str = "<deleted>";
+
+/// Frame order and line number of the last record of a key
+type LastRecord = (u64, usize);
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CompactSummary {
+    pub frames_read: usize,
+    pub frames_written: usize,
+    pub records_kept: usize,
+    /// Records dropped because a later record of the same key replaced them
+    pub records_superseded: usize,
+    /// Keys deleted by a tombstone, along with the tombstones themselves
+    pub keys_deleted: usize,
+    pub bytes_written: u64,
+}
+
+//region: Private functions
+
+/// Find the last record of every key, which is the one kept when the archive is compacted.
+fn find_last_records(
+    source: &dyn FrameSource,
+    idx_buffer: &[FrameMeta],
+    pool: &rayon::ThreadPool,
+    parse_options: &ParseOptions,
+) -> Result<DashMap<Box<[u8]>, LastRecord>> {
+    let last_records: DashMap<Box<[u8]>, LastRecord> = DashMap::new();
+
+    pool.install(|| {
+        idx_buffer
+            .par_iter()
+            .try_for_each(|idx_frame| -> Result<()> {
+                let payload = decode_frame(source, idx_frame, parse_options)?;
+                for (line_number, line_repr) in payload.split(|&b| b == b'\n').enumerate() {
+                    let Some((key_bytes, _)) =
+                        locate_record(trim_line_ending(line_repr), parse_options)
+                    else {
+                        continue;
+                    };
+
+                    // Frames are read in any order, so the later record is kept whichever is seen first
+                    let record = (idx_frame.order, line_number);
+                    let mut last = last_records.entry(key_bytes.into()).or_insert(record);
+                    if *last < record {
+                        *last = record;
+                    }
+                }
+                Ok(())
+            })
+    })?;
+    Ok(last_records)
+}
+
+//endregion:
+
+/// Rewrite an archive as a log-structured store, keeping only the last record of each key and
+/// dropping keys whose last record is a tombstone. Records which survive keep their place in the
+/// file, and lines which are not records are kept as written. The archive is read twice: once to
+/// find the last record of each key, and once to write the survivors.
+pub(crate) fn compact_zstd(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    zstd_writer: File,
+    mut idx_writer: BufWriter<File>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    repack_target: &RepackTarget,
+) -> Result<CompactSummary> {
+    // A record split across frames could not be matched against its last occurrence
+    if parse_options.split_records {
+        bail!(PipelineError::Usage(
+            "Compaction requires a frame index, so that every frame holds whole records!".into()
+        ));
+    }
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let last_records = find_last_records(source, &idx_buffer, &pool, parse_options)?;
+
+    let records_kept = AtomicUsize::new(0);
+    let records_superseded = AtomicUsize::new(0);
+    let keys_deleted = AtomicUsize::new(0);
+
+    let (idx_records, frames_read) = pack_frames(
+        source,
+        idx_buffer,
+        &zstd_writer,
+        num_threads,
+        parse_options,
+        repack_target,
+        |idx_frame, payload| {
+            let mut content: Vec<u8> = Vec::with_capacity(payload.len());
+            for (line_number, line_repr) in payload.split_inclusive(|&b| b == b'\n').enumerate() {
+                let record_repr = line_repr.strip_suffix(b"\n").unwrap_or(line_repr);
+                let Some((key_bytes, value_bytes)) =
+                    locate_record(trim_line_ending(record_repr), parse_options)
+                else {
+                    content.extend_from_slice(line_repr);
+                    continue;
+                };
+
+                let is_last = last_records
+                    .get(&key_bytes[..])
+                    .is_some_and(|last| *last == (idx_frame.order, line_number));
+                match (is_last, value_bytes == TOMBSTONE.as_bytes()) {
+                    (true, false) => {
+                        content.extend_from_slice(line_repr);
+                        records_kept.fetch_add(1, Ordering::Relaxed);
+                    }
+                    (true, true) => {
+                        keys_deleted.fetch_add(1, Ordering::Relaxed);
+                    }
+                    (false, _) => {
+                        records_superseded.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            content
+        },
+    )?;
+
+    serde_json::to_writer_pretty(&mut idx_writer, &idx_records)?;
+    idx_writer.flush()?;
+
+    Ok(CompactSummary {
+        frames_read,
+        frames_written: idx_records.len(),
+        records_kept: records_kept.into_inner(),
+        records_superseded: records_superseded.into_inner(),
+        keys_deleted: keys_deleted.into_inner(),
+        bytes_written: zstd_writer.metadata()?.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::compression::write_indexed_zstd;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+    use crate::{CompressionOptions, RecordFormat};
+    use std::io::BufReader;
+
+    #[test]
+    fn test_compact_zstd() {
+        let zstd_file = "compact_zstd.zstd";
+        let idx_file = "compact_zstd.zstd.idx";
+        let output_file = "compact_zstd.compact.zstd";
+        let output_idx_file = "compact_zstd.compact.zstd.idx";
+
+        // 'a' is replaced, 'b' is deleted, and 'c' is deleted and then written again
+        let content = "a\t1\nb\t2\nc\t3\na\t4\nb\t<deleted>\nc\t<deleted>\nd\t5\nc\t6\n";
+        write_indexed_zstd(
+            content.as_bytes(),
+            File::create(zstd_file).unwrap(),
+            BufWriter::new(File::create(idx_file).unwrap()),
+            8,
+            3,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+
+        let idx_buffer = load_frame_index(BufReader::new(File::open(idx_file).unwrap())).unwrap();
+        let repack_target = RepackTarget {
+            block_size: 1024,
+            zstd_level: 3,
+            frame_metadata: false,
+            align: None,
+            format: RecordFormat::Tsv,
+        };
+        let obs_result = compact_zstd(
+            &FileSource::new(zstd_file),
+            idx_buffer,
+            File::create(output_file).unwrap(),
+            BufWriter::new(File::create(output_idx_file).unwrap()),
+            2,
+            &ParseOptions::default(),
+            &repack_target,
+        );
+        let obs_content = zstd::decode_all(File::open(output_file).unwrap()).unwrap();
+
+        for file_path in [zstd_file, idx_file, output_file, output_idx_file] {
+            let _ = std::fs::remove_file(file_path);
+        }
+
+        let exp_summary = CompactSummary {
+            frames_read: 5,
+            frames_written: 1,
+            records_kept: 3,
+            records_superseded: 4,
+            keys_deleted: 1,
+            bytes_written: obs_result.as_ref().unwrap().bytes_written,
+        };
+        assert_eq!(exp_summary, obs_result.unwrap());
+        assert_eq!(b"a\t4\nd\t5\nc\t6\n".to_vec(), obs_content);
+    }
+}
@@ -1,308 +1,1696 @@
-use crate::FrameMeta;
-use anyhow::{bail, Result};
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Seek, Write};
-
-//region: Private functions
-
-fn read_chunk(
-    file_reader: &mut BufReader<File>,
-    read_buffer: &mut String,
-    block_size: usize,
-) -> Result<Option<u64>> {
-    // TODO: check that block_size is > 0
-    let mut total_bytes_read: usize = 0;
-
-    loop {
-        let bytes_read = file_reader.read_line(read_buffer)?;
-
-        // Terminate early on an EOF
-        if bytes_read == 0 {
-            return if total_bytes_read == 0 {
-                Ok(None)
-            } else {
-                Ok(Some(total_bytes_read as u64))
-            };
-        }
-
-        total_bytes_read += bytes_read;
-
-        // Terminate if block_size is met
-        if total_bytes_read >= block_size {
-            return Ok(Some(total_bytes_read as u64));
-        }
-    }
-}
-
-fn encode_zstd_block(
-    mut zstd_writer: &File,
-    content_bytes: &[u8],
-    zstd_level: i32,
-) -> Result<(u64, u64)> {
-    // Find the offset for the writing stream before zstd write
-    let start_offset = match zstd_writer.stream_position() {
-        Ok(u) => u,
-        Err(_) => bail!("Unable to find current location of zstd stream!"),
-    };
-
-    // Create an encoder and compress the block
-    let mut encoder = zstd::stream::Encoder::new(zstd_writer, zstd_level).unwrap();
-    encoder.include_checksum(true).unwrap();
-
-    let mut af_encoder = encoder.auto_finish();
-
-    match &af_encoder.write_all(content_bytes) {
-        Ok(_) => (),
-        Err(_) => bail!("Unable to write to zstd stream!"),
-    };
-
-    drop(af_encoder);
-
-    // Find the offset for the writing stream after zstd write
-    let end_offset = match zstd_writer.stream_position() {
-        Ok(u) => u,
-        Err(_) => bail!("Unable to find current location of zstd stream!"),
-    };
-
-    Ok((start_offset, end_offset))
-}
-
-//endregion:
-
-pub fn write_indexed_zstd(
-    mut input_reader: BufReader<File>,
-    zstd_writer: File,
-    mut idx_writer: BufWriter<File>,
-    block_size: usize,
-    zstd_level: i32,
-) -> Result<()> {
-    let mut idx_records: Vec<FrameMeta> = Vec::new();
-    let mut seq_position = 0;
-
-    let mut read_buffer = String::new();
-
-    while let Ok(Some(_)) = read_chunk(&mut input_reader, &mut read_buffer, block_size) {
-        let content = std::mem::take(&mut read_buffer);
-        let content_bytes = content.as_bytes();
-
-        let (start_pos, end_pos) = encode_zstd_block(&zstd_writer, content_bytes, zstd_level)?;
-
-        let length = end_pos - start_pos;
-        let frame_record = FrameMeta::new(start_pos, length, seq_position);
-
-        idx_records.push(frame_record);
-        seq_position += 1;
-    }
-
-    // Write out the index file
-    serde_json::to_writer_pretty(&mut idx_writer, &idx_records)?;
-    idx_writer.flush()?;
-
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-
-    use super::*;
-    use std::fs::OpenOptions;
-    use std::io::{BufReader, BufWriter, Read};
-
-    fn open_file_read(file_path: &str) -> File {
-        OpenOptions::new().read(true).open(file_path).unwrap()
-    }
-
-    fn open_file_write(file_path: &str) -> File {
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(file_path)
-            .unwrap()
-    }
-
-    #[test]
-    fn test_read_chunk_single() {
-        // Read with a block too small for a single line to ensure that reading does
-        // proceed until the end of the line.
-        let input_handle = open_file_read("test/data.txt");
-        let mut input_reader: BufReader<File> = BufReader::new(input_handle);
-
-        let mut read_buffer = String::new();
-        let result = read_chunk(&mut input_reader, &mut read_buffer, 5);
-
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_some());
-        // Evaluate the data read into `read_buffer`
-        assert_eq!("WP_413685322.1\t584\n", read_buffer);
-    }
-
-    #[test]
-    fn test_read_chunk_multi() {
-        // Read with a block too small for the whole file, but to cover several lines, to
-        // confirm expected use case of multi-line reading.
-        let input_handle = open_file_read("test/data.txt");
-        let mut input_reader: BufReader<File> = BufReader::new(input_handle);
-
-        let exp_content = concat!(
-            "WP_413685322.1\t584\nXNR99298.1\t584\nMEX9938374.1\t587\nKJX92028.1\t1047168\n",
-            "EFG1759503.1\t562\nEGJ4377881.1\t562\nEJZ1046351.1\t562\nEOA4653345.1\t562\n",
-            "EOP3024222.1\t562\nWP_198835266.1\t2779367\nMBJ2149627.1\t2779367\n",
-            "MBD3193859.1\t2053489\n"
-        );
-
-        let mut read_buffer = String::new();
-        let result = read_chunk(&mut input_reader, &mut read_buffer, 200);
-
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_some());
-        assert_eq!(exp_content, read_buffer);
-    }
-
-    #[test]
-    fn test_read_chunk_file() {
-        // Test the behaviour of the function over the complete file.
-        let input_handle = open_file_read("test/data.txt");
-        let mut input_reader: BufReader<File> = BufReader::new(input_handle);
-
-        let exp_content: Vec<String> = vec!(
-            "WP_413685322.1\t584\nXNR99298.1\t584\nMEX9938374.1\t587\nKJX92028.1\t1047168\n".into(),
-            "EFG1759503.1\t562\nEGJ4377881.1\t562\nEJZ1046351.1\t562\nEOA4653345.1\t562\nEOP3024222.1\t562\n".into(),
-            "WP_198835266.1\t2779367\nMBJ2149627.1\t2779367\nMBD3193859.1\t2053489\nMBD3198741.1\t2053489\n".into(),
-            "MBR5368159.1\t1898203\nMCL6526161.1\t2614257\nUXB85809.1\t2697049\nMDO5780201.1\t1506\n".into(),
-            "MDP1794720.1\t2201156\nMDE2592313.1\t1911520\nUMM52736.1\t2922427\nMDB4345056.1\t1869227\n".into(),
-            "XP_035011836.2\t195615\nMDY3279706.1\t2831996\nPYI97175.1\t2026799\nPYJ33862.1\t2026799\n".into(),
-            "WP_137987990.1\t492670\nTKZ18939.1\t492670\nWP_372757791.1\t1979402\nKLA26572.1\t1396\n".into(),
-            "GAA1911923.1\t433649\n".into(),
-        );
-
-        let mut read_buffer = String::new();
-        let mut obs_results: Vec<String> = Vec::new();
-
-        while let Ok(Some(_)) = read_chunk(&mut input_reader, &mut read_buffer, 70) {
-            let content = std::mem::take(&mut read_buffer);
-            obs_results.push(content);
-        }
-
-        assert_eq!(exp_content, obs_results);
-    }
-
-    #[test]
-    fn test_encode_zstd_block_single() {
-        let target_file = "encode_zstd_block_single.zstd";
-        let mut target_handle = open_file_write(target_file);
-
-        let content = "test string for compression!";
-
-        let obs_result = encode_zstd_block(&mut target_handle, content.as_bytes(), 0);
-        assert!(obs_result.is_ok());
-
-        let (start, stop) = obs_result.unwrap();
-        assert_eq!((0, 41), (start, stop));
-
-        drop(target_handle);
-        let _ = std::fs::remove_file(target_file);
-    }
-
-    #[test]
-    fn test_encode_zstd_block_multiple() {
-        let target_file = "encode_zstd_block_multiple.zstd";
-        let mut target_handle = open_file_write(target_file);
-
-        let full_content: Vec<(String, (u64, u64))> = vec![
-            ("first entry!".into(), (0, 25)),
-            ("second entry!".into(), (25, 51)),
-            ("third entry!".into(), (51, 76)),
-        ];
-
-        for (content, (exp_start, exp_stop)) in &full_content {
-            let obs_result = encode_zstd_block(&mut target_handle, content.as_bytes(), 0);
-            assert!(obs_result.is_ok());
-
-            let exp_values = (*exp_start, *exp_stop);
-            assert_eq!(exp_values, obs_result.unwrap());
-        }
-
-        drop(target_handle);
-        let _ = std::fs::remove_file(target_file);
-    }
-
-    #[test]
-    fn test_write_indexed_zstd_single_frame() {
-        // Set up in the input reader/writers for the function arguments
-        let input_handle = open_file_read("test/data.txt");
-        let input_reader: BufReader<File> = BufReader::new(input_handle);
-
-        let zstd_file = "write_indexed_zstd_single_frame.zstd";
-        let zstd_handle = open_file_write(zstd_file);
-
-        let index_file = "write_indexed_zstd_single_frame.zstd.idx";
-        let index_handle = open_file_write(index_file);
-        let index_writer: BufWriter<File> = BufWriter::new(index_handle);
-
-        // Execute the command
-        let obs_result = write_indexed_zstd(input_reader, zstd_handle, index_writer, 200, 0);
-        assert!(obs_result.is_ok());
-
-        // Decompress only the first block in the zstd file to check that the blocks
-        // are being created correctly.
-        let exp_zstd_frame = concat!(
-            "WP_413685322.1\t584\nXNR99298.1\t584\nMEX9938374.1\t587\nKJX92028.1\t1047168\n",
-            "EFG1759503.1\t562\nEGJ4377881.1\t562\nEJZ1046351.1\t562\nEOA4653345.1\t562\n",
-            "EOP3024222.1\t562\nWP_198835266.1\t2779367\nMBJ2149627.1\t2779367\n",
-            "MBD3193859.1\t2053489\n",
-        );
-
-        let mut obs_zstd = String::new();
-        let mut decoder = zstd::stream::Decoder::new(open_file_read(zstd_file))
-            .unwrap()
-            .single_frame();
-        let _ = decoder.read_to_string(&mut obs_zstd);
-
-        assert_eq!(exp_zstd_frame, obs_zstd);
-
-        // Clean up
-        let _ = std::fs::remove_file(zstd_file);
-        let _ = std::fs::remove_file(index_file);
-    }
-
-    #[test]
-    fn test_write_indexed_zstd_complete() {
-        // Set up in the input reader/writers for the function arguments
-        let input_handle = open_file_read("test/data.txt");
-        let input_reader: BufReader<File> = BufReader::new(input_handle);
-
-        let zstd_file = "write_indexed_zstd_complete.zstd";
-        let zstd_handle = open_file_write(zstd_file);
-
-        let index_file = "write_indexed_zstd_complete.zstd.idx";
-        let index_handle = open_file_write(index_file);
-        let index_writer: BufWriter<File> = BufWriter::new(index_handle);
-
-        // Execute the command
-        let obs_result = write_indexed_zstd(input_reader, zstd_handle, index_writer, 200, 0);
-        assert!(obs_result.is_ok());
-
-        // Decompress the zstd file and compare against the expected payload
-        // This just checks that compression worked
-        let exp_zstd = std::fs::read_to_string("test/data.txt").unwrap();
-
-        let mut obs_zstd = String::new();
-        let mut decoder = zstd::stream::Decoder::new(open_file_read(zstd_file)).unwrap();
-        let _ = decoder.read_to_string(&mut obs_zstd);
-
-        assert_eq!(exp_zstd, obs_zstd);
-
-        // Compare the contents of the JSON file against the expected payload
-        // This checks that compression was performed in the expected blocks
-        let exp_json: Vec<FrameMeta> =
-            serde_json::from_reader(open_file_read("test/example.zstd.idx")).unwrap();
-        let obs_json: Vec<FrameMeta> = serde_json::from_reader(open_file_read(index_file)).unwrap();
-
-        assert_eq!(exp_json, obs_json);
-
-        // Clean up
-        let _ = std::fs::remove_file(zstd_file);
-        let _ = std::fs::remove_file(index_file);
-    }
-}
+use crate::compact::TOMBSTONE;
+use crate::decompression::trim_line_ending;
+use crate::numa::build_worker_pool;
+use crate::reorder::ordered_channel;
+use crate::{
+    CompressionOptions, FrameMeta, IndexHeader, KeyRange, PipelineError, RecordFormat, RecordStats,
+    Validation,
+};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use xxhash_rust::xxh3::xxh3_64;
+
+// Skippable frame magic used for the frame metadata written with '--frame-metadata'. Other
+// skippable magics are left for pzstd and the like.
+pub(crate) const FRAME_METADATA_MAGIC: u32 = 0x184D_2A5E;
+pub(crate) const FRAME_METADATA_SIZE: usize = 32;
+// Skippable frame magic used to pad frames out to an alignment boundary
+const PADDING_MAGIC: u32 = 0x184D_2A5D;
+// Magic and size fields which begin every skippable frame
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+// Ratios of a level 1 trial compression below which a block is compressed at level 1, or at
+// the midpoint up to the requested level, as more effort gains little on such content
+const INCOMPRESSIBLE_RATIO: f64 = 1.1;
+const WEAK_RATIO: f64 = 1.5;
+// Times the median compressed frame size beyond which a frame is reported as an outlier
+const FRAME_OUTLIER_FACTOR: u64 = 100;
+// Size of the reads made when searching for the end of a block
+const PLAN_WINDOW_SIZE: usize = 64 * 1024;
+// Blocks per thread which can be compressed ahead of the one being written before workers block
+const PARALLEL_CHUNK_LOOKAHEAD: usize = 2;
+
+/// An index led by a header recording how the archive was made.
+#[derive(Serialize)]
+struct HeadedIndex<'a> {
+    header: &'a IndexHeader,
+    frames: &'a [FrameMeta],
+}
+
+/// Description of a data frame, stored in a skippable frame immediately ahead of it so that the
+/// archive can be indexed again by scanning if the index file is lost. Stored as little-endian
+/// u64 values: order, compressed length, uncompressed length and the xxh3 of the uncompressed
+/// content.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FrameMetadata {
+    pub order: u64,
+    pub length: u64,
+    pub uncompressed_length: u64,
+    pub checksum: u64,
+}
+
+impl FrameMetadata {
+    pub(crate) fn to_bytes(&self) -> [u8; FRAME_METADATA_SIZE] {
+        let mut buffer = [0u8; FRAME_METADATA_SIZE];
+        buffer[0..8].copy_from_slice(&self.order.to_le_bytes());
+        buffer[8..16].copy_from_slice(&self.length.to_le_bytes());
+        buffer[16..24].copy_from_slice(&self.uncompressed_length.to_le_bytes());
+        buffer[24..32].copy_from_slice(&self.checksum.to_le_bytes());
+        buffer
+    }
+
+    pub(crate) fn from_bytes(buffer: &[u8; FRAME_METADATA_SIZE]) -> FrameMetadata {
+        let u64_at = |i: usize| u64::from_le_bytes(buffer[i..i + 8].try_into().unwrap());
+        FrameMetadata {
+            order: u64_at(0),
+            length: u64_at(8),
+            uncompressed_length: u64_at(16),
+            checksum: u64_at(24),
+        }
+    }
+}
+
+/// Content compressed as a single frame, held until it is written to the archive.
+pub(crate) struct EncodedFrame {
+    payload: Vec<u8>,
+    uncompressed_length: u64,
+    checksum: u64,
+}
+
+impl EncodedFrame {
+    pub(crate) fn encode(content_bytes: &[u8], zstd_level: i32) -> Result<EncodedFrame> {
+        let mut payload = Cursor::new(Vec::new());
+        encode_zstd_block(&mut payload, content_bytes, zstd_level)?;
+        Ok(EncodedFrame {
+            payload: payload.into_inner(),
+            uncompressed_length: content_bytes.len() as u64,
+            checksum: xxh3_64(content_bytes),
+        })
+    }
+}
+
+/// Byte range of a block of one input shard, found ahead of reading it.
+#[derive(Clone, Debug, PartialEq)]
+struct PlannedChunk {
+    shard: usize,
+    start: u64,
+    end: u64,
+    /// Whether a newline is added after the block, as the shard ends part way through a line
+    /// and further shards follow
+    terminate: bool,
+}
+
+/// A block of the input compressed as a frame, with the details recorded in its index entry.
+struct EncodedChunk {
+    frame: EncodedFrame,
+    level: Option<i32>,
+    key_range: Option<KeyRange>,
+    record_stats: RecordStats,
+}
+
+//region: Private functions
+
+fn read_chunk<R: BufRead>(
+    file_reader: &mut R,
+    read_buffer: &mut String,
+    block_size: usize,
+    preserve_line_endings: bool,
+) -> Result<Option<u64>> {
+    let mut total_bytes_read: usize = 0;
+
+    loop {
+        let bytes_read = file_reader.read_line(read_buffer)?;
+
+        // Terminate early on an EOF
+        if bytes_read == 0 {
+            return if total_bytes_read == 0 {
+                Ok(None)
+            } else {
+                Ok(Some(total_bytes_read as u64))
+            };
+        }
+
+        total_bytes_read += bytes_read;
+
+        // Normalise CRLF endings to LF unless the bytes are to be kept exactly as read
+        if !preserve_line_endings && read_buffer.ends_with("\r\n") {
+            read_buffer.truncate(read_buffer.len() - 2);
+            read_buffer.push('\n');
+        }
+
+        // Terminate if block_size is met, unless blank lines follow. These are taken into the
+        // block, so that trailing blank lines never make a frame with no records of its own
+        if total_bytes_read >= block_size && !next_line_is_blank(file_reader)? {
+            return Ok(Some(total_bytes_read as u64));
+        }
+    }
+}
+
+fn next_line_is_blank<R: BufRead>(file_reader: &mut R) -> Result<bool> {
+    let upcoming = file_reader.fill_buf()?;
+    Ok(upcoming.starts_with(b"\n") || upcoming.starts_with(b"\r\n"))
+}
+
+fn is_valid_record(line: &str, format: &RecordFormat) -> bool {
+    match format.split_record(line.as_bytes()) {
+        Some((key, value)) => {
+            // A tombstone deletes its key, so is a record without a numeric value
+            let value = String::from_utf8_lossy(value);
+            !key.is_empty() && (value.trim().parse::<u64>().is_ok() || value.trim() == TOMBSTONE)
+        }
+        None => false,
+    }
+}
+
+fn validate_chunk(
+    content: &str,
+    line_offset: usize,
+    validation: &Validation,
+    format: &RecordFormat,
+) -> Result<usize> {
+    let mut line_number = line_offset;
+
+    for line in content.lines() {
+        line_number += 1;
+
+        if !format.is_header(line.as_bytes()) && !is_valid_record(line, format) {
+            match validation {
+                Validation::Report => {
+                    eprintln!("Malformed record on line {}: '{}'", line_number, line)
+                }
+                Validation::Reject => bail!("Malformed record on line {}: '{}'", line_number, line),
+            }
+        }
+    }
+
+    Ok(line_number)
+}
+
+fn check_key_order(
+    content: &str,
+    line_offset: usize,
+    last_key: &mut Option<Vec<u8>>,
+    format: &RecordFormat,
+) -> Result<usize> {
+    let mut line_number = line_offset;
+    let mut chunk_last: Option<&[u8]> = None;
+
+    for line in content.lines() {
+        line_number += 1;
+
+        if format.is_header(line.as_bytes()) {
+            continue;
+        }
+        let Some((key, _)) = format.split_record(line.as_bytes()) else {
+            continue;
+        };
+
+        if let Some(previous) = chunk_last.or(last_key.as_deref())
+            && key < previous
+        {
+            bail!(PipelineError::Usage(format!(
+                "Record on line {} is out of key order, as '{}' follows '{}'!",
+                line_number,
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(previous)
+            )));
+        }
+        chunk_last = Some(key);
+    }
+
+    if let Some(key) = chunk_last {
+        *last_key = Some(key.to_vec());
+    }
+    Ok(line_number)
+}
+
+/// Level to compress a block at, judged by how well a level 1 trial compresses it. Blocks which
+/// barely compress gain little from a higher level, so are spared the extra effort.
+fn choose_level(content_bytes: &[u8], max_level: i32) -> Result<i32> {
+    if max_level <= 1 || content_bytes.is_empty() {
+        return Ok(max_level);
+    }
+
+    let probe_length = zstd::bulk::compress(content_bytes, 1)?.len().max(1);
+    let probe_ratio = content_bytes.len() as f64 / probe_length as f64;
+    Ok(match probe_ratio {
+        r if r < INCOMPRESSIBLE_RATIO => 1,
+        r if r < WEAK_RATIO => (1 + max_level) / 2,
+        _ => max_level,
+    })
+}
+
+fn encode_zstd_block<W: Write + Seek>(
+    zstd_writer: &mut W,
+    content_bytes: &[u8],
+    zstd_level: i32,
+) -> Result<(u64, u64)> {
+    // Find the offset for the writing stream before zstd write
+    let start_offset = match zstd_writer.stream_position() {
+        Ok(u) => u,
+        Err(_) => bail!("Unable to find current location of zstd stream!"),
+    };
+
+    // Create an encoder and compress the block
+    let mut encoder = zstd::stream::Encoder::new(&mut *zstd_writer, zstd_level).unwrap();
+    encoder.include_checksum(true).unwrap();
+
+    let mut af_encoder = encoder.auto_finish();
+
+    match &af_encoder.write_all(content_bytes) {
+        Ok(_) => (),
+        Err(_) => bail!("Unable to write to zstd stream!"),
+    };
+
+    drop(af_encoder);
+
+    // Find the offset for the writing stream after zstd write
+    let end_offset = match zstd_writer.stream_position() {
+        Ok(u) => u,
+        Err(_) => bail!("Unable to find current location of zstd stream!"),
+    };
+
+    Ok((start_offset, end_offset))
+}
+
+fn pad_to_alignment<W: Write + Seek>(
+    zstd_writer: &mut W,
+    align: u64,
+    following_bytes: u64,
+) -> Result<()> {
+    let position = zstd_writer.stream_position()?;
+
+    // Padding is itself a skippable frame, so a gap too small to hold one is widened by a unit
+    let mut padding = (align - (position + following_bytes) % align) % align;
+    while padding > 0 && padding < SKIPPABLE_HEADER_SIZE {
+        padding += align;
+    }
+    if padding == 0 {
+        return Ok(());
+    }
+
+    let content_size = padding - SKIPPABLE_HEADER_SIZE;
+    let content_size = match u32::try_from(content_size) {
+        Ok(u) => u,
+        Err(_) => bail!("Unable to pad to an alignment of {} bytes!", align),
+    };
+    zstd_writer.write_all(&PADDING_MAGIC.to_le_bytes())?;
+    zstd_writer.write_all(&content_size.to_le_bytes())?;
+    std::io::copy(
+        &mut std::io::repeat(0).take(content_size as u64),
+        zstd_writer,
+    )?;
+    Ok(())
+}
+
+/// Pad the end of the payload to the alignment, so that an aligned read of the final frame
+/// stays within the file.
+pub(crate) fn pad_payload_end<W: Write + Seek>(
+    zstd_writer: &mut W,
+    align: Option<u64>,
+) -> Result<()> {
+    match align {
+        Some(align) => pad_to_alignment(zstd_writer, align, 0),
+        None => Ok(()),
+    }
+}
+
+fn reserve_frame_metadata<W: Write + Seek>(zstd_writer: &mut W) -> Result<u64> {
+    let metadata_offset = zstd_writer.stream_position()?;
+
+    // The compressed length is not yet known, so the content is filled in after the data frame
+    zstd_writer.write_all(&FRAME_METADATA_MAGIC.to_le_bytes())?;
+    zstd_writer.write_all(&(FRAME_METADATA_SIZE as u32).to_le_bytes())?;
+    zstd_writer.write_all(&[0u8; FRAME_METADATA_SIZE])?;
+
+    Ok(metadata_offset + SKIPPABLE_HEADER_SIZE)
+}
+
+/// The largest frame, as its order, compressed length and the median compressed length, when
+/// it is so much larger than the median that decoding it would hold up the rest.
+fn find_frame_outlier(idx_records: &[FrameMeta]) -> Option<(u64, u64, u64)> {
+    let mut lengths: Vec<u64> = idx_records.iter().map(|f| f.length).collect();
+    lengths.sort_unstable();
+    let median = *lengths.get(lengths.len() / 2)?;
+
+    let largest = idx_records.iter().max_by_key(|f| f.length)?;
+    match median > 0 && largest.length > median * FRAME_OUTLIER_FACTOR {
+        true => Some((largest.order, largest.length, median)),
+        false => None,
+    }
+}
+
+/// Pad out and flush the end of the archive, then warn of any frame large enough to hold up
+/// parallel decoding.
+fn finish_archive<W: Write + Seek>(
+    zstd_writer: &mut W,
+    idx_records: &[FrameMeta],
+    compression_options: &CompressionOptions,
+) -> Result<()> {
+    pad_payload_end(zstd_writer, compression_options.align)?;
+    zstd_writer.flush()?;
+
+    // Decoding runs in parallel by frame, so finishes no sooner than the largest frame
+    if let Some((order, length, median)) = find_frame_outlier(idx_records) {
+        eprintln!(
+            "WARNING: Frame {} is {} bytes compressed, over {}x the median frame of {} bytes, so decompression will wait on it. Consider a smaller --block-size, or --adaptive-level to spend less effort on poorly compressing blocks.",
+            order, length, FRAME_OUTLIER_FACTOR, median
+        );
+    }
+    Ok(())
+}
+
+/// Position just past the end of the line holding the given byte, or the end of the input.
+fn find_line_end(input_handle: &File, position: u64, input_length: u64) -> Result<u64> {
+    let mut window = vec![0u8; PLAN_WINDOW_SIZE];
+    let mut window_start = position;
+    while window_start < input_length {
+        let window_length = PLAN_WINDOW_SIZE.min((input_length - window_start) as usize);
+        input_handle.read_exact_at(&mut window[..window_length], window_start)?;
+        if let Some(i) = window[..window_length].iter().position(|b| *b == b'\n') {
+            return Ok(window_start + i as u64 + 1);
+        }
+        window_start += window_length as u64;
+    }
+    Ok(input_length)
+}
+
+/// Position past any blank lines starting at the given position, as blank lines after a block
+/// are taken into it.
+fn skip_blank_lines(input_handle: &File, mut position: u64, input_length: u64) -> Result<u64> {
+    let mut upcoming = [0u8; 2];
+    while position < input_length {
+        let upcoming_length = 2.min((input_length - position) as usize);
+        input_handle.read_exact_at(&mut upcoming[..upcoming_length], position)?;
+        match &upcoming[..upcoming_length] {
+            [b'\n', ..] => position += 1,
+            [b'\r', b'\n'] => position += 2,
+            _ => break,
+        }
+    }
+    Ok(position)
+}
+
+/// Byte ranges of the blocks which `read_chunk` would cut a shard into, found by reading only
+/// around each block boundary. A block shorter than the smallest frame size is folded into the
+/// block before it.
+fn plan_chunks(
+    input_handle: &File,
+    shard: usize,
+    block_size: usize,
+    min_frame_size: usize,
+) -> Result<Vec<PlannedChunk>> {
+    let input_length = input_handle.metadata()?.len();
+    let mut chunk_plan: Vec<PlannedChunk> = Vec::new();
+    let mut start: u64 = 0;
+
+    while start < input_length {
+        // A block runs to the end of the line which reaches the block size
+        let end = find_line_end(input_handle, start + block_size as u64 - 1, input_length)?;
+        let end = skip_blank_lines(input_handle, end, input_length)?;
+
+        match chunk_plan.last_mut() {
+            Some(previous) if ((end - start) as usize) < min_frame_size => previous.end = end,
+            _ => chunk_plan.push(PlannedChunk {
+                shard,
+                start,
+                end,
+                terminate: false,
+            }),
+        }
+        start = end;
+    }
+    Ok(chunk_plan)
+}
+
+/// Read a planned block of the input, normalising its line endings as `read_chunk` does.
+fn read_planned_chunk(
+    input_handle: &File,
+    planned_chunk: &PlannedChunk,
+    compression_options: &CompressionOptions,
+) -> Result<String> {
+    let (start, end) = (planned_chunk.start, planned_chunk.end);
+    let mut content_bytes = vec![0u8; (end - start) as usize];
+    input_handle.read_exact_at(&mut content_bytes, start)?;
+    let mut content = match String::from_utf8(content_bytes) {
+        Ok(c) => c,
+        Err(_) => bail!(
+            "The input between bytes {} and {} of shard {} is not valid UTF-8!",
+            start,
+            end,
+            planned_chunk.shard
+        ),
+    };
+    if planned_chunk.terminate && !content.ends_with('\n') {
+        content.push('\n');
+    }
+
+    // A CR is only ever followed by LF at the end of a line
+    match compression_options.preserve_line_endings {
+        true => Ok(content),
+        false => Ok(content.replace("\r\n", "\n")),
+    }
+}
+
+/// Compress a chunk of whole lines as a frame, at a level chosen for the chunk if the level is
+/// adaptive.
+fn encode_chunk(
+    content: &str,
+    seq_position: u64,
+    block_size: usize,
+    zstd_level: i32,
+    compression_options: &CompressionOptions,
+) -> Result<EncodedChunk> {
+    let content_bytes = content.as_bytes();
+    let level = match compression_options.adaptive_level {
+        true => Some(choose_level(content_bytes, zstd_level)?),
+        false => None,
+    };
+    let frame = EncodedFrame::encode(content_bytes, level.unwrap_or(zstd_level))?;
+
+    // A block which ends on its first line means the block size is below the line length
+    if seq_position == 0 && content.lines().nth(1).is_none() && content.len() > block_size {
+        eprintln!(
+            "The block size of {} bytes is smaller than the first line ({} bytes), so every line may be written as a frame of its own. Consider a block size of at least several lines.",
+            block_size,
+            content.len()
+        );
+    }
+
+    Ok(EncodedChunk {
+        frame,
+        level,
+        key_range: frame_key_range(content_bytes, &compression_options.format),
+        record_stats: frame_record_stats(content_bytes, &compression_options.format),
+    })
+}
+
+/// Write a compressed chunk to the archive, returning its index entry.
+fn write_encoded_chunk<W: Write + Seek>(
+    zstd_writer: &mut W,
+    encoded_chunk: EncodedChunk,
+    seq_position: u64,
+    compression_options: &CompressionOptions,
+) -> Result<FrameMeta> {
+    let mut frame_record = write_encoded_frame(
+        zstd_writer,
+        &encoded_chunk.frame,
+        seq_position,
+        compression_options.frame_metadata,
+        compression_options.align,
+    )?;
+    if let Some(level) = encoded_chunk.level {
+        frame_record = frame_record.with_level(level);
+    }
+    Ok(frame_record
+        .with_key_range(encoded_chunk.key_range)
+        .with_record_stats(encoded_chunk.record_stats))
+}
+
+fn write_chunk<W: Write + Seek>(
+    zstd_writer: &mut W,
+    content: &str,
+    seq_position: u64,
+    block_size: usize,
+    zstd_level: i32,
+    compression_options: &CompressionOptions,
+) -> Result<FrameMeta> {
+    let encoded_chunk = encode_chunk(
+        content,
+        seq_position,
+        block_size,
+        zstd_level,
+        compression_options,
+    )?;
+    write_encoded_chunk(
+        zstd_writer,
+        encoded_chunk,
+        seq_position,
+        compression_options,
+    )
+}
+
+//endregion:
+
+/// Find the first and last keys of the frame content, if its records are in byte order of their
+/// keys. Header lines are ignored.
+pub(crate) fn frame_key_range(content_bytes: &[u8], format: &RecordFormat) -> Option<KeyRange> {
+    let mut keys = content_bytes
+        .split(|&b| b == b'\n')
+        .map(trim_line_ending)
+        .filter(|line| !format.is_header(line))
+        .filter_map(|line| format.split_record(line).map(|(key, _)| key));
+
+    let first = keys.next()?;
+    let mut last = first;
+    for key in keys {
+        if key < last {
+            return None;
+        }
+        last = key;
+    }
+
+    Some(KeyRange {
+        first: String::from_utf8(first.to_vec()).ok()?,
+        last: String::from_utf8(last.to_vec()).ok()?,
+    })
+}
+
+/// Count the records of the frame content and the total lengths of their keys and values. Header
+/// lines and lines which do not split into a record are not counted.
+pub(crate) fn frame_record_stats(content_bytes: &[u8], format: &RecordFormat) -> RecordStats {
+    content_bytes
+        .split(|&b| b == b'\n')
+        .map(trim_line_ending)
+        .filter(|line| !format.is_header(line))
+        .filter_map(|line| format.split_record(line))
+        .fold(RecordStats::default(), |mut stats, (key, value)| {
+            stats.records += 1;
+            stats.key_bytes += key.len() as u64;
+            stats.value_bytes += value.len() as u64;
+            stats
+        })
+}
+
+/// Compress the content as a single frame at the current end of the writer, preceded by its
+/// metadata frame if requested, and return its index entry. With an alignment, padding is
+/// written first so that the data frame starts on an aligned offset.
+pub(crate) fn write_frame<W: Write + Seek>(
+    zstd_writer: &mut W,
+    content_bytes: &[u8],
+    order: u64,
+    zstd_level: i32,
+    frame_metadata: bool,
+    align: Option<u64>,
+) -> Result<FrameMeta> {
+    write_encoded_frame(
+        zstd_writer,
+        &EncodedFrame::encode(content_bytes, zstd_level)?,
+        order,
+        frame_metadata,
+        align,
+    )
+}
+
+/// Write a frame compressed ahead of time at the current end of the writer, as `write_frame`
+/// does, so that frames can be compressed in parallel and written in order.
+pub(crate) fn write_encoded_frame<W: Write + Seek>(
+    zstd_writer: &mut W,
+    encoded_frame: &EncodedFrame,
+    order: u64,
+    frame_metadata: bool,
+    align: Option<u64>,
+) -> Result<FrameMeta> {
+    if let Some(align) = align {
+        let metadata_bytes = match frame_metadata {
+            true => SKIPPABLE_HEADER_SIZE + FRAME_METADATA_SIZE as u64,
+            false => 0,
+        };
+        pad_to_alignment(zstd_writer, align, metadata_bytes)?;
+    }
+
+    let metadata_offset = match frame_metadata {
+        true => Some(reserve_frame_metadata(zstd_writer)?),
+        false => None,
+    };
+
+    let start_pos = zstd_writer.stream_position()?;
+    zstd_writer.write_all(&encoded_frame.payload)?;
+    let end_pos = zstd_writer.stream_position()?;
+
+    let length = end_pos - start_pos;
+    if let Some(metadata_offset) = metadata_offset {
+        let frame_metadata = FrameMetadata {
+            order,
+            length,
+            uncompressed_length: encoded_frame.uncompressed_length,
+            checksum: encoded_frame.checksum,
+        };
+        zstd_writer.seek(SeekFrom::Start(metadata_offset))?;
+        zstd_writer.write_all(&frame_metadata.to_bytes())?;
+        zstd_writer.seek(SeekFrom::Start(end_pos))?;
+    }
+
+    Ok(FrameMeta::new(start_pos, length, order)
+        .with_checksum(encoded_frame.checksum)
+        .with_uncompressed_length(encoded_frame.uncompressed_length))
+}
+
+/// Compress the input in blocks of whole lines, writing one frame per block, and return the
+/// index of the frames written. Any writer which can seek back to fill in frame metadata will
+/// do, so an archive may be built in memory.
+pub(crate) fn compress_frames<R: BufRead, W: Write + Seek>(
+    mut input_reader: R,
+    zstd_writer: &mut W,
+    block_size: usize,
+    zstd_level: i32,
+    compression_options: &CompressionOptions,
+) -> Result<Vec<FrameMeta>> {
+    let mut idx_records: Vec<FrameMeta> = Vec::new();
+    let mut seq_position = 0;
+    let mut line_position = 0;
+    let mut last_key: Option<Vec<u8>> = None;
+
+    let mut read_buffer = String::new();
+    // Each chunk is held until the next is read, so that a short chunk can join it
+    let mut pending_chunk: Option<String> = None;
+
+    // A compressed input is decoded as it is read, so a read failure is most likely corruption
+    while read_chunk(
+        &mut input_reader,
+        &mut read_buffer,
+        block_size,
+        compression_options.preserve_line_endings,
+    )
+    .map_err(|e| match &compression_options.input_codec {
+        Some(codec) => e.context(PipelineError::CorruptArchive(format!(
+            "Unable to decompress the {:?} input after {} frames",
+            codec, seq_position
+        ))),
+        None => e,
+    })?
+    .is_some()
+    {
+        let content = std::mem::take(&mut read_buffer);
+
+        let line_offset = line_position;
+        if let Some(v) = &compression_options.validation {
+            line_position = validate_chunk(&content, line_offset, v, &compression_options.format)?;
+        }
+        if compression_options.assume_sorted {
+            line_position = check_key_order(
+                &content,
+                line_offset,
+                &mut last_key,
+                &compression_options.format,
+            )?;
+        }
+
+        // A chunk below the smallest frame size is folded into the frame before it
+        match pending_chunk.as_mut() {
+            Some(previous) if content.len() < compression_options.min_frame_size => {
+                previous.push_str(&content)
+            }
+            _ => {
+                if let Some(previous) = pending_chunk.replace(content) {
+                    idx_records.push(write_chunk(
+                        zstd_writer,
+                        &previous,
+                        seq_position,
+                        block_size,
+                        zstd_level,
+                        compression_options,
+                    )?);
+                    seq_position += 1;
+                }
+            }
+        }
+    }
+    if let Some(previous) = pending_chunk {
+        idx_records.push(write_chunk(
+            zstd_writer,
+            &previous,
+            seq_position,
+            block_size,
+            zstd_level,
+            compression_options,
+        )?);
+    }
+    finish_archive(zstd_writer, &idx_records, compression_options)?;
+
+    Ok(idx_records)
+}
+
+/// Compress plain input files on several threads, as one archive holding the shards in the
+/// order given. A first pass finds where the blocks of each shard end by reading only around
+/// each block boundary, then each block is read with its own positioned read and compressed on
+/// a worker, and the frames are written in order. Blocks never span shards, and a shard which
+/// ends part way through a line has the line ended. For a single input, the archive matches
+/// that written by `compress_frames`.
+pub(crate) fn compress_files_parallel<W: Write + Seek>(
+    input_handles: &[File],
+    zstd_writer: &mut W,
+    block_size: usize,
+    zstd_level: i32,
+    num_threads: usize,
+    compression_options: &CompressionOptions,
+) -> Result<Vec<FrameMeta>> {
+    if compression_options.validation.is_some() || compression_options.input_codec.is_some() {
+        bail!(PipelineError::Usage(
+            "Reading the input on several threads is only possible for plain input which is not being validated!"
+                .into()
+        ));
+    }
+
+    let mut chunk_plan: Vec<PlannedChunk> = Vec::new();
+    for (shard, input_handle) in input_handles.iter().enumerate() {
+        let mut shard_plan = plan_chunks(
+            input_handle,
+            shard,
+            block_size,
+            compression_options.min_frame_size,
+        )?;
+        if let Some(last_chunk) = shard_plan.last_mut() {
+            last_chunk.terminate = shard + 1 < input_handles.len();
+        }
+        chunk_plan.extend(shard_plan);
+    }
+
+    let pool = build_worker_pool(num_threads, false, &compression_options.threads);
+    let (chunk_sender, chunk_receiver) =
+        ordered_channel::<EncodedChunk>(0, PARALLEL_CHUNK_LOOKAHEAD * num_threads.max(1));
+
+    let mut idx_records: Vec<FrameMeta> = Vec::with_capacity(chunk_plan.len());
+    std::thread::scope(|scope| -> Result<()> {
+        let encoder_handle = compression_options.threads.spawn_scoped(
+            scope,
+            "chunk-encoder",
+            || -> Result<()> {
+                pool.install(|| {
+                    chunk_plan.par_iter().enumerate().try_for_each_with(
+                        chunk_sender,
+                        |chunk_sender, (sequence, planned_chunk)| {
+                            let input_handle = &input_handles[planned_chunk.shard];
+                            let encoded_chunk = read_planned_chunk(
+                                input_handle,
+                                planned_chunk,
+                                compression_options,
+                            )
+                            .and_then(|content| {
+                                encode_chunk(
+                                    &content,
+                                    sequence as u64,
+                                    block_size,
+                                    zstd_level,
+                                    compression_options,
+                                )
+                            })
+                            .inspect_err(|_| chunk_sender.abandon())?;
+                            if chunk_sender.send(sequence, encoded_chunk).is_err() {
+                                bail!("Compression stopped before all blocks were written!");
+                            }
+                            Ok(())
+                        },
+                    )
+                })
+            },
+        )?;
+
+        // Chunks arrive in file order, so key order is checked across neighbouring frames
+        let mut last_key: Option<String> = None;
+        for (sequence, encoded_chunk) in chunk_receiver.enumerate() {
+            if compression_options.assume_sorted {
+                let Some(key_range) = &encoded_chunk.key_range else {
+                    bail!(PipelineError::Usage(format!(
+                        "The records of block {} are out of key order!",
+                        sequence
+                    )));
+                };
+                if last_key.as_ref().is_some_and(|k| key_range.first < *k) {
+                    bail!(PipelineError::Usage(format!(
+                        "The records of block {} are out of key order, as '{}' follows '{}'!",
+                        sequence,
+                        key_range.first,
+                        last_key.unwrap_or_default()
+                    )));
+                }
+                last_key = Some(key_range.last.clone());
+            }
+            idx_records.push(write_encoded_chunk(
+                zstd_writer,
+                encoded_chunk,
+                sequence as u64,
+                compression_options,
+            )?);
+        }
+
+        match encoder_handle.join() {
+            Ok(r) => r,
+            Err(_) => bail!("The chunk encoder thread panicked!"),
+        }
+    })?;
+
+    finish_archive(zstd_writer, &idx_records, compression_options)?;
+    Ok(idx_records)
+}
+
+pub fn write_indexed_zstd<R: BufRead, W: Write + Seek, I: Write>(
+    input_reader: R,
+    mut zstd_writer: W,
+    idx_writer: I,
+    block_size: usize,
+    zstd_level: i32,
+    compression_options: &CompressionOptions,
+) -> Result<Vec<FrameMeta>> {
+    let idx_records = compress_frames(
+        input_reader,
+        &mut zstd_writer,
+        block_size,
+        zstd_level,
+        compression_options,
+    )?;
+    write_index(idx_writer, None, &idx_records)?;
+
+    Ok(idx_records)
+}
+
+/// Write out the index file. Given a header, the frames are listed beneath it in an object rather
+/// than as a bare list.
+pub(crate) fn write_index<I: Write>(
+    mut idx_writer: I,
+    index_header: Option<&IndexHeader>,
+    idx_records: &[FrameMeta],
+) -> Result<()> {
+    match index_header {
+        Some(header) => serde_json::to_writer_pretty(
+            &mut idx_writer,
+            &HeadedIndex {
+                header,
+                frames: idx_records,
+            },
+        )?,
+        None => serde_json::to_writer_pretty(&mut idx_writer, idx_records)?,
+    }
+    idx_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Codec;
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufReader, BufWriter};
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn open_file_write(file_path: &str) -> File {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_read_chunk_single() {
+        // Read with a block too small for a single line to ensure that reading does
+        // proceed until the end of the line.
+        let input_handle = open_file_read("test/data.txt");
+        let mut input_reader: BufReader<File> = BufReader::new(input_handle);
+
+        let mut read_buffer = String::new();
+        let result = read_chunk(&mut input_reader, &mut read_buffer, 5, false);
+
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_some());
+        // Evaluate the data read into `read_buffer`
+        assert_eq!("WP_413685322.1\t584\n", read_buffer);
+    }
+
+    #[test]
+    fn test_read_chunk_multi() {
+        // Read with a block too small for the whole file, but to cover several lines, to
+        // confirm expected use case of multi-line reading.
+        let input_handle = open_file_read("test/data.txt");
+        let mut input_reader: BufReader<File> = BufReader::new(input_handle);
+
+        let exp_content = concat!(
+            "WP_413685322.1\t584\nXNR99298.1\t584\nMEX9938374.1\t587\nKJX92028.1\t1047168\n",
+            "EFG1759503.1\t562\nEGJ4377881.1\t562\nEJZ1046351.1\t562\nEOA4653345.1\t562\n",
+            "EOP3024222.1\t562\nWP_198835266.1\t2779367\nMBJ2149627.1\t2779367\n",
+            "MBD3193859.1\t2053489\n"
+        );
+
+        let mut read_buffer = String::new();
+        let result = read_chunk(&mut input_reader, &mut read_buffer, 200, false);
+
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_some());
+        assert_eq!(exp_content, read_buffer);
+    }
+
+    #[test]
+    fn test_read_chunk_file() {
+        // Test the behaviour of the function over the complete file.
+        let input_handle = open_file_read("test/data.txt");
+        let mut input_reader: BufReader<File> = BufReader::new(input_handle);
+
+        let exp_content: Vec<String> = vec!(
+            "WP_413685322.1\t584\nXNR99298.1\t584\nMEX9938374.1\t587\nKJX92028.1\t1047168\n".into(),
+            "EFG1759503.1\t562\nEGJ4377881.1\t562\nEJZ1046351.1\t562\nEOA4653345.1\t562\nEOP3024222.1\t562\n".into(),
+            "WP_198835266.1\t2779367\nMBJ2149627.1\t2779367\nMBD3193859.1\t2053489\nMBD3198741.1\t2053489\n".into(),
+            "MBR5368159.1\t1898203\nMCL6526161.1\t2614257\nUXB85809.1\t2697049\nMDO5780201.1\t1506\n".into(),
+            "MDP1794720.1\t2201156\nMDE2592313.1\t1911520\nUMM52736.1\t2922427\nMDB4345056.1\t1869227\n".into(),
+            "XP_035011836.2\t195615\nMDY3279706.1\t2831996\nPYI97175.1\t2026799\nPYJ33862.1\t2026799\n".into(),
+            "WP_137987990.1\t492670\nTKZ18939.1\t492670\nWP_372757791.1\t1979402\nKLA26572.1\t1396\n".into(),
+            "GAA1911923.1\t433649\n".into(),
+        );
+
+        let mut read_buffer = String::new();
+        let mut obs_results: Vec<String> = Vec::new();
+
+        while let Ok(Some(_)) = read_chunk(&mut input_reader, &mut read_buffer, 70, false) {
+            let content = std::mem::take(&mut read_buffer);
+            obs_results.push(content);
+        }
+
+        assert_eq!(exp_content, obs_results);
+    }
+
+    #[test]
+    fn test_read_chunk_line_endings() {
+        // Confirm that CRLF line endings are normalised unless preservation is requested.
+        let input_file = "read_chunk_line_endings.txt";
+        let mut input_handle = open_file_write(input_file);
+        input_handle.write_all(b"a\t1\r\nb\t2\r\nc\t3\n").unwrap();
+        drop(input_handle);
+
+        let mut read_buffer = String::new();
+        let mut input_reader = BufReader::new(open_file_read(input_file));
+        let result = read_chunk(&mut input_reader, &mut read_buffer, 100, false);
+
+        assert_eq!(Some(14), result.unwrap());
+        assert_eq!("a\t1\nb\t2\nc\t3\n", read_buffer);
+
+        let mut read_buffer = String::new();
+        let mut input_reader = BufReader::new(open_file_read(input_file));
+        let result = read_chunk(&mut input_reader, &mut read_buffer, 100, true);
+
+        assert_eq!(Some(14), result.unwrap());
+        assert_eq!("a\t1\r\nb\t2\r\nc\t3\n", read_buffer);
+
+        let _ = std::fs::remove_file(input_file);
+    }
+
+    #[test]
+    fn test_read_chunk_trailing_blank_lines() {
+        let mut input_reader = "a\t1\nb\t2\n\n\r\n\nc\t3\n\n".as_bytes();
+
+        let mut obs_chunks: Vec<String> = Vec::new();
+        let mut read_buffer = String::new();
+        while read_chunk(&mut input_reader, &mut read_buffer, 1, true)
+            .unwrap()
+            .is_some()
+        {
+            obs_chunks.push(std::mem::take(&mut read_buffer));
+        }
+
+        let exp_chunks = vec!["a\t1\n", "b\t2\n\n\r\n\n", "c\t3\n\n"];
+        assert_eq!(exp_chunks, obs_chunks);
+    }
+
+    #[test]
+    fn test_choose_level() {
+        let repeated_bytes = "WP_413685322.1\t584\n".repeat(1000);
+        let mut state: u64 = 1;
+        let random_bytes: Vec<u8> = (0..20000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        assert_eq!(9, choose_level(repeated_bytes.as_bytes(), 9).unwrap());
+        assert_eq!(1, choose_level(&random_bytes, 9).unwrap());
+        assert_eq!(0, choose_level(&random_bytes, 0).unwrap());
+    }
+
+    #[test]
+    fn test_compress_frames_adaptive_level() {
+        let compression_options = CompressionOptions {
+            adaptive_level: true,
+            ..Default::default()
+        };
+
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let idx_buffer = compress_frames(
+            BufReader::new(open_file_read("test/data.txt")),
+            &mut zstd_writer,
+            200,
+            5,
+            &compression_options,
+        )
+        .unwrap();
+        assert!(idx_buffer.iter().all(|f| f.level().is_some()));
+
+        // Without the adaptive level, no level is recorded
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let idx_buffer = compress_frames(
+            BufReader::new(open_file_read("test/data.txt")),
+            &mut zstd_writer,
+            200,
+            5,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+        assert!(idx_buffer.iter().all(|f| f.level().is_none()));
+    }
+
+    #[test]
+    fn test_compress_frames_min_frame_size() {
+        // The last block holds a single short record
+        let content = "a\t1\nb\t2\nc\t3\nd\t4\ne\t5\n";
+        let compression_options = CompressionOptions {
+            min_frame_size: 5,
+            ..Default::default()
+        };
+
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let obs_unfolded = compress_frames(
+            content.as_bytes(),
+            &mut zstd_writer,
+            8,
+            3,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(3, obs_unfolded.len());
+
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let obs_folded = compress_frames(
+            content.as_bytes(),
+            &mut zstd_writer,
+            8,
+            3,
+            &compression_options,
+        )
+        .unwrap();
+        assert_eq!(2, obs_folded.len());
+
+        let mut obs_content = String::new();
+        zstd::stream::Decoder::new(std::io::Cursor::new(zstd_writer.into_inner()))
+            .unwrap()
+            .read_to_string(&mut obs_content)
+            .unwrap();
+        assert_eq!(content, obs_content);
+    }
+
+    #[test]
+    fn test_find_frame_outlier() {
+        let mut idx_records: Vec<FrameMeta> =
+            (0..5).map(|i| FrameMeta::new(i * 100, 100, i)).collect();
+        assert_eq!(None, find_frame_outlier(&idx_records));
+        assert_eq!(None, find_frame_outlier(&[]));
+
+        // A frame of exactly the outlier factor times the median is let through
+        idx_records.push(FrameMeta::new(500, 10000, 5));
+        assert_eq!(None, find_frame_outlier(&idx_records));
+
+        idx_records[5].length = 10001;
+        assert_eq!(Some((5, 10001, 100)), find_frame_outlier(&idx_records));
+    }
+
+    #[test]
+    fn test_compress_files_parallel() {
+        let input_file = "compress_files_parallel.txt";
+        let content = "a\t1\nb\t2\r\n\n\r\nc\t3\nlong_key_for_one_block\t4\nd\t5\ne\t6";
+        std::fs::write(input_file, content).unwrap();
+
+        for compression_options in [
+            CompressionOptions::default(),
+            CompressionOptions {
+                preserve_line_endings: true,
+                min_frame_size: 6,
+                frame_metadata: true,
+                ..Default::default()
+            },
+        ] {
+            let mut exp_writer = std::io::Cursor::new(Vec::new());
+            let exp_records = compress_frames(
+                content.as_bytes(),
+                &mut exp_writer,
+                6,
+                3,
+                &compression_options,
+            )
+            .unwrap();
+
+            let mut obs_writer = std::io::Cursor::new(Vec::new());
+            let obs_records = compress_files_parallel(
+                &[open_file_read(input_file)],
+                &mut obs_writer,
+                6,
+                3,
+                3,
+                &compression_options,
+            )
+            .unwrap();
+
+            assert_eq!(exp_records, obs_records);
+            assert_eq!(exp_writer.into_inner(), obs_writer.into_inner());
+        }
+        let _ = std::fs::remove_file(input_file);
+    }
+
+    #[test]
+    fn test_compress_files_parallel_shards() {
+        let shard_files = [
+            "compress_files_parallel_0.txt",
+            "compress_files_parallel_1.txt",
+        ];
+        std::fs::write(shard_files[0], "a\t1\nb\t2\nc\t3").unwrap();
+        std::fs::write(shard_files[1], "d\t4\ne\t5\n").unwrap();
+
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let obs_records = compress_files_parallel(
+            &shard_files.map(open_file_read),
+            &mut zstd_writer,
+            8,
+            3,
+            2,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+        for shard_file in shard_files {
+            let _ = std::fs::remove_file(shard_file);
+        }
+
+        // The first shard ends part way through a line, which is ended rather than run into the
+        // second shard, and its short last block is not joined to the second shard
+        let exp_orders: Vec<u64> = (0..3).collect();
+        assert_eq!(
+            exp_orders,
+            obs_records.iter().map(|f| f.order).collect::<Vec<u64>>()
+        );
+
+        let mut obs_content = String::new();
+        zstd::stream::Decoder::new(std::io::Cursor::new(zstd_writer.into_inner()))
+            .unwrap()
+            .read_to_string(&mut obs_content)
+            .unwrap();
+        assert_eq!("a\t1\nb\t2\nc\t3\nd\t4\ne\t5\n", obs_content);
+    }
+
+    #[test]
+    fn test_compress_files_parallel_unsorted() {
+        let compression_options = CompressionOptions {
+            assume_sorted: true,
+            ..Default::default()
+        };
+
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let obs_result = compress_files_parallel(
+            &[open_file_read("test/data.txt")],
+            &mut zstd_writer,
+            200,
+            3,
+            2,
+            &compression_options,
+        );
+
+        let obs_error = obs_result.unwrap_err();
+        assert_eq!(crate::ErrorClass::Usage, crate::ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_compress_frames_empty() {
+        // An empty input gives an empty archive, which decompresses to an empty map
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let idx_buffer = compress_frames(
+            &b""[..],
+            &mut zstd_writer,
+            8,
+            3,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+        assert!(idx_buffer.is_empty());
+        assert!(zstd_writer.get_ref().is_empty());
+
+        let (obs_map, obs_summary) = crate::decompress_source::<String, u64>(
+            &zstd_writer,
+            idx_buffer,
+            2,
+            &crate::Mode::Vector,
+            &crate::ParseOptions::default(),
+        )
+        .unwrap();
+        assert!(obs_map.is_empty());
+        assert!(!obs_summary.is_partial());
+    }
+
+    #[test]
+    fn test_frame_key_range() {
+        let content = b"readID\tseqID\ttaxID\nr1\tWP_1.1\t562\nr2\tWP_2.1\t562\nr2\tWP_3.1\t9606\n";
+
+        let exp_range = KeyRange {
+            first: "r1".into(),
+            last: "r2".into(),
+        };
+        let obs_range = frame_key_range(content, &RecordFormat::Centrifuge);
+        assert_eq!(Some(exp_range), obs_range);
+
+        // Records out of key order have no range
+        let content = b"WP_2.1\t562\nWP_1.1\t562\n";
+        assert_eq!(None, frame_key_range(content, &RecordFormat::Tsv));
+    }
+
+    #[test]
+    fn test_check_key_order() {
+        let mut last_key: Option<Vec<u8>> = None;
+
+        let obs_lines = check_key_order("a\t1\nb\t2\n", 0, &mut last_key, &RecordFormat::Tsv);
+        assert_eq!(2, obs_lines.unwrap());
+        assert_eq!(Some(b"b".to_vec()), last_key);
+
+        // Repeated keys are in order, but a key before the last of the previous chunk is not
+        let obs_lines = check_key_order("b\t3\nc\t4\n", 2, &mut last_key, &RecordFormat::Tsv);
+        assert_eq!(4, obs_lines.unwrap());
+
+        let obs_error =
+            check_key_order("d\t5\na\t6\n", 4, &mut last_key, &RecordFormat::Tsv).unwrap_err();
+        assert_eq!(
+            "Record on line 6 is out of key order, as 'a' follows 'd'!",
+            obs_error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_write_indexed_zstd_assume_sorted() {
+        let zstd_file = "write_indexed_zstd_assume_sorted.zstd";
+        let index_file = "write_indexed_zstd_assume_sorted.zstd.idx";
+        let compression_options = CompressionOptions {
+            assume_sorted: true,
+            ..Default::default()
+        };
+
+        // The example records are not sorted
+        let obs_result = write_indexed_zstd(
+            BufReader::new(open_file_read("test/data.txt")),
+            open_file_write(zstd_file),
+            BufWriter::new(open_file_write(index_file)),
+            200,
+            0,
+            &compression_options,
+        );
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(index_file);
+
+        let obs_error = obs_result.unwrap_err();
+        assert_eq!(crate::ErrorClass::Usage, crate::ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_is_valid_record() {
+        assert!(is_valid_record("WP_413685322.1\t584", &RecordFormat::Tsv));
+        assert!(is_valid_record("WP_413685322.1\t584\r", &RecordFormat::Tsv));
+        assert!(is_valid_record(
+            "WP_413685322.1\t<deleted>",
+            &RecordFormat::Tsv
+        ));
+
+        assert!(!is_valid_record("WP_413685322.1", &RecordFormat::Tsv));
+        assert!(!is_valid_record("\t584", &RecordFormat::Tsv));
+        assert!(!is_valid_record("WP_413685322.1\tq", &RecordFormat::Tsv));
+        assert!(!is_valid_record(
+            "WP_413685322.1\t584\t1",
+            &RecordFormat::Tsv
+        ));
+    }
+
+    #[test]
+    fn test_validate_chunk_report() {
+        let content = "a\t1\nb\tq\nc\t3\n";
+
+        let obs_result = validate_chunk(content, 10, &Validation::Report, &RecordFormat::Tsv);
+        assert!(obs_result.is_ok());
+        assert_eq!(13, obs_result.unwrap());
+    }
+
+    #[test]
+    fn test_validate_chunk_reject() {
+        let content = "a\t1\nb\tq\nc\t3\n";
+
+        let obs_result = validate_chunk(content, 10, &Validation::Reject, &RecordFormat::Tsv);
+        assert!(obs_result.is_err());
+        assert_eq!(
+            "Malformed record on line 12: 'b\tq'",
+            obs_result.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_is_valid_record_formats() {
+        let kraken_line = "C\tread_1\t562\t150\t562:116 0:0";
+        assert!(is_valid_record(kraken_line, &RecordFormat::Kraken2));
+        assert!(!is_valid_record(kraken_line, &RecordFormat::Tsv));
+
+        let kraken_names_line = "C\tread_1\tEscherichia coli (taxid 562)\t150\t562:116";
+        assert!(is_valid_record(kraken_names_line, &RecordFormat::Kraken2));
+
+        let centrifuge_line = "read_1\tNC_000913.3\t562\t4225\t0\t80\t150\t1";
+        assert!(is_valid_record(centrifuge_line, &RecordFormat::Centrifuge));
+    }
+
+    #[test]
+    fn test_validate_chunk_centrifuge_header() {
+        let content = concat!(
+            "readID\tseqID\ttaxID\tscore\t2ndBestScore\thitLength\tqueryLength\tnumMatches\n",
+            "read_1\tNC_000913.3\t562\t4225\t0\t80\t150\t1\n",
+        );
+
+        let obs_result = validate_chunk(content, 0, &Validation::Reject, &RecordFormat::Centrifuge);
+        assert!(obs_result.is_ok());
+        assert_eq!(2, obs_result.unwrap());
+    }
+
+    #[test]
+    fn test_encode_zstd_block_single() {
+        let target_file = "encode_zstd_block_single.zstd";
+        let target_handle = open_file_write(target_file);
+
+        let content = "test string for compression!";
+
+        let obs_result = encode_zstd_block(&mut &target_handle, content.as_bytes(), 0);
+        assert!(obs_result.is_ok());
+
+        let (start, stop) = obs_result.unwrap();
+        assert_eq!((0, 41), (start, stop));
+
+        drop(target_handle);
+        let _ = std::fs::remove_file(target_file);
+    }
+
+    #[test]
+    fn test_encode_zstd_block_multiple() {
+        let target_file = "encode_zstd_block_multiple.zstd";
+        let target_handle = open_file_write(target_file);
+
+        let full_content: Vec<(String, (u64, u64))> = vec![
+            ("first entry!".into(), (0, 25)),
+            ("second entry!".into(), (25, 51)),
+            ("third entry!".into(), (51, 76)),
+        ];
+
+        for (content, (exp_start, exp_stop)) in &full_content {
+            let obs_result = encode_zstd_block(&mut &target_handle, content.as_bytes(), 0);
+            assert!(obs_result.is_ok());
+
+            let exp_values = (*exp_start, *exp_stop);
+            assert_eq!(exp_values, obs_result.unwrap());
+        }
+
+        drop(target_handle);
+        let _ = std::fs::remove_file(target_file);
+    }
+
+    #[test]
+    fn test_write_indexed_zstd_single_frame() {
+        // Set up in the input reader/writers for the function arguments
+        let input_handle = open_file_read("test/data.txt");
+        let input_reader: BufReader<File> = BufReader::new(input_handle);
+
+        let zstd_file = "write_indexed_zstd_single_frame.zstd";
+        let zstd_handle = open_file_write(zstd_file);
+
+        let index_file = "write_indexed_zstd_single_frame.zstd.idx";
+        let index_handle = open_file_write(index_file);
+        let index_writer: BufWriter<File> = BufWriter::new(index_handle);
+
+        // Execute the command
+        let obs_result = write_indexed_zstd(
+            input_reader,
+            zstd_handle,
+            index_writer,
+            200,
+            0,
+            &CompressionOptions::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        // Decompress only the first block in the zstd file to check that the blocks
+        // are being created correctly.
+        let exp_zstd_frame = concat!(
+            "WP_413685322.1\t584\nXNR99298.1\t584\nMEX9938374.1\t587\nKJX92028.1\t1047168\n",
+            "EFG1759503.1\t562\nEGJ4377881.1\t562\nEJZ1046351.1\t562\nEOA4653345.1\t562\n",
+            "EOP3024222.1\t562\nWP_198835266.1\t2779367\nMBJ2149627.1\t2779367\n",
+            "MBD3193859.1\t2053489\n",
+        );
+
+        let mut obs_zstd = String::new();
+        let mut decoder = zstd::stream::Decoder::new(open_file_read(zstd_file))
+            .unwrap()
+            .single_frame();
+        let _ = decoder.read_to_string(&mut obs_zstd);
+
+        assert_eq!(exp_zstd_frame, obs_zstd);
+
+        // Clean up
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(index_file);
+    }
+
+    #[test]
+    fn test_write_indexed_zstd_complete() {
+        // Set up in the input reader/writers for the function arguments
+        let input_handle = open_file_read("test/data.txt");
+        let input_reader: BufReader<File> = BufReader::new(input_handle);
+
+        let zstd_file = "write_indexed_zstd_complete.zstd";
+        let zstd_handle = open_file_write(zstd_file);
+
+        let index_file = "write_indexed_zstd_complete.zstd.idx";
+        let index_handle = open_file_write(index_file);
+        let index_writer: BufWriter<File> = BufWriter::new(index_handle);
+
+        // Execute the command
+        let obs_result = write_indexed_zstd(
+            input_reader,
+            zstd_handle,
+            index_writer,
+            200,
+            0,
+            &CompressionOptions::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        // Decompress the zstd file and compare against the expected payload
+        // This just checks that compression worked
+        let exp_zstd = std::fs::read_to_string("test/data.txt").unwrap();
+
+        let mut obs_zstd = String::new();
+        let mut decoder = zstd::stream::Decoder::new(open_file_read(zstd_file)).unwrap();
+        let _ = decoder.read_to_string(&mut obs_zstd);
+
+        assert_eq!(exp_zstd, obs_zstd);
+
+        // Compare the contents of the JSON file against the expected payload
+        // This checks that compression was performed in the expected blocks
+        let exp_json: Vec<FrameMeta> =
+            serde_json::from_reader(open_file_read("test/example.zstd.idx")).unwrap();
+        let obs_json: Vec<FrameMeta> = serde_json::from_reader(open_file_read(index_file)).unwrap();
+
+        assert_eq!(exp_json, obs_json);
+
+        // Clean up
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(index_file);
+    }
+
+    #[test]
+    fn test_write_indexed_zstd_gzip_input() {
+        let gzip_file = "write_indexed_zstd_gzip_input.gz";
+        let mut encoder = flate2::write::GzEncoder::new(
+            open_file_write(gzip_file),
+            flate2::Compression::default(),
+        );
+        encoder
+            .write_all(&std::fs::read("test/data.txt").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let zstd_file = "write_indexed_zstd_gzip_input.zstd";
+        let index_file = "write_indexed_zstd_gzip_input.zstd.idx";
+        let compression_options = CompressionOptions {
+            input_codec: Some(Codec::Gzip),
+            ..Default::default()
+        };
+
+        let obs_result = write_indexed_zstd(
+            BufReader::new(flate2::read::MultiGzDecoder::new(open_file_read(gzip_file))),
+            open_file_write(zstd_file),
+            BufWriter::new(open_file_write(index_file)),
+            200,
+            0,
+            &compression_options,
+        );
+        assert!(obs_result.is_ok());
+
+        let exp_json: Vec<FrameMeta> =
+            serde_json::from_reader(open_file_read("test/example.zstd.idx")).unwrap();
+        let obs_json: Vec<FrameMeta> = serde_json::from_reader(open_file_read(index_file)).unwrap();
+        assert_eq!(exp_json, obs_json);
+
+        // A truncated stream is reported as corrupt, rather than silently ending the output
+        let gzip_bytes = std::fs::read(gzip_file).unwrap();
+        let obs_result = write_indexed_zstd(
+            BufReader::new(flate2::read::MultiGzDecoder::new(
+                &gzip_bytes[..gzip_bytes.len() - 20],
+            )),
+            open_file_write(zstd_file),
+            BufWriter::new(open_file_write(index_file)),
+            200,
+            0,
+            &compression_options,
+        );
+
+        let _ = std::fs::remove_file(gzip_file);
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(index_file);
+
+        assert!(obs_result.unwrap_err().is::<PipelineError>());
+    }
+
+    #[test]
+    fn test_frame_metadata_bytes() {
+        let exp_metadata = FrameMetadata {
+            order: 2,
+            length: 120,
+            uncompressed_length: 158,
+            checksum: 0xDEAD_BEEF_0BAD_F00D,
+        };
+        let obs_bytes = exp_metadata.to_bytes();
+
+        assert_eq!(2u64.to_le_bytes(), obs_bytes[0..8]);
+        assert_eq!(exp_metadata, FrameMetadata::from_bytes(&obs_bytes));
+    }
+
+    #[test]
+    fn test_write_indexed_zstd_frame_metadata() {
+        let zstd_file = "write_indexed_zstd_frame_metadata.zstd";
+        let index_file = "write_indexed_zstd_frame_metadata.zstd.idx";
+        let compression_options = CompressionOptions {
+            frame_metadata: true,
+            ..Default::default()
+        };
+
+        let obs_result = write_indexed_zstd(
+            BufReader::new(open_file_read("test/data.txt")),
+            open_file_write(zstd_file),
+            BufWriter::new(open_file_write(index_file)),
+            200,
+            0,
+            &compression_options,
+        );
+        assert!(obs_result.is_ok());
+
+        let obs_payload = std::fs::read(zstd_file).unwrap();
+        let obs_json: Vec<FrameMeta> = serde_json::from_reader(open_file_read(index_file)).unwrap();
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(index_file);
+
+        // Skippable frames are ignored by ordinary zstd decoders
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        assert_eq!(exp_content, zstd::decode_all(&obs_payload[..]).unwrap());
+
+        // Each data frame is preceded by its metadata, with the index pointing past it
+        let mut exp_start: usize = 0;
+        for frame_meta in &obs_json {
+            let frame_start = frame_meta.position as usize;
+            let metadata_bytes = &obs_payload[frame_start - FRAME_METADATA_SIZE..frame_start];
+            let obs_metadata = FrameMetadata::from_bytes(metadata_bytes.try_into().unwrap());
+
+            let frame_bytes = &obs_payload[frame_start..][..frame_meta.length as usize];
+            let frame_content = zstd::decode_all(frame_bytes).unwrap();
+
+            assert_eq!(exp_start + 8 + FRAME_METADATA_SIZE, frame_start);
+            assert_eq!(frame_meta.order, obs_metadata.order);
+            assert_eq!(frame_meta.length, obs_metadata.length);
+            assert_eq!(frame_content.len() as u64, obs_metadata.uncompressed_length);
+            assert_eq!(frame_meta.checksum, Some(obs_metadata.checksum));
+            assert_eq!(xxh3_64(&frame_content), obs_metadata.checksum);
+
+            exp_start = frame_start + frame_meta.length as usize;
+        }
+    }
+
+    #[test]
+    fn test_compress_frames_in_memory() {
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        let compression_options = CompressionOptions {
+            frame_metadata: true,
+            ..Default::default()
+        };
+
+        // Frame metadata is filled in by seeking back, which a cursor supports as a file does
+        let mut zstd_writer = std::io::Cursor::new(Vec::new());
+        let idx_buffer = compress_frames(
+            &exp_content[..],
+            &mut zstd_writer,
+            200,
+            0,
+            &compression_options,
+        )
+        .unwrap();
+        assert_eq!(3, idx_buffer.len());
+
+        let (obs_map, obs_summary) = crate::decompression::read_indexed_zstd_vector::<String, u64>(
+            &zstd_writer,
+            idx_buffer,
+            2,
+            &crate::ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(30, obs_map.len());
+        assert!(!obs_summary.is_partial());
+        assert_eq!(
+            exp_content,
+            zstd::decode_all(&zstd_writer.get_ref()[..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_indexed_zstd_align() {
+        let zstd_file = "write_indexed_zstd_align.zstd";
+        let index_file = "write_indexed_zstd_align.zstd.idx";
+
+        for frame_metadata in [false, true] {
+            let compression_options = CompressionOptions {
+                frame_metadata,
+                align: Some(128),
+                ..Default::default()
+            };
+
+            let obs_result = write_indexed_zstd(
+                BufReader::new(open_file_read("test/data.txt")),
+                open_file_write(zstd_file),
+                BufWriter::new(open_file_write(index_file)),
+                200,
+                0,
+                &compression_options,
+            );
+            assert!(obs_result.is_ok());
+
+            let obs_payload = std::fs::read(zstd_file).unwrap();
+            let obs_json: Vec<FrameMeta> =
+                serde_json::from_reader(open_file_read(index_file)).unwrap();
+
+            // Every frame, and the end of the file, falls on the alignment
+            assert!(obs_json.iter().all(|f| f.position % 128 == 0));
+            assert_eq!(0, obs_payload.len() % 128);
+            assert_eq!(
+                std::fs::read("test/data.txt").unwrap(),
+                zstd::decode_all(&obs_payload[..]).unwrap()
+            );
+
+            // The padding is skipped when scanning, so the frames are found as indexed
+            let obs_frames = crate::scan::scan_zstd_file(zstd_file).unwrap();
+            let exp_positions: Vec<u64> = obs_json.iter().map(|f| f.position).collect();
+            let obs_positions: Vec<u64> = obs_frames.iter().map(|f| f.position).collect();
+            assert_eq!(exp_positions, obs_positions);
+        }
+
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(index_file);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{Mode, PipelineError};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Site or user defaults for command line options, read from a TOML file. Values given on the
+/// command line, or through the matching 'PD_*' environment variable, take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub block_size: Option<String>,
+    pub level: Option<i32>,
+    pub num_threads: Option<usize>,
+    pub mode: Option<Mode>,
+    /// Size the estimated peak memory of a decompression may not exceed, such as '48GiB'
+    pub memory_budget: Option<String>,
+}
+
+/// Named sets of compression defaults for common uses of an archive, so that a tuned combination
+/// of flags can be asked for by name.
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum Profile {
+    /// Long-term storage: 16MiB blocks at level 19, with frame metadata so that a lost index can
+    /// be rebuilt
+    Archive,
+    /// 1MiB blocks at level 6, with frame metadata
+    Balanced,
+    /// 4MiB blocks at level 1, read and compressed on every available thread
+    Fast,
+    /// Archives read over NFS or HTTP: 4MiB blocks at level 12, with frame metadata, each frame
+    /// starting on a 64KiB boundary
+    Network,
+}
+
+/// Compression defaults set by a profile. Values given on the command line take precedence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileDefaults {
+    pub block_size: &'static str,
+    pub level: i32,
+    pub frame_metadata: bool,
+    pub align: Option<u64>,
+    /// Read and compress the input on as many threads as the resources of the machine allow
+    pub parallel_read: bool,
+}
+
+impl Profile {
+    pub fn defaults(&self) -> ProfileDefaults {
+        match self {
+            Profile::Archive => ProfileDefaults {
+                block_size: "16MiB",
+                level: 19,
+                frame_metadata: true,
+                align: None,
+                parallel_read: false,
+            },
+            Profile::Balanced => ProfileDefaults {
+                block_size: "1MiB",
+                level: 6,
+                frame_metadata: true,
+                align: None,
+                parallel_read: false,
+            },
+            Profile::Fast => ProfileDefaults {
+                block_size: "4MiB",
+                level: 1,
+                frame_metadata: false,
+                align: None,
+                parallel_read: true,
+            },
+            // Frames start on the boundaries remote reads are made on, so that reading one frame
+            // does not fetch the tail of the frame before it
+            Profile::Network => ProfileDefaults {
+                block_size: "4MiB",
+                level: 12,
+                frame_metadata: true,
+                align: Some(64 * 1024),
+                parallel_read: false,
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Location of the configuration file, either named by 'PD_CONFIG' or found under the user's
+    /// configuration directory.
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(p) = std::env::var_os("PD_CONFIG") {
+            return Some(PathBuf::from(p));
+        }
+
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(d) if !d.is_empty() => PathBuf::from(d),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(
+            config_dir
+                .join("parallel_decompression")
+                .join("config.toml"),
+        )
+    }
+
+    /// Read the configuration file, treating a missing file as one with no defaults set.
+    pub fn load(config_file: &Path) -> Result<Config> {
+        let config_content = match std::fs::read_to_string(config_file) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Unable to read config file '{}'", config_file.display())
+                })
+            }
+        };
+
+        match toml::from_str(&config_content) {
+            Ok(c) => Ok(c),
+            Err(e) => bail!(PipelineError::Usage(format!(
+                "Unable to parse config file '{}': {}",
+                config_file.display(),
+                e.message()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_config_load() {
+        let config_file = "config_load.toml";
+        std::fs::write(
+            config_file,
+            "block_size = \"1MiB\"\nnum_threads = 8\nmode = \"merge\"\nmemory_budget = \"48GiB\"\n",
+        )
+        .unwrap();
+
+        let obs_config = Config::load(Path::new(config_file));
+        let _ = std::fs::remove_file(config_file);
+
+        let exp_config = Config {
+            block_size: Some(String::from("1MiB")),
+            level: None,
+            num_threads: Some(8),
+            mode: Some(Mode::Merge),
+            memory_budget: Some(String::from("48GiB")),
+        };
+        assert_eq!(exp_config, obs_config.unwrap());
+    }
+
+    #[test]
+    fn test_profile_defaults() {
+        for profile in Profile::value_variants() {
+            let defaults = profile.defaults();
+            assert!(crate::parse_block_input(defaults.block_size).is_ok());
+            assert!(defaults.align.is_none_or(|a| a.is_power_of_two()));
+        }
+        assert_eq!(1, Profile::Fast.defaults().level);
+    }
+
+    #[test]
+    fn test_config_load_missing() {
+        let obs_config = Config::load(Path::new("config_load_missing.toml"));
+        assert_eq!(Config::default(), obs_config.unwrap());
+    }
+
+    #[test]
+    fn test_config_load_invalid() {
+        let config_file = "config_load_invalid.toml";
+        std::fs::write(config_file, "threads = 8\n").unwrap();
+
+        let obs_config = Config::load(Path::new(config_file));
+        let _ = std::fs::remove_file(config_file);
+
+        // Misspelt keys are rejected rather than silently ignored
+        match obs_config {
+            Ok(_) => panic!("Expected the unknown key to be rejected!"),
+            Err(e) => assert!(e.is::<PipelineError>()),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::serve::lookup_value;
+use crate::snapshot::for_each_record;
+use crate::source::FrameSource;
+use crate::{
+    decompress_with_keys, EitherMap, FrameMeta, Mode, ParseOptions, RecordKey, RecordValue,
+};
+use anyhow::Result;
+use std::fmt::Display;
+
+// Divergent keys listed in the report, beyond which they are only counted
+const MAX_LISTED_KEYS: usize = 100;
+
+/// A key whose record differs between the maps built by two modes, with its value in each.
+/// `None` stands for a key missing from that map.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DivergentKey {
+    pub key: String,
+    pub value: Option<String>,
+    pub other_value: Option<String>,
+}
+
+/// Outcome of building the map of an archive under a second mode and comparing the two.
+#[derive(Clone, Debug)]
+pub struct CrossCheckReport {
+    /// Mode of the run, and the mode it was checked against
+    pub modes: (Mode, Mode),
+    /// Keys held by either map
+    pub keys_compared: usize,
+    /// Keys whose records differ between the maps
+    pub divergent_keys: usize,
+    /// The first of the divergent keys, in key order
+    pub divergent: Vec<DivergentKey>,
+}
+
+impl CrossCheckReport {
+    pub fn is_identical(&self) -> bool {
+        self.divergent_keys == 0
+    }
+}
+
+//region: Private functions
+
+fn to_divergent<K: RecordKey, V: Display>(
+    key: &K,
+    value: Option<&V>,
+    other_value: Option<&V>,
+) -> DivergentKey {
+    DivergentKey {
+        key: String::from_utf8_lossy(&key.key_bytes()).to_string(),
+        value: value.map(|v| v.to_string()),
+        other_value: other_value.map(|v| v.to_string()),
+    }
+}
+
+/// Compare two maps key by key, returning the keys held by either and those which differ.
+fn compare_maps<K: RecordKey, V: RecordValue + Clone + PartialEq + Display>(
+    record_map: &EitherMap<K, V>,
+    other_map: &EitherMap<K, V>,
+) -> Result<(usize, Vec<DivergentKey>)> {
+    let mut keys_compared: usize = 0;
+    let mut divergent: Vec<DivergentKey> = Vec::new();
+
+    for_each_record(record_map, |key, value| {
+        keys_compared += 1;
+        let other_value = lookup_value(other_map, key);
+        if other_value.as_ref() != Some(value) {
+            divergent.push(to_divergent(key, Some(value), other_value.as_ref()));
+        }
+        Ok(())
+    })?;
+    for_each_record(other_map, |key, other_value| {
+        if lookup_value(record_map, key).is_none() {
+            keys_compared += 1;
+            divergent.push(to_divergent(key, None, Some(other_value)));
+        }
+        Ok(())
+    })?;
+
+    divergent.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok((keys_compared, divergent))
+}
+
+//endregion:
+
+/// Build the map of the archive again under another mode, after the first, and compare it with
+/// the map of the run. Both maps are held at once. The second build is kept out of the events,
+/// byte counts and profile of the run, so these describe the run as it would be without the
+/// check.
+pub(crate) fn cross_check<K, V>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    record_map: &EitherMap<K, V>,
+    modes: (&Mode, &Mode),
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<CrossCheckReport>
+where
+    K: RecordKey,
+    V: RecordValue + Clone + PartialEq + Display,
+{
+    let parse_options = ParseOptions {
+        events: None,
+        byte_counts: None,
+        profiler: None,
+        ..parse_options.clone()
+    };
+    let (other_map, _) =
+        decompress_with_keys::<K, V>(source, idx_buffer, modes.1, num_threads, &parse_options)?;
+
+    let (keys_compared, mut divergent) = compare_maps(record_map, &other_map)?;
+    let divergent_keys = divergent.len();
+    divergent.truncate(MAX_LISTED_KEYS);
+
+    Ok(CrossCheckReport {
+        modes: (modes.0.clone(), modes.1.clone()),
+        keys_compared,
+        divergent_keys,
+        divergent,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::MemorySource;
+    use ahash::AHashMap;
+    use dashmap::DashMap;
+
+    #[test]
+    fn test_compare_maps() {
+        let record_map: EitherMap<String, u64> = EitherMap::Dash(DashMap::from_iter([
+            ("a".to_string(), 1),
+            ("b".to_string(), 2),
+            ("c".to_string(), 3),
+        ]));
+        let other_map: EitherMap<String, u64> = EitherMap::AHash(AHashMap::from_iter([
+            ("a".to_string(), 1),
+            ("b".to_string(), 5),
+            ("d".to_string(), 4),
+        ]));
+
+        let (obs_compared, obs_divergent) = compare_maps(&record_map, &other_map).unwrap();
+        assert_eq!(4, obs_compared);
+
+        let exp_divergent = vec![
+            DivergentKey {
+                key: "b".to_string(),
+                value: Some("2".to_string()),
+                other_value: Some("5".to_string()),
+            },
+            DivergentKey {
+                key: "c".to_string(),
+                value: Some("3".to_string()),
+                other_value: None,
+            },
+            DivergentKey {
+                key: "d".to_string(),
+                value: None,
+                other_value: Some("4".to_string()),
+            },
+        ];
+        assert_eq!(exp_divergent, obs_divergent);
+    }
+
+    #[test]
+    fn test_cross_check() {
+        let payload = std::fs::read("test/example.zstd").unwrap();
+        let idx_buffer: Vec<FrameMeta> =
+            serde_json::from_slice(&std::fs::read("test/example.zstd.idx").unwrap()).unwrap();
+        let source = MemorySource::new("example", payload);
+        let parse_options = ParseOptions::default();
+
+        let (record_map, _) = decompress_with_keys::<String, u64>(
+            &source,
+            idx_buffer.clone(),
+            &Mode::DashMap,
+            2,
+            &parse_options,
+        )
+        .unwrap();
+
+        let obs_report = cross_check(
+            &source,
+            idx_buffer,
+            &record_map,
+            (&Mode::DashMap, &Mode::Reference),
+            2,
+            &parse_options,
+        )
+        .unwrap();
+        assert!(obs_report.is_identical());
+        assert_eq!(30, obs_report.keys_compared);
+        assert_eq!((Mode::DashMap, Mode::Reference), obs_report.modes);
+    }
+}
@@ -1,320 +1,2568 @@
-use crate::{EitherMap, FrameMeta};
-use ahash::AHashMap;
-use anyhow::{bail, Result};
-use dashmap::DashMap;
-use rayon::prelude::*;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Cursor};
-use std::os::unix::fs::FileExt;
-
-//region: Private functions
-
-fn load_frame_index(index_file: &mut BufReader<File>) -> Result<Vec<FrameMeta>> {
-    let frame_vector: Vec<FrameMeta> = match serde_json::from_reader(index_file) {
-        Ok(v) => v,
-        Err(_) => bail!("Unable to load the zstd index!"),
-    };
-
-    Ok(frame_vector)
-}
-
-fn parse_bytes_to_numeric(bytes: &[u8]) -> Result<u64> {
-    let s = match str::from_utf8(bytes) {
-        Ok(v) => v,
-        Err(_) => bail!("Unable to parse record content. Taxid will be reported as '0'!"),
-    };
-
-    let taxid: u64 = match s.trim().parse() {
-        Ok(t) => t,
-        Err(_) => bail!("Unable to convert value to numeric. Taxid will be reported as '0'!"),
-    };
-
-    Ok(taxid)
-}
-
-fn parse_lines_to_map(buf: &[u8]) -> Vec<(String, u64)> {
-    let mut unpacked_data: Vec<(String, u64)> = Vec::new();
-
-    for line_repr in buf.split(|&b| b == b'\n') {
-        if let Some(tab_position) = line_repr.iter().position(|&b| b == b'\t') {
-            let accession = String::from_utf8_lossy(&line_repr[..tab_position]).to_string();
-
-            let taxid = match parse_bytes_to_numeric(&line_repr[tab_position + 1..]) {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("Error parsing record '{}'. {}", accession, e);
-                    0
-                }
-            };
-
-            unpacked_data.push((accession, taxid));
-        }
-    }
-    unpacked_data
-}
-
-fn map_zstd_frame(zstd_file: &str, idx_frame: FrameMeta) -> Result<Vec<(String, u64)>> {
-    let payload_length = idx_frame.parse_length()?;
-    let mut frame_payload = vec![0u8; payload_length];
-
-    let zstd_reader = OpenOptions::new().read(true).open(zstd_file)?;
-    zstd_reader.read_exact_at(&mut frame_payload, idx_frame.position)?;
-
-    let payload = zstd::decode_all(Cursor::new(frame_payload))?;
-    let payload_data = parse_lines_to_map(&payload);
-
-    Ok(payload_data)
-}
-
-//endregion:
-
-pub fn read_indexed_zstd_dashmap(
-    zstd_file: &str,
-    mut idx_reader: BufReader<File>,
-    num_threads: usize,
-) -> Result<EitherMap<String, u64>> {
-    let idx_buffer: Vec<FrameMeta> = load_frame_index(&mut idx_reader)?;
-    let record_map: DashMap<String, u64> = DashMap::new();
-
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .thread_name(|i| format!("decompression-worker-{i}"))
-        .build()
-        .unwrap();
-
-    pool.install(|| {
-        idx_buffer.into_iter().par_bridge().for_each(|idx_frame| {
-            match map_zstd_frame(zstd_file, idx_frame) {
-                Ok(payload_data) => {
-                    for (k, v) in payload_data {
-                        record_map.insert(k, v);
-                    }
-                }
-                Err(e) => eprintln!("{:#?}", e),
-            }
-        })
-    });
-
-    Ok(EitherMap::Dash(record_map))
-}
-
-pub fn read_indexed_zstd_vector(
-    zstd_file: &str,
-    mut idx_reader: BufReader<File>,
-    num_threads: usize,
-) -> Result<EitherMap<String, u64>> {
-    let idx_buffer: Vec<FrameMeta> = load_frame_index(&mut idx_reader)?;
-
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .thread_name(|i| format!("decompression-worker-{i}"))
-        .build()
-        .unwrap();
-
-    let record_buffer: Vec<Vec<(String, u64)>> = pool.install(|| {
-        idx_buffer
-            .into_iter()
-            .par_bridge()
-            .map(|idx_frame| map_zstd_frame(zstd_file, idx_frame))
-            .filter_map(Result::ok)
-            .collect()
-    });
-
-    // Condense into the returnable HashMap
-    let record_map: AHashMap<String, u64> = record_buffer.into_iter().flatten().collect();
-    Ok(EitherMap::AHash(record_map))
-}
-
-pub fn read_indexed_zstd_merge(
-    zstd_file: &str,
-    mut idx_reader: BufReader<File>,
-    num_threads: usize,
-) -> Result<EitherMap<String, u64>> {
-    let idx_buffer: Vec<FrameMeta> = load_frame_index(&mut idx_reader)?;
-
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .thread_name(|i| format!("decompression-worker-{i}"))
-        .build()
-        .unwrap();
-
-    let record_map: AHashMap<String, u64> = pool.install(|| {
-        idx_buffer
-            .into_iter()
-            .par_bridge()
-            .map(|idx_frame| map_zstd_frame(zstd_file, idx_frame))
-            .filter_map(Result::ok)
-            .into_par_iter()
-            .map(|pairs| {
-                let mut local = AHashMap::with_capacity(pairs.len());
-                for (k, v) in pairs {
-                    local.insert(k, v);
-                }
-                local
-            })
-            .reduce(AHashMap::new, |mut a, mut b| {
-                // Organise the HashMaps such that a is always larger than b
-                // This is quite a niche command, so not imported at start of file
-                if a.len() < b.len() {
-                    std::mem::swap(&mut a, &mut b);
-                }
-                a.reserve(b.len()); // Increase the capacity of larger to fit smaller
-                a.extend(b);
-                a
-            })
-    });
-
-    Ok(EitherMap::AHash(record_map))
-}
-
-#[cfg(test)]
-mod tests {
-
-    use super::*;
-    use std::fs::OpenOptions;
-    use std::io::BufRead;
-
-    fn open_file_read(file_path: &str) -> File {
-        OpenOptions::new().read(true).open(file_path).unwrap()
-    }
-
-    fn data_to_ahashmap(file_name: &str) -> AHashMap<String, u64> {
-        BufReader::new(open_file_read(file_name))
-            .lines()
-            .map(|line| {
-                let line_content = line.unwrap();
-
-                let (acc, rest) = line_content.trim().split_once('\t').unwrap();
-                let u: u64 = rest.parse().unwrap();
-
-                (acc.to_string(), u)
-            })
-            .collect()
-    }
-
-    #[test]
-    fn test_load_frame_index() {
-        let file_name = "test/example.zstd.idx";
-        let mut json_handle = BufReader::new(open_file_read(file_name));
-
-        let exp_content: Vec<FrameMeta> =
-            serde_json::from_reader(open_file_read(file_name)).unwrap();
-
-        let obs_result = load_frame_index(&mut json_handle);
-        assert!(obs_result.is_ok());
-
-        let obs_content = obs_result.unwrap();
-        assert_eq!(exp_content, obs_content);
-    }
-
-    #[test]
-    fn test_parse_bytes_to_numeric() {
-        let exp_value: u64 = 123;
-        let bytes_slice: &[u8] = "123".as_bytes();
-
-        let obs_result = parse_bytes_to_numeric(bytes_slice);
-        assert!(obs_result.is_ok());
-
-        let obs_value = obs_result.unwrap();
-        assert_eq!(exp_value, obs_value);
-    }
-
-    #[test]
-    fn test_parse_lines_to_map_success() {
-        let input_bytes = "a\t1\nb\t2\nc\t3\n".as_bytes();
-
-        let exp_vector: Vec<(String, u64)> =
-            vec![("a".into(), 1), ("b".into(), 2), ("c".into(), 3)];
-
-        let obs_vector = parse_lines_to_map(&input_bytes);
-        assert_eq!(exp_vector, obs_vector);
-    }
-
-    #[test]
-    fn test_parse_lines_to_map_fail() {
-        let input_bytes = "a\t1\nb\t2\nc\tq\n".as_bytes();
-
-        let exp_vector: Vec<(String, u64)> =
-            vec![("a".into(), 1), ("b".into(), 2), ("c".into(), 0)];
-
-        let obs_vector = parse_lines_to_map(&input_bytes);
-        assert_eq!(exp_vector, obs_vector);
-    }
-
-    #[test]
-    fn test_map_zstd_frame() {
-        // Take from the final block of the test data
-        let idx_frame = FrameMeta::new(301, 120, 2);
-        let input_file = "test/example.zstd";
-
-        let exp_vector: Vec<(String, u64)> = vec![
-            ("MDY3279706.1".into(), 2831996),
-            ("PYI97175.1".into(), 2026799),
-            ("PYJ33862.1".into(), 2026799),
-            ("WP_137987990.1".into(), 492670),
-            ("TKZ18939.1".into(), 492670),
-            ("WP_372757791.1".into(), 1979402),
-            ("KLA26572.1".into(), 1396),
-            ("GAA1911923.1".into(), 433649),
-        ];
-
-        let obs_result = map_zstd_frame(input_file, idx_frame);
-        assert!(obs_result.is_ok());
-
-        let obs_vector = obs_result.unwrap();
-
-        assert_eq!(exp_vector, obs_vector);
-    }
-
-    #[test]
-    fn test_read_indexed_zstd_dashmap() {
-        let input_file = "test/example.zstd";
-        let idx_reader = BufReader::new(open_file_read("test/example.zstd.idx"));
-
-        let exp_map: AHashMap<String, u64> = data_to_ahashmap("test/data.txt");
-
-        let obs_result = read_indexed_zstd_dashmap(input_file, idx_reader, 2);
-        assert!(obs_result.is_ok());
-
-        // DashMap does not implement PartialEq, so cast to HashMap for easy comparison.
-        match obs_result.unwrap().into_dash() {
-            Some(m) => {
-                let obs_map: AHashMap<String, u64> = m.into_iter().collect();
-                assert_eq!(exp_map, obs_map);
-            }
-            None => assert!(false, "Returned data was not of type DashMap"),
-        };
-    }
-
-    #[test]
-    fn test_read_indexed_zstd_vector() {
-        let input_file = "test/example.zstd";
-        let idx_reader = BufReader::new(open_file_read("test/example.zstd.idx"));
-
-        let exp_map: AHashMap<String, u64> = data_to_ahashmap("test/data.txt");
-
-        let obs_result = read_indexed_zstd_vector(input_file, idx_reader, 2);
-        assert!(obs_result.is_ok());
-
-        match obs_result.unwrap().into_ahash() {
-            Some(obs_map) => assert_eq!(exp_map, obs_map),
-            None => assert!(false, "Returned data was not of type AHashMap"),
-        };
-    }
-
-    #[test]
-    fn test_read_indexed_zstd_merge() {
-        let input_file = "test/example.zstd";
-        let idx_reader = BufReader::new(open_file_read("test/example.zstd.idx"));
-
-        let exp_map: AHashMap<String, u64> = data_to_ahashmap("test/data.txt");
-
-        let obs_result = read_indexed_zstd_merge(input_file, idx_reader, 2);
-        assert!(obs_result.is_ok());
-
-        match obs_result.unwrap().into_ahash() {
-            Some(obs_map) => assert_eq!(exp_map, obs_map),
-            None => assert!(false, "Returned data was not of type AHashMap"),
-        };
-    }
-}
+use crate::compact::TOMBSTONE;
+use crate::numa::build_worker_pool;
+use crate::profiling::Stage;
+use crate::scan::{GZIP_MAGIC, SKIPPABLE_MAGIC, SKIPPABLE_MAGIC_MASK, ZSTD_MAGIC};
+use crate::source::FrameSource;
+use crate::{
+    BadRecord, BadRecordPolicy, Codec, DecompressionSummary, DuplicatePolicy, EitherMap,
+    ErrorClass, Event, FailedFrame, FrameMeta, FrameSchedule, IndexHeader, ParseOptions,
+    PipelineError, RecordFormat, RecordKey, RecordValue,
+};
+use ahash::{AHashMap, RandomState};
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry as DashEntry;
+use dashmap::DashMap;
+use flate2::read::MultiGzDecoder;
+use indexmap::map::Entry as IndexEntry;
+use indexmap::IndexMap;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::hash_map::Entry as HashEntry;
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Records parsed from a frame, with those which could not be parsed.
+pub type FrameRecords<K, V> = (Vec<(K, V)>, Vec<BadRecord>);
+type OrderedFrame<T> = (u64, T, Vec<BadRecord>);
+
+/// Place of a record key within the payload of a frame view.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeySpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Records of a decoded frame whose keys are left in the frame payload, for consumers which
+/// read each record once and have no need of an owned key per record. The payload is shared,
+/// so a view is cheap to clone and hand between threads.
+#[derive(Clone, Debug)]
+pub struct FrameView<V> {
+    pub order: u64,
+    payload: Arc<[u8]>,
+    records: Vec<(KeySpan, V)>,
+}
+
+/// An index led by a header recording how the archive was made.
+#[derive(Deserialize)]
+struct HeadedIndex {
+    header: IndexHeader,
+    frames: Vec<FrameMeta>,
+}
+
+impl<V> FrameView<V> {
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The decoded content of the frame.
+    pub fn payload(&self) -> &Arc<[u8]> {
+        &self.payload
+    }
+
+    /// Key places and values of the records, in file order.
+    pub fn records(&self) -> &[(KeySpan, V)] {
+        &self.records
+    }
+
+    pub fn key(&self, key_span: KeySpan) -> &[u8] {
+        &self.payload[key_span.start..key_span.end]
+    }
+
+    /// The key as text, or None when it is not valid UTF-8.
+    pub fn key_str(&self, key_span: KeySpan) -> Option<&str> {
+        std::str::from_utf8(self.key(key_span)).ok()
+    }
+
+    /// Keys and values of the records, in file order, with the keys borrowed from the payload.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &V)> {
+        self.records.iter().map(|(k, v)| (self.key(*k), v))
+    }
+}
+
+//region: Private functions
+
+/// Peek at the first byte of the index beyond any leading whitespace.
+fn peek_index_start<R: BufRead>(index_reader: &mut R) -> Result<Option<u8>> {
+    loop {
+        let buffer = index_reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        match buffer.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => {
+                let start = buffer[i];
+                index_reader.consume(i);
+                return Ok(Some(start));
+            }
+            None => {
+                let skipped = buffer.len();
+                index_reader.consume(skipped);
+            }
+        }
+    }
+}
+
+/// Parse an index which is either a bare list of frames, or an object holding a header and the
+/// list of frames beneath it. The two are told apart by their first character, so the frames are
+/// read straight into place either way.
+fn parse_frame_index<R: Read>(index_reader: R) -> Result<(Option<IndexHeader>, Vec<FrameMeta>)> {
+    let mut index_reader = BufReader::new(index_reader);
+    let parsed = match peek_index_start(&mut index_reader)? {
+        Some(b'{') => serde_json::from_reader::<_, HeadedIndex>(index_reader)
+            .map(|index| (Some(index.header), index.frames)),
+        _ => {
+            serde_json::from_reader::<_, Vec<FrameMeta>>(index_reader).map(|frames| (None, frames))
+        }
+    };
+
+    match parsed {
+        Ok(index) => Ok(index),
+        Err(_) => bail!(PipelineError::CorruptArchive(
+            "Unable to load the zstd index!".into()
+        )),
+    }
+}
+
+/// Load a frame index, which may be gzip or zstd compressed for distribution. Compressed indexes
+/// are recognised by their leading bytes and decoded as they are read.
+pub(crate) fn load_frame_index<R: Read>(index_reader: R) -> Result<Vec<FrameMeta>> {
+    load_index_file(index_reader).map(|(_, idx_buffer)| idx_buffer)
+}
+
+/// Load a frame index along with the header recording how its archive was made, which indexes
+/// written before the header was introduced, or with '--plain-index', do not have.
+pub(crate) fn load_index_file<R: Read>(
+    mut index_reader: R,
+) -> Result<(Option<IndexHeader>, Vec<FrameMeta>)> {
+    let mut magic: Vec<u8> = Vec::with_capacity(4);
+    (&mut index_reader).take(4).read_to_end(&mut magic)?;
+
+    let is_gzip = magic.starts_with(&GZIP_MAGIC);
+    let is_zstd = magic == ZSTD_MAGIC.to_le_bytes();
+    let index_reader = Cursor::new(magic).chain(index_reader);
+
+    match (is_gzip, is_zstd) {
+        (true, _) => parse_frame_index(MultiGzDecoder::new(index_reader)),
+        (_, true) => parse_frame_index(zstd::stream::read::Decoder::new(index_reader)?),
+        _ => parse_frame_index(index_reader),
+    }
+}
+
+/// Check that every frame of the index is non-empty and lies within an archive of the given
+/// length, and that no two frames overlap, so that a malformed index is rejected before any
+/// frame is read.
+pub(crate) fn check_frame_index(idx_buffer: &[FrameMeta], archive_length: u64) -> Result<()> {
+    let mut spans: Vec<(u64, u64, u64)> = Vec::with_capacity(idx_buffer.len());
+    for frame_meta in idx_buffer {
+        if frame_meta.length == 0 {
+            bail!(PipelineError::CorruptArchive(format!(
+                "Frame {} at position {} has a length of zero!",
+                frame_meta.order, frame_meta.position
+            )));
+        }
+        match frame_meta.position.checked_add(frame_meta.length) {
+            Some(end) if end <= archive_length => {
+                spans.push((frame_meta.position, end, frame_meta.order))
+            }
+            _ => bail!(PipelineError::CorruptArchive(format!(
+                "Frame {} at position {} with length {} runs past the end of the archive ({} bytes)!",
+                frame_meta.order, frame_meta.position, frame_meta.length, archive_length
+            ))),
+        }
+    }
+
+    spans.sort_unstable();
+    for pair in spans.windows(2) {
+        let ((_, a_end, a_order), (b_start, _, b_order)) = (pair[0], pair[1]);
+        if b_start < a_end {
+            bail!(PipelineError::CorruptArchive(format!(
+                "Frames {} and {} overlap, as frame {} starts at position {} before frame {} ends at {}!",
+                a_order, b_order, b_order, b_start, a_order, a_end
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// End of the last frame of the index, which for an index written alongside its archive is the
+/// end of the archive, barring trailing skippable frames.
+pub(crate) fn indexed_length(idx_buffer: &[FrameMeta]) -> u64 {
+    idx_buffer
+        .iter()
+        .map(|f| f.position.saturating_add(f.length))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Look for the signs of an index left over from an earlier version of the archive, as when the
+/// archive is written again but its index is not. None of these prove the index wrong, so they
+/// are returned as messages for the caller to warn of. The magic of any bytes which follow the
+/// last frame is given, as trailing padding or metadata frames are expected there.
+pub(crate) fn stale_index_signs(
+    idx_buffer: &[FrameMeta],
+    archive_length: u64,
+    trailing_magic: Option<u32>,
+    archive_is_newer: bool,
+) -> Vec<String> {
+    let mut signs: Vec<String> = Vec::new();
+
+    let indexed_length = indexed_length(idx_buffer);
+    let trailing_skippable =
+        trailing_magic.is_some_and(|m| m & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC);
+    if indexed_length < archive_length && !trailing_skippable {
+        signs.push(match idx_buffer.is_empty() {
+            true => format!(
+                "the index lists no frames, but the archive holds {} bytes",
+                archive_length
+            ),
+            false => format!(
+                "the last indexed frame ends at byte {}, but the archive holds {} bytes",
+                indexed_length, archive_length
+            ),
+        });
+    }
+    if archive_is_newer {
+        signs.push("the archive was modified after the index was written".to_string());
+    }
+    signs
+}
+
+/// Parse a decimal value straight from the record bytes. Only ASCII digits can make up a value,
+/// so the bytes are never checked as UTF-8 first.
+pub(crate) fn parse_bytes_to_numeric(bytes: &[u8]) -> Result<u64> {
+    let digits = bytes.trim_ascii();
+    let digits = digits.strip_prefix(b"+").unwrap_or(digits);
+
+    let taxid = digits.iter().try_fold(0u64, |taxid, b| match b {
+        b'0'..=b'9' => taxid.checked_mul(10)?.checked_add((b - b'0') as u64),
+        _ => None,
+    });
+    match taxid {
+        Some(t) if !digits.is_empty() => Ok(t),
+        _ => bail!("Unable to convert value to numeric."),
+    }
+}
+
+fn select_columns(line_repr: &[u8], columns: &[usize], joiner: &[u8]) -> Result<Vec<u8>> {
+    let fields: Vec<&[u8]> = line_repr.split(|&b| b == b'\t').collect();
+
+    let mut selected: Vec<u8> = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        let field = match column.checked_sub(1).and_then(|c| fields.get(c)) {
+            Some(f) => f,
+            None => bail!("Record has no column {}.", column),
+        };
+        if i > 0 {
+            selected.extend_from_slice(joiner);
+        }
+        selected.extend_from_slice(field);
+    }
+
+    Ok(selected)
+}
+
+/// Locate the key and value bytes of a record, joining any key columns into a composite key and
+/// stripping the version from the key when requested.
+/// For tab-separated records with key columns, the value is the rest of the line after the last
+/// key column. Returns None if the line does not have the key columns expected.
+pub(crate) fn locate_record<'a>(
+    line_repr: &'a [u8],
+    parse_options: &ParseOptions,
+) -> Option<(Cow<'a, [u8]>, &'a [u8])> {
+    let (key_bytes, value_bytes) = parse_options.format.split_record(line_repr)?;
+    let (key_bytes, value_bytes) = match parse_options.key_columns.iter().max() {
+        Some(last_key_column) => {
+            let key_joiner = parse_options.key_joiner.as_bytes();
+            let key_bytes =
+                select_columns(line_repr, &parse_options.key_columns, key_joiner).ok()?;
+            let value_bytes = match parse_options.format {
+                RecordFormat::Tsv => line_repr
+                    .splitn(last_key_column + 1, |&b| b == b'\t')
+                    .nth(*last_key_column)
+                    .unwrap_or_default(),
+                _ => value_bytes,
+            };
+            (Cow::Owned(key_bytes), value_bytes)
+        }
+        None => (Cow::Borrowed(key_bytes), value_bytes),
+    };
+
+    let key_bytes = match (parse_options.strip_key_version, key_bytes) {
+        (true, Cow::Borrowed(k)) => Cow::Borrowed(strip_key_version(k)),
+        (true, Cow::Owned(k)) => Cow::Owned(strip_key_version(&k).to_vec()),
+        (false, k) => k,
+    };
+    Some((key_bytes, value_bytes))
+}
+
+/// Drop a trailing version suffix from an accession, so that 'WP_413685322.1' becomes
+/// 'WP_413685322'. Keys without a numeric suffix are left as they are.
+pub(crate) fn strip_key_version(key_bytes: &[u8]) -> &[u8] {
+    match key_bytes.iter().rposition(|&b| b == b'.') {
+        Some(p)
+            if p > 0
+                && p + 1 < key_bytes.len()
+                && key_bytes[p + 1..].iter().all(u8::is_ascii_digit) =>
+        {
+            &key_bytes[..p]
+        }
+        _ => key_bytes,
+    }
+}
+
+fn duplicate_key_error<K: RecordKey>(key: &K) -> anyhow::Error {
+    PipelineError::CorruptArchive(format!(
+        "Key '{}' appears in more than one record!",
+        String::from_utf8_lossy(&key.key_bytes())
+    ))
+    .into()
+}
+
+/// A map which records are inserted into under the duplicate key policy.
+trait InsertRecord<K, V> {
+    fn insert_record(&mut self, key: K, value: V, policy: &DuplicatePolicy) -> Result<()>;
+
+    fn extend_records(
+        &mut self,
+        records: impl IntoIterator<Item = (K, V)>,
+        policy: &DuplicatePolicy,
+    ) -> Result<()> {
+        for (key, value) in records {
+            self.insert_record(key, value, policy)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: RecordKey, V> InsertRecord<K, V> for AHashMap<K, V> {
+    fn insert_record(&mut self, key: K, value: V, policy: &DuplicatePolicy) -> Result<()> {
+        match self.entry(key) {
+            HashEntry::Vacant(e) => {
+                e.insert(value);
+            }
+            HashEntry::Occupied(mut e) => match policy {
+                DuplicatePolicy::Last => {
+                    e.insert(value);
+                }
+                DuplicatePolicy::First => {}
+                DuplicatePolicy::Error => return Err(duplicate_key_error(e.key())),
+            },
+        }
+        Ok(())
+    }
+}
+
+impl<K: RecordKey, V> InsertRecord<K, V> for IndexMap<K, V, RandomState> {
+    fn insert_record(&mut self, key: K, value: V, policy: &DuplicatePolicy) -> Result<()> {
+        // A replaced value keeps the position of the key's first occurrence
+        match self.entry(key) {
+            IndexEntry::Vacant(e) => {
+                e.insert(value);
+            }
+            IndexEntry::Occupied(mut e) => match policy {
+                DuplicatePolicy::Last => {
+                    e.insert(value);
+                }
+                DuplicatePolicy::First => {}
+                DuplicatePolicy::Error => return Err(duplicate_key_error(e.key())),
+            },
+        }
+        Ok(())
+    }
+}
+
+fn insert_shared<K: RecordKey, V>(
+    record_map: &DashMap<K, V>,
+    records: Vec<(K, V)>,
+    policy: &DuplicatePolicy,
+) -> Result<()> {
+    for (key, value) in records {
+        match record_map.entry(key) {
+            DashEntry::Vacant(e) => {
+                e.insert(value);
+            }
+            DashEntry::Occupied(mut e) => match policy {
+                DuplicatePolicy::Last => {
+                    e.insert(value);
+                }
+                DuplicatePolicy::First => {}
+                DuplicatePolicy::Error => return Err(duplicate_key_error(e.key())),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Merge the records of a later read into a map under the duplicate key policy, as the records
+/// of frames which follow those the map was built from.
+pub(crate) fn merge_into_map<K: RecordKey + 'static, V: 'static>(
+    record_map: &mut EitherMap<K, V>,
+    update_map: EitherMap<K, V>,
+    policy: &DuplicatePolicy,
+) -> Result<()> {
+    match record_map {
+        EitherMap::Dash(m) => insert_shared(m, update_map.into_iter().collect(), policy),
+        EitherMap::AHash(m) => m.extend_records(update_map, policy),
+        EitherMap::Ordered(m) => m.extend_records(update_map, policy),
+    }
+}
+
+pub(crate) fn trim_line_ending(line_repr: &[u8]) -> &[u8] {
+    let mut line_end = line_repr.len();
+    while line_end > 0 && matches!(line_repr[line_end - 1], b'\r' | b' ') {
+        line_end -= 1;
+    }
+    &line_repr[..line_end]
+}
+
+#[cfg(test)]
+fn parse_lines_to_map<K: RecordKey, V: RecordValue>(
+    buf: &[u8],
+    order: u64,
+    parse_options: &ParseOptions,
+) -> Result<FrameRecords<K, V>> {
+    parse_lines_from(buf, order, 0, parse_options, &FrameLedger::default())
+}
+
+fn parse_lines_from<K: RecordKey, V: RecordValue>(
+    buf: &[u8],
+    order: u64,
+    start_offset: usize,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+) -> Result<FrameRecords<K, V>> {
+    parse_lines_with(buf, order, start_offset, parse_options, frame_ledger, |k| {
+        K::from_key_bytes(k, parse_options.strict_utf8)
+    })
+}
+
+/// Parse the records of the buffer, building each key from its bytes with the given function.
+/// A key which cannot be built is reported as not valid UTF-8.
+fn parse_lines_with<K, V: RecordValue>(
+    buf: &[u8],
+    order: u64,
+    start_offset: usize,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+    key_fn: impl Fn(&[u8]) -> Option<K>,
+) -> Result<FrameRecords<K, V>> {
+    let mut unpacked_data: Vec<(K, V)> = Vec::new();
+    let mut bad_records: Vec<BadRecord> = Vec::new();
+    let mut line_offset: usize = start_offset;
+
+    for line_repr in buf.split(|&b| b == b'\n') {
+        let offset = line_offset;
+        line_offset += line_repr.len() + 1;
+
+        // Tolerate CRLF endings and trailing spaces, so that neither leaks into the record
+        let line_repr = trim_line_ending(line_repr);
+
+        if parse_options.format.is_header(line_repr) {
+            continue;
+        }
+
+        if let Some((key_bytes, value_bytes)) = locate_record(line_repr, parse_options) {
+            // A deletion is only applied by compaction, and is not itself a record of the key
+            if value_bytes == TOMBSTONE.as_bytes() {
+                continue;
+            }
+
+            let accession = match key_fn(&key_bytes) {
+                Some(k) => k,
+                None => {
+                    let record = BadRecord::new(order, offset, line_repr);
+                    return Err(
+                        anyhow::Error::from(record).context("Record key is not valid UTF-8")
+                    );
+                }
+            };
+
+            let value = match parse_options.value_columns.is_empty() {
+                true => V::from_value_bytes(value_bytes),
+                false => select_columns(line_repr, &parse_options.value_columns, b"\t")
+                    .and_then(|v| V::from_value_bytes(&v)),
+            };
+            let e = match value {
+                Ok(value) => {
+                    unpacked_data.push((accession, value));
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            let key_repr = String::from_utf8_lossy(&key_bytes);
+            let record = BadRecord::new(order, offset, line_repr);
+            match parse_options.bad_record {
+                BadRecordPolicy::Zero => {
+                    report_bad_record(parse_options, &record, &key_repr, &e, false);
+                    unpacked_data.push((accession, V::default()));
+                    frame_ledger.zeroed_records.fetch_add(1, Ordering::Relaxed);
+                }
+                BadRecordPolicy::Skip => {
+                    report_bad_record(parse_options, &record, &key_repr, &e, true);
+                    frame_ledger.skipped_records.fetch_add(1, Ordering::Relaxed);
+                }
+                BadRecordPolicy::Error => return Err(record.into()),
+                BadRecordPolicy::Collect => bad_records.push(record),
+            }
+        }
+    }
+    Ok((unpacked_data, bad_records))
+}
+
+/// Report a record given the default value or left out, as an event where an event log is kept
+/// and as plain text otherwise.
+fn report_bad_record(
+    parse_options: &ParseOptions,
+    record: &BadRecord,
+    key_repr: &str,
+    e: &anyhow::Error,
+    skipped: bool,
+) {
+    match (&parse_options.events, skipped) {
+        (Some(_), _) => parse_options.emit_event(Event::BadRecord {
+            frame: record.order,
+            offset: record.offset,
+            key: key_repr.to_string(),
+            error: format!("{:#}", e),
+            skipped,
+        }),
+        (None, false) => eprintln!(
+            "Error parsing record '{}'. {} Taxid will be reported as '0'!",
+            key_repr, e
+        ),
+        (None, true) => eprintln!(
+            "Error parsing record '{}'. {} Record will be skipped!",
+            key_repr, e
+        ),
+    }
+}
+
+fn is_transient(e: &std::io::Error) -> bool {
+    // Errors typical of network filesystems and remote storage, which may clear on a second
+    // attempt. Missing files, permissions and truncated payloads are not retried.
+    match e.kind() {
+        ErrorKind::Interrupted
+        | ErrorKind::TimedOut
+        | ErrorKind::WouldBlock
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe => true,
+        _ => matches!(e.raw_os_error(), Some(libc::EIO | libc::ESTALE)),
+    }
+}
+
+fn with_retries<T>(
+    parse_options: &ParseOptions,
+    order: u64,
+    mut read_fn: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let retry = &parse_options.retry;
+    let mut delay = retry.delay;
+    let mut attempt: u32 = 0;
+
+    loop {
+        match read_fn() {
+            Err(e) if is_transient(&e) && attempt < retry.retries => {
+                attempt += 1;
+                match parse_options.events {
+                    Some(_) => parse_options.emit_event(Event::Retry {
+                        frame: order,
+                        error: e.to_string(),
+                        delay_seconds: delay.as_secs_f64(),
+                        attempt,
+                        retries: retry.retries,
+                    }),
+                    None => eprintln!(
+                        "Transient error reading frame {} ({}), retrying in {:?} (attempt {} of {})",
+                        order, e, delay, attempt, retry.retries
+                    ),
+                }
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Read through the source on behalf of the frame with the given order, applying the read
+/// limiter and retry policy.
+fn read_with_policy(
+    length: usize,
+    order: u64,
+    parse_options: &ParseOptions,
+    mut read_fn: impl FnMut() -> std::io::Result<Bytes>,
+) -> Result<Bytes> {
+    // Time spent waiting on the read limiter is counted as part of the read
+    let start = Instant::now();
+    if let Some(read_limiter) = &parse_options.read_limiter {
+        read_limiter.acquire(length as u64);
+    }
+    let buffer = with_retries(parse_options, order, &mut read_fn)?;
+    parse_options.record_stage(Stage::Read, order, start, length as u64);
+
+    Ok(buffer)
+}
+
+/// Read a span of the archive on behalf of the frame with the given order, applying the read
+/// limiter and retry policy.
+pub(crate) fn read_at(
+    source: &dyn FrameSource,
+    position: u64,
+    length: usize,
+    order: u64,
+    parse_options: &ParseOptions,
+) -> Result<Bytes> {
+    read_with_policy(length, order, parse_options, || {
+        source.read_at(position, length)
+    })
+}
+
+pub(crate) fn decode_frame(
+    source: &dyn FrameSource,
+    idx_frame: &FrameMeta,
+    parse_options: &ParseOptions,
+) -> Result<Vec<u8>> {
+    let payload_length = idx_frame.parse_length()?;
+    let frame_payload = match &parse_options.read_batcher {
+        Some(read_batcher) => read_batcher.frame_bytes(source, idx_frame, parse_options)?,
+        None => read_with_policy(payload_length, idx_frame.order, parse_options, || {
+            source.read_frame(idx_frame)
+        })?,
+    };
+    decode_payload(frame_payload, idx_frame, parse_options)
+}
+
+/// Read and decode a frame from any seekable reader, for callers which keep the archive open.
+pub(crate) fn decode_frame_from<R: Read + Seek>(
+    reader: &mut R,
+    idx_frame: &FrameMeta,
+    parse_options: &ParseOptions,
+) -> Result<Vec<u8>> {
+    let payload_length = idx_frame.parse_length()?;
+    reader.seek(SeekFrom::Start(idx_frame.position))?;
+
+    // The buffer grows as bytes arrive, so a length past the end of the archive is never
+    // allocated in full
+    let mut frame_payload: Vec<u8> = Vec::new();
+    let read_result = reader
+        .take(idx_frame.length)
+        .read_to_end(&mut frame_payload)
+        .and_then(|n| match n == payload_length {
+            true => Ok(()),
+            false => Err(ErrorKind::UnexpectedEof.into()),
+        });
+    if let Err(e) = read_result {
+        let message = format!("Unable to read frame {}", idx_frame.order);
+        return Err(anyhow::Error::from(e).context(PipelineError::CorruptArchive(message)));
+    }
+    decode_payload(Bytes::from(frame_payload), idx_frame, parse_options)
+}
+
+/// Parse the records of a decoded frame into a view which keeps the payload, so that each key
+/// is held as its place in the payload rather than copied out. Keys rebuilt from several
+/// columns are not found in the payload, so key columns are refused.
+pub(crate) fn parse_frame_view<V: RecordValue>(
+    payload: Vec<u8>,
+    order: u64,
+    parse_options: &ParseOptions,
+) -> Result<(FrameView<V>, Vec<BadRecord>)> {
+    if !parse_options.key_columns.is_empty() {
+        bail!(PipelineError::Usage(
+            "Keys built from key columns cannot be borrowed from the frame!".into()
+        ));
+    }
+
+    let payload: Arc<[u8]> = payload.into();
+    let base = payload.as_ptr() as usize;
+    let (records, bad_records) = parse_lines_with(
+        &payload,
+        order,
+        0,
+        parse_options,
+        &FrameLedger::default(),
+        |k| {
+            if parse_options.strict_utf8 && std::str::from_utf8(k).is_err() {
+                return None;
+            }
+            // Keys are sliced from the payload, so their place is their distance from its start
+            let start = k.as_ptr() as usize - base;
+            Some(KeySpan {
+                start,
+                end: start + k.len(),
+            })
+        },
+    )?;
+
+    let frame_view = FrameView {
+        order,
+        payload,
+        records,
+    };
+    Ok((frame_view, bad_records))
+}
+
+/// Parse the records of a decoded frame which holds whole records.
+pub(crate) fn parse_frame<K: RecordKey, V: RecordValue>(
+    payload: &[u8],
+    order: u64,
+    parse_options: &ParseOptions,
+) -> Result<FrameRecords<K, V>> {
+    parse_lines_from(payload, order, 0, parse_options, &FrameLedger::default())
+}
+
+fn decode_payload(
+    frame_payload: Bytes,
+    idx_frame: &FrameMeta,
+    parse_options: &ParseOptions,
+) -> Result<Vec<u8>> {
+    let payload_length = frame_payload.len();
+    let start = Instant::now();
+    let decode_result = match parse_options.codec {
+        Codec::Zstd => zstd::decode_all(Cursor::new(frame_payload)),
+        Codec::Gzip => {
+            // A frame may hold several gzip members, which are decoded back to back
+            let mut payload: Vec<u8> = Vec::new();
+            MultiGzDecoder::new(Cursor::new(frame_payload))
+                .read_to_end(&mut payload)
+                .map(|_| payload)
+        }
+    };
+    let payload = match decode_result {
+        Ok(p) => p,
+        Err(e) => {
+            let message = format!("Unable to decode frame {}", idx_frame.order);
+            return Err(anyhow::Error::from(e).context(PipelineError::CorruptArchive(message)));
+        }
+    };
+    if parse_options.verify_frames
+        && let Some(checksum) = idx_frame.checksum
+        && xxh3_64(&payload) != checksum
+    {
+        bail!(PipelineError::CorruptArchive(format!(
+            "Frame {} does not match the checksum in the index",
+            idx_frame.order
+        )));
+    }
+    parse_options.record_stage(Stage::Decode, idx_frame.order, start, payload.len() as u64);
+    if let Some(byte_counts) = &parse_options.byte_counts {
+        byte_counts.record(payload_length as u64, payload.len() as u64);
+    }
+    parse_options.emit_event(Event::FrameDecoded {
+        frame: idx_frame.order,
+        bytes_read: payload_length as u64,
+        bytes_decompressed: payload.len() as u64,
+    });
+
+    Ok(payload)
+}
+
+fn map_zstd_frame<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_frame: FrameMeta,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+) -> Result<FrameRecords<K, V>> {
+    let payload = decode_frame(source, &idx_frame, parse_options)?;
+
+    let start = Instant::now();
+    let (start_offset, complete_lines) = match parse_options.split_records {
+        true => frame_ledger.split_fragments(idx_frame.order, &payload),
+        false => (0, &payload[..]),
+    };
+    let payload_data = parse_lines_from(
+        complete_lines,
+        idx_frame.order,
+        start_offset,
+        parse_options,
+        frame_ledger,
+    )?;
+    parse_options.record_stage(Stage::Parse, idx_frame.order, start, payload.len() as u64);
+
+    Ok(payload_data)
+}
+
+/// Partial lines cut from either end of a frame, with the offset at which the tail begins. A
+/// frame without any line ending is held entirely in the head, and has no tail. The first frame
+/// begins on a record boundary, so holds everything up to its final line ending in its tail.
+struct FrameFragments {
+    order: u64,
+    head: Vec<u8>,
+    tail: Option<(usize, Vec<u8>)>,
+}
+
+/// Shared record of what became of each frame: those which could not be read or decoded and,
+/// when records may span frames, the partial lines at the ends of the others. Also counts the
+/// malformed records which the bad record policy zeroed or skipped.
+#[derive(Default)]
+pub(crate) struct FrameLedger {
+    failed: Mutex<Vec<FailedFrame>>,
+    fragments: Mutex<Vec<FrameFragments>>,
+    zeroed_records: AtomicUsize,
+    skipped_records: AtomicUsize,
+}
+
+impl FrameLedger {
+    /// Hold back the partial lines at each end of the payload, returning the offset and bytes of
+    /// the complete lines between them.
+    fn split_fragments<'a>(&self, order: u64, payload: &'a [u8]) -> (usize, &'a [u8]) {
+        let head_end = match order {
+            0 => Some(0),
+            _ => payload.iter().position(|&b| b == b'\n').map(|i| i + 1),
+        };
+        let tail_start = payload.iter().rposition(|&b| b == b'\n').map(|i| i + 1);
+
+        let (fragments, complete_lines) = match (head_end, tail_start) {
+            (Some(head_end), tail_start) => {
+                let tail_start = tail_start.unwrap_or(0).max(head_end);
+                (
+                    FrameFragments {
+                        order,
+                        head: payload[..head_end].to_vec(),
+                        tail: Some((tail_start, payload[tail_start..].to_vec())),
+                    },
+                    (head_end, &payload[head_end..tail_start]),
+                )
+            }
+            (None, _) => (
+                FrameFragments {
+                    order,
+                    head: payload.to_vec(),
+                    tail: None,
+                },
+                (payload.len(), &payload[payload.len()..]),
+            ),
+        };
+
+        self.fragments.lock().unwrap().push(fragments);
+        complete_lines
+    }
+
+    /// Join the fragments held back from neighbouring frames into whole lines, and parse them.
+    /// Each line is given the order of the frame in which it begins, so belongs after that
+    /// frame's own records. Partial lines next to a frame which could not be read are
+    /// incomplete, so are discarded.
+    pub(crate) fn stitch<K: RecordKey, V: RecordValue>(
+        &self,
+        parse_options: &ParseOptions,
+    ) -> Result<Vec<(u64, FrameRecords<K, V>)>> {
+        let mut fragments = std::mem::take(&mut *self.fragments.lock().unwrap());
+        fragments.sort_by_key(|f| f.order);
+
+        let mut lines: Vec<(u64, usize, Vec<u8>)> = Vec::new();
+        let mut pending: Option<(u64, usize, Vec<u8>)> = None;
+        let mut previous: Option<u64> = None;
+
+        for frame_fragments in fragments {
+            let order = frame_fragments.order;
+            if previous.is_none_or(|p| p + 1 != order) {
+                pending = None;
+            }
+
+            if let Some((_, _, line)) = pending.as_mut() {
+                line.extend(frame_fragments.head);
+            }
+            if let Some((offset, tail)) = frame_fragments.tail {
+                lines.extend(pending.take());
+                pending = Some((order, offset, tail));
+            }
+            previous = Some(order);
+        }
+
+        // The final line of the file has no line ending, unless the frames after it were lost
+        let last_failed = self.failed.lock().unwrap().iter().map(|f| f.order).max();
+        if let (Some(line), Some(last_order)) = (pending, previous)
+            && !line.2.is_empty()
+            && last_failed.is_none_or(|f| f < last_order)
+        {
+            lines.push(line);
+        }
+
+        lines
+            .into_iter()
+            .map(|(order, offset, line)| {
+                let records = parse_lines_from(&line, order, offset, parse_options, self)?;
+                Ok((order, records))
+            })
+            .collect()
+    }
+
+    /// Summarise the run, given the records set aside by the workers.
+    pub(crate) fn into_summary(self, bad_records: Vec<BadRecord>) -> DecompressionSummary {
+        DecompressionSummary {
+            zeroed_records: self.zeroed_records.into_inner(),
+            skipped_records: self.skipped_records.into_inner(),
+            ..DecompressionSummary::new(bad_records, self.failed.into_inner().unwrap())
+        }
+    }
+}
+
+pub(crate) fn gather_zstd_frame<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_frame: FrameMeta,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+) -> Result<Option<FrameRecords<K, V>>> {
+    // A malformed record under the 'error' policy (or an invalid key under strict UTF-8) aborts
+    // the run, while a frame which cannot be read or decoded is reported and the remaining
+    // frames are still processed.
+    let (order, position) = (idx_frame.order, idx_frame.position);
+    match map_zstd_frame(source, idx_frame, parse_options, frame_ledger) {
+        Ok(frame_records) => Ok(Some(frame_records)),
+        Err(e) if e.is::<BadRecord>() => Err(e),
+        Err(e) => {
+            match parse_options.events {
+                Some(_) => parse_options.emit_event(Event::FrameFailed {
+                    frame: order,
+                    error: format!("{:#}", e),
+                }),
+                None => eprintln!("{:#?}", e),
+            }
+            frame_ledger.failed.lock().unwrap().push(FailedFrame {
+                order,
+                position,
+                error: ErrorClass::of(&e),
+            });
+            Ok(None)
+        }
+    }
+}
+
+/// Put the frames in the order they are to be handed to the workers. `par_bridge` gives each
+/// worker the next frame as it frees up, so the order of the iterator is the schedule. Ties keep
+/// their order in the index.
+fn schedule_frames(mut idx_buffer: Vec<FrameMeta>, schedule: &FrameSchedule) -> Vec<FrameMeta> {
+    if *schedule == FrameSchedule::LargestFirst {
+        idx_buffer.sort_by_key(|f| std::cmp::Reverse(f.length));
+    }
+    idx_buffer
+}
+
+fn gather_ordered_frames<K, V, T, F>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    pool: &rayon::ThreadPool,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+    frame_fn: F,
+) -> Result<Vec<OrderedFrame<T>>>
+where
+    K: RecordKey,
+    V: RecordValue,
+    T: Send,
+    F: Fn(Vec<(K, V)>) -> T + Sync,
+{
+    let mut frame_buffer: Vec<OrderedFrame<T>> = pool.install(|| {
+        schedule_frames(idx_buffer, &parse_options.schedule)
+            .into_iter()
+            .par_bridge()
+            .map(|idx_frame| {
+                let order = idx_frame.order;
+                gather_zstd_frame(source, idx_frame, parse_options, frame_ledger).map(
+                    |frame_records| {
+                        frame_records
+                            .map(|(records, bad_records)| (order, frame_fn(records), bad_records))
+                    },
+                )
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<_>>()
+    })?;
+
+    for (order, (records, bad_records)) in frame_ledger.stitch(parse_options)? {
+        frame_buffer.push((order, frame_fn(records), bad_records));
+    }
+
+    // Frames arrive in whatever order the workers complete them, so restore the file order.
+    // Records within a frame are already in order, and the sort is stable so that lines
+    // stitched across frames stay after the records of the frame in which they begin.
+    frame_buffer.sort_by_key(|(order, _, _)| *order);
+
+    Ok(frame_buffer)
+}
+
+//endregion:
+
+pub fn read_indexed_zstd_dashmap<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary)> {
+    let duplicate_keys = &parse_options.duplicate_keys;
+    let record_map: DashMap<K, V> = DashMap::new();
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_ledger = FrameLedger::default();
+
+    let bad_buffer: Vec<Vec<BadRecord>> = pool.install(|| {
+        schedule_frames(idx_buffer, &parse_options.schedule)
+            .into_iter()
+            .par_bridge()
+            .map(|idx_frame| {
+                let order = idx_frame.order;
+                let (payload_data, bad_records) =
+                    gather_zstd_frame(source, idx_frame, parse_options, &frame_ledger)?
+                        .unwrap_or_default();
+
+                let start = Instant::now();
+                let num_records = payload_data.len() as u64;
+                insert_shared(&record_map, payload_data, duplicate_keys)?;
+                parse_options.record_stage(Stage::Insert, order, start, num_records);
+                Ok(bad_records)
+            })
+            .collect::<Result<_>>()
+    })?;
+
+    let mut bad_records: Vec<BadRecord> = bad_buffer.into_iter().flatten().collect();
+    for (_, (payload_data, stitched_bad)) in frame_ledger.stitch(parse_options)? {
+        insert_shared(&record_map, payload_data, duplicate_keys)?;
+        bad_records.extend(stitched_bad);
+    }
+
+    let summary = frame_ledger.into_summary(bad_records);
+    Ok((EitherMap::Dash(record_map), summary))
+}
+
+pub fn read_indexed_zstd_vector<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary)> {
+    let duplicate_keys = &parse_options.duplicate_keys;
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+
+    let frame_ledger = FrameLedger::default();
+    let record_buffer = gather_ordered_frames(
+        source,
+        idx_buffer,
+        &pool,
+        parse_options,
+        &frame_ledger,
+        |r| r,
+    )?;
+
+    // Condense into the returnable HashMap
+    let total_records = record_buffer.iter().map(|(_, r, _)| r.len()).sum();
+    let mut record_map: AHashMap<K, V> = AHashMap::with_capacity(total_records);
+    let mut bad_records: Vec<BadRecord> = Vec::new();
+
+    for (order, frame_records, frame_bad) in record_buffer {
+        let start = Instant::now();
+        let num_records = frame_records.len() as u64;
+        record_map.extend_records(frame_records, duplicate_keys)?;
+        parse_options.record_stage(Stage::Insert, order, start, num_records);
+        bad_records.extend(frame_bad);
+    }
+
+    Ok((
+        EitherMap::AHash(record_map),
+        frame_ledger.into_summary(bad_records),
+    ))
+}
+
+pub fn read_indexed_zstd_ordered<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary)> {
+    let duplicate_keys = &parse_options.duplicate_keys;
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+
+    let frame_ledger = FrameLedger::default();
+    let frame_buffer = gather_ordered_frames(
+        source,
+        idx_buffer,
+        &pool,
+        parse_options,
+        &frame_ledger,
+        |r| {
+            let mut local: IndexMap<K, V, RandomState> =
+                IndexMap::with_capacity_and_hasher(r.len(), RandomState::new());
+            local.extend_records(r, duplicate_keys).map(|_| local)
+        },
+    )?;
+
+    // Merge the frame maps in file order, so that keys keep the position of their first
+    // occurrence in the original file.
+    let total_records = frame_buffer
+        .iter()
+        .map(|(_, m, _)| m.as_ref().map_or(0, |m| m.len()))
+        .sum();
+    let mut record_map: IndexMap<K, V, RandomState> =
+        IndexMap::with_capacity_and_hasher(total_records, RandomState::new());
+    let mut bad_records: Vec<BadRecord> = Vec::new();
+
+    for (order, frame_map, frame_bad) in frame_buffer {
+        let frame_map = frame_map?;
+        let start = Instant::now();
+        let num_records = frame_map.len() as u64;
+        record_map.extend_records(frame_map, duplicate_keys)?;
+        parse_options.record_stage(Stage::Insert, order, start, num_records);
+        bad_records.extend(frame_bad);
+    }
+
+    Ok((
+        EitherMap::Ordered(record_map),
+        frame_ledger.into_summary(bad_records),
+    ))
+}
+
+pub fn read_indexed_zstd_merge<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary)> {
+    let duplicate_keys = &parse_options.duplicate_keys;
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_ledger = FrameLedger::default();
+
+    let (mut record_map, mut bad_records): (AHashMap<K, V>, Vec<BadRecord>) =
+        pool.install(|| {
+            schedule_frames(idx_buffer, &parse_options.schedule)
+                .into_iter()
+                .par_bridge()
+                .map(|idx_frame| {
+                    let order = idx_frame.order;
+                    gather_zstd_frame(source, idx_frame, parse_options, &frame_ledger)
+                        .map(|frame_records| frame_records.map(|r| (order, r)))
+                })
+                .filter_map(Result::transpose)
+                .map(|frame_records| {
+                    frame_records.and_then(|(order, (pairs, bad_records))| {
+                        let start = Instant::now();
+                        let num_records = pairs.len() as u64;
+                        let mut local = AHashMap::with_capacity(pairs.len());
+                        local.extend_records(pairs, duplicate_keys)?;
+                        parse_options.record_stage(Stage::Insert, order, start, num_records);
+                        Ok((local, bad_records))
+                    })
+                })
+                .try_reduce(
+                    || (AHashMap::new(), Vec::new()),
+                    |(mut a, mut a_bad), (mut b, b_bad)| {
+                        // Organise the HashMaps such that a is always larger than b
+                        // This is quite a niche command, so not imported at start of file
+                        if a.len() < b.len() {
+                            std::mem::swap(&mut a, &mut b);
+                        }
+                        a.reserve(b.len()); // Increase the capacity of larger to fit smaller
+                        a.extend_records(b, duplicate_keys)?;
+                        a_bad.extend(b_bad);
+                        Ok((a, a_bad))
+                    },
+                )
+        })?;
+
+    for (_, (pairs, stitched_bad)) in frame_ledger.stitch(parse_options)? {
+        record_map.extend_records(pairs, duplicate_keys)?;
+        bad_records.extend(stitched_bad);
+    }
+
+    Ok((
+        EitherMap::AHash(record_map),
+        frame_ledger.into_summary(bad_records),
+    ))
+}
+
+/// Read every frame in file order on the calling thread, with no worker pool, so that events,
+/// bad records and the order of the map are the same on every run. Slow, but serves as the
+/// reference against which results of the parallel modes can be checked.
+pub fn read_indexed_zstd_reference<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    mut idx_buffer: Vec<FrameMeta>,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary)> {
+    let duplicate_keys = &parse_options.duplicate_keys;
+    idx_buffer.sort_by_key(|f| f.order);
+
+    let frame_ledger = FrameLedger::default();
+    let mut frame_buffer: Vec<OrderedFrame<Vec<(K, V)>>> = Vec::with_capacity(idx_buffer.len());
+    for idx_frame in idx_buffer {
+        let order = idx_frame.order;
+        if let Some((records, bad_records)) =
+            gather_zstd_frame(source, idx_frame, parse_options, &frame_ledger)?
+        {
+            frame_buffer.push((order, records, bad_records));
+        }
+    }
+
+    // Lines stitched across frames belong after the records of the frame in which they begin
+    for (order, (records, bad_records)) in frame_ledger.stitch(parse_options)? {
+        frame_buffer.push((order, records, bad_records));
+    }
+    frame_buffer.sort_by_key(|(order, _, _)| *order);
+
+    let total_records = frame_buffer.iter().map(|(_, r, _)| r.len()).sum();
+    let mut record_map: IndexMap<K, V, RandomState> =
+        IndexMap::with_capacity_and_hasher(total_records, RandomState::new());
+    let mut bad_records: Vec<BadRecord> = Vec::new();
+
+    for (order, frame_records, frame_bad) in frame_buffer {
+        let start = Instant::now();
+        let num_records = frame_records.len() as u64;
+        record_map.extend_records(frame_records, duplicate_keys)?;
+        parse_options.record_stage(Stage::Insert, order, start, num_records);
+        bad_records.extend(frame_bad);
+    }
+
+    Ok((
+        EitherMap::Ordered(record_map),
+        frame_ledger.into_summary(bad_records),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::source::FileSource;
+    use crate::{ErrorClass, RetryPolicy, StageProfiler};
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufRead, BufReader};
+    use std::sync::Arc;
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn data_to_ahashmap(file_name: &str) -> AHashMap<String, u64> {
+        BufReader::new(open_file_read(file_name))
+            .lines()
+            .map(|line| {
+                let line_content = line.unwrap();
+
+                let (acc, rest) = line_content.trim().split_once('\t').unwrap();
+                let u: u64 = rest.parse().unwrap();
+
+                (acc.to_string(), u)
+            })
+            .collect()
+    }
+
+    fn write_test_archive(file_stem: &str, frames: &[&str]) -> (String, String) {
+        // Build a small archive directly from the frame contents, for cases not covered by the
+        // example data.
+        let zstd_file = format!("{}.zstd", file_stem);
+        let idx_file = format!("{}.zstd.idx", file_stem);
+
+        let mut payload: Vec<u8> = Vec::new();
+        let mut idx_records: Vec<FrameMeta> = Vec::new();
+
+        for (order, content) in frames.iter().enumerate() {
+            let frame_bytes = zstd::encode_all(content.as_bytes(), 0).unwrap();
+            let frame_meta =
+                FrameMeta::new(payload.len() as u64, frame_bytes.len() as u64, order as u64);
+
+            payload.extend(frame_bytes);
+            idx_records.push(frame_meta);
+        }
+
+        std::fs::write(&zstd_file, payload).unwrap();
+        std::fs::write(&idx_file, serde_json::to_string(&idx_records).unwrap()).unwrap();
+
+        (zstd_file, idx_file)
+    }
+
+    fn parse_options(bad_record: BadRecordPolicy) -> ParseOptions {
+        ParseOptions {
+            bad_record,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_load_frame_index() {
+        let file_name = "test/example.zstd.idx";
+        let mut json_handle = BufReader::new(open_file_read(file_name));
+
+        let exp_content: Vec<FrameMeta> =
+            serde_json::from_reader(open_file_read(file_name)).unwrap();
+
+        let obs_result = load_frame_index(&mut json_handle);
+        assert!(obs_result.is_ok());
+
+        let obs_content = obs_result.unwrap();
+        assert_eq!(exp_content, obs_content);
+    }
+
+    #[test]
+    fn test_load_frame_index_without_checksums() {
+        // Indexes written before checksums were recorded still load
+        let idx_file = "load_frame_index_without_checksums.idx";
+        std::fs::write(idx_file, r#"[{"position": 0, "length": 151, "order": 0}]"#).unwrap();
+
+        let obs_result = load_frame_index(&mut BufReader::new(open_file_read(idx_file)));
+        let _ = std::fs::remove_file(idx_file);
+
+        assert_eq!(vec![FrameMeta::new(0, 151, 0)], obs_result.unwrap());
+    }
+
+    #[test]
+    fn test_load_frame_index_compressed() {
+        let exp_content = load_frame_index(open_file_read("test/example.zstd.idx")).unwrap();
+        let json_bytes = std::fs::read("test/example.zstd.idx").unwrap();
+
+        let mut gzip_encoder =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gzip_encoder, &json_bytes).unwrap();
+        let gzip_bytes = gzip_encoder.finish().unwrap();
+        let zstd_bytes = zstd::encode_all(&json_bytes[..], 3).unwrap();
+
+        assert_eq!(exp_content, load_frame_index(&gzip_bytes[..]).unwrap());
+        assert_eq!(exp_content, load_frame_index(&zstd_bytes[..]).unwrap());
+
+        // A compressed index which is cut short is as corrupt as any other unreadable index
+        let obs_error = load_frame_index(&gzip_bytes[..gzip_bytes.len() / 2]).unwrap_err();
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_load_index_file() {
+        let exp_frames = load_frame_index(open_file_read("test/example.zstd.idx")).unwrap();
+        let exp_header = IndexHeader {
+            tool_version: "0.1.0".to_string(),
+            command_line: vec!["parallel_decompression".to_string(), "compress".to_string()],
+            hostname: Some("example".to_string()),
+            created: 1_700_000_000,
+            inputs: Vec::new(),
+        };
+        let mut headed_bytes: Vec<u8> = Vec::new();
+        crate::compression::write_index(&mut headed_bytes, Some(&exp_header), &exp_frames).unwrap();
+
+        let (obs_header, obs_frames) = load_index_file(&headed_bytes[..]).unwrap();
+        assert_eq!(Some(exp_header), obs_header);
+        assert_eq!(exp_frames, obs_frames);
+
+        // A bare list of frames has no header, and either form may be compressed
+        let (obs_header, _) = load_index_file(open_file_read("test/example.zstd.idx")).unwrap();
+        assert_eq!(None, obs_header);
+        let zstd_bytes = zstd::encode_all(&headed_bytes[..], 3).unwrap();
+        assert_eq!(exp_frames, load_frame_index(&zstd_bytes[..]).unwrap());
+
+        assert!(load_index_file(&b"  \n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_bytes_to_numeric() {
+        let exp_value: u64 = 123;
+        let bytes_slice: &[u8] = "123".as_bytes();
+
+        let obs_result = parse_bytes_to_numeric(bytes_slice);
+        assert!(obs_result.is_ok());
+
+        let obs_value = obs_result.unwrap();
+        assert_eq!(exp_value, obs_value);
+
+        assert_eq!(45, parse_bytes_to_numeric(b" +45\t").unwrap());
+        assert_eq!(
+            u64::MAX,
+            parse_bytes_to_numeric(b"18446744073709551615").unwrap()
+        );
+        for bad_bytes in [
+            &b""[..],
+            b"+",
+            b"-1",
+            b"1.5",
+            b"12a",
+            b"\xff",
+            b"18446744073709551616",
+        ] {
+            assert!(parse_bytes_to_numeric(bad_bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_trim_line_ending() {
+        assert_eq!(b"a\t1", trim_line_ending(b"a\t1"));
+        assert_eq!(b"a\t1", trim_line_ending(b"a\t1\r"));
+        assert_eq!(b"a\t1", trim_line_ending(b"a\t1 \r"));
+        assert_eq!(b"a\t", trim_line_ending(b"a\t\r"));
+        assert_eq!(b"", trim_line_ending(b"\r"));
+    }
+
+    #[test]
+    fn test_decode_frame_from() {
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let mut zstd_reader = open_file_read("test/example.zstd");
+        let parse_options = ParseOptions {
+            verify_frames: true,
+            ..Default::default()
+        };
+
+        // Frames are read out of order through the one reader
+        let mut obs_records: Vec<(String, u64)> = Vec::new();
+        for idx_frame in idx_buffer.iter().rev() {
+            let exp_payload = decode_frame(
+                &FileSource::new("test/example.zstd"),
+                idx_frame,
+                &parse_options,
+            )
+            .unwrap();
+            let obs_payload =
+                decode_frame_from(&mut zstd_reader, idx_frame, &parse_options).unwrap();
+            assert_eq!(exp_payload, obs_payload);
+
+            let (records, bad_records): FrameRecords<String, u64> =
+                parse_frame(&obs_payload, idx_frame.order, &parse_options).unwrap();
+            assert!(bad_records.is_empty());
+            obs_records.extend(records);
+        }
+
+        let exp_map = data_to_ahashmap("test/data.txt");
+        assert_eq!(exp_map.len(), obs_records.len());
+        for (key, value) in obs_records {
+            assert_eq!(Some(&value), exp_map.get(&key));
+        }
+    }
+
+    #[test]
+    fn test_parse_frame_view() {
+        let payload = b"a\t1\nWP_001.2\t2\r\nbad\tx\nc\t3\n".to_vec();
+        let parse_options = ParseOptions {
+            strip_key_version: true,
+            bad_record: BadRecordPolicy::Collect,
+            ..Default::default()
+        };
+
+        let (obs_view, obs_bad) = parse_frame_view::<u64>(payload, 4, &parse_options).unwrap();
+        let obs_records: Vec<(&[u8], u64)> = obs_view.iter().map(|(k, v)| (k, *v)).collect();
+        assert_eq!(
+            vec![(&b"a"[..], 1), (&b"WP_001"[..], 2), (&b"c"[..], 3)],
+            obs_records
+        );
+        assert_eq!(4, obs_view.order);
+        assert_eq!(KeySpan { start: 4, end: 10 }, obs_view.records()[1].0);
+        assert_eq!(Some("WP_001"), obs_view.key_str(obs_view.records()[1].0));
+        assert_eq!(1, obs_bad.len());
+    }
+
+    #[test]
+    fn test_parse_frame_view_key_columns() {
+        let parse_options = ParseOptions {
+            key_columns: vec![0, 1],
+            ..Default::default()
+        };
+        let obs_error =
+            parse_frame_view::<u64>(b"a\tb\t1\n".to_vec(), 0, &parse_options).unwrap_err();
+        assert_eq!(ErrorClass::Usage, ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_decode_frame_from_truncated() {
+        let mut zstd_reader = Cursor::new(std::fs::read("test/example.zstd").unwrap());
+        let idx_frame = FrameMeta::new(400, 151, 2);
+
+        let obs_error =
+            decode_frame_from(&mut zstd_reader, &idx_frame, &ParseOptions::default()).unwrap_err();
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_check_frame_index() {
+        let idx_buffer = vec![FrameMeta::new(0, 10, 0), FrameMeta::new(10, 5, 1)];
+        assert!(check_frame_index(&idx_buffer, 15).is_ok());
+
+        let malformed_indices = vec![
+            (vec![FrameMeta::new(0, 10, 0), FrameMeta::new(10, 6, 1)], 15),
+            (vec![FrameMeta::new(8, 4, 1), FrameMeta::new(0, 10, 0)], 15),
+            (vec![FrameMeta::new(u64::MAX, 2, 0)], 15),
+            (vec![FrameMeta::new(0, 10, 0), FrameMeta::new(10, 0, 1)], 15),
+        ];
+        for (idx_buffer, archive_length) in malformed_indices {
+            let obs_error = check_frame_index(&idx_buffer, archive_length).unwrap_err();
+            assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&obs_error));
+        }
+    }
+
+    #[test]
+    fn test_stale_index_signs() {
+        let idx_buffer = vec![FrameMeta::new(0, 10, 0), FrameMeta::new(10, 5, 1)];
+        assert!(stale_index_signs(&idx_buffer, 15, None, false).is_empty());
+        assert!(stale_index_signs(&Vec::new(), 0, None, false).is_empty());
+
+        // Padding after the last frame is expected, but anything else is not
+        let padding_magic = Some(0x184D_2A5D);
+        assert!(stale_index_signs(&idx_buffer, 40, padding_magic, false).is_empty());
+        assert_eq!(
+            1,
+            stale_index_signs(&idx_buffer, 40, Some(ZSTD_MAGIC), false).len()
+        );
+
+        let obs_signs = stale_index_signs(&Vec::new(), 40, Some(ZSTD_MAGIC), true);
+        assert_eq!(2, obs_signs.len());
+        assert!(obs_signs[0].contains("no frames"));
+    }
+
+    #[test]
+    fn test_compress_bytes_round_trip() {
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+
+        for block_size in [1, 64, 200, 1024 * 1024] {
+            let (payload, idx_buffer) = crate::compress_bytes(&exp_content, block_size, 3).unwrap();
+            let obs_content = crate::decompress_bytes(&payload, &idx_buffer).unwrap();
+            assert_eq!(exp_content, obs_content);
+
+            // The same input always gives the same archive
+            assert_eq!(
+                (payload, idx_buffer),
+                crate::compress_bytes(&exp_content, block_size, 3).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_compress_bytes_zero_block_size() {
+        let obs_error = crate::compress_bytes(b"a\t1\n", 0, 3).unwrap_err();
+        assert_eq!(ErrorClass::Usage, ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_decompress_bytes_malformed() {
+        let (payload, mut idx_buffer) = crate::compress_bytes(b"a\t1\nb\t2\nc\t3\n", 4, 3).unwrap();
+
+        idx_buffer[1].length = u64::MAX;
+        let obs_error = crate::decompress_bytes(&payload, &idx_buffer).unwrap_err();
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&obs_error));
+
+        // A frame which starts inside another fails before anything is decoded
+        idx_buffer[1].length = idx_buffer[0].length;
+        idx_buffer[1].position = idx_buffer[0].position + 1;
+        let obs_error = crate::decompress_bytes(&payload, &idx_buffer).unwrap_err();
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&obs_error));
+
+        // Bytes which are not a frame are reported rather than panicking
+        let obs_error =
+            crate::decompress_bytes(b"not an archive", &[FrameMeta::new(0, 14, 0)]).unwrap_err();
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&obs_error));
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_crlf() {
+        let input_bytes = "a\t1\r\nb\t2 \r\nc\tq\r\n".as_bytes();
+
+        let exp_vector: Vec<(String, u64)> = vec![("a".into(), 1), ("b".into(), 2)];
+        let exp_bad = vec![BadRecord::new(0, 11, b"c\tq")];
+
+        let (obs_vector, obs_bad) = parse_lines_to_map::<String, u64>(
+            input_bytes,
+            0,
+            &parse_options(BadRecordPolicy::Collect),
+        )
+        .unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert_eq!(exp_bad, obs_bad);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_success() {
+        let input_bytes = "a\t1\nb\t2\nc\t3\n".as_bytes();
+
+        let exp_vector: Vec<(String, u64)> =
+            vec![("a".into(), 1), ("b".into(), 2), ("c".into(), 3)];
+
+        let obs_result = parse_lines_to_map::<String, u64>(
+            input_bytes,
+            0,
+            &parse_options(BadRecordPolicy::Zero),
+        );
+        assert!(obs_result.is_ok());
+
+        let (obs_vector, obs_bad) = obs_result.unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert!(obs_bad.is_empty());
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_fail() {
+        let input_bytes = "a\t1\nb\t2\nc\tq\n".as_bytes();
+
+        let exp_vector: Vec<(String, u64)> =
+            vec![("a".into(), 1), ("b".into(), 2), ("c".into(), 0)];
+
+        let (obs_vector, _) = parse_lines_to_map::<String, u64>(
+            input_bytes,
+            0,
+            &parse_options(BadRecordPolicy::Zero),
+        )
+        .unwrap();
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_skip() {
+        let input_bytes = "a\t1\nb\tq\nc\t3\n".as_bytes();
+
+        let exp_vector: Vec<(String, u64)> = vec![("a".into(), 1), ("c".into(), 3)];
+
+        let (obs_vector, obs_bad) = parse_lines_to_map::<String, u64>(
+            input_bytes,
+            0,
+            &parse_options(BadRecordPolicy::Skip),
+        )
+        .unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert!(obs_bad.is_empty());
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_tombstone() {
+        let input_bytes = "a\t1\nb\t<deleted>\nc\t3\n".as_bytes();
+
+        // Tombstones are applied by compaction, and are neither records nor bad records
+        let exp_vector: Vec<(String, u64)> = vec![("a".into(), 1), ("c".into(), 3)];
+
+        let (obs_vector, obs_bad) = parse_lines_to_map::<String, u64>(
+            input_bytes,
+            0,
+            &parse_options(BadRecordPolicy::Collect),
+        )
+        .unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert!(obs_bad.is_empty());
+    }
+
+    #[test]
+    fn test_parse_lines_from_counts() {
+        let input_bytes = "a\tq\nb\t2\nc\tq\n".as_bytes();
+
+        let frame_ledger = FrameLedger::default();
+        parse_lines_from::<String, u64>(
+            input_bytes,
+            0,
+            0,
+            &parse_options(BadRecordPolicy::Zero),
+            &frame_ledger,
+        )
+        .unwrap();
+        parse_lines_from::<String, u64>(
+            input_bytes,
+            1,
+            0,
+            &parse_options(BadRecordPolicy::Skip),
+            &frame_ledger,
+        )
+        .unwrap();
+
+        let obs_summary = frame_ledger.into_summary(Vec::new());
+        assert_eq!(2, obs_summary.zeroed_records);
+        assert_eq!(2, obs_summary.skipped_records);
+        assert!(!obs_summary.is_partial());
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_error() {
+        let input_bytes = "a\t1\nb\tq\nc\t3\n".as_bytes();
+
+        let obs_result = parse_lines_to_map::<String, u64>(
+            input_bytes,
+            4,
+            &parse_options(BadRecordPolicy::Error),
+        );
+        assert!(obs_result.is_err());
+
+        let obs_error = obs_result.unwrap_err();
+        assert_eq!(
+            Some(&BadRecord::new(4, 4, b"b\tq")),
+            obs_error.downcast_ref::<BadRecord>()
+        );
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_u32_overflow() {
+        let input_bytes = "a\t4294967295\nb\t4294967296\n".as_bytes();
+
+        let exp_vector: Vec<(String, u32)> = vec![("a".into(), u32::MAX)];
+        let exp_bad = vec![BadRecord::new(0, 13, b"b\t4294967296")];
+
+        let (obs_vector, obs_bad) = parse_lines_to_map::<String, u32>(
+            input_bytes,
+            0,
+            &parse_options(BadRecordPolicy::Collect),
+        )
+        .unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert_eq!(exp_bad, obs_bad);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_string_values() {
+        let input_bytes = "a\t562\tEscherichia coli\nb\t1280\tStaphylococcus aureus\n".as_bytes();
+
+        let exp_vector: Vec<(String, String)> = vec![
+            ("a".into(), "562\tEscherichia coli".into()),
+            ("b".into(), "1280\tStaphylococcus aureus".into()),
+        ];
+
+        let (obs_vector, obs_bad) =
+            parse_lines_to_map::<String, String>(input_bytes, 0, &ParseOptions::default()).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert!(obs_bad.is_empty());
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_value_columns() {
+        let input_bytes = "a\t562\tEscherichia coli\tx\nb\t1280\n".as_bytes();
+        let parse_options = ParseOptions {
+            bad_record: BadRecordPolicy::Collect,
+            value_columns: vec![4, 3],
+            ..Default::default()
+        };
+
+        let exp_vector: Vec<(String, String)> = vec![("a".into(), "x\tEscherichia coli".into())];
+        let exp_bad = vec![BadRecord::new(0, 25, b"b\t1280")];
+
+        let (obs_vector, obs_bad) =
+            parse_lines_to_map::<String, String>(input_bytes, 0, &parse_options).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert_eq!(exp_bad, obs_bad);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_key_columns() {
+        let input_bytes = "WP_1\t1\t562\nWP_1\t2\t1280\nWP_2\n".as_bytes();
+        let parse_options = ParseOptions {
+            key_columns: vec![1, 2],
+            key_joiner: ".".into(),
+            ..Default::default()
+        };
+
+        // The line without the key columns is not a record
+        let exp_vector: Vec<(String, u64)> = vec![("WP_1.1".into(), 562), ("WP_1.2".into(), 1280)];
+
+        let (obs_vector, _) =
+            parse_lines_to_map::<String, u64>(input_bytes, 0, &parse_options).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_strip_key_version() {
+        assert_eq!(b"WP_413685322", strip_key_version(b"WP_413685322.1"));
+        assert_eq!(b"NC_000913", strip_key_version(b"NC_000913.31"));
+        assert_eq!(b"WP_413685322", strip_key_version(b"WP_413685322"));
+        assert_eq!(b"sample.v2", strip_key_version(b"sample.v2"));
+        assert_eq!(b"WP_1.", strip_key_version(b"WP_1."));
+        assert_eq!(b".1", strip_key_version(b".1"));
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_strip_key_version() {
+        let input_bytes = "WP_1.1\t562\nWP_2\t1280\n".as_bytes();
+        let parse_options = ParseOptions {
+            strip_key_version: true,
+            ..Default::default()
+        };
+
+        let exp_vector: Vec<(String, u64)> = vec![("WP_1".into(), 562), ("WP_2".into(), 1280)];
+
+        let (obs_vector, _) =
+            parse_lines_to_map::<String, u64>(input_bytes, 0, &parse_options).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_select_columns() {
+        let line_repr = b"a\t1\tb\t2";
+
+        assert_eq!(
+            b"2\t1".to_vec(),
+            select_columns(line_repr, &[4, 2], b"\t").unwrap()
+        );
+        assert_eq!(
+            b"a.1".to_vec(),
+            select_columns(line_repr, &[1, 2], b".").unwrap()
+        );
+        assert!(select_columns(line_repr, &[5], b"\t").is_err());
+        assert!(select_columns(line_repr, &[0], b"\t").is_err());
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_collect() {
+        let input_bytes = "a\t1\nb\tq\nc\t3\nd\t\n".as_bytes();
+
+        let exp_vector: Vec<(String, u64)> = vec![("a".into(), 1), ("c".into(), 3)];
+        let exp_bad = vec![BadRecord::new(4, 4, b"b\tq"), BadRecord::new(4, 12, b"d\t")];
+
+        let (obs_vector, obs_bad) = parse_lines_to_map::<String, u64>(
+            input_bytes,
+            4,
+            &parse_options(BadRecordPolicy::Collect),
+        )
+        .unwrap();
+        assert_eq!(exp_vector, obs_vector);
+        assert_eq!(exp_bad, obs_bad);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_lossy_utf8() {
+        let input_bytes = b"a\xff\t1\nb\t2\n";
+
+        let exp_vector: Vec<(String, u64)> = vec![("a\u{FFFD}".into(), 1), ("b".into(), 2)];
+
+        let (obs_vector, _) =
+            parse_lines_to_map::<String, u64>(input_bytes, 0, &ParseOptions::default()).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_strict_utf8() {
+        let input_bytes = b"a\t1\nb\xff\t2\n";
+        let parse_options = ParseOptions {
+            strict_utf8: true,
+            ..Default::default()
+        };
+
+        let obs_result = parse_lines_to_map::<String, u64>(input_bytes, 3, &parse_options);
+        assert!(obs_result.is_err());
+
+        let obs_error = obs_result.unwrap_err();
+        assert_eq!(
+            Some(&BadRecord::new(3, 4, b"b\xff\t2")),
+            obs_error.downcast_ref::<BadRecord>()
+        );
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_bytes() {
+        let input_bytes = b"a\t1\nb\xff\t2\n";
+        let parse_options = ParseOptions {
+            strict_utf8: true,
+            ..Default::default()
+        };
+
+        let exp_vector: Vec<(Box<[u8]>, u64)> =
+            vec![(b"a".as_slice().into(), 1), (b"b\xff".as_slice().into(), 2)];
+
+        let (obs_vector, _) =
+            parse_lines_to_map::<Box<[u8]>, u64>(input_bytes, 0, &parse_options).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_kraken2() {
+        let input_bytes = concat!(
+            "C\tread_1\t562\t150\t562:116 0:0\n",
+            "U\tread_2\t0\t150\t0:116\n",
+            "C\tread_3\tProteus mirabilis (taxid 584)\t150\t584:116\n",
+        )
+        .as_bytes();
+        let parse_options = ParseOptions {
+            format: RecordFormat::Kraken2,
+            ..Default::default()
+        };
+
+        let exp_vector: Vec<(String, u64)> = vec![
+            ("read_1".into(), 562),
+            ("read_2".into(), 0),
+            ("read_3".into(), 584),
+        ];
+
+        let (obs_vector, _) =
+            parse_lines_to_map::<String, u64>(input_bytes, 0, &parse_options).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_parse_lines_to_map_centrifuge() {
+        let input_bytes = concat!(
+            "readID\tseqID\ttaxID\tscore\t2ndBestScore\thitLength\tqueryLength\tnumMatches\n",
+            "read_1\tNC_000913.3\t562\t4225\t0\t80\t150\t1\n",
+            "read_2\tunclassified\t0\t0\t0\t0\t150\t1\n",
+        )
+        .as_bytes();
+        let parse_options = ParseOptions {
+            bad_record: BadRecordPolicy::Error,
+            format: RecordFormat::Centrifuge,
+            ..Default::default()
+        };
+
+        let exp_vector: Vec<(String, u64)> = vec![("read_1".into(), 562), ("read_2".into(), 0)];
+
+        let (obs_vector, _) =
+            parse_lines_to_map::<String, u64>(input_bytes, 0, &parse_options).unwrap();
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_map_zstd_frame() {
+        // Take from the final block of the test data
+        let idx_frame = FrameMeta::new(301, 120, 2);
+        let input_file = "test/example.zstd";
+
+        let exp_vector: Vec<(String, u64)> = vec![
+            ("MDY3279706.1".into(), 2831996),
+            ("PYI97175.1".into(), 2026799),
+            ("PYJ33862.1".into(), 2026799),
+            ("WP_137987990.1".into(), 492670),
+            ("TKZ18939.1".into(), 492670),
+            ("WP_372757791.1".into(), 1979402),
+            ("KLA26572.1".into(), 1396),
+            ("GAA1911923.1".into(), 433649),
+        ];
+
+        let obs_result = map_zstd_frame::<String, u64>(
+            &FileSource::new(input_file),
+            idx_frame,
+            &ParseOptions::default(),
+            &FrameLedger::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        let (obs_vector, _) = obs_result.unwrap();
+
+        assert_eq!(exp_vector, obs_vector);
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_dashmap() {
+        let input_file = "test/example.zstd";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let exp_map: AHashMap<String, u64> = data_to_ahashmap("test/data.txt");
+
+        let obs_result = read_indexed_zstd_dashmap::<String, u64>(
+            &FileSource::new(input_file),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        // DashMap does not implement PartialEq, so cast to HashMap for easy comparison.
+        match obs_result.unwrap().0.into_dash() {
+            Some(m) => {
+                let obs_map: AHashMap<String, u64> = m.into_iter().collect();
+                assert_eq!(exp_map, obs_map);
+            }
+            None => panic!("Returned data was not of type DashMap"),
+        };
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_failed_frames() {
+        let (zstd_file, idx_file) = write_test_archive(
+            "read_indexed_zstd_failed_frames",
+            &["a\t1\n", "b\t2\n", "c\t3\n"],
+        );
+
+        // Overwrite the middle frame so that it can no longer be decoded
+        let idx_records: Vec<FrameMeta> =
+            serde_json::from_reader(open_file_read(&idx_file)).unwrap();
+        let mut payload = std::fs::read(&zstd_file).unwrap();
+        let corrupt_start = idx_records[1].position as usize;
+        let corrupt_end = corrupt_start + idx_records[1].length as usize;
+        payload[corrupt_start..corrupt_end].fill(0);
+        std::fs::write(&zstd_file, payload).unwrap();
+
+        let idx_buffer = load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+        let obs_result = read_indexed_zstd_merge::<String, u64>(
+            &FileSource::new(&zstd_file),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        );
+
+        let _ = std::fs::remove_file(&zstd_file);
+        let _ = std::fs::remove_file(&idx_file);
+
+        let (obs_map, obs_summary) = obs_result.unwrap();
+        assert_eq!(2, obs_map.len());
+        assert_eq!(1, obs_summary.failed_frames.len());
+        assert_eq!(1, obs_summary.failed_frames[0].order);
+        assert_eq!(
+            idx_records[1].position,
+            obs_summary.failed_frames[0].position
+        );
+        assert!(obs_summary.is_partial());
+    }
+
+    #[test]
+    fn test_frame_ledger_stitch() {
+        let frame_ledger = FrameLedger::default();
+        let frames: [&[u8]; 3] = [b"a\t1\nb\t", b"2\nc", b"\t3\n"];
+
+        // Frames are split in any order, as the workers complete them
+        let exp_lines: [(usize, &[u8]); 3] = [(0, b"a\t1\n"), (2, b""), (3, b"")];
+        for order in [2, 0, 1] {
+            let obs_lines = frame_ledger.split_fragments(order, frames[order as usize]);
+            assert_eq!(exp_lines[order as usize], obs_lines);
+        }
+
+        let obs_stitched = frame_ledger
+            .stitch::<String, u64>(&parse_options(BadRecordPolicy::Collect))
+            .unwrap();
+        let exp_stitched: Vec<(u64, FrameRecords<String, u64>)> = vec![
+            (0, (vec![("b".into(), 2)], Vec::new())),
+            (1, (vec![("c".into(), 3)], Vec::new())),
+        ];
+        assert_eq!(exp_stitched, obs_stitched);
+    }
+
+    #[test]
+    fn test_frame_ledger_stitch_failed() {
+        let frame_ledger = FrameLedger::default();
+        frame_ledger.split_fragments(0, b"a\t1\nb\t");
+        frame_ledger.split_fragments(2, b"2\nc\t3\nd\t");
+        frame_ledger.split_fragments(3, b"q\ne\t5");
+        frame_ledger.failed.lock().unwrap().push(FailedFrame {
+            order: 1,
+            position: 0,
+            error: ErrorClass::CorruptArchive,
+        });
+
+        // Lines which touch the lost frame are dropped, and bad lines keep their frame offset
+        let (obs_order, (obs_records, obs_bad)) = frame_ledger
+            .stitch::<String, u64>(&parse_options(BadRecordPolicy::Collect))
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(3, obs_order);
+        assert_eq!(vec![("e".to_string(), 5)], obs_records);
+        assert!(obs_bad.is_empty());
+
+        let frame_ledger = FrameLedger::default();
+        frame_ledger.split_fragments(0, b"a\t1\nb\t");
+        frame_ledger.split_fragments(1, b"q\n");
+        let obs_stitched = frame_ledger
+            .stitch::<String, u64>(&parse_options(BadRecordPolicy::Collect))
+            .unwrap();
+        assert_eq!(vec![BadRecord::new(0, 4, b"b\tq")], obs_stitched[0].1 .1);
+
+        // Nothing is recovered from the end of the file if the final frame was lost
+        let frame_ledger = FrameLedger::default();
+        frame_ledger.split_fragments(0, b"a\t1\nb\t");
+        frame_ledger.failed.lock().unwrap().push(FailedFrame {
+            order: 1,
+            position: 0,
+            error: ErrorClass::CorruptArchive,
+        });
+        let obs_stitched = frame_ledger
+            .stitch::<String, u64>(&ParseOptions::default())
+            .unwrap();
+        assert!(obs_stitched.is_empty());
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_split_records() {
+        // Records run across frame boundaries, as when frames are cut by size alone
+        let frames = ["a\t1\nb\t", "2\na\t", "3\n", "c\t1", "0\nb\t4"];
+        let (zstd_file, idx_file) = write_test_archive("read_indexed_zstd_split_records", &frames);
+
+        let exp_map: AHashMap<String, u64> =
+            AHashMap::from_iter([("a".into(), 3), ("b".into(), 4), ("c".into(), 10)]);
+        let parse_options = ParseOptions {
+            split_records: true,
+            ..Default::default()
+        };
+
+        for _ in 0..5 {
+            let idx_buffer =
+                load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+            let obs_result = read_indexed_zstd_vector::<String, u64>(
+                &FileSource::new(&zstd_file),
+                idx_buffer,
+                4,
+                &parse_options,
+            );
+
+            match obs_result.unwrap().0.into_ahash() {
+                Some(obs_map) => assert_eq!(exp_map, obs_map),
+                None => panic!("Returned data was not of type AHashMap"),
+            };
+        }
+
+        let idx_buffer = load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+        let obs_result = read_indexed_zstd_merge::<String, u64>(
+            &FileSource::new(&zstd_file),
+            idx_buffer,
+            2,
+            &parse_options,
+        );
+
+        let _ = std::fs::remove_file(&zstd_file);
+        let _ = std::fs::remove_file(&idx_file);
+
+        assert_eq!(3, obs_result.unwrap().0.len());
+    }
+
+    #[test]
+    fn test_read_indexed_gzip() {
+        let mut payload: Vec<u8> = Vec::new();
+        let mut idx_buffer: Vec<FrameMeta> = Vec::new();
+        for (order, content) in ["a\t1\nb", "\t2\nc\t3\n"].iter().enumerate() {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+            std::io::Write::write_all(&mut encoder, content.as_bytes()).unwrap();
+            let member = encoder.finish().unwrap();
+
+            idx_buffer.push(FrameMeta::new(
+                payload.len() as u64,
+                member.len() as u64,
+                order as u64,
+            ));
+            payload.extend(member);
+        }
+
+        let gzip_file = "read_indexed_gzip.gz";
+        std::fs::write(gzip_file, payload).unwrap();
+
+        let parse_options = ParseOptions {
+            codec: Codec::Gzip,
+            split_records: true,
+            ..Default::default()
+        };
+        let obs_result = read_indexed_zstd_dashmap::<String, u64>(
+            &FileSource::new(gzip_file),
+            idx_buffer,
+            2,
+            &parse_options,
+        );
+        let _ = std::fs::remove_file(gzip_file);
+
+        let exp_map: AHashMap<String, u64> =
+            AHashMap::from_iter([("a".into(), 1), ("b".into(), 2), ("c".into(), 3)]);
+        match obs_result.unwrap().0.into_dash() {
+            Some(m) => {
+                let obs_map: AHashMap<String, u64> = m.into_iter().collect();
+                assert_eq!(exp_map, obs_map);
+            }
+            None => panic!("Returned data was not of type DashMap"),
+        };
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_verify_frames() {
+        let mut idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        idx_buffer[1] = idx_buffer[1].clone().with_checksum(0);
+
+        let parse_options = ParseOptions {
+            verify_frames: true,
+            ..Default::default()
+        };
+        let obs_result = read_indexed_zstd_merge::<String, u64>(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer.clone(),
+            2,
+            &parse_options,
+        );
+
+        let (obs_map, obs_summary) = obs_result.unwrap();
+        assert_eq!(20, obs_map.len());
+        assert_eq!(
+            vec![1],
+            obs_summary
+                .failed_frames
+                .iter()
+                .map(|f| f.order)
+                .collect::<Vec<_>>()
+        );
+
+        // Without verification the mismatch goes unnoticed
+        let obs_result = read_indexed_zstd_merge::<String, u64>(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        );
+        assert!(obs_result.unwrap().1.failed_frames.is_empty());
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_vector() {
+        let input_file = "test/example.zstd";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let exp_map: AHashMap<String, u64> = data_to_ahashmap("test/data.txt");
+
+        let obs_result = read_indexed_zstd_vector::<String, u64>(
+            &FileSource::new(input_file),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        match obs_result.unwrap().0.into_ahash() {
+            Some(obs_map) => assert_eq!(exp_map, obs_map),
+            None => panic!("Returned data was not of type AHashMap"),
+        };
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_vector_ordering() {
+        // Duplicate keys must resolve to the last value in file order, regardless of the order
+        // in which frames complete.
+        let frames = ["a\t1\nb\t1\n", "a\t2\n", "c\t1\n", "b\t3\na\t4\n", "c\t5\n"];
+        let (zstd_file, idx_file) =
+            write_test_archive("read_indexed_zstd_vector_ordering", &frames);
+
+        let exp_map: AHashMap<String, u64> =
+            AHashMap::from_iter([("a".into(), 4), ("b".into(), 3), ("c".into(), 5)]);
+
+        for _ in 0..10 {
+            let idx_buffer =
+                load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+            let obs_result = read_indexed_zstd_vector::<String, u64>(
+                &FileSource::new(&zstd_file),
+                idx_buffer,
+                4,
+                &ParseOptions::default(),
+            );
+
+            match obs_result.unwrap().0.into_ahash() {
+                Some(obs_map) => assert_eq!(exp_map, obs_map),
+                None => panic!("Returned data was not of type AHashMap"),
+            };
+        }
+
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(idx_file);
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_vector_duplicate_keys() {
+        // Once versions are stripped, the first two records share a key
+        let frames = ["a.1\t1\nb.1\t2\n", "a.2\t3\n"];
+        let (zstd_file, idx_file) =
+            write_test_archive("read_indexed_zstd_vector_duplicate_keys", &frames);
+
+        let obs_maps: Vec<Result<AHashMap<String, u64>>> = [
+            DuplicatePolicy::First,
+            DuplicatePolicy::Last,
+            DuplicatePolicy::Error,
+        ]
+        .into_iter()
+        .map(|duplicate_keys| {
+            let idx_buffer =
+                load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+            let parse_options = ParseOptions {
+                strip_key_version: true,
+                duplicate_keys,
+                ..Default::default()
+            };
+            read_indexed_zstd_vector::<String, u64>(
+                &FileSource::new(&zstd_file),
+                idx_buffer,
+                2,
+                &parse_options,
+            )
+            .map(|(m, _)| m.into_ahash().unwrap())
+        })
+        .collect();
+
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(idx_file);
+
+        let exp_first = AHashMap::from_iter([("a".to_string(), 1), ("b".to_string(), 2)]);
+        let exp_last = AHashMap::from_iter([("a".to_string(), 3), ("b".to_string(), 2)]);
+        assert_eq!(&exp_first, obs_maps[0].as_ref().unwrap());
+        assert_eq!(&exp_last, obs_maps[1].as_ref().unwrap());
+
+        let obs_error = obs_maps[2].as_ref().unwrap_err();
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(obs_error));
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_dashmap_duplicate_error() {
+        let frames = ["a\t1\n", "b\t2\n", "a\t3\n"];
+        let (zstd_file, idx_file) =
+            write_test_archive("read_indexed_zstd_dashmap_duplicate_error", &frames);
+
+        let idx_buffer = load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+        let parse_options = ParseOptions {
+            duplicate_keys: DuplicatePolicy::Error,
+            ..Default::default()
+        };
+        let obs_result = read_indexed_zstd_dashmap::<String, u64>(
+            &FileSource::new(&zstd_file),
+            idx_buffer,
+            2,
+            &parse_options,
+        );
+
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(idx_file);
+        assert!(obs_result.is_err());
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_ordered() {
+        let input_file = "test/example.zstd";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let exp_keys: Vec<String> = BufReader::new(open_file_read("test/data.txt"))
+            .lines()
+            .map(|line| line.unwrap().split_once('\t').unwrap().0.to_string())
+            .collect();
+        let exp_map: AHashMap<String, u64> = data_to_ahashmap("test/data.txt");
+
+        let obs_result = read_indexed_zstd_ordered::<String, u64>(
+            &FileSource::new(input_file),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        match obs_result.unwrap().0.into_ordered() {
+            Some(obs_map) => {
+                let obs_keys: Vec<String> = obs_map.keys().cloned().collect();
+                assert_eq!(exp_keys, obs_keys);
+
+                let obs_map: AHashMap<String, u64> = obs_map.into_iter().collect();
+                assert_eq!(exp_map, obs_map);
+            }
+            None => panic!("Returned data was not of type IndexMap"),
+        };
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_reference() {
+        let input_file = "test/example.zstd";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let exp_keys: Vec<String> = BufReader::new(open_file_read("test/data.txt"))
+            .lines()
+            .map(|line| line.unwrap().split_once('\t').unwrap().0.to_string())
+            .collect();
+
+        // Frames listed out of order are still read in file order
+        let mut idx_buffer = idx_buffer;
+        idx_buffer.reverse();
+        let obs_result = read_indexed_zstd_reference::<String, u64>(
+            &FileSource::new(input_file),
+            idx_buffer,
+            &ParseOptions::default(),
+        );
+
+        match obs_result.unwrap().0.into_ordered() {
+            Some(obs_map) => {
+                let obs_keys: Vec<String> = obs_map.keys().cloned().collect();
+                assert_eq!(exp_keys, obs_keys);
+            }
+            None => panic!("Returned data was not of type IndexMap"),
+        };
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_merge() {
+        let input_file = "test/example.zstd";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let exp_map: AHashMap<String, u64> = data_to_ahashmap("test/data.txt");
+
+        let obs_result = read_indexed_zstd_merge::<String, u64>(
+            &FileSource::new(input_file),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        match obs_result.unwrap().0.into_ahash() {
+            Some(obs_map) => assert_eq!(exp_map, obs_map),
+            None => panic!("Returned data was not of type AHashMap"),
+        };
+    }
+
+    #[test]
+    fn test_schedule_frames() {
+        let idx_buffer = vec![
+            FrameMeta::new(0, 100, 0),
+            FrameMeta::new(100, 300, 1),
+            FrameMeta::new(400, 100, 2),
+            FrameMeta::new(500, 200, 3),
+        ];
+        let scheduled_orders = |schedule: FrameSchedule| -> Vec<u64> {
+            schedule_frames(idx_buffer.clone(), &schedule)
+                .iter()
+                .map(|f| f.order)
+                .collect()
+        };
+
+        assert_eq!(vec![0, 1, 2, 3], scheduled_orders(FrameSchedule::FileOrder));
+        assert_eq!(
+            vec![1, 3, 0, 2],
+            scheduled_orders(FrameSchedule::LargestFirst)
+        );
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_ordered_largest_first() {
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let exp_keys: Vec<String> = BufReader::new(open_file_read("test/data.txt"))
+            .lines()
+            .map(|line| line.unwrap().split_once('\t').unwrap().0.to_string())
+            .collect();
+        let parse_options = ParseOptions {
+            schedule: FrameSchedule::LargestFirst,
+            ..Default::default()
+        };
+
+        // Frames are decoded largest first, but merged in file order
+        let obs_result = read_indexed_zstd_ordered::<String, u64>(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            2,
+            &parse_options,
+        );
+        match obs_result.unwrap().0.into_ordered() {
+            Some(obs_map) => {
+                let obs_keys: Vec<String> = obs_map.keys().cloned().collect();
+                assert_eq!(exp_keys, obs_keys);
+            }
+            None => panic!("Returned data was not of type IndexMap"),
+        };
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&std::io::Error::from(ErrorKind::TimedOut)));
+        assert!(is_transient(&std::io::Error::from_raw_os_error(
+            libc::ESTALE
+        )));
+
+        assert!(!is_transient(&std::io::Error::from(ErrorKind::NotFound)));
+        assert!(!is_transient(&std::io::Error::from(
+            ErrorKind::UnexpectedEof
+        )));
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let parse_options = ParseOptions {
+            retry: RetryPolicy {
+                retries: 3,
+                delay: std::time::Duration::from_millis(1),
+            },
+            ..Default::default()
+        };
+
+        let mut attempts = 0;
+        let obs_result = with_retries(&parse_options, 0, || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(std::io::Error::from(ErrorKind::TimedOut)),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(3, obs_result.unwrap());
+    }
+
+    #[test]
+    fn test_with_retries_exhausted() {
+        let parse_options = ParseOptions {
+            retry: RetryPolicy {
+                retries: 2,
+                delay: std::time::Duration::from_millis(1),
+            },
+            ..Default::default()
+        };
+
+        let mut attempts = 0;
+        let obs_result: std::io::Result<()> = with_retries(&parse_options, 0, || {
+            attempts += 1;
+            Err(std::io::Error::from(ErrorKind::ConnectionReset))
+        });
+        assert!(obs_result.is_err());
+        assert_eq!(3, attempts);
+    }
+
+    #[test]
+    fn test_with_retries_not_transient() {
+        let mut attempts = 0;
+        let obs_result: std::io::Result<()> = with_retries(&ParseOptions::default(), 0, || {
+            attempts += 1;
+            Err(std::io::Error::from(ErrorKind::NotFound))
+        });
+        assert!(obs_result.is_err());
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    fn test_map_zstd_frame_profiler() {
+        let profiler = Arc::new(StageProfiler::default());
+        let parse_options = ParseOptions {
+            profiler: Some(Arc::clone(&profiler)),
+            ..Default::default()
+        };
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let obs_result = read_indexed_zstd_dashmap::<String, u64>(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            2,
+            &parse_options,
+        );
+        assert!(obs_result.is_ok());
+
+        // A read, decode, parse and insert event for each of the three frames
+        assert_eq!(12, profiler.len());
+
+        let obs_summary = profiler.summary();
+        assert_eq!(421, obs_summary.bytes_read);
+    }
+
+    #[test]
+    fn test_read_indexed_zstd_merge_bytes() {
+        let input_file = "test/example.zstd";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let exp_map: AHashMap<Box<[u8]>, u64> = data_to_ahashmap("test/data.txt")
+            .into_iter()
+            .map(|(k, v)| (k.into_bytes().into_boxed_slice(), v))
+            .collect();
+
+        let obs_result = read_indexed_zstd_merge::<Box<[u8]>, u64>(
+            &FileSource::new(input_file),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        match obs_result.unwrap().0.into_ahash() {
+            Some(obs_map) => assert_eq!(exp_map, obs_map),
+            None => panic!("Returned data was not of type AHashMap"),
+        };
+    }
+}
@@ -0,0 +1,290 @@
+use crate::compression::{FrameMetadata, FRAME_METADATA_MAGIC, FRAME_METADATA_SIZE};
+use crate::numa::{build_worker_pool, ThreadOptions};
+use crate::scan::ZSTD_MAGIC;
+use crate::source::FrameSource;
+use crate::{Codec, FrameMeta, IndexHeader};
+use rayon::prelude::*;
+use serde::Serialize;
+
+/// Version of the archive layout described: zstd frames of whole records, each optionally led by
+/// a skippable frame of metadata or padding, listed by a JSON frame index. Raised whenever the
+/// layout changes in a way other readers must know of.
+pub const FORMAT_VERSION: u32 = 1;
+
+// Longest zstd frame header: magic, descriptor, window, dictionary id and content size
+const MAX_FRAME_HEADER: usize = 18;
+// Skippable frame of frame metadata: magic, size and the metadata itself
+const METADATA_FRAME_SIZE: u64 = 8 + FRAME_METADATA_SIZE as u64;
+
+/// What the header of a zstd frame says of it.
+#[derive(Clone, Debug, PartialEq)]
+struct FrameHeader {
+    content_size: Option<u64>,
+    content_checksum: bool,
+    dictionary_id: Option<u32>,
+    window_size: Option<u64>,
+}
+
+/// Everything an archive says of itself, gathered from its index, the headers of its frames and
+/// any frame metadata written ahead of them, for other implementations to read the format by.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ArchiveDescription {
+    pub format_version: u32,
+    pub archive: String,
+    /// Index the frames were listed from, or `None` when the archive was scanned for them
+    pub index: Option<String>,
+    pub codec: String,
+    pub frames: usize,
+    /// Length of the archive file, when it is local
+    pub archive_bytes: Option<u64>,
+    /// Total length of the indexed frames
+    pub frame_bytes: u64,
+    /// Total decompressed length, when every frame records its own
+    pub uncompressed_bytes: Option<u64>,
+    /// Largest decompressed frame, close to the block size the archive was compressed with
+    pub largest_frame_bytes: Option<u64>,
+    /// Records in the archive, when the index counts them for every frame
+    pub records: Option<u64>,
+    /// Range of zstd levels the frames were compressed at, when the index records them
+    pub levels: Option<(i32, i32)>,
+    /// Checksums held for the frame content
+    pub checksums: Vec<String>,
+    /// Optional features of the layout found in the archive
+    pub flags: Vec<String>,
+    /// Largest window a decoder needs, from the frame headers
+    pub window_bytes: Option<u64>,
+    /// Modification times of the archive and index, in seconds since the Unix epoch
+    pub archive_modified: Option<u64>,
+    pub index_modified: Option<u64>,
+    /// Version, command line, host, time and inputs of the run which made the archive, from the
+    /// header of its index
+    pub created_by: Option<IndexHeader>,
+}
+
+//region: Private functions
+
+/// Read a zstd frame header from the bytes at the start of a frame.
+fn parse_frame_header(header: &[u8]) -> Option<FrameHeader> {
+    if u32::from_le_bytes(header.get(..4)?.try_into().ok()?) != ZSTD_MAGIC {
+        return None;
+    }
+    // Frame header descriptor: content size flag (2 bits), single segment (1), unused (1),
+    // reserved (1), checksum (1), dictionary id flag (2)
+    let descriptor = *header.get(4)?;
+    let single_segment = descriptor & 0x20 != 0;
+
+    let mut offset = 5;
+    let window_descriptor = match single_segment {
+        true => None,
+        false => {
+            offset += 1;
+            Some(*header.get(5)?)
+        }
+    };
+    let dictionary_length = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let dictionary_id = header
+        .get(offset..offset + dictionary_length)?
+        .iter()
+        .rev()
+        .fold(0u32, |id, b| (id << 8) | *b as u32);
+
+    let content_size = zstd::zstd_safe::get_frame_content_size(header)
+        .ok()
+        .flatten();
+    let window_size = match window_descriptor {
+        Some(w) => {
+            let window_base = 1u64 << (10 + (w >> 3));
+            Some(window_base + window_base / 8 * (w & 0x07) as u64)
+        }
+        // A single segment frame is decoded in one window of its content size
+        None => content_size,
+    };
+
+    Some(FrameHeader {
+        content_size,
+        content_checksum: descriptor & 0x04 != 0,
+        dictionary_id: (dictionary_id != 0).then_some(dictionary_id),
+        window_size,
+    })
+}
+
+/// Read the header of a frame, along with the frame metadata written ahead of it if there is any.
+fn inspect_frame(
+    source: &dyn FrameSource,
+    frame_meta: &FrameMeta,
+) -> (Option<FrameHeader>, Option<FrameMetadata>) {
+    let lead = frame_meta.position.min(METADATA_FRAME_SIZE);
+    let header_length = frame_meta.length.min(MAX_FRAME_HEADER as u64);
+    let bytes = match source.read_at(frame_meta.position - lead, (lead + header_length) as usize) {
+        Ok(b) => b,
+        Err(_) => return (None, None),
+    };
+    let (leading, header) = bytes.split_at((lead as usize).min(bytes.len()));
+
+    let frame_metadata = match leading.len() == METADATA_FRAME_SIZE as usize
+        && leading[..4] == FRAME_METADATA_MAGIC.to_le_bytes()
+        && leading[4..8] == (FRAME_METADATA_SIZE as u32).to_le_bytes()
+    {
+        true => Some(FrameMetadata::from_bytes(leading[8..].try_into().unwrap())),
+        false => None,
+    };
+    (parse_frame_header(header), frame_metadata)
+}
+
+//endregion:
+
+/// Describe an archive from its frame index, reading the header of every frame in parallel. Gzip
+/// members carry none of the details of zstd frames, so only the index is described for them.
+pub(crate) fn describe_archive(
+    source: &dyn FrameSource,
+    idx_buffer: &[FrameMeta],
+    codec: &Codec,
+    num_threads: usize,
+) -> ArchiveDescription {
+    let inspected: Vec<(Option<FrameHeader>, Option<FrameMetadata>)> = match codec {
+        Codec::Zstd => {
+            build_worker_pool(num_threads, false, &ThreadOptions::default()).install(|| {
+                idx_buffer
+                    .par_iter()
+                    .map(|f| inspect_frame(source, f))
+                    .collect()
+            })
+        }
+        Codec::Gzip => Vec::new(),
+    };
+    let headers: Vec<&FrameHeader> = inspected.iter().filter_map(|(h, _)| h.as_ref()).collect();
+
+    let frame_sizes: Vec<Option<u64>> = inspected
+        .iter()
+        .map(|(header, metadata)| {
+            header
+                .as_ref()
+                .and_then(|h| h.content_size)
+                .or(metadata.as_ref().map(|m| m.uncompressed_length))
+        })
+        .collect();
+    let uncompressed_bytes = match frame_sizes.len() == idx_buffer.len() {
+        true => frame_sizes.iter().copied().sum(),
+        false => None,
+    };
+
+    let records: Option<u64> = idx_buffer
+        .iter()
+        .map(|f| f.record_stats().map(|s| s.records))
+        .sum();
+    let levels =
+        idx_buffer
+            .iter()
+            .filter_map(|f| f.level)
+            .fold(None, |range: Option<(i32, i32)>, level| match range {
+                Some((low, high)) => Some((low.min(level), high.max(level))),
+                None => Some((level, level)),
+            });
+
+    let with_metadata = inspected.iter().any(|(_, m)| m.is_some());
+    let mut checksums: Vec<String> = Vec::new();
+    if with_metadata || idx_buffer.iter().any(|f| f.checksum.is_some()) {
+        checksums.push(String::from("xxh3-64"));
+    }
+    if headers.iter().any(|h| h.content_checksum) {
+        checksums.push(String::from("zstd-content-checksum"));
+    }
+
+    let mut ordered: Vec<&FrameMeta> = idx_buffer.iter().collect();
+    ordered.sort_by_key(|f| f.position);
+    let lead_bytes = match with_metadata {
+        true => METADATA_FRAME_SIZE,
+        false => 0,
+    };
+    let padded = ordered
+        .windows(2)
+        .any(|w| w[1].position > w[0].position + w[0].length + lead_bytes);
+
+    let mut flags: Vec<String> = Vec::new();
+    let flag_checks = [
+        ("frame-metadata", with_metadata),
+        ("padded", padded),
+        (
+            "key-sorted",
+            !idx_buffer.is_empty() && idx_buffer.iter().all(|f| f.key_range.is_some()),
+        ),
+        ("record-stats", records.is_some()),
+        ("per-frame-levels", levels.is_some()),
+        (
+            "dictionary",
+            headers.iter().any(|h| h.dictionary_id.is_some()),
+        ),
+    ];
+    for (flag, found) in flag_checks {
+        if found {
+            flags.push(flag.to_string());
+        }
+    }
+
+    ArchiveDescription {
+        format_version: FORMAT_VERSION,
+        archive: source.name().to_string(),
+        index: None,
+        codec: match codec {
+            Codec::Zstd => String::from("zstd"),
+            Codec::Gzip => String::from("gzip"),
+        },
+        frames: idx_buffer.len(),
+        archive_bytes: None,
+        frame_bytes: idx_buffer.iter().map(|f| f.length).sum(),
+        uncompressed_bytes,
+        largest_frame_bytes: frame_sizes.iter().flatten().max().copied(),
+        records,
+        levels,
+        checksums,
+        flags,
+        window_bytes: headers.iter().filter_map(|h| h.window_size).max(),
+        archive_modified: None,
+        index_modified: None,
+        created_by: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::MemorySource;
+
+    #[test]
+    fn test_parse_frame_header() {
+        let mut encoder = zstd::bulk::Compressor::new(3).unwrap();
+        encoder.include_contentsize(true).unwrap();
+        encoder.include_checksum(true).unwrap();
+        let frame = encoder.compress(b"a\t1\nb\t2\n").unwrap();
+
+        let obs_header = parse_frame_header(&frame[..MAX_FRAME_HEADER.min(frame.len())]).unwrap();
+        assert_eq!(Some(8), obs_header.content_size);
+        assert!(obs_header.content_checksum);
+        assert_eq!(None, obs_header.dictionary_id);
+        assert_eq!(Some(8), obs_header.window_size);
+
+        assert_eq!(None, parse_frame_header(b"\x1f\x8b\x08\x00"));
+    }
+
+    #[test]
+    fn test_describe_archive() {
+        let payload = std::fs::read("test/example.zstd").unwrap();
+        let idx_buffer: Vec<FrameMeta> =
+            serde_json::from_slice(&std::fs::read("test/example.zstd.idx").unwrap()).unwrap();
+        let source = MemorySource::new("example", payload);
+
+        let obs_description = describe_archive(&source, &idx_buffer, &Codec::Zstd, 2);
+        assert_eq!(3, obs_description.frames);
+        assert_eq!(421, obs_description.frame_bytes);
+        assert_eq!(Some(30), obs_description.records);
+        assert_eq!(vec!["record-stats"], obs_description.flags);
+
+        // Content is checksummed both in the index and by zstd, but its size is not recorded
+        assert_eq!(
+            vec!["xxh3-64", "zstd-content-checksum"],
+            obs_description.checksums
+        );
+        assert_eq!(None, obs_description.uncompressed_bytes);
+    }
+}
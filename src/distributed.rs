@@ -0,0 +1,609 @@
+use crate::decompression::{gather_zstd_frame, FrameLedger, FrameRecords};
+use crate::metrics::{FrameTiming, Metrics};
+use crate::numa::{build_worker_pool, ThreadOptions};
+use crate::source::FrameSource;
+use crate::{open_source, DecompressionSummary, FrameMeta, ParseOptions};
+use ahash::AHashMap;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type TaskRecords = (Vec<(String, u64)>, DecompressionSummary);
+
+// How long an idle worker waits before asking again, while other workers hold the last tasks
+const WORKER_WAIT: Duration = Duration::from_millis(250);
+const COORDINATOR_POLL: Duration = Duration::from_millis(50);
+
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Collect {
+    /// Return every record, merged into a single map
+    #[default]
+    Map,
+    /// Return only the number of records observed for each value
+    ValueCounts,
+}
+
+pub enum Gathered {
+    Map(AHashMap<String, u64>),
+    ValueCounts(AHashMap<u64, u64>),
+}
+
+impl Gathered {
+    pub fn len(&self) -> usize {
+        match self {
+            Gathered::Map(m) => m.len(),
+            Gathered::ValueCounts(m) => m.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum CoordinatorMessage {
+    Welcome {
+        zstd_file: String,
+        parse_options: ParseOptions,
+        collect: Collect,
+    },
+    Task {
+        task_id: usize,
+        frames: Vec<FrameMeta>,
+    },
+    Wait,
+    Finished,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum WorkerMessage {
+    Hello,
+    Request,
+    Records {
+        task_id: usize,
+        records: Vec<(String, u64)>,
+        summary: DecompressionSummary,
+        timings: Vec<FrameTiming>,
+    },
+    ValueCounts {
+        task_id: usize,
+        counts: Vec<(u64, u64)>,
+        summary: DecompressionSummary,
+        timings: Vec<FrameTiming>,
+    },
+    Failed {
+        task_id: usize,
+        error: String,
+    },
+}
+
+struct CoordinatorState {
+    pending: VecDeque<(usize, Vec<FrameMeta>)>,
+    in_flight: AHashMap<usize, Vec<FrameMeta>>,
+    completed: usize,
+    total: usize,
+    error: Option<String>,
+    gathered: Gathered,
+    summary: DecompressionSummary,
+    metrics: Arc<Metrics>,
+}
+
+impl CoordinatorState {
+    fn record_timings(&mut self, timings: &[FrameTiming]) {
+        for timing in timings {
+            self.metrics.record_frame(timing);
+        }
+        self.metrics.record_task_completed();
+        self.completed += 1;
+    }
+}
+
+//region: Private functions
+
+fn send_message<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn receive_message<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    match serde_json::from_str(&line) {
+        Ok(m) => Ok(Some(m)),
+        Err(e) => bail!("Unable to parse message from peer: {}", e),
+    }
+}
+
+fn plan_tasks(
+    idx_buffer: Vec<FrameMeta>,
+    frames_per_task: usize,
+) -> VecDeque<(usize, Vec<FrameMeta>)> {
+    idx_buffer
+        .chunks(frames_per_task.max(1))
+        .map(|c| c.to_vec())
+        .enumerate()
+        .collect()
+}
+
+fn handle_worker(
+    stream: TcpStream,
+    state: &Mutex<CoordinatorState>,
+    zstd_file: &str,
+    parse_options: &ParseOptions,
+    collect: &Collect,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut assigned: Vec<usize> = Vec::new();
+
+    let metrics = Arc::clone(&state.lock().unwrap().metrics);
+    metrics.worker_connected();
+
+    let outcome = (|| -> Result<()> {
+        while let Some(message) = receive_message::<WorkerMessage>(&mut reader)? {
+            let reply = match message {
+                WorkerMessage::Hello => CoordinatorMessage::Welcome {
+                    zstd_file: zstd_file.to_string(),
+                    parse_options: parse_options.clone(),
+                    collect: collect.clone(),
+                },
+                WorkerMessage::Request => {
+                    let mut s = state.lock().unwrap();
+                    match s.pending.pop_front() {
+                        _ if s.error.is_some() => CoordinatorMessage::Finished,
+                        Some((task_id, frames)) => {
+                            s.in_flight.insert(task_id, frames.clone());
+                            assigned.push(task_id);
+                            CoordinatorMessage::Task { task_id, frames }
+                        }
+                        None if s.in_flight.is_empty() => CoordinatorMessage::Finished,
+                        None => CoordinatorMessage::Wait,
+                    }
+                }
+                WorkerMessage::Records {
+                    task_id,
+                    records,
+                    summary,
+                    timings,
+                } => {
+                    let mut s = state.lock().unwrap();
+                    if s.in_flight.remove(&task_id).is_some() {
+                        s.record_timings(&timings);
+                        if let Gathered::Map(m) = &mut s.gathered {
+                            m.extend(records);
+                        }
+                        s.summary.absorb(summary);
+                    }
+                    assigned.retain(|t| *t != task_id);
+                    continue;
+                }
+                WorkerMessage::ValueCounts {
+                    task_id,
+                    counts,
+                    summary,
+                    timings,
+                } => {
+                    let mut s = state.lock().unwrap();
+                    if s.in_flight.remove(&task_id).is_some() {
+                        s.record_timings(&timings);
+                        if let Gathered::ValueCounts(m) = &mut s.gathered {
+                            for (value, count) in counts {
+                                *m.entry(value).or_default() += count;
+                            }
+                        }
+                        s.summary.absorb(summary);
+                    }
+                    assigned.retain(|t| *t != task_id);
+                    continue;
+                }
+                WorkerMessage::Failed { task_id, error } => {
+                    let mut s = state.lock().unwrap();
+                    s.in_flight.remove(&task_id);
+                    s.error = Some(format!("Task {} failed on a worker: {}", task_id, error));
+                    assigned.retain(|t| *t != task_id);
+                    continue;
+                }
+            };
+
+            let finished = matches!(reply, CoordinatorMessage::Finished);
+            send_message(&mut writer, &reply)?;
+            if finished {
+                break;
+            }
+        }
+        Ok(())
+    })();
+
+    // Any task still held by this worker is handed back to the queue for another worker
+    let mut s = state.lock().unwrap();
+    for task_id in assigned {
+        if let Some(frames) = s.in_flight.remove(&task_id) {
+            s.pending.push_back((task_id, frames));
+            metrics.record_task_requeued();
+        }
+    }
+    metrics.worker_disconnected();
+    outcome
+}
+
+fn timed_frame(
+    source: &dyn FrameSource,
+    idx_frame: FrameMeta,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+) -> Result<(Option<FrameRecords<String, u64>>, FrameTiming)> {
+    let bytes_read = idx_frame.parse_length()? as u64;
+    let start = Instant::now();
+    let frame_records = gather_zstd_frame(source, idx_frame, parse_options, frame_ledger)?;
+
+    let timing = FrameTiming {
+        bytes_read,
+        seconds: start.elapsed().as_secs_f64(),
+    };
+    Ok((frame_records, timing))
+}
+
+fn process_task(
+    source: &dyn FrameSource,
+    frames: Vec<FrameMeta>,
+    pool: &rayon::ThreadPool,
+    parse_options: &ParseOptions,
+) -> Result<(TaskRecords, Vec<FrameTiming>)> {
+    let frame_ledger = FrameLedger::default();
+    let frame_buffer: Vec<(Option<FrameRecords<String, u64>>, FrameTiming)> =
+        pool.install(|| {
+            frames
+                .into_par_iter()
+                .map(|idx_frame| timed_frame(source, idx_frame, parse_options, &frame_ledger))
+                .collect::<Result<_>>()
+        })?;
+
+    let (frame_records, timings): (Vec<_>, Vec<_>) = frame_buffer.into_iter().unzip();
+    let (records, bad_records): (Vec<_>, Vec<_>) = frame_records.into_iter().flatten().unzip();
+    Ok((
+        (
+            records.into_iter().flatten().collect(),
+            frame_ledger.into_summary(bad_records.into_iter().flatten().collect()),
+        ),
+        timings,
+    ))
+}
+
+//endregion:
+
+pub fn serve_frames(
+    zstd_file: &str,
+    idx_buffer: Vec<FrameMeta>,
+    listener: TcpListener,
+    frames_per_task: usize,
+    parse_options: &ParseOptions,
+    collect: &Collect,
+    metrics: Arc<Metrics>,
+) -> Result<(Gathered, DecompressionSummary)> {
+    let pending = plan_tasks(idx_buffer, frames_per_task);
+
+    let gathered = match collect {
+        Collect::Map => Gathered::Map(AHashMap::new()),
+        Collect::ValueCounts => Gathered::ValueCounts(AHashMap::new()),
+    };
+
+    let state = Arc::new(Mutex::new(CoordinatorState {
+        total: pending.len(),
+        pending,
+        in_flight: AHashMap::new(),
+        completed: 0,
+        error: None,
+        gathered,
+        summary: DecompressionSummary::default(),
+        metrics,
+    }));
+
+    // Poll for connections, so that the coordinator can stop once every task is complete
+    listener.set_nonblocking(true)?;
+
+    loop {
+        {
+            let s = state.lock().unwrap();
+            if let Some(e) = &s.error {
+                bail!(e.clone());
+            }
+            if s.completed == s.total {
+                break;
+            }
+        }
+
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                stream.set_nonblocking(false)?;
+
+                let state = Arc::clone(&state);
+                let zstd_file = zstd_file.to_string();
+                let parse_options = parse_options.clone();
+                let collect = collect.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("coordinator-{peer}"))
+                    .spawn(move || {
+                        if let Err(e) =
+                            handle_worker(stream, &state, &zstd_file, &parse_options, &collect)
+                        {
+                            eprintln!("Lost connection to worker {}: {}", peer, e);
+                        }
+                    })?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(COORDINATOR_POLL)
+            }
+            Err(e) => return Err(e).context("Unable to accept worker connection"),
+        }
+    }
+
+    // Workers still connected are told to finish on their next request, so the results can be
+    // taken while their connection threads wind down.
+    let mut s = state.lock().unwrap();
+    let gathered = std::mem::replace(&mut s.gathered, Gathered::Map(AHashMap::new()));
+    let summary = std::mem::take(&mut s.summary);
+
+    Ok((gathered, summary))
+}
+
+pub fn run_worker(
+    coordinator: &str,
+    zstd_override: Option<&str>,
+    num_threads: usize,
+    thread_options: &ThreadOptions,
+) -> Result<usize> {
+    let stream = TcpStream::connect(coordinator)
+        .with_context(|| format!("Unable to connect to coordinator '{}'", coordinator))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    send_message(&mut writer, &WorkerMessage::Hello)?;
+    let (zstd_file, parse_options, collect) = match receive_message(&mut reader)? {
+        Some(CoordinatorMessage::Welcome {
+            zstd_file,
+            parse_options,
+            collect,
+        }) => (zstd_file, parse_options, collect),
+        _ => bail!("Coordinator did not respond to the worker handshake!"),
+    };
+
+    // The payload is normally on shared storage, but may be mounted at a different path here
+    let zstd_file = zstd_override.map(String::from).unwrap_or(zstd_file);
+    let source = open_source(&zstd_file)?;
+
+    let pool = build_worker_pool(num_threads, false, thread_options);
+
+    let mut tasks_completed: usize = 0;
+
+    loop {
+        send_message(&mut writer, &WorkerMessage::Request)?;
+
+        let (task_id, frames) = match receive_message(&mut reader)? {
+            Some(CoordinatorMessage::Task { task_id, frames }) => (task_id, frames),
+            Some(CoordinatorMessage::Wait) => {
+                std::thread::sleep(WORKER_WAIT);
+                continue;
+            }
+            Some(CoordinatorMessage::Finished) | None => break,
+            Some(CoordinatorMessage::Welcome { .. }) => {
+                bail!("Unexpected handshake message from coordinator!")
+            }
+        };
+
+        let reply = match process_task(source.as_ref(), frames, &pool, &parse_options) {
+            Ok(((records, summary), timings)) => match collect {
+                Collect::Map => WorkerMessage::Records {
+                    task_id,
+                    records,
+                    summary,
+                    timings,
+                },
+                Collect::ValueCounts => {
+                    let mut counts: AHashMap<u64, u64> = AHashMap::new();
+                    for (_, value) in records {
+                        *counts.entry(value).or_default() += 1;
+                    }
+                    WorkerMessage::ValueCounts {
+                        task_id,
+                        counts: counts.into_iter().collect(),
+                        summary,
+                        timings,
+                    }
+                }
+            },
+            Err(e) => WorkerMessage::Failed {
+                task_id,
+                error: format!("{:#}", e),
+            },
+        };
+
+        send_message(&mut writer, &reply)?;
+        tasks_completed += 1;
+    }
+
+    Ok(tasks_completed)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::BadRecordPolicy;
+    use std::fs::{File, OpenOptions};
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn data_to_ahashmap(file_name: &str) -> AHashMap<String, u64> {
+        std::fs::read_to_string(file_name)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (acc, rest) = line.split_once('\t').unwrap();
+                (acc.to_string(), rest.parse().unwrap())
+            })
+            .collect()
+    }
+
+    fn run_cluster(
+        collect: Collect,
+        parse_options: ParseOptions,
+        num_workers: usize,
+        metrics: Arc<Metrics>,
+    ) -> Result<(Gathered, DecompressionSummary)> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        std::thread::scope(|scope| {
+            let coordinator = scope.spawn(|| {
+                serve_frames(
+                    "test/example.zstd",
+                    idx_buffer,
+                    listener,
+                    1,
+                    &parse_options,
+                    &collect,
+                    metrics,
+                )
+            });
+
+            for _ in 0..num_workers {
+                scope.spawn(|| run_worker(&address, None, 2, &ThreadOptions::default()));
+            }
+
+            coordinator.join().unwrap()
+        })
+    }
+
+    #[test]
+    fn test_plan_tasks() {
+        let idx_buffer: Vec<FrameMeta> = (0..5).map(|i| FrameMeta::new(i * 10, 10, i)).collect();
+
+        let obs_tasks = plan_tasks(idx_buffer, 2);
+        let obs_sizes: Vec<(usize, usize)> = obs_tasks.iter().map(|(t, f)| (*t, f.len())).collect();
+
+        assert_eq!(vec![(0, 2), (1, 2), (2, 1)], obs_sizes);
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut buffer: Vec<u8> = Vec::new();
+        send_message(&mut buffer, &WorkerMessage::Request).unwrap();
+        send_message(&mut buffer, &WorkerMessage::Hello).unwrap();
+
+        let mut reader = BufReader::new(buffer.as_slice());
+        let first = receive_message::<WorkerMessage>(&mut reader).unwrap();
+        let second = receive_message::<WorkerMessage>(&mut reader).unwrap();
+        let third = receive_message::<WorkerMessage>(&mut reader).unwrap();
+
+        assert!(matches!(first, Some(WorkerMessage::Request)));
+        assert!(matches!(second, Some(WorkerMessage::Hello)));
+        assert!(third.is_none());
+    }
+
+    #[test]
+    fn test_serve_frames_map() {
+        let exp_map = data_to_ahashmap("test/data.txt");
+
+        let obs_result = run_cluster(Collect::Map, ParseOptions::default(), 2, Arc::default());
+        assert!(obs_result.is_ok());
+
+        match obs_result.unwrap().0 {
+            Gathered::Map(obs_map) => assert_eq!(exp_map, obs_map),
+            Gathered::ValueCounts(_) => panic!("Returned data was not a record map"),
+        }
+    }
+
+    #[test]
+    fn test_serve_frames_value_counts() {
+        let obs_result = run_cluster(
+            Collect::ValueCounts,
+            ParseOptions::default(),
+            3,
+            Arc::default(),
+        );
+        assert!(obs_result.is_ok());
+
+        match obs_result.unwrap().0 {
+            Gathered::ValueCounts(obs_counts) => {
+                assert_eq!(Some(&5), obs_counts.get(&562));
+                assert_eq!(Some(&2), obs_counts.get(&584));
+                assert_eq!(30, obs_counts.values().sum::<u64>());
+            }
+            Gathered::Map(_) => panic!("Returned data was not a value count"),
+        }
+    }
+
+    #[test]
+    fn test_serve_frames_metrics() {
+        let metrics = Arc::new(Metrics::default());
+
+        let obs_result = run_cluster(
+            Collect::ValueCounts,
+            ParseOptions::default(),
+            2,
+            Arc::clone(&metrics),
+        );
+        assert!(obs_result.is_ok());
+
+        let obs_output = metrics.render();
+        assert!(obs_output.contains("parallel_decompression_frames_decoded_total 3\n"));
+        assert!(obs_output.contains("parallel_decompression_bytes_read_total 421\n"));
+        assert!(obs_output.contains("parallel_decompression_tasks_completed_total 3\n"));
+    }
+
+    #[test]
+    fn test_serve_frames_worker_failure() {
+        // A fatal parse error on any worker must fail the whole run
+        let (zstd_file, idx_file) = (
+            "serve_frames_worker_failure.zstd",
+            "serve_frames_worker_failure.zstd.idx",
+        );
+        let payload = zstd::encode_all("a\tq\n".as_bytes(), 0).unwrap();
+        std::fs::write(zstd_file, &payload).unwrap();
+        let idx_records = vec![FrameMeta::new(0, payload.len() as u64, 0)];
+        std::fs::write(idx_file, serde_json::to_string(&idx_records).unwrap()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let idx_buffer = load_frame_index(&mut BufReader::new(open_file_read(idx_file))).unwrap();
+        let parse_options = ParseOptions {
+            bad_record: BadRecordPolicy::Error,
+            ..Default::default()
+        };
+
+        let obs_result = std::thread::scope(|scope| {
+            let coordinator = scope.spawn(|| {
+                serve_frames(
+                    zstd_file,
+                    idx_buffer,
+                    listener,
+                    1,
+                    &parse_options,
+                    &Collect::Map,
+                    Arc::default(),
+                )
+            });
+            scope.spawn(|| run_worker(&address, None, 1, &ThreadOptions::default()));
+            coordinator.join().unwrap()
+        });
+        assert!(obs_result.is_err());
+
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(idx_file);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::numa::numa_node_count;
+use crate::source::FrameSource;
+use crate::ResourceLimits;
+use anyhow::Result;
+use std::time::Instant;
+
+const PROC_MEMINFO: &str = "/proc/meminfo";
+// Number of reads spread evenly across the target file when sampling its read rate
+const SAMPLE_READS: u64 = 16;
+// Reads slower than this suggest network or spinning storage, where fewer, larger reads pay off
+const SLOW_READ_MBPS: f64 = 200.0;
+// Smaller samples are dominated by the cost of each read call, so say little about the storage
+const MIN_JUDGED_BYTES: u64 = 1 << 20;
+
+/// Memory of the machine as reported by the kernel, which bounds how much of an archive the page
+/// cache can hold between runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageCache {
+    pub total_bytes: u64,
+    /// Memory which can be given to new work without swapping, including reclaimable cache
+    pub available_bytes: u64,
+    /// Memory currently holding cached file pages
+    pub cached_bytes: u64,
+}
+
+/// Rate at which reads spread across the target file completed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadSample {
+    pub file_bytes: u64,
+    pub bytes_read: u64,
+    pub seconds: f64,
+    pub mbps: f64,
+}
+
+/// A flag to try, with the finding behind it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recommendation {
+    pub flag: String,
+    pub reason: String,
+}
+
+/// Findings about the machine and storage a run would use, for users to attach when reporting a
+/// slow run.
+#[derive(Clone, Debug)]
+pub struct DoctorReport {
+    pub resource_limits: ResourceLimits,
+    pub numa_nodes: usize,
+    pub page_cache: Option<PageCache>,
+    pub zstd_version: String,
+    pub read_sample: Option<ReadSample>,
+    pub recommendations: Vec<Recommendation>,
+}
+
+//region: Private functions
+
+/// Read the memory totals from the contents of '/proc/meminfo', whose values are given in kB.
+fn parse_meminfo(meminfo: &str) -> Option<PageCache> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .map(|kb| kb * 1024)
+    };
+    Some(PageCache {
+        total_bytes: field("MemTotal")?,
+        available_bytes: field("MemAvailable")?,
+        cached_bytes: field("Cached")?,
+    })
+}
+
+/// Time reads spread evenly across the file, so that the rate reflects the storage rather than
+/// read-ahead of one region. Pages already cached are read at memory speed, so a second run
+/// reports the rate of the page cache.
+fn sample_read_rate(
+    source: &dyn FrameSource,
+    file_bytes: u64,
+    sample_size: u64,
+) -> Result<ReadSample> {
+    let read_size = (sample_size / SAMPLE_READS).clamp(1, file_bytes.max(1));
+    let reads = SAMPLE_READS.min(file_bytes.div_ceil(read_size));
+    let stride = file_bytes.saturating_sub(read_size) / reads.saturating_sub(1).max(1);
+
+    let start = Instant::now();
+    let mut bytes_read: u64 = 0;
+    for i in 0..reads {
+        let length = read_size.min(file_bytes - i * stride) as usize;
+        bytes_read += source.read_at(i * stride, length)?.len() as u64;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+
+    Ok(ReadSample {
+        file_bytes,
+        bytes_read,
+        seconds,
+        mbps: bytes_read as f64 / 1e6 / seconds.max(1e-9),
+    })
+}
+
+fn recommend(
+    resource_limits: &ResourceLimits,
+    numa_nodes: usize,
+    page_cache: Option<&PageCache>,
+    read_sample: Option<&ReadSample>,
+) -> Vec<Recommendation> {
+    let mut recommendations: Vec<Recommendation> = Vec::new();
+
+    let num_threads = resource_limits.default_threads();
+    let thread_reason = match (
+        num_threads < resource_limits.cpus,
+        resource_limits.cpu_quota,
+    ) {
+        (true, _) => format!(
+            "{} CPUs are available, but the memory limit leaves room for {} threads",
+            resource_limits.cpus, num_threads
+        ),
+        (false, true) => String::from("the CPU quota of the container allows this many threads"),
+        (false, false) => String::from("one thread for each CPU the process may run on"),
+    };
+    recommendations.push(Recommendation {
+        flag: format!("--num-threads {}", num_threads),
+        reason: thread_reason,
+    });
+
+    if numa_nodes > 1 {
+        recommendations.push(Recommendation {
+            flag: String::from("--numa"),
+            reason: format!(
+                "the CPUs are split across {} NUMA nodes, so workers kept on one node keep their frames in local memory",
+                numa_nodes
+            ),
+        });
+    }
+
+    let exceeds_memory = match (read_sample, page_cache) {
+        (Some(read_sample), Some(page_cache)) => {
+            read_sample.file_bytes > page_cache.available_bytes
+        }
+        _ => false,
+    };
+    let memory_reason = match (resource_limits.memory_limit, exceeds_memory) {
+        (Some(_), _) => Some("the container limits memory, so a map which would not fit fails before it starts"),
+        (None, true) => Some("the archive is larger than the memory available, so it cannot stay in the page cache between runs and its map may not fit either"),
+        (None, false) => None,
+    };
+    if let Some(reason) = memory_reason {
+        recommendations.push(Recommendation {
+            flag: String::from("--estimate-memory"),
+            reason: String::from(reason),
+        });
+    }
+
+    if let Some(read_sample) = read_sample
+        && read_sample.bytes_read >= MIN_JUDGED_BYTES
+        && read_sample.mbps < SLOW_READ_MBPS
+    {
+        recommendations.push(Recommendation {
+            flag: String::from("--read-batch-size 8MiB"),
+            reason: format!(
+                "reads ran at {:.1} MB/s, which suggests network or spinning storage, where fewer, larger reads help",
+                read_sample.mbps
+            ),
+        });
+    }
+
+    recommendations
+}
+
+//endregion:
+
+/// Gather the CPU, memory and NUMA layout of the machine under the limits of any container, the
+/// zstd library in use and, given a target file, the rate at which it can be read, along with
+/// the flags these suggest.
+pub(crate) fn diagnose(
+    target: Option<(&dyn FrameSource, u64)>,
+    sample_size: u64,
+) -> Result<DoctorReport> {
+    let resource_limits = ResourceLimits::detect();
+    let numa_nodes = numa_node_count();
+    let page_cache = std::fs::read_to_string(PROC_MEMINFO)
+        .ok()
+        .and_then(|m| parse_meminfo(&m));
+    let read_sample = match target {
+        Some((source, file_bytes)) => Some(sample_read_rate(source, file_bytes, sample_size)?),
+        None => None,
+    };
+
+    let recommendations = recommend(
+        &resource_limits,
+        numa_nodes,
+        page_cache.as_ref(),
+        read_sample.as_ref(),
+    );
+    Ok(DoctorReport {
+        resource_limits,
+        numa_nodes,
+        page_cache,
+        zstd_version: zstd::zstd_safe::version_string().to_string(),
+        read_sample,
+        recommendations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::source::MemorySource;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16331276 kB\nMemFree:         1203344 kB\nMemAvailable:    9530532 kB\nBuffers:          512140 kB\nCached:          7646012 kB\nSwapCached:            0 kB\n";
+
+        let exp_page_cache = PageCache {
+            total_bytes: 16331276 * 1024,
+            available_bytes: 9530532 * 1024,
+            cached_bytes: 7646012 * 1024,
+        };
+        assert_eq!(Some(exp_page_cache), parse_meminfo(meminfo));
+        assert_eq!(None, parse_meminfo("MemTotal: 1 kB\n"));
+    }
+
+    #[test]
+    fn test_sample_read_rate() {
+        let payload = std::fs::read("test/example.zstd").unwrap();
+        let source = MemorySource::new("example", payload.clone());
+
+        let obs_sample = sample_read_rate(&source, payload.len() as u64, 160).unwrap();
+        assert_eq!(payload.len() as u64, obs_sample.file_bytes);
+        assert_eq!(160, obs_sample.bytes_read);
+
+        // A sample larger than the file reads it once
+        let obs_sample = sample_read_rate(&source, payload.len() as u64, 1 << 20).unwrap();
+        assert_eq!(payload.len() as u64, obs_sample.bytes_read);
+    }
+
+    #[test]
+    fn test_recommend() {
+        let resource_limits = ResourceLimits::from_limits(8, Some(2.0), Some(1 << 30));
+        let read_sample = ReadSample {
+            file_bytes: 4 << 30,
+            bytes_read: 64 << 20,
+            seconds: 1.0,
+            mbps: 67.1,
+        };
+        let page_cache = PageCache {
+            total_bytes: 2 << 30,
+            available_bytes: 1 << 30,
+            cached_bytes: 0,
+        };
+
+        let obs_flags: Vec<String> =
+            recommend(&resource_limits, 2, Some(&page_cache), Some(&read_sample))
+                .into_iter()
+                .map(|r| r.flag)
+                .collect();
+        assert_eq!(
+            vec![
+                "--num-threads 2",
+                "--numa",
+                "--estimate-memory",
+                "--read-batch-size 8MiB"
+            ],
+            obs_flags
+        );
+
+        let resource_limits = ResourceLimits::from_limits(4, None, None);
+        let obs_flags: Vec<String> = recommend(&resource_limits, 1, None, None)
+            .into_iter()
+            .map(|r| r.flag)
+            .collect();
+        assert_eq!(vec!["--num-threads 4"], obs_flags);
+
+        // Without a memory limit, an archive too large to stay cached still calls for an estimate
+        let obs_flags: Vec<String> =
+            recommend(&resource_limits, 1, Some(&page_cache), Some(&read_sample))
+                .into_iter()
+                .map(|r| r.flag)
+                .collect();
+        assert_eq!(
+            vec![
+                "--num-threads 4",
+                "--estimate-memory",
+                "--read-batch-size 8MiB"
+            ],
+            obs_flags
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{BadRecord, DecompressionSummary};
+use serde::{Deserialize, Serialize};
+
+/// Errors raised where the cause is known, so that callers can tell a mistake in the request
+/// apart from damaged input.
+#[derive(Debug)]
+pub enum PipelineError {
+    Usage(String),
+    CorruptArchive(String),
+}
+
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PipelineError::Usage(m) => write!(f, "{}", m),
+            PipelineError::CorruptArchive(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Usage,
+    Io,
+    CorruptArchive,
+    Other,
+}
+
+impl ErrorClass {
+    /// Classify an error by its typed cause, whether raised directly or attached as context. IO
+    /// errors are only used as a fallback, as a corrupt frame surfaces through an IO error from
+    /// the decoder.
+    pub fn of(error: &anyhow::Error) -> ErrorClass {
+        if let Some(e) = error.downcast_ref::<PipelineError>() {
+            return match e {
+                PipelineError::Usage(_) => ErrorClass::Usage,
+                PipelineError::CorruptArchive(_) => ErrorClass::CorruptArchive,
+            };
+        }
+
+        if error.is::<BadRecord>() {
+            return ErrorClass::CorruptArchive;
+        }
+
+        match error.chain().any(|c| c.is::<std::io::Error>()) {
+            true => ErrorClass::Io,
+            false => ErrorClass::Other,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorClass::Usage | ErrorClass::Other => 1,
+            ErrorClass::Io => 2,
+            ErrorClass::CorruptArchive => 3,
+        }
+    }
+}
+
+/// Outcome of a run which did not fail outright.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Complete,
+    /// Some frames could not be read, or some records were set aside
+    Partial,
+}
+
+impl RunStatus {
+    pub fn from_summary(summary: &DecompressionSummary) -> RunStatus {
+        match summary.is_partial() {
+            true => RunStatus::Partial,
+            false => RunStatus::Complete,
+        }
+    }
+
+    pub const PARTIAL_EXIT_CODE: i32 = 4;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::FailedFrame;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_class_of() {
+        let usage = anyhow::Error::from(PipelineError::Usage("bad flag".into()));
+        assert_eq!(ErrorClass::Usage, ErrorClass::of(&usage));
+
+        let io = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(ErrorClass::Io, ErrorClass::of(&io));
+
+        let bad_record = anyhow::Error::from(BadRecord::new(0, 0, b"a\tq"));
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&bad_record));
+
+        assert_eq!(
+            ErrorClass::Other,
+            ErrorClass::of(&anyhow::anyhow!("unknown"))
+        );
+    }
+
+    #[test]
+    fn test_error_class_of_context() {
+        // The typed cause is found beneath any context, and ahead of an underlying IO error
+        let corrupt = Err::<(), _>(std::io::Error::other("bad magic"))
+            .context(PipelineError::CorruptArchive(
+                "Unable to decode frame 2".into(),
+            ))
+            .context("Extraction failed")
+            .unwrap_err();
+        assert_eq!(ErrorClass::CorruptArchive, ErrorClass::of(&corrupt));
+        assert_eq!(3, ErrorClass::of(&corrupt).exit_code());
+    }
+
+    #[test]
+    fn test_error_class_of_missing_file() {
+        // The IO error of a file which cannot be opened survives the message naming the file
+        let missing = crate::perform_compression(
+            &["test/missing.txt"],
+            "missing_file.zstd",
+            "missing_file.zstd.idx",
+            "1MiB",
+            3,
+            &crate::CompressionOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(ErrorClass::Io, ErrorClass::of(&missing));
+        assert_eq!(2, ErrorClass::of(&missing).exit_code());
+        assert!(
+            format!("{:#}", missing).starts_with("Unable to open input file 'test/missing.txt': ")
+        );
+
+        let missing_index = crate::read_frame_index("test/missing.zstd.idx").unwrap_err();
+        assert_eq!(2, ErrorClass::of(&missing_index).exit_code());
+
+        // An https:// index is refused outright, as there is no TLS support
+        let https_index = crate::read_frame_index("https://example.org/a.zstd.idx").unwrap_err();
+        assert_eq!(ErrorClass::Usage, ErrorClass::of(&https_index));
+    }
+
+    #[test]
+    fn test_run_status_from_summary() {
+        let complete = DecompressionSummary::new(Vec::new(), Vec::new());
+        assert_eq!(RunStatus::Complete, RunStatus::from_summary(&complete));
+
+        let partial = DecompressionSummary::new(
+            Vec::new(),
+            vec![FailedFrame {
+                order: 2,
+                position: 0,
+                error: ErrorClass::Io,
+            }],
+        );
+        assert_eq!(RunStatus::Partial, RunStatus::from_summary(&partial));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::{ErrorClass, FailedFrame, MemoryUsage, RunStatus};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Progress and failure events, written as one JSON object per line when '--log-format json'
+/// is requested.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    FrameDecoded {
+        frame: u64,
+        bytes_read: u64,
+        bytes_decompressed: u64,
+    },
+    FrameFailed {
+        frame: u64,
+        error: String,
+    },
+    /// A record which could not be parsed, and was given the default value or left out under
+    /// the 'zero' and 'skip' policies.
+    BadRecord {
+        frame: u64,
+        offset: usize,
+        key: String,
+        error: String,
+        skipped: bool,
+    },
+    /// A transient read error on a frame, which is tried again after the delay.
+    Retry {
+        frame: u64,
+        error: String,
+        delay_seconds: f64,
+        attempt: u32,
+        retries: u32,
+    },
+    /// Anything which would otherwise be written as a plain 'WARNING:' line.
+    Warning {
+        message: String,
+    },
+    Summary {
+        status: RunStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        records: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_written: Option<u64>,
+        bad_records: usize,
+        failed_frames: Vec<FailedFrame>,
+        zeroed_records: usize,
+        skipped_records: usize,
+        #[serde(flatten)]
+        memory: MemoryUsage,
+    },
+    Error {
+        class: ErrorClass,
+        exit_code: i32,
+        message: String,
+    },
+}
+
+#[derive(Serialize)]
+struct TimedEvent<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed_seconds: Option<f64>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// The event without the memory usage of the run, which differs from one run to the next.
+fn untimed(event: &Event) -> Event {
+    let mut event = event.clone();
+    if let Event::Summary { memory, .. } = &mut event {
+        *memory = MemoryUsage::default();
+    }
+    event
+}
+
+/// Writes events from any thread, keeping each line whole.
+pub struct EventLog {
+    /// Start of the run, from which events are timed. Untimed logs have none.
+    origin: Option<Instant>,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl std::fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("EventLog").finish_non_exhaustive()
+    }
+}
+
+impl EventLog {
+    pub fn new(writer: Box<dyn Write + Send>) -> EventLog {
+        EventLog {
+            origin: Some(Instant::now()),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// A log which leaves out elapsed times and memory usage, so that the same run writes the
+    /// same bytes every time, as in the reference mode.
+    pub fn untimed(writer: Box<dyn Write + Send>) -> EventLog {
+        EventLog {
+            origin: None,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn stderr() -> EventLog {
+        EventLog::new(Box::new(std::io::stderr()))
+    }
+
+    /// Write the event as a single line. Failing to log is not worth failing the run over, so
+    /// write errors are ignored.
+    pub fn emit(&self, event: &Event) {
+        let untimed_event;
+        let event = match self.origin {
+            Some(_) => event,
+            None => {
+                untimed_event = untimed(event);
+                &untimed_event
+            }
+        };
+        let timed_event = TimedEvent {
+            elapsed_seconds: self.origin.map(|o| o.elapsed().as_secs_f64()),
+            event,
+        };
+
+        let mut line = match serde_json::to_vec(&timed_event) {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writer.write_all(&line).and_then(|_| writer.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_event_log_emit() {
+        let buffer = SharedBuffer::default();
+        let event_log = EventLog::new(Box::new(buffer.clone()));
+
+        event_log.emit(&Event::FrameDecoded {
+            frame: 2,
+            bytes_read: 120,
+            bytes_decompressed: 180,
+        });
+        event_log.emit(&Event::Summary {
+            status: RunStatus::Partial,
+            records: Some(30),
+            bytes_written: None,
+            bad_records: 0,
+            failed_frames: vec![FailedFrame {
+                order: 1,
+                position: 151,
+                error: ErrorClass::CorruptArchive,
+            }],
+            zeroed_records: 2,
+            skipped_records: 0,
+            memory: MemoryUsage {
+                peak_rss_bytes: Some(4096),
+                allocator: None,
+            },
+        });
+
+        let obs_output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let obs_lines: Vec<Value> = obs_output
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(2, obs_lines.len());
+        assert_eq!("frame_decoded", obs_lines[0]["event"]);
+        assert_eq!(2, obs_lines[0]["frame"]);
+        assert!(obs_lines[0]["elapsed_seconds"].is_f64());
+
+        assert_eq!("summary", obs_lines[1]["event"]);
+        assert_eq!("partial", obs_lines[1]["status"]);
+        assert_eq!(30, obs_lines[1]["records"]);
+        assert!(obs_lines[1].get("bytes_written").is_none());
+        assert_eq!(1, obs_lines[1]["failed_frames"][0]["order"]);
+        assert_eq!(151, obs_lines[1]["failed_frames"][0]["position"]);
+        assert_eq!("corrupt_archive", obs_lines[1]["failed_frames"][0]["error"]);
+        assert_eq!(2, obs_lines[1]["zeroed_records"]);
+        assert_eq!(4096, obs_lines[1]["peak_rss_bytes"]);
+    }
+
+    #[test]
+    fn test_event_log_bad_record() {
+        let buffer = SharedBuffer::default();
+        let event_log = EventLog::untimed(Box::new(buffer.clone()));
+
+        event_log.emit(&Event::BadRecord {
+            frame: 1,
+            offset: 42,
+            key: "acc1".to_string(),
+            error: "invalid digit found in string".to_string(),
+            skipped: true,
+        });
+
+        let exp_output = "{\"event\":\"bad_record\",\"frame\":1,\"offset\":42,\"key\":\"acc1\",\"error\":\"invalid digit found in string\",\"skipped\":true}\n";
+        let obs_output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(exp_output, obs_output);
+    }
+
+    #[test]
+    fn test_event_log_untimed() {
+        let buffer = SharedBuffer::default();
+        let event_log = EventLog::untimed(Box::new(buffer.clone()));
+
+        event_log.emit(&Event::FrameDecoded {
+            frame: 0,
+            bytes_read: 151,
+            bytes_decompressed: 220,
+        });
+        event_log.emit(&Event::Summary {
+            status: RunStatus::Complete,
+            records: Some(30),
+            bytes_written: None,
+            bad_records: 0,
+            failed_frames: Vec::new(),
+            zeroed_records: 0,
+            skipped_records: 0,
+            memory: MemoryUsage {
+                peak_rss_bytes: Some(4096),
+                allocator: None,
+            },
+        });
+
+        let exp_output = concat!(
+            "{\"event\":\"frame_decoded\",\"frame\":0,\"bytes_read\":151,\"bytes_decompressed\":220}\n",
+            "{\"event\":\"summary\",\"status\":\"complete\",\"records\":30,\"bad_records\":0,\"failed_frames\":[],\"zeroed_records\":0,\"skipped_records\":0}\n"
+        );
+        let obs_output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(exp_output, obs_output);
+    }
+}
@@ -0,0 +1,360 @@
+use crate::decompression::{check_frame_index, load_frame_index};
+use crate::numa::build_worker_pool;
+use crate::scan::scan_zstd_file;
+use crate::source::{FileSource, FrameSource};
+use crate::{FrameMeta, KeyRange, ParseOptions, PipelineError};
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+/// One frame of an archive written out as an archive of its own. Shards written elsewhere, such
+/// as by the workers of a distributed compression, need only give their order and file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Shard {
+    /// Order of the frame in the archive it was taken from, or of the shard among the others
+    pub order: u64,
+    /// Name of the shard file, within the shard directory
+    pub file: String,
+    /// Name of the index of the shard file, within the shard directory. Without one, the frames
+    /// of the shard are found by scanning it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_file: Option<String>,
+    #[serde(default)]
+    pub compressed_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_range: Option<KeyRange>,
+}
+
+/// List of the shards written from an archive, in frame order, so that the entry of an array
+/// job can be found by its task id.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShardManifest {
+    pub archive: String,
+    pub shards: Vec<Shard>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ExplodeSummary {
+    pub frames_written: usize,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct AssembleSummary {
+    pub shards_read: usize,
+    pub frames_written: usize,
+    pub bytes_written: u64,
+}
+
+//region: Private functions
+
+/// Shard names are padded to the widest frame order, so that they list in frame order.
+fn shard_name(order: u64, width: usize) -> String {
+    format!("frame_{:0width$}.zst", order)
+}
+
+fn write_shard(
+    source: &dyn FrameSource,
+    idx_frame: &FrameMeta,
+    output_dir: &Path,
+    shard: &Shard,
+) -> Result<()> {
+    let payload = source.read_frame(idx_frame)?;
+
+    let shard_path = output_dir.join(&shard.file);
+    let mut shard_handle = File::create(&shard_path)
+        .with_context(|| format!("Unable to create shard file '{}'", shard_path.display()))?;
+    shard_handle.write_all(&payload)?;
+
+    // The shard holds the frame alone, so is indexed as the first frame from its start
+    let mut shard_frame = idx_frame.clone();
+    shard_frame.position = 0;
+    shard_frame.order = 0;
+
+    let index_file = shard.index_file.as_deref().unwrap_or_default();
+    let index_path = output_dir.join(index_file);
+    let index_handle = File::create(&index_path)
+        .with_context(|| format!("Unable to create index file '{}'", index_path.display()))?;
+    let mut index_writer = BufWriter::new(index_handle);
+    serde_json::to_writer_pretty(&mut index_writer, &[shard_frame])?;
+    index_writer.flush()?;
+    Ok(())
+}
+
+/// Frames of a shard, from its index when it has one and by scanning it otherwise. A shard of a
+/// single unindexed frame takes the checksum and key range given for it in the manifest.
+fn shard_frames(
+    shard_path: &Path,
+    index_path: Option<&Path>,
+    shard: &Shard,
+) -> Result<Vec<FrameMeta>> {
+    let shard_file = shard_path.to_string_lossy();
+    let mut shard_frames = match index_path {
+        Some(index_path) => {
+            let index_handle = File::open(index_path)
+                .with_context(|| format!("Unable to open index file '{}'", index_path.display()))?;
+            let shard_frames = load_frame_index(BufReader::new(index_handle))?;
+            let shard_length = std::fs::metadata(shard_path)
+                .with_context(|| format!("Unable to open shard file '{}'", shard_file))?
+                .len();
+            check_frame_index(&shard_frames, shard_length).map_err(|e| {
+                e.context(format!(
+                    "Index '{}' does not match '{}'",
+                    index_path.display(),
+                    shard_file
+                ))
+            })?;
+            shard_frames
+        }
+        None => {
+            let mut shard_frames = scan_zstd_file(&shard_file)?;
+            if let [frame_meta] = shard_frames.as_mut_slice() {
+                frame_meta.checksum = frame_meta.checksum.or(shard.checksum);
+                frame_meta.key_range = frame_meta.key_range.take().or(shard.key_range.clone());
+            }
+            shard_frames
+        }
+    };
+
+    if shard_frames.is_empty() {
+        bail!(PipelineError::CorruptArchive(format!(
+            "Shard '{}' holds no frames!",
+            shard_file
+        )));
+    }
+    shard_frames.sort_by_key(|f| f.order);
+    Ok(shard_frames)
+}
+
+//endregion:
+
+/// Plan the shards of an archive, naming one file and index per frame in frame order.
+pub(crate) fn plan_shards(archive: &str, idx_buffer: &mut [FrameMeta]) -> ShardManifest {
+    idx_buffer.sort_by_key(|f| f.order);
+    let width = idx_buffer.last().map_or(1, |f| f.order.to_string().len());
+
+    let shards = idx_buffer
+        .iter()
+        .map(|f| {
+            let file = shard_name(f.order, width);
+            Shard {
+                order: f.order,
+                index_file: Some(format!("{}.idx", file)),
+                file,
+                compressed_bytes: f.length,
+                checksum: f.checksum,
+                key_range: f.key_range.clone(),
+            }
+        })
+        .collect();
+    ShardManifest {
+        archive: archive.to_string(),
+        shards,
+    }
+}
+
+/// Copy each frame of an archive to a file of its own, with an index, then write the manifest
+/// of the shards. Frames are copied as they are compressed, so no frame is decoded.
+pub(crate) fn explode_zstd(
+    source: &dyn FrameSource,
+    idx_buffer: &[FrameMeta],
+    manifest: &ShardManifest,
+    output_dir: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<ExplodeSummary> {
+    let output_path = Path::new(output_dir);
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    pool.install(|| {
+        idx_buffer
+            .par_iter()
+            .zip(&manifest.shards)
+            .try_for_each(|(idx_frame, shard)| write_shard(source, idx_frame, output_path, shard))
+    })?;
+
+    let manifest_path = output_path.join(MANIFEST_FILE);
+    let manifest_handle = File::create(&manifest_path).with_context(|| {
+        format!(
+            "Unable to create manifest file '{}'",
+            manifest_path.display()
+        )
+    })?;
+    let mut manifest_writer = BufWriter::new(manifest_handle);
+    serde_json::to_writer_pretty(&mut manifest_writer, manifest)?;
+    manifest_writer.flush()?;
+
+    Ok(ExplodeSummary {
+        frames_written: manifest.shards.len(),
+        bytes_written: manifest.shards.iter().map(|s| s.compressed_bytes).sum(),
+    })
+}
+
+/// Concatenate the frames of the shards of a manifest into a single archive, in shard order and
+/// then frame order within each shard, and return the index of the archive. Frames are copied as
+/// they are compressed, keeping their checksums and key ranges, while their positions and orders
+/// are counted afresh across the whole archive.
+pub(crate) fn assemble_zstd<W: Write>(
+    manifest: &ShardManifest,
+    shard_dir: &str,
+    zstd_writer: &mut W,
+) -> Result<(Vec<FrameMeta>, AssembleSummary)> {
+    let mut shards: Vec<&Shard> = manifest.shards.iter().collect();
+    shards.sort_by_key(|s| s.order);
+    if let Some(pair) = shards.windows(2).find(|p| p[0].order == p[1].order) {
+        bail!(PipelineError::Usage(format!(
+            "Shards '{}' and '{}' are both listed at order {}!",
+            pair[0].file, pair[1].file, pair[0].order
+        )));
+    }
+
+    let shard_dir = Path::new(shard_dir);
+    let mut idx_records: Vec<FrameMeta> = Vec::new();
+    let mut position: u64 = 0;
+
+    for shard in &shards {
+        let shard_path = shard_dir.join(&shard.file);
+        let index_path = shard.index_file.as_ref().map(|f| shard_dir.join(f));
+        let shard_frames = shard_frames(&shard_path, index_path.as_deref(), shard)?;
+
+        // Padding and skippable frames between the frames of a shard are left behind
+        let source = FileSource::new(&shard_path.to_string_lossy());
+        for mut frame_meta in shard_frames {
+            let payload = source.read_frame(&frame_meta)?;
+            zstd_writer.write_all(&payload)?;
+
+            frame_meta.position = position;
+            frame_meta.order = idx_records.len() as u64;
+            position += frame_meta.length;
+            idx_records.push(frame_meta);
+        }
+    }
+    zstd_writer.flush()?;
+
+    let summary = AssembleSummary {
+        shards_read: shards.len(),
+        frames_written: idx_records.len(),
+        bytes_written: position,
+    };
+    Ok((idx_records, summary))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompress_bytes;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn test_plan_shards() {
+        let mut idx_buffer: Vec<FrameMeta> = (0..12)
+            .rev()
+            .map(|i| FrameMeta::new(i * 10, 10, i))
+            .collect();
+
+        let obs_manifest = plan_shards("example.zstd", &mut idx_buffer);
+
+        assert_eq!(12, obs_manifest.shards.len());
+        assert_eq!("frame_00.zst", obs_manifest.shards[0].file);
+        assert_eq!(
+            Some("frame_11.zst.idx"),
+            obs_manifest.shards[11].index_file.as_deref()
+        );
+        assert_eq!(0, idx_buffer[0].order);
+    }
+
+    #[test]
+    fn test_explode_zstd() {
+        let output_dir = "explode_zstd";
+        let idx_handle = OpenOptions::new()
+            .read(true)
+            .open("test/example.zstd.idx")
+            .unwrap();
+        let mut idx_buffer = load_frame_index(BufReader::new(idx_handle)).unwrap();
+        let source = FileSource::new("test/example.zstd");
+
+        std::fs::create_dir_all(output_dir).unwrap();
+        let manifest = plan_shards("test/example.zstd", &mut idx_buffer);
+        let obs_summary = explode_zstd(
+            &source,
+            &idx_buffer,
+            &manifest,
+            output_dir,
+            2,
+            &ParseOptions::default(),
+        );
+
+        // Each shard decodes on its own to the content of its frame
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        let mut obs_content: Vec<u8> = Vec::new();
+        for shard in &manifest.shards {
+            let shard_bytes = std::fs::read(Path::new(output_dir).join(&shard.file)).unwrap();
+            obs_content.extend(zstd::decode_all(&shard_bytes[..]).unwrap());
+        }
+        let obs_manifest: ShardManifest = serde_json::from_slice(
+            &std::fs::read(Path::new(output_dir).join(MANIFEST_FILE)).unwrap(),
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(output_dir);
+
+        assert_eq!(idx_buffer.len(), obs_summary.unwrap().frames_written);
+        assert_eq!(exp_content, obs_content);
+        assert_eq!(manifest, obs_manifest);
+    }
+
+    #[test]
+    fn test_assemble_zstd() {
+        let shard_dir = "assemble_zstd";
+        let idx_handle = OpenOptions::new()
+            .read(true)
+            .open("test/example.zstd.idx")
+            .unwrap();
+        let mut idx_buffer = load_frame_index(BufReader::new(idx_handle)).unwrap();
+        let source = FileSource::new("test/example.zstd");
+
+        std::fs::create_dir_all(shard_dir).unwrap();
+        let mut manifest = plan_shards("test/example.zstd", &mut idx_buffer);
+        explode_zstd(
+            &source,
+            &idx_buffer,
+            &manifest,
+            shard_dir,
+            1,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        // A shard listed without its index is scanned, and shards are taken in order
+        manifest.shards[1].index_file = None;
+        manifest.shards.reverse();
+        let mut zstd_writer: Vec<u8> = Vec::new();
+        let obs_result = assemble_zstd(&manifest, shard_dir, &mut zstd_writer);
+
+        manifest.shards[0].order = 0;
+        let obs_duplicate = assemble_zstd(&manifest, shard_dir, &mut Vec::new());
+        let _ = std::fs::remove_dir_all(shard_dir);
+
+        let (obs_index, obs_summary) = obs_result.unwrap();
+        assert_eq!(idx_buffer.len(), obs_summary.frames_written);
+        assert_eq!(
+            idx_buffer.iter().map(|f| f.checksum).collect::<Vec<_>>(),
+            obs_index.iter().map(|f| f.checksum).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            std::fs::read("test/data.txt").unwrap(),
+            decompress_bytes(&zstd_writer, &obs_index).unwrap()
+        );
+        assert!(obs_duplicate.is_err());
+    }
+}
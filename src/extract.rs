@@ -0,0 +1,395 @@
+use crate::decompression::decode_frame;
+use crate::frame_cache::archive_key;
+use crate::numa::build_worker_pool;
+use crate::reorder::{ordered_channel, OrderedReceiver};
+use crate::source::FrameSource;
+use crate::{FrameMeta, ParseOptions, PipelineError};
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+
+// Number of frames which can be decoded ahead of the next one to be written before workers block
+const EXTRACT_CHANNEL_BOUND: usize = 64;
+
+/// Progress of an extraction, recording the leading run of frames already written in full.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub frames_written: usize,
+    pub bytes_written: u64,
+    /// Hash of the index being extracted, so that an extraction is only resumed against the
+    /// index it was started from. Zero in checkpoints written before it was recorded.
+    #[serde(default)]
+    pub index_key: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ExtractSummary {
+    pub frames_skipped: usize,
+    pub frames_written: usize,
+    pub bytes_written: u64,
+}
+
+//region: Private functions
+
+fn checkpoint_path(output_file: &str) -> String {
+    format!("{}.checkpoint", output_file)
+}
+
+fn load_checkpoint(checkpoint_file: &str) -> Result<Option<Checkpoint>> {
+    let checkpoint_handle = match File::open(checkpoint_file) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Unable to open checkpoint '{}'", checkpoint_file))
+        }
+    };
+
+    match serde_json::from_reader(BufReader::new(checkpoint_handle)) {
+        Ok(c) => Ok(Some(c)),
+        Err(_) => bail!("Unable to parse checkpoint '{}'!", checkpoint_file),
+    }
+}
+
+fn save_checkpoint(checkpoint_file: &str, checkpoint: &Checkpoint) -> Result<()> {
+    // Write beside the checkpoint and rename over it, so a crash never leaves a partial file
+    let staging_file = format!("{}.tmp", checkpoint_file);
+    std::fs::write(&staging_file, serde_json::to_vec(checkpoint)?)?;
+    std::fs::rename(&staging_file, checkpoint_file)?;
+    Ok(())
+}
+
+fn open_output(
+    output_file: &str,
+    checkpoint_file: &str,
+    resume: bool,
+    index_key: u64,
+) -> Result<(File, Checkpoint)> {
+    let fresh_checkpoint = Checkpoint {
+        index_key,
+        ..Default::default()
+    };
+    let checkpoint = match resume {
+        true => load_checkpoint(checkpoint_file)?.unwrap_or(fresh_checkpoint),
+        false => fresh_checkpoint,
+    };
+
+    // The frames already written belong to the index the checkpoint was taken against, so they
+    // would be misplaced in an output continued from any other
+    if checkpoint.index_key != index_key {
+        bail!(PipelineError::Usage(format!(
+            "Checkpoint '{}' was taken while extracting from another index, unable to resume! Extract again without --resume.",
+            checkpoint_file
+        )));
+    }
+
+    let mut output_handle = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(output_file)
+        .with_context(|| format!("Unable to open output file '{}'", output_file))?;
+
+    // Anything past the checkpoint may be a partially written frame, so it is discarded
+    let output_length = output_handle.metadata()?.len();
+    if output_length < checkpoint.bytes_written {
+        bail!(
+            "Output file '{}' is shorter than its checkpoint ({} of {} bytes), unable to resume!",
+            output_file,
+            output_length,
+            checkpoint.bytes_written
+        );
+    }
+    output_handle.set_len(checkpoint.bytes_written)?;
+    output_handle.seek(SeekFrom::End(0))?;
+
+    Ok((output_handle, checkpoint))
+}
+
+fn frame_writer(
+    frame_receiver: OrderedReceiver<Vec<u8>>,
+    output_handle: File,
+    checkpoint_file: &str,
+    mut checkpoint: Checkpoint,
+    checkpoint_interval: usize,
+) -> Result<Checkpoint> {
+    let mut output_writer = BufWriter::new(output_handle);
+
+    let mut since_checkpoint: usize = 0;
+
+    // Frames complete out of order, but the receiver yields them in sequence
+    for payload in frame_receiver {
+        output_writer.write_all(&payload)?;
+        checkpoint.frames_written += 1;
+        checkpoint.bytes_written += payload.len() as u64;
+        since_checkpoint += 1;
+
+        if since_checkpoint >= checkpoint_interval {
+            // The frames must be on disk before the checkpoint claims them
+            output_writer.flush()?;
+            output_writer.get_ref().sync_data()?;
+            save_checkpoint(checkpoint_file, &checkpoint)?;
+            since_checkpoint = 0;
+        }
+    }
+
+    output_writer.flush()?;
+    output_writer.get_ref().sync_data()?;
+    save_checkpoint(checkpoint_file, &checkpoint)?;
+    Ok(checkpoint)
+}
+
+//endregion:
+
+pub fn extract_zstd(
+    source: &dyn FrameSource,
+    mut idx_buffer: Vec<FrameMeta>,
+    output_file: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    resume: bool,
+    checkpoint_interval: usize,
+) -> Result<ExtractSummary> {
+    idx_buffer.sort_by_key(|f| f.order);
+
+    let checkpoint_file = checkpoint_path(output_file);
+    let (output_handle, checkpoint) = open_output(
+        output_file,
+        &checkpoint_file,
+        resume,
+        archive_key(&idx_buffer),
+    )?;
+
+    let frames_skipped = checkpoint.frames_written;
+    if frames_skipped > idx_buffer.len() {
+        bail!(
+            "Checkpoint records {} frames, but the index only contains {}!",
+            frames_skipped,
+            idx_buffer.len()
+        );
+    }
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let (frame_sender, frame_receiver) =
+        ordered_channel::<Vec<u8>>(frames_skipped, EXTRACT_CHANNEL_BOUND);
+
+    let checkpoint = std::thread::scope(|scope| {
+        let writer_handle = parse_options
+            .threads
+            .spawn_scoped(scope, "extract-writer", || {
+                frame_writer(
+                    frame_receiver,
+                    output_handle,
+                    &checkpoint_file,
+                    checkpoint,
+                    checkpoint_interval.max(1),
+                )
+            })?;
+
+        let decode_result: Result<()> = pool.install(|| {
+            idx_buffer
+                .par_iter()
+                .enumerate()
+                .skip(frames_skipped)
+                .try_for_each_with(frame_sender, |frame_sender, (sequence, idx_frame)| {
+                    // Unlike parsing, a frame which cannot be read leaves a hole in the output,
+                    // so the extraction stops and can be resumed from the last checkpoint.
+                    let payload = decode_frame(source, idx_frame, parse_options)
+                        .inspect_err(|_| frame_sender.abandon())?;
+                    if frame_sender.send(sequence, payload).is_err() {
+                        bail!("The output writer stopped before all frames were written!");
+                    }
+                    Ok(())
+                })
+        });
+
+        // A writer failure also stops the decoders, so report it ahead of theirs
+        let checkpoint = match writer_handle.join() {
+            Ok(r) => r?,
+            Err(_) => bail!("The output writer thread panicked!"),
+        };
+
+        decode_result?;
+        Ok(checkpoint)
+    })?;
+
+    // The extraction is complete, so there is nothing left to resume
+    let _ = std::fs::remove_file(&checkpoint_file);
+
+    Ok(ExtractSummary {
+        frames_skipped,
+        frames_written: checkpoint.frames_written - frames_skipped,
+        bytes_written: checkpoint.bytes_written,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let checkpoint_file = "checkpoint_round_trip.checkpoint";
+        let exp_checkpoint = Checkpoint {
+            frames_written: 2,
+            bytes_written: 301,
+            index_key: 7,
+        };
+
+        assert!(save_checkpoint(checkpoint_file, &exp_checkpoint).is_ok());
+        let obs_checkpoint = load_checkpoint(checkpoint_file);
+        let _ = std::fs::remove_file(checkpoint_file);
+
+        assert_eq!(Some(exp_checkpoint), obs_checkpoint.unwrap());
+        assert_eq!(None, load_checkpoint("missing.checkpoint").unwrap());
+    }
+
+    #[test]
+    fn test_extract_zstd() {
+        let output_file = "extract_zstd.txt";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let obs_result = extract_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            output_file,
+            2,
+            &ParseOptions::default(),
+            false,
+            1,
+        );
+        let obs_content = std::fs::read(output_file).unwrap();
+        let checkpoint_exists = std::path::Path::new(&checkpoint_path(output_file)).exists();
+        let _ = std::fs::remove_file(output_file);
+
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        let obs_summary = obs_result.unwrap();
+        assert_eq!(3, obs_summary.frames_written);
+        assert_eq!(exp_content.len() as u64, obs_summary.bytes_written);
+        assert_eq!(exp_content, obs_content);
+        assert!(!checkpoint_exists);
+    }
+
+    #[test]
+    fn test_extract_zstd_resume() {
+        let output_file = "extract_zstd_resume.txt";
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+
+        // Simulate a crash after the first frame, with part of the second frame also written
+        let first_frame = decode_frame(
+            &FileSource::new("test/example.zstd"),
+            &FrameMeta::new(0, 151, 0),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let mut partial = first_frame.clone();
+        partial.extend_from_slice(b"partial frame");
+        std::fs::write(output_file, &partial).unwrap();
+
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let checkpoint = Checkpoint {
+            frames_written: 1,
+            bytes_written: first_frame.len() as u64,
+            index_key: archive_key(&idx_buffer),
+        };
+        save_checkpoint(&checkpoint_path(output_file), &checkpoint).unwrap();
+
+        let obs_result = extract_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            output_file,
+            2,
+            &ParseOptions::default(),
+            true,
+            1,
+        );
+        let obs_content = std::fs::read(output_file).unwrap();
+        let _ = std::fs::remove_file(output_file);
+
+        let obs_summary = obs_result.unwrap();
+        assert_eq!(1, obs_summary.frames_skipped);
+        assert_eq!(2, obs_summary.frames_written);
+        assert_eq!(exp_content, obs_content);
+    }
+
+    #[test]
+    fn test_extract_zstd_resume_short_output() {
+        let output_file = "extract_zstd_resume_short_output.txt";
+        std::fs::write(output_file, b"short").unwrap();
+
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let checkpoint = Checkpoint {
+            frames_written: 1,
+            bytes_written: 100,
+            index_key: archive_key(&idx_buffer),
+        };
+        save_checkpoint(&checkpoint_path(output_file), &checkpoint).unwrap();
+
+        let obs_result = extract_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            output_file,
+            1,
+            &ParseOptions::default(),
+            true,
+            1,
+        );
+
+        let _ = std::fs::remove_file(output_file);
+        let _ = std::fs::remove_file(checkpoint_path(output_file));
+        assert!(obs_result.is_err());
+    }
+
+    #[test]
+    fn test_extract_zstd_resume_other_index() {
+        let output_file = "extract_zstd_resume_other_index.txt";
+        std::fs::write(output_file, vec![b'a'; 220]).unwrap();
+
+        // The checkpoint was taken against an index whose first frame has since been rewritten
+        let mut idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let checkpoint = Checkpoint {
+            frames_written: 1,
+            bytes_written: 220,
+            index_key: archive_key(&idx_buffer),
+        };
+        save_checkpoint(&checkpoint_path(output_file), &checkpoint).unwrap();
+        idx_buffer[0] = idx_buffer[0].clone().with_checksum(1);
+
+        let obs_result = extract_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            output_file,
+            1,
+            &ParseOptions::default(),
+            true,
+            1,
+        );
+        let obs_content = std::fs::read(output_file).unwrap();
+
+        let _ = std::fs::remove_file(output_file);
+        let _ = std::fs::remove_file(checkpoint_path(output_file));
+        assert_eq!(
+            crate::ErrorClass::Usage,
+            crate::ErrorClass::of(&obs_result.unwrap_err())
+        );
+        // The output is left as it was, rather than cut back to the checkpoint
+        assert_eq!(vec![b'a'; 220], obs_content);
+    }
+}
@@ -0,0 +1,518 @@
+use crate::decompression::{check_frame_index, decode_frame, merge_into_map};
+use crate::metrics::Metrics;
+use crate::numa::build_worker_pool;
+use crate::source::{FileSource, FrameSource};
+use crate::{
+    decompress_with_keys, read_frame_index, DecompressionSummary, EitherMap, FrameMeta, Mode,
+    ParseOptions, PipelineError, RecordKey, RecordValue, RunStatus,
+};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How an archive which is still being written is followed.
+#[derive(Debug)]
+pub struct FollowOptions {
+    /// Time between checks of the index for new frames.
+    pub poll_interval: Duration,
+    /// Stop once no new frames have been indexed for this long, rather than following until
+    /// interrupted.
+    pub idle_timeout: Option<Duration>,
+    /// Write the content of each new frame to this file as it arrives, rather than building a map.
+    pub output_file: Option<String>,
+    /// Listener on which to expose Prometheus metrics at `/metrics` while following, if any.
+    pub metrics_listener: Option<TcpListener>,
+}
+
+/// Frames read in one check of a followed archive, for the caller to report as they arrive.
+#[derive(Debug, Default)]
+pub struct FollowUpdate {
+    pub first_order: u64,
+    pub last_order: u64,
+    /// Records held in the map once these frames are merged into it, when building a map
+    pub records: Option<usize>,
+    /// Bytes written to the output so far, when streaming the records
+    pub bytes_written: Option<u64>,
+    pub summary: DecompressionSummary,
+}
+
+/// Outcome of following an archive until it went idle, for the caller to report.
+#[derive(Debug, Default)]
+pub struct FollowReport {
+    pub frames_read: usize,
+    pub records: Option<usize>,
+    pub bytes_written: Option<u64>,
+    pub summary: DecompressionSummary,
+}
+
+impl FollowReport {
+    pub fn run_status(&self) -> RunStatus {
+        RunStatus::from_summary(&self.summary)
+    }
+}
+
+//region: Private functions
+
+fn check_for_frames(
+    zstd_file: &str,
+    idx_file: &str,
+    watermark: Option<u64>,
+) -> Result<Vec<FrameMeta>> {
+    let idx_buffer = read_frame_index(idx_file)?;
+    let archive_length = std::fs::metadata(zstd_file)?.len();
+    check_frame_index(&idx_buffer, archive_length)?;
+    Ok(frames_after(idx_buffer, watermark))
+}
+
+/// Start answering scrapes of `/metrics`, if a metrics listener was given.
+fn start_metrics(follow_options: &FollowOptions) -> Result<Option<Arc<Metrics>>> {
+    let Some(metrics_listener) = &follow_options.metrics_listener else {
+        return Ok(None);
+    };
+    let metrics = Arc::new(Metrics::default());
+    crate::metrics::serve_metrics(metrics_listener.try_clone()?, Arc::clone(&metrics))?;
+    Ok(Some(metrics))
+}
+
+/// Hand each run of newly indexed frames to `on_frames` in order, until no frames have been
+/// indexed for the idle timeout. Returns the number of frames read.
+fn follow_frames(
+    zstd_file: &str,
+    idx_file: &str,
+    follow_options: &FollowOptions,
+    metrics: Option<&Metrics>,
+    mut on_frames: impl FnMut(Vec<FrameMeta>) -> Result<()>,
+) -> Result<usize> {
+    if zstd_file.starts_with("http://") || idx_file == "-" {
+        bail!(PipelineError::Usage(
+            "Following requires a local archive, and an index which can be read again!".into()
+        ));
+    }
+
+    // Nothing has been read to fall back on, so the first check fails as any other run would
+    let mut pending = check_for_frames(zstd_file, idx_file, None)?;
+    let mut last_stamp = file_stamp(idx_file);
+    let mut watermark: Option<u64> = None;
+    let mut frames_read: usize = 0;
+    let mut idle_since = Instant::now();
+
+    loop {
+        if let Some(last_frame) = pending.last() {
+            watermark = Some(last_frame.order);
+            frames_read += pending.len();
+            if let Some(metrics) = metrics {
+                let bytes_read = pending.iter().map(|f| f.length).sum();
+                metrics.record_frames(pending.len() as u64, bytes_read);
+            }
+            on_frames(std::mem::take(&mut pending))?;
+            idle_since = Instant::now();
+        } else if follow_options
+            .idle_timeout
+            .is_some_and(|t| idle_since.elapsed() >= t)
+        {
+            return Ok(frames_read);
+        }
+
+        std::thread::sleep(follow_options.poll_interval);
+
+        let stamp = file_stamp(idx_file);
+        if stamp.is_some() && stamp == last_stamp {
+            continue;
+        }
+
+        // An index or archive caught part way through being written is read again at the next
+        // check, so its stamp is only taken once it reads cleanly
+        if let Ok(frames) = check_for_frames(zstd_file, idx_file, watermark) {
+            pending = frames;
+            last_stamp = stamp;
+        }
+    }
+}
+
+/// Merge the records of the frames into the map, gathering them the way the map was built.
+fn merge_frames<K: RecordKey + 'static, V: RecordValue + 'static>(
+    source: &dyn FrameSource,
+    new_frames: Vec<FrameMeta>,
+    record_map: &mut EitherMap<K, V>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<DecompressionSummary> {
+    let mode = match record_map {
+        EitherMap::Dash(_) => Mode::DashMap,
+        EitherMap::AHash(_) => Mode::Vector,
+        EitherMap::Ordered(_) => Mode::Ordered,
+    };
+    let (update_map, summary) =
+        decompress_with_keys::<K, V>(source, new_frames, &mode, num_threads, parse_options)?;
+    merge_into_map(record_map, update_map, &parse_options.duplicate_keys)?;
+    Ok(summary)
+}
+
+//endregion:
+
+/// Size and modification time of a local file, which change when it is written again. A file
+/// fetched over HTTP has neither, so is read again at every check.
+pub(crate) fn file_stamp(file_path: &str) -> Option<(u64, SystemTime)> {
+    if file_path.contains("://") {
+        return None;
+    }
+    let file_meta = std::fs::metadata(file_path).ok()?;
+    Some((file_meta.len(), file_meta.modified().ok()?))
+}
+
+/// Frames of the index which follow the watermark, the order of the last frame already read, in
+/// an unbroken run of orders. Frames past a gap are left for a later check, as the frames of the
+/// gap may yet be indexed.
+pub(crate) fn frames_after(
+    mut idx_buffer: Vec<FrameMeta>,
+    watermark: Option<u64>,
+) -> Vec<FrameMeta> {
+    idx_buffer.sort_by_key(|f| f.order);
+
+    let mut next_order = watermark.map_or(0, |w| w + 1);
+    idx_buffer
+        .into_iter()
+        .filter(|f| watermark.is_none_or(|w| f.order > w))
+        .take_while(|f| {
+            let in_run = f.order == next_order;
+            next_order += 1;
+            in_run
+        })
+        .collect()
+}
+
+/// Merge the records of the frames which follow the watermark into the map. Returns the order of
+/// the last frame merged, or the watermark again when no new frames were indexed.
+pub(crate) fn update_map<K: RecordKey + 'static, V: RecordValue + 'static>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    record_map: &mut EitherMap<K, V>,
+    watermark: Option<u64>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<(Option<u64>, DecompressionSummary)> {
+    let new_frames = frames_after(idx_buffer, watermark);
+    let Some(last_order) = new_frames.last().map(|f| f.order) else {
+        return Ok((watermark, DecompressionSummary::default()));
+    };
+
+    let summary = merge_frames(source, new_frames, record_map, num_threads, parse_options)?;
+    Ok((Some(last_order), summary))
+}
+
+/// Follow an archive into a map, decoding only the frames indexed since the last check and
+/// merging their records into the map under the duplicate key policy.
+pub(crate) fn follow_map<K: RecordKey + 'static, V: RecordValue + 'static>(
+    zstd_file: &str,
+    idx_file: &str,
+    num_threads: usize,
+    mode: &Mode,
+    parse_options: &ParseOptions,
+    follow_options: &FollowOptions,
+    on_update: &mut dyn FnMut(&FollowUpdate),
+) -> Result<FollowReport> {
+    let source = FileSource::new(zstd_file);
+    let mut record_map: Option<EitherMap<K, V>> = None;
+    let mut summary = DecompressionSummary::default();
+    let metrics = start_metrics(follow_options)?;
+
+    let frames_read = follow_frames(
+        zstd_file,
+        idx_file,
+        follow_options,
+        metrics.as_deref(),
+        |new_frames| {
+            let first_order = new_frames[0].order;
+            let last_order = new_frames[new_frames.len() - 1].order;
+
+            // The map is gathered under the requested mode, and later frames are merged into it
+            let update_summary = match &mut record_map {
+                Some(m) => merge_frames(&source, new_frames, m, num_threads, parse_options)?,
+                None => {
+                    let (update_map, update_summary) = decompress_with_keys::<K, V>(
+                        &source,
+                        new_frames,
+                        mode,
+                        num_threads,
+                        parse_options,
+                    )?;
+                    record_map = Some(update_map);
+                    update_summary
+                }
+            };
+            let records = record_map.as_ref().map_or(0, |m| m.len());
+            if let Some(metrics) = &metrics {
+                metrics.set_records_held(records as u64);
+            }
+
+            on_update(&FollowUpdate {
+                first_order,
+                last_order,
+                records: Some(records),
+                bytes_written: None,
+                summary: update_summary.clone(),
+            });
+            summary.absorb(update_summary);
+            Ok(())
+        },
+    )?;
+
+    Ok(FollowReport {
+        frames_read,
+        records: Some(record_map.map_or(0, |m| m.len())),
+        bytes_written: None,
+        summary,
+    })
+}
+
+/// Follow an archive into the output, writing the content of each newly indexed frame in order.
+/// The output is flushed after every check, so that a reader sees the records as they arrive.
+pub(crate) fn follow_stream<W: Write>(
+    zstd_file: &str,
+    idx_file: &str,
+    mut output_writer: W,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    follow_options: &FollowOptions,
+    on_update: &mut dyn FnMut(&FollowUpdate),
+) -> Result<FollowReport> {
+    let source = FileSource::new(zstd_file);
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let mut bytes_written: u64 = 0;
+    let metrics = start_metrics(follow_options)?;
+
+    let frames_read = follow_frames(
+        zstd_file,
+        idx_file,
+        follow_options,
+        metrics.as_deref(),
+        |new_frames| {
+            // A lost frame would leave a hole in the output, so following stops
+            let payloads: Vec<Vec<u8>> = pool.install(|| {
+                new_frames
+                    .par_iter()
+                    .map(|idx_frame| decode_frame(&source, idx_frame, parse_options))
+                    .collect::<Result<_>>()
+            })?;
+            for payload in &payloads {
+                output_writer.write_all(payload)?;
+                bytes_written += payload.len() as u64;
+            }
+            output_writer.flush()?;
+
+            on_update(&FollowUpdate {
+                first_order: new_frames[0].order,
+                last_order: new_frames[new_frames.len() - 1].order,
+                records: None,
+                bytes_written: Some(bytes_written),
+                summary: DecompressionSummary::default(),
+            });
+            Ok(())
+        },
+    )?;
+
+    Ok(FollowReport {
+        frames_read,
+        records: None,
+        bytes_written: Some(bytes_written),
+        summary: DecompressionSummary::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::compression::compress_frames;
+    use crate::CompressionOptions;
+    use std::io::{BufWriter, Cursor};
+
+    fn write_archive(stem: &str, content: &str) -> (String, String) {
+        let zstd_file = format!("{}.zstd", stem);
+        let idx_file = format!("{}.zstd.idx", stem);
+
+        let mut payload = Cursor::new(Vec::new());
+        let idx_buffer = compress_frames(
+            content.as_bytes(),
+            &mut payload,
+            8,
+            3,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+        std::fs::write(&zstd_file, payload.into_inner()).unwrap();
+        std::fs::write(&idx_file, serde_json::to_vec(&idx_buffer).unwrap()).unwrap();
+        (zstd_file, idx_file)
+    }
+
+    #[test]
+    fn test_frames_after() {
+        let idx_buffer = vec![
+            FrameMeta::new(20, 10, 2),
+            FrameMeta::new(0, 10, 0),
+            FrameMeta::new(10, 10, 1),
+            FrameMeta::new(40, 10, 4),
+        ];
+
+        let obs_orders = |watermark: Option<u64>| -> Vec<u64> {
+            frames_after(idx_buffer.clone(), watermark)
+                .iter()
+                .map(|f| f.order)
+                .collect()
+        };
+
+        // Frame 4 is held back until frame 3 is indexed
+        assert_eq!(vec![0, 1, 2], obs_orders(None));
+        assert_eq!(vec![2], obs_orders(Some(1)));
+        assert!(obs_orders(Some(2)).is_empty());
+        assert_eq!(vec![4], obs_orders(Some(3)));
+    }
+
+    #[test]
+    fn test_follow_map() {
+        let (zstd_file, idx_file) = write_archive("follow_map", "a\t1\nb\t2\nc\t3\nd\t4\n");
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let metrics_address = metrics_listener.local_addr().unwrap();
+        let follow_options = FollowOptions {
+            poll_interval: Duration::from_millis(10),
+            idle_timeout: Some(Duration::from_millis(50)),
+            output_file: None,
+            metrics_listener: Some(metrics_listener),
+        };
+
+        let mut obs_updates: Vec<(u64, u64, Option<usize>)> = Vec::new();
+        let obs_result = follow_map::<String, u64>(
+            &zstd_file,
+            &idx_file,
+            2,
+            &Mode::Vector,
+            &ParseOptions::default(),
+            &follow_options,
+            &mut |u| obs_updates.push((u.first_order, u.last_order, u.records)),
+        );
+
+        let _ = std::fs::remove_file(&zstd_file);
+        let _ = std::fs::remove_file(&idx_file);
+
+        let obs_report = obs_result.unwrap();
+        // Two records fill each frame
+        assert_eq!(2, obs_report.frames_read);
+        assert_eq!(Some(4), obs_report.records);
+        assert_eq!(vec![(0, 1, Some(4))], obs_updates);
+
+        // The metrics endpoint is still answering once following has stopped
+        let mut client = std::net::TcpStream::connect(metrics_address).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut obs_metrics = String::new();
+        std::io::Read::read_to_string(&mut client, &mut obs_metrics).unwrap();
+        assert!(obs_metrics.contains("parallel_decompression_frames_decoded_total 2\n"));
+        assert!(obs_metrics.contains("parallel_decompression_records_held 4\n"));
+    }
+
+    #[test]
+    fn test_update_map() {
+        let idx_buffer = vec![FrameMeta::new(0, 0, 0), FrameMeta::new(0, 0, 1)];
+        let mut record_map: EitherMap<String, u64> = EitherMap::AHash(Default::default());
+
+        // Nothing follows the watermark, so nothing is read
+        let obs_result = update_map(
+            &FileSource::new("no_such_archive.zstd"),
+            idx_buffer,
+            &mut record_map,
+            Some(1),
+            1,
+            &ParseOptions::default(),
+        );
+        let (obs_watermark, obs_summary) = obs_result.unwrap();
+        assert_eq!(Some(1), obs_watermark);
+        assert!(!obs_summary.is_partial());
+        assert!(record_map.is_empty());
+
+        let (zstd_file, idx_file) = write_archive("update_map", "a\t1\nb\t2\nc\t3\na\t4\n");
+        let idx_buffer = read_frame_index(&idx_file).unwrap();
+        let mut record_map: EitherMap<String, u64> = EitherMap::Dash(
+            [("a".to_string(), 0), ("z".to_string(), 9)]
+                .into_iter()
+                .collect(),
+        );
+
+        let obs_result = update_map(
+            &FileSource::new(&zstd_file),
+            idx_buffer,
+            &mut record_map,
+            Some(0),
+            2,
+            &ParseOptions::default(),
+        );
+
+        let _ = std::fs::remove_file(&zstd_file);
+        let _ = std::fs::remove_file(&idx_file);
+
+        // Only the second frame is read, and its records replace those already held
+        let (obs_watermark, _) = obs_result.unwrap();
+        assert_eq!(Some(1), obs_watermark);
+        let obs_map = record_map.into_dash().unwrap();
+        assert_eq!(3, obs_map.len());
+        assert_eq!(4, *obs_map.get("a").unwrap());
+        assert_eq!(3, *obs_map.get("c").unwrap());
+        assert!(obs_map.get("b").is_none());
+    }
+
+    #[test]
+    fn test_follow_stream_growing() {
+        let (zstd_file, idx_file) = write_archive("follow_stream_growing", "a\t1\nb\t2\n");
+        let follow_options = FollowOptions {
+            poll_interval: Duration::from_millis(10),
+            idle_timeout: Some(Duration::from_millis(500)),
+            output_file: None,
+            metrics_listener: None,
+        };
+
+        // The archive and index are written again with further frames while being followed
+        let writer_handle = {
+            let (zstd_file, idx_file) = (zstd_file.clone(), idx_file.clone());
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                let mut payload = Cursor::new(Vec::new());
+                let idx_buffer = compress_frames(
+                    "a\t1\nb\t2\nc\t3\n".as_bytes(),
+                    &mut payload,
+                    8,
+                    3,
+                    &CompressionOptions::default(),
+                )
+                .unwrap();
+                std::fs::write(&zstd_file, payload.into_inner()).unwrap();
+                std::fs::write(&idx_file, serde_json::to_vec(&idx_buffer).unwrap()).unwrap();
+            })
+        };
+
+        let mut obs_output: Vec<u8> = Vec::new();
+        let mut obs_updates: Vec<(u64, u64)> = Vec::new();
+        let obs_result = follow_stream(
+            &zstd_file,
+            &idx_file,
+            BufWriter::new(&mut obs_output),
+            1,
+            &ParseOptions::default(),
+            &follow_options,
+            &mut |u| obs_updates.push((u.first_order, u.last_order)),
+        );
+        writer_handle.join().unwrap();
+
+        let _ = std::fs::remove_file(&zstd_file);
+        let _ = std::fs::remove_file(&idx_file);
+
+        let obs_report = obs_result.unwrap();
+        assert_eq!(2, obs_report.frames_read);
+        assert_eq!(vec![(0, 0), (1, 1)], obs_updates);
+        assert_eq!(b"a\t1\nb\t2\nc\t3\n".to_vec(), obs_output);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::decompression::decode_frame;
+use crate::source::FrameSource;
+use crate::{FrameMeta, ParseOptions};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+const CACHE_EXTENSION: &str = "frame";
+// Checksum of the content, written ahead of it in every cached frame
+const CHECKSUM_SIZE: usize = 8;
+
+/// A directory of decoded frames kept between runs, so that frames read again after a restart
+/// are not decoded again. Frames are filed under a hash of the index of their archive and their
+/// order, and each is checked against its checksum when read back. Once the frames held exceed
+/// the capacity, those read least recently are removed. Several processes may share a cache.
+#[derive(Debug)]
+pub struct FrameCache {
+    directory: PathBuf,
+    capacity_bytes: u64,
+    /// Bytes held by the cache as last counted, plus any written since
+    used_bytes: AtomicU64,
+    eviction: Mutex<()>,
+    warned: AtomicBool,
+    /// Frames read back from the cache, and frames which had to be decoded, by this process
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+//region: Private functions
+
+/// Cached frames of the directory, with the time each was last read and its size.
+fn list_frames(directory: &Path) -> Result<Vec<(SystemTime, u64, PathBuf)>> {
+    let mut frames: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|e| e != CACHE_EXTENSION) {
+            continue;
+        }
+        // A frame removed by another process since the listing is simply passed over
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            frames.push((metadata.modified()?, metadata.len(), path));
+        }
+    }
+    Ok(frames)
+}
+
+//endregion:
+
+/// Identity of an archive for the cache, from the place, length, order and checksum of each
+/// frame of its index. Frames with checksums are also checked against them when read from the
+/// cache, so a cached frame is never served for another archive.
+pub(crate) fn archive_key(idx_buffer: &[FrameMeta]) -> u64 {
+    let mut hasher = Xxh3::new();
+    for frame_meta in idx_buffer {
+        for field in [
+            frame_meta.position,
+            frame_meta.length,
+            frame_meta.order,
+            frame_meta.checksum.unwrap_or_default(),
+        ] {
+            hasher.update(&field.to_le_bytes());
+        }
+    }
+    hasher.digest()
+}
+
+/// Decode a frame, or read it back from the frame cache of the parse options if it was cached by
+/// an earlier read. Frames which are decoded are added to the cache.
+pub(crate) fn decode_cached(
+    source: &dyn FrameSource,
+    frame_meta: &FrameMeta,
+    archive_key: u64,
+    parse_options: &ParseOptions,
+) -> Result<Vec<u8>> {
+    let Some(frame_cache) = &parse_options.frame_cache else {
+        return decode_frame(source, frame_meta, parse_options);
+    };
+    if let Some(payload) = frame_cache.get(archive_key, frame_meta) {
+        frame_cache.hits.fetch_add(1, Ordering::Relaxed);
+        return Ok(payload);
+    }
+    frame_cache.misses.fetch_add(1, Ordering::Relaxed);
+
+    let payload = decode_frame(source, frame_meta, parse_options)?;
+    frame_cache.put(archive_key, frame_meta, &payload);
+    Ok(payload)
+}
+
+impl FrameCache {
+    /// Open a cache directory, creating it if need be, which holds up to `capacity_bytes` of
+    /// decoded frames.
+    pub fn open(directory: &str, capacity_bytes: u64) -> Result<FrameCache> {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Unable to create frame cache directory '{}'", directory))?;
+        let used_bytes = list_frames(Path::new(directory))
+            .with_context(|| format!("Unable to read frame cache directory '{}'", directory))?
+            .iter()
+            .map(|(_, size, _)| size)
+            .sum();
+
+        Ok(FrameCache {
+            directory: PathBuf::from(directory),
+            capacity_bytes,
+            used_bytes: AtomicU64::new(used_bytes),
+            eviction: Mutex::new(()),
+            warned: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Bytes of cached frames, as last counted, plus any written by this process since.
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Frames this process has read back from the cache rather than decoding.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Frames this process looked for in the cache but had to decode.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn frame_path(&self, archive_key: u64, order: u64) -> PathBuf {
+        self.directory.join(format!(
+            "{:016x}-{}.{}",
+            archive_key, order, CACHE_EXTENSION
+        ))
+    }
+
+    /// Read a frame back from the cache. A frame which fails its checksum, or that of the index,
+    /// is removed and read as missing.
+    pub(crate) fn get(&self, archive_key: u64, frame_meta: &FrameMeta) -> Option<Vec<u8>> {
+        let frame_path = self.frame_path(archive_key, frame_meta.order);
+        let mut frame_handle = File::open(&frame_path).ok()?;
+        let mut cached: Vec<u8> = Vec::new();
+        frame_handle.read_to_end(&mut cached).ok()?;
+
+        let intact = cached.len() >= CHECKSUM_SIZE && {
+            let (checksum, payload) = cached.split_at(CHECKSUM_SIZE);
+            let checksum = u64::from_le_bytes(checksum.try_into().unwrap());
+            xxh3_64(payload) == checksum && frame_meta.checksum.is_none_or(|c| c == checksum)
+        };
+        if !intact {
+            let _ = std::fs::remove_file(&frame_path);
+            return None;
+        }
+
+        // The modification time stands in for the time of last use, which decides eviction
+        let _ = frame_handle.set_modified(SystemTime::now());
+        cached.drain(..CHECKSUM_SIZE);
+        Some(cached)
+    }
+
+    /// Add a decoded frame to the cache, evicting the frames read least recently if the cache
+    /// then exceeds its capacity. A frame is written under a temporary name and renamed into
+    /// place, so another process never reads one part written. Failing to write a frame only
+    /// costs decoding it again, so is warned of once rather than failing the read.
+    pub(crate) fn put(&self, archive_key: u64, frame_meta: &FrameMeta, payload: &[u8]) {
+        let cached_bytes = (CHECKSUM_SIZE + payload.len()) as u64;
+        if cached_bytes > self.capacity_bytes {
+            return;
+        }
+
+        let frame_path = self.frame_path(archive_key, frame_meta.order);
+        let temporary_path = frame_path.with_extension(format!("{}.tmp", std::process::id()));
+        let write_result = File::create(&temporary_path)
+            .and_then(|mut f| {
+                f.write_all(&xxh3_64(payload).to_le_bytes())?;
+                f.write_all(payload)
+            })
+            .and_then(|_| std::fs::rename(&temporary_path, &frame_path));
+        if let Err(e) = write_result {
+            let _ = std::fs::remove_file(&temporary_path);
+            if !self.warned.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "WARNING: unable to write to the frame cache '{}': {}",
+                    self.directory.display(),
+                    e
+                );
+            }
+            return;
+        }
+
+        if self.used_bytes.fetch_add(cached_bytes, Ordering::Relaxed) + cached_bytes
+            > self.capacity_bytes
+        {
+            self.evict();
+        }
+    }
+
+    /// Remove the frames read least recently until the cache is back within its capacity. The
+    /// directory is counted afresh, taking in frames written by other processes.
+    fn evict(&self) {
+        let _eviction = self.eviction.lock().unwrap();
+        let Ok(mut frames) = list_frames(&self.directory) else {
+            return;
+        };
+        frames.sort();
+
+        let mut used_bytes: u64 = frames.iter().map(|(_, size, _)| size).sum();
+        for (_, size, path) in frames {
+            if used_bytes <= self.capacity_bytes {
+                break;
+            }
+            if std::fs::remove_file(path).is_ok() {
+                used_bytes -= size;
+            }
+        }
+        self.used_bytes.store(used_bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_frame_cache_get() {
+        let cache_dir = "frame_cache_get";
+        let frame_cache = FrameCache::open(cache_dir, 1 << 20).unwrap();
+        let payload = b"a\t1\nb\t2\n";
+        let frame_meta = FrameMeta::new(0, 20, 0).with_checksum(xxh3_64(payload));
+
+        assert_eq!(None, frame_cache.get(7, &frame_meta));
+        frame_cache.put(7, &frame_meta, payload);
+        let obs_payload = frame_cache.get(7, &frame_meta);
+        let obs_other_archive = frame_cache.get(8, &frame_meta);
+
+        // A frame whose index checksum differs is not served, and is removed
+        let changed_meta = FrameMeta::new(0, 20, 0).with_checksum(1);
+        let obs_changed = frame_cache.get(7, &changed_meta);
+        let obs_removed = frame_cache.get(7, &frame_meta);
+        let _ = std::fs::remove_dir_all(cache_dir);
+
+        assert_eq!(Some(payload.to_vec()), obs_payload);
+        assert_eq!(None, obs_other_archive);
+        assert_eq!(None, obs_changed);
+        assert_eq!(None, obs_removed);
+    }
+
+    #[test]
+    fn test_frame_cache_evict() {
+        let cache_dir = "frame_cache_evict";
+        let frame_cache = FrameCache::open(cache_dir, 250).unwrap();
+        let payload = [b'a'; 92];
+
+        // Frames are given distinct times of last use, oldest first
+        for order in 0..3 {
+            let frame_meta = FrameMeta::new(order * 10, 10, order);
+            frame_cache.put(1, &frame_meta, &payload);
+            if let Ok(f) = File::options()
+                .write(true)
+                .open(frame_cache.frame_path(1, order))
+            {
+                f.set_modified(UNIX_EPOCH + Duration::from_secs(1000 * (order + 1)))
+                    .unwrap();
+            }
+        }
+
+        let obs_held: Vec<bool> = (0..3)
+            .map(|order| frame_cache.frame_path(1, order).exists())
+            .collect();
+        let obs_used = frame_cache.used_bytes();
+        let _ = std::fs::remove_dir_all(cache_dir);
+
+        assert_eq!(vec![false, true, true], obs_held);
+        assert_eq!(200, obs_used);
+    }
+
+    #[test]
+    fn test_archive_key() {
+        let idx_buffer = vec![FrameMeta::new(0, 100, 0), FrameMeta::new(100, 50, 1)];
+        let moved_buffer = vec![FrameMeta::new(0, 100, 0), FrameMeta::new(100, 51, 1)];
+
+        assert_eq!(archive_key(&idx_buffer), archive_key(&idx_buffer.clone()));
+        assert_ne!(archive_key(&idx_buffer), archive_key(&moved_buffer));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::decompression::{gather_zstd_frame, FrameLedger};
+use crate::numa::build_worker_pool;
+use crate::source::FrameSource;
+use crate::{BadRecord, DecompressionSummary, FrameMeta, ParseOptions, RunStatus};
+use ahash::AHashMap;
+use anyhow::Result;
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+type FrameEntries = Vec<(Box<[u8]>, Box<[u8]>)>;
+type ValueCounts = AHashMap<Box<[u8]>, u64>;
+
+/// Ranking of a value, under which higher counts come first and equal counts fall back to the
+/// order of the value text.
+type ValueRank = (u64, Reverse<Box<[u8]>>);
+
+/// Outcome of a top values report, for the caller to report.
+#[derive(Debug, Default)]
+pub struct TopValuesReport {
+    pub records: usize,
+    /// Number of distinct values counted
+    pub distinct_values: usize,
+    /// Most frequent values with their record counts, most frequent first
+    pub values: Vec<(String, u64)>,
+    pub summary: DecompressionSummary,
+}
+
+impl TopValuesReport {
+    pub fn run_status(&self) -> RunStatus {
+        RunStatus::from_summary(&self.summary)
+    }
+}
+
+//region: Private functions
+
+fn count_entries(value_counts: &mut ValueCounts, entries: FrameEntries) -> usize {
+    let records = entries.len();
+    for (_, value) in entries {
+        *value_counts.entry(value).or_default() += 1;
+    }
+    records
+}
+
+fn merge_counts(mut a: ValueCounts, b: ValueCounts) -> ValueCounts {
+    // Fold the smaller map into the larger, so that the fewest entries are moved
+    if a.len() < b.len() {
+        return merge_counts(b, a);
+    }
+    for (value, count) in b {
+        *a.entry(value).or_default() += count;
+    }
+    a
+}
+
+/// Keep the `k` most frequent values in a min-heap, so that only `k` values are held while
+/// the counts are ranked.
+fn top_values(value_counts: ValueCounts, k: usize) -> Vec<(String, u64)> {
+    let mut top_heap: BinaryHeap<Reverse<ValueRank>> = BinaryHeap::with_capacity(k + 1);
+    for (value, count) in value_counts {
+        top_heap.push(Reverse((count, Reverse(value))));
+        if top_heap.len() > k {
+            top_heap.pop();
+        }
+    }
+
+    top_heap
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((count, Reverse(value)))| {
+            (String::from_utf8_lossy(&value).to_string(), count)
+        })
+        .collect()
+}
+
+//endregion:
+
+/// Count the records of each value and report the `k` most frequent. Each worker counts into
+/// its own map, and the maps are merged once every frame is read.
+pub(crate) fn top_values_zstd(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    k: usize,
+) -> Result<TopValuesReport> {
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_ledger = FrameLedger::default();
+
+    let (mut value_counts, mut records, mut bad_buffer) = pool.install(|| {
+        idx_buffer
+            .into_par_iter()
+            .try_fold(
+                || (ValueCounts::new(), 0, Vec::new()),
+                |(mut value_counts, records, mut bad_buffer), idx_frame| -> Result<_> {
+                    let (entries, bad_records): (FrameEntries, Vec<BadRecord>) =
+                        gather_zstd_frame(source, idx_frame, parse_options, &frame_ledger)?
+                            .unwrap_or_default();
+                    let frame_records = count_entries(&mut value_counts, entries);
+                    bad_buffer.extend(bad_records);
+                    Ok((value_counts, records + frame_records, bad_buffer))
+                },
+            )
+            .try_reduce(
+                || (ValueCounts::new(), 0, Vec::new()),
+                |(a_counts, a_records, mut a_bad), (b_counts, b_records, b_bad)| {
+                    a_bad.extend(b_bad);
+                    Ok((
+                        merge_counts(a_counts, b_counts),
+                        a_records + b_records,
+                        a_bad,
+                    ))
+                },
+            )
+    })?;
+
+    // Lines which span frames are only complete once every frame has been read
+    for (_, (entries, stitched_bad)) in frame_ledger.stitch(parse_options)? {
+        records += count_entries(&mut value_counts, entries);
+        bad_buffer.extend(stitched_bad);
+    }
+
+    Ok(TopValuesReport {
+        records,
+        distinct_values: value_counts.len(),
+        values: top_values(value_counts, k),
+        summary: frame_ledger.into_summary(bad_buffer),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+    use std::fs::OpenOptions;
+    use std::io::BufReader;
+
+    #[test]
+    fn test_top_values() {
+        let value_counts: ValueCounts = [("562", 3), ("9606", 5), ("1280", 3), ("287", 1)]
+            .into_iter()
+            .map(|(v, c)| (v.as_bytes().into(), c))
+            .collect();
+
+        // Equal counts are ranked by value
+        let exp_values = vec![
+            ("9606".to_string(), 5),
+            ("1280".to_string(), 3),
+            ("562".to_string(), 3),
+        ];
+        assert_eq!(exp_values, top_values(value_counts, 3));
+    }
+
+    #[test]
+    fn test_top_values_zstd() {
+        let idx_file = OpenOptions::new()
+            .read(true)
+            .open("test/example.zstd.idx")
+            .unwrap();
+        let idx_buffer = load_frame_index(&mut BufReader::new(idx_file)).unwrap();
+
+        let obs_report = top_values_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+            5,
+        )
+        .unwrap();
+
+        let content = std::fs::read_to_string("test/data.txt").unwrap();
+        let mut exp_counts: ValueCounts = ValueCounts::new();
+        for line in content.lines() {
+            let value = line.split('\t').nth(1).unwrap();
+            *exp_counts.entry(value.as_bytes().into()).or_default() += 1;
+        }
+
+        assert_eq!(30, obs_report.records);
+        assert_eq!(exp_counts.len(), obs_report.distinct_values);
+        assert_eq!(top_values(exp_counts, 5), obs_report.values);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::PipelineError;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::io::Read;
+
+// Accession prefixes, in byte order so that a sorted run can walk them in turn
+const ACCESSION_PREFIXES: [&str; 7] = ["EFG", "KJX", "MEX", "NP_", "WP_", "XP_", "YP_"];
+// Size of the line buffer filled at a time
+const LINE_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum KeyStyle {
+    /// Versioned protein accessions such as 'WP_000123456.1'
+    #[default]
+    Accession,
+    /// Zero-padded integers
+    Numeric,
+}
+
+/// Shape of a synthetic record set. The same options and seed always give the same records.
+#[derive(Clone, Debug)]
+pub struct GenerateOptions {
+    /// Number of records written, including duplicates
+    pub records: u64,
+    pub key_style: KeyStyle,
+    /// Values are drawn evenly from 1 to this value
+    pub value_max: u64,
+    /// Write the records in key order
+    pub sorted: bool,
+    /// Share of records which repeat the key of an earlier record, from 0 up to (but not
+    /// including) 1
+    pub duplicate_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions {
+            records: 0,
+            key_style: KeyStyle::Accession,
+            value_max: 3_000_000,
+            sorted: false,
+            duplicate_rate: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+/// Synthetic KEY<TAB>VALUE records, read as text. Keys are drawn from a numbering of the
+/// distinct keys, which is walked in order for sorted records and through a fixed stride
+/// otherwise, so no record needs to be held once written.
+pub(crate) struct SyntheticRecords {
+    options: GenerateOptions,
+    state: u64,
+    stride: u64,
+    key_width: usize,
+    records_written: u64,
+    keys_written: u64,
+    last_key: u64,
+    line_buffer: Vec<u8>,
+    line_position: usize,
+}
+
+//region: Private functions
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Stride sharing no factor with the number of keys, so that stepping through the keys by it
+/// visits each exactly once. Starting near the golden ratio of the count spreads neighbouring
+/// keys far apart.
+fn coprime_stride(key_count: u64) -> u64 {
+    if key_count <= 1 {
+        return 1;
+    }
+    let mut stride = (key_count as f64 * 0.618_033_988_75) as u64 % key_count;
+    while stride == 0 || gcd(stride, key_count) != 1 {
+        stride = (stride + 1) % key_count;
+    }
+    stride
+}
+
+//endregion:
+
+impl SyntheticRecords {
+    pub(crate) fn new(options: &GenerateOptions) -> Result<SyntheticRecords> {
+        if !(0.0..1.0).contains(&options.duplicate_rate) {
+            bail!(PipelineError::Usage(format!(
+                "The duplicate rate must be at least 0 and below 1, not {}!",
+                options.duplicate_rate
+            )));
+        }
+        if options.value_max == 0 {
+            bail!(PipelineError::Usage(
+                "The largest value must be greater than zero!".into()
+            ));
+        }
+
+        Ok(SyntheticRecords {
+            options: options.clone(),
+            state: options.seed,
+            stride: coprime_stride(options.records),
+            key_width: options.records.saturating_sub(1).max(1).ilog10() as usize + 1,
+            records_written: 0,
+            keys_written: 0,
+            last_key: 0,
+            line_buffer: Vec::with_capacity(LINE_BUFFER_SIZE),
+            line_position: 0,
+        })
+    }
+
+    /// Next number of the splitmix64 sequence.
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Distinct key written as the given key of the run.
+    fn key_number(&self, key_position: u64) -> u64 {
+        match self.options.sorted {
+            true => key_position,
+            false => {
+                ((key_position as u128 * self.stride as u128) % self.options.records as u128) as u64
+            }
+        }
+    }
+
+    fn write_key(&mut self, key_number: u64) {
+        match self.options.key_style {
+            KeyStyle::Accession => {
+                // Prefixes cover consecutive runs of key numbers, so key order is number order
+                let prefix_position = (key_number as u128 * ACCESSION_PREFIXES.len() as u128
+                    / self.options.records as u128) as usize;
+                let version = 1 + key_number % 3;
+                self.line_buffer.extend_from_slice(
+                    format!(
+                        "{}{:0width$}.{}",
+                        ACCESSION_PREFIXES[prefix_position],
+                        key_number,
+                        version,
+                        width = self.key_width.max(9)
+                    )
+                    .as_bytes(),
+                );
+            }
+            KeyStyle::Numeric => self.line_buffer.extend_from_slice(
+                format!("{:0width$}", key_number, width = self.key_width).as_bytes(),
+            ),
+        }
+    }
+
+    fn write_record(&mut self) {
+        let duplicate_threshold = (self.options.duplicate_rate * u64::MAX as f64) as u64;
+        let key_number = match self.keys_written > 0 && self.next_random() < duplicate_threshold {
+            // A duplicate follows the record it repeats when sorted, so the order holds
+            true if self.options.sorted => self.last_key,
+            true => {
+                let key_position = self.next_random() % self.keys_written;
+                self.key_number(key_position)
+            }
+            false => {
+                self.keys_written += 1;
+                self.key_number(self.keys_written - 1)
+            }
+        };
+        self.last_key = key_number;
+
+        let value = 1 + self.next_random() % self.options.value_max;
+        self.write_key(key_number);
+        self.line_buffer
+            .extend_from_slice(format!("\t{}\n", value).as_bytes());
+        self.records_written += 1;
+    }
+}
+
+impl Read for SyntheticRecords {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.line_position == self.line_buffer.len() {
+            self.line_buffer.clear();
+            self.line_position = 0;
+            while self.line_buffer.len() < LINE_BUFFER_SIZE
+                && self.records_written < self.options.records
+            {
+                self.write_record();
+            }
+        }
+
+        let available = &self.line_buffer[self.line_position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.line_position += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn generate_lines(options: &GenerateOptions) -> Vec<String> {
+        let mut content = String::new();
+        SyntheticRecords::new(options)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        content.lines().map(|l| l.to_string()).collect()
+    }
+
+    fn keys_of(lines: &[String]) -> Vec<&str> {
+        lines
+            .iter()
+            .map(|l| l.split('\t').next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_synthetic_records_unique() {
+        let options = GenerateOptions {
+            records: 1000,
+            value_max: 10,
+            ..Default::default()
+        };
+
+        let obs_lines = generate_lines(&options);
+        let mut obs_keys = keys_of(&obs_lines);
+        assert_eq!(1000, obs_lines.len());
+        assert!(!obs_keys.is_sorted());
+        assert!(obs_lines.iter().all(|l| {
+            let value: u64 = l.split('\t').nth(1).unwrap().parse().unwrap();
+            (1..=10).contains(&value)
+        }));
+
+        obs_keys.sort();
+        obs_keys.dedup();
+        assert_eq!(1000, obs_keys.len());
+
+        // The same seed gives the same records
+        assert_eq!(obs_lines, generate_lines(&options));
+    }
+
+    #[test]
+    fn test_synthetic_records_sorted_duplicates() {
+        let options = GenerateOptions {
+            records: 2000,
+            key_style: KeyStyle::Numeric,
+            sorted: true,
+            duplicate_rate: 0.25,
+            ..Default::default()
+        };
+
+        let obs_lines = generate_lines(&options);
+        let mut obs_keys = keys_of(&obs_lines);
+        assert_eq!(2000, obs_lines.len());
+        assert!(obs_keys.is_sorted());
+        assert_eq!("0000", obs_keys[0]);
+
+        obs_keys.dedup();
+        let obs_duplicates = 2000 - obs_keys.len();
+        assert!((400..600).contains(&obs_duplicates));
+    }
+
+    #[test]
+    fn test_synthetic_records_bad_rate() {
+        let options = GenerateOptions {
+            records: 10,
+            duplicate_rate: 1.0,
+            ..Default::default()
+        };
+        assert!(SyntheticRecords::new(&options).is_err());
+    }
+}
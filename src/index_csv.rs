@@ -0,0 +1,225 @@
+use crate::{FrameMeta, KeyRange, PipelineError};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::io::{BufRead, Write};
+
+// Columns of an exported index, in the order they are written
+const CSV_COLUMNS: [&str; 7] = [
+    "order",
+    "offset",
+    "length",
+    "checksum",
+    "level",
+    "first_key",
+    "last_key",
+];
+
+/// Layout of an exported frame index.
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum IndexFormat {
+    /// One line per frame, under a header naming the columns
+    #[default]
+    Csv,
+    /// The internal representation, uncompressed
+    Json,
+}
+
+//region: Private functions
+
+/// Quote a field when it holds a comma, quote or line ending, doubling any quotes within it.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\r', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// Split a line into its fields, undoing the quoting of `csv_field`.
+fn split_csv_line(line: &str) -> Result<Vec<String>> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        bail!("A quoted field is not closed.");
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&String>, column: &str) -> Result<Option<T>> {
+    match field.map(|f| f.trim()) {
+        None | Some("") => Ok(None),
+        Some(f) => match f.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => bail!("'{}' is not a valid {}.", f, column),
+        },
+    }
+}
+
+fn parse_frame(fields: &[String], columns: &[Option<usize>; 7]) -> Result<FrameMeta> {
+    let field = |i: usize| columns[i].and_then(|c| fields.get(c));
+
+    let (Some(order), Some(position), Some(length)) = (
+        parse_field::<u64>(field(0), CSV_COLUMNS[0])?,
+        parse_field::<u64>(field(1), CSV_COLUMNS[1])?,
+        parse_field::<u64>(field(2), CSV_COLUMNS[2])?,
+    ) else {
+        bail!("The order, offset and length of a frame are required.");
+    };
+
+    let mut frame_meta = FrameMeta::new(position, length, order);
+    frame_meta.checksum = parse_field(field(3), CSV_COLUMNS[3])?;
+    frame_meta.level = parse_field(field(4), CSV_COLUMNS[4])?;
+    frame_meta.key_range = match (field(5), field(6)) {
+        (Some(first), Some(last)) if !first.is_empty() || !last.is_empty() => Some(KeyRange {
+            first: first.clone(),
+            last: last.clone(),
+        }),
+        _ => None,
+    };
+    Ok(frame_meta)
+}
+
+//endregion:
+
+/// Write a frame index as CSV, one line per frame in the order of the index. Fields absent from
+/// a frame, such as the checksum of a scanned frame, are left empty.
+pub(crate) fn write_index_csv<W: Write>(idx_buffer: &[FrameMeta], mut csv_writer: W) -> Result<()> {
+    writeln!(csv_writer, "{}", CSV_COLUMNS.join(","))?;
+    for frame_meta in idx_buffer {
+        let (first_key, last_key) = match &frame_meta.key_range {
+            Some(k) => (csv_field(&k.first), csv_field(&k.last)),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            csv_writer,
+            "{},{},{},{},{},{},{}",
+            frame_meta.order,
+            frame_meta.position,
+            frame_meta.length,
+            frame_meta.checksum.map_or(String::new(), |c| c.to_string()),
+            frame_meta.level.map_or(String::new(), |l| l.to_string()),
+            first_key,
+            last_key
+        )?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// Read a frame index from CSV under a header naming its columns. Columns may come in any order,
+/// and only 'order', 'offset' and 'length' are required, so that a layout can be written by
+/// tools which know nothing of checksums or key ranges.
+pub(crate) fn read_index_csv<R: BufRead>(csv_reader: R) -> Result<Vec<FrameMeta>> {
+    let mut lines = csv_reader.lines();
+    let header = match lines.next() {
+        Some(h) => split_csv_line(h?.trim_end_matches('\r'))?,
+        None => bail!(PipelineError::Usage(
+            "The CSV index is empty, with no header naming its columns!".into()
+        )),
+    };
+
+    let mut columns: [Option<usize>; 7] = [None; 7];
+    for (i, name) in header.iter().enumerate() {
+        match CSV_COLUMNS.iter().position(|c| *c == name.trim()) {
+            Some(c) => columns[c] = Some(i),
+            None => bail!(PipelineError::Usage(format!(
+                "Unknown column '{}' in the CSV index, which may hold {}!",
+                name,
+                CSV_COLUMNS.join(", ")
+            ))),
+        }
+    }
+    if columns[..3].iter().any(Option::is_none) {
+        bail!(PipelineError::Usage(
+            "The CSV index needs 'order', 'offset' and 'length' columns!".into()
+        ));
+    }
+
+    let mut idx_buffer: Vec<FrameMeta> = Vec::new();
+    for (line_number, line) in lines.enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let frame_meta = split_csv_line(line)
+            .and_then(|fields| parse_frame(&fields, &columns))
+            .map_err(|e| {
+                PipelineError::Usage(format!(
+                    "Line {} of the CSV index is malformed: {}",
+                    line_number + 2,
+                    e
+                ))
+            })?;
+        idx_buffer.push(frame_meta);
+    }
+
+    idx_buffer.sort_by_key(|f| f.order);
+    if let Some(pair) = idx_buffer.windows(2).find(|p| p[0].order == p[1].order) {
+        bail!(PipelineError::Usage(format!(
+            "Frame order {} is listed more than once in the CSV index!",
+            pair[0].order
+        )));
+    }
+    Ok(idx_buffer)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_index_csv_round_trip() {
+        let exp_index = vec![
+            FrameMeta::new(0, 120, 0).with_checksum(42),
+            FrameMeta::new(120, 80, 1)
+                .with_level(19)
+                .with_key_range(Some(KeyRange {
+                    first: "a,\"b\"".into(),
+                    last: "c".into(),
+                })),
+        ];
+
+        let mut csv_buffer: Vec<u8> = Vec::new();
+        write_index_csv(&exp_index, &mut csv_buffer).unwrap();
+        let obs_index = read_index_csv(csv_buffer.as_slice()).unwrap();
+
+        assert_eq!(exp_index, obs_index);
+        assert!(String::from_utf8(csv_buffer)
+            .unwrap()
+            .starts_with("order,offset,length,checksum,level,first_key,last_key\n0,0,120,42,,,\n"));
+    }
+
+    #[test]
+    fn test_read_index_csv_minimal() {
+        let csv_content = "length,offset,order\r\n80,120,1\r\n120,0,0\r\n";
+
+        let obs_index = read_index_csv(csv_content.as_bytes()).unwrap();
+        assert_eq!(
+            vec![FrameMeta::new(0, 120, 0), FrameMeta::new(120, 80, 1)],
+            obs_index
+        );
+
+        assert!(read_index_csv("order,offset\n0,0\n".as_bytes()).is_err());
+        assert!(read_index_csv("order,offset,length,size\n0,0,1,1\n".as_bytes()).is_err());
+        assert!(read_index_csv("order,offset,length\n0,0,x\n".as_bytes()).is_err());
+        assert!(read_index_csv("order,offset,length\n0,0,1\n0,1,1\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{FrameMeta, PipelineError};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+
+/// Changes made to a frame index by `index edit`, applied in the order of the fields: frames are
+/// dropped, then held to the kept range, then put in the given sequence. The payload is never
+/// touched, so an edited index reads a subset or rearrangement of the same archive.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexEdit {
+    /// Orders of the frames to leave out, such as those found to be corrupt
+    pub drop: Vec<u64>,
+    /// First and last order (inclusive) of the frames to keep
+    pub keep: Option<(u64, u64)>,
+    /// Orders of the remaining frames in the sequence they are to be read, each listed once
+    pub reorder: Vec<u64>,
+    /// Number the frames from zero in their new sequence, rather than keeping their orders
+    pub renumber: bool,
+}
+
+//region: Private functions
+
+/// Parse a list of frame orders such as '3,7-9', where ranges are inclusive.
+fn parse_frame_orders(orders: &str) -> Result<Vec<u64>> {
+    let mut frame_orders: Vec<u64> = Vec::new();
+    for part in orders.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        match (first.trim().parse::<u64>(), last.trim().parse::<u64>()) {
+            (Ok(first), Ok(last)) if first <= last => frame_orders.extend(first..=last),
+            _ => bail!(PipelineError::Usage(format!(
+                "'{}' is not a frame order or an ascending range of them (e.g. '3' or '7-9')!",
+                part
+            ))),
+        }
+    }
+    Ok(frame_orders)
+}
+
+/// Parse an inclusive range of frame orders such as '0-99'.
+fn parse_frame_range(range: &str) -> Result<(u64, u64)> {
+    match parse_frame_orders(range)?.as_slice() {
+        [order] => Ok((*order, *order)),
+        [first, .., last] if !range.contains(',') => Ok((*first, *last)),
+        _ => bail!(PipelineError::Usage(format!(
+            "'{}' is not a single range of frame orders (e.g. '0-99')!",
+            range
+        ))),
+    }
+}
+
+//endregion:
+
+impl IndexEdit {
+    /// Build an edit from lists of frame orders as given on the command line, such as '3,7-9'
+    /// for the frames to drop and '0-99' for the range to keep.
+    pub fn parse(
+        drop: Option<&str>,
+        keep: Option<&str>,
+        reorder: Option<&str>,
+        renumber: bool,
+    ) -> Result<IndexEdit> {
+        Ok(IndexEdit {
+            drop: drop
+                .map(parse_frame_orders)
+                .transpose()?
+                .unwrap_or_default(),
+            keep: keep.map(parse_frame_range).transpose()?,
+            reorder: reorder
+                .map(parse_frame_orders)
+                .transpose()?
+                .unwrap_or_default(),
+            renumber,
+        })
+    }
+
+    /// Apply the edit to an index, failing where it names frames the index does not hold so that
+    /// a mistyped order is not silently ignored.
+    pub fn apply(&self, mut idx_buffer: Vec<FrameMeta>) -> Result<Vec<FrameMeta>> {
+        idx_buffer.sort_by_key(|f| f.order);
+        let held: HashSet<u64> = idx_buffer.iter().map(|f| f.order).collect();
+
+        if let Some(order) = self.drop.iter().find(|o| !held.contains(o)) {
+            bail!(PipelineError::Usage(format!(
+                "Frame {} cannot be dropped, as the index does not hold it!",
+                order
+            )));
+        }
+        let dropped: HashSet<u64> = self.drop.iter().copied().collect();
+        idx_buffer.retain(|f| !dropped.contains(&f.order));
+
+        if let Some((first, last)) = self.keep {
+            idx_buffer.retain(|f| (first..=last).contains(&f.order));
+        }
+
+        if !self.reorder.is_empty() {
+            let mut remaining: HashMap<u64, FrameMeta> =
+                idx_buffer.into_iter().map(|f| (f.order, f)).collect();
+            let mut reordered: Vec<FrameMeta> = Vec::with_capacity(remaining.len());
+            for order in &self.reorder {
+                match remaining.remove(order) {
+                    Some(f) => reordered.push(f),
+                    None => bail!(PipelineError::Usage(format!(
+                        "Frame {} cannot be reordered, as it is listed twice or is not among the frames kept!",
+                        order
+                    ))),
+                }
+            }
+            if let Some(order) = remaining.keys().min() {
+                bail!(PipelineError::Usage(format!(
+                    "Frame {} is kept but missing from the new order, which must list every frame kept!",
+                    order
+                )));
+            }
+            idx_buffer = reordered;
+        }
+
+        if idx_buffer.is_empty() {
+            bail!(PipelineError::Usage(
+                "The edit leaves no frames in the index!".into()
+            ));
+        }
+        if self.renumber || !self.reorder.is_empty() {
+            for (order, frame_meta) in idx_buffer.iter_mut().enumerate() {
+                frame_meta.order = order as u64;
+            }
+        }
+        Ok(idx_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn example_index() -> Vec<FrameMeta> {
+        (0..6).map(|i| FrameMeta::new(i * 100, 100, i)).collect()
+    }
+
+    #[test]
+    fn test_parse_frame_orders() {
+        assert_eq!(vec![3, 7, 8, 9], parse_frame_orders("3, 7-9").unwrap());
+        assert!(parse_frame_orders("9-7").is_err());
+        assert!(parse_frame_orders("x").is_err());
+
+        assert_eq!((0, 99), parse_frame_range("0-99").unwrap());
+        assert_eq!((4, 4), parse_frame_range("4").unwrap());
+        assert!(parse_frame_range("1,3").is_err());
+
+        let obs_edit = IndexEdit::parse(Some("1,4-5"), None, Some("3,0"), false).unwrap();
+        assert_eq!(vec![1, 4, 5], obs_edit.drop);
+        assert_eq!(vec![3, 0], obs_edit.reorder);
+    }
+
+    #[test]
+    fn test_index_edit_drop_and_keep() {
+        let index_edit = IndexEdit {
+            drop: vec![2],
+            keep: Some((1, 4)),
+            ..Default::default()
+        };
+
+        let obs_orders: Vec<(u64, u64)> = index_edit
+            .apply(example_index())
+            .unwrap()
+            .iter()
+            .map(|f| (f.order, f.position))
+            .collect();
+        assert_eq!(vec![(1, 100), (3, 300), (4, 400)], obs_orders);
+
+        let index_edit = IndexEdit {
+            drop: vec![6],
+            ..Default::default()
+        };
+        assert!(index_edit.apply(example_index()).is_err());
+
+        let index_edit = IndexEdit {
+            keep: Some((10, 20)),
+            ..Default::default()
+        };
+        assert!(index_edit.apply(example_index()).is_err());
+    }
+
+    #[test]
+    fn test_index_edit_reorder() {
+        let index_edit = IndexEdit {
+            keep: Some((0, 2)),
+            reorder: vec![2, 0, 1],
+            ..Default::default()
+        };
+
+        // Frames are numbered in their new sequence, while still pointing at the same bytes
+        let obs_positions: Vec<(u64, u64)> = index_edit
+            .apply(example_index())
+            .unwrap()
+            .iter()
+            .map(|f| (f.order, f.position))
+            .collect();
+        assert_eq!(vec![(0, 200), (1, 0), (2, 100)], obs_positions);
+
+        for reorder in [vec![2, 0], vec![2, 0, 0], vec![2, 0, 5]] {
+            let index_edit = IndexEdit {
+                keep: Some((0, 2)),
+                reorder,
+                ..Default::default()
+            };
+            assert!(index_edit.apply(example_index()).is_err());
+        }
+    }
+}
@@ -0,0 +1,733 @@
+use crate::decompression::{gather_zstd_frame, strip_key_version, trim_line_ending, FrameLedger};
+use crate::numa::build_worker_pool;
+use crate::reorder::{ordered_channel, OrderedReceiver, OrderedSender};
+use crate::source::FrameSource;
+use crate::{
+    BadRecord, DecompressionSummary, FrameMeta, ParseOptions, PipelineError, RecordKey, RunStatus,
+};
+use ahash::RandomState;
+use anyhow::{bail, Result};
+use indexmap::IndexSet;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::iter::Peekable;
+use std::sync::Mutex;
+
+// Number of frames which can be decoded ahead of the next one to be written before workers block
+const JOIN_CHANNEL_BOUND: usize = 64;
+// Number of frames of each archive which can be decoded ahead of the merge
+const MERGE_CHANNEL_BOUND: usize = 16;
+
+type JoinedRecords<K> = Vec<(K, Box<[u8]>)>;
+type MergeRecord = (Box<[u8]>, Box<[u8]>);
+
+/// Query keys in the order of the query file.
+pub type QueryKeys<K> = IndexSet<K, RandomState>;
+
+/// Outcome of a join, for the caller to report.
+#[derive(Debug, Default)]
+pub struct JoinReport {
+    /// Distinct keys in the query file
+    pub query_keys: usize,
+    /// Query keys found in at least one record
+    pub keys_found: usize,
+    /// Query keys found in no record
+    pub keys_missing: usize,
+    pub records_written: usize,
+    /// Frames not decoded, as their key range holds none of the query keys
+    pub frames_skipped: usize,
+    pub summary: DecompressionSummary,
+}
+
+impl JoinReport {
+    pub fn run_status(&self) -> RunStatus {
+        RunStatus::from_summary(&self.summary)
+    }
+}
+
+/// Outcome of a merge join of two archives, for the caller to report.
+#[derive(Debug, Default)]
+pub struct MergeJoinReport {
+    /// Distinct keys present in both archives
+    pub keys_matched: usize,
+    pub pairs_written: usize,
+    pub left_summary: DecompressionSummary,
+    pub right_summary: DecompressionSummary,
+}
+
+impl MergeJoinReport {
+    pub fn run_status(&self) -> RunStatus {
+        match RunStatus::from_summary(&self.left_summary) {
+            RunStatus::Complete => RunStatus::from_summary(&self.right_summary),
+            RunStatus::Partial => RunStatus::Partial,
+        }
+    }
+}
+
+/// Records of one side of a merge join, checked to be in key order as they are read.
+struct SortedRecords<'a, I: Iterator<Item = MergeRecord>> {
+    source: &'a dyn FrameSource,
+    records: Peekable<I>,
+}
+
+//region: Private functions
+
+fn record_writer<K: RecordKey>(
+    record_receiver: OrderedReceiver<JoinedRecords<K>>,
+    output_handle: Option<File>,
+    query_keys: &QueryKeys<K>,
+) -> Result<(usize, Vec<bool>)> {
+    let mut output_writer = output_handle.map(BufWriter::new);
+    let mut keys_found: Vec<bool> = vec![false; query_keys.len()];
+    let mut records_written: usize = 0;
+
+    for records in record_receiver {
+        for (key, value) in records {
+            if let Some(output_writer) = output_writer.as_mut() {
+                output_writer.write_all(&key.key_bytes())?;
+                output_writer.write_all(b"\t")?;
+                output_writer.write_all(&value)?;
+                output_writer.write_all(b"\n")?;
+                records_written += 1;
+            }
+
+            if let Some(i) = query_keys.get_index_of(&key) {
+                keys_found[i] = true;
+            }
+        }
+    }
+
+    if let Some(mut output_writer) = output_writer {
+        output_writer.flush()?;
+    }
+    Ok((records_written, keys_found))
+}
+
+/// Whether any of the sorted keys falls within the key range of the frame. Frames without a
+/// recorded range may hold any key.
+fn may_hold_any(idx_frame: &FrameMeta, sorted_keys: &[&[u8]]) -> bool {
+    match &idx_frame.key_range {
+        Some(key_range) => {
+            let i = sorted_keys.partition_point(|k| *k < key_range.first.as_bytes());
+            i < sorted_keys.len() && sorted_keys[i] <= key_range.last.as_bytes()
+        }
+        None => true,
+    }
+}
+
+fn write_missing_keys<K: RecordKey>(
+    missing_handle: File,
+    query_keys: &QueryKeys<K>,
+    keys_found: &[bool],
+) -> Result<()> {
+    let mut missing_writer = BufWriter::new(missing_handle);
+    for (query_key, _) in query_keys
+        .iter()
+        .zip(keys_found)
+        .filter(|(_, found)| !**found)
+    {
+        missing_writer.write_all(&query_key.key_bytes())?;
+        missing_writer.write_all(b"\n")?;
+    }
+    missing_writer.flush()?;
+    Ok(())
+}
+
+fn stream_frames<K: RecordKey>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    record_sender: OrderedSender<JoinedRecords<K>>,
+) -> Result<DecompressionSummary> {
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_ledger = FrameLedger::default();
+    let bad_records: Mutex<Vec<BadRecord>> = Mutex::default();
+
+    // The merge stops reading once either archive is exhausted, so a closed receiver (None) ends
+    // the stream without an error
+    let stream_result: Result<(), Option<anyhow::Error>> = pool.install(|| {
+        idx_buffer.into_par_iter().enumerate().try_for_each_with(
+            record_sender,
+            |record_sender, (sequence, idx_frame)| {
+                let (records, frame_bad): (JoinedRecords<K>, Vec<BadRecord>) =
+                    gather_zstd_frame(source, idx_frame, parse_options, &frame_ledger)
+                        .map_err(|e| {
+                            record_sender.abandon();
+                            Some(e)
+                        })?
+                        .unwrap_or_default();
+                bad_records.lock().unwrap().extend(frame_bad);
+                record_sender.send(sequence, records).map_err(|_| None)
+            },
+        )
+    });
+
+    match stream_result {
+        Ok(()) | Err(None) => Ok(frame_ledger.into_summary(bad_records.into_inner().unwrap())),
+        Err(Some(e)) => Err(e),
+    }
+}
+
+impl<'a, I: Iterator<Item = MergeRecord>> SortedRecords<'a, I> {
+    fn peek_key(&mut self) -> Option<&[u8]> {
+        self.records.peek().map(|(k, _)| &k[..])
+    }
+
+    fn advance(&mut self) -> Result<Option<MergeRecord>> {
+        let record = self.records.next();
+        if let Some((key, _)) = &record
+            && let Some(next_key) = self.peek_key()
+            && next_key < &key[..]
+        {
+            bail!(PipelineError::CorruptArchive(format!(
+                "The records of '{}' are not in key order, although its index records them as sorted!",
+                self.source.name()
+            )));
+        }
+        Ok(record)
+    }
+
+    /// Take the value of the next record, if it has the given key.
+    fn next_value_of(&mut self, key: &[u8]) -> Result<Option<Box<[u8]>>> {
+        match self.peek_key() == Some(key) {
+            true => Ok(self.advance()?.map(|(_, v)| v)),
+            false => Ok(None),
+        }
+    }
+}
+
+fn merge_records<K: RecordKey>(
+    left: (&dyn FrameSource, OrderedReceiver<JoinedRecords<K>>),
+    right: (&dyn FrameSource, OrderedReceiver<JoinedRecords<K>>),
+    output_handle: File,
+) -> Result<(usize, usize)> {
+    let to_bytes = |(k, v): (K, Box<[u8]>)| (Box::from(k.key_bytes()), v);
+    let mut left = SortedRecords {
+        source: left.0,
+        records: left.1.flatten().map(to_bytes).peekable(),
+    };
+    let mut right = SortedRecords {
+        source: right.0,
+        records: right.1.flatten().map(to_bytes).peekable(),
+    };
+
+    let mut output_writer = BufWriter::new(output_handle);
+    let mut keys_matched: usize = 0;
+    let mut pairs_written: usize = 0;
+    let mut right_values: Vec<Box<[u8]>> = Vec::new();
+
+    while let (Some(left_key), Some(right_key)) = (left.peek_key(), right.peek_key()) {
+        match left_key.cmp(right_key) {
+            Ordering::Less => {
+                left.advance()?;
+            }
+            Ordering::Greater => {
+                right.advance()?;
+            }
+            Ordering::Equal => {
+                // Only the right records of the current key are held, to pair with each left one
+                let key: Box<[u8]> = Box::from(left_key);
+                right_values.clear();
+                while let Some(right_value) = right.next_value_of(&key)? {
+                    right_values.push(right_value);
+                }
+
+                while let Some(left_value) = left.next_value_of(&key)? {
+                    for right_value in &right_values {
+                        output_writer.write_all(&key)?;
+                        output_writer.write_all(b"\t")?;
+                        output_writer.write_all(&left_value)?;
+                        output_writer.write_all(b"\t")?;
+                        output_writer.write_all(right_value)?;
+                        output_writer.write_all(b"\n")?;
+                        pairs_written += 1;
+                    }
+                }
+                keys_matched += 1;
+            }
+        }
+    }
+
+    output_writer.flush()?;
+    Ok((keys_matched, pairs_written))
+}
+
+//endregion:
+
+/// Whether the index records the archive as compressed from key-sorted input, with the records
+/// of every frame in key order and no frame starting before the last key of the one ahead of it.
+pub(crate) fn is_key_sorted(idx_buffer: &[FrameMeta]) -> bool {
+    let mut last_key: Option<&str> = None;
+    for idx_frame in idx_buffer {
+        let Some(key_range) = &idx_frame.key_range else {
+            return false;
+        };
+        if last_key.is_some_and(|k| key_range.first.as_str() < k) {
+            return false;
+        }
+        last_key = Some(&key_range.last);
+    }
+    true
+}
+
+/// Read the query keys, one per line, normalised in the same way as the keys of the archive.
+pub fn load_query_keys<K: RecordKey>(
+    mut query_handle: impl Read,
+    parse_options: &ParseOptions,
+) -> Result<QueryKeys<K>> {
+    let mut query_content: Vec<u8> = Vec::new();
+    query_handle.read_to_end(&mut query_content)?;
+
+    let mut query_keys: QueryKeys<K> = QueryKeys::default();
+    for line_repr in query_content.split(|&b| b == b'\n') {
+        let mut key_bytes = trim_line_ending(line_repr);
+        if parse_options.strip_key_version {
+            key_bytes = strip_key_version(key_bytes);
+        }
+        if key_bytes.is_empty() {
+            continue;
+        }
+
+        match K::from_key_bytes(key_bytes, parse_options.strict_utf8) {
+            Some(k) => query_keys.insert(k),
+            None => bail!(PipelineError::Usage(format!(
+                "Query key '{}' is not valid UTF-8!",
+                String::from_utf8_lossy(key_bytes)
+            ))),
+        };
+    }
+
+    Ok(query_keys)
+}
+
+/// Write every record whose key is in the query set to the output, as 'key<TAB>value' lines in
+/// file order. Frames are decoded and filtered in parallel, so only the matching records of the
+/// frames in flight are held at once. Records which span frames in an unindexed archive are only
+/// complete once every frame is read, so they follow all other records. The query keys found in
+/// no record are written to the missing handle, in query order.
+pub fn join_zstd<K: RecordKey>(
+    source: &dyn FrameSource,
+    mut idx_buffer: Vec<FrameMeta>,
+    query_keys: &QueryKeys<K>,
+    output_handle: Option<File>,
+    missing_handle: Option<File>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<JoinReport> {
+    idx_buffer.sort_by_key(|f| f.order);
+
+    // Key ranges are of the keys as written, so cannot rule out frames once keys are rebuilt
+    let indexed_count = idx_buffer.len();
+    if parse_options.key_columns.is_empty()
+        && !parse_options.strip_key_version
+        && !parse_options.split_records
+    {
+        let mut query_bytes: Vec<Cow<[u8]>> = query_keys.iter().map(|k| k.key_bytes()).collect();
+        query_bytes.sort_unstable();
+        let sorted_keys: Vec<&[u8]> = query_bytes.iter().map(|k| k.as_ref()).collect();
+        idx_buffer.retain(|f| may_hold_any(f, &sorted_keys));
+    }
+    let frame_count = idx_buffer.len();
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_ledger = FrameLedger::default();
+    let (record_sender, record_receiver) =
+        ordered_channel::<JoinedRecords<K>>(0, JOIN_CHANNEL_BOUND);
+
+    std::thread::scope(|scope| {
+        let writer_handle = parse_options
+            .threads
+            .spawn_scoped(scope, "join-writer", || {
+                record_writer(record_receiver, output_handle, query_keys)
+            })?;
+
+        let bad_buffer: Result<Vec<Vec<BadRecord>>> = pool.install(|| {
+            idx_buffer
+                .into_par_iter()
+                .enumerate()
+                .map_with(
+                    record_sender.clone(),
+                    |record_sender, (sequence, idx_frame)| {
+                        // A frame which could not be read still takes its place in the sequence,
+                        // so that the frames after it are written
+                        let (mut records, bad_records): (JoinedRecords<K>, Vec<BadRecord>) =
+                            gather_zstd_frame(source, idx_frame, parse_options, &frame_ledger)
+                                .inspect_err(|_| record_sender.abandon())?
+                                .unwrap_or_default();
+                        records.retain(|(k, _)| query_keys.contains(k));
+
+                        if record_sender.send(sequence, records).is_err() {
+                            bail!("The output writer stopped before all records were written!");
+                        }
+                        Ok(bad_records)
+                    },
+                )
+                .collect()
+        });
+
+        let bad_buffer = bad_buffer.and_then(|mut bad_buffer| {
+            let stitched = frame_ledger.stitch::<K, Box<[u8]>>(parse_options)?;
+            for (sequence, (_, (mut records, stitched_bad))) in (frame_count..).zip(stitched) {
+                records.retain(|(k, _)| query_keys.contains(k));
+                if record_sender.send(sequence, records).is_err() {
+                    bail!("The output writer stopped before all records were written!");
+                }
+                bad_buffer.push(stitched_bad);
+            }
+            Ok(bad_buffer)
+        });
+
+        // Close the channel so that the writer finishes once the queue is drained
+        drop(record_sender);
+
+        // A writer failure also stops the decoders, so report it ahead of theirs
+        let (records_written, keys_found) = match writer_handle.join() {
+            Ok(r) => r?,
+            Err(_) => bail!("The output writer thread panicked!"),
+        };
+        let bad_buffer = bad_buffer?;
+
+        // Keys are only missing once every frame has been searched
+        if let Some(missing_handle) = missing_handle {
+            write_missing_keys(missing_handle, query_keys, &keys_found)?;
+        }
+
+        let keys_found = keys_found.iter().filter(|f| **f).count();
+        Ok(JoinReport {
+            query_keys: query_keys.len(),
+            keys_found,
+            keys_missing: query_keys.len() - keys_found,
+            records_written,
+            frames_skipped: indexed_count - frame_count,
+            summary: frame_ledger.into_summary(bad_buffer.into_iter().flatten().collect()),
+        })
+    })
+}
+
+/// Join two archives compressed from key-sorted input, writing a 'key<TAB>left<TAB>right' line
+/// for every pair of records which share a key. Both archives are streamed in frame order, each
+/// decoded with half of the threads, so only the frames in flight and the right records of the
+/// current key are held at once.
+pub fn merge_join_zstd<K: RecordKey>(
+    left_source: &dyn FrameSource,
+    mut left_idx: Vec<FrameMeta>,
+    right_source: &dyn FrameSource,
+    mut right_idx: Vec<FrameMeta>,
+    output_handle: File,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<MergeJoinReport> {
+    left_idx.sort_by_key(|f| f.order);
+    right_idx.sort_by_key(|f| f.order);
+    for (source, idx_buffer) in [(left_source, &left_idx), (right_source, &right_idx)] {
+        if !is_key_sorted(idx_buffer) {
+            bail!(PipelineError::Usage(format!(
+                "The index of '{}' does not record the archive as key-sorted!",
+                source.name()
+            )));
+        }
+    }
+
+    let side_threads = num_threads.div_ceil(2);
+    let (left_sender, left_receiver) = ordered_channel::<JoinedRecords<K>>(0, MERGE_CHANNEL_BOUND);
+    let (right_sender, right_receiver) =
+        ordered_channel::<JoinedRecords<K>>(0, MERGE_CHANNEL_BOUND);
+
+    std::thread::scope(|scope| {
+        let left_handle = parse_options
+            .threads
+            .spawn_scoped(scope, "merge-left", || {
+                stream_frames(
+                    left_source,
+                    left_idx,
+                    side_threads,
+                    parse_options,
+                    left_sender,
+                )
+            })?;
+        let right_handle = parse_options
+            .threads
+            .spawn_scoped(scope, "merge-right", || {
+                stream_frames(
+                    right_source,
+                    right_idx,
+                    side_threads,
+                    parse_options,
+                    right_sender,
+                )
+            })?;
+
+        // A failed archive ends its stream early, so report its error ahead of the merge's
+        let merged = merge_records(
+            (left_source, left_receiver),
+            (right_source, right_receiver),
+            output_handle,
+        );
+        let left_summary = match left_handle.join() {
+            Ok(r) => r?,
+            Err(_) => bail!("The decoder of '{}' panicked!", left_source.name()),
+        };
+        let right_summary = match right_handle.join() {
+            Ok(r) => r?,
+            Err(_) => bail!("The decoder of '{}' panicked!", right_source.name()),
+        };
+        let (keys_matched, pairs_written) = merged?;
+
+        Ok(MergeJoinReport {
+            keys_matched,
+            pairs_written,
+            left_summary,
+            right_summary,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+    use crate::ErrorClass;
+    use std::fs::OpenOptions;
+    use std::io::BufReader;
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn open_file_write(file_path: &str) -> File {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_load_query_keys() {
+        let query_content = "WP_1.1\r\nWP_2\n\nWP_1.2\n";
+        let parse_options = ParseOptions {
+            strip_key_version: true,
+            ..Default::default()
+        };
+
+        let exp_keys: Vec<String> = vec!["WP_1".into(), "WP_2".into()];
+        let obs_keys = load_query_keys::<String>(query_content.as_bytes(), &parse_options).unwrap();
+        assert_eq!(exp_keys, obs_keys.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_join_zstd() {
+        let output_file = "join_zstd.tsv";
+        let data_content = std::fs::read_to_string("test/data.txt").unwrap();
+        let data_lines: Vec<&str> = data_content.lines().collect();
+
+        // Query a key from each frame, in a different order to the file, and one which is absent
+        let query_lines = [data_lines[25], data_lines[0], data_lines[12]];
+        let query_content: String = query_lines
+            .iter()
+            .map(|l| format!("{}\n", l.split('\t').next().unwrap()))
+            .chain(["missing\n".to_string()])
+            .collect();
+        let query_keys =
+            load_query_keys::<Box<[u8]>>(query_content.as_bytes(), &ParseOptions::default())
+                .unwrap();
+
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let obs_result = join_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            &query_keys,
+            Some(open_file_write(output_file)),
+            None,
+            2,
+            &ParseOptions::default(),
+        );
+        let obs_content = std::fs::read_to_string(output_file).unwrap();
+        let _ = std::fs::remove_file(output_file);
+
+        let obs_report = obs_result.unwrap();
+        assert_eq!(4, obs_report.query_keys);
+        assert_eq!(3, obs_report.keys_found);
+        assert_eq!(1, obs_report.keys_missing);
+        assert_eq!(3, obs_report.records_written);
+
+        // Records are written in file order
+        let exp_content: String = [data_lines[0], data_lines[12], data_lines[25]]
+            .iter()
+            .map(|l| format!("{}\n", l))
+            .collect();
+        assert_eq!(exp_content, obs_content);
+    }
+
+    #[test]
+    fn test_join_zstd_missing() {
+        let missing_file = "join_zstd_missing.txt";
+        let query_content = "stale_2\nWP_413685322.1\nstale_1\n";
+        let query_keys =
+            load_query_keys::<Box<[u8]>>(query_content.as_bytes(), &ParseOptions::default())
+                .unwrap();
+
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let obs_result = join_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            &query_keys,
+            None,
+            Some(open_file_write(missing_file)),
+            2,
+            &ParseOptions::default(),
+        );
+        let obs_content = std::fs::read_to_string(missing_file).unwrap();
+        let _ = std::fs::remove_file(missing_file);
+
+        // Without an output, no records are written, but the keys found are still counted
+        let obs_report = obs_result.unwrap();
+        assert_eq!(0, obs_report.records_written);
+        assert_eq!(1, obs_report.keys_found);
+        assert_eq!(2, obs_report.keys_missing);
+
+        // Missing keys are written in query order
+        assert_eq!("stale_2\nstale_1\n", obs_content);
+    }
+
+    fn write_archive(stem: &str, content: &str, block_size: usize) -> (String, Vec<FrameMeta>) {
+        let zstd_file = format!("{}.zstd", stem);
+        let idx_file = format!("{}.zstd.idx", stem);
+
+        crate::compression::write_indexed_zstd(
+            content.as_bytes(),
+            open_file_write(&zstd_file),
+            BufWriter::new(open_file_write(&idx_file)),
+            block_size,
+            3,
+            &crate::CompressionOptions::default(),
+        )
+        .unwrap();
+        let idx_buffer = load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+        let _ = std::fs::remove_file(&idx_file);
+
+        (zstd_file, idx_buffer)
+    }
+
+    #[test]
+    fn test_is_key_sorted() {
+        let (zstd_file, idx_buffer) = write_archive("is_key_sorted", "a\t1\nb\t2\nb\t3\nc\t4\n", 8);
+        let _ = std::fs::remove_file(&zstd_file);
+        assert_eq!(2, idx_buffer.len());
+        assert!(is_key_sorted(&idx_buffer));
+
+        // Frames which are each sorted, but overlap, are not
+        let (zstd_file, idx_buffer) =
+            write_archive("is_key_sorted_overlap", "a\t1\nc\t2\nb\t3\nd\t4\n", 8);
+        let _ = std::fs::remove_file(&zstd_file);
+        assert!(!is_key_sorted(&idx_buffer));
+
+        // Nor are frames without a recorded key range
+        let idx_buffer = vec![FrameMeta::new(0, 151, 0)];
+        assert!(!is_key_sorted(&idx_buffer));
+    }
+
+    #[test]
+    fn test_join_zstd_key_ranges() {
+        let output_file = "join_zstd_key_ranges.tsv";
+        let (zstd_file, idx_buffer) = write_archive(
+            "join_zstd_key_ranges",
+            "a\t1\nb\t2\nc\t3\nd\t4\ne\t5\nf\t6\n",
+            8,
+        );
+        let query_keys =
+            load_query_keys::<Box<[u8]>>("d\nbb\n".as_bytes(), &ParseOptions::default()).unwrap();
+
+        let obs_result = join_zstd(
+            &FileSource::new(&zstd_file),
+            idx_buffer,
+            &query_keys,
+            Some(open_file_write(output_file)),
+            None,
+            2,
+            &ParseOptions::default(),
+        );
+        let obs_content = std::fs::read_to_string(output_file).unwrap();
+        let _ = std::fs::remove_file(output_file);
+        let _ = std::fs::remove_file(&zstd_file);
+
+        // Only the frame of 'c' and 'd' can hold either key
+        let obs_report = obs_result.unwrap();
+        assert_eq!(2, obs_report.frames_skipped);
+        assert_eq!(1, obs_report.keys_found);
+        assert_eq!("d\t4\n", obs_content);
+    }
+
+    #[test]
+    fn test_merge_join_zstd() {
+        let output_file = "merge_join_zstd.tsv";
+        let (left_file, left_idx) =
+            write_archive("merge_join_zstd_left", "a\t1\nb\t2\nb\t3\nd\t4\ne\t5\n", 8);
+        let (right_file, right_idx) = write_archive(
+            "merge_join_zstd_right",
+            "b\tx\nc\ty\ne\tz\ne\tw\nf\tv\n",
+            12,
+        );
+
+        let obs_result = merge_join_zstd::<Box<[u8]>>(
+            &FileSource::new(&left_file),
+            left_idx,
+            &FileSource::new(&right_file),
+            right_idx,
+            open_file_write(output_file),
+            3,
+            &ParseOptions::default(),
+        );
+        let obs_content = std::fs::read_to_string(output_file).unwrap();
+        for file_path in [output_file, &left_file, &right_file] {
+            let _ = std::fs::remove_file(file_path);
+        }
+
+        // Repeated keys on either side are paired with every record of the other
+        let obs_report = obs_result.unwrap();
+        assert_eq!(2, obs_report.keys_matched);
+        assert_eq!(4, obs_report.pairs_written);
+        assert_eq!(RunStatus::Complete, obs_report.run_status());
+        assert_eq!("b\t2\tx\nb\t3\tx\ne\t5\tz\ne\t5\tw\n", obs_content);
+    }
+
+    #[test]
+    fn test_merge_join_zstd_unsorted() {
+        let output_file = "merge_join_zstd_unsorted.tsv";
+        let (left_file, left_idx) = write_archive("merge_join_zstd_unsorted", "b\t1\na\t2\n", 8);
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let obs_result = merge_join_zstd::<Box<[u8]>>(
+            &FileSource::new(&left_file),
+            left_idx,
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            open_file_write(output_file),
+            2,
+            &ParseOptions::default(),
+        );
+        let _ = std::fs::remove_file(output_file);
+        let _ = std::fs::remove_file(&left_file);
+
+        let obs_error = obs_result.unwrap_err();
+        assert_eq!(ErrorClass::Usage, ErrorClass::of(&obs_error));
+    }
+}
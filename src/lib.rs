@@ -1,161 +1,3013 @@
-mod compression;
-mod decompression;
-use ahash::AHashMap;
-use anyhow::{bail, Result};
-use byte_unit::Byte;
-use clap::ValueEnum;
-use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter};
-
-#[derive(ValueEnum, Clone, Debug)]
-pub enum Mode {
-    DashMap,
-    Vector,
-    Merge,
-}
-
-pub enum EitherMap<K, V> {
-    Dash(DashMap<K, V>),
-    AHash(AHashMap<K, V>),
-}
-
-impl<K: Eq + std::hash::Hash, V> EitherMap<K, V> {
-    pub fn len(&self) -> usize {
-        match self {
-            EitherMap::Dash(m) => m.len(),
-            EitherMap::AHash(m) => m.len(),
-        }
-    }
-
-    pub fn into_ahash(self) -> Option<ahash::AHashMap<K, V>> {
-        match self {
-            EitherMap::AHash(m) => Some(m),
-            _ => None,
-        }
-    }
-
-    pub fn into_dash(self) -> Option<dashmap::DashMap<K, V>> {
-        match self {
-            EitherMap::Dash(m) => Some(m),
-            _ => None,
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct FrameMeta {
-    position: u64,
-    length: u64,
-    order: u64,
-}
-
-impl FrameMeta {
-    pub fn new(position: u64, length: u64, order: u64) -> FrameMeta {
-        FrameMeta {
-            position,
-            length,
-            order,
-        }
-    }
-
-    pub fn parse_length(&self) -> Result<usize> {
-        let u: usize = match self.length.try_into() {
-            Ok(u) => u,
-            Err(_) => bail!(
-                "The frame at position {} could not be parsed correctly!",
-                self.position,
-            ),
-        };
-        Ok(u)
-    }
-}
-
-fn parse_block_input(block_size: &str) -> Result<usize> {
-    let block_value: u64 = match Byte::parse_str(block_size, true) {
-        Ok(b) => b.as_u64(),
-        Err(_) => bail!("Unable to parse user-specified block size to numeric value!"),
-    };
-
-    let parsed_block: usize = match usize::try_from(block_value) {
-        Ok(u) => u,
-        Err(_) => bail!("Value to large for block specification!"),
-    };
-
-    Ok(parsed_block)
-}
-
-pub fn perform_compression(
-    input_file: &str,
-    output_file: &str,
-    index_file: &str,
-    block_size: &str,
-    zstd_level: i32,
-) -> Result<()> {
-    let block_usize: usize = parse_block_input(block_size)?;
-    let input_handle = OpenOptions::new().read(true).open(input_file).unwrap();
-
-    let output_handle = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(output_file)
-        .unwrap();
-
-    let index_handle = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(index_file)
-        .unwrap();
-
-    let input_reader: BufReader<File> = BufReader::new(input_handle);
-    let idx_writer: BufWriter<File> = BufWriter::new(index_handle);
-
-    let operation_result = compression::write_indexed_zstd(
-        input_reader,
-        output_handle,
-        idx_writer,
-        block_usize,
-        zstd_level,
-    );
-
-    if operation_result.is_ok() {
-        println!("Success!");
-        println!("  Input file:  {}", input_file);
-        println!("  Output file: {}", output_file);
-        println!("  Index file:  {}", index_file);
-    }
-    operation_result
-}
-
-pub fn perform_decompression(
-    zstd_file: &str,
-    idx_file: &str,
-    mode: &Mode,
-    num_threads: usize,
-) -> Result<()> {
-    let idx_handle = OpenOptions::new().read(true).open(idx_file)?;
-    let idx_reader: BufReader<File> = BufReader::new(idx_handle);
-
-    let operation_result = match mode {
-        Mode::DashMap => {
-            decompression::read_indexed_zstd_dashmap(zstd_file, idx_reader, num_threads)
-        }
-        Mode::Vector => decompression::read_indexed_zstd_vector(zstd_file, idx_reader, num_threads),
-        Mode::Merge => decompression::read_indexed_zstd_merge(zstd_file, idx_reader, num_threads),
-    };
-
-    match &operation_result {
-        Ok(map) => {
-            println!("Success!");
-            println!("  Input file:  {}", zstd_file);
-            println!("  Index file:  {}", idx_file);
-            println!("  Total records processed: {}", map.len());
-        }
-        Err(e) => bail!(e.to_string()),
-    }
-
-    Ok(())
-}
+mod allocator;
+mod archive;
+mod batch;
+mod bitmap;
+mod cardinality;
+mod compact;
+mod compression;
+mod config;
+mod cross_check;
+mod decompression;
+mod describe;
+mod distributed;
+mod doctor;
+mod error;
+mod events;
+mod explode;
+mod extract;
+mod follow;
+mod frame_cache;
+mod frequency;
+mod generate;
+mod index_csv;
+mod index_edit;
+mod join;
+mod membership;
+mod memory;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod mount;
+mod numa;
+mod packed;
+mod partition;
+mod profiling;
+mod provenance;
+mod quarantine;
+mod reorder;
+mod repack;
+mod resources;
+mod scan;
+mod serve;
+mod snapshot;
+mod sort;
+mod source;
+mod taxonomy;
+mod throttle;
+mod tune;
+mod unique;
+use ahash::AHashMap;
+use anyhow::{bail, Context, Result};
+use byte_unit::{Byte, UnitType};
+use clap::ValueEnum;
+use dashmap::DashMap;
+use flate2::read::MultiGzDecoder;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use allocator::{AllocatorStats, CountingAllocator, MemoryUsage};
+pub use archive::{FramePriority, IndexedArchive};
+pub use batch::ReadBatcher;
+pub use bitmap::RoaringBitmap;
+pub use cardinality::CardinalityReport;
+pub use compact::{CompactSummary, TOMBSTONE};
+pub use config::{Config, Profile, ProfileDefaults};
+pub use cross_check::{CrossCheckReport, DivergentKey};
+pub use decompression::{FrameRecords, FrameView, KeySpan};
+pub use describe::{ArchiveDescription, FORMAT_VERSION};
+pub use distributed::Collect;
+pub use doctor::{DoctorReport, PageCache, ReadSample, Recommendation};
+pub use error::{ErrorClass, PipelineError, RunStatus};
+pub use events::{Event, EventLog, LogFormat};
+pub use explode::{AssembleSummary, ExplodeSummary, Shard, ShardManifest};
+pub use extract::{Checkpoint, ExtractSummary};
+pub use follow::{FollowOptions, FollowReport, FollowUpdate};
+pub use frame_cache::FrameCache;
+pub use frequency::TopValuesReport;
+pub use generate::{GenerateOptions, KeyStyle};
+pub use index_csv::IndexFormat;
+pub use index_edit::IndexEdit;
+pub use join::{JoinReport, MergeJoinReport};
+pub use membership::{MembershipIndex, MembershipReport};
+pub use memory::MemoryEstimate;
+pub use numa::ThreadOptions;
+pub use packed::PackedKey;
+pub use profiling::{ByteCounts, Stage, StageProfiler, StageSummary, ThreadStages};
+pub use provenance::{IndexHeader, InputIdentity};
+pub use quarantine::{QuarantineReport, QuarantinedFrame};
+pub use repack::RepackSummary;
+pub use resources::ResourceLimits;
+pub use serve::ClientLookupSummary;
+pub use snapshot::SnapshotHeader;
+pub use sort::SortSummary;
+pub use source::{FileSource, FrameSource, HttpSource, MemorySource, MmapSource};
+pub use taxonomy::{enrich_map, TaxonInfo, Taxonomy};
+pub use throttle::ReadLimiter;
+pub use tune::{TuneReport, TuneTrial};
+pub use unique::{DuplicateKey, UniqueReport};
+
+#[derive(ValueEnum, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    DashMap,
+    Vector,
+    Merge,
+    Ordered,
+    Reference,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum Validation {
+    Report,
+    Reject,
+}
+
+/// Compression of the archive being read. Gzip files are split into frames along their member
+/// boundaries, so only multi-member files (such as BGZF) can be decoded in parallel.
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Codec {
+    #[default]
+    Zstd,
+    Gzip,
+}
+
+#[derive(ValueEnum, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum BadRecordPolicy {
+    #[default]
+    Zero,
+    Skip,
+    Error,
+    Collect,
+}
+
+/// Which value is kept when several records share a key. Records are inserted in file order in
+/// the vector and ordered modes, but in the order frames complete in the dash-map and merge
+/// modes, so there 'first' and 'last' only guarantee that one of the values is kept.
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum DuplicatePolicy {
+    #[default]
+    Last,
+    First,
+    Error,
+}
+
+/// Order in which frames are handed to the workers of a parallel decompression. The largest
+/// frames take longest to decode, so starting them first keeps one from landing at the end of the
+/// run while the other workers sit idle.
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FrameSchedule {
+    /// Frames in the order of the index
+    #[default]
+    FileOrder,
+    /// Frames in descending order of compressed length
+    LargestFirst,
+}
+
+/// Whether keys are held as text, as the raw bytes of the record without UTF-8 conversion, or
+/// packed into integers where they are accessions.
+#[derive(ValueEnum, Clone, Debug)]
+pub enum KeyType {
+    String,
+    Bytes,
+    /// Accessions packed into integers, with other keys held as bytes
+    Packed,
+}
+
+/// Whether values are parsed as integers (taxids), or kept as the text of the record.
+#[derive(ValueEnum, Clone, Debug, Default)]
+pub enum ValueType {
+    #[default]
+    Integer,
+    String,
+}
+
+/// Integer type in which values are held in the map. Taxids fit within 32 bits, which halves
+/// the memory taken by the values.
+#[derive(ValueEnum, Clone, Debug, Default)]
+pub enum ValueWidth {
+    #[value(name = "32")]
+    U32,
+    #[default]
+    #[value(name = "64")]
+    U64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ParseOptions {
+    pub bad_record: BadRecordPolicy,
+    pub strict_utf8: bool,
+    pub format: RecordFormat,
+    /// Columns of the line (counting from 1) joined into a composite key, in place of the key
+    /// column of the format. The format's key column is used when empty.
+    #[serde(default)]
+    pub key_columns: Vec<usize>,
+    /// Placed between the columns of a composite key.
+    #[serde(default)]
+    pub key_joiner: String,
+    /// Drop the version suffix from keys, so that 'WP_413685322.1' is read as 'WP_413685322'.
+    #[serde(default)]
+    pub strip_key_version: bool,
+    /// Which record is kept when several share a key.
+    #[serde(default)]
+    pub duplicate_keys: DuplicatePolicy,
+    /// Columns of the line (counting from 1) joined with tabs to form the value, in place of the
+    /// value column of the format. The format's value column is used when empty.
+    #[serde(default)]
+    pub value_columns: Vec<usize>,
+    pub codec: Codec,
+    /// Check each decoded frame against the checksum recorded in the index, when there is one.
+    pub verify_frames: bool,
+    /// Order in which frames are handed to the workers.
+    #[serde(default)]
+    pub schedule: FrameSchedule,
+    /// Hold back the partial lines at the ends of each frame and join them once all frames are
+    /// read, for archives whose frames do not end on a record boundary. Never sent to remote
+    /// workers.
+    #[serde(skip)]
+    pub split_records: bool,
+    /// Records per-frame stage timings when set. Never sent to remote workers.
+    #[serde(skip)]
+    pub profiler: Option<Arc<StageProfiler>>,
+    /// Caps the combined read rate of all workers when set. Never sent to remote workers.
+    #[serde(skip)]
+    pub read_limiter: Option<Arc<ReadLimiter>>,
+    /// Reads neighbouring frames together in one call when set. Never sent to remote workers.
+    #[serde(skip)]
+    pub read_batcher: Option<Arc<ReadBatcher>>,
+    /// Keeps frames decoded on request on disk between runs, for archives whose frames are read
+    /// lazily. Never sent to remote workers.
+    #[serde(skip)]
+    pub frame_cache: Option<Arc<FrameCache>>,
+    /// Spread worker threads across NUMA nodes, pinning each to its node's CPUs.
+    #[serde(skip)]
+    pub numa_placement: bool,
+    /// Fail, rather than warn, when the index looks to be stale against its archive. Never sent
+    /// to remote workers.
+    #[serde(skip)]
+    pub strict_index: bool,
+    pub retry: RetryPolicy,
+    /// Receives progress and failure events for '--log-format json'. Never sent to remote workers.
+    #[serde(skip)]
+    pub events: Option<Arc<EventLog>>,
+    /// Totals the bytes read and decompressed, for throughput reporting. Never sent to remote workers.
+    #[serde(skip)]
+    pub byte_counts: Option<Arc<ByteCounts>>,
+    /// Names and niceness of the threads started to read and parse frames. Never sent to remote
+    /// workers, which are given their own.
+    #[serde(skip)]
+    pub threads: ThreadOptions,
+}
+
+/// How often, and after how long, a frame read which failed with a transient IO error is retried.
+/// The delay doubles after each attempt.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            delay: std::time::Duration::from_millis(200),
+        }
+    }
+}
+
+impl ParseOptions {
+    pub(crate) fn record_stage(
+        &self,
+        stage: profiling::Stage,
+        order: u64,
+        start: Instant,
+        bytes: u64,
+    ) {
+        if let Some(profiler) = &self.profiler {
+            profiler.record(stage, order, start, bytes);
+        }
+    }
+
+    pub(crate) fn emit_event(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.emit(&event);
+        }
+    }
+
+    /// Warn as an event where an event log is kept, so that JSON logs stay one object per line,
+    /// and as a plain 'WARNING:' line otherwise.
+    pub(crate) fn warn(&self, message: String) {
+        match &self.events {
+            Some(events) => events.emit(&Event::Warning { message }),
+            None => eprintln!("WARNING: {}", message),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RepackOptions {
+    pub block_size: String,
+    pub zstd_level: i32,
+    pub frame_metadata: bool,
+    pub align: Option<u64>,
+    /// Overwrite existing output and index files.
+    pub force: bool,
+}
+
+/// How the records of a decompression are held in the resulting map.
+#[derive(Clone, Debug)]
+pub struct MapOptions {
+    pub mode: Mode,
+    pub key_type: KeyType,
+    pub value_type: ValueType,
+    pub value_width: ValueWidth,
+    /// Snapshot to start from, decoding only the frames indexed after it was saved
+    pub from_snapshot: Option<String>,
+    /// File to save the finished map to, for a later run to start from
+    pub save_snapshot: Option<String>,
+    /// Predict the peak memory of the map from the index before reading any frame
+    pub estimate_memory: bool,
+    /// Memory the predicted peak may not exceed, failing the run before it starts
+    pub memory_budget: Option<u64>,
+    /// Mode under which the map is built a second time, to compare against the first
+    pub cross_check: Option<Mode>,
+}
+
+/// An archive answered by the lookup service, under the namespace requests name it by.
+#[derive(Clone, Debug)]
+pub struct ServedArchive {
+    pub namespace: String,
+    pub zstd_file: String,
+    pub idx_file: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ServeOptions {
+    pub archives: Vec<ServedArchive>,
+    /// Key prefixes, and the namespace of the archive each is routed to
+    pub routes: Vec<(String, String)>,
+    /// Time between checks of the archive files for changes, if they are watched
+    pub watch_interval: Option<Duration>,
+    /// Whether clients may ask for the archives to be reloaded by posting to `/reload`
+    pub allow_reload: bool,
+    /// Connections answered at once, beyond which further connections wait to be accepted
+    pub max_connections: usize,
+    /// Listener on which to expose Prometheus metrics at `/metrics`, if any
+    pub metrics_listener: Option<TcpListener>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TaxonomyOptions {
+    pub taxdump_dir: String,
+    pub rank: Option<String>,
+}
+
+pub trait RecordKey: Eq + std::hash::Hash + Send + Sync + Sized {
+    /// Name of the key type recorded in map snapshots, which must never change once released.
+    const TAG: &'static str;
+
+    /// Build a key from the raw bytes of a record, returning None if the bytes are not
+    /// acceptable under the requested UTF-8 handling.
+    fn from_key_bytes(bytes: &[u8], strict_utf8: bool) -> Option<Self>;
+
+    /// The bytes of the key, as they are written back out to a text record. Keys held in another
+    /// form are written out afresh.
+    fn key_bytes(&self) -> Cow<'_, [u8]>;
+}
+
+impl RecordKey for String {
+    const TAG: &'static str = "string";
+
+    fn from_key_bytes(bytes: &[u8], strict_utf8: bool) -> Option<Self> {
+        if strict_utf8 {
+            String::from_utf8(bytes.to_vec()).ok()
+        } else {
+            Some(String::from_utf8_lossy(bytes).to_string())
+        }
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+impl RecordKey for Box<[u8]> {
+    const TAG: &'static str = "bytes";
+
+    fn from_key_bytes(bytes: &[u8], _strict_utf8: bool) -> Option<Self> {
+        Some(bytes.into())
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+pub trait RecordValue: Default + Send + Sync + Sized {
+    /// Name of the value type recorded in map snapshots, which must never change once released.
+    const TAG: &'static str;
+
+    /// Parse a value from the raw bytes of a record, failing with a description of the problem
+    /// when the bytes do not hold a value of this type.
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self>;
+
+    /// The bytes under which the value is stored in a map snapshot.
+    fn snapshot_bytes(&self) -> Cow<'_, [u8]>;
+
+    /// Rebuild a value from the bytes stored in a map snapshot.
+    fn from_snapshot_bytes(bytes: &[u8]) -> Result<Self>;
+}
+
+impl RecordValue for u64 {
+    const TAG: &'static str = "u64";
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self> {
+        decompression::parse_bytes_to_numeric(bytes)
+    }
+
+    fn snapshot_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.to_le_bytes().to_vec())
+    }
+
+    fn from_snapshot_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes.try_into() {
+            Ok(b) => Ok(u64::from_le_bytes(b)),
+            Err(_) => bail!(
+                "Snapshot value of {} bytes is not a 64-bit value.",
+                bytes.len()
+            ),
+        }
+    }
+}
+
+impl RecordValue for u32 {
+    const TAG: &'static str = "u32";
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self> {
+        // Parse at full width first, so that a value too large is reported rather than wrapped
+        let value = decompression::parse_bytes_to_numeric(bytes)?;
+        match u32::try_from(value) {
+            Ok(v) => Ok(v),
+            Err(_) => bail!("Value {} does not fit within 32 bits.", value),
+        }
+    }
+
+    fn snapshot_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.to_le_bytes().to_vec())
+    }
+
+    fn from_snapshot_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes.try_into() {
+            Ok(b) => Ok(u32::from_le_bytes(b)),
+            Err(_) => bail!(
+                "Snapshot value of {} bytes is not a 32-bit value.",
+                bytes.len()
+            ),
+        }
+    }
+}
+
+impl RecordValue for String {
+    const TAG: &'static str = "string";
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(String::from_utf8_lossy(bytes).to_string())
+    }
+
+    fn snapshot_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+
+    fn from_snapshot_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_value_bytes(bytes)
+    }
+}
+
+impl RecordValue for Box<[u8]> {
+    const TAG: &'static str = "bytes";
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bytes.into())
+    }
+
+    fn snapshot_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+
+    fn from_snapshot_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_value_bytes(bytes)
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum RecordFormat {
+    #[default]
+    Tsv,
+    Kraken2,
+    Centrifuge,
+}
+
+impl RecordFormat {
+    /// Whether the line is a column header, rather than a record.
+    pub fn is_header(&self, line: &[u8]) -> bool {
+        match self {
+            RecordFormat::Centrifuge => line.starts_with(b"readID\t"),
+            _ => false,
+        }
+    }
+
+    /// Locate the key and value bytes of a record, returning None if the line does not have
+    /// the columns expected for the format.
+    pub fn split_record<'a>(&self, line: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+        let mut fields = line.split(|&b| b == b'\t');
+
+        match self {
+            RecordFormat::Tsv => {
+                let tab_position = line.iter().position(|&b| b == b'\t')?;
+                Some((&line[..tab_position], &line[tab_position + 1..]))
+            }
+            RecordFormat::Kraken2 => {
+                // Classification status, read ID, taxid, length, LCA mappings
+                let key = fields.nth(1)?;
+                let value = fields.next()?;
+                Some((key, strip_taxon_name(value)))
+            }
+            RecordFormat::Centrifuge => {
+                // Read ID, sequence ID, taxid, scores...
+                let key = fields.next()?;
+                let value = fields.nth(1)?;
+                Some((key, value))
+            }
+        }
+    }
+}
+
+fn strip_taxon_name(value: &[u8]) -> &[u8] {
+    // kraken2 run with '--use-names' reports the taxid as 'Escherichia coli (taxid 562)'
+    let taxid_tag = b"(taxid ";
+
+    match value.strip_suffix(b")") {
+        Some(v) => match v.windows(taxid_tag.len()).rposition(|w| w == taxid_tag) {
+            Some(p) => &v[p + taxid_tag.len()..],
+            None => value,
+        },
+        None => value,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompressionOptions {
+    pub validation: Option<Validation>,
+    pub format: RecordFormat,
+    pub preserve_line_endings: bool,
+    /// Compression of the input file, which is decoded as it is read. Plain text when unset.
+    pub input_codec: Option<Codec>,
+    /// Write a skippable frame describing each data frame ahead of it.
+    pub frame_metadata: bool,
+    /// Pad between frames with skippable frames, so that each starts on a multiple of this many
+    /// bytes.
+    pub align: Option<u64>,
+    /// Check that the records are in key order, failing on the first which is not, so that the
+    /// index records the archive as key-sorted.
+    pub assume_sorted: bool,
+    /// Make any missing parent directories of the output and index files.
+    pub create_dirs: bool,
+    /// Overwrite existing output and index files.
+    pub force: bool,
+    /// Probe each block with a fast trial compression and compress blocks which barely compress
+    /// at a lower level, treating the compression level as the most to spend on a block. The
+    /// level of each frame is recorded in the index.
+    pub adaptive_level: bool,
+    /// Fold a block smaller than this many bytes, such as a short final block, into the frame
+    /// before it rather than writing it as a frame of its own.
+    pub min_frame_size: usize,
+    /// Read and compress the blocks of a plain input file on this many threads, after a first
+    /// pass which finds where each block ends.
+    pub read_threads: Option<usize>,
+    /// Names and niceness of the threads started to compress in parallel.
+    pub threads: ThreadOptions,
+    /// Write the index as a bare list of frames, leaving out the header which records the
+    /// version, command line, host, time and inputs of the run, for readers which predate it.
+    pub plain_index: bool,
+}
+
+pub enum EitherMap<K, V> {
+    Dash(DashMap<K, V>),
+    AHash(AHashMap<K, V>),
+    Ordered(IndexMap<K, V, ahash::RandomState>),
+}
+
+impl<K: Eq + std::hash::Hash, V> EitherMap<K, V> {
+    pub fn len(&self) -> usize {
+        match self {
+            EitherMap::Dash(m) => m.len(),
+            EitherMap::AHash(m) => m.len(),
+            EitherMap::Ordered(m) => m.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_ahash(self) -> Option<ahash::AHashMap<K, V>> {
+        match self {
+            EitherMap::AHash(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn into_ordered(self) -> Option<IndexMap<K, V, ahash::RandomState>> {
+        match self {
+            EitherMap::Ordered(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn into_dash(self) -> Option<dashmap::DashMap<K, V>> {
+        match self {
+            EitherMap::Dash(m) => Some(m),
+            _ => None,
+        }
+    }
+}
+
+impl<K: Eq + std::hash::Hash + 'static, V: 'static> IntoIterator for EitherMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = Box<dyn Iterator<Item = (K, V)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            EitherMap::Dash(m) => Box::new(m.into_iter()),
+            EitherMap::AHash(m) => Box::new(m.into_iter()),
+            EitherMap::Ordered(m) => Box::new(m.into_iter()),
+        }
+    }
+}
+
+/// First and last keys of a frame whose records are in key order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyRange {
+    pub first: String,
+    pub last: String,
+}
+
+/// Sizes of the records of a frame, so that the memory of a map can be judged from the index
+/// before any frame is read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordStats {
+    pub records: u64,
+    /// Total length of the record keys
+    pub key_bytes: u64,
+    /// Total length of the record values, as text
+    pub value_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FrameMeta {
+    position: u64,
+    length: u64,
+    order: u64,
+    /// xxh3 of the uncompressed frame content. Absent from indexes written before checksums
+    /// were recorded, and for frames located by scanning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<u64>,
+    /// Keys of the frame, recorded only when its records are in key order. Absent from indexes
+    /// written before key ranges were recorded, and for frames located by scanning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_range: Option<KeyRange>,
+    /// zstd level the frame was compressed at, recorded when the level is chosen per frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    level: Option<i32>,
+    /// Record counts and lengths, recorded at compression. Absent from indexes written before
+    /// they were recorded, and for frames located by scanning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    record_stats: Option<RecordStats>,
+    /// Length of the frame content once decoded, recorded at compression. Absent from indexes
+    /// written before it was recorded, and for frames located by scanning without metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uncompressed_length: Option<u64>,
+}
+
+impl FrameMeta {
+    pub fn new(position: u64, length: u64, order: u64) -> FrameMeta {
+        FrameMeta {
+            position,
+            length,
+            order,
+            checksum: None,
+            key_range: None,
+            level: None,
+            record_stats: None,
+            uncompressed_length: None,
+        }
+    }
+
+    pub fn with_checksum(mut self, checksum: u64) -> FrameMeta {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub fn with_key_range(mut self, key_range: Option<KeyRange>) -> FrameMeta {
+        self.key_range = key_range;
+        self
+    }
+
+    pub fn with_level(mut self, level: i32) -> FrameMeta {
+        self.level = Some(level);
+        self
+    }
+
+    pub fn with_record_stats(mut self, record_stats: RecordStats) -> FrameMeta {
+        self.record_stats = Some(record_stats);
+        self
+    }
+
+    pub fn with_uncompressed_length(mut self, uncompressed_length: u64) -> FrameMeta {
+        self.uncompressed_length = Some(uncompressed_length);
+        self
+    }
+
+    pub fn level(&self) -> Option<i32> {
+        self.level
+    }
+
+    pub fn record_stats(&self) -> Option<RecordStats> {
+        self.record_stats
+    }
+
+    pub fn uncompressed_length(&self) -> Option<u64> {
+        self.uncompressed_length
+    }
+
+    pub fn parse_length(&self) -> Result<usize> {
+        let u: usize = match self.length.try_into() {
+            Ok(u) => u,
+            Err(_) => bail!(
+                "The frame at position {} could not be parsed correctly!",
+                self.position,
+            ),
+        };
+        Ok(u)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BadRecord {
+    pub order: u64,
+    pub offset: usize,
+    pub line: String,
+}
+
+impl BadRecord {
+    pub fn new(order: u64, offset: usize, line_bytes: &[u8]) -> BadRecord {
+        BadRecord {
+            order,
+            offset,
+            line: String::from_utf8_lossy(line_bytes).to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for BadRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Malformed record '{}' in frame {} (byte offset {})",
+            self.line, self.order, self.offset
+        )
+    }
+}
+
+impl std::error::Error for BadRecord {}
+
+/// A frame which could not be read or decoded, so whose records are missing from the run.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FailedFrame {
+    pub order: u64,
+    /// Position of the frame in the archive
+    pub position: u64,
+    pub error: ErrorClass,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DecompressionSummary {
+    pub bad_records: Vec<BadRecord>,
+    pub failed_frames: Vec<FailedFrame>,
+    /// Malformed records loaded with a value of zero, under the 'zero' bad record policy
+    pub zeroed_records: usize,
+    /// Malformed records left out, under the 'skip' bad record policy
+    pub skipped_records: usize,
+}
+
+impl DecompressionSummary {
+    pub fn new(
+        mut bad_records: Vec<BadRecord>,
+        mut failed_frames: Vec<FailedFrame>,
+    ) -> DecompressionSummary {
+        // Frames complete in arbitrary order, so report the records as they appear in the file
+        bad_records.sort_by_key(|r| (r.order, r.offset));
+        failed_frames.sort_by_key(|f| f.order);
+        DecompressionSummary {
+            bad_records,
+            failed_frames,
+            ..Default::default()
+        }
+    }
+
+    /// Fold in the summary of another part of the same run, such as a task of a distributed run.
+    pub(crate) fn absorb(&mut self, other: DecompressionSummary) {
+        self.bad_records.extend(other.bad_records);
+        self.bad_records.sort_by_key(|r| (r.order, r.offset));
+        self.failed_frames.extend(other.failed_frames);
+        self.failed_frames.sort_by_key(|f| f.order);
+        self.zeroed_records += other.zeroed_records;
+        self.skipped_records += other.skipped_records;
+    }
+
+    /// Whether some content was lost or set aside, through unreadable frames or collected records.
+    pub fn is_partial(&self) -> bool {
+        !(self.bad_records.is_empty() && self.failed_frames.is_empty())
+    }
+}
+
+/// Outcome of a decompression, partition or distributed run, for the caller to report.
+#[derive(Debug, Default)]
+pub struct DecompressionReport {
+    /// Records in the resulting map, or records written to the partition files
+    pub records: usize,
+    /// Records which resolved to the requested taxonomic rank, if one was given
+    pub resolved: Option<usize>,
+    pub summary: DecompressionSummary,
+    /// Predicted peak memory of the map, when it was estimated
+    pub memory_estimate: Option<MemoryEstimate>,
+    /// Comparison with the map built under a second mode, when one was asked for
+    pub cross_check: Option<CrossCheckReport>,
+}
+
+impl DecompressionReport {
+    pub fn run_status(&self) -> RunStatus {
+        RunStatus::from_summary(&self.summary)
+    }
+}
+
+fn emit_summary(parse_options: &ParseOptions, summary: &DecompressionSummary, records: usize) {
+    parse_options.emit_event(Event::Summary {
+        status: RunStatus::from_summary(summary),
+        records: Some(records),
+        bytes_written: None,
+        bad_records: summary.bad_records.len(),
+        failed_frames: summary.failed_frames.clone(),
+        zeroed_records: summary.zeroed_records,
+        skipped_records: summary.skipped_records,
+        memory: MemoryUsage::current(),
+    });
+}
+
+fn parse_block_input(block_size: &str) -> Result<usize> {
+    let block_value: u64 = match Byte::parse_str(block_size, true) {
+        Ok(b) => b.as_u64(),
+        Err(_) => bail!(PipelineError::Usage(
+            "Unable to parse user-specified block size to numeric value!".into()
+        )),
+    };
+
+    let parsed_block: usize = match usize::try_from(block_value) {
+        Ok(u) => u,
+        Err(_) => bail!(PipelineError::Usage(
+            "Value to large for block specification!".into()
+        )),
+    };
+
+    Ok(parsed_block)
+}
+
+/// Memory available to new allocations, from the 'MemAvailable' line of /proc/meminfo. Unknown
+/// where that cannot be read, such as off Linux.
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    kib.checked_mul(1024)
+}
+
+/// Resolve a path to compare it with others, following links. A file which does not yet exist is
+/// resolved through its parent directory.
+fn resolve_path(file_path: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(file_path);
+    if let Ok(resolved) = std::fs::canonicalize(path) {
+        return resolved;
+    }
+
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => std::path::Path::new("."),
+    };
+    match (std::fs::canonicalize(parent), path.file_name()) {
+        (Ok(resolved), Some(file_name)) => resolved.join(file_name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Refuse output files which would overwrite an input, or each other, and existing output files
+/// unless overwriting is forced. Outputs are truncated when opened, so a mistyped path would
+/// otherwise destroy the file it names.
+fn check_output_paths(input_files: &[&str], output_files: &[&str], force: bool) -> Result<()> {
+    let input_paths: Vec<_> = input_files
+        .iter()
+        .filter(|f| **f != "-" && !f.starts_with("http://"))
+        .map(|f| (f, resolve_path(f)))
+        .collect();
+    let mut output_paths: Vec<(&str, std::path::PathBuf)> = Vec::new();
+
+    for output_file in output_files {
+        let output_path = resolve_path(output_file);
+
+        if let Some((input_file, _)) = input_paths.iter().find(|(_, p)| *p == output_path) {
+            bail!(PipelineError::Usage(format!(
+                "Output file '{}' is the input file '{}'!",
+                output_file, input_file
+            )));
+        }
+        if let Some((other_file, _)) = output_paths.iter().find(|(_, p)| *p == output_path) {
+            bail!(PipelineError::Usage(format!(
+                "Output files '{}' and '{}' are the same file!",
+                other_file, output_file
+            )));
+        }
+        if !force && output_path.exists() {
+            bail!(PipelineError::Usage(format!(
+                "Output file '{}' already exists! Pass '--force' to overwrite it.",
+                output_file
+            )));
+        }
+        output_paths.push((output_file, output_path));
+    }
+    Ok(())
+}
+
+fn create_parent_dirs(target_file: &str) -> Result<()> {
+    let Some(parent) = std::path::Path::new(target_file).parent() else {
+        return Ok(());
+    };
+    if parent.as_os_str().is_empty() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("Unable to create directory '{}'", parent.display()))
+}
+
+/// Reject a block size of zero, or one which would need more memory than is available once
+/// every thread holds a block.
+fn check_block_size(block_size: usize, num_threads: usize) -> Result<()> {
+    if block_size == 0 {
+        bail!(PipelineError::Usage(
+            "The block size must be greater than zero!".into()
+        ));
+    }
+
+    let required_bytes = (block_size as u64).saturating_mul(num_threads.max(1) as u64);
+    if let Some(available_bytes) = available_memory()
+        && required_bytes > available_bytes
+    {
+        bail!(PipelineError::Usage(format!(
+            "A block size of {} bytes on each of {} threads needs more than the {} bytes of memory available! Use a smaller '--block-size' or fewer threads.",
+            block_size,
+            num_threads.max(1),
+            available_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// Compress the input file into an indexed archive, returning the index of the frames written.
+/// Several input files are compressed concurrently as shards of one archive, in the order given.
+pub fn perform_compression(
+    input_files: &[&str],
+    output_file: &str,
+    index_file: &str,
+    block_size: &str,
+    zstd_level: i32,
+    compression_options: &CompressionOptions,
+) -> Result<Vec<FrameMeta>> {
+    let block_usize: usize = parse_block_input(block_size)?;
+    check_block_size(block_usize, 1)?;
+    check_output_paths(
+        input_files,
+        &[output_file, index_file],
+        compression_options.force,
+    )?;
+    let [input_file] = input_files else {
+        return compress_shards(
+            input_files,
+            output_file,
+            index_file,
+            block_usize,
+            zstd_level,
+            compression_options,
+        );
+    };
+
+    if compression_options.create_dirs {
+        for target_file in [output_file, index_file] {
+            create_parent_dirs(target_file)?;
+        }
+    }
+
+    let input_handle = OpenOptions::new()
+        .read(true)
+        .open(input_file)
+        .with_context(|| format!("Unable to open input file '{}'", input_file))?;
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let index_handle = File::create(index_file)
+        .with_context(|| format!("Unable to create index file '{}'", index_file))?;
+    let idx_writer: BufWriter<File> = BufWriter::new(index_handle);
+    let index_header = index_header_for(input_files, compression_options)?;
+
+    if let Some(read_threads) = compression_options.read_threads {
+        let mut output_handle = output_handle;
+        let idx_records = compression::compress_files_parallel(
+            std::slice::from_ref(&input_handle),
+            &mut output_handle,
+            block_usize,
+            zstd_level,
+            read_threads,
+            compression_options,
+        )?;
+        compression::write_index(idx_writer, index_header.as_ref(), &idx_records)?;
+        return Ok(idx_records);
+    }
+
+    // Compressed input is streamed through its decoder, so it is never written out in full
+    let input_reader: Box<dyn BufRead> = match compression_options.input_codec {
+        None => Box::new(BufReader::new(input_handle)),
+        Some(Codec::Zstd) => Box::new(BufReader::new(zstd::stream::read::Decoder::new(
+            input_handle,
+        )?)),
+        Some(Codec::Gzip) => Box::new(BufReader::new(MultiGzDecoder::new(input_handle))),
+    };
+
+    let mut output_handle = output_handle;
+    let idx_records = compression::compress_frames(
+        input_reader,
+        &mut output_handle,
+        block_usize,
+        zstd_level,
+        compression_options,
+    )?;
+    compression::write_index(idx_writer, index_header.as_ref(), &idx_records)?;
+    Ok(idx_records)
+}
+
+/// Header of the index of a compression run, unless a plain index was asked for.
+fn index_header_for(
+    input_files: &[&str],
+    compression_options: &CompressionOptions,
+) -> Result<Option<IndexHeader>> {
+    match compression_options.plain_index {
+        true => Ok(None),
+        false => provenance::capture_header(input_files).map(Some),
+    }
+}
+
+/// Compress input shards concurrently into one archive, on `--read-threads` threads or one
+/// thread per shard.
+fn compress_shards(
+    input_files: &[&str],
+    output_file: &str,
+    index_file: &str,
+    block_size: usize,
+    zstd_level: i32,
+    compression_options: &CompressionOptions,
+) -> Result<Vec<FrameMeta>> {
+    if input_files.is_empty() {
+        bail!(PipelineError::Usage(
+            "At least one input file is needed to compress!".into()
+        ));
+    }
+    if compression_options.create_dirs {
+        for target_file in [output_file, index_file] {
+            create_parent_dirs(target_file)?;
+        }
+    }
+
+    let input_handles: Vec<File> = input_files
+        .iter()
+        .map(|f| File::open(f).with_context(|| format!("Unable to open input file '{}'", f)))
+        .collect::<Result<_>>()?;
+    let mut output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let index_handle = File::create(index_file)
+        .with_context(|| format!("Unable to create index file '{}'", index_file))?;
+    let index_header = index_header_for(input_files, compression_options)?;
+
+    let idx_records = compression::compress_files_parallel(
+        &input_handles,
+        &mut output_handle,
+        block_size,
+        zstd_level,
+        compression_options
+            .read_threads
+            .unwrap_or(input_files.len()),
+        compression_options,
+    )?;
+    compression::write_index(
+        BufWriter::new(index_handle),
+        index_header.as_ref(),
+        &idx_records,
+    )?;
+    Ok(idx_records)
+}
+
+/// Write synthetic KEY<TAB>VALUE records for benchmarking, shaped by the generate options. Given
+/// an index file, the records are compressed straight into an indexed archive, which is recorded
+/// as key-sorted when the records are sorted.
+pub fn perform_generate(
+    output_file: &str,
+    index_file: Option<&str>,
+    block_size: &str,
+    zstd_level: i32,
+    generate_options: &GenerateOptions,
+    force: bool,
+) -> Result<()> {
+    let synthetic_records = generate::SyntheticRecords::new(generate_options)?;
+    let mut output_files = vec![output_file];
+    output_files.extend(index_file);
+    check_output_paths(&[], &output_files, force)?;
+
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let Some(index_file) = index_file else {
+        let mut output_writer = BufWriter::new(output_handle);
+        std::io::copy(&mut BufReader::new(synthetic_records), &mut output_writer)?;
+        output_writer.flush()?;
+        return Ok(());
+    };
+
+    let block_usize: usize = parse_block_input(block_size)?;
+    check_block_size(block_usize, 1)?;
+    let index_handle = File::create(index_file)
+        .with_context(|| format!("Unable to create index file '{}'", index_file))?;
+    compression::write_indexed_zstd(
+        BufReader::new(synthetic_records),
+        output_handle,
+        BufWriter::new(index_handle),
+        block_usize,
+        zstd_level,
+        &CompressionOptions {
+            assume_sorted: generate_options.sorted,
+            force,
+            ..Default::default()
+        },
+    )
+    .map(|_| ())
+}
+
+/// Compress a sample from the start of the input file at each block size and level, measuring
+/// the compression ratio and, given a number of decoding threads, the parallel decode rate on
+/// this machine, and recommend the settings to compress the whole file with.
+pub fn perform_tune(
+    input_file: &str,
+    sample_size: u64,
+    block_sizes: &[usize],
+    levels: &[i32],
+    decode_threads: Option<usize>,
+) -> Result<TuneReport> {
+    let input_handle = File::open(input_file)
+        .with_context(|| format!("Unable to open input file '{}'", input_file))?;
+    let input_bytes = input_handle.metadata()?.len();
+    tune::tune_compression(
+        BufReader::new(input_handle),
+        input_bytes,
+        sample_size,
+        block_sizes,
+        levels,
+        decode_threads,
+    )
+}
+
+/// Describe the layout of an archive for other implementations of the format: its codec, frames,
+/// checksums and the optional features it uses. The codec is told from the first bytes of the
+/// archive, and without an index the frames are found by scanning it.
+pub fn perform_describe(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+) -> Result<ArchiveDescription> {
+    let source = open_source(zstd_file)?;
+    let leading = source.read_at(0, scan::GZIP_MAGIC.len())?;
+    let codec = match leading.starts_with(&scan::GZIP_MAGIC) {
+        true => Codec::Gzip,
+        false => Codec::Zstd,
+    };
+    let parse_options = ParseOptions {
+        codec: codec.clone(),
+        ..ParseOptions::default()
+    };
+    let (index_header, idx_buffer, _) = load_headed_index(zstd_file, idx_file, &parse_options)?;
+
+    let modified = |file: &str| {
+        std::fs::metadata(file)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+    };
+    Ok(ArchiveDescription {
+        index: idx_file.map(String::from),
+        archive_bytes: std::fs::metadata(zstd_file).ok().map(|m| m.len()),
+        archive_modified: modified(zstd_file),
+        index_modified: idx_file.and_then(modified),
+        created_by: index_header,
+        ..describe::describe_archive(source.as_ref(), &idx_buffer, &codec, num_threads)
+    })
+}
+
+/// Report the resources of the machine and, given an archive, the rate at which it can be read,
+/// along with the flags these suggest. Reads are sampled from local files only.
+pub fn perform_doctor(zstd_file: Option<&str>, sample_size: u64) -> Result<DoctorReport> {
+    match zstd_file {
+        Some(zstd_file) => {
+            if zstd_file.starts_with("http://") {
+                bail!(PipelineError::Usage(format!(
+                    "Read rates can only be sampled from local files, not '{}'!",
+                    zstd_file
+                )));
+            }
+            let file_bytes = std::fs::metadata(zstd_file)
+                .with_context(|| format!("Unable to open input file '{}'", zstd_file))?
+                .len();
+            let source = FileSource::new(zstd_file);
+            doctor::diagnose(Some((&source, file_bytes)), sample_size)
+        }
+        None => doctor::diagnose(None, sample_size),
+    }
+}
+
+/// Compress the input into any seekable writer, such as a `Cursor<Vec<u8>>`, returning the
+/// frame index rather than writing it out. Together with `decompress_source` this lets an
+/// archive be built and read without touching the filesystem.
+pub fn compress_to<R: BufRead, W: Write + Seek>(
+    input_reader: R,
+    zstd_writer: &mut W,
+    block_size: usize,
+    zstd_level: i32,
+    compression_options: &CompressionOptions,
+) -> Result<Vec<FrameMeta>> {
+    check_block_size(block_size, 1)?;
+    compression::compress_frames(
+        input_reader,
+        zstd_writer,
+        block_size,
+        zstd_level,
+        compression_options,
+    )
+}
+
+/// Compress a byte slice into an archive held in memory, returning its payload and frame index.
+/// Default compression options are used, so the same input, block size and level always give
+/// the same archive.
+pub fn compress_bytes(
+    input: &[u8],
+    block_size: usize,
+    zstd_level: i32,
+) -> Result<(Vec<u8>, Vec<FrameMeta>)> {
+    check_block_size(block_size, 1)?;
+    let mut zstd_writer = std::io::Cursor::new(Vec::new());
+    let idx_buffer = compression::compress_frames(
+        input,
+        &mut zstd_writer,
+        block_size,
+        zstd_level,
+        &CompressionOptions::default(),
+    )?;
+    Ok((zstd_writer.into_inner(), idx_buffer))
+}
+
+/// Decompress an archive held in memory back to its content, in frame order. The index is
+/// checked against the payload first, so that frames which overlap or run past its end are
+/// reported as a corrupt archive, and every frame is checked against its checksum.
+pub fn decompress_bytes(payload: &[u8], idx_buffer: &[FrameMeta]) -> Result<Vec<u8>> {
+    decompression::check_frame_index(idx_buffer, payload.len() as u64)?;
+    let parse_options = ParseOptions {
+        verify_frames: true,
+        ..Default::default()
+    };
+
+    let mut idx_buffer = idx_buffer.to_vec();
+    idx_buffer.sort_by_key(|f| f.order);
+
+    let mut content: Vec<u8> = Vec::new();
+    for frame_meta in &idx_buffer {
+        content.extend(decompression::decode_frame(
+            &payload,
+            frame_meta,
+            &parse_options,
+        )?);
+    }
+    Ok(content)
+}
+
+/// Read the frame index, or without one, find the frames by scanning the file itself. For gzip
+/// files the index is an optional '.gzi' member index. Frames are only known to end on a record
+/// boundary when they come from a frame index, so otherwise the parse options are adjusted to
+/// join records which span frames. Any read batcher is planned against the frames found.
+fn load_index(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    parse_options: &ParseOptions,
+) -> Result<(Vec<FrameMeta>, ParseOptions)> {
+    load_headed_index(zstd_file, idx_file, parse_options)
+        .map(|(_, idx_buffer, parse_options)| (idx_buffer, parse_options))
+}
+
+/// As `load_index`, also returning the header of the frame index where it has one.
+fn load_headed_index(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    parse_options: &ParseOptions,
+) -> Result<(Option<IndexHeader>, Vec<FrameMeta>, ParseOptions)> {
+    // A remote archive is only read in frames, so cannot be scanned for them
+    if idx_file.is_none() && zstd_file.starts_with("http://") {
+        bail!(PipelineError::Usage(format!(
+            "Archive '{}' is read over HTTP, so needs a frame index!",
+            zstd_file
+        )));
+    }
+
+    let mut parse_options = parse_options.clone();
+    parse_options.split_records = idx_file.is_none() || parse_options.codec == Codec::Gzip;
+
+    let (index_header, idx_buffer) = match (&parse_options.codec, idx_file) {
+        (Codec::Zstd, Some(idx_file)) => {
+            let (index_header, idx_buffer) = read_index_file(idx_file)?;
+
+            // A bad index fails here with the frame at fault, rather than as a read error part
+            // way through the run. A remote archive is measured with a one-byte range request.
+            let archive_length = match zstd_file.starts_with("http://") {
+                true => HttpSource::new(zstd_file)?.length()?,
+                false => std::fs::metadata(zstd_file)?.len(),
+            };
+            decompression::check_frame_index(&idx_buffer, archive_length).map_err(|e| {
+                e.context(format!(
+                    "Index '{}' does not match '{}'",
+                    idx_file, zstd_file
+                ))
+            })?;
+            check_index_freshness(
+                zstd_file,
+                idx_file,
+                &idx_buffer,
+                archive_length,
+                &parse_options,
+            )?;
+            (index_header, idx_buffer)
+        }
+        (Codec::Zstd, None) => (None, scan::scan_zstd_file(zstd_file)?),
+        (Codec::Gzip, gzi_file) => (None, scan::scan_gzip_file(zstd_file, gzi_file)?),
+    };
+
+    if let Some(read_batcher) = &parse_options.read_batcher {
+        parse_options.read_batcher = Some(Arc::new(read_batcher.with_plan(&idx_buffer)));
+    }
+    Ok((index_header, idx_buffer, parse_options))
+}
+
+/// Warn when the index looks to have been written for an earlier version of the archive, as when
+/// the archive is regenerated but the index is not. Under strict checking this fails instead.
+fn check_index_freshness(
+    zstd_file: &str,
+    idx_file: &str,
+    idx_buffer: &[FrameMeta],
+    archive_length: u64,
+    parse_options: &ParseOptions,
+) -> Result<()> {
+    let indexed_length = decompression::indexed_length(idx_buffer);
+    let trailing_magic = match indexed_length < archive_length {
+        true => open_source(zstd_file)?
+            .read_at(indexed_length, 4)
+            .ok()
+            .map(|magic| u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]])),
+        false => None,
+    };
+
+    // Only files on disk have timestamps to compare
+    let modified = |file: &str| match file == "-" || file.contains("://") {
+        true => None,
+        false => std::fs::metadata(file).and_then(|m| m.modified()).ok(),
+    };
+    let archive_is_newer = match (modified(zstd_file), modified(idx_file)) {
+        (Some(a), Some(i)) => a > i,
+        _ => false,
+    };
+
+    let signs = decompression::stale_index_signs(
+        idx_buffer,
+        archive_length,
+        trailing_magic,
+        archive_is_newer,
+    );
+    if signs.is_empty() {
+        return Ok(());
+    }
+    if parse_options.strict_index {
+        bail!(PipelineError::CorruptArchive(format!(
+            "Index '{}' looks to be stale for '{}', as {}!",
+            idx_file,
+            zstd_file,
+            signs.join(" and ")
+        )));
+    }
+
+    if parse_options.events.is_some() {
+        parse_options.warn(format!(
+            "Index '{}' may be stale for '{}', as {}. Write the index again if the archive has changed since it was indexed.",
+            idx_file,
+            zstd_file,
+            signs.join(" and ")
+        ));
+        return Ok(());
+    }
+    eprintln!(
+        "WARNING: index '{}' may be stale for '{}'",
+        idx_file, zstd_file
+    );
+    for sign in &signs {
+        eprintln!("WARNING:   {}", sign);
+    }
+    eprintln!("WARNING: write the index again if the archive has changed since it was indexed");
+    Ok(())
+}
+
+/// Read a frame index from a file, from stdin given '-', or from a plain 'http://' URL, so that
+/// an index can be handed over without writing it out first.
+pub(crate) fn read_frame_index(idx_file: &str) -> Result<Vec<FrameMeta>> {
+    read_index_file(idx_file).map(|(_, idx_buffer)| idx_buffer)
+}
+
+/// Read a frame index along with any header recording how its archive was made.
+fn read_index_file(idx_file: &str) -> Result<(Option<IndexHeader>, Vec<FrameMeta>)> {
+    if idx_file == "-" {
+        return decompression::load_index_file(std::io::stdin().lock());
+    }
+    if idx_file.starts_with("http://") {
+        let idx_bytes = HttpSource::new(idx_file)?.read_all()?;
+        return decompression::load_index_file(&idx_bytes[..]);
+    }
+    if idx_file.contains("://") {
+        bail!(PipelineError::Usage(format!(
+            "Index '{}' cannot be fetched, as only plain 'http://' URLs are supported. Fetch an 'https://' index separately and pipe it in with '-'!",
+            idx_file
+        )));
+    }
+
+    let idx_handle = OpenOptions::new()
+        .read(true)
+        .open(idx_file)
+        .with_context(|| format!("Unable to open index file '{}'", idx_file))?;
+    decompression::load_index_file(BufReader::new(idx_handle))
+}
+
+/// Open the archive for reading frames. Plain 'http://' URLs are read with range requests, and
+/// anything else as a local file.
+pub(crate) fn open_source(zstd_file: &str) -> Result<Box<dyn FrameSource>> {
+    match zstd_file.starts_with("http://") {
+        true => Ok(Box::new(HttpSource::new(zstd_file)?)),
+        false => Ok(Box::new(FileSource::new(zstd_file))),
+    }
+}
+
+pub(crate) fn decompress_with_keys<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    mode: &Mode,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary)> {
+    match mode {
+        Mode::DashMap => {
+            decompression::read_indexed_zstd_dashmap(source, idx_buffer, num_threads, parse_options)
+        }
+        Mode::Vector => {
+            decompression::read_indexed_zstd_vector(source, idx_buffer, num_threads, parse_options)
+        }
+        Mode::Merge => {
+            decompression::read_indexed_zstd_merge(source, idx_buffer, num_threads, parse_options)
+        }
+        Mode::Ordered => {
+            decompression::read_indexed_zstd_ordered(source, idx_buffer, num_threads, parse_options)
+        }
+        Mode::Reference => {
+            decompression::read_indexed_zstd_reference(source, idx_buffer, parse_options)
+        }
+    }
+}
+
+/// Decompress an archive from any frame source into a map, under the given index. The index must
+/// describe whole-record frames, as those written by a compression do.
+pub fn decompress_source<K: RecordKey, V: RecordValue>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    mode: &Mode,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary)> {
+    decompress_with_keys(source, idx_buffer, mode, num_threads, parse_options)
+}
+
+/// Predict the peak memory of the map from the index, failing when it exceeds the budget so that
+/// nothing is allocated for a run which cannot finish. An index without record counts cannot be
+/// judged, so is let through with a warning, written as an event where an event log is kept.
+fn check_memory_estimate(
+    idx_buffer: &[FrameMeta],
+    map_options: &MapOptions,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<Option<MemoryEstimate>> {
+    let Some(memory_estimate) = memory::estimate_memory(idx_buffer, map_options, num_threads)
+    else {
+        parse_options.warn(
+            "The index records no record counts, so the memory of the map cannot be estimated. Compress the archive again to record them.".into(),
+        );
+        return Ok(None);
+    };
+
+    let peak_bytes = memory_estimate.peak_bytes(&map_options.mode);
+    if let Some(memory_budget) = map_options.memory_budget
+        && peak_bytes > memory_budget
+    {
+        let mode_name = |mode: &Mode| {
+            mode.to_possible_value()
+                .map_or(String::new(), |v| v.get_name().to_string())
+        };
+        let format_bytes = |bytes: u64| {
+            format!(
+                "{:.2}",
+                Byte::from_u64(bytes).get_appropriate_unit(UnitType::Binary)
+            )
+        };
+        let fitting_mode = memory_estimate
+            .peaks
+            .iter()
+            .filter(|(_, peak)| *peak <= memory_budget)
+            .min_by_key(|(_, peak)| *peak);
+        let advice = match fitting_mode {
+            Some((mode, peak)) => format!(
+                "though mode '{}' would fit at {}",
+                mode_name(mode),
+                format_bytes(*peak)
+            ),
+            None => String::from(
+                "as would every mode, so a narrower value width, packed keys or fewer threads are needed",
+            ),
+        };
+        bail!(PipelineError::Usage(format!(
+            "The '{}' map of {} records is estimated to peak at {}, over the memory budget of {}, {}!",
+            mode_name(&map_options.mode),
+            memory_estimate.records,
+            format_bytes(peak_bytes),
+            format_bytes(memory_budget),
+            advice
+        )));
+    }
+    Ok(Some(memory_estimate))
+}
+
+fn enrich_and_count<K: RecordKey + 'static, V: RecordValue + Copy + Into<u64> + 'static>(
+    record_map: EitherMap<K, V>,
+    taxonomy: Option<&Taxonomy>,
+    rank: Option<&str>,
+) -> (usize, Option<usize>) {
+    // Only the count is reported, so the enriched map is never built
+    let resolved = taxonomy
+        .zip(rank)
+        .map(|(t, r)| taxonomy::count_resolved(&record_map, t, r));
+    (record_map.len(), resolved)
+}
+
+/// Build the map of an archive, starting from a snapshot of it when one is given, and save the
+/// finished map to a snapshot when asked. Returns the map along with how far through the archive
+/// it was built. A snapshot is only resumed against an index listing the same frames up to its
+/// watermark, so that the records of another archive, or of this one before it was rewritten, are
+/// never carried over.
+fn gather_map<K: RecordKey + 'static, V: RecordValue + 'static>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+) -> Result<(EitherMap<K, V>, DecompressionSummary, snapshot::MapPosition)> {
+    let index_copy = idx_buffer.clone();
+    let (record_map, watermark, summary) = match &map_options.from_snapshot {
+        Some(snapshot_file) => {
+            let (mut record_map, header) =
+                snapshot::load_snapshot::<K, V>(snapshot_file, &map_options.mode)?;
+            if snapshot::MapPosition::new(&idx_buffer, header.watermark).index_key
+                != header.index_key
+            {
+                bail!(PipelineError::Usage(format!(
+                    "Snapshot '{}' was saved from '{}', whose frames differ from those indexed for '{}', so cannot be resumed from!",
+                    snapshot_file,
+                    header.archive,
+                    source.name()
+                )));
+            }
+            let (watermark, summary) = follow::update_map(
+                source,
+                idx_buffer,
+                &mut record_map,
+                header.watermark,
+                num_threads,
+                parse_options,
+            )?;
+            (record_map, watermark, summary)
+        }
+        None => {
+            let watermark = follow::frames_after(idx_buffer.clone(), None)
+                .last()
+                .map(|f| f.order);
+            let (record_map, summary) = decompress_with_keys::<K, V>(
+                source,
+                idx_buffer,
+                &map_options.mode,
+                num_threads,
+                parse_options,
+            )?;
+            (record_map, watermark, summary)
+        }
+    };
+
+    let position = snapshot::MapPosition::new(&index_copy, watermark);
+    if let Some(snapshot_file) = &map_options.save_snapshot {
+        // A map missing the records of failed frames would hide them from every warm start
+        if summary.failed_frames.is_empty() {
+            snapshot::save_snapshot(&record_map, source.name(), &position, snapshot_file)?;
+        } else {
+            eprintln!(
+                "WARNING: Snapshot '{}' was not saved, as {} frames failed to decode.",
+                snapshot_file,
+                summary.failed_frames.len()
+            );
+        }
+    }
+    Ok((record_map, summary, position))
+}
+
+/// Build the map of an archive and, when asked, build it again under a second mode and compare
+/// the two.
+fn gather_checked_map<K, V>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+) -> Result<(
+    EitherMap<K, V>,
+    DecompressionSummary,
+    Option<CrossCheckReport>,
+)>
+where
+    K: RecordKey + 'static,
+    V: RecordValue + Clone + PartialEq + std::fmt::Display + 'static,
+{
+    let check_buffer = map_options.cross_check.as_ref().map(|_| idx_buffer.clone());
+    let (record_map, summary, _) =
+        gather_map::<K, V>(source, idx_buffer, num_threads, map_options, parse_options)?;
+
+    let cross_check = match (&map_options.cross_check, check_buffer) {
+        (Some(other_mode), Some(check_buffer)) => Some(cross_check::cross_check(
+            source,
+            check_buffer,
+            &record_map,
+            (&map_options.mode, other_mode),
+            num_threads,
+            parse_options,
+        )?),
+        _ => None,
+    };
+    Ok((record_map, summary, cross_check))
+}
+
+type MapCounts = (
+    (usize, Option<usize>),
+    DecompressionSummary,
+    Option<CrossCheckReport>,
+);
+
+/// Build the map with values of the requested type, reporting its size and, given a taxonomy
+/// and rank, the number of values resolved to that rank.
+fn decompress_and_count<K: RecordKey + 'static>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+    taxonomy: Option<&Taxonomy>,
+    rank: Option<&str>,
+) -> Result<MapCounts> {
+    match (&map_options.value_type, &map_options.value_width) {
+        (ValueType::Integer, ValueWidth::U64) => gather_checked_map::<K, u64>(
+            source,
+            idx_buffer,
+            num_threads,
+            map_options,
+            parse_options,
+        )
+        .map(|(map, summary, check)| (enrich_and_count(map, taxonomy, rank), summary, check)),
+        (ValueType::Integer, ValueWidth::U32) => gather_checked_map::<K, u32>(
+            source,
+            idx_buffer,
+            num_threads,
+            map_options,
+            parse_options,
+        )
+        .map(|(map, summary, check)| (enrich_and_count(map, taxonomy, rank), summary, check)),
+        (ValueType::String, _) => gather_checked_map::<K, String>(
+            source,
+            idx_buffer,
+            num_threads,
+            map_options,
+            parse_options,
+        )
+        .map(|(map, summary, check)| ((map.len(), None), summary, check)),
+    }
+}
+
+pub fn perform_decompression(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+    taxonomy_options: Option<&TaxonomyOptions>,
+) -> Result<DecompressionReport> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let parse_options = &parse_options;
+
+    let memory_estimate = match map_options.estimate_memory {
+        true => check_memory_estimate(&idx_buffer, map_options, num_threads, parse_options)?,
+        false => None,
+    };
+
+    if taxonomy_options.is_some() && matches!(map_options.value_type, ValueType::String) {
+        bail!(PipelineError::Usage(
+            "Taxonomy enrichment requires integer values!".into()
+        ));
+    }
+
+    // Load the taxonomy first, so that a bad taxdump fails before any decompression
+    let taxonomy = match taxonomy_options {
+        Some(t) => Some(Taxonomy::load(&t.taxdump_dir)?),
+        None => None,
+    };
+    let rank = taxonomy_options.and_then(|t| t.rank.as_deref());
+
+    // Only the map size is reported, so the key representation can be dropped here
+    let taxonomy = taxonomy.as_ref();
+    let operation_result = match map_options.key_type {
+        KeyType::String => decompress_and_count::<String>(
+            source.as_ref(),
+            idx_buffer,
+            num_threads,
+            map_options,
+            parse_options,
+            taxonomy,
+            rank,
+        ),
+        KeyType::Bytes => decompress_and_count::<Box<[u8]>>(
+            source.as_ref(),
+            idx_buffer,
+            num_threads,
+            map_options,
+            parse_options,
+            taxonomy,
+            rank,
+        ),
+        KeyType::Packed => decompress_and_count::<PackedKey>(
+            source.as_ref(),
+            idx_buffer,
+            num_threads,
+            map_options,
+            parse_options,
+            taxonomy,
+            rank,
+        ),
+    };
+
+    let ((records, resolved), summary, cross_check) = operation_result?;
+
+    emit_summary(parse_options, &summary, records);
+    Ok(DecompressionReport {
+        records,
+        resolved,
+        summary,
+        memory_estimate,
+        cross_check,
+    })
+}
+
+/// Bring a map built from an archive up to date after frames are added to it, decoding only the
+/// frames whose order is above `last_seen_order` and merging their records into the map under
+/// the duplicate key policy. Returns the order of the last frame read, to pass to the next
+/// update, along with the summary of the frames read. `None` stands for no frames seen, so that
+/// the first update reads the whole archive.
+pub fn update_from<K: RecordKey + 'static, V: RecordValue + 'static>(
+    zstd_file: &str,
+    idx_file: &str,
+    record_map: &mut EitherMap<K, V>,
+    last_seen_order: Option<u64>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<(Option<u64>, DecompressionSummary)> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, Some(idx_file), parse_options)?;
+    let source = open_source(zstd_file)?;
+
+    follow::update_map(
+        source.as_ref(),
+        idx_buffer,
+        record_map,
+        last_seen_order,
+        num_threads,
+        &parse_options,
+    )
+}
+
+fn follow_with_keys<K: RecordKey + 'static>(
+    zstd_file: &str,
+    idx_file: &str,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+    follow_options: &FollowOptions,
+    on_update: &mut dyn FnMut(&FollowUpdate),
+) -> Result<FollowReport> {
+    let mode = &map_options.mode;
+
+    match (&map_options.value_type, &map_options.value_width) {
+        (ValueType::Integer, ValueWidth::U64) => follow::follow_map::<K, u64>(
+            zstd_file,
+            idx_file,
+            num_threads,
+            mode,
+            parse_options,
+            follow_options,
+            on_update,
+        ),
+        (ValueType::Integer, ValueWidth::U32) => follow::follow_map::<K, u32>(
+            zstd_file,
+            idx_file,
+            num_threads,
+            mode,
+            parse_options,
+            follow_options,
+            on_update,
+        ),
+        (ValueType::String, _) => follow::follow_map::<K, String>(
+            zstd_file,
+            idx_file,
+            num_threads,
+            mode,
+            parse_options,
+            follow_options,
+            on_update,
+        ),
+    }
+}
+
+/// Build the map of one served archive, reporting its summary.
+fn load_served_map<K: RecordKey + 'static, V: RecordValue + 'static>(
+    archive: &ServedArchive,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+) -> Result<(serve::ServedMap<K, V>, DecompressionReport)> {
+    let (idx_buffer, parse_options) = load_index(
+        &archive.zstd_file,
+        archive.idx_file.as_deref(),
+        parse_options,
+    )?;
+    let source = open_source(&archive.zstd_file)?;
+    let (record_map, summary, position) = gather_map::<K, V>(
+        source.as_ref(),
+        idx_buffer,
+        num_threads,
+        map_options,
+        &parse_options,
+    )?;
+
+    let report = DecompressionReport {
+        records: record_map.len(),
+        resolved: None,
+        summary,
+        memory_estimate: None,
+        cross_check: None,
+    };
+    emit_summary(&parse_options, &report.summary, report.records);
+    let served_map = serve::ServedMap {
+        records: record_map,
+        position,
+    };
+    Ok((served_map, report))
+}
+
+fn serve_with_values<K, V>(
+    serve_options: &ServeOptions,
+    routes: Vec<(Vec<u8>, usize)>,
+    listener: TcpListener,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+    on_ready: &mut dyn FnMut(&ServedArchive, &DecompressionReport),
+) -> Result<()>
+where
+    K: RecordKey + 'static,
+    V: RecordValue + Clone + std::fmt::Display + Serialize + 'static,
+{
+    let archives = &serve_options.archives;
+    let mut namespaces: Vec<(String, serve::ServedMap<K, V>)> = Vec::with_capacity(archives.len());
+    for archive in archives {
+        let (record_map, report) =
+            load_served_map::<K, V>(archive, num_threads, map_options, parse_options)?;
+        on_ready(archive, &report);
+        namespaces.push((archive.namespace.clone(), record_map));
+    }
+
+    // A reloaded archive is decoded in full, as its frames may have been rewritten since the
+    // snapshot was saved
+    let reload_archives = archives.clone();
+    let reload_map_options = MapOptions {
+        from_snapshot: None,
+        ..map_options.clone()
+    };
+    let reload_parse_options = parse_options.clone();
+    let loader: serve::MapLoader<K, V> = Box::new(move |position| {
+        load_served_map::<K, V>(
+            &reload_archives[position],
+            num_threads,
+            &reload_map_options,
+            &reload_parse_options,
+        )
+        .map(|(served_map, _)| served_map)
+    });
+
+    let pool = numa::build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let mut service = serve::LookupService::new(namespaces, routes, pool)
+        .with_loader(loader)
+        .with_remote_reload(serve_options.allow_reload);
+    if let Some(snapshot_file) = map_options.save_snapshot.clone() {
+        // Only a single archive may be served with snapshots, so the file is always its own
+        let snapshot_archives = archives.clone();
+        service = service.with_snapshot_writer(Box::new(move |position, served_map| {
+            snapshot::save_snapshot(
+                &served_map.records,
+                &snapshot_archives[position].zstd_file,
+                &served_map.position,
+                &snapshot_file,
+            )?;
+            Ok(snapshot_file.clone())
+        }));
+    }
+    if let Some(metrics_listener) = &serve_options.metrics_listener {
+        let metrics = Arc::new(metrics::Metrics::default());
+        metrics::serve_metrics(metrics_listener.try_clone()?, Arc::clone(&metrics))?;
+        service = service.with_metrics(metrics);
+    }
+    let service = Arc::new(service);
+    if let Some(watch_interval) = serve_options.watch_interval {
+        let watched_files = archives
+            .iter()
+            .map(|a| {
+                std::iter::once(a.zstd_file.clone())
+                    .chain(a.idx_file.clone())
+                    .collect()
+            })
+            .collect();
+        serve::watch_archives(Arc::clone(&service), watched_files, watch_interval)?;
+    }
+    serve::serve_lookups(listener, service, serve_options.max_connections)
+}
+
+fn serve_with_keys<K: RecordKey + 'static>(
+    serve_options: &ServeOptions,
+    routes: Vec<(Vec<u8>, usize)>,
+    listener: TcpListener,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+    on_ready: &mut dyn FnMut(&ServedArchive, &DecompressionReport),
+) -> Result<()> {
+    match (&map_options.value_type, &map_options.value_width) {
+        (ValueType::Integer, ValueWidth::U64) => serve_with_values::<K, u64>(
+            serve_options,
+            routes,
+            listener,
+            num_threads,
+            map_options,
+            parse_options,
+            on_ready,
+        ),
+        (ValueType::Integer, ValueWidth::U32) => serve_with_values::<K, u32>(
+            serve_options,
+            routes,
+            listener,
+            num_threads,
+            map_options,
+            parse_options,
+            on_ready,
+        ),
+        (ValueType::String, _) => serve_with_values::<K, String>(
+            serve_options,
+            routes,
+            listener,
+            num_threads,
+            map_options,
+            parse_options,
+            on_ready,
+        ),
+    }
+}
+
+/// Build the map of each archive and answer bulk lookups against them on an already bound
+/// listener, until the process is stopped. Keys are posted to `/lookup` as a JSON array or one
+/// per line, and resolved in parallel across the worker pool. Each archive is served under its
+/// own namespace, which a request can name. Otherwise keys are sent to the namespace of their
+/// longest matching prefix route, or looked for in each archive in turn. Archives are rebuilt
+/// and swapped in when `/reload` is posted to, if reloads are allowed, or when their files change
+/// if a watch interval is set. `on_ready` is called as the map of each archive is first built.
+pub fn perform_serve(
+    serve_options: &ServeOptions,
+    listener: TcpListener,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+    mut on_ready: impl FnMut(&ServedArchive, &DecompressionReport),
+) -> Result<()> {
+    if serve_options.archives.len() > 1
+        && (map_options.from_snapshot.is_some() || map_options.save_snapshot.is_some())
+    {
+        bail!(PipelineError::Usage(
+            "Snapshots hold the map of a single archive, so cannot be used when serving several!"
+                .into()
+        ));
+    }
+
+    // Check the routes first, so that a bad route fails before any decompression
+    let namespaces: Vec<&str> = serve_options
+        .archives
+        .iter()
+        .map(|a| a.namespace.as_str())
+        .collect();
+    let routes = serve::resolve_routes(&namespaces, &serve_options.routes)?;
+
+    match map_options.key_type {
+        KeyType::String => serve_with_keys::<String>(
+            serve_options,
+            routes,
+            listener,
+            num_threads,
+            map_options,
+            parse_options,
+            &mut on_ready,
+        ),
+        KeyType::Bytes => serve_with_keys::<Box<[u8]>>(
+            serve_options,
+            routes,
+            listener,
+            num_threads,
+            map_options,
+            parse_options,
+            &mut on_ready,
+        ),
+        KeyType::Packed => serve_with_keys::<PackedKey>(
+            serve_options,
+            routes,
+            listener,
+            num_threads,
+            map_options,
+            parse_options,
+            &mut on_ready,
+        ),
+    }
+}
+
+/// Look up the keys of a file, one per line, against a remote `serve` instance, writing
+/// KEY<TAB>VALUE lines for the keys found. Keys are sent in batches of `batch_size`, to the
+/// given namespace or else routed by the service.
+pub fn perform_client_lookup(
+    server: &str,
+    namespace: Option<&str>,
+    keys_file: &str,
+    output_file: &str,
+    batch_size: usize,
+) -> Result<ClientLookupSummary> {
+    let keys_handle = File::open(keys_file)
+        .with_context(|| format!("Unable to open keys file '{}'", keys_file))?;
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+
+    serve::lookup_remote(
+        server,
+        namespace,
+        BufReader::new(keys_handle),
+        BufWriter::new(output_handle),
+        batch_size,
+    )
+}
+
+/// Mount the decompressed content of an archive as a read-only file in `mount_point`, which
+/// holds it until the filesystem is unmounted. Frames are decoded as the file is read, so a
+/// reader seeking into the file decodes only the frames it touches. `on_ready` is given the path
+/// and length of the mounted file. The hits and misses of the frame cache are exposed at
+/// `/metrics` on the metrics listener, if one is given. Only available on Linux, through the
+/// kernel FUSE interface.
+pub fn perform_mount(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    mount_point: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    metrics_listener: Option<TcpListener>,
+    mut on_ready: impl FnMut(&str, u64),
+) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(metrics_listener) = metrics_listener {
+            let metrics =
+                metrics::Metrics::default().with_frame_cache(parse_options.frame_cache.clone());
+            metrics::serve_metrics(metrics_listener, Arc::new(metrics))?;
+        }
+        let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+        mount::mount_archive(
+            zstd_file,
+            open_source(zstd_file)?,
+            idx_buffer,
+            mount_point,
+            num_threads,
+            &parse_options,
+            &mut on_ready,
+        )
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (
+            zstd_file,
+            idx_file,
+            num_threads,
+            parse_options,
+            metrics_listener,
+            &mut on_ready,
+        );
+        bail!(PipelineError::Usage(format!(
+            "Unable to mount at '{}', as mounting is only supported on Linux!",
+            mount_point
+        )))
+    }
+}
+
+/// Follow an archive which is still being written, such as a compressed log, decoding only the
+/// frames indexed since the last check. Their records are merged into a map or, given an output
+/// file in the follow options, their content is written to it in order. `on_update` is called
+/// after each check which found new frames.
+pub fn perform_follow_decompression(
+    zstd_file: &str,
+    idx_file: &str,
+    num_threads: usize,
+    map_options: &MapOptions,
+    parse_options: &ParseOptions,
+    follow_options: &FollowOptions,
+    mut on_update: impl FnMut(&FollowUpdate),
+) -> Result<FollowReport> {
+    let report = match (&follow_options.output_file, &map_options.key_type) {
+        (Some(output_file), _) => {
+            let output_handle = File::create(output_file)
+                .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+            follow::follow_stream(
+                zstd_file,
+                idx_file,
+                BufWriter::new(output_handle),
+                num_threads,
+                parse_options,
+                follow_options,
+                &mut on_update,
+            )
+        }
+        (None, KeyType::String) => follow_with_keys::<String>(
+            zstd_file,
+            idx_file,
+            num_threads,
+            map_options,
+            parse_options,
+            follow_options,
+            &mut on_update,
+        ),
+        (None, KeyType::Bytes) => follow_with_keys::<Box<[u8]>>(
+            zstd_file,
+            idx_file,
+            num_threads,
+            map_options,
+            parse_options,
+            follow_options,
+            &mut on_update,
+        ),
+        (None, KeyType::Packed) => follow_with_keys::<PackedKey>(
+            zstd_file,
+            idx_file,
+            num_threads,
+            map_options,
+            parse_options,
+            follow_options,
+            &mut on_update,
+        ),
+    }?;
+
+    parse_options.emit_event(Event::Summary {
+        status: report.run_status(),
+        records: report.records,
+        bytes_written: report.bytes_written,
+        bad_records: report.summary.bad_records.len(),
+        failed_frames: report.summary.failed_frames.clone(),
+        zeroed_records: report.summary.zeroed_records,
+        skipped_records: report.summary.skipped_records,
+        memory: MemoryUsage::current(),
+    });
+    Ok(report)
+}
+
+pub fn perform_partition(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+    key_type: &KeyType,
+    parse_options: &ParseOptions,
+    output_dir: &str,
+    buckets: Option<u64>,
+) -> Result<DecompressionReport> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let parse_options = &parse_options;
+
+    let operation_result = match key_type {
+        KeyType::String => partition::write_partitioned_zstd::<String>(
+            source.as_ref(),
+            idx_buffer,
+            num_threads,
+            parse_options,
+            output_dir,
+            buckets,
+        ),
+        KeyType::Bytes => partition::write_partitioned_zstd::<Box<[u8]>>(
+            source.as_ref(),
+            idx_buffer,
+            num_threads,
+            parse_options,
+            output_dir,
+            buckets,
+        ),
+        KeyType::Packed => partition::write_partitioned_zstd::<PackedKey>(
+            source.as_ref(),
+            idx_buffer,
+            num_threads,
+            parse_options,
+            output_dir,
+            buckets,
+        ),
+    };
+
+    let (records, summary) = operation_result?;
+
+    emit_summary(parse_options, &summary, records);
+    Ok(DecompressionReport {
+        records,
+        resolved: None,
+        summary,
+        memory_estimate: None,
+        cross_check: None,
+    })
+}
+
+/// Estimate the number of distinct keys and values in an archive from merged per-frame
+/// sketches, without building a map.
+pub fn perform_estimate_cardinality(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<CardinalityReport> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let report = cardinality::estimate_cardinality_zstd(
+        source.as_ref(),
+        idx_buffer,
+        num_threads,
+        &parse_options,
+    )?;
+
+    emit_summary(&parse_options, &report.summary, report.records);
+    Ok(report)
+}
+
+/// Count the records of each value in an archive and report the `k` most frequent, without
+/// building a map of the keys.
+pub fn perform_top_values(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    k: usize,
+) -> Result<TopValuesReport> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let report =
+        frequency::top_values_zstd(source.as_ref(), idx_buffer, num_threads, &parse_options, k)?;
+
+    emit_summary(&parse_options, &report.summary, report.records);
+    Ok(report)
+}
+
+/// Decompress an archive into a map of integer values under the given mode, and write it out as
+/// a membership index of the keys holding each value, in place of keeping the map.
+pub fn perform_membership_index(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+    mode: &Mode,
+    parse_options: &ParseOptions,
+    index_file: &str,
+) -> Result<MembershipReport> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let (record_map, summary) = decompress_with_keys::<Box<[u8]>, u64>(
+        source.as_ref(),
+        idx_buffer,
+        mode,
+        num_threads,
+        &parse_options,
+    )?;
+
+    let membership_index = MembershipIndex::from_map(&record_map)?;
+    membership_index.save(source.name(), index_file)?;
+
+    emit_summary(&parse_options, &summary, record_map.len());
+    Ok(MembershipReport {
+        records: record_map.len(),
+        values: membership_index.values().len(),
+        index_bytes: std::fs::metadata(index_file)?.len(),
+        summary,
+    })
+}
+
+/// Write an index leaving out the frames which failed during a run, such as those rejected by
+/// '--verify-frames', so that the intact frames can still be read, along with a report of the
+/// frames left out and the spans of decompressed content lost with them.
+pub fn perform_quarantine(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    summary: &DecompressionSummary,
+    quarantine_index: &str,
+) -> Result<QuarantineReport> {
+    if idx_file == Some("-") {
+        bail!(PipelineError::Usage(
+            "A quarantine index needs an index which can be read again, not one read from stdin!"
+                .into()
+        ));
+    }
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let report_file = format!("{}.quarantine.tsv", quarantine_index);
+    let input_files: Vec<&str> = [Some(zstd_file), idx_file].into_iter().flatten().collect();
+    check_output_paths(&input_files, &[quarantine_index, &report_file], true)?;
+
+    let (kept, quarantined) = quarantine::quarantine_frames(
+        source.as_ref(),
+        idx_buffer,
+        &summary.failed_frames,
+        num_threads,
+        &parse_options,
+    );
+
+    let index_handle = File::create(quarantine_index).with_context(|| {
+        format!(
+            "Unable to create quarantine index file '{}'",
+            quarantine_index
+        )
+    })?;
+    let mut idx_writer = BufWriter::new(index_handle);
+    serde_json::to_writer_pretty(&mut idx_writer, &kept)?;
+    idx_writer.flush()?;
+    quarantine::write_quarantine_report(&quarantined, &report_file)?;
+
+    Ok(QuarantineReport {
+        frames_kept: kept.len(),
+        quarantined,
+        report_file,
+    })
+}
+
+/// Coordinate a distributed decompression on an already bound listener, so that the caller can
+/// report the address (including any port assigned by the OS) before workers connect.
+pub fn perform_serve_frames(
+    zstd_file: &str,
+    idx_file: &str,
+    listener: TcpListener,
+    frames_per_task: usize,
+    parse_options: &ParseOptions,
+    collect: &Collect,
+    metrics_listener: Option<TcpListener>,
+) -> Result<DecompressionReport> {
+    let (idx_buffer, _) = load_index(zstd_file, Some(idx_file), parse_options)?;
+
+    let metrics = Arc::new(metrics::Metrics::default());
+    if let Some(metrics_listener) = metrics_listener {
+        metrics::serve_metrics(metrics_listener, Arc::clone(&metrics))?;
+    }
+
+    let operation_result = distributed::serve_frames(
+        zstd_file,
+        idx_buffer,
+        listener,
+        frames_per_task,
+        parse_options,
+        collect,
+        metrics,
+    );
+
+    let (gathered, summary) = operation_result?;
+
+    // With value counts, the records reported are the distinct values observed
+    emit_summary(parse_options, &summary, gathered.len());
+    Ok(DecompressionReport {
+        records: gathered.len(),
+        resolved: None,
+        summary,
+        memory_estimate: None,
+        cross_check: None,
+    })
+}
+
+/// Run as a worker until the coordinator has no tasks left, returning the number completed.
+pub fn perform_worker(
+    coordinator: &str,
+    zstd_override: Option<&str>,
+    num_threads: usize,
+    thread_options: &ThreadOptions,
+) -> Result<usize> {
+    distributed::run_worker(coordinator, zstd_override, num_threads, thread_options)
+}
+
+pub fn perform_extraction(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    output_file: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    resume: bool,
+    checkpoint_interval: usize,
+) -> Result<ExtractSummary> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let parse_options = &parse_options;
+
+    let operation_result = extract::extract_zstd(
+        source.as_ref(),
+        idx_buffer,
+        output_file,
+        num_threads,
+        parse_options,
+        resume,
+        checkpoint_interval,
+    );
+
+    let summary = operation_result?;
+
+    parse_options.emit_event(Event::Summary {
+        status: RunStatus::Complete,
+        records: None,
+        bytes_written: Some(summary.bytes_written),
+        bad_records: 0,
+        failed_frames: Vec::new(),
+        zeroed_records: 0,
+        skipped_records: 0,
+        memory: MemoryUsage::current(),
+    });
+    Ok(summary)
+}
+
+/// Write the records of the archive whose keys appear in the query file, one key per line.
+/// Query keys are compared as raw bytes, after the same normalisation as the archive's keys.
+pub fn perform_join(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    query_file: &str,
+    output_file: Option<&str>,
+    missing_file: Option<&str>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<JoinReport> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let parse_options = &parse_options;
+
+    // Read the queries first, so that a missing query file fails before any decompression
+    let query_handle = File::open(query_file)
+        .with_context(|| format!("Unable to open query file '{}'", query_file))?;
+    let query_keys = join::load_query_keys::<Box<[u8]>>(query_handle, parse_options)?;
+    let output_handle = output_file.map(File::create).transpose()?;
+    let missing_handle = missing_file.map(File::create).transpose()?;
+
+    let report = join::join_zstd(
+        source.as_ref(),
+        idx_buffer,
+        &query_keys,
+        output_handle,
+        missing_handle,
+        num_threads,
+        parse_options,
+    )?;
+
+    emit_summary(parse_options, &report.summary, report.records_written);
+    Ok(report)
+}
+
+/// Find the keys held by more than one record of the archive.
+pub fn perform_check_unique(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<UniqueReport> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+
+    // Records which span frames are only complete once every frame is read, so cannot be checked
+    if parse_options.split_records {
+        bail!(PipelineError::Usage(
+            "Checking for unique keys needs a frame index, so that every frame holds whole records!"
+                .into()
+        ));
+    }
+
+    let report =
+        unique::check_unique_zstd(source.as_ref(), idx_buffer, num_threads, &parse_options)?;
+
+    emit_summary(&parse_options, &report.summary, report.records_checked);
+    Ok(report)
+}
+
+/// Rewrite an archive with its records sorted by key, in frames written as for a repack.
+pub fn perform_sort(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    output_file: &str,
+    index_file: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    repack_options: &RepackOptions,
+) -> Result<SortSummary> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let block_size = parse_block_input(&repack_options.block_size)?;
+    check_block_size(block_size, num_threads)?;
+    let input_files: Vec<&str> = [Some(zstd_file), idx_file].into_iter().flatten().collect();
+    check_output_paths(
+        &input_files,
+        &[output_file, index_file],
+        repack_options.force,
+    )?;
+
+    // Frames are sorted independently, so a record must never run from one frame into the next
+    if parse_options.split_records {
+        bail!(PipelineError::Usage(
+            "Sorting needs a frame index, so that every frame holds whole records!".into()
+        ));
+    }
+
+    let sort_target = repack::RepackTarget {
+        block_size,
+        zstd_level: repack_options.zstd_level,
+        frame_metadata: repack_options.frame_metadata,
+        align: repack_options.align,
+        format: parse_options.format.clone(),
+    };
+
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let index_handle = File::create(index_file)
+        .with_context(|| format!("Unable to create index file '{}'", index_file))?;
+
+    let summary = sort::sort_zstd(
+        source.as_ref(),
+        idx_buffer,
+        output_handle,
+        BufWriter::new(index_handle),
+        num_threads,
+        &parse_options,
+        &sort_target,
+    )?;
+
+    parse_options.emit_event(Event::Summary {
+        status: RunStatus::Complete,
+        records: Some(summary.records_written),
+        bytes_written: Some(summary.bytes_written),
+        bad_records: 0,
+        failed_frames: Vec::new(),
+        zeroed_records: 0,
+        skipped_records: 0,
+        memory: MemoryUsage::current(),
+    });
+    Ok(summary)
+}
+
+/// Join two archives compressed from key-sorted input, each given as its archive and index files.
+pub fn perform_merge_join(
+    left: (&str, &str),
+    right: (&str, &str),
+    output_file: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<MergeJoinReport> {
+    // The key order recorded in the index is that of the plain record keys
+    if !parse_options.key_columns.is_empty() || parse_options.strip_key_version {
+        bail!(PipelineError::Usage(
+            "A merge join cannot rebuild the keys of the records!".into()
+        ));
+    }
+
+    // Only frames from a frame index can be recorded as sorted, so records never span frames
+    let (left_idx, _) = load_index(left.0, Some(left.1), parse_options)?;
+    let (right_idx, _) = load_index(right.0, Some(right.1), parse_options)?;
+    let (left_source, right_source) = (open_source(left.0)?, open_source(right.0)?);
+    let output_handle = File::create(output_file)?;
+
+    let report = join::merge_join_zstd::<Box<[u8]>>(
+        left_source.as_ref(),
+        left_idx,
+        right_source.as_ref(),
+        right_idx,
+        output_handle,
+        num_threads,
+        parse_options,
+    )?;
+
+    parse_options.emit_event(Event::Summary {
+        status: report.run_status(),
+        records: Some(report.pairs_written),
+        bytes_written: None,
+        bad_records: report.left_summary.bad_records.len() + report.right_summary.bad_records.len(),
+        failed_frames: [&report.left_summary, &report.right_summary]
+            .into_iter()
+            .flat_map(|s| s.failed_frames.iter().cloned())
+            .collect(),
+        zeroed_records: report.left_summary.zeroed_records + report.right_summary.zeroed_records,
+        skipped_records: report.left_summary.skipped_records + report.right_summary.skipped_records,
+        memory: MemoryUsage::current(),
+    });
+    Ok(report)
+}
+
+pub fn perform_repack(
+    zstd_file: &str,
+    idx_file: Option<&str>,
+    output_file: &str,
+    index_file: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    repack_options: &RepackOptions,
+) -> Result<RepackSummary> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, idx_file, parse_options)?;
+    let source = open_source(zstd_file)?;
+    let block_size = parse_block_input(&repack_options.block_size)?;
+    check_block_size(block_size, num_threads)?;
+    let input_files: Vec<&str> = [Some(zstd_file), idx_file].into_iter().flatten().collect();
+    check_output_paths(
+        &input_files,
+        &[output_file, index_file],
+        repack_options.force,
+    )?;
+
+    let repack_target = repack::RepackTarget {
+        block_size,
+        zstd_level: repack_options.zstd_level,
+        frame_metadata: repack_options.frame_metadata,
+        align: repack_options.align,
+        format: parse_options.format.clone(),
+    };
+
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let index_handle = File::create(index_file)
+        .with_context(|| format!("Unable to create index file '{}'", index_file))?;
+
+    let summary = repack::repack_zstd(
+        source.as_ref(),
+        idx_buffer,
+        output_handle,
+        BufWriter::new(index_handle),
+        num_threads,
+        &parse_options,
+        &repack_target,
+    )?;
+
+    parse_options.emit_event(Event::Summary {
+        status: RunStatus::Complete,
+        records: None,
+        bytes_written: Some(summary.bytes_written),
+        bad_records: 0,
+        failed_frames: Vec::new(),
+        zeroed_records: 0,
+        skipped_records: 0,
+        memory: MemoryUsage::current(),
+    });
+    Ok(summary)
+}
+
+/// Rewrite an archive keeping only the last record of each key, and dropping keys whose last
+/// record is a tombstone, so that an archive written as a log of changes can be read as a map.
+pub fn perform_compact(
+    zstd_file: &str,
+    idx_file: &str,
+    output_file: &str,
+    index_file: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    repack_options: &RepackOptions,
+) -> Result<CompactSummary> {
+    let (idx_buffer, parse_options) = load_index(zstd_file, Some(idx_file), parse_options)?;
+    let source = open_source(zstd_file)?;
+    let block_size = parse_block_input(&repack_options.block_size)?;
+    check_block_size(block_size, num_threads)?;
+    check_output_paths(
+        &[zstd_file, idx_file],
+        &[output_file, index_file],
+        repack_options.force,
+    )?;
+
+    let repack_target = repack::RepackTarget {
+        block_size,
+        zstd_level: repack_options.zstd_level,
+        frame_metadata: repack_options.frame_metadata,
+        align: repack_options.align,
+        format: parse_options.format.clone(),
+    };
+
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let index_handle = File::create(index_file)
+        .with_context(|| format!("Unable to create index file '{}'", index_file))?;
+
+    let summary = compact::compact_zstd(
+        source.as_ref(),
+        idx_buffer,
+        output_handle,
+        BufWriter::new(index_handle),
+        num_threads,
+        &parse_options,
+        &repack_target,
+    )?;
+
+    parse_options.emit_event(Event::Summary {
+        status: RunStatus::Complete,
+        records: Some(summary.records_kept),
+        bytes_written: Some(summary.bytes_written),
+        bad_records: 0,
+        failed_frames: Vec::new(),
+        zeroed_records: 0,
+        skipped_records: 0,
+        memory: MemoryUsage::current(),
+    });
+    Ok(summary)
+}
+
+/// Split an archive into one standalone archive per frame, each a '.zst' file holding the
+/// frame as it was compressed with an index of its own, and write a manifest of the shards so
+/// that external schedulers can hand out the frames as separate tasks.
+pub fn perform_explode(
+    zstd_file: &str,
+    idx_file: &str,
+    output_dir: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    force: bool,
+) -> Result<ExplodeSummary> {
+    let (mut idx_buffer, parse_options) = load_index(zstd_file, Some(idx_file), parse_options)?;
+    let source = open_source(zstd_file)?;
+
+    let manifest = explode::plan_shards(zstd_file, &mut idx_buffer);
+    let output_path = std::path::Path::new(output_dir);
+    let output_files: Vec<String> = manifest
+        .shards
+        .iter()
+        .flat_map(|s| [Some(s.file.as_str()), s.index_file.as_deref()])
+        .flatten()
+        .chain([explode::MANIFEST_FILE])
+        .map(|f| output_path.join(f).to_string_lossy().to_string())
+        .collect();
+    let output_files: Vec<&str> = output_files.iter().map(String::as_str).collect();
+    check_output_paths(&[zstd_file, idx_file], &output_files, force)?;
+
+    std::fs::create_dir_all(output_dir)
+        .with_context(|| format!("Unable to create output directory '{}'", output_dir))?;
+    let summary = explode::explode_zstd(
+        source.as_ref(),
+        &idx_buffer,
+        &manifest,
+        output_dir,
+        num_threads,
+        &parse_options,
+    )?;
+
+    parse_options.emit_event(Event::Summary {
+        status: RunStatus::Complete,
+        records: None,
+        bytes_written: Some(summary.bytes_written),
+        bad_records: 0,
+        failed_frames: Vec::new(),
+        zeroed_records: 0,
+        skipped_records: 0,
+        memory: MemoryUsage::current(),
+    });
+    Ok(summary)
+}
+
+/// Concatenate a directory of shards into a single indexed archive, the inverse of
+/// `perform_explode`. The manifest lists the shards in order, and defaults to the one written
+/// by `perform_explode` in the shard directory; shards written by other tools need only give
+/// their order and file.
+pub fn perform_assemble(
+    shard_dir: &str,
+    manifest_file: Option<&str>,
+    output_file: &str,
+    index_file: &str,
+    force: bool,
+) -> Result<AssembleSummary> {
+    let manifest_file = match manifest_file {
+        Some(f) => f.to_string(),
+        None => std::path::Path::new(shard_dir)
+            .join(explode::MANIFEST_FILE)
+            .to_string_lossy()
+            .to_string(),
+    };
+    let manifest_handle = File::open(&manifest_file)
+        .with_context(|| format!("Unable to open manifest file '{}'", manifest_file))?;
+    let manifest: ShardManifest = match serde_json::from_reader(BufReader::new(manifest_handle)) {
+        Ok(m) => m,
+        Err(e) => bail!(PipelineError::Usage(format!(
+            "Unable to parse manifest file '{}': {}!",
+            manifest_file, e
+        ))),
+    };
+
+    let shard_path = std::path::Path::new(shard_dir);
+    let input_files: Vec<String> = manifest
+        .shards
+        .iter()
+        .flat_map(|s| [Some(&s.file), s.index_file.as_ref()])
+        .flatten()
+        .map(|f| shard_path.join(f).to_string_lossy().to_string())
+        .chain([manifest_file.clone()])
+        .collect();
+    let input_files: Vec<&str> = input_files.iter().map(String::as_str).collect();
+    check_output_paths(&input_files, &[output_file, index_file], force)?;
+
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let index_handle = File::create(index_file)
+        .with_context(|| format!("Unable to create index file '{}'", index_file))?;
+
+    let (idx_records, summary) =
+        explode::assemble_zstd(&manifest, shard_dir, &mut BufWriter::new(output_handle))?;
+
+    let mut idx_writer = BufWriter::new(index_handle);
+    serde_json::to_writer_pretty(&mut idx_writer, &idx_records)?;
+    idx_writer.flush()?;
+    Ok(summary)
+}
+
+/// Write a frame index out as CSV (or as uncompressed JSON) for tools outside this crate,
+/// returning the number of frames written.
+pub fn perform_index_export(
+    idx_file: &str,
+    output_file: &str,
+    format: &IndexFormat,
+    force: bool,
+) -> Result<usize> {
+    let idx_buffer = read_frame_index(idx_file)?;
+    check_output_paths(&[idx_file], &[output_file], force)?;
+
+    let output_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create output file '{}'", output_file))?;
+    let mut output_writer = BufWriter::new(output_handle);
+    match format {
+        IndexFormat::Csv => index_csv::write_index_csv(&idx_buffer, &mut output_writer)?,
+        IndexFormat::Json => {
+            serde_json::to_writer_pretty(&mut output_writer, &idx_buffer)?;
+            output_writer.flush()?;
+        }
+    }
+    Ok(idx_buffer.len())
+}
+
+/// Read a frame index from CSV, as written by `perform_index_export` or by other tools, and
+/// write it as an index this crate reads. Given the archive, the frames are checked to lie within
+/// it without overlapping. Returns the number of frames written.
+pub fn perform_index_import(
+    csv_file: &str,
+    output_file: &str,
+    zstd_file: Option<&str>,
+    force: bool,
+) -> Result<usize> {
+    let csv_handle = File::open(csv_file)
+        .with_context(|| format!("Unable to open CSV index file '{}'", csv_file))?;
+    let idx_buffer = index_csv::read_index_csv(BufReader::new(csv_handle))?;
+    if let Some(zstd_file) = zstd_file {
+        let archive_length = std::fs::metadata(zstd_file)?.len();
+        decompression::check_frame_index(&idx_buffer, archive_length).map_err(|e| {
+            e.context(format!(
+                "Index '{}' does not match '{}'",
+                csv_file, zstd_file
+            ))
+        })?;
+    }
+    let input_files: Vec<&str> = [Some(csv_file), zstd_file].into_iter().flatten().collect();
+    check_output_paths(&input_files, &[output_file], force)?;
+
+    let index_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create index file '{}'", output_file))?;
+    let mut idx_writer = BufWriter::new(index_handle);
+    serde_json::to_writer_pretty(&mut idx_writer, &idx_buffer)?;
+    idx_writer.flush()?;
+    Ok(idx_buffer.len())
+}
+
+/// Write a new index over the same payload with frames dropped, held to a range or put in a new
+/// sequence, such as to quarantine corrupt frames while the rest of the archive stays readable.
+/// The header of the index, recording how the payload was made, is carried over. Returns the
+/// number of frames before and after the edit.
+pub fn perform_index_edit(
+    idx_file: &str,
+    output_file: &str,
+    index_edit: &IndexEdit,
+    force: bool,
+) -> Result<(usize, usize)> {
+    let (index_header, idx_buffer) = read_index_file(idx_file)?;
+    check_output_paths(&[idx_file], &[output_file], force)?;
+    let frames_read = idx_buffer.len();
+    let idx_buffer = index_edit.apply(idx_buffer)?;
+
+    let index_handle = File::create(output_file)
+        .with_context(|| format!("Unable to create index file '{}'", output_file))?;
+    compression::write_index(
+        BufWriter::new(index_handle),
+        index_header.as_ref(),
+        &idx_buffer,
+    )?;
+    Ok((frames_read, idx_buffer.len()))
+}
+
+/// Decode a single frame of a zstd archive from any seekable reader, such as the open archive
+/// file, for tools which schedule their own reads. The content is checked against the checksum
+/// of the index entry when it holds one.
+pub fn decode_frame<R: Read + Seek>(archive: &mut R, frame_meta: &FrameMeta) -> Result<Vec<u8>> {
+    let parse_options = ParseOptions {
+        verify_frames: true,
+        ..Default::default()
+    };
+    decompression::decode_frame_from(archive, frame_meta, &parse_options)
+}
+
+/// Decode a single frame and parse its records as a decompression would, under the codec,
+/// record format and bad record policy of the parse options. The frame must hold whole records,
+/// as the frames of an indexed archive do.
+pub fn decode_frame_records<R, K, V>(
+    archive: &mut R,
+    frame_meta: &FrameMeta,
+    parse_options: &ParseOptions,
+) -> Result<FrameRecords<K, V>>
+where
+    R: Read + Seek,
+    K: RecordKey,
+    V: RecordValue,
+{
+    let payload = decompression::decode_frame_from(archive, frame_meta, parse_options)?;
+    decompression::parse_frame(&payload, frame_meta.order, parse_options)
+}
+
+/// Decode a single frame and parse its records as `decode_frame_records` does, but keep the
+/// decoded payload and borrow each key from it rather than allocating one per record. Keys
+/// built from key columns are not held in the payload, and are refused.
+pub fn decode_frame_view<R, V>(
+    archive: &mut R,
+    frame_meta: &FrameMeta,
+    parse_options: &ParseOptions,
+) -> Result<(FrameView<V>, Vec<BadRecord>)>
+where
+    R: Read + Seek,
+    V: RecordValue,
+{
+    let payload = decompression::decode_frame_from(archive, frame_meta, parse_options)?;
+    decompression::parse_frame_view(payload, frame_meta.order, parse_options)
+}
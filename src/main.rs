@@ -1,83 +1,3531 @@
-use anyhow::Result;
-use clap::Parser;
-use parallel_decompression::Mode;
+use anyhow::{bail, Result};
+use byte_unit::{Byte, Unit, UnitType};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use parallel_decompression::{
+    ArchiveDescription, BadRecordPolicy, ByteCounts, Codec, Collect, CompressionOptions, Config,
+    CrossCheckReport, DecompressionSummary, DoctorReport, DuplicateKey, DuplicatePolicy,
+    ErrorClass, Event, EventLog, FollowOptions, FrameCache, FrameSchedule, GenerateOptions,
+    IndexEdit, IndexFormat, KeyStyle, KeyType, LogFormat, MapOptions, MemoryEstimate, MemoryUsage,
+    Mode, ParseOptions, PipelineError, Profile, ReadBatcher, ReadLimiter, RecordFormat,
+    RepackOptions, ResourceLimits, RetryPolicy, RunStatus, ServeOptions, ServedArchive, Stage,
+    StageProfiler, StageSummary, TaxonomyOptions, ThreadOptions, Validation, ValueType, ValueWidth,
+};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The map-heavy decompression modes spend much of their time allocating, and the system malloc
+// varies between distributions, so jemalloc or mimalloc can be built in its place. Features are
+// additive, so jemalloc is used should both be enabled.
+
+#[cfg(feature = "jemalloc")]
+type BaseAllocator = tikv_jemallocator::Jemalloc;
+#[cfg(feature = "jemalloc")]
+const BASE_ALLOCATOR: BaseAllocator = tikv_jemallocator::Jemalloc;
+#[cfg(feature = "jemalloc")]
+const ALLOCATOR_NAME: &str = "jemalloc";
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+type BaseAllocator = mimalloc::MiMalloc;
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+const BASE_ALLOCATOR: BaseAllocator = mimalloc::MiMalloc;
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+const ALLOCATOR_NAME: &str = "mimalloc";
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+type BaseAllocator = std::alloc::System;
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+const BASE_ALLOCATOR: BaseAllocator = std::alloc::System;
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+const ALLOCATOR_NAME: &str = "system";
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: parallel_decompression::CountingAllocator<BaseAllocator> =
+    parallel_decompression::CountingAllocator::new(BASE_ALLOCATOR);
+
+#[cfg(not(feature = "alloc-stats"))]
+#[global_allocator]
+static ALLOCATOR: BaseAllocator = BASE_ALLOCATOR;
+
+/// Version with the allocator built in, as allocation speed differs widely between them.
+fn long_version() -> String {
+    let counting = match cfg!(feature = "alloc-stats") {
+        true => ", counting allocations",
+        false => "",
+    };
+    format!(
+        "{}\nallocator: {}{}",
+        env!("CARGO_PKG_VERSION"),
+        ALLOCATOR_NAME,
+        counting
+    )
+}
 
 fn main() {
-    let user_inputs = ArgumentParser::parse();
+    let config = match Config::default_path() {
+        Some(config_file) => match Config::load(&config_file) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(ErrorClass::of(&e).exit_code());
+            }
+        },
+        None => Config::default(),
+    };
+
+    // Usage errors exit with 1 rather than clap's default of 2, which is reserved for IO errors
+    let resource_limits = ResourceLimits::detect();
+    let cli = apply_config_defaults(
+        ArgumentParser::command().long_version(long_version()),
+        &config,
+        &resource_limits,
+    );
+    let user_inputs = match cli
+        .clone()
+        .try_get_matches()
+        .and_then(|m| {
+            // A profile replaces the defaults of the compress options, so the arguments are
+            // parsed again under them
+            let profile = m
+                .subcommand_matches("compress")
+                .and_then(|c| c.get_one::<Profile>("profile"));
+            match profile {
+                Some(profile) => {
+                    apply_profile_defaults(cli, profile, &m, &resource_limits).try_get_matches()
+                }
+                None => Ok(m),
+            }
+        })
+        .and_then(|m| {
+            warn_oversubscribed(&m, &resource_limits);
+            ArgumentParser::from_arg_matches(&m)
+        }) {
+        Ok(u) => u,
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(if e.use_stderr() { 1 } else { 0 });
+        }
+    };
+
+    // Shell support files are written to stdout, so are handled before any run output is printed
+    let command = match (&user_inputs.command, user_inputs.generate_manpage) {
+        (Some(Workflow::Completions { shell }), _) => {
+            let mut cli = ArgumentParser::command();
+            let bin_name = cli.get_name().to_string();
+            clap_complete::generate(*shell, &mut cli, bin_name, &mut std::io::stdout());
+            return;
+        }
+        (Some(_), true) => {
+            let _ = ArgumentParser::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "'--generate-manpage' cannot be used with a subcommand",
+                )
+                .print();
+            std::process::exit(1);
+        }
+        (Some(command), false) => command,
+        (None, true) => {
+            let man = clap_mangen::Man::new(ArgumentParser::command());
+            if let Err(e) = man.render(&mut std::io::stdout()) {
+                eprintln!("Unable to write manpage: {}", e);
+                std::process::exit(2);
+            }
+            return;
+        }
+        (None, false) => {
+            let _ = ArgumentParser::command()
+                .error(
+                    clap::error::ErrorKind::MissingSubcommand,
+                    "A subcommand is required",
+                )
+                .print();
+            std::process::exit(1);
+        }
+    };
+
+    // A JSON description is written alone to stdout, so that it can be piped straight to a parser
+    let quiet = user_inputs.quiet || matches!(command, Workflow::Describe { json: true, .. });
+    let start = Instant::now();
+    let byte_counts = Arc::new(ByteCounts::default());
+    // The reference mode writes the same log on every run, so leaves out timings
+    let reference_mode = matches!(
+        command,
+        Workflow::Decompress {
+            mode: Mode::Reference,
+            ..
+        }
+    );
+    let event_log = match (&user_inputs.log_format, reference_mode) {
+        (LogFormat::Json, false) => Some(Arc::new(EventLog::stderr())),
+        (LogFormat::Json, true) => Some(Arc::new(EventLog::untimed(Box::new(std::io::stderr())))),
+        (LogFormat::Text, _) => None,
+    };
+    let thread_options = ThreadOptions {
+        name_prefix: user_inputs.thread_name_prefix.clone(),
+        niceness: user_inputs.niceness,
+    };
 
-    let operation_results: Result<()> = match &user_inputs.command {
+    let operation_results: Result<RunStatus> = match command {
         Workflow::Compress {
             input,
             output,
             zindex,
             block_size,
             level,
-        } => parallel_decompression::perform_compression(input, output, zindex, block_size, *level),
+            validate,
+            preserve_line_endings,
+            format,
+            input_codec,
+            frame_metadata,
+            no_frame_metadata,
+            align,
+            assume_sorted,
+            create_dirs,
+            force,
+            adaptive_level,
+            min_frame_size,
+            read_threads,
+            plain_index,
+            ..
+        } => {
+            let compression_options = CompressionOptions {
+                validation: validate.clone(),
+                format: format.clone(),
+                preserve_line_endings: *preserve_line_endings,
+                input_codec: input_codec.clone(),
+                frame_metadata: *frame_metadata && !*no_frame_metadata,
+                align: *align,
+                assume_sorted: *assume_sorted,
+                create_dirs: *create_dirs,
+                force: *force,
+                adaptive_level: *adaptive_level,
+                min_frame_size: min_frame_size.unwrap_or_default() as usize,
+                read_threads: read_threads.map(|t| t as usize),
+                threads: thread_options.clone(),
+                plain_index: *plain_index,
+            };
+            let input_files: Vec<&str> = input.iter().map(|i| i.as_str()).collect();
+            parallel_decompression::perform_compression(
+                &input_files,
+                output,
+                zindex,
+                block_size,
+                *level,
+                &compression_options,
+            )
+            .map(|idx_records| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input.join(", "));
+                    println!("  Output file: {}", output);
+                    println!("  Index file:  {}", zindex);
+                    let mut frame_lengths: Vec<u64> = idx_records
+                        .iter()
+                        .filter_map(|f| f.parse_length().ok())
+                        .map(|l| l as u64)
+                        .collect();
+                    frame_lengths.sort_unstable();
+                    if let (Some(smallest), Some(largest)) =
+                        (frame_lengths.first(), frame_lengths.last())
+                    {
+                        println!(
+                            "  Compressed frame sizes: smallest {}, median {}, largest {}",
+                            format_bytes(*smallest),
+                            format_bytes(frame_lengths[frame_lengths.len() / 2]),
+                            format_bytes(*largest)
+                        );
+                    }
+                    if *adaptive_level {
+                        let mut level_counts: BTreeMap<i32, usize> = BTreeMap::new();
+                        for level in idx_records.iter().filter_map(|f| f.level()) {
+                            *level_counts.entry(level).or_default() += 1;
+                        }
+                        let level_counts: Vec<String> = level_counts
+                            .iter()
+                            .map(|(level, frames)| format!("level {}: {}", level, frames))
+                            .collect();
+                        println!("  Frames per level: {}", level_counts.join(", "));
+                    }
+                    // The size of a compressed input says nothing of the uncompressed content
+                    let uncompressed = match input_codec {
+                        Some(_) => None,
+                        None => input.iter().map(|i| file_size(i)).sum(),
+                    };
+                    print_throughput(start.elapsed(), file_size(output), uncompressed, None);
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::Tune {
+            input,
+            sample_size,
+            block_sizes,
+            levels,
+            measure_decode,
+            num_threads,
+        } => {
+            let block_sizes: Vec<usize> = block_sizes.iter().map(|b| *b as usize).collect();
+            parallel_decompression::perform_tune(
+                input,
+                *sample_size,
+                &block_sizes,
+                levels,
+                measure_decode.then_some(*num_threads),
+            )
+            .map(|report| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input);
+                    println!(
+                        "  Sample size: {} of {}",
+                        format_bytes(report.sample_bytes),
+                        format_bytes(report.input_bytes)
+                    );
+                    println!();
+                    println!("  Block size\tLevel\tFrames\tRatio\tCompress (s)\tDecode (MB/s)");
+                    for trial in &report.trials {
+                        let decode = trial
+                            .decode_mbps
+                            .map_or(String::from("-"), |d| format!("{:.1}", d));
+                        println!(
+                            "  {}\t{}\t{}\t{:.2}\t{:.3}\t{}",
+                            format_bytes(trial.block_size as u64),
+                            trial.level,
+                            trial.frames,
+                            trial.ratio,
+                            trial.compress_seconds,
+                            decode
+                        );
+                    }
+                    println!();
+                    let recommendation = report.recommendation();
+                    println!(
+                        "  Recommended: --block-size {} --level {}",
+                        Byte::from_u64(recommendation.block_size as u64)
+                            .get_appropriate_unit(UnitType::Binary)
+                            .to_string()
+                            .replace(' ', ""),
+                        recommendation.level
+                    );
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::Describe {
+            input,
+            zindex,
+            json,
+            num_threads,
+        } => parallel_decompression::perform_describe(input, zindex.as_deref(), *num_threads)
+            .and_then(|description| {
+                match json {
+                    true => println!("{}", serde_json::to_string_pretty(&description)?),
+                    false if !quiet => {
+                        println!("Success!");
+                        print_description(&description);
+                    }
+                    false => {}
+                }
+                Ok(RunStatus::Complete)
+            }),
+        Workflow::Doctor { input, sample_size } => {
+            parallel_decompression::perform_doctor(input.as_deref(), *sample_size).map(|report| {
+                if !quiet {
+                    println!("Success!");
+                    print_doctor_report(&report);
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::Generate {
+            records,
+            key_style,
+            value_max,
+            sorted,
+            duplicate_rate,
+            seed,
+            output,
+            zindex,
+            block_size,
+            level,
+            force,
+        } => {
+            let generate_options = GenerateOptions {
+                records: *records,
+                key_style: key_style.clone(),
+                value_max: *value_max,
+                sorted: *sorted,
+                duplicate_rate: *duplicate_rate,
+                seed: *seed,
+            };
+            parallel_decompression::perform_generate(
+                output,
+                zindex.as_deref(),
+                block_size,
+                *level,
+                &generate_options,
+                *force,
+            )
+            .map(|_| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Output file: {}", output);
+                    if let Some(zindex) = zindex {
+                        println!("  Index file:  {}", zindex);
+                    }
+                    println!("  Records written: {}", records);
+                    let (compressed, uncompressed) = match zindex {
+                        Some(_) => (file_size(output), None),
+                        None => (None, file_size(output)),
+                    };
+                    print_throughput(
+                        start.elapsed(),
+                        compressed,
+                        uncompressed,
+                        Some(*records as usize),
+                    );
+                }
+                RunStatus::Complete
+            })
+        }
         Workflow::Decompress {
             input,
             zindex,
+            input_codec,
+            verify_frames,
+            quarantine_index,
+            mode,
+            num_threads,
+            bad_record,
+            strict_utf8,
+            key_type,
+            value_type,
+            value_width,
+            value_columns,
+            key_columns,
+            key_joiner,
+            strip_key_version,
+            duplicate_keys,
+            partition_by_value,
+            output_dir,
+            partition_buckets,
+            estimate_cardinality,
+            top_values,
+            membership_index,
+            taxdump,
+            rank,
+            format,
+            trace_out,
+            stage_report,
+            read_args,
+            numa,
+            schedule,
+            from_snapshot,
+            save_snapshot,
+            estimate_memory,
+            memory_budget,
+            cross_check,
+        } => {
+            let parse_options = ParseOptions {
+                bad_record: bad_record.clone(),
+                strict_utf8: *strict_utf8,
+                format: format.clone(),
+                key_columns: key_columns.iter().map(|c| *c as usize).collect(),
+                key_joiner: key_joiner.clone(),
+                strip_key_version: *strip_key_version,
+                duplicate_keys: duplicate_keys.clone(),
+                value_columns: value_columns.iter().map(|c| *c as usize).collect(),
+                codec: input_codec.clone(),
+                verify_frames: *verify_frames,
+                schedule: schedule.clone(),
+                split_records: false,
+                profiler: (trace_out.is_some() || *stage_report)
+                    .then(|| Arc::new(StageProfiler::default())),
+                read_limiter: read_args.read_limiter(),
+                read_batcher: read_args.read_batcher(),
+                frame_cache: None,
+                numa_placement: *numa,
+                strict_index: user_inputs.strict,
+                retry: read_args.retry_policy(),
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                threads: thread_options.clone(),
+            };
+            let taxonomy_options = taxdump.as_ref().map(|t| TaxonomyOptions {
+                taxdump_dir: t.clone(),
+                rank: rank.clone(),
+            });
+
+            let decompression_result = match (output_dir, top_values) {
+                _ if *estimate_cardinality => parallel_decompression::perform_estimate_cardinality(
+                    input,
+                    zindex.as_deref(),
+                    *num_threads,
+                    &parse_options,
+                )
+                .map(|report| {
+                    if !quiet {
+                        println!("Success!");
+                        println!("  Input file:  {}", input);
+                        println!("  Index file:  {}", index_label(zindex.as_deref()));
+                        println!("  Total records processed: {}", report.records);
+                        println!("  Estimated distinct keys:   {}", report.distinct_keys);
+                        println!("  Estimated distinct values: {}", report.distinct_values);
+                        print_throughput(
+                            start.elapsed(),
+                            Some(byte_counts.bytes_read()),
+                            Some(byte_counts.bytes_decompressed()),
+                            Some(report.records),
+                        );
+                        print_bad_records(&report.summary);
+                    }
+                    report.run_status()
+                }),
+                _ if membership_index.is_some() => {
+                    let index_file = membership_index.as_deref().unwrap_or_default();
+                    parallel_decompression::perform_membership_index(
+                        input,
+                        zindex.as_deref(),
+                        *num_threads,
+                        mode,
+                        &parse_options,
+                        index_file,
+                    )
+                    .map(|report| {
+                        if !quiet {
+                            println!("Success!");
+                            println!("  Input file:  {}", input);
+                            println!("  Index file:  {}", index_label(zindex.as_deref()));
+                            println!("  Membership index: {}", index_file);
+                            println!("  Total records processed: {}", report.records);
+                            println!("  Distinct values: {}", report.values);
+                            println!(
+                                "  Membership index size: {}",
+                                Byte::from_u64(report.index_bytes)
+                                    .get_appropriate_unit(UnitType::Binary)
+                            );
+                            print_throughput(
+                                start.elapsed(),
+                                Some(byte_counts.bytes_read()),
+                                Some(byte_counts.bytes_decompressed()),
+                                Some(report.records),
+                            );
+                            print_bad_records(&report.summary);
+                        }
+                        report.run_status()
+                    })
+                }
+                (_, Some(k)) => parallel_decompression::perform_top_values(
+                    input,
+                    zindex.as_deref(),
+                    *num_threads,
+                    &parse_options,
+                    *k as usize,
+                )
+                .map(|report| {
+                    if !quiet {
+                        println!("Success!");
+                        println!("  Input file:  {}", input);
+                        println!("  Index file:  {}", index_label(zindex.as_deref()));
+                        println!("  Total records processed: {}", report.records);
+                        println!("  Distinct values: {}", report.distinct_values);
+                        println!("  Most frequent values:");
+                        println!("    {:>12}  Value", "Records");
+                        for (value, count) in &report.values {
+                            println!("    {:>12}  {}", count, value);
+                        }
+                        print_throughput(
+                            start.elapsed(),
+                            Some(byte_counts.bytes_read()),
+                            Some(byte_counts.bytes_decompressed()),
+                            Some(report.records),
+                        );
+                        print_bad_records(&report.summary);
+                    }
+                    report.run_status()
+                }),
+                (Some(output_dir), None) if *partition_by_value => {
+                    parallel_decompression::perform_partition(
+                        input,
+                        zindex.as_deref(),
+                        *num_threads,
+                        key_type,
+                        &parse_options,
+                        output_dir,
+                        *partition_buckets,
+                    )
+                    .map(|report| {
+                        if !quiet {
+                            println!("Success!");
+                            println!("  Input file:  {}", input);
+                            println!("  Index file:  {}", index_label(zindex.as_deref()));
+                            println!("  Output directory: {}", output_dir);
+                            println!("  Total records written: {}", report.records);
+                            print_throughput(
+                                start.elapsed(),
+                                Some(byte_counts.bytes_read()),
+                                Some(byte_counts.bytes_decompressed()),
+                                Some(report.records),
+                            );
+                            print_bad_records(&report.summary);
+                        }
+                        report.run_status()
+                    })
+                }
+                _ => parallel_decompression::perform_decompression(
+                    input,
+                    zindex.as_deref(),
+                    *num_threads,
+                    &MapOptions {
+                        mode: mode.clone(),
+                        key_type: key_type.clone(),
+                        value_type: value_type.clone(),
+                        value_width: value_width.clone(),
+                        from_snapshot: from_snapshot.clone(),
+                        save_snapshot: save_snapshot.clone(),
+                        estimate_memory: *estimate_memory,
+                        memory_budget: *memory_budget,
+                        cross_check: cross_check.clone(),
+                    },
+                    &parse_options,
+                    taxonomy_options.as_ref(),
+                )
+                .and_then(|report| {
+                    let quarantine = quarantine_index
+                        .as_ref()
+                        .map(|q| {
+                            parallel_decompression::perform_quarantine(
+                                input,
+                                zindex.as_deref(),
+                                *num_threads,
+                                &parse_options,
+                                &report.summary,
+                                q,
+                            )
+                        })
+                        .transpose()?;
+                    Ok((report, quarantine))
+                })
+                .and_then(|(report, quarantine)| {
+                    if !quiet {
+                        println!("Success!");
+                        println!("  Input file:  {}", input);
+                        println!("  Index file:  {}", index_label(zindex.as_deref()));
+                        println!("  Total records processed: {}", report.records);
+                        if let Some(memory_estimate) = &report.memory_estimate {
+                            print_memory_estimate(memory_estimate, mode);
+                        }
+                        if let (Some(r), Some(resolved)) = (rank, report.resolved) {
+                            println!("  Records resolved to rank '{}': {}", r, resolved);
+                        }
+                        print_throughput(
+                            start.elapsed(),
+                            Some(byte_counts.bytes_read()),
+                            Some(byte_counts.bytes_decompressed()),
+                            Some(report.records),
+                        );
+                        print_bad_records(&report.summary);
+                        if let (Some(q), Some(quarantine)) = (quarantine_index, &quarantine) {
+                            println!(
+                                "  Quarantine index: {} ({} frames kept, {} quarantined)",
+                                q,
+                                quarantine.frames_kept,
+                                quarantine.quarantined.len()
+                            );
+                            println!("  Quarantine report: {}", quarantine.report_file);
+                        }
+                        if let Some(cross_check) = &report.cross_check {
+                            print_cross_check(cross_check);
+                        }
+                    }
+
+                    if let Some(cross_check) = &report.cross_check
+                        && !cross_check.is_identical()
+                    {
+                        bail!(
+                            "The '{}' and '{}' modes built different maps, with {} divergent keys!",
+                            mode_name(&cross_check.modes.0),
+                            mode_name(&cross_check.modes.1),
+                            cross_check.divergent_keys
+                        );
+                    }
+                    Ok(report.run_status())
+                }),
+            };
+
+            decompression_result.and_then(|run_status| {
+                if let Some(profiler) = &parse_options.profiler {
+                    export_profile(profiler, trace_out.as_deref(), *stage_report, quiet)?;
+                }
+                Ok(run_status)
+            })
+        }
+        Workflow::ServeFrames {
+            input,
+            zindex,
+            bind,
+            frames_per_task,
+            collect,
+            metrics_bind,
+            bad_record,
+            strict_utf8,
+            format,
+        } => {
+            let parse_options = ParseOptions {
+                bad_record: bad_record.clone(),
+                strict_utf8: *strict_utf8,
+                format: format.clone(),
+                key_columns: Vec::new(),
+                key_joiner: String::new(),
+                strip_key_version: false,
+                duplicate_keys: DuplicatePolicy::default(),
+                value_columns: Vec::new(),
+                codec: Codec::Zstd,
+                verify_frames: false,
+                schedule: FrameSchedule::FileOrder,
+                split_records: false,
+                profiler: None,
+                read_limiter: None,
+                read_batcher: None,
+                frame_cache: None,
+                numa_placement: false,
+                strict_index: user_inputs.strict,
+                retry: RetryPolicy::default(),
+                events: event_log.clone(),
+                byte_counts: None,
+                threads: thread_options.clone(),
+            };
+            bind_listeners(bind, metrics_bind.as_deref(), quiet)
+                .and_then(|(listener, metrics_listener)| {
+                    parallel_decompression::perform_serve_frames(
+                        input,
+                        zindex,
+                        listener,
+                        *frames_per_task as usize,
+                        &parse_options,
+                        collect,
+                        metrics_listener,
+                    )
+                })
+                .map(|report| {
+                    if !quiet {
+                        println!("Success!");
+                        println!("  Input file:  {}", input);
+                        println!("  Index file:  {}", zindex);
+                        match collect {
+                            Collect::Map => {
+                                println!("  Total records processed: {}", report.records)
+                            }
+                            Collect::ValueCounts => {
+                                println!("  Distinct values observed: {}", report.records)
+                            }
+                        }
+                        // Frames are decoded by the workers, so only the run time is known here
+                        print_throughput(start.elapsed(), None, None, None);
+                        print_bad_records(&report.summary);
+                    }
+                    report.run_status()
+                })
+        }
+        Workflow::Extract {
+            input,
+            zindex,
+            input_codec,
+            verify_frames,
+            output,
+            num_threads,
+            resume,
+            checkpoint_interval,
+            read_args,
+        } => {
+            let parse_options = ParseOptions {
+                codec: input_codec.clone(),
+                verify_frames: *verify_frames,
+                read_limiter: read_args.read_limiter(),
+                read_batcher: read_args.read_batcher(),
+                retry: read_args.retry_policy(),
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            parallel_decompression::perform_extraction(
+                input,
+                zindex.as_deref(),
+                output,
+                *num_threads,
+                &parse_options,
+                *resume,
+                *checkpoint_interval,
+            )
+            .map(|summary| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input);
+                    println!("  Index file:  {}", index_label(zindex.as_deref()));
+                    println!("  Output file: {}", output);
+                    if summary.frames_skipped > 0 {
+                        println!(
+                            "  Frames resumed from checkpoint: {}",
+                            summary.frames_skipped
+                        );
+                    }
+                    println!("  Frames written: {}", summary.frames_written);
+                    println!("  Total bytes written: {}", summary.bytes_written);
+                    print_throughput(
+                        start.elapsed(),
+                        Some(byte_counts.bytes_read()),
+                        Some(byte_counts.bytes_decompressed()),
+                        None,
+                    );
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::FollowDecompress {
+            input,
+            zindex,
+            output,
+            verify_frames,
+            mode,
+            num_threads,
+            bad_record,
+            strict_utf8,
+            key_type,
+            value_type,
+            value_width,
+            duplicate_keys,
+            format,
+            poll_interval,
+            idle_timeout,
+            metrics_bind,
+        } => {
+            let parse_options = ParseOptions {
+                bad_record: bad_record.clone(),
+                strict_utf8: *strict_utf8,
+                format: format.clone(),
+                duplicate_keys: duplicate_keys.clone(),
+                verify_frames: *verify_frames,
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            if !quiet {
+                println!("Following '{}' (index '{}')", input, zindex);
+            }
+            bind_metrics_listener(metrics_bind.as_deref(), quiet).and_then(|metrics_listener| {
+                let follow_options = FollowOptions {
+                    poll_interval: Duration::from_millis(*poll_interval),
+                    idle_timeout: idle_timeout.map(Duration::from_secs),
+                    output_file: output.clone(),
+                    metrics_listener,
+                };
+                parallel_decompression::perform_follow_decompression(
+                    input,
+                    zindex,
+                    *num_threads,
+                    &MapOptions {
+                        mode: mode.clone(),
+                        key_type: key_type.clone(),
+                        value_type: value_type.clone(),
+                        value_width: value_width.clone(),
+                        from_snapshot: None,
+                        save_snapshot: None,
+                        estimate_memory: false,
+                        memory_budget: None,
+                        cross_check: None,
+                    },
+                    &parse_options,
+                    &follow_options,
+                    |update| {
+                        if quiet {
+                            return;
+                        }
+                        let held = match (update.records, update.bytes_written) {
+                            (Some(records), _) => format!("{} records held", records),
+                            (None, Some(bytes_written)) => {
+                                format!("{} bytes written", bytes_written)
+                            }
+                            (None, None) => String::new(),
+                        };
+                        println!(
+                            "  Frames {} to {} read: {}",
+                            update.first_order, update.last_order, held
+                        );
+                    },
+                )
+                .map(|report| {
+                    if !quiet {
+                        println!("Success!");
+                        println!("  Input file:  {}", input);
+                        println!("  Index file:  {}", zindex);
+                        if let Some(output) = output {
+                            println!("  Output file: {}", output);
+                        }
+                        println!("  Frames read: {}", report.frames_read);
+                        if let Some(records) = report.records {
+                            println!("  Total records processed: {}", records);
+                        }
+                        if let Some(bytes_written) = report.bytes_written {
+                            println!("  Total bytes written: {}", bytes_written);
+                        }
+                        print_throughput(
+                            start.elapsed(),
+                            Some(byte_counts.bytes_read()),
+                            Some(byte_counts.bytes_decompressed()),
+                            report.records,
+                        );
+                        print_bad_records(&report.summary);
+                    }
+                    report.run_status()
+                })
+            })
+        }
+        Workflow::Serve {
+            input,
+            zindex,
+            namespace,
+            route,
+            bind,
+            verify_frames,
             mode,
             num_threads,
-        } => parallel_decompression::perform_decompression(input, zindex, mode, *num_threads),
+            bad_record,
+            strict_utf8,
+            key_type,
+            value_type,
+            value_width,
+            duplicate_keys,
+            format,
+            from_snapshot,
+            save_snapshot,
+            watch_interval,
+            allow_reload,
+            max_connections,
+            metrics_bind,
+        } => {
+            let parse_options = ParseOptions {
+                bad_record: bad_record.clone(),
+                strict_utf8: *strict_utf8,
+                format: format.clone(),
+                duplicate_keys: duplicate_keys.clone(),
+                verify_frames: *verify_frames,
+                events: event_log.clone(),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            let mut archives_loaded: usize = 0;
+            served_archives(input, zindex, namespace).and_then(|archives| {
+                let serve_options = ServeOptions {
+                    archives,
+                    routes: route.clone(),
+                    watch_interval: watch_interval.map(Duration::from_secs),
+                    allow_reload: *allow_reload,
+                    max_connections: *max_connections as usize,
+                    metrics_listener: bind_metrics_listener(metrics_bind.as_deref(), quiet)?,
+                };
+                let listener = bind_lookup_listener(bind)?;
+                let listener_address = listener.local_addr()?;
+                parallel_decompression::perform_serve(
+                    &serve_options,
+                    listener,
+                    *num_threads,
+                    &MapOptions {
+                        mode: mode.clone(),
+                        key_type: key_type.clone(),
+                        value_type: value_type.clone(),
+                        value_width: value_width.clone(),
+                        from_snapshot: from_snapshot.clone(),
+                        save_snapshot: save_snapshot.clone(),
+                        estimate_memory: false,
+                        memory_budget: None,
+                        cross_check: None,
+                    },
+                    &parse_options,
+                    |archive, report| {
+                        archives_loaded += 1;
+                        if !quiet {
+                            println!(
+                                "Loaded {} records from '{}' as '{}'",
+                                report.records, archive.zstd_file, archive.namespace
+                            );
+                            print_bad_records(&report.summary);
+                            if archives_loaded == serve_options.archives.len() {
+                                println!("Answering lookups at http://{}/lookup", listener_address);
+                            }
+                        }
+                    },
+                )
+                .map(|_| RunStatus::Complete)
+            })
+        }
+        Workflow::Client {
+            command:
+                ClientWorkflow::Lookup {
+                    server,
+                    namespace,
+                    keys_file,
+                    output,
+                    batch_size,
+                },
+        } => parallel_decompression::perform_client_lookup(
+            server,
+            namespace.as_deref(),
+            keys_file,
+            output,
+            *batch_size as usize,
+        )
+        .map(|summary| {
+            if !quiet {
+                println!("Success!");
+                println!("  Server:      {}", server);
+                println!("  Keys file:   {}", keys_file);
+                println!("  Output file: {}", output);
+                println!("  Keys looked up: {}", summary.keys_sent);
+                println!("  Records found:  {}", summary.records_found);
+                println!("  Requests sent:  {}", summary.requests);
+            }
+            RunStatus::Complete
+        }),
+        Workflow::Mount {
+            input,
+            zindex,
+            mount_point,
+            verify_frames,
+            num_threads,
+            frame_cache,
+            frame_cache_size,
+            metrics_bind,
+        } => frame_cache
+            .as_deref()
+            .map(|d| FrameCache::open(d, *frame_cache_size).map(Arc::new))
+            .transpose()
+            .and_then(|frame_cache| {
+                let parse_options = ParseOptions {
+                    verify_frames: *verify_frames,
+                    events: event_log.clone(),
+                    strict_index: user_inputs.strict,
+                    threads: thread_options.clone(),
+                    frame_cache,
+                    ..Default::default()
+                };
+                parallel_decompression::perform_mount(
+                    input,
+                    zindex.as_deref(),
+                    mount_point,
+                    *num_threads,
+                    &parse_options,
+                    bind_metrics_listener(metrics_bind.as_deref(), quiet)?,
+                    |mounted_file, content_length| {
+                        if !quiet {
+                            println!(
+                                "Mounted {} bytes of '{}' at '{}' (unmount to stop)",
+                                content_length, input, mounted_file
+                            );
+                        }
+                    },
+                )
+            })
+            .map(|_| RunStatus::Complete),
+        Workflow::Join {
+            input,
+            zindex,
+            input_codec,
+            query,
+            output,
+            missing,
+            num_threads,
+            format,
+            key_columns,
+            key_joiner,
+            value_columns,
+            strip_key_version,
+        } => {
+            let parse_options = ParseOptions {
+                format: format.clone(),
+                key_columns: key_columns.iter().map(|c| *c as usize).collect(),
+                key_joiner: key_joiner.clone(),
+                strip_key_version: *strip_key_version,
+                value_columns: value_columns.iter().map(|c| *c as usize).collect(),
+                codec: input_codec.clone(),
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            parallel_decompression::perform_join(
+                input,
+                zindex.as_deref(),
+                query,
+                output.as_deref(),
+                missing.as_deref(),
+                *num_threads,
+                &parse_options,
+            )
+            .map(|report| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input);
+                    println!("  Index file:  {}", index_label(zindex.as_deref()));
+                    println!("  Query file:  {}", query);
+                    if let Some(output) = output {
+                        println!("  Output file: {}", output);
+                    }
+                    if let Some(missing) = missing {
+                        println!("  Missing keys file: {}", missing);
+                    }
+                    println!(
+                        "  Query keys found: {} of {}",
+                        report.keys_found, report.query_keys
+                    );
+                    println!("  Query keys missing: {}", report.keys_missing);
+                    println!("  Total records written: {}", report.records_written);
+                    if report.frames_skipped > 0 {
+                        println!("  Frames skipped by key range: {}", report.frames_skipped);
+                    }
+                    print_throughput(
+                        start.elapsed(),
+                        Some(byte_counts.bytes_read()),
+                        Some(byte_counts.bytes_decompressed()),
+                        Some(report.records_written),
+                    );
+                    print_bad_records(&report.summary);
+                }
+                report.run_status()
+            })
+        }
+        Workflow::MergeJoin {
+            input,
+            zindex,
+            right,
+            right_zindex,
+            output,
+            num_threads,
+            format,
+            value_columns,
+        } => {
+            let parse_options = ParseOptions {
+                format: format.clone(),
+                value_columns: value_columns.iter().map(|c| *c as usize).collect(),
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            parallel_decompression::perform_merge_join(
+                (input, zindex),
+                (right, right_zindex),
+                output,
+                *num_threads,
+                &parse_options,
+            )
+            .map(|report| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Left file:   {}", input);
+                    println!("  Right file:  {}", right);
+                    println!("  Output file: {}", output);
+                    println!("  Keys matched: {}", report.keys_matched);
+                    println!("  Total pairs written: {}", report.pairs_written);
+                    print_throughput(
+                        start.elapsed(),
+                        Some(byte_counts.bytes_read()),
+                        Some(byte_counts.bytes_decompressed()),
+                        Some(report.pairs_written),
+                    );
+                    print_bad_records(&report.left_summary);
+                    print_bad_records(&report.right_summary);
+                }
+                report.run_status()
+            })
+        }
+        Workflow::Repack {
+            input,
+            zindex,
+            input_codec,
+            verify_frames,
+            output,
+            output_index,
+            block_size,
+            level,
+            frame_metadata,
+            align,
+            num_threads,
+            force,
+        } => {
+            let parse_options = ParseOptions {
+                codec: input_codec.clone(),
+                verify_frames: *verify_frames,
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            let repack_options = RepackOptions {
+                block_size: block_size.clone(),
+                zstd_level: *level,
+                frame_metadata: *frame_metadata,
+                align: *align,
+                force: *force,
+            };
+            parallel_decompression::perform_repack(
+                input,
+                zindex.as_deref(),
+                output,
+                output_index,
+                *num_threads,
+                &parse_options,
+                &repack_options,
+            )
+            .map(|summary| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input);
+                    println!("  Index file:  {}", index_label(zindex.as_deref()));
+                    println!("  Output file: {}", output);
+                    println!("  Output index file: {}", output_index);
+                    println!(
+                        "  Frames repacked: {} -> {}",
+                        summary.frames_read, summary.frames_written
+                    );
+                    print_throughput(
+                        start.elapsed(),
+                        Some(byte_counts.bytes_read()),
+                        Some(byte_counts.bytes_decompressed()),
+                        None,
+                    );
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::Compact {
+            input,
+            zindex,
+            verify_frames,
+            output,
+            output_index,
+            format,
+            key_columns,
+            key_joiner,
+            strip_key_version,
+            block_size,
+            level,
+            frame_metadata,
+            align,
+            num_threads,
+            force,
+        } => {
+            let parse_options = ParseOptions {
+                format: format.clone(),
+                key_columns: key_columns.iter().map(|c| *c as usize).collect(),
+                key_joiner: key_joiner.clone(),
+                strip_key_version: *strip_key_version,
+                verify_frames: *verify_frames,
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            let repack_options = RepackOptions {
+                block_size: block_size.clone(),
+                zstd_level: *level,
+                frame_metadata: *frame_metadata,
+                align: *align,
+                force: *force,
+            };
+            parallel_decompression::perform_compact(
+                input,
+                zindex,
+                output,
+                output_index,
+                *num_threads,
+                &parse_options,
+                &repack_options,
+            )
+            .map(|summary| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input);
+                    println!("  Index file:  {}", zindex);
+                    println!("  Output file: {}", output);
+                    println!("  Output index file: {}", output_index);
+                    println!(
+                        "  Frames compacted: {} -> {}",
+                        summary.frames_read, summary.frames_written
+                    );
+                    println!("  Records kept: {}", summary.records_kept);
+                    println!("  Records superseded: {}", summary.records_superseded);
+                    println!("  Keys deleted: {}", summary.keys_deleted);
+                    print_throughput(
+                        start.elapsed(),
+                        Some(byte_counts.bytes_read()),
+                        Some(byte_counts.bytes_decompressed()),
+                        Some(summary.records_kept),
+                    );
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::Explode {
+            input,
+            zindex,
+            output_dir,
+            num_threads,
+            force,
+        } => {
+            let parse_options = ParseOptions {
+                events: event_log.clone(),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            parallel_decompression::perform_explode(
+                input,
+                zindex,
+                output_dir,
+                *num_threads,
+                &parse_options,
+                *force,
+            )
+            .map(|summary| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input);
+                    println!("  Index file:  {}", zindex);
+                    println!("  Output directory: {}", output_dir);
+                    println!("  Shards written: {}", summary.frames_written);
+                    print_throughput(start.elapsed(), Some(summary.bytes_written), None, None);
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::Assemble {
+            input_dir,
+            manifest,
+            output,
+            output_index,
+            force,
+        } => parallel_decompression::perform_assemble(
+            input_dir,
+            manifest.as_deref(),
+            output,
+            output_index,
+            *force,
+        )
+        .map(|summary| {
+            if !quiet {
+                println!("Success!");
+                println!("  Input directory: {}", input_dir);
+                println!("  Output file: {}", output);
+                println!("  Output index file: {}", output_index);
+                println!(
+                    "  Shards assembled: {} ({} frames)",
+                    summary.shards_read, summary.frames_written
+                );
+                print_throughput(start.elapsed(), Some(summary.bytes_written), None, None);
+            }
+            RunStatus::Complete
+        }),
+        Workflow::Index {
+            command:
+                IndexWorkflow::Export {
+                    zindex,
+                    output,
+                    format,
+                    force,
+                },
+        } => parallel_decompression::perform_index_export(zindex, output, format, *force).map(
+            |frames| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Index file:  {}", zindex);
+                    println!("  Output file: {}", output);
+                    println!("  Frames exported: {}", frames);
+                }
+                RunStatus::Complete
+            },
+        ),
+        Workflow::Index {
+            command:
+                IndexWorkflow::Edit {
+                    zindex,
+                    output,
+                    drop,
+                    keep,
+                    reorder,
+                    renumber,
+                    force,
+                },
+        } => IndexEdit::parse(
+            drop.as_deref(),
+            keep.as_deref(),
+            reorder.as_deref(),
+            *renumber,
+        )
+        .and_then(|index_edit| {
+            parallel_decompression::perform_index_edit(zindex, output, &index_edit, *force)
+        })
+        .map(|(frames_read, frames_kept)| {
+            if !quiet {
+                println!("Success!");
+                println!("  Index file:  {}", zindex);
+                println!("  Output index file: {}", output);
+                println!("  Frames kept: {} of {}", frames_kept, frames_read);
+            }
+            RunStatus::Complete
+        }),
+        Workflow::Index {
+            command:
+                IndexWorkflow::Import {
+                    input,
+                    output,
+                    archive,
+                    force,
+                },
+        } => {
+            parallel_decompression::perform_index_import(input, output, archive.as_deref(), *force)
+                .map(|frames| {
+                    if !quiet {
+                        println!("Success!");
+                        println!("  Input file:  {}", input);
+                        println!("  Output index file: {}", output);
+                        println!("  Frames imported: {}", frames);
+                    }
+                    RunStatus::Complete
+                })
+        }
+        Workflow::CheckUnique {
+            input,
+            zindex,
+            input_codec,
+            num_threads,
+            format,
+            key_columns,
+            key_joiner,
+            strip_key_version,
+            report,
+        } => {
+            let parse_options = ParseOptions {
+                format: format.clone(),
+                key_columns: key_columns.iter().map(|c| *c as usize).collect(),
+                key_joiner: key_joiner.clone(),
+                strip_key_version: *strip_key_version,
+                codec: input_codec.clone(),
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            parallel_decompression::perform_check_unique(
+                input,
+                zindex.as_deref(),
+                *num_threads,
+                &parse_options,
+            )
+            .and_then(|unique_report| {
+                if let Some(report) = report {
+                    write_duplicate_report(report, &unique_report.duplicates)?;
+                }
+                if !quiet {
+                    match unique_report.duplicates.is_empty() {
+                        true => println!("Success!"),
+                        false => println!("Duplicate keys found!"),
+                    }
+                    println!("  Input file:  {}", input);
+                    println!("  Index file:  {}", index_label(zindex.as_deref()));
+                    println!(
+                        "  Checked as: {}",
+                        match unique_report.sorted {
+                            true => "key-sorted, comparing neighbouring records",
+                            false => "unsorted, gathering every key",
+                        }
+                    );
+                    println!("  Total records checked: {}", unique_report.records_checked);
+                    println!("  Duplicate keys: {}", unique_report.duplicates.len());
+                    if report.is_none() {
+                        for duplicate in &unique_report.duplicates {
+                            println!("    {}", duplicate.key);
+                            for (order, value) in &duplicate.occurrences {
+                                println!("      Frame {}: '{}'", order, value);
+                            }
+                        }
+                    }
+                    print_throughput(
+                        start.elapsed(),
+                        Some(byte_counts.bytes_read()),
+                        Some(byte_counts.bytes_decompressed()),
+                        Some(unique_report.records_checked),
+                    );
+                    print_bad_records(&unique_report.summary);
+                }
+
+                if !unique_report.duplicates.is_empty() {
+                    bail!(PipelineError::CorruptArchive(format!(
+                        "Keys held by more than one record: {}",
+                        unique_report.duplicates.len()
+                    )));
+                }
+                Ok(unique_report.run_status())
+            })
+        }
+        Workflow::Sort {
+            input,
+            zindex,
+            verify_frames,
+            output,
+            output_index,
+            format,
+            block_size,
+            level,
+            frame_metadata,
+            align,
+            num_threads,
+            force,
+        } => {
+            let parse_options = ParseOptions {
+                format: format.clone(),
+                verify_frames: *verify_frames,
+                events: event_log.clone(),
+                byte_counts: Some(Arc::clone(&byte_counts)),
+                strict_index: user_inputs.strict,
+                threads: thread_options.clone(),
+                ..Default::default()
+            };
+            let repack_options = RepackOptions {
+                block_size: block_size.clone(),
+                zstd_level: *level,
+                frame_metadata: *frame_metadata,
+                align: *align,
+                force: *force,
+            };
+            parallel_decompression::perform_sort(
+                input,
+                zindex.as_deref(),
+                output,
+                output_index,
+                *num_threads,
+                &parse_options,
+                &repack_options,
+            )
+            .map(|summary| {
+                if !quiet {
+                    println!("Success!");
+                    println!("  Input file:  {}", input);
+                    println!("  Index file:  {}", index_label(zindex.as_deref()));
+                    println!("  Output file: {}", output);
+                    println!("  Output index file: {}", output_index);
+                    println!(
+                        "  Frames sorted: {} -> {}",
+                        summary.frames_read, summary.frames_written
+                    );
+                    println!("  Total records written: {}", summary.records_written);
+                    print_throughput(
+                        start.elapsed(),
+                        Some(byte_counts.bytes_read()),
+                        Some(byte_counts.bytes_decompressed()),
+                        Some(summary.records_written),
+                    );
+                }
+                RunStatus::Complete
+            })
+        }
+        Workflow::Worker {
+            coordinator,
+            input,
+            num_threads,
+        } => parallel_decompression::perform_worker(
+            coordinator,
+            input.as_deref(),
+            *num_threads,
+            &thread_options,
+        )
+        .map(|tasks_completed| {
+            if !quiet {
+                println!("Success!");
+                println!("  Coordinator: {}", coordinator);
+                println!("  Tasks completed: {}", tasks_completed);
+                print_throughput(start.elapsed(), None, None, None);
+            }
+            RunStatus::Complete
+        }),
+        Workflow::Completions { .. } => unreachable!("Completions are written before any run"),
     };
 
     match operation_results {
-        Ok(_) => println!("\nCompleted!"),
+        Ok(RunStatus::Complete) => {
+            if !quiet {
+                println!("\nCompleted!");
+            }
+        }
+        Ok(RunStatus::Partial) => {
+            if !quiet {
+                println!("\nCompleted with errors!");
+            }
+            if user_inputs.strict {
+                std::process::exit(RunStatus::PARTIAL_EXIT_CODE);
+            }
+        }
         Err(e) => {
-            eprintln!("Operation failed!\n");
-            eprintln!("{}", e);
+            let error_class = ErrorClass::of(&e);
+            match &event_log {
+                Some(event_log) => event_log.emit(&Event::Error {
+                    class: error_class,
+                    exit_code: error_class.exit_code(),
+                    message: format!("{:#}", e),
+                }),
+                None => {
+                    eprintln!("Operation failed!\n");
+                    eprintln!("{:#}", e);
+                }
+            }
+            std::process::exit(error_class.exit_code());
         }
     }
 }
 
-#[derive(Parser)]
-#[clap(author="David Waite", version, about, long_about=None)]
-struct ArgumentParser {
-    #[command(subcommand)]
-    command: Workflow,
+fn print_bad_records(summary: &DecompressionSummary) {
+    if !summary.failed_frames.is_empty() {
+        println!("  Unreadable frames: {}", summary.failed_frames.len());
+        for failed_frame in &summary.failed_frames {
+            println!(
+                "    Frame {} at position {}: {:?}",
+                failed_frame.order, failed_frame.position, failed_frame.error
+            );
+        }
+    }
+
+    if summary.zeroed_records > 0 {
+        println!(
+            "  Malformed records loaded as zero: {}",
+            summary.zeroed_records
+        );
+    }
+    if summary.skipped_records > 0 {
+        println!("  Malformed records skipped: {}", summary.skipped_records);
+    }
+
+    if !summary.bad_records.is_empty() {
+        println!("  Malformed records: {}", summary.bad_records.len());
+        for record in &summary.bad_records {
+            println!(
+                "    Frame {}, offset {}: '{}'",
+                record.order, record.offset, record.line
+            );
+        }
+    }
 }
 
-#[derive(clap::Subcommand)]
-enum Workflow {
-    /// Create an indexed zstd compression of the target input file
-    Compress {
-        /// The input file to which taxonomic information is appended (REQUIRED)
-        #[clap(short, long, value_parser, value_name = "INPUT")]
-        input: String,
+/// Write each record of a repeated key as a KEY<TAB>FRAME<TAB>VALUE line.
+fn write_duplicate_report(report_file: &str, duplicates: &[DuplicateKey]) -> Result<()> {
+    let mut report_writer = match std::fs::File::create(report_file) {
+        Ok(f) => std::io::BufWriter::new(f),
+        Err(e) => bail!("Unable to create report file '{}': {}", report_file, e),
+    };
+    for duplicate in duplicates {
+        for (order, value) in &duplicate.occurrences {
+            writeln!(report_writer, "{}\t{}\t{}", duplicate.key, order, value)?;
+        }
+    }
+    report_writer.flush()?;
+    Ok(())
+}
 
-        /// Target file to store the blocked zstd payload (REQUIRED)
-        #[clap(short, long, value_parser, value_name = "OUTPUT")]
-        output: String,
+fn index_label(idx_file: Option<&str>) -> &str {
+    idx_file.unwrap_or("none (frames located by scanning)")
+}
 
-        /// Target file to store the blocked zstd index (REQUIRED)
-        #[clap(short, long, value_parser, value_name = "INDEX")]
-        zindex: String,
+fn file_size(file_path: &str) -> Option<u64> {
+    std::fs::metadata(file_path).map(|m| m.len()).ok()
+}
 
-        /// The block size for compression (supports human-readable formats e.g. '64KiB, 128MiB, 2GB')
-        #[clap(short, long, default_value_t = String::from("64KiB"), value_name = "BLOCK_SIZE")]
-        block_size: String,
+fn format_bytes(bytes: u64) -> String {
+    let adjusted_bytes = Byte::from_u64(bytes).get_appropriate_unit(UnitType::Binary);
+    format!("{:.2}", adjusted_bytes)
+}
 
-        /// Compression level for zstd
-        #[clap(short, long, default_value_t = 3, value_name = "COMPRESSION")]
-        level: i32,
-    },
+fn mode_name(mode: &Mode) -> String {
+    mode.to_possible_value()
+        .map_or(String::new(), |v| v.get_name().to_string())
+}
 
-    /// Read an indexed zstd compression and parse results to a HashMap
-    Decompress {
-        /// The zstd file to be decompressed and parsed (REQUIRED)
-        #[clap(short, long, value_parser, value_name = "INPUT")]
-        input: String,
+/// Report the predicted peak memory of each mode, marking the mode of the run.
+fn print_memory_estimate(memory_estimate: &MemoryEstimate, mode: &Mode) {
+    println!(
+        "  Estimated peak memory (records counted in {} of {} frames):",
+        memory_estimate.frames_counted, memory_estimate.frames
+    );
+    for (estimate_mode, peak_bytes) in &memory_estimate.peaks {
+        let mode_name = mode_name(estimate_mode);
+        let marker = match estimate_mode == mode {
+            true => " (this run)",
+            false => "",
+        };
+        println!(
+            "    {:<10} {}{}",
+            mode_name,
+            format_bytes(*peak_bytes),
+            marker
+        );
+    }
+}
 
-        /// The zstd index file to be decompressed and parsed (REQUIRED)
-        #[clap(short, long, value_parser, value_name = "INDEX")]
-        zindex: String,
+/// Report the comparison of the maps built under two modes, listing the keys which differ.
+fn print_cross_check(cross_check: &CrossCheckReport) {
+    println!(
+        "  Cross-check against '{}': {} of {} keys differ",
+        mode_name(&cross_check.modes.1),
+        cross_check.divergent_keys,
+        cross_check.keys_compared
+    );
+    let missing = String::from("(missing)");
+    for divergent in &cross_check.divergent {
+        println!(
+            "    {}\t{}: {}\t{}: {}",
+            divergent.key,
+            mode_name(&cross_check.modes.0),
+            divergent.value.as_ref().unwrap_or(&missing),
+            mode_name(&cross_check.modes.1),
+            divergent.other_value.as_ref().unwrap_or(&missing)
+        );
+    }
+    if cross_check.divergent.len() < cross_check.divergent_keys {
+        println!(
+            "    ... and {} more",
+            cross_check.divergent_keys - cross_check.divergent.len()
+        );
+    }
+}
 
-        /// Number of threads to use for parallel file parsing
-        #[clap(short, long, default_value_t = 1, value_name = "THREADS")]
-        num_threads: usize,
+/// Report the peak memory of the run, and the allocations made when built with 'alloc-stats'.
+fn print_memory_usage(memory_usage: &MemoryUsage) {
+    if let Some(peak_rss_bytes) = memory_usage.peak_rss_bytes {
+        println!("  Peak resident memory: {}", format_bytes(peak_rss_bytes));
+    }
+    if let Some(allocator) = &memory_usage.allocator {
+        println!(
+            "  Allocations: {} ({} allocated, peak heap {})",
+            allocator.allocations,
+            format_bytes(allocator.allocated_bytes),
+            format_bytes(allocator.peak_heap_bytes)
+        );
+    }
+}
 
-        /// Method for gathering zstd frame results
-        #[clap(long, default_value_t = Mode::DashMap, value_name = "MODE", value_enum)]
-        mode: Mode,
+/// Report run time, sizes and rates. The rates are taken against the uncompressed size, being
+/// the volume of records which the run actually worked through.
+fn print_throughput(
+    elapsed: Duration,
+    compressed_bytes: Option<u64>,
+    uncompressed_bytes: Option<u64>,
+    records: Option<usize>,
+) {
+    let seconds = elapsed.as_secs_f64();
+    println!("  Elapsed time: {:.3} s", seconds);
+
+    if let Some(compressed_bytes) = compressed_bytes {
+        println!("  Compressed size:   {}", format_bytes(compressed_bytes));
+    }
+
+    if let Some(uncompressed_bytes) = uncompressed_bytes {
+        println!("  Uncompressed size: {}", format_bytes(uncompressed_bytes));
+    }
+
+    if let (Some(c), Some(u)) = (compressed_bytes, uncompressed_bytes)
+        && c > 0
+    {
+        println!("  Compression ratio: {:.2}", u as f64 / c as f64);
+    }
+
+    print_memory_usage(&MemoryUsage::current());
+
+    if seconds <= 0.0 {
+        return;
+    }
+
+    if let Some(rate) = uncompressed_bytes.and_then(|u| Byte::from_f64(u as f64 / seconds)) {
+        println!("  Throughput: {:.2}/s", rate.get_adjusted_unit(Unit::MB));
+    }
+
+    if let Some(records) = records {
+        println!("  Records per second: {:.0}", records as f64 / seconds);
+    }
+}
+
+fn print_description(description: &ArchiveDescription) {
+    let unknown = || String::from("unknown");
+    println!("  Archive: {}", description.archive);
+    println!(
+        "  Index: {}",
+        description
+            .index
+            .as_deref()
+            .unwrap_or("none, frames found by scanning")
+    );
+    println!("  Format version: {}", description.format_version);
+    println!("  Codec: {}", description.codec);
+    println!("  Frames: {}", description.frames);
+    if let Some(archive_bytes) = description.archive_bytes {
+        println!("  Archive size: {}", format_bytes(archive_bytes));
+    }
+    println!("  Frame bytes: {}", format_bytes(description.frame_bytes));
+    println!(
+        "  Uncompressed size: {}",
+        description
+            .uncompressed_bytes
+            .map_or_else(unknown, format_bytes)
+    );
+    println!(
+        "  Largest frame: {}",
+        description
+            .largest_frame_bytes
+            .map_or_else(unknown, format_bytes)
+    );
+    println!(
+        "  Records: {}",
+        description.records.map_or_else(unknown, |r| r.to_string())
+    );
+    if let Some((low, high)) = description.levels {
+        println!("  Levels: {} to {}", low, high);
+    }
+    if let Some(window_bytes) = description.window_bytes {
+        println!("  Window size: {}", format_bytes(window_bytes));
+    }
+    let listed = |items: &[String]| match items.is_empty() {
+        true => String::from("none"),
+        false => items.join(", "),
+    };
+    println!("  Checksums: {}", listed(&description.checksums));
+    println!("  Flags: {}", listed(&description.flags));
+
+    match &description.created_by {
+        Some(header) => {
+            println!("  Created: {}", format_timestamp(header.created));
+            println!("  Created with: version {}", header.tool_version);
+            println!(
+                "  Created on: {}",
+                header.hostname.as_deref().unwrap_or("unknown host")
+            );
+            println!("  Command line: {}", header.command_line.join(" "));
+            for input in &header.inputs {
+                println!(
+                    "  Input: {} ({}, xxh3 {:016x})",
+                    input.path,
+                    format_bytes(input.bytes),
+                    input.checksum
+                );
+            }
+        }
+        None => println!("  Created: unknown, the index has no header"),
+    }
+}
+
+/// Format seconds since the Unix epoch as a UTC date and time.
+fn format_timestamp(seconds: u64) -> String {
+    // Civil date from days since the epoch, counted in 400 year eras of 146097 days from March
+    let days = seconds / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = match shifted_month < 10 {
+        true => shifted_month + 3,
+        false => shifted_month - 9,
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    let time_of_day = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+fn print_doctor_report(report: &DoctorReport) {
+    let limits = &report.resource_limits;
+    println!("  Version: {}", long_version().replace('\n', ", "));
+    println!("  zstd: {}", report.zstd_version);
+    println!(
+        "  CPUs: {}{}",
+        limits.cpus,
+        match limits.cpu_quota {
+            true => " (held down by a cgroup CPU quota)",
+            false => "",
+        }
+    );
+    println!("  NUMA nodes: {}", report.numa_nodes);
+    println!(
+        "  Memory limit: {}",
+        limits
+            .memory_limit
+            .map_or(String::from("none"), format_bytes)
+    );
+    if let Some(page_cache) = &report.page_cache {
+        println!(
+            "  Memory total:     {}",
+            format_bytes(page_cache.total_bytes)
+        );
+        println!(
+            "  Memory available: {}",
+            format_bytes(page_cache.available_bytes)
+        );
+        println!(
+            "  Page cache:       {}",
+            format_bytes(page_cache.cached_bytes)
+        );
+    }
+    if let Some(read_sample) = &report.read_sample {
+        println!(
+            "  Read rate: {:.1} MB/s ({} read across {} in {:.3} s)",
+            read_sample.mbps,
+            format_bytes(read_sample.bytes_read),
+            format_bytes(read_sample.file_bytes),
+            read_sample.seconds
+        );
+    }
+    println!();
+    println!("  Recommended:");
+    for recommendation in &report.recommendations {
+        println!("    {}: {}", recommendation.flag, recommendation.reason);
+    }
+}
+
+fn print_stage_summary(summary: &StageSummary) {
+    println!("  Bytes read:         {}", summary.bytes_read);
+    println!("  Bytes decompressed: {}", summary.bytes_decompressed);
+    println!("  Time per stage (ms):");
+
+    let mut header = format!("    {:<28}", "Thread");
+    for stage in Stage::ALL {
+        header.push_str(&format!("{:>10}", stage.name()));
+    }
+    println!("{}", header);
+
+    let mut totals = [Duration::ZERO; 4];
+    for thread_stages in &summary.threads {
+        let mut row = format!("    {:<28}", thread_stages.thread);
+        for (i, stage_time) in thread_stages.stage_time.iter().enumerate() {
+            row.push_str(&format!("{:>10.3}", stage_time.as_secs_f64() * 1e3));
+            totals[i] += *stage_time;
+        }
+        println!("{}", row);
+    }
+
+    let mut row = format!("    {:<28}", "Total");
+    for stage_time in totals {
+        row.push_str(&format!("{:>10.3}", stage_time.as_secs_f64() * 1e3));
+    }
+    println!("{}", row);
+}
+
+fn export_profile(
+    profiler: &StageProfiler,
+    trace_file: Option<&str>,
+    stage_report: bool,
+    quiet: bool,
+) -> Result<()> {
+    if let Some(trace_file) = trace_file {
+        profiler.write_chrome_trace(trace_file)?;
+        if !quiet {
+            println!("  Trace file:  {}", trace_file);
+            println!("  Trace events recorded: {}", profiler.len());
+        }
+    }
+
+    // The stage report was asked for explicitly, so is printed even when quiet
+    if stage_report {
+        print_stage_summary(&profiler.summary());
+    }
+
+    Ok(())
+}
+
+/// Pair each served archive with its index and namespace, which are given in the order of the
+/// inputs.
+fn served_archives(
+    inputs: &[String],
+    zindexes: &[String],
+    namespaces: &[String],
+) -> Result<Vec<ServedArchive>> {
+    if !zindexes.is_empty() && zindexes.len() != inputs.len() {
+        bail!(PipelineError::Usage(format!(
+            "{} index files were given for {} inputs!",
+            zindexes.len(),
+            inputs.len()
+        )));
+    }
+    if !namespaces.is_empty() && namespaces.len() != inputs.len() {
+        bail!(PipelineError::Usage(format!(
+            "{} namespaces were given for {} inputs!",
+            namespaces.len(),
+            inputs.len()
+        )));
+    }
+
+    Ok(inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            let namespace = match namespaces.get(i) {
+                Some(n) => n.clone(),
+                None => {
+                    let file_name = Path::new(input)
+                        .file_name()
+                        .map_or(input.clone(), |f| f.to_string_lossy().to_string());
+                    match file_name.rsplit_once('.') {
+                        Some((stem, "zstd" | "zst")) => stem.to_string(),
+                        _ => file_name,
+                    }
+                }
+            };
+            ServedArchive {
+                namespace,
+                zstd_file: input.clone(),
+                idx_file: zindexes.get(i).cloned(),
+            }
+        })
+        .collect())
+}
+
+fn bind_lookup_listener(bind_address: &str) -> Result<TcpListener> {
+    match TcpListener::bind(bind_address) {
+        Ok(l) => Ok(l),
+        Err(e) => bail!("Unable to bind lookup service to '{}': {}", bind_address, e),
+    }
+}
+
+fn bind_listeners(
+    bind_address: &str,
+    metrics_address: Option<&str>,
+    quiet: bool,
+) -> Result<(TcpListener, Option<TcpListener>)> {
+    let listener = match TcpListener::bind(bind_address) {
+        Ok(l) => l,
+        Err(e) => bail!("Unable to bind coordinator to '{}': {}", bind_address, e),
+    };
+    if !quiet {
+        println!("Coordinator listening on {}", listener.local_addr()?);
+    }
+
+    let metrics_listener = bind_metrics_listener(metrics_address, quiet)?;
+    Ok((listener, metrics_listener))
+}
+
+fn bind_metrics_listener(
+    metrics_address: Option<&str>,
+    quiet: bool,
+) -> Result<Option<TcpListener>> {
+    let metrics_listener = match metrics_address {
+        Some(metrics_address) => match TcpListener::bind(metrics_address) {
+            Ok(l) => Some(l),
+            Err(e) => bail!(
+                "Unable to bind metrics endpoint to '{}': {}",
+                metrics_address,
+                e
+            ),
+        },
+        None => None,
+    };
+    if let (Some(metrics_listener), false) = (&metrics_listener, quiet) {
+        println!(
+            "Metrics available at http://{}/metrics",
+            metrics_listener.local_addr()?
+        );
+    }
+
+    Ok(metrics_listener)
+}
+
+/// The subcommands taking each setting of the config file, and its matching 'PD_*' environment
+/// variable. A subcommand given a new option of this kind must be listed here as well.
+const CONFIG_SUBCOMMANDS: &[(&str, &[&str])] = &[
+    (
+        "block_size",
+        &["compress", "generate", "repack", "compact", "sort"],
+    ),
+    (
+        "level",
+        &["compress", "generate", "repack", "compact", "sort"],
+    ),
+    ("mode", &["decompress", "follow-decompress", "serve"]),
+    (
+        "num_threads",
+        &[
+            "check-unique",
+            "compact",
+            "decompress",
+            "describe",
+            "explode",
+            "extract",
+            "follow-decompress",
+            "join",
+            "merge-join",
+            "mount",
+            "repack",
+            "serve",
+            "sort",
+            "tune",
+            "worker",
+        ],
+    ),
+    ("memory_budget", &["decompress"]),
+];
+
+/// Replace the built-in defaults with those from the config file. Environment variables and
+/// command line values are resolved by clap, so still take precedence over these.
+fn apply_config_defaults(
+    mut command: clap::Command,
+    config: &Config,
+    resource_limits: &ResourceLimits,
+) -> clap::Command {
+    // Without a configured thread count, use what the CPUs and memory of the container allow,
+    // and without a configured budget, the map may take up to the memory limit of the container
+    let num_threads = config
+        .num_threads
+        .unwrap_or_else(|| resource_limits.default_threads());
+    let memory_budget = config
+        .memory_budget
+        .clone()
+        .or_else(|| resource_limits.memory_limit.map(|m| m.to_string()));
+
+    for (setting, subcommands) in CONFIG_SUBCOMMANDS {
+        let default_value = match *setting {
+            "block_size" => config.block_size.clone(),
+            "level" => config.level.map(|l| l.to_string()),
+            "mode" => config
+                .mode
+                .as_ref()
+                .and_then(ValueEnum::to_possible_value)
+                .map(|m| m.get_name().to_string()),
+            "num_threads" => Some(num_threads.to_string()),
+            "memory_budget" => memory_budget.clone(),
+            _ => None,
+        };
+        let Some(default_value) = default_value else {
+            continue;
+        };
+        for subcommand in *subcommands {
+            command = command.mut_subcommand(subcommand, |s| {
+                s.mut_arg(setting, |a| a.default_value(default_value.clone()))
+            });
+        }
+    }
+
+    command
+}
+
+/// Replace the defaults of the compress options with those of a profile. Environment variables
+/// and command line values are resolved by clap, so still take precedence over these. Reading on
+/// several threads needs plain input which is not validated, so is left off for any other input.
+fn apply_profile_defaults(
+    command: clap::Command,
+    profile: &Profile,
+    matches: &clap::ArgMatches,
+    resource_limits: &ResourceLimits,
+) -> clap::Command {
+    let defaults = profile.defaults();
+    let plain_input = matches.subcommand_matches("compress").is_some_and(|m| {
+        m.value_source("validate").is_none() && m.value_source("input_codec").is_none()
+    });
+
+    command.mut_subcommand("compress", |mut s| {
+        s = s
+            .mut_arg("block_size", |a| a.default_value(defaults.block_size))
+            .mut_arg("level", |a| a.default_value(defaults.level.to_string()));
+        if defaults.frame_metadata {
+            s = s.mut_arg("frame_metadata", |a| a.default_value("true"));
+        }
+        if let Some(align) = defaults.align {
+            s = s.mut_arg("align", |a| a.default_value(align.to_string()));
+        }
+        if defaults.parallel_read && plain_input {
+            let read_threads = resource_limits.default_threads().to_string();
+            s = s.mut_arg("read_threads", |a| a.default_value(read_threads));
+        }
+        s
+    })
+}
+
+/// Warn when more threads are asked for than the CPU quota of the container allows, as the
+/// threads would then take turns on the CPUs rather than run together.
+fn warn_oversubscribed(matches: &clap::ArgMatches, resource_limits: &ResourceLimits) {
+    let num_threads = matches
+        .subcommand()
+        .and_then(|(_, m)| m.try_get_one::<usize>("num_threads").ok().flatten());
+    if let Some(num_threads) = num_threads
+        && resource_limits.cpu_quota
+        && *num_threads > resource_limits.cpus
+    {
+        eprintln!(
+            "WARNING: {} threads were asked for, but the CPU quota of this container allows {}!",
+            num_threads, resource_limits.cpus
+        );
+    }
+}
+
+fn parse_alignment(s: &str) -> Result<u64, String> {
+    match Byte::parse_str(s, true).map(|b| b.as_u64()) {
+        Ok(a) if a.is_power_of_two() && a <= 1 << 30 => Ok(a),
+        _ => Err(String::from("must be a power of two no larger than 1GiB")),
+    }
+}
+
+fn parse_byte_size(s: &str) -> Result<u64, String> {
+    match Byte::parse_str(s, true).map(|b| b.as_u64()) {
+        Ok(b) if b > 0 && b <= 1 << 32 => Ok(b),
+        _ => Err(String::from("must be a size between 1B and 4GiB")),
+    }
+}
+
+fn parse_memory_size(s: &str) -> Result<u64, String> {
+    match Byte::parse_str(s, true).map(|b| b.as_u64()) {
+        Ok(b) if b > 0 => Ok(b),
+        _ => Err(String::from("must be a size of at least 1B")),
+    }
+}
+
+fn parse_route(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((prefix, namespace)) if !prefix.is_empty() && !namespace.is_empty() => {
+            Ok((prefix.to_string(), namespace.to_string()))
+        }
+        _ => Err(String::from("must be given as PREFIX=NAMESPACE")),
+    }
+}
+
+fn parse_read_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if r.is_finite() && r > 0.0 => Ok(r),
+        _ => Err(String::from("must be a positive number of MB/s")),
+    }
+}
+
+#[derive(Subcommand)]
+enum IndexWorkflow {
+    /// Write a frame index out as CSV (order, offset, length, checksum, level, first_key, last_key) for other tools
+    Export {
+        /// The zstd index file to export ('-' reads it from stdin, and a plain http:// URL fetches it) (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// Target file for the exported index (REQUIRED)
+        #[clap(short, long, value_name = "OUTPUT")]
+        output: String,
+
+        /// Layout of the exported index
+        #[clap(long, default_value_t = IndexFormat::Csv, value_name = "FORMAT", value_enum)]
+        format: IndexFormat,
+
+        /// Overwrite the output file if it already exists
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Write a new index over the same payload with frames dropped, held to a range or reordered, such as to quarantine corrupt frames
+    Edit {
+        /// The zstd index file to edit ('-' reads it from stdin, and a plain http:// URL fetches it) (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// Target file for the edited index (REQUIRED)
+        #[clap(short, long, value_name = "OUTPUT")]
+        output: String,
+
+        /// Orders of the frames to drop, as a comma-separated list of orders and inclusive ranges (e.g. '3,7-9')
+        #[clap(long, value_name = "ORDERS")]
+        drop: Option<String>,
+
+        /// Keep only the frames within an inclusive range of orders (e.g. '0-99')
+        #[clap(long, value_name = "RANGE")]
+        keep: Option<String>,
+
+        /// Read the remaining frames in this sequence of orders, which must list each of them once (e.g. '2,0,1'). The frames are numbered afresh in their new sequence
+        #[clap(long, value_name = "ORDERS")]
+        reorder: Option<String>,
+
+        /// Number the remaining frames from zero, rather than keeping their orders
+        #[clap(long)]
+        renumber: bool,
+
+        /// Overwrite the output file if it already exists
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Write a frame index from CSV, under a header naming at least the order, offset and length columns
+    Import {
+        /// The CSV file describing the frames (REQUIRED)
+        #[clap(short, long, value_name = "CSV")]
+        input: String,
+
+        /// Target file for the zstd index (REQUIRED)
+        #[clap(short, long, value_name = "OUTPUT")]
+        output: String,
+
+        /// The archive the frames belong to, checked to hold every frame without overlaps
+        #[clap(long, value_name = "ARCHIVE")]
+        archive: Option<String>,
+
+        /// Overwrite the output file if it already exists
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ClientWorkflow {
+    /// Look up a file of keys, one per line, writing KEY<TAB>VALUE lines for the keys found
+    Lookup {
+        /// Address of the lookup service, as HOST:PORT (REQUIRED)
+        #[clap(short, long, value_name = "HOST")]
+        server: String,
+
+        /// Archive of the service to look keys up in, rather than letting the service route each key
+        #[clap(long, value_name = "NAMESPACE")]
+        namespace: Option<String>,
+
+        /// File of keys to look up, one per line (REQUIRED)
+        #[clap(short, long, value_name = "KEYS")]
+        keys_file: String,
+
+        /// Target file for the records found (REQUIRED)
+        #[clap(short, long, value_name = "OUTPUT")]
+        output: String,
+
+        /// Number of keys sent in each request
+        #[clap(long, default_value_t = 100000, value_name = "KEYS", value_parser = clap::value_parser!(u64).range(1..))]
+        batch_size: u64,
+    },
+}
+
+/// How frames are read, for the workflows which read archives that may sit on shared or remote
+/// storage.
+#[derive(clap::Args)]
+struct ReadArgs {
+    /// Cap the combined read rate of all threads, in MB/s (e.g. to share NFS/Lustre bandwidth)
+    #[clap(long, value_name = "MBPS", value_parser = parse_read_rate)]
+    max_read_mbps: Option<f64>,
+
+    /// Read neighbouring frames together, in single reads of up to this size (e.g. '8MiB'), rather than one read per frame. Helps with many small frames on high-latency storage
+    #[clap(long, value_name = "BATCH_SIZE", value_parser = parse_byte_size)]
+    read_batch_size: Option<u64>,
+
+    /// Number of times to retry a frame read which fails with a transient IO error
+    #[clap(long, default_value_t = 3, value_name = "RETRIES")]
+    retries: u32,
+
+    /// Delay before the first retry of a frame read, in milliseconds (doubles on each retry)
+    #[clap(long, default_value_t = 200, value_name = "MILLISECONDS")]
+    retry_delay: u64,
+}
+
+impl ReadArgs {
+    fn read_limiter(&self) -> Option<Arc<ReadLimiter>> {
+        self.max_read_mbps.map(|m| Arc::new(ReadLimiter::new(m)))
+    }
+
+    fn read_batcher(&self) -> Option<Arc<ReadBatcher>> {
+        self.read_batch_size
+            .map(|b| Arc::new(ReadBatcher::new(b as usize)))
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            delay: Duration::from_millis(self.retry_delay),
+        }
+    }
+}
+
+#[derive(Parser)]
+#[clap(
+    author = "David Waite",
+    version,
+    about,
+    long_about = None,
+        arg_required_else_help = true
+)]
+struct ArgumentParser {
+    #[command(subcommand)]
+    command: Option<Workflow>,
+
+    /// Write a manpage for the tool to stdout, rather than running a subcommand
+    #[clap(long, exclusive = true)]
+    generate_manpage: bool,
+
+    /// Exit with status 4 when a run completes with unreadable frames or set-aside records, and
+    /// fail rather than warn when an index looks stale against its archive
+    #[clap(long, global = true)]
+    strict: bool,
+
+    /// Print nothing to stdout on success, other than explicitly requested reports
+    #[clap(short, long, global = true)]
+    quiet: bool,
+
+    /// Format of progress and failure messages on stderr ('json' writes one event object per line)
+    #[clap(long, global = true, default_value_t = LogFormat::Text, value_name = "FORMAT", value_enum)]
+    log_format: LogFormat,
+
+    /// Name worker threads '<PREFIX>-worker-<N>', and put the prefix ahead of the names of reader and writer threads, so they can be picked out in tools such as top
+    #[clap(long, global = true, value_name = "PREFIX")]
+    thread_name_prefix: Option<String>,
+
+    /// Run worker, reader and writer threads at this niceness (Linux only), from -20 to 19. Values above the current niceness need no privileges, and let bulk work give way to interactive use of a shared node
+    #[clap(long, global = true, value_name = "NICENESS", allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    niceness: Option<i32>,
+}
+
+#[derive(clap::Subcommand)]
+enum Workflow {
+    /// Create an indexed zstd compression of the target input file
+    Compress {
+        /// The input file to which taxonomic information is appended (REQUIRED). Several files (e.g. 'shard_*.tsv') are compressed concurrently as shards of one archive, in the order given
+        #[clap(short, long, value_parser, value_name = "INPUT", num_args = 1.., required = true)]
+        input: Vec<String>,
+
+        /// Target file to store the blocked zstd payload (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Target file to store the blocked zstd index (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// The block size for compression (supports human-readable formats e.g. '64KiB, 128MiB, 2GB')
+        #[clap(short, long, default_value_t = String::from("64KiB"), value_name = "BLOCK_SIZE", env = "PD_BLOCK_SIZE")]
+        block_size: String,
+
+        /// Compression level for zstd
+        #[clap(
+            short,
+            long,
+            default_value_t = 3,
+            value_name = "COMPRESSION",
+            env = "PD_LEVEL"
+        )]
+        level: i32,
+
+        /// Take the defaults of the block size, level, frame metadata, alignment and read threads from a named profile. Flags given alongside it take precedence
+        #[clap(long, value_name = "PROFILE", value_enum)]
+        profile: Option<Profile>,
+
+        /// Check that each line is a key<TAB>numeric-value record, either reporting or rejecting malformed lines
+        #[clap(long, value_name = "VALIDATE", value_enum, num_args = 0..=1, default_missing_value = "report")]
+        validate: Option<Validation>,
+
+        /// Keep CRLF line endings exactly as read, rather than normalising them to LF
+        #[clap(long)]
+        preserve_line_endings: bool,
+
+        /// Layout of the input records, used when validating
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Compression of the input file, which is decoded as it is read (e.g. 'gzip' to convert an existing .gz without an intermediate file)
+        #[clap(long, value_name = "CODEC", value_enum)]
+        input_codec: Option<Codec>,
+
+        /// Write a skippable frame describing each data frame ahead of it, so that the archive can be indexed again by scanning if the index is lost
+        #[clap(long)]
+        frame_metadata: bool,
+
+        /// Leave out the frame metadata a profile would otherwise write
+        #[clap(long, overrides_with = "frame_metadata")]
+        no_frame_metadata: bool,
+
+        /// Start every frame on a multiple of this many bytes, padding between frames, for direct IO (e.g. '4KiB')
+        #[clap(long, value_name = "ALIGNMENT", value_parser = parse_alignment)]
+        align: Option<u64>,
+
+        /// Check that the input is sorted by key (e.g. with 'LC_ALL=C sort'), failing on the first record out of order, so that the index records the archive as key-sorted
+        #[clap(long)]
+        assume_sorted: bool,
+
+        /// Make any missing parent directories of the output and index files
+        #[clap(long)]
+        create_dirs: bool,
+
+        /// Overwrite the output and index files if they already exist
+        #[clap(long)]
+        force: bool,
+
+        /// Probe each block with a fast level 1 trial and compress blocks which barely compress at a lower level, spending up to '--level' only where it pays off. The level of each frame is recorded in the index
+        #[clap(long)]
+        adaptive_level: bool,
+
+        /// Fold a block smaller than this (such as a short final block) into the frame before it, rather than writing a frame too small to be worth compressing (e.g. '4KiB')
+        #[clap(long, value_name = "MIN_FRAME_SIZE", value_parser = parse_byte_size)]
+        min_frame_size: Option<u64>,
+
+        /// Read and compress blocks on this many threads, after a quick first pass which finds where each block ends. Suits fast storage such as NVMe, where a single reader cannot keep up (plain input files only, and not with '--validate')
+        #[clap(long, value_name = "THREADS", value_parser = clap::value_parser!(u64).range(1..))]
+        read_threads: Option<u64>,
+
+        /// Write the index as a bare list of frames, without the header recording the version, command line, host, time and input checksums of the run. Older releases can only read indexes written this way
+        #[clap(long)]
+        plain_index: bool,
+    },
+
+    /// Write synthetic KEY<TAB>VALUE records (or an indexed archive of them) for benchmarking modes and block sizes
+    Generate {
+        /// Number of records to write, including duplicates (REQUIRED)
+        #[clap(long, value_name = "RECORDS")]
+        records: u64,
+
+        /// Form of the keys
+        #[clap(long, default_value_t = KeyStyle::Accession, value_name = "KEY_STYLE", value_enum)]
+        key_style: KeyStyle,
+
+        /// Largest value, with values drawn evenly from 1 to this value
+        #[clap(long, default_value_t = 3_000_000, value_name = "VALUE", value_parser = clap::value_parser!(u64).range(1..))]
+        value_max: u64,
+
+        /// Write the records in key order
+        #[clap(long)]
+        sorted: bool,
+
+        /// Share of records which repeat the key of an earlier record (e.g. '0.05')
+        #[clap(long, default_value_t = 0.0, value_name = "RATE")]
+        duplicate_rate: f64,
+
+        /// Seed for the record generator, so that runs with the same seed write the same records
+        #[clap(long, default_value_t = 1, value_name = "SEED")]
+        seed: u64,
+
+        /// Target file for the records, or for the compressed archive when an index file is given (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Compress the records straight into an indexed archive, writing its index to this file
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// The block size for compression, when writing an archive
+        #[clap(short, long, default_value_t = String::from("64KiB"), value_name = "BLOCK_SIZE", env = "PD_BLOCK_SIZE")]
+        block_size: String,
+
+        /// Compression level for zstd, when writing an archive
+        #[clap(
+            short,
+            long,
+            default_value_t = 3,
+            value_name = "COMPRESSION",
+            env = "PD_LEVEL"
+        )]
+        level: i32,
+
+        /// Overwrite the output and index files if they already exist
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Compress a sample of the input at several block sizes and levels, and recommend the settings which balance compression ratio against parallel decoding
+    Tune {
+        /// The input file to be compressed (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// Amount read from the start of the input to compress at each setting
+        #[clap(long, default_value_t = 256 << 20, value_name = "SAMPLE_SIZE", value_parser = parse_byte_size)]
+        sample_size: u64,
+
+        /// Block sizes to try, separated by commas
+        #[clap(long, default_value = "1MiB,16MiB,64MiB,256MiB", value_name = "BLOCK_SIZES", value_delimiter = ',', value_parser = parse_byte_size)]
+        block_sizes: Vec<u64>,
+
+        /// Compression levels to try, separated by commas
+        #[clap(
+            long,
+            default_value = "1,3,9",
+            value_name = "LEVELS",
+            value_delimiter = ','
+        )]
+        levels: Vec<i32>,
+
+        /// Also time parallel decoding of each trial on this machine, and recommend the setting which decodes fastest among the most compact
+        #[clap(long)]
+        measure_decode: bool,
+
+        /// Number of threads with which decoding is measured, and which the recommended block size must keep busy
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+    },
+
+    /// Describe the layout of an archive: format version, codec, frames, checksums and the optional features it uses, for other implementations of the format to read it by
+    Describe {
+        /// The archive to describe (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file of the archive. Without one, the archive is scanned for its frames
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Write the description to stdout as JSON, and nothing else
+        #[clap(long)]
+        json: bool,
+
+        /// Number of threads with which frame headers are read
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+    },
+
+    /// Report the CPUs, memory and zstd library available to a run and, given an archive, how fast it can be read, along with the flags to try. Attach the output when reporting a slow run
+    Doctor {
+        /// An archive on the storage to be read from, whose read rate is sampled
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: Option<String>,
+
+        /// Amount read from across the archive to measure its read rate
+        #[clap(long, default_value_t = 64 << 20, value_name = "SAMPLE_SIZE", value_parser = parse_byte_size)]
+        sample_size: u64,
+    },
+
+    /// Read an indexed zstd compression and parse results to a HashMap
+    Decompress {
+        /// The zstd file to be decompressed and parsed (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames ('-' reads it from stdin, and a plain http:// URL fetches it). If omitted, the frames of a plain multi-frame zstd file are located by scanning it. For gzip input, an optional '.gzi' index of the members
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Compression of the input file ('gzip' decodes multi-member files such as BGZF in parallel)
+        #[clap(long, default_value_t = Codec::Zstd, value_name = "CODEC", value_enum)]
+        input_codec: Codec,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index, treating a mismatch as a corrupt frame
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// After the run, write an index to FILE leaving out every frame which failed, with a report of the frames and decompressed ranges lost in FILE.quarantine.tsv
+        #[clap(long, value_name = "FILE", conflicts_with_all = ["partition_by_value", "estimate_cardinality", "top_values", "membership_index"])]
+        quarantine_index: Option<String>,
+
+        /// Number of threads to use for parallel file parsing
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Method for gathering zstd frame results
+        #[clap(long, default_value_t = Mode::DashMap, value_name = "MODE", value_enum, env = "PD_MODE")]
+        mode: Mode,
+
+        /// How to handle records whose value cannot be parsed
+        #[clap(long, default_value_t = BadRecordPolicy::Zero, value_name = "POLICY", value_enum)]
+        bad_record: BadRecordPolicy,
+
+        /// Fail on keys which are not valid UTF-8, instead of replacing the invalid bytes
+        #[clap(long)]
+        strict_utf8: bool,
+
+        /// Representation of record keys ('bytes' keeps keys as raw bytes without UTF-8 conversion, 'packed' packs accessions such as 'WP_413685322.1' into integers)
+        #[clap(long, default_value_t = KeyType::String, value_name = "KEY_TYPE", value_enum)]
+        key_type: KeyType,
+
+        /// Representation of values ('string' keeps the value text as it appears in the record)
+        #[clap(long, default_value_t = ValueType::Integer, value_name = "VALUE_TYPE", value_enum)]
+        value_type: ValueType,
+
+        /// Bits per value in the map ('32' halves value memory, reporting values which do not fit as
+        /// bad records)
+        #[clap(long, default_value_t = ValueWidth::U64, value_name = "BITS", value_enum)]
+        value_width: ValueWidth,
+
+        /// Columns (counting from 1, comma-separated) joined with tabs to form the value, in place
+        /// of the value column of the record format
+        #[clap(long, value_name = "COLUMNS", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        value_columns: Vec<u64>,
+
+        /// Columns (counting from 1, comma-separated) joined into a composite key, in place of the
+        /// key column of the record format
+        #[clap(long, value_name = "COLUMNS", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        key_columns: Vec<u64>,
+
+        /// Separator placed between the columns of a composite key
+        #[clap(
+            long,
+            default_value = ".",
+            value_name = "JOINER",
+            requires = "key_columns"
+        )]
+        key_joiner: String,
+
+        /// Drop version suffixes from keys, reading 'WP_413685322.1' as 'WP_413685322'
+        #[clap(long)]
+        strip_key_version: bool,
+
+        /// Value kept when records share a key ('error' fails the run on the first repeated key)
+        #[clap(long, default_value_t = DuplicatePolicy::Last, value_name = "POLICY", value_enum)]
+        duplicate_keys: DuplicatePolicy,
+
+        /// Write records into one file per value in OUTPUT_DIR, instead of building a map. At most 512 files are held open at once, with the least recently written closed and later appended to
+        #[clap(long, requires = "output_dir")]
+        partition_by_value: bool,
+
+        /// Directory for the partitioned output files
+        #[clap(long, value_name = "OUTPUT_DIR", requires = "partition_by_value")]
+        output_dir: Option<String>,
+
+        /// Partition values into this many hash buckets, rather than one file per value
+        #[clap(long, value_name = "BUCKETS", requires = "partition_by_value", value_parser = clap::value_parser!(u64).range(1..))]
+        partition_buckets: Option<u64>,
+
+        /// Report approximate counts of distinct keys and values from HyperLogLog sketches of
+        /// each frame, instead of building a map
+        #[clap(long, conflicts_with_all = ["partition_by_value", "taxdump"])]
+        estimate_cardinality: bool,
+
+        /// Report the K most frequent values (e.g. taxids) with their record counts, instead of
+        /// building a map
+        #[clap(long, value_name = "K", conflicts_with_all = ["partition_by_value", "taxdump", "estimate_cardinality"], value_parser = clap::value_parser!(u64).range(1..))]
+        top_values: Option<u64>,
+
+        /// Write a membership index to FILE, holding a dictionary of the keys and a compressed
+        /// bitmap of the keys of each value (e.g. taxid), instead of keeping the map
+        #[clap(long, value_name = "FILE", conflicts_with_all = ["partition_by_value", "taxdump", "estimate_cardinality", "top_values", "value_type", "save_snapshot"])]
+        membership_index: Option<String>,
+
+        /// Directory of an NCBI taxdump (nodes.dmp and names.dmp) used to describe each taxid
+        #[clap(long, value_name = "TAXDUMP")]
+        taxdump: Option<String>,
+
+        /// Roll each taxid up to this rank of its lineage (e.g. 'genus', 'family')
+        #[clap(long, value_name = "RANK", requires = "taxdump")]
+        rank: Option<String>,
+
+        /// Layout of the compressed records ('kraken2' and 'centrifuge' map read IDs to taxids)
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Write per-frame read, decode and parse timings for each worker to a Chrome tracing file
+        #[clap(long, value_name = "TRACE_FILE")]
+        trace_out: Option<String>,
+
+        /// Report bytes read and decompressed, and the time each thread spent reading, decoding, parsing and inserting records
+        #[clap(long)]
+        stage_report: bool,
+
+        #[command(flatten)]
+        read_args: Box<ReadArgs>,
+
+        /// Spread worker threads across NUMA nodes and pin each to its node's CPUs. Memory is not bound to a node, so only buffers a worker touches first (such as the frames it decodes) are likely to be node-local
+        #[clap(long)]
+        numa: bool,
+
+        /// Order in which frames are handed to the threads. 'largest-first' starts the largest frames first, so that a run whose frame sizes vary widely does not end waiting on one large frame
+        #[clap(long, default_value_t = FrameSchedule::FileOrder, value_name = "SCHEDULE", value_enum)]
+        schedule: FrameSchedule,
+
+        /// Start from a map snapshot saved by an earlier run, decoding only the frames indexed after it
+        #[clap(long, value_name = "SNAPSHOT", conflicts_with_all = ["partition_by_value", "estimate_cardinality", "top_values"])]
+        from_snapshot: Option<String>,
+
+        /// Save the finished map to a snapshot file, for a later run to start from
+        #[clap(long, value_name = "SNAPSHOT", conflicts_with_all = ["partition_by_value", "estimate_cardinality", "top_values"])]
+        save_snapshot: Option<String>,
+
+        /// Predict the peak memory of the map in each mode from the record counts in the index, before reading any frame
+        #[clap(long, conflicts_with_all = ["partition_by_value", "estimate_cardinality", "top_values", "membership_index"])]
+        estimate_memory: bool,
+
+        /// Fail before reading any frame when the estimated peak memory is above this size (e.g. '48GiB'). Defaults to the memory limit of the container
+        #[clap(long, value_name = "SIZE", value_parser = parse_memory_size)]
+        memory_budget: Option<u64>,
+
+        /// Build the map a second time under this mode once the first is done, and fail if the two maps differ, listing the divergent keys. Both maps are held at once
+        #[clap(long, value_name = "MODE", value_enum, conflicts_with_all = ["partition_by_value", "estimate_cardinality", "top_values", "membership_index", "from_snapshot"])]
+        cross_check: Option<Mode>,
+    },
+
+    /// Decompress an indexed zstd compression back to the original file, decoding frames in parallel
+    Extract {
+        /// The zstd file to be decompressed (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames ('-' reads it from stdin, and a plain http:// URL fetches it). If omitted, the frames of a plain multi-frame zstd file are located by scanning it. For gzip input, an optional '.gzi' index of the members
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Compression of the input file ('gzip' decodes multi-member files such as BGZF in parallel)
+        #[clap(long, default_value_t = Codec::Zstd, value_name = "CODEC", value_enum)]
+        input_codec: Codec,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index, treating a mismatch as a corrupt frame
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// Target file for the decompressed content (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Number of threads to use for parallel frame decoding
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Continue an interrupted extraction from its checkpoint (OUTPUT.checkpoint), which is refused if the index has changed since
+        #[clap(long)]
+        resume: bool,
+
+        /// Number of frames written between checkpoints
+        #[clap(long, default_value_t = 64, value_name = "FRAMES")]
+        checkpoint_interval: usize,
+
+        #[command(flatten)]
+        read_args: ReadArgs,
+    },
+
+    /// Follow an indexed zstd archive which is still being written, decoding frames as they are indexed
+    FollowDecompress {
+        /// The zstd file to be followed (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames, checked for new frames as it is written again (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// Write the content of each new frame to this file (or named pipe) as it arrives, instead of building a map
+        #[clap(short, long, value_name = "OUTPUT")]
+        output: Option<String>,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index, treating a mismatch as a corrupt frame
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// Method for gathering zstd frame results
+        #[clap(long, default_value_t = Mode::DashMap, value_name = "MODE", value_enum, env = "PD_MODE")]
+        mode: Mode,
+
+        /// Number of threads to use for parallel file parsing
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// How to handle records whose value cannot be parsed
+        #[clap(long, default_value_t = BadRecordPolicy::Zero, value_name = "POLICY", value_enum)]
+        bad_record: BadRecordPolicy,
+
+        /// Fail on keys which are not valid UTF-8, instead of replacing the invalid bytes
+        #[clap(long)]
+        strict_utf8: bool,
+
+        /// Representation of record keys ('bytes' keeps keys as raw bytes without UTF-8 conversion, 'packed' packs accessions such as 'WP_413685322.1' into integers)
+        #[clap(long, default_value_t = KeyType::String, value_name = "KEY_TYPE", value_enum)]
+        key_type: KeyType,
+
+        /// Representation of values ('string' keeps the value text as it appears in the record)
+        #[clap(long, default_value_t = ValueType::Integer, value_name = "VALUE_TYPE", value_enum)]
+        value_type: ValueType,
+
+        /// Bits per value in the map ('32' halves value memory, reporting values which do not fit as
+        /// bad records)
+        #[clap(long, default_value_t = ValueWidth::U64, value_name = "BITS", value_enum)]
+        value_width: ValueWidth,
+
+        /// Value kept when records share a key, including keys written again in later frames
+        #[clap(long, default_value_t = DuplicatePolicy::Last, value_name = "POLICY", value_enum)]
+        duplicate_keys: DuplicatePolicy,
+
+        /// Layout of the compressed records ('kraken2' and 'centrifuge' map read IDs to taxids)
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Time between checks of the index for new frames, in milliseconds
+        #[clap(long, default_value_t = 1000, value_name = "MILLISECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        poll_interval: u64,
+
+        /// Stop once no new frames have been indexed for this many seconds, rather than following until interrupted
+        #[clap(long, value_name = "SECONDS")]
+        idle_timeout: Option<u64>,
+
+        /// Address on which to expose Prometheus metrics at '/metrics' while the archive is followed
+        #[clap(long, value_name = "ADDRESS")]
+        metrics_bind: Option<String>,
+    },
+
+    /// Build the maps of one or more indexed zstd archives and answer bulk key lookups against them over HTTP
+    Serve {
+        /// The zstd file to be decompressed and served, given once per archive (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT", required = true)]
+        input: Vec<String>,
+
+        /// The zstd index file describing the frames, given once per archive in the order of the inputs ('-' reads it from stdin, and a plain http:// URL fetches it). If omitted, the frames of plain multi-frame zstd files are located by scanning them
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Vec<String>,
+
+        /// Name under which each archive is served, given in the order of the inputs (defaults to the input file name without its '.zstd' extension)
+        #[clap(long, value_name = "NAMESPACE")]
+        namespace: Vec<String>,
+
+        /// Send keys starting with PREFIX to the archive of NAMESPACE, when a lookup names no namespace (e.g. 'WP_=prot')
+        #[clap(long, value_name = "PREFIX=NAMESPACE", value_parser = parse_route)]
+        route: Vec<(String, String)>,
+
+        /// Address on which to answer lookups
+        #[clap(long, default_value_t = String::from("127.0.0.1:8080"), value_name = "ADDRESS")]
+        bind: String,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index, treating a mismatch as a corrupt frame
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// Method for gathering zstd frame results
+        #[clap(long, default_value_t = Mode::DashMap, value_name = "MODE", value_enum, env = "PD_MODE")]
+        mode: Mode,
+
+        /// Number of threads to use for parallel file parsing and for resolving lookups
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// How to handle records whose value cannot be parsed
+        #[clap(long, default_value_t = BadRecordPolicy::Zero, value_name = "POLICY", value_enum)]
+        bad_record: BadRecordPolicy,
+
+        /// Fail on keys which are not valid UTF-8, instead of replacing the invalid bytes
+        #[clap(long)]
+        strict_utf8: bool,
+
+        /// Representation of record keys ('bytes' keeps keys as raw bytes without UTF-8 conversion, 'packed' packs accessions such as 'WP_413685322.1' into integers)
+        #[clap(long, default_value_t = KeyType::String, value_name = "KEY_TYPE", value_enum)]
+        key_type: KeyType,
+
+        /// Representation of values ('string' keeps the value text as it appears in the record)
+        #[clap(long, default_value_t = ValueType::Integer, value_name = "VALUE_TYPE", value_enum)]
+        value_type: ValueType,
+
+        /// Bits per value in the map ('32' halves value memory, reporting values which do not fit as
+        /// bad records)
+        #[clap(long, default_value_t = ValueWidth::U64, value_name = "BITS", value_enum)]
+        value_width: ValueWidth,
+
+        /// Value kept when records share a key
+        #[clap(long, default_value_t = DuplicatePolicy::Last, value_name = "POLICY", value_enum)]
+        duplicate_keys: DuplicatePolicy,
+
+        /// Layout of the compressed records ('kraken2' and 'centrifuge' map read IDs to taxids)
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Start from a map snapshot saved by an earlier run, decoding only the frames indexed after it (single archive only)
+        #[clap(long, value_name = "SNAPSHOT")]
+        from_snapshot: Option<String>,
+
+        /// Save the map to a snapshot file once it is built, and again whenever a client posts to /snapshot, for a later run to start from (single archive only)
+        #[clap(long, value_name = "SNAPSHOT")]
+        save_snapshot: Option<String>,
+
+        /// Check the archive and index files for changes every this many seconds, swapping in a newly decoded map once a changed archive has been left alone for a whole check
+        #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        watch_interval: Option<u64>,
+
+        /// Let clients reload the archives by posting to /reload, which is otherwise refused. The service has no authentication, so only enable this where every client reaching the bind address is trusted
+        #[clap(long)]
+        allow_reload: bool,
+
+        /// Most connections answered at once, beyond which further connections wait to be accepted
+        #[clap(long, default_value_t = 64, value_name = "CONNECTIONS", value_parser = clap::value_parser!(u64).range(1..))]
+        max_connections: u64,
+
+        /// Address on which to expose Prometheus metrics at '/metrics', including the lookups answered
+        #[clap(long, value_name = "ADDRESS")]
+        metrics_bind: Option<String>,
+    },
+
+    /// Query a remote lookup service started with 'serve'
+    Client {
+        #[command(subcommand)]
+        command: ClientWorkflow,
+    },
+
+    /// Mount the decompressed content of an indexed zstd archive as a read-only file, decoding frames as they are read (Linux only)
+    Mount {
+        /// The zstd file to be mounted (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames ('-' reads it from stdin, and a plain http:// URL fetches it). If omitted, the frames of a plain multi-frame zstd file are located by scanning it
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Empty directory in which the decompressed file appears, until it is unmounted (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "MOUNT_POINT")]
+        mount_point: String,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index, treating a mismatch as a corrupt frame
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// Number of threads to use for decoding frames
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Keep decoded frames in this directory between mounts, so that frames read again after a restart are not decoded again. The directory may be shared by several archives and processes
+        #[clap(long, value_name = "DIRECTORY")]
+        frame_cache: Option<String>,
+
+        /// Most decoded content the frame cache may hold, beyond which the frames read least recently are removed (e.g. '20GiB')
+        #[clap(long, default_value = "4GiB", value_name = "SIZE", value_parser = parse_memory_size, requires = "frame_cache")]
+        frame_cache_size: u64,
+
+        /// Address on which to expose Prometheus metrics at '/metrics', including the hits and misses of the frame cache
+        #[clap(long, value_name = "ADDRESS")]
+        metrics_bind: Option<String>,
+    },
+
+    /// Write the records whose keys appear in a query file of one key per line, as KEY<TAB>VALUE lines
+    Join {
+        /// The zstd file to be searched (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames ('-' reads it from stdin, and a plain http:// URL fetches it). If omitted, the frames of a plain multi-frame zstd file are located by scanning it. For gzip input, an optional '.gzi' index of the members
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Compression of the input file ('gzip' decodes multi-member files such as BGZF in parallel)
+        #[clap(long, default_value_t = Codec::Zstd, value_name = "CODEC", value_enum)]
+        input_codec: Codec,
+
+        /// File of keys to look up, one per line (REQUIRED)
+        #[clap(short = 'k', long, value_parser, value_name = "QUERY")]
+        query: String,
+
+        /// Target file for the matching records (REQUIRED unless --missing is given)
+        #[clap(
+            short,
+            long,
+            value_parser,
+            value_name = "OUTPUT",
+            required_unless_present = "missing"
+        )]
+        output: Option<String>,
+
+        /// Target file for the query keys found in no record, one per line in query order
+        #[clap(long, value_parser, value_name = "MISSING")]
+        missing: Option<String>,
+
+        /// Number of threads to use for parallel frame decoding
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Layout of the records in the archive
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Columns (counting from 1, comma-separated) joined into a composite key, in place of the
+        /// key column of the record format
+        #[clap(long, value_name = "COLUMNS", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        key_columns: Vec<u64>,
+
+        /// Separator placed between the columns of a composite key
+        #[clap(
+            long,
+            default_value = ".",
+            value_name = "JOINER",
+            requires = "key_columns"
+        )]
+        key_joiner: String,
+
+        /// Columns (counting from 1, comma-separated) joined with tabs to form the value written
+        #[clap(long, value_name = "COLUMNS", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        value_columns: Vec<u64>,
+
+        /// Drop version suffixes from the keys of both the archive and the query file
+        #[clap(long)]
+        strip_key_version: bool,
+    },
+
+    /// Join two archives compressed from key-sorted input, writing KEY<TAB>LEFT<TAB>RIGHT for every pair of records sharing a key
+    MergeJoin {
+        /// The left zstd file of the join (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file of the left archive, which must record it as key-sorted (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// The right zstd file of the join (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "RIGHT")]
+        right: String,
+
+        /// The zstd index file of the right archive, which must record it as key-sorted (REQUIRED)
+        #[clap(long, value_parser, value_name = "RIGHT_INDEX")]
+        right_zindex: String,
+
+        /// Target file for the joined records (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Number of threads to use for parallel frame decoding, shared between the two archives
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Layout of the records in both archives
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Columns (counting from 1, comma-separated) joined with tabs to form the values written
+        #[clap(long, value_name = "COLUMNS", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        value_columns: Vec<u64>,
+    },
+
+    /// Rewrite an archive with small frames coalesced up to a target block size, along with a new index
+    Repack {
+        /// The archive to be repacked (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames ('-' reads it from stdin, and a plain http:// URL fetches it). If omitted, the frames of a plain multi-frame zstd file are located by scanning it. For gzip input, an optional '.gzi' index of the members
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Compression of the input file ('gzip' converts a multi-member file such as BGZF into an indexed zstd file)
+        #[clap(long, default_value_t = Codec::Zstd, value_name = "CODEC", value_enum)]
+        input_codec: Codec,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index before repacking it
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// Target file for the repacked zstd payload (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Target file for the index of the repacked payload (REQUIRED)
+        #[clap(long, value_parser, value_name = "OUTPUT_INDEX")]
+        output_index: String,
+
+        /// Minimum size of the repacked frames, before compression (supports human-readable formats e.g. '4MiB')
+        #[clap(short, long, default_value_t = String::from("4MiB"), value_name = "BLOCK_SIZE", env = "PD_BLOCK_SIZE")]
+        block_size: String,
+
+        /// Compression level for the repacked frames
+        #[clap(
+            short,
+            long,
+            default_value_t = 3,
+            value_name = "COMPRESSION",
+            env = "PD_LEVEL"
+        )]
+        level: i32,
+
+        /// Write a skippable frame describing each data frame ahead of it
+        #[clap(long)]
+        frame_metadata: bool,
+
+        /// Start every frame on a multiple of this many bytes, padding between frames, for direct IO (e.g. '4KiB')
+        #[clap(long, value_name = "ALIGNMENT", value_parser = parse_alignment)]
+        align: Option<u64>,
+
+        /// Number of threads to use for parallel frame decoding
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Overwrite the output and index files if they already exist
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Rewrite an archive keeping only the last record of each key, dropping keys deleted by a '<deleted>' tombstone record
+    Compact {
+        /// The archive to be compacted (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames ('-' reads it from stdin, and a plain http:// URL fetches it) (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index before compacting it
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// Target file for the compacted zstd payload (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Target file for the index of the compacted payload (REQUIRED)
+        #[clap(long, value_parser, value_name = "OUTPUT_INDEX")]
+        output_index: String,
+
+        /// Layout of the compressed records ('kraken2' and 'centrifuge' map read IDs to taxids)
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Columns (counting from 1, comma-separated) joined into a composite key, in place of the
+        /// key column of the record format
+        #[clap(long, value_name = "COLUMNS", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        key_columns: Vec<u64>,
+
+        /// Separator placed between the columns of a composite key
+        #[clap(
+            long,
+            default_value = ".",
+            value_name = "JOINER",
+            requires = "key_columns"
+        )]
+        key_joiner: String,
+
+        /// Drop version suffixes from keys, so that 'WP_413685322.1' replaces 'WP_413685322.2'
+        #[clap(long)]
+        strip_key_version: bool,
+
+        /// Minimum size of the compacted frames, before compression (supports human-readable formats e.g. '4MiB')
+        #[clap(short, long, default_value_t = String::from("4MiB"), value_name = "BLOCK_SIZE", env = "PD_BLOCK_SIZE")]
+        block_size: String,
+
+        /// Compression level for the compacted frames
+        #[clap(
+            short,
+            long,
+            default_value_t = 3,
+            value_name = "COMPRESSION",
+            env = "PD_LEVEL"
+        )]
+        level: i32,
+
+        /// Write a skippable frame describing each data frame ahead of it
+        #[clap(long)]
+        frame_metadata: bool,
+
+        /// Start every frame on a multiple of this many bytes, padding between frames, for direct IO (e.g. '4KiB')
+        #[clap(long, value_name = "ALIGNMENT", value_parser = parse_alignment)]
+        align: Option<u64>,
+
+        /// Number of threads to use for parallel frame decoding
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Overwrite the output and index files if they already exist
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Write each frame of an archive as a standalone archive with its own index, plus a manifest of the shards for external schedulers
+    Explode {
+        /// The archive to be split (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames ('-' reads it from stdin, and a plain http:// URL fetches it) (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// Directory for the shard files, their indexes and the 'manifest.json' listing them (REQUIRED)
+        #[clap(long, value_name = "DIR")]
+        output_dir: String,
+
+        /// Number of threads to use for copying frames
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Overwrite the shard files and manifest if they already exist
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Concatenate a directory of per-frame shards into a single indexed archive, the inverse of explode
+    Assemble {
+        /// Directory holding the shard files (REQUIRED)
+        #[clap(long, value_name = "DIR")]
+        input_dir: String,
+
+        /// Manifest listing the shards in order, if not the 'manifest.json' of the input directory
+        #[clap(long, value_name = "MANIFEST")]
+        manifest: Option<String>,
+
+        /// Target file for the assembled zstd payload (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Target file for the index of the assembled payload (REQUIRED)
+        #[clap(long, value_parser, value_name = "OUTPUT_INDEX")]
+        output_index: String,
+
+        /// Overwrite the output and index files if they already exist
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Convert frame indexes to and from CSV for tooling outside this crate
+    Index {
+        #[command(subcommand)]
+        command: IndexWorkflow,
+    },
+
+    /// Report every key held by more than one record, failing if there are any
+    CheckUnique {
+        /// The zstd file to be checked (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames (REQUIRED, as every frame must hold whole records). An index recording the archive as key-sorted allows a cheaper check
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Compression of the input file ('gzip' decodes multi-member files such as BGZF in parallel)
+        #[clap(long, default_value_t = Codec::Zstd, value_name = "CODEC", value_enum)]
+        input_codec: Codec,
+
+        /// Number of threads to use for parallel frame decoding
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Layout of the records in the archive
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Columns (counting from 1, comma-separated) joined into a composite key, in place of the
+        /// key column of the record format
+        #[clap(long, value_name = "COLUMNS", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        key_columns: Vec<u64>,
+
+        /// Separator placed between the columns of a composite key
+        #[clap(
+            long,
+            default_value = ".",
+            value_name = "JOINER",
+            requires = "key_columns"
+        )]
+        key_joiner: String,
+
+        /// Drop version suffixes from keys, so that versions of an accession count as duplicates
+        #[clap(long)]
+        strip_key_version: bool,
+
+        /// Target file for the repeated keys, as KEY<TAB>FRAME<TAB>VALUE lines (printed in the summary if omitted)
+        #[clap(long, value_parser, value_name = "REPORT")]
+        report: Option<String>,
+    },
+
+    /// Rewrite an archive with its records sorted by key, along with a new index recording it as sorted
+    Sort {
+        /// The archive to be sorted (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames (REQUIRED, as every frame must hold whole records)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: Option<String>,
+
+        /// Check each decoded frame against the xxh3 checksum recorded in the index before sorting it
+        #[clap(long)]
+        verify_frames: bool,
+
+        /// Target file for the sorted zstd payload (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "OUTPUT")]
+        output: String,
+
+        /// Target file for the index of the sorted payload (REQUIRED)
+        #[clap(long, value_parser, value_name = "OUTPUT_INDEX")]
+        output_index: String,
+
+        /// Layout of the records, which determines the key they are sorted on
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+
+        /// Minimum size of the sorted frames, before compression (supports human-readable formats e.g. '4MiB')
+        #[clap(short, long, default_value_t = String::from("4MiB"), value_name = "BLOCK_SIZE", env = "PD_BLOCK_SIZE")]
+        block_size: String,
+
+        /// Compression level for the sorted frames
+        #[clap(
+            short,
+            long,
+            default_value_t = 3,
+            value_name = "COMPRESSION",
+            env = "PD_LEVEL"
+        )]
+        level: i32,
+
+        /// Write a skippable frame describing each data frame ahead of it
+        #[clap(long)]
+        frame_metadata: bool,
+
+        /// Start every frame on a multiple of this many bytes, padding between frames, for direct IO (e.g. '4KiB')
+        #[clap(long, value_name = "ALIGNMENT", value_parser = parse_alignment)]
+        align: Option<u64>,
+
+        /// Number of threads to use for parallel frame decoding and sorting
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+
+        /// Overwrite the output and index files if they already exist
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Coordinate a distributed decompression, handing frame ranges out to connected workers
+    ServeFrames {
+        /// The zstd file to be decompressed, as seen by the workers (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INPUT")]
+        input: String,
+
+        /// The zstd index file describing the frames to distribute (REQUIRED)
+        #[clap(short, long, value_parser, value_name = "INDEX")]
+        zindex: String,
+
+        /// Address on which to listen for workers
+        #[clap(long, default_value_t = String::from("0.0.0.0:7878"), value_name = "ADDRESS")]
+        bind: String,
+
+        /// Number of frames handed to a worker in each task
+        #[clap(long, default_value_t = 64, value_name = "FRAMES", value_parser = clap::value_parser!(u64).range(1..))]
+        frames_per_task: u64,
+
+        /// Whether workers return every record, or only the count of records per value
+        #[clap(long, default_value_t = Collect::Map, value_name = "COLLECT", value_enum)]
+        collect: Collect,
+
+        /// Address on which to expose Prometheus metrics at '/metrics' while the coordinator runs
+        #[clap(long, value_name = "ADDRESS")]
+        metrics_bind: Option<String>,
+
+        /// How to handle records whose value cannot be parsed
+        #[clap(long, default_value_t = BadRecordPolicy::Zero, value_name = "POLICY", value_enum)]
+        bad_record: BadRecordPolicy,
+
+        /// Fail on keys which are not valid UTF-8, instead of replacing the invalid bytes
+        #[clap(long)]
+        strict_utf8: bool,
+
+        /// Layout of the compressed records ('kraken2' and 'centrifuge' map read IDs to taxids)
+        #[clap(long, default_value_t = RecordFormat::Tsv, value_name = "FORMAT", value_enum)]
+        format: RecordFormat,
+    },
+
+    /// Connect to a coordinator and decompress the frames it assigns
+    Worker {
+        /// Address of the serve-frames coordinator (REQUIRED)
+        #[clap(short, long, value_name = "ADDRESS")]
+        coordinator: String,
+
+        /// Local path to the zstd file, if it differs from the path given to the coordinator
+        #[clap(short, long, value_name = "INPUT")]
+        input: Option<String>,
+
+        /// Number of threads to use for parallel frame parsing
+        #[clap(
+            short,
+            long,
+            default_value_t = 1,
+            value_name = "THREADS",
+            env = "PD_THREADS"
+        )]
+        num_threads: usize,
+    },
+
+    /// Write a shell completion script to stdout (e.g. 'completions bash > parallel_decompression.bash')
+    Completions {
+        /// Shell to generate completions for (REQUIRED)
+        #[clap(value_name = "SHELL", value_enum)]
+        shell: clap_complete::Shell,
     },
 }
@@ -0,0 +1,249 @@
+use crate::bitmap::RoaringBitmap;
+use crate::snapshot::{for_each_record, read_field, write_field};
+use crate::{DecompressionSummary, EitherMap, PipelineError, RecordKey, RunStatus};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+// Marks a membership index, and the version of its layout
+const MEMBERSHIP_MAGIC: &[u8; 8] = b"PDMEMB01";
+const MEMBERSHIP_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct MembershipHeader {
+    archive: String,
+    keys: usize,
+    values: usize,
+}
+
+/// The keys holding each value (such as a taxid) of a map, as a compressed bitmap of key ids.
+/// Keys are numbered by their place in a dictionary of the keys in byte order, so the ids of
+/// one value are dense where its keys sort together, and a key is found by binary search.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MembershipIndex {
+    keys: Vec<Box<[u8]>>,
+    members: HashMap<u64, RoaringBitmap>,
+}
+
+/// Outcome of writing a membership index, for the caller to report.
+#[derive(Debug, Default)]
+pub struct MembershipReport {
+    pub records: usize,
+    /// Number of distinct values, each with its own bitmap
+    pub values: usize,
+    /// Size of the written index file
+    pub index_bytes: u64,
+    pub summary: DecompressionSummary,
+}
+
+impl MembershipReport {
+    pub fn run_status(&self) -> RunStatus {
+        RunStatus::from_summary(&self.summary)
+    }
+}
+
+//region: Private functions
+
+fn read_index<R: Read>(index_reader: &mut R, header: &MembershipHeader) -> Result<MembershipIndex> {
+    let mut field_buffer: Vec<u8> = Vec::new();
+
+    // Counts are only trusted as far as the fields which follow bear them out
+    let mut keys: Vec<Box<[u8]>> = Vec::with_capacity(header.keys.min(1 << 20));
+    for _ in 0..header.keys {
+        read_field(index_reader, &mut field_buffer)?;
+        keys.push(field_buffer.as_slice().into());
+    }
+    if !keys.is_sorted() {
+        bail!("Key dictionary is not in byte order.");
+    }
+
+    let mut members: HashMap<u64, RoaringBitmap> =
+        HashMap::with_capacity(header.values.min(1 << 20));
+    for _ in 0..header.values {
+        let mut value = [0u8; 8];
+        index_reader.read_exact(&mut value)?;
+        read_field(index_reader, &mut field_buffer)?;
+        let bitmap = RoaringBitmap::from_bytes(&field_buffer)?;
+        if bitmap
+            .iter()
+            .last()
+            .is_some_and(|id| id as usize >= keys.len())
+        {
+            bail!("Bitmap refers to a key beyond the dictionary.");
+        }
+        members.insert(u64::from_le_bytes(value), bitmap);
+    }
+    Ok(MembershipIndex { keys, members })
+}
+
+//endregion:
+
+impl MembershipIndex {
+    /// Build the index of a map of integer values. Each key is given the id of its place in the
+    /// sorted dictionary, and added to the bitmap of its value.
+    pub fn from_map<K: RecordKey, V: Copy + Into<u64>>(
+        record_map: &EitherMap<K, V>,
+    ) -> Result<MembershipIndex> {
+        if record_map.len() > u32::MAX as usize {
+            bail!(PipelineError::Usage(format!(
+                "A membership index can hold at most {} keys, not {}!",
+                u32::MAX,
+                record_map.len()
+            )));
+        }
+
+        let mut entries: Vec<(Box<[u8]>, u64)> = Vec::with_capacity(record_map.len());
+        for_each_record(record_map, |key, value| {
+            entries.push((Box::from(key.key_bytes()), (*value).into()));
+            Ok(())
+        })?;
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        // Ids are taken in ascending order, so each insert extends the end of its bitmap
+        let mut members: HashMap<u64, RoaringBitmap> = HashMap::new();
+        let keys = entries
+            .into_iter()
+            .enumerate()
+            .map(|(key_id, (key, value))| {
+                members.entry(value).or_default().insert(key_id as u32);
+                key
+            })
+            .collect();
+        Ok(MembershipIndex { keys, members })
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Id of a key, when the index holds it.
+    pub fn key_id(&self, key: &[u8]) -> Option<u32> {
+        self.keys
+            .binary_search_by(|k| k.as_ref().cmp(key))
+            .ok()
+            .map(|p| p as u32)
+    }
+
+    pub fn key(&self, key_id: u32) -> Option<&[u8]> {
+        self.keys.get(key_id as usize).map(|k| k.as_ref())
+    }
+
+    /// Ids of the keys holding a value.
+    pub fn members(&self, value: u64) -> Option<&RoaringBitmap> {
+        self.members.get(&value)
+    }
+
+    /// The values of the index, with the ids of the keys holding each.
+    pub fn values(&self) -> &HashMap<u64, RoaringBitmap> {
+        &self.members
+    }
+
+    /// Write the index as the key dictionary in id order, then each value with its bitmap.
+    pub fn save(&self, archive: &str, index_file: &str) -> Result<()> {
+        let index_handle = File::create(index_file)
+            .with_context(|| format!("Unable to create membership index file '{}'", index_file))?;
+        let mut index_writer = BufWriter::new(index_handle);
+
+        let header = MembershipHeader {
+            archive: archive.to_string(),
+            keys: self.keys.len(),
+            values: self.members.len(),
+        };
+        index_writer.write_all(MEMBERSHIP_MAGIC)?;
+        write_field(&mut index_writer, &serde_json::to_vec(&header)?)?;
+
+        let mut record_writer = zstd::Encoder::new(index_writer, MEMBERSHIP_LEVEL)?;
+        for key in &self.keys {
+            write_field(&mut record_writer, key)?;
+        }
+        let mut values: Vec<&u64> = self.members.keys().collect();
+        values.sort_unstable();
+        for value in values {
+            record_writer.write_all(&value.to_le_bytes())?;
+            write_field(&mut record_writer, &self.members[value].to_bytes())?;
+        }
+        record_writer.finish()?.flush()?;
+        Ok(())
+    }
+
+    pub fn load(index_file: &str) -> Result<MembershipIndex> {
+        let index_handle = File::open(index_file)
+            .with_context(|| format!("Unable to open membership index file '{}'", index_file))?;
+        let mut index_reader = BufReader::new(index_handle);
+
+        let mut magic = [0u8; 8];
+        if index_reader.read_exact(&mut magic).is_err() || &magic != MEMBERSHIP_MAGIC {
+            bail!(PipelineError::Usage(format!(
+                "'{}' is not a membership index!",
+                index_file
+            )));
+        }
+        let mut header_bytes: Vec<u8> = Vec::new();
+        read_field(&mut index_reader, &mut header_bytes)?;
+        let header: MembershipHeader = match serde_json::from_slice(&header_bytes) {
+            Ok(h) => h,
+            Err(_) => bail!(PipelineError::CorruptArchive(format!(
+                "Unable to parse the header of membership index '{}'!",
+                index_file
+            ))),
+        };
+
+        let mut record_reader = zstd::Decoder::with_buffer(index_reader)?;
+        let membership_index = read_index(&mut record_reader, &header).map_err(|e| {
+            PipelineError::CorruptArchive(format!(
+                "Unable to read membership index '{}': {}",
+                index_file, e
+            ))
+        })?;
+        Ok(membership_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn example_index() -> MembershipIndex {
+        let record_map: EitherMap<String, u64> = EitherMap::AHash(
+            [("c", 562), ("a", 9606), ("b", 562), ("d", 562)]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        );
+        MembershipIndex::from_map(&record_map).unwrap()
+    }
+
+    #[test]
+    fn test_membership_index_from_map() {
+        let obs_index = example_index();
+
+        assert_eq!(4, obs_index.key_count());
+        assert_eq!(Some(2), obs_index.key_id(b"c"));
+        assert_eq!(None, obs_index.key_id(b"e"));
+        assert_eq!(Some(&b"a"[..]), obs_index.key(0));
+
+        let obs_keys: Vec<&[u8]> = obs_index
+            .members(562)
+            .unwrap()
+            .iter()
+            .filter_map(|id| obs_index.key(id))
+            .collect();
+        assert_eq!(vec![&b"b"[..], b"c", b"d"], obs_keys);
+        assert!(obs_index.members(1).is_none());
+    }
+
+    #[test]
+    fn test_membership_index_round_trip() {
+        let index_file = "membership_index_round_trip.membership";
+        let exp_index = example_index();
+
+        exp_index.save("example.zstd", index_file).unwrap();
+        let obs_result = MembershipIndex::load(index_file);
+        let _ = std::fs::remove_file(index_file);
+
+        assert_eq!(exp_index, obs_result.unwrap());
+    }
+}
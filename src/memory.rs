@@ -0,0 +1,255 @@
+use crate::{FrameMeta, KeyType, MapOptions, Mode, PackedKey, RecordStats, ValueType, ValueWidth};
+use std::mem::size_of;
+
+// Heap allocations are rounded up to this many bytes by the allocator
+const ALLOCATION_SIZE: u64 = 16;
+// Bytes per record in the decoded text beyond its key and value: the separator and line ending
+const RECORD_FRAMING: u64 = 2;
+// Hash and index stored for each entry of an IndexMap, beside the key and value
+const INDEX_MAP_ENTRY: u64 = 2 * size_of::<usize>() as u64;
+
+/// Predicted peak memory of building the map of an archive in each mode, worked out from the
+/// record counts and lengths recorded in the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryEstimate {
+    pub records: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    pub frames: usize,
+    /// Frames whose records are counted in the index. The records of any others are scaled from
+    /// these by compressed length.
+    pub frames_counted: usize,
+    /// Predicted peak of each mode, in bytes
+    pub peaks: Vec<(Mode, u64)>,
+}
+
+impl MemoryEstimate {
+    pub fn peak_bytes(&self, mode: &Mode) -> u64 {
+        self.peaks
+            .iter()
+            .find(|(m, _)| m == mode)
+            .map_or(0, |(_, peak)| *peak)
+    }
+}
+
+/// Sizes of one map entry: the key and value held in the table, and what they allocate on the
+/// heap beyond it.
+struct EntrySize {
+    inline: u64,
+    heap: u64,
+}
+
+//region: Private functions
+
+/// Heap taken by `count` allocations holding `total` bytes between them.
+fn heap_bytes(total: u64, count: u64) -> u64 {
+    match (total, count) {
+        (0, _) | (_, 0) => 0,
+        _ => count * total.div_ceil(count).next_multiple_of(ALLOCATION_SIZE),
+    }
+}
+
+/// Size of a map entry under the key and value types of the map. Packed keys are taken to be
+/// accessions, which need nothing beyond the table.
+fn entry_size(map_options: &MapOptions, stats: &RecordStats) -> EntrySize {
+    let (key_inline, key_heap) = match map_options.key_type {
+        KeyType::String => (
+            size_of::<String>(),
+            heap_bytes(stats.key_bytes, stats.records),
+        ),
+        KeyType::Bytes => (
+            size_of::<Box<[u8]>>(),
+            heap_bytes(stats.key_bytes, stats.records),
+        ),
+        KeyType::Packed => (size_of::<PackedKey>(), 0),
+    };
+    let (value_inline, value_heap) = match (&map_options.value_type, &map_options.value_width) {
+        (ValueType::Integer, ValueWidth::U32) => (size_of::<u32>(), 0),
+        (ValueType::Integer, ValueWidth::U64) => (size_of::<u64>(), 0),
+        (ValueType::String, _) => (
+            size_of::<String>(),
+            heap_bytes(stats.value_bytes, stats.records),
+        ),
+    };
+    EntrySize {
+        inline: (key_inline + value_inline) as u64,
+        heap: key_heap + value_heap,
+    }
+}
+
+/// Table of a hash map holding the entries, which is kept at most 7/8 full and grows by doubling,
+/// with a control byte beside each slot.
+fn hash_table_bytes(entries: u64, slot_bytes: u64) -> u64 {
+    match entries {
+        0 => 0,
+        _ => (entries * 8).div_ceil(7).next_power_of_two() * (slot_bytes + 1),
+    }
+}
+
+/// An IndexMap, holding its entries in a vector under a hash table of their positions.
+fn index_map_bytes(entries: u64, slot_bytes: u64) -> u64 {
+    entries * (slot_bytes + INDEX_MAP_ENTRY) + hash_table_bytes(entries, size_of::<usize>() as u64)
+}
+
+/// Peak of the map alone in each mode, following how each gathers its records.
+fn map_peak(mode: &Mode, records: u64, entry: &EntrySize) -> u64 {
+    let map_bytes = match mode {
+        // Records go straight into the shared map
+        Mode::DashMap => hash_table_bytes(records, entry.inline),
+        // Every record is buffered before the map is built at its full size
+        Mode::Vector => records * entry.inline + hash_table_bytes(records, entry.inline),
+        // The map of each frame is merged into the largest, which holds its old table while
+        // growing into the new
+        Mode::Merge => 2 * hash_table_bytes(records, entry.inline),
+        // The map of every frame is buffered before they are merged in order
+        Mode::Ordered => 2 * index_map_bytes(records, entry.inline),
+        // Every record is buffered before the map is built in order
+        Mode::Reference => records * entry.inline + index_map_bytes(records, entry.inline),
+    };
+    map_bytes + entry.heap
+}
+
+//endregion:
+
+/// Predict the peak memory of building the map of an archive in every mode, before any frame is
+/// read. Each worker thread also holds a decoded frame and its parsed records, so the largest
+/// frame is counted once per thread, or once in the reference mode, which has no workers.
+/// Returns `None` when no frame of the index records its records, as for an index written before
+/// they were counted.
+pub(crate) fn estimate_memory(
+    idx_buffer: &[FrameMeta],
+    map_options: &MapOptions,
+    num_threads: usize,
+) -> Option<MemoryEstimate> {
+    let counted: Vec<(u64, RecordStats)> = idx_buffer
+        .iter()
+        .filter_map(|f| f.record_stats().map(|s| (f.length, s)))
+        .collect();
+    if counted.is_empty() {
+        return None;
+    }
+
+    let mut stats = counted
+        .iter()
+        .fold(RecordStats::default(), |mut total, (_, s)| {
+            total.records += s.records;
+            total.key_bytes += s.key_bytes;
+            total.value_bytes += s.value_bytes;
+            total
+        });
+    let counted_length: u64 = counted.iter().map(|(length, _)| length).sum();
+    let total_length: u64 = idx_buffer.iter().map(|f| f.length).sum();
+    if counted.len() < idx_buffer.len() && counted_length > 0 {
+        let scale = |n: u64| (n as f64 * total_length as f64 / counted_length as f64).ceil() as u64;
+        stats = RecordStats {
+            records: scale(stats.records),
+            key_bytes: scale(stats.key_bytes),
+            value_bytes: scale(stats.value_bytes),
+        };
+    }
+
+    let entry = entry_size(map_options, &stats);
+    let frame_bytes = counted
+        .iter()
+        .map(|(_, s)| {
+            let frame_entry = entry_size(map_options, s);
+            s.key_bytes
+                + s.value_bytes
+                + s.records * (RECORD_FRAMING + frame_entry.inline)
+                + frame_entry.heap
+        })
+        .max()
+        .unwrap_or_default();
+
+    let peaks = [
+        Mode::DashMap,
+        Mode::Vector,
+        Mode::Merge,
+        Mode::Ordered,
+        Mode::Reference,
+    ]
+    .into_iter()
+    .map(|mode| {
+        let workers = match mode {
+            Mode::Reference => 1,
+            _ => num_threads.max(1) as u64,
+        };
+        let peak = map_peak(&mode, stats.records, &entry) + frame_bytes * workers;
+        (mode, peak)
+    })
+    .collect();
+
+    Some(MemoryEstimate {
+        records: stats.records,
+        key_bytes: stats.key_bytes,
+        value_bytes: stats.value_bytes,
+        frames: idx_buffer.len(),
+        frames_counted: counted.len(),
+        peaks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn map_options(mode: Mode) -> MapOptions {
+        MapOptions {
+            mode,
+            key_type: KeyType::String,
+            value_type: ValueType::Integer,
+            value_width: ValueWidth::U64,
+            from_snapshot: None,
+            save_snapshot: None,
+            estimate_memory: true,
+            memory_budget: None,
+            cross_check: None,
+        }
+    }
+
+    #[test]
+    fn test_heap_bytes() {
+        assert_eq!(0, heap_bytes(0, 10));
+        assert_eq!(160, heap_bytes(120, 10));
+        assert_eq!(320, heap_bytes(170, 10));
+    }
+
+    #[test]
+    fn test_estimate_memory() {
+        let stats = RecordStats {
+            records: 1000,
+            key_bytes: 12_000,
+            value_bytes: 4_000,
+        };
+        let idx_buffer = vec![
+            FrameMeta::new(0, 100, 0).with_record_stats(stats),
+            FrameMeta::new(100, 100, 1).with_record_stats(stats),
+        ];
+
+        let obs_estimate = estimate_memory(&idx_buffer, &map_options(Mode::DashMap), 2).unwrap();
+        assert_eq!(2000, obs_estimate.records);
+        assert_eq!(2, obs_estimate.frames_counted);
+
+        // Buffering every record or frame map costs more than inserting into one shared map
+        let dash_peak = obs_estimate.peak_bytes(&Mode::DashMap);
+        assert!(dash_peak > 2000 * 16);
+        for mode in [Mode::Vector, Mode::Merge, Mode::Ordered] {
+            assert!(obs_estimate.peak_bytes(&mode) > dash_peak);
+        }
+
+        // A frame without counts is scaled from the others by its compressed length
+        let idx_buffer = vec![
+            FrameMeta::new(0, 100, 0).with_record_stats(stats),
+            FrameMeta::new(100, 300, 1),
+        ];
+        let obs_estimate = estimate_memory(&idx_buffer, &map_options(Mode::DashMap), 1).unwrap();
+        assert_eq!(4000, obs_estimate.records);
+        assert_eq!(1, obs_estimate.frames_counted);
+
+        assert_eq!(
+            None,
+            estimate_memory(&[FrameMeta::new(0, 100, 0)], &map_options(Mode::DashMap), 1)
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::FrameCache;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Upper bounds (seconds) of the decode latency buckets, spanning small cached frames to slow reads
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FrameTiming {
+    pub bytes_read: u64,
+    pub seconds: f64,
+}
+
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, seconds: f64) {
+        // Buckets are stored non-cumulatively, and summed when rendered
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| seconds <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add((seconds * 1e6) as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, output: &mut String) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+
+        let mut cumulative: u64 = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(output, "{}_sum {}", name, sum);
+        let _ = writeln!(output, "{}_count {}", name, count);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    frames_decoded: AtomicU64,
+    bytes_read: AtomicU64,
+    decode_latency: Histogram,
+    tasks_completed: AtomicU64,
+    tasks_requeued: AtomicU64,
+    workers_connected: AtomicU64,
+    lookup_requests: AtomicU64,
+    keys_looked_up: AtomicU64,
+    keys_found: AtomicU64,
+    records_held: AtomicU64,
+    /// Cache whose hits and misses are reported, when frames are read through one
+    frame_cache: Option<Arc<FrameCache>>,
+}
+
+//region: Private functions
+
+fn render_counter(name: &str, help: &str, kind: &str, value: u64, output: &mut String) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+    let _ = writeln!(output, "{} {}", name, value);
+}
+
+fn handle_request(stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Drain the request headers, which are not needed to answer a scrape
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, content_type, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..]
+    {
+        ["GET", "/metrics", ..] => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        ["GET", ..] => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+
+    let mut writer = stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+//endregion:
+
+impl Metrics {
+    /// Report the hits and misses of a frame cache alongside the other metrics.
+    pub fn with_frame_cache(mut self, frame_cache: Option<Arc<FrameCache>>) -> Self {
+        self.frame_cache = frame_cache;
+        self
+    }
+
+    pub fn record_frame(&self, timing: &FrameTiming) {
+        self.frames_decoded.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read
+            .fetch_add(timing.bytes_read, Ordering::Relaxed);
+        self.decode_latency.observe(timing.seconds);
+    }
+
+    pub fn record_task_completed(&self) {
+        self.tasks_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_task_requeued(&self) {
+        self.tasks_requeued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count frames decoded together, whose individual decode times were not taken.
+    pub fn record_frames(&self, frames: u64, bytes_read: u64) {
+        self.frames_decoded.fetch_add(frames, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes_read, Ordering::Relaxed);
+    }
+
+    pub fn record_lookup(&self, keys: u64, found: u64) {
+        self.lookup_requests.fetch_add(1, Ordering::Relaxed);
+        self.keys_looked_up.fetch_add(keys, Ordering::Relaxed);
+        self.keys_found.fetch_add(found, Ordering::Relaxed);
+    }
+
+    pub fn set_records_held(&self, records: u64) {
+        self.records_held.store(records, Ordering::Relaxed);
+    }
+
+    pub fn worker_connected(&self) {
+        self.workers_connected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn worker_disconnected(&self) {
+        self.workers_connected.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        render_counter(
+            "parallel_decompression_frames_decoded_total",
+            "Number of zstd frames decoded.",
+            "counter",
+            self.frames_decoded.load(Ordering::Relaxed),
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_bytes_read_total",
+            "Compressed bytes read from the zstd payload.",
+            "counter",
+            self.bytes_read.load(Ordering::Relaxed),
+            &mut output,
+        );
+        self.decode_latency.render(
+            "parallel_decompression_frame_decode_seconds",
+            "Time taken to read, decode and parse a single frame.",
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_tasks_completed_total",
+            "Number of frame ranges completed by workers.",
+            "counter",
+            self.tasks_completed.load(Ordering::Relaxed),
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_tasks_requeued_total",
+            "Number of frame ranges returned to the queue after a worker disconnected.",
+            "counter",
+            self.tasks_requeued.load(Ordering::Relaxed),
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_workers_connected",
+            "Number of workers currently connected to the coordinator.",
+            "gauge",
+            self.workers_connected.load(Ordering::Relaxed),
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_lookup_requests_total",
+            "Number of lookup requests answered.",
+            "counter",
+            self.lookup_requests.load(Ordering::Relaxed),
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_lookup_keys_total",
+            "Number of keys looked up.",
+            "counter",
+            self.keys_looked_up.load(Ordering::Relaxed),
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_lookup_keys_found_total",
+            "Number of keys looked up which had a record.",
+            "counter",
+            self.keys_found.load(Ordering::Relaxed),
+            &mut output,
+        );
+        render_counter(
+            "parallel_decompression_records_held",
+            "Number of records held in the map of a followed archive.",
+            "gauge",
+            self.records_held.load(Ordering::Relaxed),
+            &mut output,
+        );
+
+        if let Some(frame_cache) = &self.frame_cache {
+            render_counter(
+                "parallel_decompression_frame_cache_hits_total",
+                "Number of frames read back from the frame cache.",
+                "counter",
+                frame_cache.hits(),
+                &mut output,
+            );
+            render_counter(
+                "parallel_decompression_frame_cache_misses_total",
+                "Number of frames missing from the frame cache, which were decoded.",
+                "counter",
+                frame_cache.misses(),
+                &mut output,
+            );
+            render_counter(
+                "parallel_decompression_frame_cache_bytes",
+                "Bytes of decoded frames held in the frame cache.",
+                "gauge",
+                frame_cache.used_bytes(),
+                &mut output,
+            );
+        }
+
+        output
+    }
+}
+
+/// Answer scrapes of `/metrics` on a background thread for the lifetime of the process.
+pub fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    std::thread::Builder::new()
+        .name("metrics-endpoint".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = handle_request(stream, &metrics) {
+                    eprintln!("Unable to answer metrics request: {}", e);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_histogram_render() {
+        let histogram = Histogram::default();
+        histogram.observe(0.0002);
+        histogram.observe(0.003);
+        histogram.observe(2.0);
+
+        let mut obs_output = String::new();
+        histogram.render("latency", "Test histogram.", &mut obs_output);
+
+        assert!(obs_output.contains("# TYPE latency histogram\n"));
+        assert!(obs_output.contains("latency_bucket{le=\"0.0005\"} 1\n"));
+        assert!(obs_output.contains("latency_bucket{le=\"0.005\"} 2\n"));
+        assert!(obs_output.contains("latency_bucket{le=\"1\"} 2\n"));
+        assert!(obs_output.contains("latency_bucket{le=\"+Inf\"} 3\n"));
+        assert!(obs_output.contains("latency_count 3\n"));
+    }
+
+    #[test]
+    fn test_metrics_record_frame() {
+        let metrics = Metrics::default();
+        metrics.record_frame(&FrameTiming {
+            bytes_read: 151,
+            seconds: 0.002,
+        });
+        metrics.record_frame(&FrameTiming {
+            bytes_read: 120,
+            seconds: 0.004,
+        });
+
+        let obs_output = metrics.render();
+        assert!(obs_output.contains("parallel_decompression_frames_decoded_total 2\n"));
+        assert!(obs_output.contains("parallel_decompression_bytes_read_total 271\n"));
+        assert!(obs_output.contains("parallel_decompression_frame_decode_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn test_metrics_record_lookup() {
+        let metrics = Metrics::default();
+        metrics.record_lookup(3, 2);
+        metrics.record_lookup(1, 0);
+
+        let obs_output = metrics.render();
+        assert!(obs_output.contains("parallel_decompression_lookup_requests_total 2\n"));
+        assert!(obs_output.contains("parallel_decompression_lookup_keys_total 4\n"));
+        assert!(obs_output.contains("parallel_decompression_lookup_keys_found_total 2\n"));
+        assert!(!obs_output.contains("frame_cache"));
+    }
+
+    #[test]
+    fn test_metrics_frame_cache() {
+        let cache_dir = "metrics_frame_cache";
+        let frame_cache = Arc::new(FrameCache::open(cache_dir, 1 << 20).unwrap());
+        let metrics = Metrics::default().with_frame_cache(Some(frame_cache));
+
+        let obs_output = metrics.render();
+        let _ = std::fs::remove_dir_all(cache_dir);
+
+        assert!(obs_output.contains("parallel_decompression_frame_cache_hits_total 0\n"));
+        assert!(obs_output.contains("parallel_decompression_frame_cache_misses_total 0\n"));
+    }
+
+    #[test]
+    fn test_serve_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.worker_connected();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(serve_metrics(listener, metrics).is_ok());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut obs_response = String::new();
+        client.read_to_string(&mut obs_response).unwrap();
+
+        assert!(obs_response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(obs_response.contains("parallel_decompression_workers_connected 1\n"));
+    }
+
+    #[test]
+    fn test_serve_metrics_not_found() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(serve_metrics(listener, Arc::new(Metrics::default())).is_ok());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /other HTTP/1.1\r\n\r\n").unwrap();
+        let mut obs_response = String::new();
+        client.read_to_string(&mut obs_response).unwrap();
+
+        assert!(obs_response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,775 @@
+use crate::archive::IndexedArchive;
+use crate::frame_cache::{archive_key, decode_cached};
+use crate::numa::build_worker_pool;
+use crate::source::FrameSource;
+use crate::{FrameMeta, ParseOptions, PipelineError};
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Version of the kernel FUSE protocol spoken, which the kernel meets with its own
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+// Largest read answered in one reply, and the request buffer which holds it
+const MAX_READ: usize = 128 * 1024;
+const REQUEST_BUFFER: usize = MAX_READ + 4096;
+
+// Setuid helpers which mount on behalf of users without permission to mount filesystems, newest
+// first
+const FUSERMOUNT_BINARIES: [&str; 2] = ["fusermount3", "fusermount"];
+
+const ROOT_ID: u64 = 1;
+const CONTENT_ID: u64 = 2;
+
+// Request opcodes of the kernel FUSE protocol
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+// Keep pages of the content in the page cache between opens, as the content never changes
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+// Seconds for which the kernel may cache names and attributes
+const ATTR_TIMEOUT: u64 = 3600;
+
+/// Start of each frame in the decompressed content, in archive order.
+#[derive(Debug, PartialEq)]
+pub(crate) struct FrameLayout {
+    orders: Vec<u64>,
+    starts: Vec<u64>,
+    content_length: u64,
+}
+
+/// The decompressed content of an archive, presented as a single read-only file.
+struct MountedContent {
+    file_name: String,
+    archive: IndexedArchive,
+    layout: FrameLayout,
+    modified: u64,
+    uid: u32,
+    gid: u32,
+}
+
+/// A request from the kernel, split into its header fields and argument bytes.
+struct FuseRequest<'a> {
+    opcode: u32,
+    unique: u64,
+    node_id: u64,
+    arguments: &'a [u8],
+}
+
+//region: Private functions
+
+fn read_u32(bytes: &[u8], position: usize) -> u32 {
+    u32::from_ne_bytes(bytes[position..position + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], position: usize) -> u64 {
+    u64::from_ne_bytes(bytes[position..position + 8].try_into().unwrap())
+}
+
+fn parse_request(buffer: &[u8]) -> Option<FuseRequest<'_>> {
+    if buffer.len() < 40 {
+        return None;
+    }
+    Some(FuseRequest {
+        opcode: read_u32(buffer, 4),
+        unique: read_u64(buffer, 8),
+        node_id: read_u64(buffer, 16),
+        arguments: &buffer[40..],
+    })
+}
+
+/// Name of the file holding the decompressed content, which is the archive name without its
+/// '.zstd' extension.
+fn content_name(zstd_file: &str) -> String {
+    let file_name = std::path::Path::new(zstd_file)
+        .file_name()
+        .map_or(zstd_file.to_string(), |f| f.to_string_lossy().to_string());
+    match file_name.rsplit_once('.') {
+        Some((stem, "zstd" | "zst")) if !stem.is_empty() => stem.to_string(),
+        _ => format!("{}.out", file_name),
+    }
+}
+
+fn send_reply(fd: &OwnedFd, unique: u64, error: i32, body: &[u8]) -> Result<()> {
+    let mut reply: Vec<u8> = Vec::with_capacity(16 + body.len());
+    reply.extend_from_slice(&((16 + body.len()) as u32).to_ne_bytes());
+    reply.extend_from_slice(&(-error).to_ne_bytes());
+    reply.extend_from_slice(&unique.to_ne_bytes());
+    reply.extend_from_slice(body);
+
+    // A request interrupted before its reply is no longer waited on, which is not an error
+    // SAFETY: the pointer and length describe the reply buffer, which outlives the call.
+    let written = unsafe { libc::write(fd.as_raw_fd(), reply.as_ptr().cast(), reply.len()) };
+    match written {
+        n if n >= 0 => Ok(()),
+        _ => match std::io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            e => Err(e.into()),
+        },
+    }
+}
+
+/// How the filesystem was mounted, which decides how it is unmounted on failure.
+enum Mounted {
+    /// Through the mount syscall, by a process permitted to mount filesystems
+    Direct,
+    /// By a setuid fusermount binary, which also unmounts it
+    Helper(&'static str),
+}
+
+/// Mount the filesystem through the kernel, returning the FUSE device it is served on. Without
+/// permission to mount filesystems, the mount is left to fusermount3 (or fusermount), as libfuse
+/// does, so that ordinary users can mount and later 'fusermount -u' it.
+fn mount_fuse(mount_point: &str, uid: u32, gid: u32) -> Result<(OwnedFd, Mounted)> {
+    let fd: OwnedFd = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .context("Unable to open '/dev/fuse'")?
+        .into();
+    let target = CString::new(mount_point)?;
+    let mount_options = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        fd.as_raw_fd(),
+        uid,
+        gid
+    ))?;
+    // SAFETY: every pointer is to a NUL-terminated string which outlives the call.
+    let mounted = unsafe {
+        libc::mount(
+            c"parallel_decompression".as_ptr(),
+            target.as_ptr(),
+            c"fuse.parallel_decompression".as_ptr(),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+            mount_options.as_ptr().cast(),
+        )
+    };
+    if mounted == 0 {
+        return Ok((fd, Mounted::Direct));
+    }
+
+    let mount_error = std::io::Error::last_os_error();
+    if mount_error.raw_os_error() != Some(libc::EPERM) {
+        bail!(PipelineError::Usage(format!(
+            "Unable to mount at '{}': {}",
+            mount_point, mount_error
+        )));
+    }
+    drop(fd);
+
+    for binary in FUSERMOUNT_BINARIES {
+        match mount_with_helper(binary, mount_point) {
+            Ok(fd) => return Ok((fd, Mounted::Helper(binary))),
+            Err(e)
+                if e.downcast_ref::<std::io::Error>().map(|e| e.kind())
+                    == Some(std::io::ErrorKind::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    bail!(PipelineError::Usage(format!(
+        "Unable to mount at '{}', which needs permission to mount filesystems or fusermount3 to be installed: {}",
+        mount_point, mount_error
+    )))
+}
+
+/// Have a fusermount binary mount the filesystem and pass back the FUSE device it opened, over
+/// the socket named by '_FUSE_COMMFD'.
+fn mount_with_helper(binary: &str, mount_point: &str) -> Result<OwnedFd> {
+    let (socket, helper_socket) = UnixStream::pair()?;
+    // SAFETY: fcntl only changes the flags of a descriptor owned by helper_socket.
+    let cleared = unsafe { libc::fcntl(helper_socket.as_raw_fd(), libc::F_SETFD, 0) };
+    if cleared != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut helper = Command::new(binary)
+        .env("_FUSE_COMMFD", helper_socket.as_raw_fd().to_string())
+        .args([
+            "-o",
+            "ro,nosuid,nodev,default_permissions,fsname=parallel_decompression,subtype=parallel_decompression",
+            "--",
+            mount_point,
+        ])
+        .spawn()?;
+    // The helper holds its own copy, so the socket is closed at its end once it exits
+    drop(helper_socket);
+
+    let received = receive_fd(&socket);
+    let status = helper.wait()?;
+    match (received, status.success()) {
+        (Ok(fd), true) => Ok(fd),
+        (Ok(_), false) | (Err(_), _) => bail!(PipelineError::Usage(format!(
+            "Unable to mount at '{}', as {} failed ({})!",
+            mount_point, binary, status
+        ))),
+    }
+}
+
+/// Receive a single file descriptor passed over the socket as SCM_RIGHTS.
+fn receive_fd(socket: &UnixStream) -> Result<OwnedFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // Aligned for the cmsghdr it holds, and large enough for one descriptor
+    let mut control = [0u64; 8];
+
+    // SAFETY: msghdr is plain data, valid when zeroed, and its pointers are set to buffers which
+    // outlive the recvmsg call. CMSG_FIRSTHDR returns either null or a header within the control
+    // buffer the kernel filled, whose data holds a descriptor when its type is SCM_RIGHTS.
+    let received = unsafe {
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = libc::CMSG_SPACE(size_of::<libc::c_int>() as u32) as _;
+
+        match libc::recvmsg(socket.as_raw_fd(), &mut message, 0) {
+            n if n > 0 => {
+                let header = libc::CMSG_FIRSTHDR(&message);
+                match !header.is_null()
+                    && (*header).cmsg_level == libc::SOL_SOCKET
+                    && (*header).cmsg_type == libc::SCM_RIGHTS
+                {
+                    true => Some(std::ptr::read_unaligned(
+                        libc::CMSG_DATA(header) as *const libc::c_int
+                    )),
+                    false => None,
+                }
+            }
+            _ => None,
+        }
+    };
+    match received {
+        // SAFETY: the descriptor was just created for this process, and nothing else owns it.
+        Some(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+        None => bail!("No FUSE device was passed back"),
+    }
+}
+
+/// Unmount after a failure, detaching the filesystem in case a reader still holds it open.
+fn unmount(mount_point: &str, mounted: &Mounted) {
+    match mounted {
+        Mounted::Direct => {
+            if let Ok(target) = CString::new(mount_point) {
+                // SAFETY: the pointer is to a NUL-terminated string which outlives the call.
+                unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+            }
+        }
+        Mounted::Helper(binary) => {
+            let _ = Command::new(binary)
+                .args(["-u", "-z", "--", mount_point])
+                .status();
+        }
+    }
+}
+
+//endregion:
+
+impl FrameLayout {
+    /// Find the start of each frame in the decompressed content from the length of each frame.
+    pub(crate) fn from_lengths(frame_lengths: &[(u64, u64)]) -> FrameLayout {
+        let mut starts: Vec<u64> = Vec::with_capacity(frame_lengths.len());
+        let mut content_length: u64 = 0;
+        for (_, length) in frame_lengths {
+            starts.push(content_length);
+            content_length += length;
+        }
+
+        FrameLayout {
+            orders: frame_lengths.iter().map(|(order, _)| *order).collect(),
+            starts,
+            content_length,
+        }
+    }
+
+    /// Find the decompressed length of every frame. The index records the length of each frame it
+    /// compressed, so only frames of older indexes, or located by scanning, are decoded to
+    /// measure them, in parallel. Frames held in a frame cache are measured from the cache, and
+    /// those decoded are added to it, ready for the reads which follow.
+    pub(crate) fn measure(
+        source: &dyn FrameSource,
+        idx_buffer: &[FrameMeta],
+        num_threads: usize,
+        parse_options: &ParseOptions,
+    ) -> Result<FrameLayout> {
+        let pool = build_worker_pool(
+            num_threads,
+            parse_options.numa_placement,
+            &parse_options.threads,
+        );
+        let archive_key = archive_key(idx_buffer);
+        let mut frame_lengths: Vec<(u64, u64)> = pool.install(|| {
+            idx_buffer
+                .par_iter()
+                .map(|f| match f.uncompressed_length() {
+                    Some(length) => Ok((f.order, length)),
+                    None => Ok((
+                        f.order,
+                        decode_cached(source, f, archive_key, parse_options)?.len() as u64,
+                    )),
+                })
+                .collect::<Result<_>>()
+        })?;
+        frame_lengths.sort();
+        Ok(FrameLayout::from_lengths(&frame_lengths))
+    }
+
+    pub(crate) fn content_length(&self) -> u64 {
+        self.content_length
+    }
+
+    /// Decompressed length of the frame at a position in `orders`.
+    fn frame_length(&self, i: usize) -> u64 {
+        self.starts.get(i + 1).unwrap_or(&self.content_length) - self.starts[i]
+    }
+
+    /// Position in `orders` of the frame holding a byte of the content, and the offset of the
+    /// byte within the frame.
+    pub(crate) fn locate(&self, offset: u64) -> Option<(usize, u64)> {
+        if offset >= self.content_length {
+            return None;
+        }
+        let i = self.starts.partition_point(|s| *s <= offset) - 1;
+        Some((i, offset - self.starts[i]))
+    }
+}
+
+impl MountedContent {
+    fn attr(&self, node_id: u64) -> Vec<u8> {
+        let (size, mode, nlink) = match node_id {
+            ROOT_ID => (0, libc::S_IFDIR | 0o555, 2),
+            _ => (self.layout.content_length, libc::S_IFREG | 0o444, 1),
+        };
+
+        let mut attr: Vec<u8> = Vec::with_capacity(88);
+        attr.extend_from_slice(&node_id.to_ne_bytes());
+        attr.extend_from_slice(&size.to_ne_bytes());
+        attr.extend_from_slice(&size.div_ceil(512).to_ne_bytes());
+        for _ in 0..3 {
+            attr.extend_from_slice(&self.modified.to_ne_bytes());
+        }
+        attr.extend_from_slice(&[0u8; 12]);
+        for field in [mode, nlink, self.uid, self.gid, 0, 4096, 0] {
+            attr.extend_from_slice(&field.to_ne_bytes());
+        }
+        attr
+    }
+
+    fn entry_out(&self, node_id: u64) -> Vec<u8> {
+        let mut entry: Vec<u8> = Vec::with_capacity(128);
+        for field in [node_id, 0, ATTR_TIMEOUT, ATTR_TIMEOUT] {
+            entry.extend_from_slice(&field.to_ne_bytes());
+        }
+        entry.extend_from_slice(&[0u8; 8]);
+        entry.extend(self.attr(node_id));
+        entry
+    }
+
+    fn attr_out(&self, node_id: u64) -> Vec<u8> {
+        let mut attr_out: Vec<u8> = Vec::with_capacity(104);
+        attr_out.extend_from_slice(&ATTR_TIMEOUT.to_ne_bytes());
+        attr_out.extend_from_slice(&[0u8; 8]);
+        attr_out.extend(self.attr(node_id));
+        attr_out
+    }
+
+    /// Entries of the root directory after the given offset, packed as the kernel expects and
+    /// limited to the size asked for.
+    fn directory_entries(&self, offset: u64, size: usize) -> Vec<u8> {
+        let entries = [
+            (ROOT_ID, ".", libc::DT_DIR),
+            (ROOT_ID, "..", libc::DT_DIR),
+            (CONTENT_ID, self.file_name.as_str(), libc::DT_REG),
+        ];
+
+        let mut packed: Vec<u8> = Vec::new();
+        for (i, (node_id, name, kind)) in entries.iter().enumerate().skip(offset as usize) {
+            let entry_length = (24 + name.len()).next_multiple_of(8);
+            if packed.len() + entry_length > size {
+                break;
+            }
+            packed.extend_from_slice(&node_id.to_ne_bytes());
+            packed.extend_from_slice(&(i as u64 + 1).to_ne_bytes());
+            packed.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            packed.extend_from_slice(&(*kind as u32).to_ne_bytes());
+            packed.extend_from_slice(name.as_bytes());
+            packed.resize(packed.len() + entry_length - 24 - name.len(), 0);
+        }
+        packed
+    }
+
+    /// Read a range of the content, decoding its frames through the archive, which prefetches
+    /// the frames ahead of a reader working through the file in order.
+    fn read_content(&self, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let mut content: Vec<u8> = Vec::with_capacity(size);
+        let mut position = offset;
+
+        while content.len() < size {
+            let Some((i, frame_offset)) = self.layout.locate(position) else {
+                break;
+            };
+            let order = self.layout.orders[i];
+            let payload = self.archive.frame(order)?;
+            // A frame which does not decode to the length the index recorded would misplace
+            // every byte after it
+            if payload.len() as u64 != self.layout.frame_length(i) {
+                bail!(PipelineError::CorruptArchive(format!(
+                    "Frame {} decoded to {} bytes, where the index records {}!",
+                    order,
+                    payload.len(),
+                    self.layout.frame_length(i)
+                )));
+            }
+            let frame_offset = frame_offset as usize;
+            let take = (size - content.len()).min(payload.len() - frame_offset);
+            content.extend_from_slice(&payload[frame_offset..frame_offset + take]);
+            position += take as u64;
+        }
+        Ok(content)
+    }
+
+    /// Answer one request, returning false once the filesystem is being torn down.
+    fn handle_request(&self, fd: &OwnedFd, request: &FuseRequest) -> Result<bool> {
+        let (unique, node_id, arguments) = (request.unique, request.node_id, request.arguments);
+
+        match request.opcode {
+            FUSE_INIT => {
+                let mut init_out: Vec<u8> = Vec::with_capacity(64);
+                init_out.extend_from_slice(&FUSE_KERNEL_VERSION.to_ne_bytes());
+                init_out.extend_from_slice(&FUSE_KERNEL_MINOR_VERSION.to_ne_bytes());
+                init_out.extend_from_slice(&read_u32(arguments, 8).to_ne_bytes());
+                init_out.extend_from_slice(&0u32.to_ne_bytes());
+                init_out.extend_from_slice(&[0u8; 4]);
+                init_out.extend_from_slice(&(MAX_READ as u32).to_ne_bytes());
+                init_out.resize(64, 0);
+                send_reply(fd, unique, 0, &init_out)?;
+            }
+            FUSE_LOOKUP => {
+                let name = arguments.split(|&b| b == 0).next().unwrap_or_default();
+                match node_id == ROOT_ID && name == self.file_name.as_bytes() {
+                    true => send_reply(fd, unique, 0, &self.entry_out(CONTENT_ID))?,
+                    false => send_reply(fd, unique, libc::ENOENT, &[])?,
+                }
+            }
+            FUSE_GETATTR => match node_id {
+                ROOT_ID | CONTENT_ID => send_reply(fd, unique, 0, &self.attr_out(node_id))?,
+                _ => send_reply(fd, unique, libc::ENOENT, &[])?,
+            },
+            FUSE_OPEN | FUSE_OPENDIR => {
+                let mut open_out: Vec<u8> = Vec::with_capacity(16);
+                open_out.extend_from_slice(&0u64.to_ne_bytes());
+                open_out.extend_from_slice(&FOPEN_KEEP_CACHE.to_ne_bytes());
+                open_out.extend_from_slice(&[0u8; 4]);
+                send_reply(fd, unique, 0, &open_out)?;
+            }
+            FUSE_READ => {
+                let (offset, size) = (read_u64(arguments, 8), read_u32(arguments, 16) as usize);
+                match self.read_content(offset, size.min(MAX_READ)) {
+                    Ok(content) => send_reply(fd, unique, 0, &content)?,
+                    Err(e) => {
+                        eprintln!("Unable to read {} bytes at offset {}: {}", size, offset, e);
+                        send_reply(fd, unique, libc::EIO, &[])?
+                    }
+                }
+            }
+            FUSE_READDIR => {
+                let (offset, size) = (read_u64(arguments, 8), read_u32(arguments, 16) as usize);
+                send_reply(fd, unique, 0, &self.directory_entries(offset, size))?;
+            }
+            FUSE_STATFS => {
+                let mut statfs_out: Vec<u8> = vec![0u8; 80];
+                statfs_out[40..44].copy_from_slice(&4096u32.to_ne_bytes());
+                statfs_out[44..48].copy_from_slice(&255u32.to_ne_bytes());
+                send_reply(fd, unique, 0, &statfs_out)?;
+            }
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_ACCESS => {
+                send_reply(fd, unique, 0, &[])?;
+            }
+            // Forgetting a node and interrupting a request take no reply
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => {}
+            FUSE_DESTROY => return Ok(false),
+            _ => send_reply(fd, unique, libc::ENOSYS, &[])?,
+        }
+        Ok(true)
+    }
+}
+
+/// Mount the decompressed content of an archive as a read-only file in an otherwise empty
+/// directory, answering the kernel until the filesystem is unmounted. Frames are decoded on
+/// first read and kept by the archive. `on_ready` is given the path and length of the file once
+/// it is mounted.
+pub(crate) fn mount_archive(
+    zstd_file: &str,
+    source: Box<dyn FrameSource>,
+    idx_buffer: Vec<FrameMeta>,
+    mount_point: &str,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    on_ready: &mut dyn FnMut(&str, u64),
+) -> Result<()> {
+    let layout = FrameLayout::measure(source.as_ref(), &idx_buffer, num_threads, parse_options)?;
+    let modified = std::fs::metadata(zstd_file)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+        })
+        .as_secs();
+    let content = MountedContent {
+        file_name: content_name(zstd_file),
+        archive: IndexedArchive::from_source(source, idx_buffer, num_threads, parse_options)?,
+        layout,
+        modified,
+        // SAFETY: getuid and getgid cannot fail and take no pointers.
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    };
+
+    let (fd, mounted) = mount_fuse(mount_point, content.uid, content.gid)?;
+    on_ready(
+        &format!(
+            "{}/{}",
+            mount_point.trim_end_matches('/'),
+            content.file_name
+        ),
+        content.layout.content_length(),
+    );
+
+    let mut buffer: Vec<u8> = vec![0u8; REQUEST_BUFFER];
+    loop {
+        // SAFETY: the pointer and length describe the request buffer, which outlives the call.
+        let read = unsafe { libc::read(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+        if read < 0 {
+            match std::io::Error::last_os_error().raw_os_error() {
+                // The request was interrupted before it was read, so the next is waited on
+                Some(libc::ENOENT | libc::EINTR | libc::EAGAIN) => continue,
+                // The filesystem was unmounted
+                Some(libc::ENODEV) => return Ok(()),
+                _ => return Err(std::io::Error::last_os_error().into()),
+            }
+        }
+
+        let Some(request) = parse_request(&buffer[..read as usize]) else {
+            continue;
+        };
+        match content.handle_request(&fd, &request) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => {
+                unmount(mount_point, &mounted);
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::MemorySource;
+
+    fn load_index() -> Vec<FrameMeta> {
+        crate::decompression::load_frame_index(
+            std::fs::File::open("test/example.zstd.idx").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn mount_content(idx_buffer: Vec<FrameMeta>) -> MountedContent {
+        let source = MemorySource::new("example", std::fs::read("test/example.zstd").unwrap());
+        let parse_options = ParseOptions::default();
+        let layout = FrameLayout::measure(&source, &idx_buffer, 1, &parse_options).unwrap();
+
+        MountedContent {
+            file_name: "example".to_string(),
+            archive: IndexedArchive::from_source(Box::new(source), idx_buffer, 1, &parse_options)
+                .unwrap(),
+            layout,
+            modified: 0,
+            uid: 1000,
+            gid: 1000,
+        }
+    }
+
+    #[test]
+    fn test_frame_layout_measure() {
+        let exp_layout = FrameLayout::from_lengths(&[(0, 220), (1, 204), (2, 158)]);
+
+        // The lengths recorded in the index are used as they are, so the frames are never read
+        let zeroed_source = MemorySource::new("example", vec![0; 421]);
+        let obs_layout =
+            FrameLayout::measure(&zeroed_source, &load_index(), 1, &ParseOptions::default());
+        assert_eq!(exp_layout, obs_layout.unwrap());
+
+        // An index without lengths has its frames decoded to measure them
+        let unmeasured: Vec<FrameMeta> = load_index()
+            .into_iter()
+            .map(|f| FrameMeta::new(f.position, f.length, f.order))
+            .collect();
+        let source = MemorySource::new("example", std::fs::read("test/example.zstd").unwrap());
+        let obs_layout = FrameLayout::measure(&source, &unmeasured, 2, &ParseOptions::default());
+        assert_eq!(exp_layout, obs_layout.unwrap());
+    }
+
+    #[test]
+    fn test_read_content() {
+        let content = mount_content(load_index());
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+
+        // Within a frame, across the boundary of two frames, and past the end of the content
+        assert_eq!(
+            &exp_content[10..60],
+            &content.read_content(10, 50).unwrap()[..]
+        );
+        assert_eq!(
+            &exp_content[210..430],
+            &content.read_content(210, 220).unwrap()[..]
+        );
+        assert_eq!(
+            &exp_content[570..],
+            &content.read_content(570, 100).unwrap()[..]
+        );
+        assert_eq!(exp_content, content.read_content(0, 1 << 20).unwrap());
+        assert!(content.read_content(582, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_content_length_mismatch() {
+        let mut idx_buffer = load_index();
+        idx_buffer[0].uncompressed_length = Some(200);
+        let content = mount_content(idx_buffer);
+
+        let obs_error = content.read_content(0, 10).unwrap_err();
+        assert_eq!(
+            "Frame 0 decoded to 220 bytes, where the index records 200!",
+            format!("{:#}", obs_error)
+        );
+    }
+
+    #[test]
+    fn test_attr() {
+        let content = mount_content(load_index());
+
+        let obs_attr = content.attr(CONTENT_ID);
+        assert_eq!(88, obs_attr.len());
+        assert_eq!(CONTENT_ID, read_u64(&obs_attr, 0));
+        assert_eq!(582, read_u64(&obs_attr, 8));
+        assert_eq!(2, read_u64(&obs_attr, 16));
+        assert_eq!(libc::S_IFREG | 0o444, read_u32(&obs_attr, 60));
+        assert_eq!(1, read_u32(&obs_attr, 64));
+        assert_eq!(1000, read_u32(&obs_attr, 68));
+
+        let obs_attr = content.attr(ROOT_ID);
+        assert_eq!(0, read_u64(&obs_attr, 8));
+        assert_eq!(libc::S_IFDIR | 0o555, read_u32(&obs_attr, 60));
+        assert_eq!(2, read_u32(&obs_attr, 64));
+
+        // The entry and attribute replies wrap the attributes with their cache timeouts
+        let obs_entry = content.entry_out(CONTENT_ID);
+        assert_eq!(128, obs_entry.len());
+        assert_eq!(CONTENT_ID, read_u64(&obs_entry, 0));
+        assert_eq!(ATTR_TIMEOUT, read_u64(&obs_entry, 16));
+        assert_eq!(obs_attr.len() + 16, content.attr_out(ROOT_ID).len());
+        assert_eq!(582, read_u64(&content.attr_out(CONTENT_ID), 24));
+    }
+
+    #[test]
+    fn test_directory_entries() {
+        let content = mount_content(load_index());
+
+        // Each entry is a 24 byte header and its name, padded to 8 bytes
+        let obs_entries = content.directory_entries(0, 4096);
+        assert_eq!(96, obs_entries.len());
+        assert_eq!(b".", &obs_entries[24..25]);
+        assert_eq!(CONTENT_ID, read_u64(&obs_entries, 64));
+        assert_eq!(3, read_u64(&obs_entries, 72));
+        assert_eq!(7, read_u32(&obs_entries, 80));
+        assert_eq!(b"example", &obs_entries[88..95]);
+
+        // Entries after the offset, limited to those which fit in full
+        assert_eq!(&obs_entries[64..], &content.directory_entries(2, 4096)[..]);
+        assert_eq!(&obs_entries[..32], &content.directory_entries(0, 40)[..]);
+        assert!(content.directory_entries(3, 4096).is_empty());
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert!(parse_request(&[0u8; 39]).is_none());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        for field in [48u32, FUSE_READ] {
+            buffer.extend_from_slice(&field.to_ne_bytes());
+        }
+        for field in [7u64, CONTENT_ID, 0, 0] {
+            buffer.extend_from_slice(&field.to_ne_bytes());
+        }
+        buffer.extend_from_slice(&[1u8; 8]);
+
+        let obs_request = parse_request(&buffer).unwrap();
+        assert_eq!(FUSE_READ, obs_request.opcode);
+        assert_eq!(7, obs_request.unique);
+        assert_eq!(CONTENT_ID, obs_request.node_id);
+        assert_eq!(&[1u8; 8], obs_request.arguments);
+    }
+
+    #[test]
+    fn test_frame_layout_locate() {
+        let layout = FrameLayout::from_lengths(&[(0, 10), (1, 0), (2, 5)]);
+
+        assert_eq!(15, layout.content_length());
+        assert_eq!(Some((0, 0)), layout.locate(0));
+        assert_eq!(Some((0, 9)), layout.locate(9));
+        assert_eq!(Some((2, 0)), layout.locate(10));
+        assert_eq!(Some((2, 4)), layout.locate(14));
+        assert_eq!(None, layout.locate(15));
+    }
+
+    #[test]
+    fn test_content_name() {
+        assert_eq!("example", content_name("test/example.zstd"));
+        assert_eq!(
+            "nucl.accession2taxid",
+            content_name("nucl.accession2taxid.zst")
+        );
+        assert_eq!("example.out", content_name("example"));
+    }
+
+    #[test]
+    fn test_receive_fd() {
+        use std::io::Write;
+
+        // A helper which writes without passing a descriptor, or exits without writing, has not
+        // mounted anything
+        let (socket, mut helper_socket) = UnixStream::pair().unwrap();
+        helper_socket.write_all(b"x").unwrap();
+        assert!(receive_fd(&socket).is_err());
+
+        drop(helper_socket);
+        assert!(receive_fd(&socket).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::{bail, Result};
+use std::path::Path;
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+// CPUs a cpu_set_t can name, beyond which a worker cannot be pinned
+#[cfg(target_os = "linux")]
+const CPU_SET_CAPACITY: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const CPU_SET_CAPACITY: usize = usize::MAX;
+
+/// Naming and scheduling of the threads a run starts, so that bulk work can be told apart from
+/// other processes on a shared node and made to give way to them.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadOptions {
+    /// Replaces 'decompression' in the names of worker threads, and is put ahead of the names of
+    /// the threads which read and write alongside them
+    pub name_prefix: Option<String>,
+    /// Niceness given to each thread as it starts, from -20 (most favoured) to 19 (least)
+    pub niceness: Option<i32>,
+}
+
+impl ThreadOptions {
+    pub(crate) fn thread_name(&self, role: &str) -> String {
+        match &self.name_prefix {
+            Some(prefix) => format!("{prefix}-{role}"),
+            None => role.to_string(),
+        }
+    }
+
+    /// Apply the scheduling settings to the calling thread, warning when they cannot be.
+    pub(crate) fn apply_current(&self) {
+        if let Some(niceness) = self.niceness
+            && let Err(e) = renice_current_thread(niceness)
+        {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Start a named thread within the scope, under the scheduling settings.
+    pub(crate) fn spawn_scoped<'scope, T, F>(
+        &self,
+        scope: &'scope std::thread::Scope<'scope, '_>,
+        role: &str,
+        thread_fn: F,
+    ) -> std::io::Result<std::thread::ScopedJoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let thread_options = self.clone();
+        std::thread::Builder::new()
+            .name(self.thread_name(role))
+            .spawn_scoped(scope, move || {
+                thread_options.apply_current();
+                thread_fn()
+            })
+    }
+}
+
+//region: Private functions
+
+fn parse_cpulist(cpulist: &str) -> Result<Vec<usize>> {
+    // Kernel CPU lists are comma-separated CPUs or inclusive ranges, e.g. '0-3,8-11'
+    let mut cpus: Vec<usize> = Vec::new();
+
+    for part in cpulist.trim().split(',').filter(|p| !p.is_empty()) {
+        let parsed = match part.split_once('-') {
+            Some((first, last)) => first
+                .parse::<usize>()
+                .and_then(|f| last.parse::<usize>().map(|l| (f, l))),
+            None => part.parse::<usize>().map(|c| (c, c)),
+        };
+
+        match parsed {
+            Ok((first, last)) if first <= last => cpus.extend(first..=last),
+            _ => bail!("Unable to parse CPU list '{}'!", cpulist.trim()),
+        }
+    }
+
+    Ok(cpus)
+}
+
+fn load_numa_nodes(node_dir: &Path) -> Result<Vec<Vec<usize>>> {
+    let mut nodes: Vec<(usize, Vec<usize>)> = Vec::new();
+
+    for entry in std::fs::read_dir(node_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        let node_id = match file_name.strip_prefix("node").map(str::parse::<usize>) {
+            Some(Ok(n)) => n,
+            _ => continue,
+        };
+
+        let cpulist = std::fs::read_to_string(entry.path().join("cpulist"))?;
+        let cpus = parse_cpulist(&cpulist)?;
+
+        // Memory-only nodes have no CPUs for workers to run on
+        if !cpus.is_empty() {
+            nodes.push((node_id, cpus));
+        }
+    }
+
+    nodes.sort_by_key(|(n, _)| *n);
+    Ok(nodes.into_iter().map(|(_, cpus)| cpus).collect())
+}
+
+/// Drop the CPUs a cpu_set_t cannot name, and any node left without CPUs, returning the number
+/// of CPUs dropped.
+fn settable_cpus(nodes: Vec<Vec<usize>>, capacity: usize) -> (Vec<Vec<usize>>, usize) {
+    let mut dropped = 0;
+    let nodes = nodes
+        .into_iter()
+        .map(|cpus| {
+            let (settable, beyond): (Vec<usize>, Vec<usize>) =
+                cpus.into_iter().partition(|cpu| *cpu < capacity);
+            dropped += beyond.len();
+            settable
+        })
+        .filter(|cpus| !cpus.is_empty())
+        .collect();
+    (nodes, dropped)
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpus: &[usize]) -> Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, valid when zeroed, and is only passed by reference
+    // to the kernel for the calling thread. CPU_SET indexes the mask, so is only given CPUs below
+    // CPU_SETSIZE, as left by settable_cpus.
+    unsafe {
+        let mut cpu_set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut cpu_set);
+        }
+
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) != 0 {
+            bail!(
+                "Unable to pin worker thread: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpus: &[usize]) -> Result<()> {
+    bail!("Pinning worker threads to NUMA nodes is only supported on Linux!")
+}
+
+#[cfg(target_os = "linux")]
+fn renice_current_thread(niceness: i32) -> Result<()> {
+    // On Linux each thread has its own niceness, set through its thread id
+    // SAFETY: gettid and setpriority only act on the calling thread and take no pointers.
+    let result =
+        unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, niceness) };
+    if result != 0 {
+        bail!(
+            "Unable to set the niceness of thread '{}' to {}: {}",
+            std::thread::current().name().unwrap_or_default(),
+            niceness,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn renice_current_thread(_niceness: i32) -> Result<()> {
+    bail!("Setting the niceness of threads is only supported on Linux!")
+}
+
+//endregion:
+
+/// Number of NUMA nodes with CPUs, counting a machine whose topology cannot be read as one node.
+pub(crate) fn numa_node_count() -> usize {
+    load_numa_nodes(Path::new(NODE_DIR)).map_or(1, |n| n.len().max(1))
+}
+
+/// Build the worker pool used to read and parse frames. With NUMA placement, workers are spread
+/// round-robin across the nodes and pinned to that node's CPUs. Only threads are placed: no
+/// memory is bound to a node, so a page lands on the node of the thread which first touches it.
+/// The frames a worker reads and decodes are likely to be local to it, but the map they are
+/// gathered into is not, and frames are not assigned to the node which read them. Workers are
+/// named and scheduled under the thread options.
+pub(crate) fn build_worker_pool(
+    num_threads: usize,
+    numa_placement: bool,
+    thread_options: &ThreadOptions,
+) -> rayon::ThreadPool {
+    let nodes = match numa_placement {
+        true => match load_numa_nodes(Path::new(NODE_DIR))
+            .map(|n| settable_cpus(n, CPU_SET_CAPACITY))
+        {
+            Ok((n, dropped)) if !n.is_empty() => {
+                if dropped > 0 {
+                    eprintln!(
+                        "WARNING: {} CPUs are numbered beyond the {} a CPU set can hold, so no worker will be pinned to them.",
+                        dropped, CPU_SET_CAPACITY
+                    );
+                }
+                n
+            }
+            Ok(_) => {
+                eprintln!("No NUMA nodes with CPUs were found, worker threads will not be pinned.");
+                Vec::new()
+            }
+            Err(e) => {
+                eprintln!(
+                    "Unable to read the NUMA topology, worker threads will not be pinned: {}",
+                    e
+                );
+                Vec::new()
+            }
+        },
+        false => Vec::new(),
+    };
+
+    let name_prefix = thread_options
+        .name_prefix
+        .clone()
+        .unwrap_or_else(|| "decompression".to_string());
+    let mut pool_builder = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(move |i| format!("{name_prefix}-worker-{i}"));
+
+    if !nodes.is_empty() || thread_options.niceness.is_some() {
+        let thread_options = thread_options.clone();
+        pool_builder = pool_builder.start_handler(move |i| {
+            if !nodes.is_empty()
+                && let Err(e) = pin_current_thread(&nodes[i % nodes.len()])
+            {
+                eprintln!("{}", e);
+            }
+            thread_options.apply_current();
+        });
+    }
+
+    pool_builder.build().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(vec![0], parse_cpulist("0\n").unwrap());
+        assert_eq!(vec![0, 1, 2, 3], parse_cpulist("0-3").unwrap());
+        assert_eq!(vec![0, 1, 8, 9, 12], parse_cpulist("0-1,8-9,12").unwrap());
+        assert_eq!(Vec::<usize>::new(), parse_cpulist("\n").unwrap());
+    }
+
+    #[test]
+    fn test_parse_cpulist_invalid() {
+        assert!(parse_cpulist("a-b").is_err());
+        assert!(parse_cpulist("3-1").is_err());
+    }
+
+    #[test]
+    fn test_load_numa_nodes() {
+        let node_dir = "load_numa_nodes";
+        for (node, cpulist) in [("node1", "4-7\n"), ("node0", "0-3\n"), ("node2", "\n")] {
+            let path = Path::new(node_dir).join(node);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join("cpulist"), cpulist).unwrap();
+        }
+        std::fs::write(Path::new(node_dir).join("online"), "0-2\n").unwrap();
+
+        let obs_result = load_numa_nodes(Path::new(node_dir));
+        let _ = std::fs::remove_dir_all(node_dir);
+
+        // Nodes are ordered by id, and the CPU-less node is dropped
+        let exp_nodes = vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]];
+        assert_eq!(exp_nodes, obs_result.unwrap());
+    }
+
+    #[test]
+    fn test_settable_cpus() {
+        let nodes = vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]];
+
+        let (obs_nodes, obs_dropped) = settable_cpus(nodes.clone(), 6);
+        assert_eq!(vec![vec![0, 1, 2, 3], vec![4, 5]], obs_nodes);
+        assert_eq!(4, obs_dropped);
+
+        let (obs_nodes, obs_dropped) = settable_cpus(nodes.clone(), 16);
+        assert_eq!(nodes, obs_nodes);
+        assert_eq!(0, obs_dropped);
+    }
+
+    #[test]
+    fn test_build_worker_pool() {
+        let pool = build_worker_pool(2, true, &ThreadOptions::default());
+        assert_eq!(2, pool.current_num_threads());
+
+        let obs_name = pool.install(|| std::thread::current().name().map(String::from));
+        assert!(obs_name.unwrap().starts_with("decompression-worker-"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_build_worker_pool_thread_options() {
+        // Raising the niceness needs no privileges, so it can be checked from any test run
+        let thread_options = ThreadOptions {
+            name_prefix: Some("bulk".into()),
+            niceness: Some(19),
+        };
+        let pool = build_worker_pool(1, false, &thread_options);
+
+        let (obs_name, obs_niceness) = pool.install(|| {
+            // SAFETY: getpriority only reads the niceness of the calling thread.
+            let niceness = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+            (std::thread::current().name().map(String::from), niceness)
+        });
+        assert_eq!(Some("bulk-worker-0".into()), obs_name);
+        assert_eq!(19, obs_niceness);
+
+        let obs_name = std::thread::scope(|scope| {
+            thread_options
+                .spawn_scoped(scope, "join-writer", || {
+                    std::thread::current().name().map(String::from)
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        });
+        assert_eq!(Some("bulk-join-writer".into()), obs_name);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::RecordKey;
+use std::borrow::Cow;
+
+// Letters (or underscores) ahead of the digits of an accession, five bits each
+const MAX_PREFIX_LENGTH: usize = 6;
+const PREFIX_BITS: u32 = 5;
+// Every number of up to 19 digits fits within a u64
+const MAX_DIGITS: usize = 19;
+const MAX_VERSION: u64 = u16::MAX as u64;
+
+const DIGITS_SHIFT: u32 = PREFIX_BITS * MAX_PREFIX_LENGTH as u32;
+const VERSIONED_SHIFT: u32 = DIGITS_SHIFT + 5;
+const VERSION_SHIFT: u32 = VERSIONED_SHIFT + 1;
+
+/// A record key which packs NCBI-style accessions, such as 'WP_413685322.1' or 'NC_000913',
+/// into two integers in place of a heap allocation. Keys of any other shape are held as their
+/// bytes, so every key can be read and written back exactly as it appears in the record.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PackedKey {
+    /// The prefix, digit count and version of the accession, and the value of its digits
+    Accession {
+        layout: u64,
+        number: u64,
+    },
+    Bytes(Box<[u8]>),
+}
+
+//region: Private functions
+
+fn prefix_code(b: u8) -> Option<u64> {
+    match b {
+        b'A'..=b'Z' => Some((b - b'A') as u64 + 1),
+        b'_' => Some(27),
+        _ => None,
+    }
+}
+
+fn prefix_byte(code: u64) -> u8 {
+    match code {
+        27 => b'_',
+        c => b'A' + (c - 1) as u8,
+    }
+}
+
+fn parse_digits(digits: &[u8]) -> Option<u64> {
+    digits.iter().try_fold(0u64, |n, b| match b {
+        b'0'..=b'9' => n.checked_mul(10)?.checked_add((b - b'0') as u64),
+        _ => None,
+    })
+}
+
+/// Pack an accession of one to six capital letters or underscores, then one to 19 digits, then
+/// optionally a version of up to 65535 written without leading zeros.
+fn pack_accession(bytes: &[u8]) -> Option<(u64, u64)> {
+    let (accession, version) = match bytes.iter().rposition(|&b| b == b'.') {
+        Some(p) => {
+            let version = &bytes[p + 1..];
+            if version.is_empty() || (version.len() > 1 && version[0] == b'0') {
+                return None;
+            }
+            match parse_digits(version) {
+                Some(v) if v <= MAX_VERSION => (&bytes[..p], Some(v)),
+                _ => return None,
+            }
+        }
+        None => (bytes, None),
+    };
+
+    let prefix_length = accession.iter().take_while(|b| !b.is_ascii_digit()).count();
+    let digits = &accession[prefix_length..];
+    if !(1..=MAX_PREFIX_LENGTH).contains(&prefix_length)
+        || !(1..=MAX_DIGITS).contains(&digits.len())
+    {
+        return None;
+    }
+
+    let mut layout: u64 = 0;
+    for (i, b) in accession[..prefix_length].iter().enumerate() {
+        layout |= prefix_code(*b)? << (PREFIX_BITS * i as u32);
+    }
+    layout |= (digits.len() as u64) << DIGITS_SHIFT;
+    if let Some(v) = version {
+        layout |= 1 << VERSIONED_SHIFT | v << VERSION_SHIFT;
+    }
+    Some((layout, parse_digits(digits)?))
+}
+
+fn unpack_accession(layout: u64, number: u64) -> Vec<u8> {
+    let mut bytes: Vec<u8> = (0..MAX_PREFIX_LENGTH as u32)
+        .map(|i| (layout >> (PREFIX_BITS * i)) & 0x1f)
+        .take_while(|c| *c != 0)
+        .map(prefix_byte)
+        .collect();
+
+    let digit_count = ((layout >> DIGITS_SHIFT) & 0x1f) as usize;
+    bytes.extend_from_slice(format!("{:0digit_count$}", number).as_bytes());
+    if (layout >> VERSIONED_SHIFT) & 1 == 1 {
+        bytes.extend_from_slice(format!(".{}", layout >> VERSION_SHIFT).as_bytes());
+    }
+    bytes
+}
+
+//endregion:
+
+impl RecordKey for PackedKey {
+    const TAG: &'static str = "packed";
+
+    fn from_key_bytes(bytes: &[u8], strict_utf8: bool) -> Option<Self> {
+        if let Some((layout, number)) = pack_accession(bytes) {
+            return Some(PackedKey::Accession { layout, number });
+        }
+        if strict_utf8 && std::str::from_utf8(bytes).is_err() {
+            return None;
+        }
+        Some(PackedKey::Bytes(bytes.into()))
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            PackedKey::Accession { layout, number } => {
+                Cow::Owned(unpack_accession(*layout, *number))
+            }
+            PackedKey::Bytes(bytes) => Cow::Borrowed(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_packed_key_accessions() {
+        for accession in [
+            "WP_413685322.1",
+            "NC_000913.3",
+            "NC_000913",
+            "XP_000000001.12",
+            "GCF_000001405.40",
+            "A0",
+            "ABCDEF9999999999999999999.65535",
+        ] {
+            let obs_key = PackedKey::from_key_bytes(accession.as_bytes(), true).unwrap();
+            assert!(
+                matches!(obs_key, PackedKey::Accession { .. }),
+                "{accession}"
+            );
+            assert_eq!(accession.as_bytes(), &obs_key.key_bytes()[..]);
+        }
+
+        // Leading zeros and versions are part of the key
+        let key_of = |k: &str| PackedKey::from_key_bytes(k.as_bytes(), true).unwrap();
+        assert_ne!(key_of("WP_1"), key_of("WP_01"));
+        assert_ne!(key_of("WP_1"), key_of("WP_1.1"));
+    }
+
+    #[test]
+    fn test_packed_key_fallback() {
+        for key in [
+            "wp_413685322.1",
+            "WP_413685322.01",
+            "WP_413685322.65536",
+            "WP_413685322.",
+            "ABCDEFG1",
+            "WP_99999999999999999999",
+            "WP_1A",
+            "12345",
+            "",
+        ] {
+            let obs_key = PackedKey::from_key_bytes(key.as_bytes(), true).unwrap();
+            assert_eq!(PackedKey::Bytes(key.as_bytes().into()), obs_key);
+            assert_eq!(key.as_bytes(), &obs_key.key_bytes()[..]);
+        }
+
+        assert!(PackedKey::from_key_bytes(b"\xff1", true).is_none());
+        assert!(PackedKey::from_key_bytes(b"\xff1", false).is_some());
+    }
+
+    #[test]
+    fn test_packed_key_size() {
+        // No larger than a String, and with no allocation behind it for an accession
+        assert!(std::mem::size_of::<PackedKey>() <= std::mem::size_of::<String>());
+    }
+}
@@ -0,0 +1,390 @@
+use crate::decompression::{gather_zstd_frame, FrameLedger};
+use crate::numa::build_worker_pool;
+use crate::source::FrameSource;
+use crate::{BadRecord, DecompressionSummary, FrameMeta, ParseOptions, PipelineError, RecordKey};
+use ahash::{AHashMap, AHashSet};
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+// Number of record batches which can be queued for each writer before workers block
+const PARTITION_CHANNEL_BOUND: usize = 64;
+// Partition files held open at once across all writers, kept well inside the usual limit of
+// 1024 open files
+const MAX_OPEN_PARTITIONS: usize = 512;
+
+/// The partition files of one writer. Once more than `max_open` are open, the file written least
+/// recently is closed, and opened again to append to it should another record arrive for it.
+struct PartitionFiles<'a> {
+    output_dir: &'a str,
+    buckets: Option<u64>,
+    max_open: usize,
+    open: AHashMap<u64, (BufWriter<File>, u64)>,
+    /// Open partitions by the tick of their last write, oldest first
+    recently_used: BTreeMap<u64, u64>,
+    /// Partitions created by this run, which are appended to rather than truncated when reopened
+    created: AHashSet<u64>,
+    tick: u64,
+}
+
+//region: Private functions
+
+fn partition_of(value: u64, buckets: Option<u64>) -> u64 {
+    match buckets {
+        Some(b) => value % b,
+        None => value,
+    }
+}
+
+fn partition_path(output_dir: &str, partition: u64, buckets: Option<u64>) -> String {
+    let file_name = match buckets {
+        Some(_) => format!("bucket_{}.tsv", partition),
+        None => format!("{}.tsv", partition),
+    };
+
+    Path::new(output_dir)
+        .join(file_name)
+        .to_string_lossy()
+        .to_string()
+}
+
+fn partition_writer<K: RecordKey>(
+    batch_receiver: Receiver<Vec<(K, u64)>>,
+    output_dir: &str,
+    buckets: Option<u64>,
+    max_open: usize,
+) -> Result<usize> {
+    // Each writer owns every partition routed to it, so no file is shared between threads
+    let mut partition_files = PartitionFiles::new(output_dir, buckets, max_open);
+    let mut records_written: usize = 0;
+
+    for batch in batch_receiver {
+        for (key, value) in batch {
+            let writer = partition_files.writer(partition_of(value, buckets))?;
+            writer.write_all(&key.key_bytes())?;
+            writeln!(writer, "\t{}", value)?;
+            records_written += 1;
+        }
+    }
+
+    partition_files.close_all()?;
+    Ok(records_written)
+}
+
+fn route_frame<K: RecordKey>(
+    source: &dyn FrameSource,
+    idx_frame: FrameMeta,
+    parse_options: &ParseOptions,
+    batch_senders: &[SyncSender<Vec<(K, u64)>>],
+    buckets: Option<u64>,
+    frame_ledger: &FrameLedger,
+) -> Result<Vec<BadRecord>> {
+    let (payload_data, bad_records) =
+        gather_zstd_frame(source, idx_frame, parse_options, frame_ledger)?.unwrap_or_default();
+
+    send_batches(payload_data, batch_senders, buckets)?;
+    Ok(bad_records)
+}
+
+fn send_batches<K: RecordKey>(
+    payload_data: Vec<(K, u64)>,
+    batch_senders: &[SyncSender<Vec<(K, u64)>>],
+    buckets: Option<u64>,
+) -> Result<()> {
+    let num_writers = batch_senders.len() as u64;
+    let mut batches: Vec<Vec<(K, u64)>> = (0..num_writers).map(|_| Vec::new()).collect();
+
+    for (k, v) in payload_data {
+        let writer_index = partition_of(v, buckets) % num_writers;
+        batches[writer_index as usize].push((k, v));
+    }
+
+    for (batch_sender, batch) in batch_senders.iter().zip(batches) {
+        if !batch.is_empty() && batch_sender.send(batch).is_err() {
+            bail!("A partition writer stopped before all records were written!");
+        }
+    }
+
+    Ok(())
+}
+
+//endregion:
+
+impl<'a> PartitionFiles<'a> {
+    fn new(output_dir: &'a str, buckets: Option<u64>, max_open: usize) -> PartitionFiles<'a> {
+        PartitionFiles {
+            output_dir,
+            buckets,
+            max_open: max_open.max(1),
+            open: AHashMap::new(),
+            recently_used: BTreeMap::new(),
+            created: AHashSet::new(),
+            tick: 0,
+        }
+    }
+
+    /// The writer of a partition, opening its file if need be.
+    fn writer(&mut self, partition: u64) -> Result<&mut BufWriter<File>> {
+        self.tick += 1;
+        if let Some((_, last_used)) = self.open.get(&partition) {
+            self.recently_used.remove(last_used);
+        } else {
+            if self.open.len() >= self.max_open {
+                self.close_oldest()?;
+            }
+            let path = partition_path(self.output_dir, partition, self.buckets);
+            let handle = match self.created.insert(partition) {
+                true => File::create(&path),
+                false => OpenOptions::new().append(true).open(&path),
+            }
+            .with_context(|| format!("Unable to create partition file '{}'", path))?;
+            self.open
+                .insert(partition, (BufWriter::new(handle), self.tick));
+        }
+
+        self.recently_used.insert(self.tick, partition);
+        let (writer, last_used) = self.open.get_mut(&partition).unwrap();
+        *last_used = self.tick;
+        Ok(writer)
+    }
+
+    fn close_oldest(&mut self) -> Result<()> {
+        if let Some((_, partition)) = self.recently_used.pop_first()
+            && let Some((mut writer, _)) = self.open.remove(&partition)
+        {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn close_all(&mut self) -> Result<()> {
+        for (writer, _) in self.open.values_mut() {
+            writer.flush()?;
+        }
+        self.open.clear();
+        self.recently_used.clear();
+        Ok(())
+    }
+}
+
+pub fn write_partitioned_zstd<K: RecordKey>(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    output_dir: &str,
+    buckets: Option<u64>,
+) -> Result<(usize, DecompressionSummary)> {
+    if buckets == Some(0) {
+        bail!(PipelineError::Usage(
+            "The number of partition buckets must be greater than zero!".into()
+        ));
+    }
+    std::fs::create_dir_all(output_dir)?;
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+
+    // One writer thread per worker, each fed through its own bounded channel, and sharing the
+    // open partition files between them
+    let (batch_senders, batch_receivers): (Vec<_>, Vec<_>) = (0..num_threads.max(1))
+        .map(|_| sync_channel::<Vec<(K, u64)>>(PARTITION_CHANNEL_BOUND))
+        .unzip();
+    let max_open = MAX_OPEN_PARTITIONS / batch_receivers.len();
+
+    let frame_ledger = FrameLedger::default();
+
+    std::thread::scope(|scope| {
+        let writer_handles: Vec<_> = batch_receivers
+            .into_iter()
+            .enumerate()
+            .map(|(i, batch_receiver)| {
+                parse_options
+                    .threads
+                    .spawn_scoped(scope, &format!("partition-writer-{i}"), move || {
+                        partition_writer(batch_receiver, output_dir, buckets, max_open)
+                    })
+                    .unwrap()
+            })
+            .collect();
+
+        let bad_buffer: Result<Vec<Vec<BadRecord>>> = pool.install(|| {
+            idx_buffer
+                .into_iter()
+                .par_bridge()
+                .map(|idx_frame| {
+                    route_frame(
+                        source,
+                        idx_frame,
+                        parse_options,
+                        &batch_senders,
+                        buckets,
+                        &frame_ledger,
+                    )
+                })
+                .collect()
+        });
+
+        // Lines which span frames are only complete once every frame has been read
+        let bad_buffer = bad_buffer.and_then(|mut bad_buffer| {
+            for (_, (payload_data, stitched_bad)) in frame_ledger.stitch(parse_options)? {
+                send_batches(payload_data, &batch_senders, buckets)?;
+                bad_buffer.push(stitched_bad);
+            }
+            Ok(bad_buffer)
+        });
+
+        // Close the channels so that the writers finish once their queues are drained
+        drop(batch_senders);
+
+        let mut records_written: usize = 0;
+        for writer_handle in writer_handles {
+            match writer_handle.join() {
+                Ok(result) => records_written += result?,
+                Err(_) => bail!("A partition writer thread panicked!"),
+            }
+        }
+
+        let summary = frame_ledger.into_summary(bad_buffer?.into_iter().flatten().collect());
+        Ok((records_written, summary))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+    use std::fs::OpenOptions;
+    use std::io::BufReader;
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn read_sorted_lines(file_path: &str) -> Vec<String> {
+        let mut lines: Vec<String> = std::fs::read_to_string(file_path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn test_partition_of() {
+        assert_eq!(562, partition_of(562, None));
+        assert_eq!(2, partition_of(562, Some(4)));
+    }
+
+    #[test]
+    fn test_partition_path() {
+        assert_eq!("out/562.tsv", partition_path("out", 562, None));
+        assert_eq!("out/bucket_2.tsv", partition_path("out", 2, Some(4)));
+    }
+
+    #[test]
+    fn test_partition_files() {
+        let output_dir = "partition_files";
+        std::fs::create_dir_all(output_dir).unwrap();
+        std::fs::write(partition_path(output_dir, 3, None), "stale\n").unwrap();
+
+        // With room for two open files, writing to a third closes the one written least recently,
+        // which is reopened to append when written to again
+        let mut partition_files = PartitionFiles::new(output_dir, None, 2);
+        for (partition, line) in [(1, "a"), (2, "b"), (1, "c"), (3, "d"), (2, "e"), (3, "f")] {
+            writeln!(partition_files.writer(partition).unwrap(), "{}", line).unwrap();
+            assert!(partition_files.open.len() <= 2);
+        }
+        partition_files.close_all().unwrap();
+
+        let obs_contents: Vec<String> = [1, 2, 3]
+            .iter()
+            .map(|p| std::fs::read_to_string(partition_path(output_dir, *p, None)).unwrap())
+            .collect();
+        let _ = std::fs::remove_dir_all(output_dir);
+
+        // A file left by an earlier run is written over, not appended to
+        assert_eq!(vec!["a\nc\n", "b\ne\n", "d\nf\n"], obs_contents);
+    }
+
+    #[test]
+    fn test_write_partitioned_zstd_by_value() {
+        let output_dir = "write_partitioned_zstd_by_value";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let obs_result = write_partitioned_zstd::<String>(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            2,
+            &ParseOptions::default(),
+            output_dir,
+            None,
+        );
+        assert!(obs_result.is_ok());
+
+        let (obs_written, _) = obs_result.unwrap();
+        assert_eq!(30, obs_written);
+
+        let exp_lines: Vec<String> = vec![
+            "EFG1759503.1\t562".into(),
+            "EGJ4377881.1\t562".into(),
+            "EJZ1046351.1\t562".into(),
+            "EOA4653345.1\t562".into(),
+            "EOP3024222.1\t562".into(),
+        ];
+        assert_eq!(
+            exp_lines,
+            read_sorted_lines("write_partitioned_zstd_by_value/562.tsv")
+        );
+
+        let exp_lines: Vec<String> = vec!["KLA26572.1\t1396".into()];
+        assert_eq!(
+            exp_lines,
+            read_sorted_lines("write_partitioned_zstd_by_value/1396.tsv")
+        );
+
+        let _ = std::fs::remove_dir_all(output_dir);
+    }
+
+    #[test]
+    fn test_write_partitioned_zstd_buckets() {
+        let output_dir = "write_partitioned_zstd_buckets";
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+
+        let obs_result = write_partitioned_zstd::<String>(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            3,
+            &ParseOptions::default(),
+            output_dir,
+            Some(2),
+        );
+        assert!(obs_result.is_ok());
+
+        // Every record should land in the bucket matching its value
+        let mut total_lines = 0;
+        for bucket in 0..2 {
+            let lines = read_sorted_lines(&partition_path(output_dir, bucket, Some(2)));
+            for line in &lines {
+                let (_, value) = line.split_once('\t').unwrap();
+                assert_eq!(bucket, value.parse::<u64>().unwrap() % 2);
+            }
+            total_lines += lines.len();
+        }
+        assert_eq!(30, total_lines);
+
+        let _ = std::fs::remove_dir_all(output_dir);
+    }
+}
@@ -0,0 +1,295 @@
+use ahash::AHashMap;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Read,
+    Decode,
+    Parse,
+    Insert,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Read, Stage::Decode, Stage::Parse, Stage::Insert];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Decode => "decode",
+            Stage::Parse => "parse",
+            Stage::Insert => "insert",
+        }
+    }
+
+    /// Unit of the size recorded with each event; bytes for IO and decoding, records otherwise.
+    fn size_unit(&self) -> &'static str {
+        match self {
+            Stage::Insert => "records",
+            _ => "bytes",
+        }
+    }
+}
+
+/// Time spent in each stage (in the order of Stage::ALL) by a single worker thread.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadStages {
+    pub thread: String,
+    pub stage_time: [Duration; 4],
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StageSummary {
+    pub bytes_read: u64,
+    pub bytes_decompressed: u64,
+    pub threads: Vec<ThreadStages>,
+}
+
+/// Running totals of the bytes read from, and decompressed out of, the frames of an archive.
+#[derive(Debug, Default)]
+pub struct ByteCounts {
+    read: AtomicU64,
+    decompressed: AtomicU64,
+}
+
+impl ByteCounts {
+    pub fn record(&self, bytes_read: u64, bytes_decompressed: u64) {
+        self.read.fetch_add(bytes_read, Ordering::Relaxed);
+        self.decompressed
+            .fetch_add(bytes_decompressed, Ordering::Relaxed);
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_decompressed(&self) -> u64 {
+        self.decompressed.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct StageEvent {
+    thread: String,
+    stage: Stage,
+    order: u64,
+    start_us: u64,
+    duration_us: u64,
+    size: u64,
+}
+
+/// Records how long each worker spends on each stage of each frame.
+#[derive(Debug)]
+pub struct StageProfiler {
+    origin: Instant,
+    events: Mutex<Vec<StageEvent>>,
+}
+
+impl Default for StageProfiler {
+    fn default() -> Self {
+        StageProfiler {
+            origin: Instant::now(),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl StageProfiler {
+    pub fn record(&self, stage: Stage, order: u64, start: Instant, size: u64) {
+        let thread = std::thread::current().name().unwrap_or("main").to_string();
+
+        let event = StageEvent {
+            thread,
+            stage,
+            order,
+            start_us: start.saturating_duration_since(self.origin).as_micros() as u64,
+            duration_us: start.elapsed().as_micros() as u64,
+            size,
+        };
+        self.events.lock().unwrap().push(event);
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Build the events in the Chrome tracing format, with one track per worker thread.
+    fn chrome_trace(&self) -> Value {
+        let events = self.events.lock().unwrap();
+        let mut thread_ids: AHashMap<&str, usize> = AHashMap::new();
+        let mut trace_events: Vec<Value> = Vec::new();
+
+        for event in events.iter() {
+            let next_id = thread_ids.len();
+            let tid = *thread_ids.entry(event.thread.as_str()).or_insert_with(|| {
+                trace_events.push(json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": 1,
+                    "tid": next_id,
+                    "args": { "name": event.thread },
+                }));
+                next_id
+            });
+
+            trace_events.push(json!({
+                "name": event.stage.name(),
+                "cat": "frame",
+                "ph": "X",
+                "ts": event.start_us,
+                "dur": event.duration_us,
+                "pid": 1,
+                "tid": tid,
+                "args": { "frame": event.order, event.stage.size_unit(): event.size },
+            }));
+        }
+
+        json!({ "traceEvents": trace_events, "displayTimeUnit": "ms" })
+    }
+
+    /// Aggregate the recorded events by thread and stage.
+    pub fn summary(&self) -> StageSummary {
+        let events = self.events.lock().unwrap();
+        let mut summary = StageSummary::default();
+        let mut thread_index: AHashMap<&str, usize> = AHashMap::new();
+
+        for event in events.iter() {
+            match event.stage {
+                Stage::Read => summary.bytes_read += event.size,
+                Stage::Decode => summary.bytes_decompressed += event.size,
+                _ => {}
+            }
+
+            let i = *thread_index
+                .entry(event.thread.as_str())
+                .or_insert_with(|| {
+                    summary.threads.push(ThreadStages {
+                        thread: event.thread.clone(),
+                        stage_time: [Duration::ZERO; 4],
+                    });
+                    summary.threads.len() - 1
+                });
+            summary.threads[i].stage_time[event.stage as usize] +=
+                Duration::from_micros(event.duration_us);
+        }
+
+        summary.threads.sort_by(|a, b| a.thread.cmp(&b.thread));
+        summary
+    }
+
+    pub fn write_chrome_trace(&self, trace_file: &str) -> Result<()> {
+        let trace_handle = File::create(trace_file)
+            .with_context(|| format!("Unable to create trace file '{}'", trace_file))?;
+
+        let mut trace_writer = BufWriter::new(trace_handle);
+        serde_json::to_writer(&mut trace_writer, &self.chrome_trace())?;
+        trace_writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_stage_profiler_record() {
+        let profiler = StageProfiler::default();
+        assert!(profiler.is_empty());
+
+        profiler.record(Stage::Read, 0, Instant::now(), 151);
+        profiler.record(Stage::Decode, 0, Instant::now(), 300);
+
+        assert_eq!(2, profiler.len());
+    }
+
+    #[test]
+    fn test_stage_profiler_chrome_trace() {
+        let profiler = StageProfiler::default();
+        profiler.record(Stage::Read, 3, Instant::now(), 151);
+
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .name("trace-worker".to_string())
+                .spawn_scoped(scope, || {
+                    profiler.record(Stage::Parse, 4, Instant::now(), 300)
+                })
+                .unwrap();
+        });
+
+        let obs_trace = profiler.chrome_trace();
+        let obs_events = obs_trace["traceEvents"].as_array().unwrap();
+
+        // One metadata event naming each thread, followed by its first stage event
+        assert_eq!(4, obs_events.len());
+        assert_eq!("M", obs_events[0]["ph"]);
+        assert_eq!("X", obs_events[1]["ph"]);
+        assert_eq!("read", obs_events[1]["name"]);
+        assert_eq!(3, obs_events[1]["args"]["frame"]);
+        assert_eq!("trace-worker", obs_events[2]["args"]["name"]);
+        assert_eq!("parse", obs_events[3]["name"]);
+        assert_eq!(1, obs_events[3]["tid"]);
+    }
+
+    #[test]
+    fn test_stage_profiler_summary() {
+        let profiler = StageProfiler::default();
+        let start = Instant::now() - Duration::from_millis(5);
+
+        profiler.record(Stage::Read, 0, start, 151);
+        profiler.record(Stage::Decode, 0, start, 300);
+        profiler.record(Stage::Read, 1, start, 120);
+        profiler.record(Stage::Insert, 1, start, 10);
+
+        let obs_summary = profiler.summary();
+        assert_eq!(271, obs_summary.bytes_read);
+        assert_eq!(300, obs_summary.bytes_decompressed);
+        assert_eq!(1, obs_summary.threads.len());
+
+        let obs_times = obs_summary.threads[0].stage_time;
+        assert!(obs_times[Stage::Read as usize] >= Duration::from_millis(10));
+        assert!(obs_times[Stage::Decode as usize] >= Duration::from_millis(5));
+        assert_eq!(Duration::ZERO, obs_times[Stage::Parse as usize]);
+        assert!(obs_times[Stage::Insert as usize] >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_byte_counts_record() {
+        let byte_counts = ByteCounts::default();
+
+        std::thread::scope(|scope| {
+            for (bytes_read, bytes_decompressed) in [(151, 220), (150, 204), (120, 158)] {
+                let byte_counts = &byte_counts;
+                scope.spawn(move || byte_counts.record(bytes_read, bytes_decompressed));
+            }
+        });
+
+        assert_eq!(421, byte_counts.bytes_read());
+        assert_eq!(582, byte_counts.bytes_decompressed());
+    }
+
+    #[test]
+    fn test_write_chrome_trace() {
+        let trace_file = "write_chrome_trace.json";
+        let profiler = StageProfiler::default();
+        profiler.record(Stage::Decode, 0, Instant::now(), 300);
+
+        assert!(profiler.write_chrome_trace(trace_file).is_ok());
+
+        let obs_trace: Value =
+            serde_json::from_str(&std::fs::read_to_string(trace_file).unwrap()).unwrap();
+        assert_eq!(2, obs_trace["traceEvents"].as_array().unwrap().len());
+
+        let _ = std::fs::remove_file(trace_file);
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::Xxh3;
+
+// Size of the reads made while checksumming an input file
+const CHECKSUM_READ_SIZE: usize = 1 << 20;
+
+/// A file compressed into an archive, identified by its size and content.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InputIdentity {
+    pub path: String,
+    pub bytes: u64,
+    /// xxh3 of the file as it was read, before any compressed input was decoded
+    pub checksum: u64,
+}
+
+/// How an archive was made and from what, written at the head of its index so that an archive
+/// found in shared storage can be traced back to the run and inputs which produced it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexHeader {
+    pub tool_version: String,
+    pub command_line: Vec<String>,
+    pub hostname: Option<String>,
+    /// Time the archive was made, in seconds since the Unix epoch
+    pub created: u64,
+    pub inputs: Vec<InputIdentity>,
+}
+
+//region: Private functions
+
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    match unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } {
+        0 => {
+            let length = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+            Some(String::from_utf8_lossy(&buffer[..length]).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Read an input file through once, taking its size and checksum.
+fn identify_input(input_file: &str) -> Result<InputIdentity> {
+    let mut input_handle = File::open(input_file)
+        .with_context(|| format!("Unable to open input file '{}'", input_file))?;
+
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0u8; CHECKSUM_READ_SIZE];
+    let mut bytes: u64 = 0;
+    loop {
+        let read = input_handle.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        bytes += read as u64;
+    }
+
+    Ok(InputIdentity {
+        path: input_file.to_string(),
+        bytes,
+        checksum: hasher.digest(),
+    })
+}
+
+//endregion:
+
+/// Record the version, command line, host and time of this run, along with the identity of each
+/// input file. Every input is read through to checksum it, ahead of compression, which then
+/// mostly reads it back from the page cache.
+pub(crate) fn capture_header(input_files: &[&str]) -> Result<IndexHeader> {
+    let inputs = input_files
+        .iter()
+        .map(|f| identify_input(f))
+        .collect::<Result<Vec<InputIdentity>>>()?;
+
+    Ok(IndexHeader {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: std::env::args().collect(),
+        hostname: hostname(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        inputs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use xxhash_rust::xxh3::xxh3_64;
+
+    #[test]
+    fn test_identify_input() {
+        let content = std::fs::read("test/data.txt").unwrap();
+
+        let obs_identity = identify_input("test/data.txt").unwrap();
+        assert_eq!(content.len() as u64, obs_identity.bytes);
+        assert_eq!(xxh3_64(&content), obs_identity.checksum);
+
+        assert!(identify_input("test/missing.txt").is_err());
+    }
+
+    #[test]
+    fn test_capture_header() {
+        let obs_header = capture_header(&["test/data.txt", "test/example.zstd"]).unwrap();
+        assert_eq!(env!("CARGO_PKG_VERSION"), obs_header.tool_version);
+        assert!(!obs_header.command_line.is_empty());
+        assert!(obs_header.created > 0);
+
+        let obs_paths: Vec<&str> = obs_header.inputs.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(vec!["test/data.txt", "test/example.zstd"], obs_paths);
+    }
+}
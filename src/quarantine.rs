@@ -0,0 +1,235 @@
+use crate::decompression::decode_frame;
+use crate::numa::build_worker_pool;
+use crate::source::FrameSource;
+use crate::{ErrorClass, FailedFrame, FrameMeta, ParseOptions};
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Longest zstd frame header: magic, descriptor, window, dictionary id and content size
+const MAX_FRAME_HEADER: u64 = 18;
+
+/// A frame left out of a quarantine index, with the span of decompressed content lost with it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuarantinedFrame {
+    pub order: u64,
+    /// Position of the frame in the archive
+    pub position: u64,
+    pub length: u64,
+    pub error: ErrorClass,
+    /// Start of the frame's content within the decompressed archive, known when every frame
+    /// before it can be measured
+    pub uncompressed_start: Option<u64>,
+    /// End of the frame's content, known when its header also records its content size
+    pub uncompressed_end: Option<u64>,
+}
+
+/// Outcome of writing a quarantine index, for the caller to report.
+#[derive(Debug, Default)]
+pub struct QuarantineReport {
+    pub frames_kept: usize,
+    pub quarantined: Vec<QuarantinedFrame>,
+    /// File listing the quarantined frames and their decompressed ranges
+    pub report_file: String,
+}
+
+//region: Private functions
+
+/// Decompressed size of a frame as recorded in its header, where the header can be read and the
+/// size was recorded when the frame was written.
+fn frame_content_size(source: &dyn FrameSource, frame_meta: &FrameMeta) -> Option<u64> {
+    let header = source
+        .read_at(
+            frame_meta.position,
+            frame_meta.length.min(MAX_FRAME_HEADER) as usize,
+        )
+        .ok()?;
+    zstd::zstd_safe::get_frame_content_size(&header).ok()?
+}
+
+/// Decompressed size of a frame, from its header or else by decoding it again. A failed frame is
+/// never decoded, so its size is known only from its header.
+fn measure_frame(
+    source: &dyn FrameSource,
+    frame_meta: &FrameMeta,
+    failed: bool,
+    parse_options: &ParseOptions,
+) -> Option<u64> {
+    match (frame_content_size(source, frame_meta), failed) {
+        (Some(size), _) => Some(size),
+        (None, true) => None,
+        (None, false) => decode_frame(source, frame_meta, parse_options)
+            .ok()
+            .map(|content| content.len() as u64),
+    }
+}
+
+//endregion:
+
+/// Split an index into the frames which can still be read and those which failed, locating the
+/// decompressed content of each failed frame. Frame sizes come from the frame headers where they
+/// were recorded, and otherwise from decoding the intact frames up to the last failed frame.
+pub(crate) fn quarantine_frames(
+    source: &dyn FrameSource,
+    mut idx_buffer: Vec<FrameMeta>,
+    failed_frames: &[FailedFrame],
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> (Vec<FrameMeta>, Vec<QuarantinedFrame>) {
+    idx_buffer.sort_by_key(|f| f.order);
+    let failed: HashMap<u64, ErrorClass> =
+        failed_frames.iter().map(|f| (f.order, f.error)).collect();
+
+    // Frames past the last failed frame have no bearing on where the lost content lies
+    let last_failed = failed.keys().max().copied();
+    let measured = idx_buffer
+        .iter()
+        .take_while(|f| last_failed.is_some_and(|l| f.order <= l))
+        .count();
+
+    // Measuring reads frames a second time, so is kept out of the byte counts of the run
+    let parse_options = ParseOptions {
+        byte_counts: None,
+        profiler: None,
+        ..parse_options.clone()
+    };
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_sizes: Vec<Option<u64>> = pool.install(|| {
+        idx_buffer[..measured]
+            .par_iter()
+            .map(|f| measure_frame(source, f, failed.contains_key(&f.order), &parse_options))
+            .collect()
+    });
+
+    let mut kept: Vec<FrameMeta> = Vec::with_capacity(idx_buffer.len());
+    let mut quarantined: Vec<QuarantinedFrame> = Vec::with_capacity(failed.len());
+    let mut uncompressed_start: Option<u64> = Some(0);
+
+    for (i, frame_meta) in idx_buffer.into_iter().enumerate() {
+        // Once one frame size is unknown, so is the start of every frame after it
+        let uncompressed_end = uncompressed_start
+            .zip(frame_sizes.get(i).copied().flatten())
+            .map(|(start, size)| start + size);
+
+        match failed.get(&frame_meta.order) {
+            Some(error) => quarantined.push(QuarantinedFrame {
+                order: frame_meta.order,
+                position: frame_meta.position,
+                length: frame_meta.length,
+                error: *error,
+                uncompressed_start,
+                uncompressed_end,
+            }),
+            None => kept.push(frame_meta),
+        }
+        uncompressed_start = uncompressed_end;
+    }
+    (kept, quarantined)
+}
+
+/// Write the quarantined frames as TSV, with empty fields where a range is unknown.
+pub(crate) fn write_quarantine_report(
+    quarantined: &[QuarantinedFrame],
+    report_file: &str,
+) -> Result<()> {
+    let report_handle = File::create(report_file)
+        .with_context(|| format!("Unable to create quarantine report file '{}'", report_file))?;
+    let mut report_writer = BufWriter::new(report_handle);
+    writeln!(
+        report_writer,
+        "order\tposition\tlength\terror\tuncompressed_start\tuncompressed_end"
+    )?;
+    for frame in quarantined {
+        writeln!(
+            report_writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            frame.order,
+            frame.position,
+            frame.length,
+            serde_json::to_value(frame.error)?
+                .as_str()
+                .unwrap_or_default(),
+            frame
+                .uncompressed_start
+                .map_or(String::new(), |s| s.to_string()),
+            frame
+                .uncompressed_end
+                .map_or(String::new(), |e| e.to_string())
+        )?;
+    }
+    report_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{decompress_bytes, MemorySource};
+
+    #[test]
+    fn test_quarantine_frames() {
+        let payload = std::fs::read("test/example.zstd").unwrap();
+        let idx_buffer: Vec<FrameMeta> =
+            serde_json::from_slice(&std::fs::read("test/example.zstd.idx").unwrap()).unwrap();
+        let source = MemorySource::new("example", payload.clone());
+        let failed_frames = vec![FailedFrame {
+            order: 1,
+            position: idx_buffer[1].position,
+            error: ErrorClass::CorruptArchive,
+        }];
+
+        let (obs_kept, obs_quarantined) = quarantine_frames(
+            &source,
+            idx_buffer.clone(),
+            &failed_frames,
+            2,
+            &ParseOptions::default(),
+        );
+
+        assert_eq!(idx_buffer.len() - 1, obs_kept.len());
+        assert!(obs_kept.iter().all(|f| f.order != 1));
+        assert_eq!(1, obs_quarantined.len());
+
+        // No frame header records its content size, so the first frame is decoded to find where
+        // the second starts, while the end of the failed frame stays unknown
+        let exp_start = decompress_bytes(&payload, &idx_buffer[..1]).unwrap().len() as u64;
+        assert_eq!(Some(exp_start), obs_quarantined[0].uncompressed_start);
+        assert_eq!(None, obs_quarantined[0].uncompressed_end);
+
+        // With content sizes in the headers, the whole lost range is known
+        let mut encoder = zstd::bulk::Compressor::new(3).unwrap();
+        encoder.include_contentsize(true).unwrap();
+        let frames: Vec<Vec<u8>> = [&b"a\t1\n"[..], b"bb\t2\n", b"c\t3\n"]
+            .iter()
+            .map(|c| encoder.compress(c).unwrap())
+            .collect();
+        let mut position = 0;
+        let idx_buffer: Vec<FrameMeta> = frames
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                position += f.len() as u64;
+                FrameMeta::new(position - f.len() as u64, f.len() as u64, i as u64)
+            })
+            .collect();
+        let source = MemorySource::new("sized", frames.concat());
+
+        let (_, obs_quarantined) = quarantine_frames(
+            &source,
+            idx_buffer,
+            &failed_frames,
+            1,
+            &ParseOptions::default(),
+        );
+        assert_eq!(Some(4), obs_quarantined[0].uncompressed_start);
+        assert_eq!(Some(9), obs_quarantined[0].uncompressed_end);
+    }
+}
@@ -0,0 +1,252 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::mpsc::{sync_channel, Receiver, SendError, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Default)]
+struct WindowState {
+    next: usize,
+    receiver_dropped: bool,
+    abandoned: bool,
+}
+
+/// Position of the consumer, which senders wait on to stay within the lookahead.
+struct Window {
+    state: Mutex<WindowState>,
+    advanced: Condvar,
+    lookahead: usize,
+}
+
+struct Sequenced<T>(usize, T);
+
+impl<T> PartialEq for Sequenced<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for Sequenced<T> {}
+
+impl<T> PartialOrd for Sequenced<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Sequenced<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+/// Sending half of an ordered channel. Items may be sent in any order, but a sender blocks
+/// while its item is more than the lookahead beyond the next item the receiver needs.
+pub(crate) struct OrderedSender<T> {
+    sender: SyncSender<(usize, T)>,
+    window: Arc<Window>,
+}
+
+impl<T> Clone for OrderedSender<T> {
+    fn clone(&self) -> Self {
+        OrderedSender {
+            sender: self.sender.clone(),
+            window: Arc::clone(&self.window),
+        }
+    }
+}
+
+impl<T> OrderedSender<T> {
+    /// Send an item, failing with it returned if the receiver has been dropped. Once the channel
+    /// is abandoned, items are discarded.
+    pub(crate) fn send(&self, sequence: usize, item: T) -> Result<(), SendError<T>> {
+        let mut state = self.window.state.lock().unwrap();
+        while !state.receiver_dropped
+            && !state.abandoned
+            && sequence >= state.next + self.window.lookahead
+        {
+            state = self.window.advanced.wait(state).unwrap();
+        }
+        if state.receiver_dropped {
+            return Err(SendError(item));
+        }
+        if state.abandoned {
+            return Ok(());
+        }
+        drop(state);
+
+        self.sender
+            .send((sequence, item))
+            .map_err(|SendError((_, item))| SendError(item))
+    }
+
+    /// Give up on the sequence after a sender fails to produce its item. Senders waiting on the
+    /// window are released, and the receiver ends once every sender is dropped, as it would
+    /// otherwise wait on the missing item while the senders wait on the receiver.
+    pub(crate) fn abandon(&self) {
+        let mut state = self.window.state.lock().unwrap();
+        state.abandoned = true;
+        self.window.advanced.notify_all();
+    }
+}
+
+/// Receiving half of an ordered channel, yielding items in sequence. Items which arrive early
+/// are held in a min-heap keyed on their sequence until the gap before them is filled.
+/// Iteration ends when every sender is dropped and the next item is missing.
+pub(crate) struct OrderedReceiver<T> {
+    receiver: Receiver<(usize, T)>,
+    window: Arc<Window>,
+    pending: BinaryHeap<Reverse<Sequenced<T>>>,
+    next: usize,
+}
+
+impl<T> OrderedReceiver<T> {
+    /// Sequence of the next item to be yielded.
+    pub(crate) fn next_sequence(&self) -> usize {
+        self.next
+    }
+}
+
+impl<T> Iterator for OrderedReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(Reverse(Sequenced(sequence, _))) = self.pending.peek()
+                && *sequence == self.next
+            {
+                let Reverse(Sequenced(_, item)) = self.pending.pop().unwrap();
+                self.next += 1;
+
+                let mut state = self.window.state.lock().unwrap();
+                state.next = self.next;
+                self.window.advanced.notify_all();
+                return Some(item);
+            }
+
+            match self.receiver.recv() {
+                Ok((sequence, item)) => self.pending.push(Reverse(Sequenced(sequence, item))),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+impl<T> Drop for OrderedReceiver<T> {
+    fn drop(&mut self) {
+        // Release any senders waiting on the window, so that they fail rather than hang
+        let mut state = self.window.state.lock().unwrap();
+        state.receiver_dropped = true;
+        self.window.advanced.notify_all();
+    }
+}
+
+/// Create a channel which delivers items in sequence from `start`, while at most `lookahead`
+/// items beyond the next expected one are in flight.
+pub(crate) fn ordered_channel<T>(
+    start: usize,
+    lookahead: usize,
+) -> (OrderedSender<T>, OrderedReceiver<T>) {
+    let lookahead = lookahead.max(1);
+    let (sender, receiver) = sync_channel::<(usize, T)>(lookahead);
+    let window = Arc::new(Window {
+        state: Mutex::new(WindowState {
+            next: start,
+            ..Default::default()
+        }),
+        advanced: Condvar::new(),
+        lookahead,
+    });
+
+    (
+        OrderedSender {
+            sender,
+            window: Arc::clone(&window),
+        },
+        OrderedReceiver {
+            receiver,
+            window,
+            pending: BinaryHeap::new(),
+            next: start,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rayon::prelude::*;
+
+    #[test]
+    fn test_ordered_channel() {
+        let (sender, receiver) = ordered_channel::<usize>(0, 4);
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for sequence in [1, 0, 3, 2, 4, 6, 5] {
+                    sender.send(sequence, sequence * 10).unwrap();
+                }
+            });
+
+            let obs_items: Vec<usize> = receiver.collect();
+            assert_eq!(vec![0, 10, 20, 30, 40, 50, 60], obs_items);
+        });
+    }
+
+    #[test]
+    fn test_ordered_channel_parallel() {
+        let (sender, mut receiver) = ordered_channel::<usize>(5, 2);
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                (5..500usize)
+                    .into_par_iter()
+                    .try_for_each_with(sender, |sender, sequence| sender.send(sequence, sequence))
+                    .unwrap();
+            });
+
+            let obs_items: Vec<usize> = receiver.by_ref().collect();
+            assert_eq!((5..500).collect::<Vec<_>>(), obs_items);
+        });
+        assert_eq!(500, receiver.next_sequence());
+    }
+
+    #[test]
+    fn test_ordered_channel_receiver_dropped() {
+        let (sender, receiver) = ordered_channel::<usize>(0, 2);
+        drop(receiver);
+
+        // A sender beyond the window is released rather than left waiting
+        assert!(sender.send(5, 5).is_err());
+    }
+
+    #[test]
+    fn test_ordered_channel_abandoned() {
+        let (sender, receiver) = ordered_channel::<usize>(0, 2);
+
+        std::thread::scope(|scope| {
+            // Item 0 never arrives, so the sender of item 3 waits until the channel is abandoned
+            let waiting_sender = sender.clone();
+            let waiting = scope.spawn(move || waiting_sender.send(3, 3));
+
+            sender.send(1, 1).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            sender.abandon();
+            drop(sender);
+
+            assert!(waiting.join().unwrap().is_ok());
+            assert!(receiver.collect::<Vec<_>>().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_ordered_channel_gap() {
+        let (sender, receiver) = ordered_channel::<usize>(0, 4);
+        sender.send(0, 0).unwrap();
+        sender.send(2, 2).unwrap();
+        drop(sender);
+
+        // Items after a missing sequence are never delivered
+        assert_eq!(vec![0], receiver.collect::<Vec<_>>());
+    }
+}
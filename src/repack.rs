@@ -0,0 +1,282 @@
+use crate::compression::{frame_key_range, frame_record_stats, pad_payload_end, write_frame};
+use crate::decompression::decode_frame;
+use crate::numa::build_worker_pool;
+use crate::reorder::{ordered_channel, OrderedReceiver};
+use crate::source::FrameSource;
+use crate::{FrameMeta, ParseOptions, RecordFormat};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Number of frames which can be decoded ahead of the next one to be written before workers block
+const REPACK_CHANNEL_BOUND: usize = 64;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RepackSummary {
+    pub frames_read: usize,
+    pub frames_written: usize,
+    pub bytes_written: u64,
+}
+
+/// Settings for the frames written by a repack.
+pub(crate) struct RepackTarget {
+    pub block_size: usize,
+    pub zstd_level: i32,
+    pub frame_metadata: bool,
+    pub align: Option<u64>,
+    /// Layout of the records, used to find the key range of each frame
+    pub format: RecordFormat,
+}
+
+//region: Private functions
+
+fn frame_packer(
+    mut frame_receiver: OrderedReceiver<Vec<u8>>,
+    mut zstd_writer: &File,
+    repack_target: &RepackTarget,
+) -> Result<(Vec<FrameMeta>, usize)> {
+    let mut idx_records: Vec<FrameMeta> = Vec::new();
+
+    let mut content: Vec<u8> = Vec::new();
+
+    // Frames complete out of order, but the receiver yields them in sequence
+    for payload in frame_receiver.by_ref() {
+        content.extend(payload);
+
+        // Cut at the last line ending, so that a record split across the old frames is never
+        // split across the new ones
+        if content.len() >= repack_target.block_size
+            && let Some(line_end) = content.iter().rposition(|&b| b == b'\n')
+        {
+            let remainder = content.split_off(line_end + 1);
+            let frame_record = write_frame(
+                &mut zstd_writer,
+                &content,
+                idx_records.len() as u64,
+                repack_target.zstd_level,
+                repack_target.frame_metadata,
+                repack_target.align,
+            )?;
+            let key_range = frame_key_range(&content, &repack_target.format);
+            let record_stats = frame_record_stats(&content, &repack_target.format);
+            idx_records.push(
+                frame_record
+                    .with_key_range(key_range)
+                    .with_record_stats(record_stats),
+            );
+            content = remainder;
+        }
+    }
+
+    if !content.is_empty() {
+        let frame_record = write_frame(
+            &mut zstd_writer,
+            &content,
+            idx_records.len() as u64,
+            repack_target.zstd_level,
+            repack_target.frame_metadata,
+            repack_target.align,
+        )?;
+        let key_range = frame_key_range(&content, &repack_target.format);
+        let record_stats = frame_record_stats(&content, &repack_target.format);
+        idx_records.push(
+            frame_record
+                .with_key_range(key_range)
+                .with_record_stats(record_stats),
+        );
+    }
+    pad_payload_end(&mut zstd_writer, repack_target.align)?;
+
+    Ok((idx_records, frame_receiver.next_sequence()))
+}
+
+//endregion:
+
+/// Decode the frames of an archive in parallel and write their content to new frames of the
+/// target block size, in order. The content of each frame is passed through `transform` before
+/// it is packed, so that callers can drop records on the way. Returns the index of the frames
+/// written and the number of frames read.
+pub(crate) fn pack_frames(
+    source: &dyn FrameSource,
+    mut idx_buffer: Vec<FrameMeta>,
+    zstd_writer: &File,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    repack_target: &RepackTarget,
+    transform: impl Fn(&FrameMeta, Vec<u8>) -> Vec<u8> + Sync,
+) -> Result<(Vec<FrameMeta>, usize)> {
+    idx_buffer.sort_by_key(|f| f.order);
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let (frame_sender, frame_receiver) = ordered_channel::<Vec<u8>>(0, REPACK_CHANNEL_BOUND);
+
+    std::thread::scope(|scope| {
+        let packer_handle = parse_options
+            .threads
+            .spawn_scoped(scope, "repack-writer", || {
+                frame_packer(frame_receiver, zstd_writer, repack_target)
+            })?;
+
+        let decode_result: Result<()> = pool.install(|| {
+            idx_buffer.par_iter().enumerate().try_for_each_with(
+                frame_sender,
+                |frame_sender, (sequence, idx_frame)| {
+                    // A lost frame would leave a hole in the output, so the repack stops
+                    let payload = decode_frame(source, idx_frame, parse_options)
+                        .inspect_err(|_| frame_sender.abandon())?;
+                    let payload = transform(idx_frame, payload);
+                    if frame_sender.send(sequence, payload).is_err() {
+                        bail!("The output writer stopped before all frames were written!");
+                    }
+                    Ok(())
+                },
+            )
+        });
+
+        // A writer failure also stops the decoders, so report it ahead of theirs
+        let packed = match packer_handle.join() {
+            Ok(r) => r?,
+            Err(_) => bail!("The output writer thread panicked!"),
+        };
+
+        decode_result?;
+        Ok(packed)
+    })
+}
+
+/// Rewrite an archive with its frames coalesced up to the target block size. Frames are decoded
+/// in parallel and re-encoded in order, so only a block of uncompressed content is held at once.
+pub(crate) fn repack_zstd(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    zstd_writer: File,
+    mut idx_writer: BufWriter<File>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    repack_target: &RepackTarget,
+) -> Result<RepackSummary> {
+    let (idx_records, frames_read) = pack_frames(
+        source,
+        idx_buffer,
+        &zstd_writer,
+        num_threads,
+        parse_options,
+        repack_target,
+        |_, payload| payload,
+    )?;
+
+    serde_json::to_writer_pretty(&mut idx_writer, &idx_records)?;
+    idx_writer.flush()?;
+
+    Ok(RepackSummary {
+        frames_read,
+        frames_written: idx_records.len(),
+        bytes_written: zstd_writer.metadata()?.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+    use std::fs::OpenOptions;
+    use std::io::BufReader;
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn open_file_write(file_path: &str) -> File {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap()
+    }
+
+    fn repack_example(
+        output_stem: &str,
+        block_size: usize,
+    ) -> (RepackSummary, Vec<u8>, Vec<FrameMeta>) {
+        let zstd_file = format!("{}.zstd", output_stem);
+        let idx_file = format!("{}.zstd.idx", output_stem);
+
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let repack_target = RepackTarget {
+            block_size,
+            zstd_level: 3,
+            frame_metadata: false,
+            align: None,
+            format: RecordFormat::Tsv,
+        };
+
+        let obs_result = repack_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            open_file_write(&zstd_file),
+            BufWriter::new(open_file_write(&idx_file)),
+            2,
+            &ParseOptions::default(),
+            &repack_target,
+        );
+        let obs_payload = std::fs::read(&zstd_file).unwrap();
+        let obs_idx = load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+
+        let _ = std::fs::remove_file(&zstd_file);
+        let _ = std::fs::remove_file(&idx_file);
+
+        (obs_result.unwrap(), obs_payload, obs_idx)
+    }
+
+    #[test]
+    fn test_repack_zstd() {
+        let (obs_summary, obs_payload, obs_idx) = repack_example("repack_zstd", 400);
+
+        // Frames of 220, 204 and 158 bytes are packed as 424 and 158
+        assert_eq!(3, obs_summary.frames_read);
+        assert_eq!(2, obs_summary.frames_written);
+        assert_eq!(obs_payload.len() as u64, obs_summary.bytes_written);
+
+        let exp_content = std::fs::read("test/data.txt").unwrap();
+        let mut obs_content: Vec<u8> = Vec::new();
+        for frame_meta in &obs_idx {
+            let frame_bytes =
+                &obs_payload[frame_meta.position as usize..][..frame_meta.length as usize];
+            let frame_content = zstd::decode_all(frame_bytes).unwrap();
+
+            assert!(frame_content.ends_with(b"\n"));
+            assert_eq!(
+                Some(xxhash_rust::xxh3::xxh3_64(&frame_content)),
+                frame_meta.checksum
+            );
+            obs_content.extend(frame_content);
+        }
+        assert_eq!(exp_content, obs_content);
+        assert_eq!(
+            vec![0, 1],
+            obs_idx.iter().map(|f| f.order).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_repack_zstd_single_frame() {
+        let (obs_summary, obs_payload, obs_idx) =
+            repack_example("repack_zstd_single_frame", 1024 * 1024);
+
+        assert_eq!(1, obs_summary.frames_written);
+        assert_eq!(1, obs_idx.len());
+        assert_eq!(
+            std::fs::read("test/data.txt").unwrap(),
+            zstd::decode_all(&obs_payload[..]).unwrap()
+        );
+    }
+}
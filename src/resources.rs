@@ -0,0 +1,265 @@
+use std::path::{Path, PathBuf};
+
+const PROC_CGROUP: &str = "/proc/self/cgroup";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// cgroup v1 reports an unset memory limit as a page-rounded maximum rather than a keyword
+const UNLIMITED_MEMORY_V1: u64 = 1 << 60;
+// Share of the memory limit the worker threads may take between them, leaving the rest to the
+// map of records and the frames queued for writing
+const MEMORY_BUDGET_SHARE: u64 = 2;
+// Memory set aside for each worker thread, covering a decoded frame of the larger common block
+// sizes and the records parsed from it
+const THREAD_MEMORY: u64 = 64 << 20;
+
+/// CPU and memory available to the process, under the limits of its cgroup when it runs in a
+/// container. Thread counts default to what these limits allow, so that a small pod is not
+/// oversubscribed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResourceLimits {
+    /// CPUs the process may run on, from its CPU affinity and any cgroup CPU quota
+    pub cpus: usize,
+    /// Whether the CPU count is held down by a cgroup CPU quota
+    pub cpu_quota: bool,
+    /// Memory limit of the cgroup, when one is set
+    pub memory_limit: Option<u64>,
+}
+
+//region: Private functions
+
+/// Directory of the cgroup under the controller's hierarchy, falling back to the root of the
+/// hierarchy when the path is not visible, as inside a container with its own cgroup namespace.
+fn cgroup_dir(hierarchy: &Path, cgroup_path: &str) -> PathBuf {
+    let cgroup_dir = hierarchy.join(cgroup_path.trim_start_matches('/'));
+    match cgroup_dir.is_dir() {
+        true => cgroup_dir,
+        false => hierarchy.to_path_buf(),
+    }
+}
+
+fn read_value(limit_file: &Path) -> Option<String> {
+    std::fs::read_to_string(limit_file)
+        .ok()
+        .map(|v| v.trim().to_string())
+}
+
+/// CPU quota from a v2 'cpu.max' file, holding '<quota> <period>' or 'max <period>'.
+fn parse_cpu_max(cpu_max: &str) -> Option<f64> {
+    let (quota, period) = cpu_max.trim().split_once(' ')?;
+    let quota: f64 = quota.parse().ok()?;
+    let period: f64 = period.trim().parse().ok()?;
+    (quota > 0.0 && period > 0.0).then_some(quota / period)
+}
+
+/// CPU quota from the v1 'cpu.cfs_quota_us' and 'cpu.cfs_period_us' files, where a quota of -1
+/// is unlimited.
+fn parse_cfs_quota(quota: &str, period: &str) -> Option<f64> {
+    let quota: i64 = quota.trim().parse().ok()?;
+    let period: i64 = period.trim().parse().ok()?;
+    (quota > 0 && period > 0).then_some(quota as f64 / period as f64)
+}
+
+/// Memory limit from a v2 'memory.max' or v1 'memory.limit_in_bytes' file.
+fn parse_memory_limit(memory_max: &str) -> Option<u64> {
+    match memory_max.trim().parse::<u64>() {
+        Ok(m) if m < UNLIMITED_MEMORY_V1 => Some(m),
+        _ => None,
+    }
+}
+
+/// Lowest limit found from the cgroup's directory up to the root of its hierarchy, as the limits
+/// of every ancestor apply to the processes below it.
+fn lowest_limit<T: PartialOrd>(
+    hierarchy: &Path,
+    cgroup_path: &str,
+    read_limit: impl Fn(&Path) -> Option<T>,
+) -> Option<T> {
+    let mut lowest: Option<T> = None;
+    let mut limit_dir = Some(cgroup_dir(hierarchy, cgroup_path));
+
+    while let Some(d) = limit_dir {
+        if let Some(limit) = read_limit(&d)
+            && lowest.as_ref().is_none_or(|l| limit < *l)
+        {
+            lowest = Some(limit);
+        }
+        limit_dir = match d == hierarchy {
+            true => None,
+            false => d.parent().map(Path::to_path_buf),
+        };
+    }
+    lowest
+}
+
+/// CPU quota (in CPUs) and memory limit of the cgroups listed in '/proc/self/cgroup', read from
+/// the unified (v2) hierarchy or the per-controller (v1) hierarchies under the cgroup root.
+fn load_cgroup_limits(proc_cgroup: &str, cgroup_root: &Path) -> (Option<f64>, Option<u64>) {
+    let mut cpu_quota: Option<f64> = None;
+    let mut memory_limit: Option<u64> = None;
+
+    for line in proc_cgroup.lines() {
+        // Each line is '<id>:<controllers>:<path>', with no controllers for the unified hierarchy
+        let mut fields = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(cgroup_path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+
+        let controllers: Vec<&str> = controllers.split(',').collect();
+        if controllers == [""] {
+            let hierarchy = match cgroup_root.join("unified").is_dir() {
+                true => cgroup_root.join("unified"),
+                false => cgroup_root.to_path_buf(),
+            };
+            cpu_quota = cpu_quota.or(lowest_limit(&hierarchy, cgroup_path, |d| {
+                parse_cpu_max(&read_value(&d.join("cpu.max"))?)
+            }));
+            memory_limit = memory_limit.or(lowest_limit(&hierarchy, cgroup_path, |d| {
+                parse_memory_limit(&read_value(&d.join("memory.max"))?)
+            }));
+        } else if controllers.contains(&"cpu") {
+            let hierarchy = cgroup_root.join(controllers.join(","));
+            let hierarchy = match hierarchy.is_dir() {
+                true => hierarchy,
+                false => cgroup_root.join("cpu"),
+            };
+            cpu_quota = lowest_limit(&hierarchy, cgroup_path, |d| {
+                parse_cfs_quota(
+                    &read_value(&d.join("cpu.cfs_quota_us"))?,
+                    &read_value(&d.join("cpu.cfs_period_us"))?,
+                )
+            })
+            .or(cpu_quota);
+        } else if controllers.contains(&"memory") {
+            let hierarchy = cgroup_root.join("memory");
+            memory_limit = lowest_limit(&hierarchy, cgroup_path, |d| {
+                parse_memory_limit(&read_value(&d.join("memory.limit_in_bytes"))?)
+            })
+            .or(memory_limit);
+        }
+    }
+    (cpu_quota, memory_limit)
+}
+
+//endregion:
+
+impl ResourceLimits {
+    /// Read the limits of the running process. Outside Linux, or where no cgroup limits can be
+    /// read, only the CPUs the process may run on are known.
+    pub fn detect() -> ResourceLimits {
+        let available_cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let (cpu_quota, memory_limit) = match std::fs::read_to_string(PROC_CGROUP) {
+            Ok(proc_cgroup) => load_cgroup_limits(&proc_cgroup, Path::new(CGROUP_ROOT)),
+            Err(_) => (None, None),
+        };
+        ResourceLimits::from_limits(available_cpus, cpu_quota, memory_limit)
+    }
+
+    pub(crate) fn from_limits(
+        available_cpus: usize,
+        cpu_quota: Option<f64>,
+        memory_limit: Option<u64>,
+    ) -> ResourceLimits {
+        // A fractional quota still lets a thread run part of the time, so rounds up
+        let quota_cpus = cpu_quota.map(|q| (q.ceil() as usize).max(1));
+        ResourceLimits {
+            cpus: quota_cpus.map_or(available_cpus, |q| q.min(available_cpus)),
+            cpu_quota: quota_cpus.is_some_and(|q| q < available_cpus),
+            memory_limit,
+        }
+    }
+
+    /// Memory the worker threads may take between them, when the cgroup limits memory.
+    pub fn memory_budget(&self) -> Option<u64> {
+        self.memory_limit.map(|m| m / MEMORY_BUDGET_SHARE)
+    }
+
+    /// Thread count used when none is given: one per available CPU, and no more than fit within
+    /// the memory budget.
+    pub fn default_threads(&self) -> usize {
+        let memory_threads = self
+            .memory_budget()
+            .map_or(usize::MAX, |b| (b / THREAD_MEMORY) as usize);
+        self.cpus.min(memory_threads).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn write_limit(dir: &Path, file_name: &str, value: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(file_name), value).unwrap();
+    }
+
+    #[test]
+    fn test_parse_cpu_max() {
+        assert_eq!(Some(2.0), parse_cpu_max("200000 100000\n"));
+        assert_eq!(Some(0.5), parse_cpu_max("50000 100000"));
+        assert_eq!(None, parse_cpu_max("max 100000\n"));
+        assert_eq!(None, parse_cfs_quota("-1", "100000"));
+        assert_eq!(Some(1.5), parse_cfs_quota("150000\n", "100000\n"));
+    }
+
+    #[test]
+    fn test_parse_memory_limit() {
+        assert_eq!(Some(1 << 30), parse_memory_limit("1073741824\n"));
+        assert_eq!(None, parse_memory_limit("max\n"));
+        assert_eq!(None, parse_memory_limit("9223372036854771712"));
+    }
+
+    #[test]
+    fn test_load_cgroup_limits_v2() {
+        let cgroup_root = Path::new("load_cgroup_limits_v2");
+        let pod_dir = cgroup_root.join("kubepods/pod1");
+        write_limit(&cgroup_root.join("kubepods"), "cpu.max", "400000 100000\n");
+        write_limit(&cgroup_root.join("kubepods"), "memory.max", "max\n");
+        write_limit(&pod_dir, "cpu.max", "150000 100000\n");
+        write_limit(&pod_dir, "memory.max", "536870912\n");
+
+        let obs_limits = load_cgroup_limits("0::/kubepods/pod1\n", cgroup_root);
+        let _ = std::fs::remove_dir_all(cgroup_root);
+
+        // The lower limit of the pod applies over that of its parent
+        assert_eq!((Some(1.5), Some(512 << 20)), obs_limits);
+    }
+
+    #[test]
+    fn test_load_cgroup_limits_v1() {
+        let cgroup_root = Path::new("load_cgroup_limits_v1");
+        let proc_cgroup = "4:memory:/docker/abc\n2:cpu,cpuacct:/docker/abc\n0::/\n";
+        let cpu_dir = cgroup_root.join("cpu,cpuacct/docker/abc");
+        write_limit(&cpu_dir, "cpu.cfs_quota_us", "200000\n");
+        write_limit(&cpu_dir, "cpu.cfs_period_us", "100000\n");
+        write_limit(
+            &cgroup_root.join("memory/docker/abc"),
+            "memory.limit_in_bytes",
+            "9223372036854771712\n",
+        );
+
+        let obs_limits = load_cgroup_limits(proc_cgroup, cgroup_root);
+        let _ = std::fs::remove_dir_all(cgroup_root);
+
+        assert_eq!((Some(2.0), None), obs_limits);
+    }
+
+    #[test]
+    fn test_default_threads() {
+        // The quota holds the threads below the CPUs, and rounds up
+        let limits = ResourceLimits::from_limits(16, Some(2.5), None);
+        assert_eq!(3, limits.default_threads());
+        assert!(limits.cpu_quota);
+
+        // A 256MiB pod leaves room for two threads
+        let limits = ResourceLimits::from_limits(16, None, Some(256 << 20));
+        assert_eq!(2, limits.default_threads());
+        assert!(!limits.cpu_quota);
+
+        let limits = ResourceLimits::from_limits(4, Some(0.1), Some(1 << 20));
+        assert_eq!(1, limits.default_threads());
+    }
+}
@@ -0,0 +1,514 @@
+use crate::compression::{FrameMetadata, FRAME_METADATA_MAGIC, FRAME_METADATA_SIZE};
+use crate::{FrameMeta, PipelineError};
+use anyhow::{bail, Context, Result};
+use flate2::bufread::GzDecoder;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom};
+
+pub(crate) const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+pub(crate) const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+pub(crate) const SKIPPABLE_MAGIC_MASK: u32 = 0xFFFF_FFF0;
+// pzstd precedes each frame with a skippable frame holding only the compressed size of that frame
+const PZSTD_HEADER_SIZE: u32 = 4;
+
+pub(crate) const GZIP_MAGIC: [u8; 3] = [0x1F, 0x8B, 0x08];
+// Neighbouring gzip members are grouped into frames of around this many compressed bytes, as
+// BGZF blocks are far too small to be worth a task each
+const GZIP_FRAME_TARGET: u64 = 4 * 1024 * 1024;
+
+//region: Private functions
+
+fn read_bytes<const N: usize, R: Read>(reader: &mut R) -> std::io::Result<[u8; N]> {
+    let mut buffer = [0u8; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn frame_header_length(descriptor: u8) -> Result<u64> {
+    // Frame header descriptor: content size flag (2 bits), single segment (1), unused (1),
+    // reserved (1), checksum (1), dictionary id flag (2)
+    if descriptor & 0x08 != 0 {
+        bail!("reserved bit set in frame header");
+    }
+
+    let single_segment = descriptor & 0x20 != 0;
+    let window_descriptor: u64 = if single_segment { 0 } else { 1 };
+    let dictionary_id: u64 = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let content_size: u64 = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+
+    Ok(window_descriptor + dictionary_id + content_size)
+}
+
+/// What a skippable frame says of the data frame after it: its compressed size and, when it
+/// holds frame metadata, its order and checksum.
+struct FrameHint {
+    length: u64,
+    metadata: Option<FrameMetadata>,
+}
+
+fn skip_skippable_frame<R: Read + Seek>(reader: &mut R, magic: u32) -> Result<Option<FrameHint>> {
+    let frame_size = u32::from_le_bytes(read_bytes::<4, _>(reader)?);
+
+    if magic == SKIPPABLE_MAGIC && frame_size == PZSTD_HEADER_SIZE {
+        let next_frame_size = u32::from_le_bytes(read_bytes::<4, _>(reader)?);
+        return Ok(Some(FrameHint {
+            length: next_frame_size as u64,
+            metadata: None,
+        }));
+    }
+
+    if magic == FRAME_METADATA_MAGIC && frame_size as usize == FRAME_METADATA_SIZE {
+        let frame_metadata = FrameMetadata::from_bytes(&read_bytes(reader)?);
+        return Ok(Some(FrameHint {
+            length: frame_metadata.length,
+            metadata: Some(frame_metadata),
+        }));
+    }
+
+    reader.seek_relative(frame_size as i64)?;
+    Ok(None)
+}
+
+fn skip_frame<R: Read + Seek>(reader: &mut R) -> Result<()> {
+    let descriptor = read_bytes::<1, _>(reader)?[0];
+    reader.seek_relative(frame_header_length(descriptor)? as i64)?;
+
+    loop {
+        // Block header: last block (1 bit), block type (2), block size (21)
+        let [b0, b1, b2] = read_bytes::<3, _>(reader)?;
+        let block_header = u32::from_le_bytes([b0, b1, b2, 0]);
+        let block_size = (block_header >> 3) as i64;
+
+        let content_length = match (block_header >> 1) & 0x03 {
+            0 | 2 => block_size,
+            1 => 1,
+            _ => bail!("reserved block type"),
+        };
+        reader.seek_relative(content_length)?;
+
+        if block_header & 0x01 != 0 {
+            break;
+        }
+    }
+
+    if descriptor & 0x04 != 0 {
+        reader.seek_relative(4)?;
+    }
+    Ok(())
+}
+
+fn bgzf_block_size<R: Read>(reader: &mut R) -> Result<Option<u64>> {
+    // Member header: magic and method (3 bytes), flags (1), mtime (4), extra flags (1), OS (1)
+    let header = read_bytes::<10, _>(reader)?;
+    if header[..3] != GZIP_MAGIC {
+        bail!("missing gzip magic number");
+    }
+    if header[3] & 0x04 == 0 {
+        return Ok(None);
+    }
+
+    let extra_length = u16::from_le_bytes(read_bytes::<2, _>(reader)?) as usize;
+    let mut extra = vec![0u8; extra_length];
+    reader.read_exact(&mut extra)?;
+
+    // Extra subfields are two identifier bytes and a two byte length, followed by the data. BGZF
+    // stores the size of the whole member, less one, in a 'BC' subfield.
+    let mut subfields = &extra[..];
+    while let [id_1, id_2, l_1, l_2, rest @ ..] = subfields {
+        let subfield_length = u16::from_le_bytes([*l_1, *l_2]) as usize;
+        if let ([b'B', b'C'], 2, [s_1, s_2, ..]) = ([*id_1, *id_2], subfield_length, rest) {
+            return Ok(Some(u16::from_le_bytes([*s_1, *s_2]) as u64 + 1));
+        }
+        subfields = &rest[subfield_length.min(rest.len())..];
+    }
+    Ok(None)
+}
+
+fn group_members(members: Vec<(u64, u64)>, frame_target: u64) -> Vec<FrameMeta> {
+    let mut frames: Vec<FrameMeta> = Vec::new();
+    let mut current: Option<(u64, u64)> = None;
+
+    for (position, length) in members {
+        let (start, end) = current.get_or_insert((position, position));
+        *end = position + length;
+
+        if *end - *start >= frame_target {
+            frames.push(FrameMeta::new(*start, *end - *start, frames.len() as u64));
+            current = None;
+        }
+    }
+
+    if let Some((start, end)) = current {
+        frames.push(FrameMeta::new(start, end - start, frames.len() as u64));
+    }
+    frames
+}
+
+//endregion:
+
+/// Locate the members of a gzip file, returning the position and length of each. BGZF members
+/// record their own size, but any others are inflated (and the output discarded) to find where
+/// they end. Members are the only split points, as no seek points are found within a deflate
+/// stream, so a single-member file is returned as one frame.
+pub fn scan_gzip_members<R: BufRead + Seek>(reader: &mut R) -> Result<Vec<(u64, u64)>> {
+    let mut members: Vec<(u64, u64)> = Vec::new();
+
+    let start = reader.stream_position()?;
+    let stream_length = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(start))?;
+
+    while !reader.fill_buf()?.is_empty() {
+        let position = reader.stream_position()?;
+
+        let member_result = bgzf_block_size(reader).and_then(|block_size| match block_size {
+            Some(block_size) => {
+                reader.seek(SeekFrom::Start(position + block_size))?;
+                Ok(())
+            }
+            None => {
+                reader.seek(SeekFrom::Start(position))?;
+                std::io::copy(&mut GzDecoder::new(&mut *reader), &mut std::io::sink())?;
+                Ok(())
+            }
+        });
+
+        let end = reader.stream_position()?;
+        let member_result = member_result.and_then(|_| match end > stream_length {
+            true => bail!("member extends past the end of the file"),
+            false => Ok(()),
+        });
+        if let Err(e) = member_result {
+            bail!(PipelineError::CorruptArchive(format!(
+                "Unable to read the gzip member at byte {}: {:#}",
+                position, e
+            )));
+        }
+
+        members.push((position, end - position));
+    }
+
+    Ok(members)
+}
+
+/// Read a '.gzi' member index, as written by 'bgzip -i'. The index holds the number of entries,
+/// then the compressed and uncompressed offset of each member after the first, all as
+/// little-endian u64 values.
+pub fn load_gzi<R: Read>(reader: &mut R, file_length: u64) -> Result<Vec<(u64, u64)>> {
+    let num_entries = u64::from_le_bytes(read_bytes::<8, _>(reader)?);
+
+    let mut offsets: Vec<u64> = vec![0];
+    for _ in 0..num_entries {
+        let compressed_offset = u64::from_le_bytes(read_bytes::<8, _>(reader)?);
+        let _ = read_bytes::<8, _>(reader)?;
+        offsets.push(compressed_offset);
+    }
+    offsets.push(file_length);
+
+    if offsets.windows(2).any(|w| w[0] > w[1]) {
+        bail!(PipelineError::CorruptArchive(
+            "Unable to use the gzip index, as its offsets are out of order!".into()
+        ));
+    }
+
+    Ok(offsets
+        .windows(2)
+        .filter(|w| w[0] < w[1])
+        .map(|w| (w[0], w[1] - w[0]))
+        .collect())
+}
+
+/// Split a gzip file into frames of whole members, using its '.gzi' index when given.
+pub fn scan_gzip_file(gzip_file: &str, gzi_file: Option<&str>) -> Result<Vec<FrameMeta>> {
+    let gzip_handle = File::open(gzip_file)
+        .with_context(|| format!("Unable to open gzip file '{}'", gzip_file))?;
+
+    let members = match gzi_file {
+        Some(gzi_file) => {
+            let gzi_handle = File::open(gzi_file)
+                .with_context(|| format!("Unable to open gzip index '{}'", gzi_file))?;
+            let file_length = gzip_handle.metadata()?.len();
+            load_gzi(&mut BufReader::new(gzi_handle), file_length)?
+        }
+        None => scan_gzip_members(&mut BufReader::new(gzip_handle))?,
+    };
+
+    Ok(group_members(members, GZIP_FRAME_TARGET))
+}
+
+/// Locate the frames of a plain (unindexed) zstd file by walking the frame and block headers,
+/// without decoding any content. Skippable frames are stepped over and left out of the result,
+/// except that the frame sizes written by pzstd, and the frame metadata written by
+/// 'compress --frame-metadata', are used to jump straight past the next frame.
+pub fn scan_zstd_frames<R: Read + Seek>(reader: &mut R) -> Result<Vec<FrameMeta>> {
+    let mut frames: Vec<FrameMeta> = Vec::new();
+
+    let start = reader.stream_position()?;
+    let stream_length = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(start))?;
+
+    let mut frame_hint: Option<FrameHint> = None;
+
+    loop {
+        let position = reader.stream_position()?;
+        let next_frame = frame_hint.take();
+
+        let magic = match read_bytes::<4, _>(reader) {
+            Ok(m) => u32::from_le_bytes(m),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let frame_result = match (magic, &next_frame) {
+            (ZSTD_MAGIC, Some(hint)) if hint.length >= 4 => reader
+                .seek_relative(hint.length as i64 - 4)
+                .map_err(anyhow::Error::from),
+            (ZSTD_MAGIC, _) => skip_frame(reader),
+            (m, _) if m & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC => {
+                skip_skippable_frame(reader, m).map(|hint| frame_hint = hint)
+            }
+            _ => bail!(PipelineError::CorruptArchive(format!(
+                "Unable to find a zstd frame at byte {}!",
+                position
+            ))),
+        };
+
+        // Seeking past the end succeeds, so truncation is caught by comparing against the length
+        let end = reader.stream_position()?;
+        let frame_result = frame_result.and_then(|_| match end > stream_length {
+            true => bail!("frame extends past the end of the file"),
+            false => Ok(()),
+        });
+        if let Err(e) = frame_result {
+            bail!(PipelineError::CorruptArchive(format!(
+                "Unable to read the zstd frame at byte {}: {:#}",
+                position, e
+            )));
+        }
+
+        if magic == ZSTD_MAGIC {
+            let frame_meta = match next_frame.and_then(|hint| hint.metadata) {
+                Some(m) => FrameMeta::new(position, end - position, m.order)
+                    .with_checksum(m.checksum)
+                    .with_uncompressed_length(m.uncompressed_length),
+                None => FrameMeta::new(position, end - position, frames.len() as u64),
+            };
+            frames.push(frame_meta);
+        }
+    }
+
+    Ok(frames)
+}
+
+pub fn scan_zstd_file(zstd_file: &str) -> Result<Vec<FrameMeta>> {
+    let zstd_handle = File::open(zstd_file)
+        .with_context(|| format!("Unable to open zstd file '{}'", zstd_file))?;
+    scan_zstd_frames(&mut BufReader::new(zstd_handle))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame_header_length() {
+        // Single segment with a one byte content size
+        assert_eq!(1, frame_header_length(0x20).unwrap());
+        // Window descriptor, four byte dictionary id and eight byte content size
+        assert_eq!(13, frame_header_length(0xC3).unwrap());
+        assert!(frame_header_length(0x08).is_err());
+    }
+
+    #[test]
+    fn test_scan_zstd_file() {
+        let exp_frames = vec![
+            FrameMeta::new(0, 151, 0),
+            FrameMeta::new(151, 150, 1),
+            FrameMeta::new(301, 120, 2),
+        ];
+        assert_eq!(exp_frames, scan_zstd_file("test/example.zstd").unwrap());
+    }
+
+    #[test]
+    fn test_scan_zstd_frames_skippable() {
+        let first_frame = zstd::encode_all(&b"a\t1\nb\t2\n"[..], 3).unwrap();
+        let second_frame = zstd::encode_all(&b"c\t3\n"[..], 19).unwrap();
+
+        let mut payload = first_frame.clone();
+        payload.extend_from_slice(&0x184D_2A5Au32.to_le_bytes());
+        payload.extend_from_slice(&3u32.to_le_bytes());
+        payload.extend_from_slice(b"pad");
+        payload.extend_from_slice(&second_frame);
+
+        let obs_frames = scan_zstd_frames(&mut Cursor::new(payload)).unwrap();
+
+        let second_position = first_frame.len() as u64 + 11;
+        let exp_frames = vec![
+            FrameMeta::new(0, first_frame.len() as u64, 0),
+            FrameMeta::new(second_position, second_frame.len() as u64, 1),
+        ];
+        assert_eq!(exp_frames, obs_frames);
+    }
+
+    #[test]
+    fn test_scan_zstd_frames_pzstd() {
+        let frames = [
+            zstd::encode_all(&b"a\t1\nb\t2\n"[..], 3).unwrap(),
+            zstd::encode_all(&b"c\t3\n"[..], 3).unwrap(),
+        ];
+
+        // Each frame is preceded by a pzstd size header, with an unrelated skippable frame between
+        let mut payload: Vec<u8> = Vec::new();
+        let mut exp_frames: Vec<FrameMeta> = Vec::new();
+        for (order, frame) in frames.iter().enumerate() {
+            payload.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+            payload.extend_from_slice(&PZSTD_HEADER_SIZE.to_le_bytes());
+            payload.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+
+            exp_frames.push(FrameMeta::new(
+                payload.len() as u64,
+                frame.len() as u64,
+                order as u64,
+            ));
+            payload.extend_from_slice(frame);
+
+            payload.extend_from_slice(&0x184D_2A5Fu32.to_le_bytes());
+            payload.extend_from_slice(&2u32.to_le_bytes());
+            payload.extend_from_slice(b"ok");
+        }
+
+        let obs_frames = scan_zstd_frames(&mut Cursor::new(payload)).unwrap();
+        assert_eq!(exp_frames, obs_frames);
+    }
+
+    #[test]
+    fn test_scan_zstd_frames_metadata() {
+        let zstd_file = "scan_zstd_frames_metadata.zstd";
+        let index_file = "scan_zstd_frames_metadata.zstd.idx";
+        let compression_options = crate::CompressionOptions {
+            frame_metadata: true,
+            ..Default::default()
+        };
+
+        crate::compression::write_indexed_zstd(
+            BufReader::new(File::open("test/data.txt").unwrap()),
+            File::create(zstd_file).unwrap(),
+            std::io::BufWriter::new(File::create(index_file).unwrap()),
+            200,
+            0,
+            &compression_options,
+        )
+        .unwrap();
+
+        // The frames found by scanning match the index that was written alongside them, other
+        // than the record counts, which the metadata frames do not carry
+        let obs_frames = scan_zstd_file(zstd_file);
+        let mut exp_frames: Vec<FrameMeta> =
+            serde_json::from_reader(File::open(index_file).unwrap()).unwrap();
+        for frame_meta in exp_frames.iter_mut() {
+            frame_meta.record_stats = None;
+        }
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(index_file);
+
+        assert_eq!(exp_frames, obs_frames.unwrap());
+    }
+
+    #[test]
+    fn test_scan_zstd_frames_pzstd_truncated() {
+        let frame = zstd::encode_all(&b"a\t1\n"[..], 3).unwrap();
+
+        let mut payload: Vec<u8> = Vec::new();
+        payload.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+        payload.extend_from_slice(&PZSTD_HEADER_SIZE.to_le_bytes());
+        payload.extend_from_slice(&(frame.len() as u32 + 10).to_le_bytes());
+        payload.extend_from_slice(&frame);
+
+        let obs_result = scan_zstd_frames(&mut Cursor::new(payload));
+        assert!(obs_result.unwrap_err().is::<PipelineError>());
+    }
+
+    fn gzip_member(content: &[u8], extra: Option<Vec<u8>>) -> Vec<u8> {
+        let mut builder = flate2::GzBuilder::new();
+        if let Some(extra) = extra {
+            builder = builder.extra(extra);
+        }
+        let mut encoder = builder.write(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bgzf_member(content: &[u8]) -> Vec<u8> {
+        // The block size is only known after compressing, so compress again once it is known
+        let member = gzip_member(content, Some(vec![b'B', b'C', 2, 0, 0, 0]));
+        let block_size = (member.len() as u16 - 1).to_le_bytes();
+        gzip_member(
+            content,
+            Some(vec![b'B', b'C', 2, 0, block_size[0], block_size[1]]),
+        )
+    }
+
+    #[test]
+    fn test_scan_gzip_members() {
+        let members = [
+            gzip_member(b"a\t1\nb\t2\n", None),
+            bgzf_member(b"c\t3\n"),
+            gzip_member(b"d\t4\n", Some(b"XY\x01\x00z".to_vec())),
+        ];
+
+        let mut exp_members: Vec<(u64, u64)> = Vec::new();
+        let mut payload: Vec<u8> = Vec::new();
+        for member in &members {
+            exp_members.push((payload.len() as u64, member.len() as u64));
+            payload.extend_from_slice(member);
+        }
+
+        let obs_members = scan_gzip_members(&mut Cursor::new(payload.clone())).unwrap();
+        assert_eq!(exp_members, obs_members);
+
+        let truncated = payload[..payload.len() - 3].to_vec();
+        let obs_result = scan_gzip_members(&mut Cursor::new(truncated));
+        assert!(obs_result.unwrap_err().is::<PipelineError>());
+    }
+
+    #[test]
+    fn test_load_gzi() {
+        let mut gzi: Vec<u8> = Vec::new();
+        for value in [2u64, 100, 400, 250, 900] {
+            gzi.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let exp_members = vec![(0, 100), (100, 150), (250, 50)];
+        assert_eq!(exp_members, load_gzi(&mut Cursor::new(&gzi), 300).unwrap());
+        assert!(load_gzi(&mut Cursor::new(&gzi), 200).is_err());
+    }
+
+    #[test]
+    fn test_group_members() {
+        let members = vec![(0, 40), (40, 40), (80, 40), (120, 10)];
+
+        let exp_frames = vec![FrameMeta::new(0, 80, 0), FrameMeta::new(80, 50, 1)];
+        assert_eq!(exp_frames, group_members(members, 64));
+    }
+
+    #[test]
+    fn test_scan_zstd_frames_invalid() {
+        let frame = zstd::encode_all(&b"a\t1\n"[..], 3).unwrap();
+
+        let mut garbage = frame.clone();
+        garbage.extend_from_slice(b"not zstd");
+        let obs_result = scan_zstd_frames(&mut Cursor::new(garbage));
+        assert!(obs_result.unwrap_err().is::<PipelineError>());
+
+        let truncated = frame[..frame.len() - 2].to_vec();
+        let obs_result = scan_zstd_frames(&mut Cursor::new(truncated));
+        assert!(obs_result.unwrap_err().is::<PipelineError>());
+    }
+}
@@ -0,0 +1,1136 @@
+use crate::decompression::trim_line_ending;
+use crate::follow::file_stamp;
+use crate::metrics::Metrics;
+use crate::snapshot::MapPosition;
+use crate::{EitherMap, PipelineError, RecordKey};
+use ahash::AHashSet;
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+// Largest lookup body accepted, which still allows millions of accessions in one request
+const MAX_LOOKUP_BYTES: u64 = 1 << 30;
+// Body buffer reserved up front, which then grows as the body arrives rather than on the word of
+// its Content-Length
+const INITIAL_BODY_BYTES: u64 = 64 * 1024;
+// Largest request line and headers accepted, together
+const MAX_HEADER_BYTES: u64 = 16 * 1024;
+// Time a connection may sit idle part way through a request or response before it is dropped,
+// so that a silent client cannot hold a connection permit for ever
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Layout of a lookup response.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum LookupFormat {
+    /// A JSON object of each key to its value, or null for keys without a record
+    Json,
+    /// KEY<TAB>VALUE lines for the keys with a record
+    Tsv,
+}
+
+impl LookupFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            LookupFormat::Json => "application/json",
+            LookupFormat::Tsv => "text/tab-separated-values",
+        }
+    }
+
+    fn write_start<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if *self == LookupFormat::Json {
+            writer.write_all(b"{")?;
+        }
+        Ok(())
+    }
+
+    fn write_record<W: Write, V: Display + Serialize>(
+        &self,
+        writer: &mut W,
+        key: &[u8],
+        value: Option<&V>,
+        first: bool,
+    ) -> Result<()> {
+        match (self, value) {
+            (LookupFormat::Json, _) => {
+                if !first {
+                    writer.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut *writer, &String::from_utf8_lossy(key))?;
+                writer.write_all(b":")?;
+                serde_json::to_writer(&mut *writer, &value)?;
+            }
+            (LookupFormat::Tsv, Some(v)) => {
+                writer.write_all(key)?;
+                writeln!(writer, "\t{}", v)?;
+            }
+            (LookupFormat::Tsv, None) => {}
+        }
+        Ok(())
+    }
+
+    fn write_end<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if *self == LookupFormat::Json {
+            writer.write_all(b"}\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of looking keys up against a remote lookup service.
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientLookupSummary {
+    pub keys_sent: usize,
+    pub records_found: usize,
+    pub requests: usize,
+}
+
+/// A lookup request, as read from the connection.
+struct LookupRequest {
+    method: String,
+    path: String,
+    format: LookupFormat,
+    namespace: Option<String>,
+    body: Vec<u8>,
+}
+
+/// The map of a namespace, with how far through its archive it was built.
+pub(crate) struct ServedMap<K, V> {
+    pub records: EitherMap<K, V>,
+    pub position: MapPosition,
+}
+
+/// Builds the map of the archive at a namespace position again, when its files change.
+pub(crate) type MapLoader<K, V> = Box<dyn Fn(usize) -> Result<ServedMap<K, V>> + Send + Sync>;
+
+/// Saves the map of a namespace position to a snapshot, returning the file written.
+pub(crate) type SnapshotWriter<K, V> =
+    Box<dyn Fn(usize, &ServedMap<K, V>) -> Result<String> + Send + Sync>;
+
+/// The map of a namespace, which a reload replaces whole
+type SwappableMap<K, V> = RwLock<Arc<ServedMap<K, V>>>;
+
+/// Size and modification time of each watched file of an archive
+type ArchiveStamps = Vec<Option<(u64, SystemTime)>>;
+
+/// Maps of records from one or more archives, each under its own namespace, answering bulk
+/// lookups over HTTP. Each map is held behind its own lock, so that a reloaded map can be swapped
+/// in whole while lookups against the earlier one finish.
+pub(crate) struct LookupService<K, V> {
+    namespaces: Vec<(String, SwappableMap<K, V>)>,
+    /// Key prefixes and the namespace each is routed to, longest prefix first
+    routes: Vec<(Vec<u8>, usize)>,
+    pool: rayon::ThreadPool,
+    loader: Option<MapLoader<K, V>>,
+    /// Held while a map is rebuilt, so that reloads do not decode archives side by side
+    reloading: Mutex<()>,
+    /// Whether clients may ask for reloads through `/reload`
+    remote_reload: bool,
+    snapshot_writer: Option<SnapshotWriter<K, V>>,
+    /// Held while a snapshot is written, so that two requests do not write one file together
+    snapshotting: Mutex<()>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// Counts the connections being answered, holding back new ones once the limit is reached.
+struct ConnectionLimit {
+    active: Mutex<usize>,
+    released: Condvar,
+    limit: usize,
+}
+
+/// A connection counted against the limit until it is dropped.
+struct ConnectionPermit(Arc<ConnectionLimit>);
+
+impl ConnectionLimit {
+    fn new(limit: usize) -> Arc<ConnectionLimit> {
+        Arc::new(ConnectionLimit {
+            active: Mutex::new(0),
+            released: Condvar::new(),
+            limit: limit.max(1),
+        })
+    }
+
+    /// Wait until fewer connections than the limit are being answered, then count one more.
+    fn acquire(self: &Arc<Self>) -> ConnectionPermit {
+        let mut active = self
+            .released
+            .wait_while(self.active.lock().unwrap(), |a| *a >= self.limit)
+            .unwrap();
+        *active += 1;
+        ConnectionPermit(Arc::clone(self))
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+//region: Private functions
+
+pub(crate) fn lookup_value<K: RecordKey, V: Clone>(
+    record_map: &EitherMap<K, V>,
+    key: &K,
+) -> Option<V> {
+    match record_map {
+        EitherMap::Dash(m) => m.get(key).map(|v| v.value().clone()),
+        EitherMap::AHash(m) => m.get(key).cloned(),
+        EitherMap::Ordered(m) => m.get(key).cloned(),
+    }
+}
+
+/// Decode a query string value, in which '+' stands for a space and '%XX' for any byte.
+fn percent_decode(value: &str) -> Result<String> {
+    let mut decoded: Vec<u8> = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(byte) if hex.len() == 2 => decoded.push(byte),
+                    _ => bail!("Malformed escape in query value '{}'.", value),
+                }
+            }
+            _ => decoded.push(b),
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Encode a query string value, escaping every byte but the unreserved characters of a URL.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Read one line of the request head, failing once the head runs past its limit.
+fn read_head_line<R: BufRead>(
+    head_reader: &mut std::io::Take<R>,
+    line: &mut String,
+) -> Result<usize> {
+    let read = head_reader.read_line(line)?;
+    if read > 0 && !line.ends_with('\n') && head_reader.limit() == 0 {
+        bail!(
+            "Request line and headers are over the limit of {} bytes.",
+            MAX_HEADER_BYTES
+        );
+    }
+    Ok(read)
+}
+
+fn read_request(stream: &TcpStream) -> Result<LookupRequest> {
+    parse_request(BufReader::new(stream.try_clone()?))
+}
+
+fn parse_request<R: BufRead>(mut reader: R) -> Result<LookupRequest> {
+    let mut head_reader = (&mut reader).take(MAX_HEADER_BYTES);
+    let mut request_line = String::new();
+    read_head_line(&mut head_reader, &mut request_line)?;
+
+    let (method, target) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, target, ..] => (method.to_string(), target.to_string()),
+        _ => bail!("Malformed request line '{}'.", request_line.trim_end()),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    // TSV is asked for by the query string or the Accept header, and JSON is the default
+    let mut format = LookupFormat::Json;
+    let mut namespace: Option<String> = None;
+    for parameter in query.split('&') {
+        match parameter.split_once('=') {
+            Some(("format", "tsv")) => format = LookupFormat::Tsv,
+            Some(("namespace", n)) => namespace = Some(percent_decode(n)?),
+            _ => {}
+        }
+    }
+    let mut content_length: u64 = 0;
+    let mut header = String::new();
+    while read_head_line(&mut head_reader, &mut header)? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse()?,
+                "accept"
+                    if !query.contains("format=")
+                        && value.starts_with("text/tab-separated-values") =>
+                {
+                    format = LookupFormat::Tsv
+                }
+                _ => {}
+            }
+        }
+        header.clear();
+    }
+
+    if content_length > MAX_LOOKUP_BYTES {
+        bail!(
+            "Lookup body of {} bytes is over the limit of {} bytes.",
+            content_length,
+            MAX_LOOKUP_BYTES
+        );
+    }
+    let mut body: Vec<u8> = Vec::with_capacity(content_length.min(INITIAL_BODY_BYTES) as usize);
+    reader.take(content_length).read_to_end(&mut body)?;
+
+    Ok(LookupRequest {
+        method,
+        path: path.to_string(),
+        format,
+        namespace,
+        body,
+    })
+}
+
+fn write_response(
+    mut stream: TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Address to connect to for a server given as 'HOST:PORT' or 'http://HOST:PORT/'.
+fn server_address(server: &str) -> &str {
+    let server = server.strip_prefix("http://").unwrap_or(server);
+    server.trim_end_matches('/')
+}
+
+/// Post one batch of keys to a lookup service, returning its KEY<TAB>VALUE lines.
+fn post_lookup(server: &str, namespace: Option<&str>, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let address = server_address(server);
+    let body = keys.join(&b'\n');
+    let target = match namespace {
+        Some(n) => format!("/lookup?format=tsv&namespace={}", percent_encode(n)),
+        None => "/lookup?format=tsv".to_string(),
+    };
+
+    let mut stream = TcpStream::connect(address)
+        .with_context(|| format!("Unable to connect to lookup service '{}'", server))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        target,
+        address,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+
+    let mut response: Vec<u8> = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let Some(header_end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        bail!("Incomplete response from lookup service '{}'.", server);
+    };
+    let body = response.split_off(header_end + 4);
+    let header = String::from_utf8_lossy(&response[..header_end]);
+
+    match header.split_whitespace().nth(1) {
+        Some("200") => Ok(body),
+        status => bail!(
+            "Lookup service '{}' answered with status {}: {}",
+            server,
+            status.unwrap_or("unknown"),
+            String::from_utf8_lossy(&body).trim_end()
+        ),
+    }
+}
+
+//endregion:
+
+/// Check the namespaces of a service and the key prefix routes between them, returning the
+/// routes by namespace position with the longest prefix first.
+pub(crate) fn resolve_routes(
+    namespaces: &[&str],
+    routes: &[(String, String)],
+) -> Result<Vec<(Vec<u8>, usize)>> {
+    for (i, namespace) in namespaces.iter().enumerate() {
+        if namespaces[..i].contains(namespace) {
+            bail!(PipelineError::Usage(format!(
+                "Namespace '{}' is given to more than one archive!",
+                namespace
+            )));
+        }
+    }
+
+    let mut resolved = routes
+        .iter()
+        .map(
+            |(prefix, namespace)| match namespaces.iter().position(|n| n == namespace) {
+                Some(i) => Ok((prefix.as_bytes().to_vec(), i)),
+                None => bail!(PipelineError::Usage(format!(
+                    "Prefix '{}' is routed to '{}', which is not the namespace of any archive!",
+                    prefix, namespace
+                ))),
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    resolved.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    Ok(resolved)
+}
+
+/// Read the keys of a lookup body, given either as a JSON array of strings or as one key per
+/// line. Blank lines are ignored.
+pub(crate) fn parse_lookup_keys(body: &[u8]) -> Result<Vec<Vec<u8>>> {
+    if body.trim_ascii_start().starts_with(b"[") {
+        let keys: Vec<String> = match serde_json::from_slice(body) {
+            Ok(k) => k,
+            Err(e) => bail!("Lookup body is not a JSON array of keys: {}", e),
+        };
+        return Ok(keys.into_iter().map(String::into_bytes).collect());
+    }
+
+    Ok(body
+        .split(|&b| b == b'\n')
+        .map(trim_line_ending)
+        .filter(|k| !k.is_empty())
+        .map(|k| k.to_vec())
+        .collect())
+}
+
+impl<K, V> LookupService<K, V>
+where
+    K: RecordKey,
+    V: Clone + Display + Serialize + Send + Sync,
+{
+    pub(crate) fn new(
+        namespaces: Vec<(String, ServedMap<K, V>)>,
+        routes: Vec<(Vec<u8>, usize)>,
+        pool: rayon::ThreadPool,
+    ) -> Self {
+        LookupService {
+            namespaces: namespaces
+                .into_iter()
+                .map(|(n, m)| (n, RwLock::new(Arc::new(m))))
+                .collect(),
+            routes,
+            pool,
+            loader: None,
+            reloading: Mutex::new(()),
+            remote_reload: false,
+            snapshot_writer: None,
+            snapshotting: Mutex::new(()),
+            metrics: None,
+        }
+    }
+
+    /// Count the lookups answered in the metrics, for a scrape of `/metrics` to report.
+    pub(crate) fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Let clients save the map of a namespace to a snapshot by posting to `/snapshot`.
+    pub(crate) fn with_snapshot_writer(mut self, snapshot_writer: SnapshotWriter<K, V>) -> Self {
+        self.snapshot_writer = Some(snapshot_writer);
+        self
+    }
+
+    /// Let clients ask for reloads by posting to `/reload`. Without this, reloads come only from
+    /// watching the archive files.
+    pub(crate) fn with_remote_reload(mut self, remote_reload: bool) -> Self {
+        self.remote_reload = remote_reload;
+        self
+    }
+
+    /// Allow the maps of the service to be rebuilt with the given loader.
+    pub(crate) fn with_loader(mut self, loader: MapLoader<K, V>) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    fn namespace_position(&self, namespace: &str) -> Result<usize> {
+        match self.namespaces.iter().position(|(n, _)| n == namespace) {
+            Some(i) => Ok(i),
+            None => bail!("Unknown namespace '{}'.", namespace),
+        }
+    }
+
+    fn current_map(&self, position: usize) -> Arc<ServedMap<K, V>> {
+        Arc::clone(&self.namespaces[position].1.read().unwrap())
+    }
+
+    /// Rebuild the map of a namespace and swap it in, returning its number of records. The
+    /// earlier map is kept if the rebuild fails.
+    pub(crate) fn reload(&self, position: usize) -> Result<usize> {
+        let Some(loader) = &self.loader else {
+            bail!("This service cannot reload its archives.");
+        };
+        let _reloading = self.reloading.lock().unwrap();
+
+        let served_map = loader(position)?;
+        let records = served_map.records.len();
+        *self.namespaces[position].1.write().unwrap() = Arc::new(served_map);
+        Ok(records)
+    }
+
+    /// Save the map of a namespace as it now stands to a snapshot, returning its number of
+    /// records and the file written. Lookups carry on against the map while it is written.
+    pub(crate) fn snapshot(&self, position: usize) -> Result<(usize, String)> {
+        let Some(snapshot_writer) = &self.snapshot_writer else {
+            bail!("This service cannot save snapshots.");
+        };
+        let _snapshotting = self.snapshotting.lock().unwrap();
+
+        let served_map = self.current_map(position);
+        let snapshot_file = snapshot_writer(position, &served_map)?;
+        Ok((served_map.records.len(), snapshot_file))
+    }
+
+    /// Find the value of a key in the named namespace or, failing that, the namespace its prefix
+    /// is routed to. Keys which match no route are looked for in each namespace in turn.
+    fn find_value(
+        &self,
+        record_maps: &[Arc<ServedMap<K, V>>],
+        key_bytes: &[u8],
+        namespace: Option<usize>,
+    ) -> Option<V> {
+        let key = K::from_key_bytes(key_bytes, false)?;
+        let routed = namespace.or_else(|| {
+            self.routes
+                .iter()
+                .find(|(prefix, _)| key_bytes.starts_with(prefix))
+                .map(|(_, i)| *i)
+        });
+
+        match routed {
+            Some(i) => lookup_value(&record_maps[i].records, &key),
+            None => record_maps
+                .iter()
+                .find_map(|record_map| lookup_value(&record_map.records, &key)),
+        }
+    }
+
+    /// Resolve a batch of keys across the worker pool, returning the value of each key in the
+    /// order asked, or None for keys without a record.
+    pub(crate) fn lookup(
+        &self,
+        keys: &[Vec<u8>],
+        namespace: Option<&str>,
+    ) -> Result<Vec<Option<V>>> {
+        let namespace = namespace.map(|n| self.namespace_position(n)).transpose()?;
+
+        // The whole batch is resolved against the maps as they stood when it arrived
+        let record_maps: Vec<Arc<ServedMap<K, V>>> = (0..self.namespaces.len())
+            .map(|i| self.current_map(i))
+            .collect();
+        let values: Vec<Option<V>> = self.pool.install(|| {
+            keys.par_iter()
+                .map(|k| self.find_value(&record_maps, k, namespace))
+                .collect()
+        });
+
+        if let Some(metrics) = &self.metrics {
+            let found = values.iter().filter(|v| v.is_some()).count();
+            metrics.record_lookup(keys.len() as u64, found as u64);
+        }
+        Ok(values)
+    }
+
+    /// Write the values of a lookup in the requested layout. A key asked for more than once is
+    /// written once, at its first position.
+    pub(crate) fn render(
+        &self,
+        keys: &[Vec<u8>],
+        values: &[Option<V>],
+        format: &LookupFormat,
+    ) -> Result<Vec<u8>> {
+        let mut seen: AHashSet<&[u8]> = AHashSet::with_capacity(keys.len());
+        let mut output: Vec<u8> = Vec::new();
+
+        format.write_start(&mut output)?;
+        for (key, value) in keys.iter().zip(values) {
+            if seen.insert(key) {
+                format.write_record(&mut output, key, value.as_ref(), seen.len() == 1)?;
+            }
+        }
+        format.write_end(&mut output)?;
+        Ok(output)
+    }
+
+    /// Find the namespace to snapshot, which must be named when more than one archive is served.
+    fn snapshot_position(&self, namespace: Option<&str>) -> Result<usize> {
+        match (namespace, self.namespaces.len()) {
+            (Some(n), _) => self.namespace_position(n),
+            (None, 1) => Ok(0),
+            (None, _) => bail!("Name the namespace, as more than one archive is served."),
+        }
+    }
+
+    fn handle_request(&self, stream: TcpStream) -> Result<()> {
+        let request = match read_request(&stream) {
+            Ok(r) => r,
+            Err(e) => {
+                return write_response(
+                    stream,
+                    "400 Bad Request",
+                    "text/plain",
+                    format!("{}\n", e).as_bytes(),
+                );
+            }
+        };
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/lookup") => {}
+            ("POST", "/snapshot") if self.snapshot_writer.is_none() => {
+                return write_response(
+                    stream,
+                    "403 Forbidden",
+                    "text/plain",
+                    b"Snapshots are not enabled for this service\n",
+                );
+            }
+            ("POST", "/snapshot") => {
+                let snapshot_result = self
+                    .snapshot_position(request.namespace.as_deref())
+                    .and_then(|position| {
+                        let (records, snapshot_file) = self.snapshot(position)?;
+                        Ok(format!(
+                            "Saved {} records of '{}' to '{}'\n",
+                            records, self.namespaces[position].0, snapshot_file
+                        ))
+                    });
+                return match snapshot_result {
+                    Ok(body) => write_response(stream, "200 OK", "text/plain", body.as_bytes()),
+                    Err(e) => write_response(
+                        stream,
+                        "500 Internal Server Error",
+                        "text/plain",
+                        format!("{}\n", e).as_bytes(),
+                    ),
+                };
+            }
+            ("POST", "/reload") if !self.remote_reload => {
+                return write_response(
+                    stream,
+                    "403 Forbidden",
+                    "text/plain",
+                    b"Reloads are not enabled for this service\n",
+                );
+            }
+            ("POST", "/reload") => {
+                let positions: Vec<usize> = match request.namespace.as_deref() {
+                    None => (0..self.namespaces.len()).collect(),
+                    Some(n) => match self.namespace_position(n) {
+                        Ok(i) => vec![i],
+                        Err(e) => {
+                            return write_response(
+                                stream,
+                                "400 Bad Request",
+                                "text/plain",
+                                format!("{}\n", e).as_bytes(),
+                            );
+                        }
+                    },
+                };
+
+                let mut body = String::new();
+                for position in positions {
+                    match self.reload(position) {
+                        Ok(records) => body.push_str(&format!(
+                            "Reloaded {} records into '{}'\n",
+                            records, self.namespaces[position].0
+                        )),
+                        Err(e) => {
+                            body.push_str(&format!("{}\n", e));
+                            return write_response(
+                                stream,
+                                "500 Internal Server Error",
+                                "text/plain",
+                                body.as_bytes(),
+                            );
+                        }
+                    }
+                }
+                return write_response(stream, "200 OK", "text/plain", body.as_bytes());
+            }
+            (_, "/lookup" | "/reload" | "/snapshot") => {
+                return write_response(
+                    stream,
+                    "405 Method Not Allowed",
+                    "text/plain",
+                    b"Method not allowed\n",
+                );
+            }
+            _ => return write_response(stream, "404 Not Found", "text/plain", b"Not found\n"),
+        }
+
+        let lookup_result = parse_lookup_keys(&request.body).and_then(|keys| {
+            let values = self.lookup(&keys, request.namespace.as_deref())?;
+            Ok((keys, values))
+        });
+        let (keys, values) = match lookup_result {
+            Ok(r) => r,
+            Err(e) => {
+                return write_response(
+                    stream,
+                    "400 Bad Request",
+                    "text/plain",
+                    format!("{}\n", e).as_bytes(),
+                );
+            }
+        };
+        let body = self.render(&keys, &values, &request.format)?;
+        write_response(stream, "200 OK", request.format.content_type(), &body)
+    }
+}
+
+/// Look up the keys of a reader, one per line, against a remote lookup service in batches of
+/// `batch_size` keys, writing KEY<TAB>VALUE lines for the keys found. Without a namespace, the
+/// service routes each key itself.
+pub(crate) fn lookup_remote<R: BufRead, W: Write>(
+    server: &str,
+    namespace: Option<&str>,
+    key_reader: R,
+    mut writer: W,
+    batch_size: usize,
+) -> Result<ClientLookupSummary> {
+    let mut summary = ClientLookupSummary::default();
+    let mut batch: Vec<Vec<u8>> = Vec::with_capacity(batch_size);
+
+    let mut send_batch = |batch: &mut Vec<Vec<u8>>| -> Result<()> {
+        let records = post_lookup(server, namespace, batch)?;
+        summary.keys_sent += batch.len();
+        summary.records_found += records.iter().filter(|&&b| b == b'\n').count();
+        summary.requests += 1;
+        writer.write_all(&records)?;
+        batch.clear();
+        Ok(())
+    };
+
+    for line in key_reader.split(b'\n') {
+        let line = line?;
+        let key = trim_line_ending(&line);
+        if key.is_empty() {
+            continue;
+        }
+
+        batch.push(key.to_vec());
+        if batch.len() == batch_size {
+            send_batch(&mut batch)?;
+        }
+    }
+    if !batch.is_empty() {
+        send_batch(&mut batch)?;
+    }
+
+    writer.flush()?;
+    Ok(summary)
+}
+
+/// Check the files of each archive every interval, reloading the map of an archive once its files
+/// have changed and then been left alone for a whole interval, so that a partly written archive
+/// is not decoded. A failed reload keeps the earlier map, and is tried again when the files next
+/// change.
+pub(crate) fn watch_archives<K, V>(
+    service: Arc<LookupService<K, V>>,
+    watched_files: Vec<Vec<String>>,
+    interval: Duration,
+) -> Result<()>
+where
+    K: RecordKey + 'static,
+    V: Clone + Display + Serialize + Send + Sync + 'static,
+{
+    let take_stamps = move || -> Vec<ArchiveStamps> {
+        watched_files
+            .iter()
+            .map(|files| files.iter().map(|f| file_stamp(f)).collect())
+            .collect()
+    };
+
+    std::thread::Builder::new()
+        .name("archive-watcher".to_string())
+        .spawn(move || {
+            let mut loaded = take_stamps();
+            let mut previous = loaded.clone();
+            loop {
+                std::thread::sleep(interval);
+                let current = take_stamps();
+
+                for (position, stamps) in current.iter().enumerate() {
+                    if *stamps == loaded[position] || *stamps != previous[position] {
+                        continue;
+                    }
+                    let namespace = &service.namespaces[position].0;
+                    match service.reload(position) {
+                        Ok(records) => eprintln!("Reloaded {} records into '{}'", records, namespace),
+                        Err(e) => eprintln!(
+                            "WARNING: Unable to reload '{}', so its earlier map is still served: {}",
+                            namespace, e
+                        ),
+                    }
+                    loaded[position] = stamps.clone();
+                }
+                previous = current;
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Answer lookups posted to `/lookup`, reloads posted to `/reload` and snapshots posted to
+/// `/snapshot`, for as long as the listener accepts connections. Each connection is served on its own thread, up to `max_connections` at once,
+/// beyond which further connections wait to be accepted. A connection which fails before it is
+/// answered is reported and passed over.
+pub(crate) fn serve_lookups<K, V>(
+    listener: TcpListener,
+    service: Arc<LookupService<K, V>>,
+    max_connections: usize,
+) -> Result<()>
+where
+    K: RecordKey + 'static,
+    V: Clone + Display + Serialize + Send + Sync + 'static,
+{
+    let connection_limit = ConnectionLimit::new(max_connections);
+    for stream in listener.incoming() {
+        // A client which hangs up straight away has no peer address left to report
+        let (stream, peer) = match stream.and_then(|s| s.peer_addr().map(|p| (s, p))) {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("WARNING: Unable to accept lookup connection: {}", e);
+                continue;
+            }
+        };
+        let timeouts = stream
+            .set_read_timeout(Some(CONNECTION_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT)));
+        if let Err(e) = timeouts {
+            eprintln!("WARNING: Unable to answer lookup from {}: {}", peer, e);
+            continue;
+        }
+        let permit = connection_limit.acquire();
+        let service = Arc::clone(&service);
+
+        let spawned = std::thread::Builder::new()
+            .name(format!("lookup-{peer}"))
+            .spawn(move || {
+                let _permit = permit;
+                if let Err(e) = service.handle_request(stream) {
+                    eprintln!("Unable to answer lookup from {}: {}", peer, e);
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("WARNING: Unable to answer lookup from {}: {}", peer, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ahash::AHashMap;
+
+    fn served(record_map: AHashMap<String, u64>) -> ServedMap<String, u64> {
+        ServedMap {
+            records: EitherMap::AHash(record_map),
+            position: MapPosition::default(),
+        }
+    }
+
+    fn build_service() -> Arc<LookupService<String, u64>> {
+        let record_map: AHashMap<String, u64> = [("a".to_string(), 562), ("b".to_string(), 9606)]
+            .into_iter()
+            .collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        Arc::new(LookupService::new(
+            vec![("prot".to_string(), served(record_map))],
+            Vec::new(),
+            pool,
+        ))
+    }
+
+    fn send_request(listener_address: std::net::SocketAddr, request: &[u8]) -> String {
+        let mut client = TcpStream::connect(listener_address).unwrap();
+        client.write_all(request).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!("prot", percent_decode("prot").unwrap());
+        assert_eq!("a b&c", percent_decode("a+b%26c").unwrap());
+        assert_eq!(
+            "nr/prot ns",
+            percent_decode(&percent_encode("nr/prot ns")).unwrap()
+        );
+        assert_eq!("nr%2Fprot%20ns", percent_encode("nr/prot ns"));
+        assert!(percent_decode("a%2").is_err());
+        assert!(percent_decode("a%zz").is_err());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let connection_limit = ConnectionLimit::new(1);
+        let first = connection_limit.acquire();
+
+        // The second connection is held back until the first is answered
+        let limit = Arc::clone(&connection_limit);
+        let waiting = std::thread::spawn(move || drop(limit.acquire()));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting.join().unwrap();
+        assert_eq!(0, *connection_limit.active.lock().unwrap());
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let obs_request = parse_request(std::io::Cursor::new(
+            b"POST /lookup?namespace=prot HTTP/1.1\r\nContent-Length: 3\r\nAccept: text/tab-separated-values\r\n\r\na\nb".to_vec(),
+        ))
+        .unwrap();
+        assert_eq!(
+            ("POST", "/lookup"),
+            (obs_request.method.as_str(), obs_request.path.as_str())
+        );
+        assert_eq!(LookupFormat::Tsv, obs_request.format);
+        assert_eq!(Some("prot".to_string()), obs_request.namespace);
+        assert_eq!(b"a\nb".to_vec(), obs_request.body);
+
+        // A request line or headers past the limit are refused before they are read in full
+        let mut long_line = b"GET /".to_vec();
+        long_line.resize(MAX_HEADER_BYTES as usize + 10, b'a');
+        assert!(parse_request(std::io::Cursor::new(long_line)).is_err());
+        let mut long_header = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        long_header.resize(MAX_HEADER_BYTES as usize + 10, b'a');
+        long_header.extend_from_slice(b"\r\n\r\n");
+        assert!(parse_request(std::io::Cursor::new(long_header)).is_err());
+
+        // A body shorter than its Content-Length is read as far as it goes
+        let obs_request = parse_request(std::io::Cursor::new(
+            b"POST /lookup HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\na".to_vec(),
+        ))
+        .unwrap();
+        assert_eq!(b"a".to_vec(), obs_request.body);
+    }
+
+    #[test]
+    fn test_parse_lookup_keys() {
+        let exp_keys = vec![b"a".to_vec(), b"b".to_vec()];
+
+        assert_eq!(exp_keys, parse_lookup_keys(b"a\r\n\nb\n").unwrap());
+        assert_eq!(exp_keys, parse_lookup_keys(b" [\"a\", \"b\"]").unwrap());
+        assert!(parse_lookup_keys(b"[\"a\", 1]").is_err());
+    }
+
+    #[test]
+    fn test_lookup_service_routes() {
+        let prot_map: AHashMap<String, u64> = [("WP_1".to_string(), 562), ("X".to_string(), 1)]
+            .into_iter()
+            .collect();
+        let nucl_map: AHashMap<String, u64> = [("NC_1".to_string(), 9606), ("X".to_string(), 2)]
+            .into_iter()
+            .collect();
+        let routes = resolve_routes(
+            &["prot", "nucl"],
+            &[
+                ("N".to_string(), "nucl".to_string()),
+                ("NC_".to_string(), "nucl".to_string()),
+            ],
+        )
+        .unwrap();
+        let service = LookupService::new(
+            vec![
+                ("prot".to_string(), served(prot_map)),
+                ("nucl".to_string(), served(nucl_map)),
+            ],
+            routes,
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap(),
+        );
+        let keys = vec![b"NC_1".to_vec(), b"WP_1".to_vec(), b"X".to_vec()];
+
+        // Unrouted keys are found in the first namespace holding them
+        assert_eq!(
+            vec![Some(9606), Some(562), Some(1)],
+            service.lookup(&keys, None).unwrap()
+        );
+        assert_eq!(
+            vec![Some(9606), None, Some(2)],
+            service.lookup(&keys, Some("nucl")).unwrap()
+        );
+        assert!(service.lookup(&keys, Some("other")).is_err());
+        assert!(resolve_routes(&["prot"], &[("N".to_string(), "nucl".to_string())]).is_err());
+        assert!(resolve_routes(&["prot", "prot"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_lookup_service_render() {
+        let service = build_service();
+        let keys = vec![b"b".to_vec(), b"c".to_vec(), b"b".to_vec(), b"a".to_vec()];
+        let values = service.lookup(&keys, None).unwrap();
+
+        assert_eq!(vec![Some(9606), None, Some(9606), Some(562)], values);
+        assert_eq!(
+            b"{\"b\":9606,\"c\":null,\"a\":562}\n".to_vec(),
+            service.render(&keys, &values, &LookupFormat::Json).unwrap()
+        );
+        assert_eq!(
+            b"b\t9606\na\t562\n".to_vec(),
+            service.render(&keys, &values, &LookupFormat::Tsv).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_lookup_service_reload() {
+        let service = Arc::try_unwrap(build_service())
+            .ok()
+            .unwrap()
+            .with_loader(Box::new(|_| {
+                Ok(served([("a".to_string(), 9606)].into_iter().collect()))
+            }));
+        let keys = vec![b"a".to_vec(), b"b".to_vec()];
+
+        assert_eq!(
+            vec![Some(562), Some(9606)],
+            service.lookup(&keys, None).unwrap()
+        );
+        assert_eq!(1, service.reload(0).unwrap());
+        assert_eq!(vec![Some(9606), None], service.lookup(&keys, None).unwrap());
+
+        // A service without a loader keeps its maps
+        assert!(Arc::try_unwrap(build_service())
+            .ok()
+            .unwrap()
+            .reload(0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_lookup_remote() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = format!("http://{}/", listener.local_addr().unwrap());
+        let service = build_service();
+        std::thread::spawn(move || serve_lookups(listener, service, 4));
+
+        let mut obs_output: Vec<u8> = Vec::new();
+        let obs_summary =
+            lookup_remote(&server, None, &b"a\nc\n\nb\n"[..], &mut obs_output, 2).unwrap();
+
+        let exp_summary = ClientLookupSummary {
+            keys_sent: 3,
+            records_found: 2,
+            requests: 2,
+        };
+        assert_eq!(exp_summary, obs_summary);
+        assert_eq!(b"a\t562\nb\t9606\n".to_vec(), obs_output);
+    }
+
+    #[test]
+    fn test_serve_lookups() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener_address = listener.local_addr().unwrap();
+        let service = build_service();
+        std::thread::spawn(move || serve_lookups(listener, service, 4));
+
+        let obs_json = send_request(
+            listener_address,
+            b"POST /lookup HTTP/1.1\r\nContent-Length: 4\r\n\r\na\nc\n",
+        );
+        assert!(obs_json.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(obs_json.ends_with("\r\n\r\n{\"a\":562,\"c\":null}\n"));
+
+        let obs_tsv = send_request(
+            listener_address,
+            b"POST /lookup?format=tsv HTTP/1.1\r\nContent-Length: 9\r\n\r\n[\"a\",\"b\"]",
+        );
+        assert!(obs_tsv.ends_with("\r\n\r\na\t562\nb\t9606\n"));
+
+        let obs_method = send_request(listener_address, b"GET /lookup HTTP/1.1\r\n\r\n");
+        assert!(obs_method.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        // Namespaces are decoded from the query string
+        let obs_namespace = send_request(
+            listener_address,
+            b"POST /lookup?format=tsv&namespace=pr%6Ft HTTP/1.1\r\nContent-Length: 1\r\n\r\na",
+        );
+        assert!(obs_namespace.ends_with("\r\n\r\na\t562\n"));
+
+        // Reloads and snapshots were not enabled for the service
+        let obs_reload = send_request(listener_address, b"POST /reload HTTP/1.1\r\n\r\n");
+        assert!(obs_reload.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let obs_snapshot = send_request(listener_address, b"POST /snapshot HTTP/1.1\r\n\r\n");
+        assert!(obs_snapshot.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    fn test_lookup_service_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let service = Arc::try_unwrap(build_service())
+            .ok()
+            .unwrap()
+            .with_metrics(Arc::clone(&metrics));
+
+        let keys = vec![b"a".to_vec(), b"c".to_vec()];
+        assert!(service.lookup(&keys, None).is_ok());
+
+        let obs_output = metrics.render();
+        assert!(obs_output.contains("parallel_decompression_lookup_requests_total 1\n"));
+        assert!(obs_output.contains("parallel_decompression_lookup_keys_total 2\n"));
+        assert!(obs_output.contains("parallel_decompression_lookup_keys_found_total 1\n"));
+    }
+
+    #[test]
+    fn test_lookup_service_snapshot() {
+        let snapshot_file = "lookup_service_snapshot.snapshot";
+        let service = Arc::try_unwrap(build_service())
+            .ok()
+            .unwrap()
+            .with_snapshot_writer(Box::new(move |_, served_map| {
+                crate::snapshot::save_snapshot(
+                    &served_map.records,
+                    "example.zstd",
+                    &served_map.position,
+                    snapshot_file,
+                )?;
+                Ok(snapshot_file.to_string())
+            }));
+
+        let obs_saved = service.snapshot(0);
+        let obs_map =
+            crate::snapshot::load_snapshot::<String, u64>(snapshot_file, &crate::Mode::Vector);
+        let _ = std::fs::remove_file(snapshot_file);
+
+        assert_eq!((2, snapshot_file.to_string()), obs_saved.unwrap());
+        let (obs_map, _) = obs_map.unwrap();
+        assert_eq!(2, obs_map.len());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::frame_cache::archive_key;
+use crate::{EitherMap, FrameMeta, Mode, PipelineError, RecordKey, RecordValue};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+// Marks a map snapshot, and the version of its layout
+const SNAPSHOT_MAGIC: &[u8; 8] = b"PDSNAP02";
+// Snapshots of the first layout, which named their types in a way that was not stable
+const LEGACY_SNAPSHOT_MAGIC: &[u8; 8] = b"PDSNAP01";
+const SNAPSHOT_LEVEL: i32 = 3;
+
+/// Description of a snapshot, stored as JSON ahead of its records. The key and value types are
+/// recorded by their tags so that a snapshot is never read back as a map of another type, and
+/// the frames held are recorded by the identity of their index entries, so that a snapshot is
+/// never resumed against another archive.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    /// Archive the map was decoded from, for messages
+    pub archive: String,
+    /// Order of the last frame of the archive held in the map, from which a warm start resumes
+    pub watermark: Option<u64>,
+    /// Identity of the index entries of the frames up to the watermark
+    pub index_key: u64,
+    pub records: usize,
+    pub key_type: String,
+    pub value_type: String,
+}
+
+/// How far a map has been built through its archive: the last frame it holds, and the identity
+/// of the index entries of the frames up to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct MapPosition {
+    pub watermark: Option<u64>,
+    pub index_key: u64,
+}
+
+impl MapPosition {
+    /// Position of a map holding the frames of the index up to the watermark.
+    pub(crate) fn new(idx_buffer: &[FrameMeta], watermark: Option<u64>) -> MapPosition {
+        let mut held: Vec<FrameMeta> = idx_buffer
+            .iter()
+            .filter(|f| watermark.is_some_and(|w| f.order <= w))
+            .cloned()
+            .collect();
+        held.sort_by_key(|f| f.order);
+        MapPosition {
+            watermark,
+            index_key: archive_key(&held),
+        }
+    }
+}
+
+//region: Private functions
+
+pub(crate) fn write_field<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+pub(crate) fn read_field<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> Result<()> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_le_bytes(length) as u64;
+
+    // A bad length is caught when the field runs short, rather than allocated up front
+    buffer.clear();
+    if reader.take(length).read_to_end(buffer)? as u64 != length {
+        bail!("Snapshot ends part way through a record.");
+    }
+    Ok(())
+}
+
+fn read_header<R: Read>(reader: &mut R, snapshot_file: &str) -> Result<SnapshotHeader> {
+    let mut magic = [0u8; 8];
+    if reader.read_exact(&mut magic).is_ok() && &magic == LEGACY_SNAPSHOT_MAGIC {
+        bail!(PipelineError::Usage(format!(
+            "Snapshot '{}' was saved by an earlier version in a layout which is no longer read, so must be saved again!",
+            snapshot_file
+        )));
+    }
+    if &magic != SNAPSHOT_MAGIC {
+        bail!(PipelineError::Usage(format!(
+            "'{}' is not a map snapshot!",
+            snapshot_file
+        )));
+    }
+
+    let mut header_bytes: Vec<u8> = Vec::new();
+    read_field(reader, &mut header_bytes)?;
+    match serde_json::from_slice(&header_bytes) {
+        Ok(h) => Ok(h),
+        Err(_) => bail!(PipelineError::CorruptArchive(format!(
+            "Unable to parse the header of snapshot '{}'!",
+            snapshot_file
+        ))),
+    }
+}
+
+fn write_snapshot<K: RecordKey, V: RecordValue, W: Write>(
+    mut snapshot_writer: W,
+    header: &SnapshotHeader,
+    record_map: &EitherMap<K, V>,
+) -> Result<()> {
+    snapshot_writer.write_all(SNAPSHOT_MAGIC)?;
+    write_field(&mut snapshot_writer, &serde_json::to_vec(header)?)?;
+
+    let mut record_writer = zstd::Encoder::new(snapshot_writer, SNAPSHOT_LEVEL)?;
+    for_each_record(record_map, |key, value| {
+        write_field(&mut record_writer, &key.key_bytes())?;
+        write_field(&mut record_writer, &value.snapshot_bytes())
+    })?;
+    record_writer.finish()?.flush()?;
+    Ok(())
+}
+
+fn read_records<K: RecordKey, V: RecordValue, R: Read>(
+    record_reader: &mut R,
+    records: usize,
+    mode: &Mode,
+) -> Result<EitherMap<K, V>> {
+    let mut key_buffer: Vec<u8> = Vec::new();
+    let mut value_buffer: Vec<u8> = Vec::new();
+    let entries = (0..records).map(|_| -> Result<(K, V)> {
+        read_field(record_reader, &mut key_buffer)?;
+        read_field(record_reader, &mut value_buffer)?;
+        let key = match K::from_key_bytes(&key_buffer, true) {
+            Some(k) => k,
+            None => bail!("Snapshot key is not valid UTF-8."),
+        };
+        Ok((key, V::from_snapshot_bytes(&value_buffer)?))
+    });
+
+    // Keys are unique within a snapshot, so are inserted without a duplicate key policy
+    Ok(match mode {
+        Mode::DashMap => EitherMap::Dash(entries.collect::<Result<_>>()?),
+        Mode::Vector | Mode::Merge => EitherMap::AHash(entries.collect::<Result<_>>()?),
+        Mode::Ordered | Mode::Reference => EitherMap::Ordered(entries.collect::<Result<_>>()?),
+    })
+}
+
+//endregion:
+
+/// Call `record_fn` on every record of a map, stopping at the first error.
+pub(crate) fn for_each_record<K: RecordKey, V>(
+    record_map: &EitherMap<K, V>,
+    mut record_fn: impl FnMut(&K, &V) -> Result<()>,
+) -> Result<()> {
+    match record_map {
+        EitherMap::Dash(m) => m.iter().try_for_each(|r| record_fn(r.key(), r.value())),
+        EitherMap::AHash(m) => m.iter().try_for_each(|(k, v)| record_fn(k, v)),
+        EitherMap::Ordered(m) => m.iter().try_for_each(|(k, v)| record_fn(k, v)),
+    }
+}
+
+/// Write the records of a map to a snapshot file, so that a later run can start from the map
+/// rather than decoding the archive again. The snapshot is written under a temporary name and
+/// renamed into place, so an earlier snapshot is only replaced by a complete one.
+pub(crate) fn save_snapshot<K: RecordKey, V: RecordValue>(
+    record_map: &EitherMap<K, V>,
+    archive: &str,
+    position: &MapPosition,
+    snapshot_file: &str,
+) -> Result<()> {
+    let temporary_file = format!("{}.{}.tmp", snapshot_file, std::process::id());
+    let snapshot_handle = File::create(&temporary_file)
+        .with_context(|| format!("Unable to create snapshot file '{}'", snapshot_file))?;
+    let snapshot_writer = BufWriter::new(snapshot_handle);
+
+    let header = SnapshotHeader {
+        archive: archive.to_string(),
+        watermark: position.watermark,
+        index_key: position.index_key,
+        records: record_map.len(),
+        key_type: K::TAG.to_string(),
+        value_type: V::TAG.to_string(),
+    };
+    let write_result = write_snapshot(snapshot_writer, &header, record_map).and_then(|_| {
+        std::fs::rename(&temporary_file, snapshot_file)
+            .with_context(|| format!("Unable to create snapshot file '{}'", snapshot_file))
+    });
+    if write_result.is_err() {
+        let _ = std::fs::remove_file(&temporary_file);
+    }
+    write_result
+}
+
+/// Read a snapshot back into a map gathered under the given mode, along with its header.
+pub(crate) fn load_snapshot<K: RecordKey, V: RecordValue>(
+    snapshot_file: &str,
+    mode: &Mode,
+) -> Result<(EitherMap<K, V>, SnapshotHeader)> {
+    let snapshot_handle = File::open(snapshot_file)
+        .with_context(|| format!("Unable to open snapshot file '{}'", snapshot_file))?;
+    let mut snapshot_reader = BufReader::new(snapshot_handle);
+
+    let header = read_header(&mut snapshot_reader, snapshot_file)?;
+    if header.key_type != K::TAG || header.value_type != V::TAG {
+        bail!(PipelineError::Usage(format!(
+            "Snapshot '{}' holds a map of {} keys and {} values, so cannot be read as {} keys and {} values!",
+            snapshot_file,
+            header.key_type,
+            header.value_type,
+            K::TAG,
+            V::TAG
+        )));
+    }
+
+    let mut record_reader = zstd::Decoder::with_buffer(snapshot_reader)?;
+    let record_map = read_records(&mut record_reader, header.records, mode).map_err(|e| {
+        PipelineError::CorruptArchive(format!(
+            "Unable to read the records of snapshot '{}': {}",
+            snapshot_file, e
+        ))
+    })?;
+    Ok((record_map, header))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let snapshot_file = "snapshot_round_trip.snapshot";
+        let record_map: EitherMap<String, u32> = EitherMap::AHash(
+            [("a".to_string(), 562), ("b".to_string(), 9606)]
+                .into_iter()
+                .collect(),
+        );
+
+        let position = MapPosition {
+            watermark: Some(2),
+            index_key: 7,
+        };
+        save_snapshot(&record_map, "example.zstd", &position, snapshot_file).unwrap();
+        let obs_result = load_snapshot::<String, u32>(snapshot_file, &Mode::DashMap);
+        let obs_mismatch = load_snapshot::<String, u64>(snapshot_file, &Mode::DashMap);
+        let _ = std::fs::remove_file(snapshot_file);
+
+        let (obs_map, obs_header) = obs_result.unwrap();
+        assert_eq!(Some(2), obs_header.watermark);
+        assert_eq!(7, obs_header.index_key);
+        assert_eq!("example.zstd", obs_header.archive);
+        assert_eq!("u32", obs_header.value_type);
+        let obs_map = obs_map.into_dash().unwrap();
+        assert_eq!(2, obs_map.len());
+        assert_eq!(9606, *obs_map.get("b").unwrap());
+
+        // A snapshot of 32-bit values is not read as a map of 64-bit values
+        assert!(obs_mismatch.is_err());
+    }
+
+    #[test]
+    fn test_map_position() {
+        let idx_buffer = vec![
+            FrameMeta::new(100, 50, 1),
+            FrameMeta::new(0, 100, 0),
+            FrameMeta::new(150, 50, 2),
+        ];
+
+        // Frames past the watermark, as appended to a growing archive, do not change the position
+        let obs_position = MapPosition::new(&idx_buffer, Some(1));
+        assert_eq!(obs_position, MapPosition::new(&idx_buffer[..2], Some(1)));
+        assert_eq!(
+            archive_key(&[FrameMeta::new(0, 100, 0), FrameMeta::new(100, 50, 1)]),
+            obs_position.index_key
+        );
+
+        let rewritten = vec![FrameMeta::new(0, 90, 0), FrameMeta::new(90, 60, 1)];
+        assert_ne!(obs_position, MapPosition::new(&rewritten, Some(1)));
+    }
+
+    #[test]
+    fn test_load_snapshot_not_snapshot() {
+        let obs_result = load_snapshot::<String, u64>("test/example.zstd.idx", &Mode::Vector);
+        assert!(obs_result.is_err());
+    }
+}
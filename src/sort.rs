@@ -0,0 +1,264 @@
+use crate::compression::{frame_key_range, frame_record_stats, pad_payload_end, write_frame};
+use crate::decompression::{decode_frame, trim_line_ending};
+use crate::numa::build_worker_pool;
+use crate::repack::RepackTarget;
+use crate::source::FrameSource;
+use crate::{FrameMeta, ParseOptions, RecordFormat};
+use anyhow::Result;
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+// Compression level of the sorted runs held until they are merged, chosen for speed over size
+const RUN_ZSTD_LEVEL: i32 = 1;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SortSummary {
+    pub frames_read: usize,
+    pub frames_written: usize,
+    pub records_written: usize,
+    pub bytes_written: u64,
+}
+
+/// Next line of a sorted run, as (key, run, line). Lines of equal keys are taken from the
+/// earlier run first, so that records sharing a key keep their archive order.
+type RunHead = Reverse<(Vec<u8>, usize, Vec<u8>)>;
+
+//region: Private functions
+
+fn record_key<'a>(line: &'a [u8], format: &RecordFormat) -> &'a [u8] {
+    // Lines which are not records sort on the whole line, so that nothing is lost
+    let line = trim_line_ending(line);
+    match format.split_record(line) {
+        Some((key, _)) => key,
+        None => line,
+    }
+}
+
+/// Sort the lines of a frame by key, dropping header lines, and compress the result as a run.
+fn sort_frame(payload: &[u8], format: &RecordFormat) -> Result<Vec<u8>> {
+    let mut lines: Vec<&[u8]> = payload
+        .split(|&b| b == b'\n')
+        .filter(|line| !trim_line_ending(line).is_empty() && !format.is_header(line))
+        .collect();
+    lines.sort_by_key(|line| record_key(line, format));
+
+    let mut sorted_content: Vec<u8> = Vec::with_capacity(payload.len() + 1);
+    for line in &lines {
+        sorted_content.extend_from_slice(line);
+        sorted_content.push(b'\n');
+    }
+
+    Ok(zstd::encode_all(&sorted_content[..], RUN_ZSTD_LEVEL)?)
+}
+
+fn next_head(
+    run_reader: &mut impl BufRead,
+    run: usize,
+    format: &RecordFormat,
+) -> Result<Option<RunHead>> {
+    let mut line: Vec<u8> = Vec::new();
+    if run_reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let key = record_key(&line[..line.len() - 1], format).to_vec();
+    Ok(Some(Reverse((key, run, line))))
+}
+
+fn write_sorted_frame(
+    mut zstd_writer: &File,
+    content: &[u8],
+    idx_records: &mut Vec<FrameMeta>,
+    sort_target: &RepackTarget,
+) -> Result<()> {
+    let frame_record = write_frame(
+        &mut zstd_writer,
+        content,
+        idx_records.len() as u64,
+        sort_target.zstd_level,
+        sort_target.frame_metadata,
+        sort_target.align,
+    )?;
+    let key_range = frame_key_range(content, &sort_target.format);
+    let record_stats = frame_record_stats(content, &sort_target.format);
+    idx_records.push(
+        frame_record
+            .with_key_range(key_range)
+            .with_record_stats(record_stats),
+    );
+    Ok(())
+}
+
+fn merge_runs(
+    runs: &[Vec<u8>],
+    mut zstd_writer: &File,
+    sort_target: &RepackTarget,
+) -> Result<(Vec<FrameMeta>, usize)> {
+    let format = &sort_target.format;
+    let mut run_readers: Vec<BufReader<Box<dyn Read + '_>>> = Vec::with_capacity(runs.len());
+    for run in runs {
+        let decoder = zstd::stream::read::Decoder::with_buffer(&run[..])?;
+        run_readers.push(BufReader::new(Box::new(decoder)));
+    }
+
+    // Only the next line of each run is held, so the merge needs little beyond the runs
+    let mut run_heads: BinaryHeap<RunHead> = BinaryHeap::with_capacity(runs.len());
+    for (run, run_reader) in run_readers.iter_mut().enumerate() {
+        if let Some(head) = next_head(run_reader, run, format)? {
+            run_heads.push(head);
+        }
+    }
+
+    let mut idx_records: Vec<FrameMeta> = Vec::new();
+    let mut records_written: usize = 0;
+    let mut content: Vec<u8> = Vec::new();
+
+    while let Some(Reverse((_, run, line))) = run_heads.pop() {
+        content.extend_from_slice(&line);
+        records_written += 1;
+
+        if let Some(head) = next_head(&mut run_readers[run], run, format)? {
+            run_heads.push(head);
+        }
+
+        if content.len() >= sort_target.block_size {
+            write_sorted_frame(zstd_writer, &content, &mut idx_records, sort_target)?;
+            content.clear();
+        }
+    }
+
+    if !content.is_empty() {
+        write_sorted_frame(zstd_writer, &content, &mut idx_records, sort_target)?;
+    }
+    pad_payload_end(&mut zstd_writer, sort_target.align)?;
+
+    Ok((idx_records, records_written))
+}
+
+//endregion:
+
+/// Rewrite an archive with its records sorted by key. Frames are decoded and sorted in
+/// parallel, each held as a compressed run, and the runs are then merged into frames of the
+/// target block size. The new index records the key range of every frame, so the archive is
+/// eligible for lookups and joins which need sorted input.
+pub(crate) fn sort_zstd(
+    source: &dyn FrameSource,
+    mut idx_buffer: Vec<FrameMeta>,
+    zstd_writer: File,
+    mut idx_writer: BufWriter<File>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+    sort_target: &RepackTarget,
+) -> Result<SortSummary> {
+    idx_buffer.sort_by_key(|f| f.order);
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let runs: Vec<Vec<u8>> = pool.install(|| {
+        idx_buffer
+            .par_iter()
+            .map(|idx_frame| {
+                let payload = decode_frame(source, idx_frame, parse_options)?;
+                sort_frame(&payload, &sort_target.format)
+            })
+            .collect::<Result<_>>()
+    })?;
+
+    let (idx_records, records_written) = merge_runs(&runs, &zstd_writer, sort_target)?;
+
+    serde_json::to_writer_pretty(&mut idx_writer, &idx_records)?;
+    idx_writer.flush()?;
+
+    Ok(SortSummary {
+        frames_read: runs.len(),
+        frames_written: idx_records.len(),
+        records_written,
+        bytes_written: zstd_writer.metadata()?.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::join::is_key_sorted;
+    use crate::source::FileSource;
+    use std::fs::OpenOptions;
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn open_file_write(file_path: &str) -> File {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sort_frame() {
+        let payload = b"readID\tseqID\ttaxID\nr2\tWP_1.1\t562\nr1\tWP_2.1\t9606\nr2\tWP_3.1\t562";
+
+        let obs_run = sort_frame(payload, &RecordFormat::Centrifuge).unwrap();
+
+        // The header is dropped and records sharing a key keep their order
+        let exp_content = b"r1\tWP_2.1\t9606\nr2\tWP_1.1\t562\nr2\tWP_3.1\t562\n".to_vec();
+        assert_eq!(exp_content, zstd::decode_all(&obs_run[..]).unwrap());
+    }
+
+    #[test]
+    fn test_sort_zstd() {
+        let zstd_file = "sort_zstd.zstd";
+        let idx_file = "sort_zstd.zstd.idx";
+
+        let idx_buffer =
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap();
+        let sort_target = RepackTarget {
+            block_size: 200,
+            zstd_level: 3,
+            frame_metadata: false,
+            align: None,
+            format: RecordFormat::Tsv,
+        };
+
+        let obs_result = sort_zstd(
+            &FileSource::new("test/example.zstd"),
+            idx_buffer,
+            open_file_write(zstd_file),
+            BufWriter::new(open_file_write(idx_file)),
+            2,
+            &ParseOptions::default(),
+            &sort_target,
+        );
+        let obs_payload = std::fs::read(zstd_file).unwrap();
+        let obs_idx = load_frame_index(&mut BufReader::new(open_file_read(idx_file))).unwrap();
+        let _ = std::fs::remove_file(zstd_file);
+        let _ = std::fs::remove_file(idx_file);
+
+        let obs_summary = obs_result.unwrap();
+        assert_eq!(3, obs_summary.frames_read);
+        assert_eq!(30, obs_summary.records_written);
+        assert_eq!(obs_idx.len(), obs_summary.frames_written);
+        assert!(is_key_sorted(&obs_idx));
+
+        let mut exp_lines: Vec<String> = std::fs::read_to_string("test/data.txt")
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        exp_lines.sort_by(|a, b| a.split('\t').next().cmp(&b.split('\t').next()));
+
+        let obs_content = String::from_utf8(zstd::decode_all(&obs_payload[..]).unwrap()).unwrap();
+        assert_eq!(exp_lines, obs_content.lines().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,604 @@
+use crate::FrameMeta;
+use bytes::Bytes;
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+// Time allowed for an HTTP range request to connect, or to make progress once connected
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the compressed bytes of an archive come from. The decode core reads every frame
+/// through a source, so an archive may be a local file, a memory map, a remote object or a
+/// buffer, and reads from several threads at once.
+pub trait FrameSource: Send + Sync {
+    /// Name of the archive, for messages.
+    fn name(&self) -> &str;
+
+    /// Read `length` bytes of the archive from `position`, failing if the archive ends first.
+    fn read_at(&self, position: u64, length: usize) -> std::io::Result<Bytes>;
+
+    /// Read the compressed bytes of a frame.
+    fn read_frame(&self, frame_meta: &FrameMeta) -> std::io::Result<Bytes> {
+        let length = usize::try_from(frame_meta.length).map_err(std::io::Error::other)?;
+        self.read_at(frame_meta.position, length)
+    }
+}
+
+/// Reads an archive file with positioned reads. The file is opened for each read, so that a
+/// retry after a stale handle on a network filesystem starts afresh.
+#[derive(Debug)]
+pub struct FileSource {
+    path: String,
+}
+
+impl FileSource {
+    pub fn new(path: &str) -> FileSource {
+        FileSource {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl FrameSource for FileSource {
+    fn name(&self) -> &str {
+        &self.path
+    }
+
+    fn read_at(&self, position: u64, length: usize) -> std::io::Result<Bytes> {
+        let zstd_reader = OpenOptions::new().read(true).open(&self.path)?;
+
+        // A span past the end of the file fails before its buffer is allocated
+        let file_length = zstd_reader.metadata()?.len();
+        match position.checked_add(length as u64) {
+            Some(end) if end <= file_length => {}
+            _ => return Err(ErrorKind::UnexpectedEof.into()),
+        }
+
+        let mut buffer = vec![0u8; length];
+        zstd_reader.read_exact_at(&mut buffer, position)?;
+        Ok(Bytes::from(buffer))
+    }
+}
+
+/// A read-only memory map of a whole file, unmapped once the last bytes taken from it are dropped.
+struct Mapping {
+    address: *mut libc::c_void,
+    length: usize,
+}
+
+// The mapping is never written, so may be read from any thread
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl AsRef<[u8]> for Mapping {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.address as *const u8, self.length) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.address, self.length);
+        }
+    }
+}
+
+/// Reads an archive file through a memory map, so that frames are taken from the page cache
+/// without copying. Frames read from it borrow the mapped pages, so it is only sound to use on
+/// files which are not changed while mapped; see [`MmapSource::open`].
+pub struct MmapSource {
+    path: String,
+    map: Bytes,
+}
+
+impl std::fmt::Debug for MmapSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MmapSource")
+            .field("path", &self.path)
+            .field("length", &self.map.len())
+            .finish()
+    }
+}
+
+impl MmapSource {
+    /// Map the file at `path` for reading.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or written to, by this or any other process, for as long
+    /// as the source or any frame read from it is alive. The bytes handed out are views of the
+    /// mapped pages, so a write shows through them as data changing underneath a shared
+    /// reference, and a truncation turns a later read of them into a SIGBUS. Use a
+    /// [`FileSource`] for archives which may still be written, such as those being followed.
+    pub unsafe fn open(path: &str) -> std::io::Result<MmapSource> {
+        let zstd_reader = File::open(path)?;
+        let length =
+            usize::try_from(zstd_reader.metadata()?.len()).map_err(std::io::Error::other)?;
+
+        // A map may not be empty, so an empty file has nothing to map
+        let map = match length {
+            0 => Bytes::new(),
+            _ => {
+                let address = unsafe {
+                    libc::mmap(
+                        std::ptr::null_mut(),
+                        length,
+                        libc::PROT_READ,
+                        libc::MAP_PRIVATE,
+                        zstd_reader.as_raw_fd(),
+                        0,
+                    )
+                };
+                if address == libc::MAP_FAILED {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Bytes::from_owner(Mapping { address, length })
+            }
+        };
+
+        Ok(MmapSource {
+            path: path.to_string(),
+            map,
+        })
+    }
+}
+
+impl FrameSource for MmapSource {
+    fn name(&self) -> &str {
+        &self.path
+    }
+
+    fn read_at(&self, position: u64, length: usize) -> std::io::Result<Bytes> {
+        slice_span(&self.map, position, length)
+    }
+}
+
+/// Holds a whole archive in memory.
+pub struct MemorySource {
+    name: String,
+    payload: Bytes,
+}
+
+impl std::fmt::Debug for MemorySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MemorySource")
+            .field("name", &self.name)
+            .field("length", &self.payload.len())
+            .finish()
+    }
+}
+
+impl MemorySource {
+    pub fn new(name: &str, payload: impl Into<Bytes>) -> MemorySource {
+        MemorySource {
+            name: name.to_string(),
+            payload: payload.into(),
+        }
+    }
+}
+
+impl FrameSource for MemorySource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read_at(&self, position: u64, length: usize) -> std::io::Result<Bytes> {
+        slice_span(&self.payload, position, length)
+    }
+}
+
+// Buffers such as those written by an in-memory compression are read by copying out each span
+impl FrameSource for Cursor<Vec<u8>> {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn read_at(&self, position: u64, length: usize) -> std::io::Result<Bytes> {
+        self.get_ref().as_slice().read_at(position, length)
+    }
+}
+
+impl FrameSource for &[u8] {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn read_at(&self, position: u64, length: usize) -> std::io::Result<Bytes> {
+        let start = usize::try_from(position).map_err(std::io::Error::other)?;
+        match start.checked_add(length) {
+            Some(end) if end <= self.len() => Ok(Bytes::copy_from_slice(&self[start..end])),
+            _ => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Reads an archive from an HTTP server (such as an object store gateway) with one range
+/// request per read. Only plain 'http://' URLs are supported.
+#[derive(Debug)]
+pub struct HttpSource {
+    url: String,
+    host: String,
+    path: String,
+}
+
+impl HttpSource {
+    pub fn new(url: &str) -> std::io::Result<HttpSource> {
+        let Some(location) = url.strip_prefix("http://") else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' is not an http:// URL", url),
+            ));
+        };
+        let (host, path) = match location.find('/') {
+            Some(i) => location.split_at(i),
+            None => (location, "/"),
+        };
+
+        Ok(HttpSource {
+            url: url.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    fn address(&self) -> String {
+        match self.host.contains(':') {
+            true => self.host.clone(),
+            false => format!("{}:80", self.host),
+        }
+    }
+
+    /// Send a GET request, for the given range of bytes if any, and return the response status,
+    /// its headers (lower-cased) and its body.
+    fn get(&self, range: Option<(u64, u64)>) -> std::io::Result<(String, String, Bytes)> {
+        let range_header = match range {
+            Some((first, last)) => format!("Range: bytes={}-{}\r\n", first, last),
+            None => String::new(),
+        };
+
+        let mut stream = TcpStream::connect(self.address())?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+            self.path, self.host, range_header
+        )?;
+
+        let mut response: Vec<u8> = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response = Bytes::from(response);
+
+        let Some(header_end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Incomplete HTTP response",
+            ));
+        };
+        let header = String::from_utf8_lossy(&response[..header_end]).to_ascii_lowercase();
+
+        if header.contains("transfer-encoding: chunked") {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "Chunked HTTP responses are not supported",
+            ));
+        }
+
+        let status = header
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_string();
+        Ok((status, header, response.slice(header_end + 4..)))
+    }
+
+    /// Length of the whole object, taken from the 'Content-Range' of a request for its first
+    /// byte, so that a frame index can be checked against it before any frame is read.
+    pub fn length(&self) -> std::io::Result<u64> {
+        let (status, header, _) = self.get(Some((0, 0)))?;
+        match (status.as_str(), content_range(&header)) {
+            ("206", Some((_, _, Some(total)))) => Ok(total),
+            ("206", _) => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Server gave no length for '{}'", self.url),
+            )),
+            ("200", _) => Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                format!("Server ignored Range for '{}'", self.url),
+            )),
+            _ => Err(std::io::Error::other(format!(
+                "HTTP status {} for '{}'",
+                status, self.url
+            ))),
+        }
+    }
+
+    /// Read the whole object, such as a frame index held alongside the archive.
+    pub fn read_all(&self) -> std::io::Result<Bytes> {
+        match self.get(None)? {
+            (status, _, body) if status == "200" => Ok(body),
+            (status, _, _) => Err(std::io::Error::other(format!(
+                "HTTP status {} for '{}'",
+                status, self.url
+            ))),
+        }
+    }
+}
+
+impl FrameSource for HttpSource {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn read_at(&self, position: u64, length: usize) -> std::io::Result<Bytes> {
+        if length == 0 {
+            return Ok(Bytes::new());
+        }
+
+        // The span comes from the index, so a corrupt index may place it past any real offset
+        let last = position.checked_add(length as u64 - 1).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Range of {} bytes at {} is out of bounds", length, position),
+            )
+        })?;
+        let (status, header, body) = self.get(Some((position, last)))?;
+
+        match status.as_str() {
+            "206" => match content_range(&header) {
+                Some((first, end, _)) if (first, end) == (position, last) => {
+                    match body.len() >= length {
+                        true => Ok(body.slice(..length)),
+                        false => Err(ErrorKind::UnexpectedEof.into()),
+                    }
+                }
+                _ => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Server answered range {}-{} of '{}' with another span",
+                        position, last, self.url
+                    ),
+                )),
+            },
+            // Reading the whole object for every frame would cost the archive size per read
+            "200" => Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                format!("Server ignored Range for '{}'", self.url),
+            )),
+            _ => Err(std::io::Error::other(format!(
+                "HTTP status {} for range {}-{}",
+                status, position, last
+            ))),
+        }
+    }
+}
+
+//region: Private functions
+
+/// First and last bytes of a ranged reply, and the length of the whole object when the server
+/// gives it, from its 'Content-Range' header.
+fn content_range(header: &str) -> Option<(u64, u64, Option<u64>)> {
+    let value = header
+        .lines()
+        .find_map(|line| line.strip_prefix("content-range:"))?
+        .trim()
+        .strip_prefix("bytes ")?;
+    let (span, total) = value.split_once('/')?;
+    let (first, last) = span.split_once('-')?;
+    Some((
+        first.trim().parse().ok()?,
+        last.trim().parse().ok()?,
+        total.trim().parse().ok(),
+    ))
+}
+
+fn slice_span(payload: &Bytes, position: u64, length: usize) -> std::io::Result<Bytes> {
+    let start = usize::try_from(position).map_err(std::io::Error::other)?;
+    match start.checked_add(length) {
+        Some(end) if end <= payload.len() => Ok(payload.slice(start..end)),
+        _ => Err(ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+//endregion:
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_memory_source() {
+        let source = MemorySource::new("memory", b"0123456789".to_vec());
+
+        assert_eq!(&b"345"[..], source.read_at(3, 3).unwrap());
+        assert_eq!(
+            &b"89"[..],
+            source.read_frame(&FrameMeta::new(8, 2, 0)).unwrap()
+        );
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            source.read_at(8, 3).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_slice_source() {
+        let payload = Cursor::new(b"0123456789".to_vec());
+
+        assert_eq!(&b"345"[..], payload.read_at(3, 3).unwrap());
+        assert_eq!(&b"89"[..], (&b"0123456789"[..]).read_at(8, 2).unwrap());
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            payload.read_at(8, 3).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_mmap_source() {
+        let exp_payload = std::fs::read("test/example.zstd").unwrap();
+        let file_source = FileSource::new("test/example.zstd");
+        // SAFETY: the test archive is never written while the tests run
+        let mmap_source = unsafe { MmapSource::open("test/example.zstd") }.unwrap();
+
+        assert_eq!(
+            &exp_payload[151..301],
+            mmap_source.read_at(151, 150).unwrap()
+        );
+        assert_eq!(
+            file_source.read_at(301, 120).unwrap(),
+            mmap_source.read_at(301, 120).unwrap()
+        );
+        assert!(mmap_source.read_at(301, 121).is_err());
+    }
+
+    /// Answer a single request for the example archive, building the reply from the range
+    /// asked for, if any.
+    fn answer_once(
+        respond: impl FnOnce(&[u8], Option<(usize, usize)>) -> Vec<u8> + Send + 'static,
+    ) -> (String, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/example.zstd", listener.local_addr().unwrap());
+
+        let server = std::thread::spawn(move || {
+            let payload = std::fs::read("test/example.zstd").unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut range: Option<(usize, usize)> = None;
+            for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                let line = line.unwrap();
+                if let Some(r) = line.strip_prefix("Range: bytes=") {
+                    let (start, end) = r.split_once('-').unwrap();
+                    range = Some((start.parse().unwrap(), end.parse().unwrap()));
+                }
+                if line.is_empty() {
+                    break;
+                }
+            }
+            stream.write_all(&respond(&payload, range)).unwrap();
+        });
+        (url, server)
+    }
+
+    fn partial_reply(payload: &[u8], start: usize, end: usize) -> Vec<u8> {
+        let mut reply = format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+            start,
+            end,
+            payload.len(),
+            end + 1 - start
+        )
+        .into_bytes();
+        reply.extend_from_slice(&payload[start..=end]);
+        reply
+    }
+
+    #[test]
+    fn test_content_range() {
+        let header = "http/1.1 206 partial content\r\ncontent-range: bytes 151-300/421";
+        assert_eq!(Some((151, 300, Some(421))), content_range(header));
+        assert_eq!(
+            Some((0, 0, None)),
+            content_range("content-range: bytes 0-0/*")
+        );
+        assert_eq!(None, content_range("content-length: 10"));
+    }
+
+    #[test]
+    fn test_http_source() {
+        let exp_payload = std::fs::read("test/example.zstd").unwrap();
+
+        // Answer a single range request, as an object store would
+        let (url, server) = answer_once(|payload, range| {
+            let (start, end) = range.unwrap();
+            partial_reply(payload, start, end)
+        });
+        let source = HttpSource::new(&url).unwrap();
+        let obs_bytes = source.read_frame(&FrameMeta::new(151, 150, 1)).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(&exp_payload[151..301], obs_bytes);
+        assert!(HttpSource::new("https://example.org/a.zstd").is_err());
+
+        // A span which overflows is refused before any request is sent
+        let obs_error = source.read_at(u64::MAX, 2).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, obs_error.kind());
+    }
+
+    #[test]
+    fn test_http_source_ignored_range() {
+        // The whole object is sent, as by a server which does not support ranges
+        let (url, server) = answer_once(|payload, _| {
+            let mut reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                payload.len()
+            )
+            .into_bytes();
+            reply.extend_from_slice(payload);
+            reply
+        });
+        let obs_result = HttpSource::new(&url).unwrap().read_at(151, 150);
+        server.join().unwrap();
+        assert_eq!(ErrorKind::Unsupported, obs_result.unwrap_err().kind());
+
+        // A ranged reply for a span other than the one asked for
+        let (url, server) = answer_once(|payload, _| partial_reply(payload, 0, 149));
+        let obs_result = HttpSource::new(&url).unwrap().read_at(151, 150);
+        server.join().unwrap();
+        assert_eq!(ErrorKind::InvalidData, obs_result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_http_source_length() {
+        let (url, server) = answer_once(|payload, range| {
+            let (start, end) = range.unwrap();
+            partial_reply(payload, start, end)
+        });
+        let obs_length = HttpSource::new(&url).unwrap().length().unwrap();
+        server.join().unwrap();
+        assert_eq!(421, obs_length);
+
+        // A ranged reply which leaves the total length unknown
+        let (url, server) = answer_once(|_, _| {
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-0/*\r\nContent-Length: 1\r\n\r\nx"
+                .to_vec()
+        });
+        let obs_result = HttpSource::new(&url).unwrap().length();
+        server.join().unwrap();
+        assert_eq!(ErrorKind::InvalidData, obs_result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_http_source_read_all() {
+        let exp_payload = std::fs::read("test/example.zstd.idx").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/example.zstd.idx", listener.local_addr().unwrap());
+
+        let server_payload = exp_payload.clone();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                if line.unwrap().is_empty() {
+                    break;
+                }
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                server_payload.len()
+            )
+            .unwrap();
+            stream.write_all(&server_payload).unwrap();
+        });
+
+        let obs_payload = HttpSource::new(&url).unwrap().read_all().unwrap();
+        server.join().unwrap();
+
+        assert_eq!(exp_payload, obs_payload);
+    }
+}
@@ -0,0 +1,298 @@
+use crate::snapshot::for_each_record;
+use crate::{EitherMap, RecordKey};
+use ahash::AHashMap;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TaxonInfo {
+    pub taxid: u64,
+    pub rank: Option<Arc<str>>,
+    pub name: Option<Arc<str>>,
+}
+
+pub struct Taxonomy {
+    nodes: AHashMap<u64, (u64, Arc<str>)>,
+    names: AHashMap<u64, Arc<str>>,
+}
+
+//region: Private functions
+
+fn split_dmp_line(line: &str) -> Vec<&str> {
+    // Fields in the taxdump files are delimited by '\t|\t', with a trailing '\t|' on each line
+    let line = line.strip_suffix("\t|").unwrap_or(line);
+    line.split("\t|\t").collect()
+}
+
+fn open_dmp_file(taxdump_dir: &str, file_name: &str) -> Result<BufReader<File>> {
+    let file_path = Path::new(taxdump_dir).join(file_name);
+
+    let dmp_handle = File::open(&file_path)
+        .with_context(|| format!("Unable to open taxdump file '{}'", file_path.display()))?;
+    Ok(BufReader::new(dmp_handle))
+}
+
+fn load_nodes(nodes_reader: impl BufRead) -> Result<AHashMap<u64, (u64, Arc<str>)>> {
+    let mut ranks: AHashMap<String, Arc<str>> = AHashMap::new();
+    let mut nodes: AHashMap<u64, (u64, Arc<str>)> = AHashMap::new();
+
+    for line in nodes_reader.lines() {
+        let line = line?;
+        let fields = split_dmp_line(&line);
+
+        let (taxid, parent, rank) = match fields.as_slice() {
+            [taxid, parent, rank, ..] => match (taxid.parse::<u64>(), parent.parse::<u64>()) {
+                (Ok(t), Ok(p)) => (t, p, *rank),
+                _ => bail!("Unable to parse nodes.dmp record '{}'!", line),
+            },
+            _ => bail!("Unable to parse nodes.dmp record '{}'!", line),
+        };
+
+        // There are only a few dozen ranks, so share a single copy of each between nodes
+        let rank = ranks
+            .entry(rank.to_string())
+            .or_insert_with(|| Arc::from(rank))
+            .clone();
+        nodes.insert(taxid, (parent, rank));
+    }
+
+    Ok(nodes)
+}
+
+fn load_names(names_reader: impl BufRead) -> Result<AHashMap<u64, Arc<str>>> {
+    let mut names: AHashMap<u64, Arc<str>> = AHashMap::new();
+
+    for line in names_reader.lines() {
+        let line = line?;
+        let fields = split_dmp_line(&line);
+
+        if let [taxid, name, _, "scientific name", ..] = fields.as_slice() {
+            match taxid.parse::<u64>() {
+                Ok(t) => names.insert(t, Arc::from(*name)),
+                Err(_) => bail!("Unable to parse names.dmp record '{}'!", line),
+            };
+        }
+    }
+
+    Ok(names)
+}
+
+//endregion:
+
+impl Taxonomy {
+    pub fn load(taxdump_dir: &str) -> Result<Taxonomy> {
+        let nodes = load_nodes(open_dmp_file(taxdump_dir, "nodes.dmp")?)?;
+        let names = load_names(open_dmp_file(taxdump_dir, "names.dmp")?)?;
+
+        Ok(Taxonomy { nodes, names })
+    }
+
+    /// Walk up the lineage of a taxid until a node of the requested rank is found. Returns
+    /// None if the taxid is unknown, or no ancestor holds the rank.
+    pub fn rollup(&self, taxid: u64, rank: &str) -> Option<u64> {
+        let mut current = taxid;
+
+        loop {
+            let (parent, current_rank) = self.nodes.get(&current)?;
+            if current_rank.as_ref() == rank {
+                return Some(current);
+            }
+
+            // The root node is its own parent
+            if *parent == current {
+                return None;
+            }
+            current = *parent;
+        }
+    }
+
+    pub fn name(&self, taxid: u64) -> Option<&str> {
+        self.names.get(&taxid).map(|n| n.as_ref())
+    }
+
+    /// Describe a taxid, first rolling it up to the requested rank if possible. Taxids which
+    /// cannot be rolled up are described as they are.
+    pub fn taxon_info(&self, taxid: u64, rank: Option<&str>) -> TaxonInfo {
+        let taxid = match rank {
+            Some(r) => self.rollup(taxid, r).unwrap_or(taxid),
+            None => taxid,
+        };
+
+        TaxonInfo {
+            taxid,
+            rank: self.nodes.get(&taxid).map(|(_, r)| r.clone()),
+            name: self.names.get(&taxid).cloned(),
+        }
+    }
+}
+
+/// Describe the value of every record as a taxon, rolled up to the rank if one is given.
+pub fn enrich_map<K: RecordKey + 'static, V: Copy + Into<u64> + 'static>(
+    record_map: EitherMap<K, V>,
+    taxonomy: &Taxonomy,
+    rank: Option<&str>,
+) -> AHashMap<K, TaxonInfo> {
+    // Many records share a taxid, so resolve each taxid only once
+    let mut taxon_cache: AHashMap<u64, TaxonInfo> = AHashMap::new();
+
+    record_map
+        .into_iter()
+        .map(|(k, value)| {
+            let taxid: u64 = value.into();
+            let taxon_info = taxon_cache
+                .entry(taxid)
+                .or_insert_with(|| taxonomy.taxon_info(taxid, rank));
+            (k, taxon_info.clone())
+        })
+        .collect()
+}
+
+/// Count the records whose value rolls up to a taxon of the rank, without building the
+/// enriched map.
+pub(crate) fn count_resolved<K: RecordKey, V: Copy + Into<u64>>(
+    record_map: &EitherMap<K, V>,
+    taxonomy: &Taxonomy,
+    rank: &str,
+) -> usize {
+    // Many records share a taxid, so resolve each taxid only once
+    let mut resolved_cache: AHashMap<u64, bool> = AHashMap::new();
+    let mut resolved: usize = 0;
+
+    let _ = for_each_record(record_map, |_, value| {
+        let taxid: u64 = (*value).into();
+        if *resolved_cache
+            .entry(taxid)
+            .or_insert_with(|| taxonomy.rollup(taxid, rank).is_some())
+        {
+            resolved += 1;
+        }
+        Ok(())
+    });
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn taxon_info(taxid: u64, rank: &str, name: &str) -> TaxonInfo {
+        TaxonInfo {
+            taxid,
+            rank: Some(Arc::from(rank)),
+            name: Some(Arc::from(name)),
+        }
+    }
+
+    #[test]
+    fn test_split_dmp_line() {
+        let exp_fields = vec!["562", "561", "species", ""];
+        assert_eq!(
+            exp_fields,
+            split_dmp_line("562\t|\t561\t|\tspecies\t|\t\t|")
+        );
+    }
+
+    #[test]
+    fn test_taxonomy_load() {
+        let obs_result = Taxonomy::load("test/taxdump");
+        assert!(obs_result.is_ok());
+
+        let taxonomy = obs_result.unwrap();
+        assert_eq!(14, taxonomy.nodes.len());
+        assert_eq!(14, taxonomy.names.len());
+
+        // Only scientific names are retained
+        assert_eq!(Some("Escherichia coli"), taxonomy.name(562));
+        assert_eq!(Some("Pseudomonadota"), taxonomy.name(1224));
+    }
+
+    #[test]
+    fn test_taxonomy_load_missing() {
+        let obs_result = Taxonomy::load("test/missing_taxdump");
+        assert!(obs_result.is_err());
+    }
+
+    #[test]
+    fn test_taxonomy_rollup() {
+        let taxonomy = Taxonomy::load("test/taxdump").unwrap();
+
+        assert_eq!(Some(561), taxonomy.rollup(562, "genus"));
+        assert_eq!(Some(562), taxonomy.rollup(562, "species"));
+        assert_eq!(Some(1903414), taxonomy.rollup(587, "family"));
+        assert_eq!(Some(2), taxonomy.rollup(584, "superkingdom"));
+
+        // Ranks below the taxid, and unknown taxids, cannot be resolved
+        assert_eq!(None, taxonomy.rollup(561, "species"));
+        assert_eq!(None, taxonomy.rollup(1047168, "genus"));
+    }
+
+    #[test]
+    fn test_taxonomy_taxon_info() {
+        let taxonomy = Taxonomy::load("test/taxdump").unwrap();
+
+        assert_eq!(
+            taxon_info(562, "species", "Escherichia coli"),
+            taxonomy.taxon_info(562, None)
+        );
+        assert_eq!(
+            taxon_info(543, "family", "Enterobacteriaceae"),
+            taxonomy.taxon_info(562, Some("family"))
+        );
+        assert_eq!(
+            taxon_info(561, "genus", "Escherichia"),
+            taxonomy.taxon_info(561, Some("species"))
+        );
+
+        let exp_unknown = TaxonInfo {
+            taxid: 1047168,
+            rank: None,
+            name: None,
+        };
+        assert_eq!(exp_unknown, taxonomy.taxon_info(1047168, Some("genus")));
+    }
+
+    #[test]
+    fn test_enrich_map() {
+        let taxonomy = Taxonomy::load("test/taxdump").unwrap();
+
+        let record_map: AHashMap<String, u64> =
+            AHashMap::from_iter([("a".into(), 562), ("b".into(), 584), ("c".into(), 7)]);
+
+        let exp_map: AHashMap<String, TaxonInfo> = AHashMap::from_iter([
+            ("a".into(), taxon_info(561, "genus", "Escherichia")),
+            ("b".into(), taxon_info(583, "genus", "Proteus")),
+            (
+                "c".into(),
+                TaxonInfo {
+                    taxid: 7,
+                    rank: None,
+                    name: None,
+                },
+            ),
+        ]);
+
+        let obs_map = enrich_map(EitherMap::AHash(record_map), &taxonomy, Some("genus"));
+        assert_eq!(exp_map, obs_map);
+    }
+
+    #[test]
+    fn test_count_resolved() {
+        let taxonomy = Taxonomy::load("test/taxdump").unwrap();
+
+        let record_map: AHashMap<String, u64> = AHashMap::from_iter([
+            ("a".into(), 562),
+            ("b".into(), 584),
+            ("c".into(), 7),
+            ("d".into(), 562),
+        ]);
+        let record_map = EitherMap::AHash(record_map);
+
+        assert_eq!(3, count_resolved(&record_map, &taxonomy, "genus"));
+        assert_eq!(0, count_resolved(&record_map, &taxonomy, "kingdom"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket shared between reader threads, capping the combined read rate. The bucket holds
+/// up to one second of reads, and a read larger than the remaining tokens puts the bucket into
+/// debt which later reads must wait out.
+#[derive(Debug)]
+pub struct ReadLimiter {
+    bytes_per_second: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl ReadLimiter {
+    /// Create a limiter for the given rate in MB/s. Rates which are not positive are raised to
+    /// one byte per second, rather than disabling the limit.
+    pub fn new(max_read_mbps: f64) -> ReadLimiter {
+        let bytes_per_second = (max_read_mbps * 1_000_000.0).max(1.0);
+        ReadLimiter {
+            bytes_per_second,
+            bucket: Mutex::new((bytes_per_second, Instant::now())),
+        }
+    }
+
+    /// Take tokens for a read of the given size, sleeping until the bucket can cover it.
+    pub fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, last_refill) = &mut *bucket;
+
+            let now = Instant::now();
+            *tokens = (*tokens
+                + now.duration_since(*last_refill).as_secs_f64() * self.bytes_per_second)
+                .min(self.bytes_per_second);
+            *last_refill = now;
+
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.bytes_per_second)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        // Sleep outside the lock, so that other readers can queue their own debt meanwhile
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_read_limiter_new() {
+        assert_eq!(10_000_000.0, ReadLimiter::new(10.0).bytes_per_second);
+        assert_eq!(1.0, ReadLimiter::new(0.0).bytes_per_second);
+        assert_eq!(1.0, ReadLimiter::new(-1.0).bytes_per_second);
+    }
+
+    #[test]
+    fn test_read_limiter_acquire() {
+        // 0.01 MB/s is 10,000 bytes per second, all of which is available immediately
+        let limiter = ReadLimiter::new(0.01);
+
+        let start = Instant::now();
+        limiter.acquire(10_000);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // The bucket is now empty, so the next 1,000 bytes take around 100ms
+        limiter.acquire(1_000);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn test_read_limiter_shared() {
+        let limiter = ReadLimiter::new(0.01);
+        limiter.acquire(10_000);
+
+        // Debt is shared, so four threads reading 500 bytes each wait around 200ms in total
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| limiter.acquire(500));
+            }
+        });
+        assert!(start.elapsed() >= Duration::from_millis(180));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::decompression::decode_frame;
+use crate::numa::{build_worker_pool, ThreadOptions};
+use crate::source::MemorySource;
+use crate::{compress_to, CompressionOptions, ParseOptions, PipelineError};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::io::{Cursor, Read};
+use std::time::Instant;
+
+// Settings within this share of the best compression ratio are treated as equally compact
+const RATIO_TOLERANCE: f64 = 0.05;
+// Frames wanted per decoding thread across the whole input, so that threads are kept busy
+// to the end rather than waiting on the last few large frames
+const FRAMES_PER_THREAD: u64 = 4;
+
+/// Outcome of compressing the sample at one block size and level.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TuneTrial {
+    pub block_size: usize,
+    pub level: i32,
+    pub frames: usize,
+    pub compressed_bytes: u64,
+    /// Sample bytes per compressed byte
+    pub ratio: f64,
+    pub compress_seconds: f64,
+    /// Rate at which the frames were decoded across the decoding threads, in decompressed MB/s,
+    /// when decoding was measured
+    pub decode_mbps: Option<f64>,
+}
+
+/// Trials of every block size and level against a sample of the input, for the caller to report.
+#[derive(Debug, Default)]
+pub struct TuneReport {
+    pub input_bytes: u64,
+    pub sample_bytes: u64,
+    pub trials: Vec<TuneTrial>,
+    /// Position of the recommended trial
+    pub recommended: usize,
+}
+
+impl TuneReport {
+    pub fn recommendation(&self) -> &TuneTrial {
+        &self.trials[self.recommended]
+    }
+}
+
+//region: Private functions
+
+/// Read up to the sample size from the start of the input, cut back to the last whole line.
+fn read_sample<R: Read>(input_reader: R, sample_size: u64) -> Result<Vec<u8>> {
+    let mut sample: Vec<u8> = Vec::new();
+    input_reader.take(sample_size).read_to_end(&mut sample)?;
+    if sample.len() as u64 == sample_size
+        && let Some(last_newline) = sample.iter().rposition(|b| *b == b'\n')
+    {
+        sample.truncate(last_newline + 1);
+    }
+    Ok(sample)
+}
+
+fn run_trial(
+    sample: &[u8],
+    block_size: usize,
+    level: i32,
+    decode_pool: Option<&rayon::ThreadPool>,
+) -> Result<TuneTrial> {
+    let start = Instant::now();
+    let mut zstd_writer = Cursor::new(Vec::new());
+    let idx_buffer = compress_to(
+        Cursor::new(sample),
+        &mut zstd_writer,
+        block_size,
+        level,
+        &CompressionOptions::default(),
+    )?;
+    let compress_seconds = start.elapsed().as_secs_f64();
+    let compressed_bytes = zstd_writer.get_ref().len() as u64;
+
+    let decode_mbps = match decode_pool {
+        Some(pool) => {
+            let source = MemorySource::new("sample", zstd_writer.into_inner());
+            let parse_options = ParseOptions::default();
+            let start = Instant::now();
+            let decoded_bytes: usize = pool.install(|| {
+                idx_buffer
+                    .par_iter()
+                    .map(|f| Ok(decode_frame(&source, f, &parse_options)?.len()))
+                    .sum::<Result<usize>>()
+            })?;
+            Some(decoded_bytes as f64 / 1e6 / start.elapsed().as_secs_f64().max(1e-9))
+        }
+        None => None,
+    };
+
+    Ok(TuneTrial {
+        block_size,
+        level,
+        frames: idx_buffer.len(),
+        compressed_bytes,
+        ratio: sample.len() as f64 / compressed_bytes.max(1) as f64,
+        compress_seconds,
+        decode_mbps,
+    })
+}
+
+/// Choose among the trials whose block size still splits the whole input into enough frames to
+/// keep every decoding thread busy. Of those within the ratio tolerance of the most compact,
+/// take the fastest to decode when decoding was measured, and the fastest to compress otherwise.
+fn recommend(trials: &[TuneTrial], input_bytes: u64, num_threads: usize) -> usize {
+    let frames_wanted = FRAMES_PER_THREAD * num_threads as u64;
+    let mut candidates: Vec<usize> = (0..trials.len())
+        .filter(|i| input_bytes.div_ceil(trials[*i].block_size as u64) >= frames_wanted)
+        .collect();
+    if candidates.is_empty() {
+        candidates = (0..trials.len()).collect();
+    }
+
+    let best_ratio = candidates
+        .iter()
+        .map(|i| trials[*i].ratio)
+        .fold(0.0, f64::max);
+    candidates.retain(|i| trials[*i].ratio >= best_ratio * (1.0 - RATIO_TOLERANCE));
+
+    let score = |i: &usize| match trials[*i].decode_mbps {
+        Some(decode_mbps) => decode_mbps,
+        None => -trials[*i].compress_seconds,
+    };
+    candidates
+        .into_iter()
+        .max_by(|a, b| score(a).total_cmp(&score(b)))
+        .unwrap_or_default()
+}
+
+//endregion:
+
+/// Compress a sample from the start of the input at every pairing of block size and level,
+/// measuring the compression ratio and, given a decoding pool size, the rate at which the frames
+/// decode in parallel on this machine. The recommendation is weighed against the size of the
+/// whole input, as the sample holds fewer frames than the full archive would.
+pub(crate) fn tune_compression<R: Read>(
+    input_reader: R,
+    input_bytes: u64,
+    sample_size: u64,
+    block_sizes: &[usize],
+    levels: &[i32],
+    decode_threads: Option<usize>,
+) -> Result<TuneReport> {
+    if block_sizes.is_empty() || levels.is_empty() {
+        bail!(PipelineError::Usage(
+            "At least one block size and one level are needed to tune compression!".into()
+        ));
+    }
+    let sample = read_sample(input_reader, sample_size)?;
+    if sample.is_empty() {
+        bail!(PipelineError::Usage(
+            "The input is empty, so there is nothing to tune against!".into()
+        ));
+    }
+
+    let decode_pool =
+        decode_threads.map(|n| build_worker_pool(n, false, &ThreadOptions::default()));
+    let mut trials: Vec<TuneTrial> = Vec::with_capacity(block_sizes.len() * levels.len());
+    for block_size in block_sizes {
+        for level in levels {
+            trials.push(run_trial(
+                &sample,
+                *block_size,
+                *level,
+                decode_pool.as_ref(),
+            )?);
+        }
+    }
+
+    let recommended = recommend(&trials, input_bytes, decode_threads.unwrap_or(1));
+    Ok(TuneReport {
+        input_bytes,
+        sample_bytes: sample.len() as u64,
+        trials,
+        recommended,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trial(block_size: usize, ratio: f64, decode_mbps: Option<f64>) -> TuneTrial {
+        TuneTrial {
+            block_size,
+            level: 3,
+            frames: 1,
+            compressed_bytes: 1,
+            ratio,
+            compress_seconds: 1.0,
+            decode_mbps,
+        }
+    }
+
+    #[test]
+    fn test_read_sample() {
+        let content = "a\t1\nb\t2\nc\t3\n";
+
+        assert_eq!(
+            b"a\t1\nb\t2\n".to_vec(),
+            read_sample(content.as_bytes(), 10).unwrap()
+        );
+        assert_eq!(
+            content.as_bytes(),
+            read_sample(content.as_bytes(), 1000).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_recommend() {
+        let trials = vec![
+            trial(1024, 2.8, Some(900.0)),
+            trial(4096, 3.1, Some(800.0)),
+            trial(1 << 20, 3.5, Some(500.0)),
+        ];
+
+        // The largest block leaves too few frames for four threads, and the smallest is too
+        // far behind on ratio to trade for its faster decoding
+        assert_eq!(1, recommend(&trials, 64 * 1024, 4));
+
+        // With a single thread, the largest block gives enough frames and the best ratio
+        assert_eq!(2, recommend(&trials, 64 << 20, 1));
+    }
+
+    #[test]
+    fn test_tune_compression() {
+        let content = std::fs::read("test/data.txt").unwrap();
+
+        let obs_report = tune_compression(
+            content.as_slice(),
+            content.len() as u64,
+            1 << 20,
+            &[64, 256],
+            &[1, 3],
+            Some(2),
+        )
+        .unwrap();
+
+        assert_eq!(4, obs_report.trials.len());
+        assert_eq!(content.len() as u64, obs_report.sample_bytes);
+        assert!(obs_report.trials.iter().all(|t| t.decode_mbps.is_some()));
+        assert!(obs_report.trials[0].frames > obs_report.trials[2].frames);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::decompression::{gather_zstd_frame, FrameLedger};
+use crate::join::is_key_sorted;
+use crate::numa::build_worker_pool;
+use crate::reorder::{ordered_channel, OrderedReceiver};
+use crate::source::FrameSource;
+use crate::{BadRecord, DecompressionSummary, FrameMeta, ParseOptions, PipelineError, RunStatus};
+use anyhow::{bail, Result};
+use dashmap::DashMap;
+use rayon::prelude::*;
+
+// Number of frames which can be decoded ahead of the one being checked before workers block
+const UNIQUE_CHANNEL_BOUND: usize = 64;
+
+type FrameEntries = Vec<(Box<[u8]>, Box<[u8]>)>;
+/// Frame and value of each record of a key
+type Occurrences = Vec<(u64, Box<[u8]>)>;
+
+/// A key found in more than one record, with the frame and value of each record in file order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateKey {
+    pub key: String,
+    pub occurrences: Vec<(u64, String)>,
+}
+
+/// Outcome of a uniqueness check, for the caller to report.
+#[derive(Debug, Default)]
+pub struct UniqueReport {
+    pub records_checked: usize,
+    /// Whether the archive was checked as key-sorted, comparing neighbouring records only
+    pub sorted: bool,
+    /// Repeated keys, in key order
+    pub duplicates: Vec<DuplicateKey>,
+    pub summary: DecompressionSummary,
+}
+
+impl UniqueReport {
+    pub fn run_status(&self) -> RunStatus {
+        RunStatus::from_summary(&self.summary)
+    }
+}
+
+//region: Private functions
+
+fn to_duplicate(key: &[u8], occurrences: Occurrences) -> DuplicateKey {
+    DuplicateKey {
+        key: String::from_utf8_lossy(key).to_string(),
+        occurrences: occurrences
+            .into_iter()
+            .map(|(order, value)| (order, String::from_utf8_lossy(&value).to_string()))
+            .collect(),
+    }
+}
+
+/// Check the records of a sorted archive as they arrive in file order, so that only the records
+/// of the current key are held.
+fn check_adjacent(
+    source: &dyn FrameSource,
+    entry_receiver: OrderedReceiver<(u64, FrameEntries)>,
+) -> Result<(usize, Vec<DuplicateKey>)> {
+    let mut records_checked: usize = 0;
+    let mut duplicates: Vec<DuplicateKey> = Vec::new();
+    let mut current_key: Option<Box<[u8]>> = None;
+    let mut occurrences: Occurrences = Vec::new();
+
+    for (order, entries) in entry_receiver {
+        for (key, value) in entries {
+            records_checked += 1;
+
+            match current_key.as_deref().map(|k| k.cmp(&key[..])) {
+                Some(std::cmp::Ordering::Equal) => {
+                    occurrences.push((order, value));
+                    continue;
+                }
+                Some(std::cmp::Ordering::Greater) => bail!(PipelineError::CorruptArchive(format!(
+                    "The records of '{}' are not in key order, although its index records them as sorted!",
+                    source.name()
+                ))),
+                _ => {}
+            }
+
+            if let Some(k) = current_key.take()
+                && occurrences.len() > 1
+            {
+                duplicates.push(to_duplicate(&k, std::mem::take(&mut occurrences)));
+            }
+            current_key = Some(key);
+            occurrences = vec![(order, value)];
+        }
+    }
+
+    if let Some(k) = current_key
+        && occurrences.len() > 1
+    {
+        duplicates.push(to_duplicate(&k, occurrences));
+    }
+    Ok((records_checked, duplicates))
+}
+
+fn check_sorted(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    pool: &rayon::ThreadPool,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+) -> Result<(usize, Vec<DuplicateKey>, Vec<BadRecord>)> {
+    let (entry_sender, entry_receiver) =
+        ordered_channel::<(u64, FrameEntries)>(0, UNIQUE_CHANNEL_BOUND);
+
+    std::thread::scope(|scope| {
+        let checker_handle = parse_options
+            .threads
+            .spawn_scoped(scope, "unique-checker", || {
+                check_adjacent(source, entry_receiver)
+            })?;
+
+        let bad_buffer: Result<Vec<Vec<BadRecord>>> = pool.install(|| {
+            idx_buffer
+                .into_par_iter()
+                .enumerate()
+                .map_with(entry_sender, |entry_sender, (sequence, idx_frame)| {
+                    let order = idx_frame.order;
+                    let (entries, bad_records): (FrameEntries, Vec<BadRecord>) =
+                        gather_zstd_frame(source, idx_frame, parse_options, frame_ledger)
+                            .inspect_err(|_| entry_sender.abandon())?
+                            .unwrap_or_default();
+
+                    if entry_sender.send(sequence, (order, entries)).is_err() {
+                        bail!("The uniqueness check stopped before all frames were read!");
+                    }
+                    Ok(bad_records)
+                })
+                .collect()
+        });
+
+        // A checker failure also stops the decoders, so report it ahead of theirs
+        let (records_checked, duplicates) = match checker_handle.join() {
+            Ok(r) => r?,
+            Err(_) => bail!("The uniqueness checker thread panicked!"),
+        };
+        Ok((records_checked, duplicates, bad_buffer?.concat()))
+    })
+}
+
+fn check_sharded(
+    source: &dyn FrameSource,
+    idx_buffer: Vec<FrameMeta>,
+    pool: &rayon::ThreadPool,
+    parse_options: &ParseOptions,
+    frame_ledger: &FrameLedger,
+) -> Result<(usize, Vec<DuplicateKey>, Vec<BadRecord>)> {
+    let seen: DashMap<Box<[u8]>, Occurrences> = DashMap::new();
+
+    let frame_counts: Result<Vec<(usize, Vec<BadRecord>)>> = pool.install(|| {
+        idx_buffer
+            .into_par_iter()
+            .map(|idx_frame| {
+                let order = idx_frame.order;
+                let (entries, bad_records): (FrameEntries, Vec<BadRecord>) =
+                    gather_zstd_frame(source, idx_frame, parse_options, frame_ledger)?
+                        .unwrap_or_default();
+
+                let records_checked = entries.len();
+                for (key, value) in entries {
+                    seen.entry(key).or_default().push((order, value));
+                }
+                Ok((records_checked, bad_records))
+            })
+            .collect()
+    });
+    let (records_checked, bad_buffer): (Vec<usize>, Vec<Vec<BadRecord>>) =
+        frame_counts?.into_iter().unzip();
+
+    // Frames complete in any order, but the records of one frame are added in file order
+    let mut duplicates: Vec<DuplicateKey> = seen
+        .into_iter()
+        .filter(|(_, occurrences)| occurrences.len() > 1)
+        .map(|(key, mut occurrences)| {
+            occurrences.sort_by_key(|(order, _)| *order);
+            to_duplicate(&key, occurrences)
+        })
+        .collect();
+    duplicates.sort_by(|a, b| a.key.cmp(&b.key));
+
+    Ok((
+        records_checked.into_iter().sum(),
+        duplicates,
+        bad_buffer.concat(),
+    ))
+}
+
+//endregion:
+
+/// Find every key held by more than one record of the archive. An archive whose index records
+/// it as key-sorted is checked by comparing neighbouring records as they are read in file order.
+/// Otherwise every key is gathered into a sharded map, which holds the records of the archive.
+pub(crate) fn check_unique_zstd(
+    source: &dyn FrameSource,
+    mut idx_buffer: Vec<FrameMeta>,
+    num_threads: usize,
+    parse_options: &ParseOptions,
+) -> Result<UniqueReport> {
+    idx_buffer.sort_by_key(|f| f.order);
+
+    // Key ranges are of the keys as written, so say nothing of the order of rebuilt keys
+    let sorted = is_key_sorted(&idx_buffer)
+        && parse_options.key_columns.is_empty()
+        && !parse_options.strip_key_version
+        && !parse_options.split_records;
+
+    let pool = build_worker_pool(
+        num_threads,
+        parse_options.numa_placement,
+        &parse_options.threads,
+    );
+    let frame_ledger = FrameLedger::default();
+    let (records_checked, duplicates, bad_records) = match sorted {
+        true => check_sorted(source, idx_buffer, &pool, parse_options, &frame_ledger)?,
+        false => check_sharded(source, idx_buffer, &pool, parse_options, &frame_ledger)?,
+    };
+
+    Ok(UniqueReport {
+        records_checked,
+        sorted,
+        duplicates,
+        summary: frame_ledger.into_summary(bad_records),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::decompression::load_frame_index;
+    use crate::source::FileSource;
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufReader, BufWriter};
+
+    fn open_file_read(file_path: &str) -> File {
+        OpenOptions::new().read(true).open(file_path).unwrap()
+    }
+
+    fn open_file_write(file_path: &str) -> File {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap()
+    }
+
+    fn check_content(stem: &str, content: &str, parse_options: &ParseOptions) -> UniqueReport {
+        let zstd_file = format!("{}.zstd", stem);
+        let idx_file = format!("{}.zstd.idx", stem);
+
+        crate::compression::write_indexed_zstd(
+            content.as_bytes(),
+            open_file_write(&zstd_file),
+            BufWriter::new(open_file_write(&idx_file)),
+            8,
+            3,
+            &crate::CompressionOptions::default(),
+        )
+        .unwrap();
+        let idx_buffer = load_frame_index(&mut BufReader::new(open_file_read(&idx_file))).unwrap();
+
+        let obs_result =
+            check_unique_zstd(&FileSource::new(&zstd_file), idx_buffer, 2, parse_options);
+        let _ = std::fs::remove_file(&zstd_file);
+        let _ = std::fs::remove_file(&idx_file);
+        obs_result.unwrap()
+    }
+
+    #[test]
+    fn test_check_unique_zstd() {
+        let obs_report = check_unique_zstd(
+            &FileSource::new("test/example.zstd"),
+            load_frame_index(&mut BufReader::new(open_file_read("test/example.zstd.idx"))).unwrap(),
+            2,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        assert!(!obs_report.sorted);
+        assert_eq!(30, obs_report.records_checked);
+        assert!(obs_report.duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_check_unique_zstd_sorted() {
+        // The run of 'b' crosses from the first frame into the second
+        let content = "a\t1\nb\t2\nb\t3\nb\t4\nc\t5\nc\t5\n";
+
+        let exp_duplicates = vec![
+            DuplicateKey {
+                key: "b".into(),
+                occurrences: vec![(0, "2".into()), (1, "3".into()), (1, "4".into())],
+            },
+            DuplicateKey {
+                key: "c".into(),
+                occurrences: vec![(2, "5".into()), (2, "5".into())],
+            },
+        ];
+
+        let obs_report = check_content(
+            "check_unique_zstd_sorted",
+            content,
+            &ParseOptions::default(),
+        );
+        assert!(obs_report.sorted);
+        assert_eq!(6, obs_report.records_checked);
+        assert_eq!(exp_duplicates, obs_report.duplicates);
+    }
+
+    #[test]
+    fn test_check_unique_zstd_sharded() {
+        let content = "b\t1\na\t2\nc\t3\nb\t4\n";
+
+        let exp_duplicates = vec![DuplicateKey {
+            key: "b".into(),
+            occurrences: vec![(0, "1".into()), (1, "4".into())],
+        }];
+
+        let obs_report = check_content(
+            "check_unique_zstd_sharded",
+            content,
+            &ParseOptions::default(),
+        );
+        assert!(!obs_report.sorted);
+        assert_eq!(4, obs_report.records_checked);
+        assert_eq!(exp_duplicates, obs_report.duplicates);
+    }
+}